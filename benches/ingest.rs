@@ -0,0 +1,118 @@
+// Per-message cost of publishing readings to the per-sensor gauges at the
+// rate of a busy site: 1000 messages a second from 200 sensors, three
+// readings each, with a `location` mapping and one enrichment label.
+// `SensorGauges::observe` with its cached series handles is compared with
+// looking up the labelled child (`with_label_values`) for every reading,
+// as the gauges did before the handle cache.
+//
+// Run with: `cargo bench --bench ingest`
+use prometheus::{GaugeVec, Opts, Registry};
+use rust_to_mqtt_prometheus_exporter::config::{EnrichmentConfig, EnrichmentRule, LoggingConfig, MetricsConfig};
+use rust_to_mqtt_prometheus_exporter::db::{start_db_worker, DbConfig};
+use rust_to_mqtt_prometheus_exporter::enrichment::Enrichment;
+use rust_to_mqtt_prometheus_exporter::logging::LogLimiter;
+use rust_to_mqtt_prometheus_exporter::metrics::SensorGauges;
+use rust_to_mqtt_prometheus_exporter::mqtt_buffer::{measurement_code, NormalizedRow};
+use rust_to_mqtt_prometheus_exporter::state::{key_for, Mapping};
+use std::collections::{BTreeMap, HashMap};
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Messages per second of the simulated site.
+const RATE: usize = 1000;
+const SENSORS: usize = 200;
+/// Seconds of traffic timed per variant.
+const SECONDS: usize = 20;
+
+fn messages() -> Vec<Vec<NormalizedRow>> {
+    let types = ["temperature_C", "humidity", "battery_ok"].map(|key| measurement_code(key).unwrap());
+    let now = chrono::Utc::now().naive_utc();
+    (0..RATE)
+        .map(|i| {
+            types
+                .iter()
+                .map(|&measurement_type| NormalizedRow {
+                    timestamp: now,
+                    sensor_id: (i % SENSORS).to_string(),
+                    model: "Acurite-Tower".to_string(),
+                    measurement_type,
+                    value: i as f64 / 10.0,
+                    topic: "rtl_433/events".to_string(),
+                    seq: 0,
+                    site: String::new(),
+                    probe: String::new(),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn mappings() -> HashMap<String, Mapping> {
+    (0..SENSORS)
+        .map(|i| {
+            let mapping = Mapping {
+                sensor_id: i.to_string(),
+                manufacturer: "Acurite-Tower".to_string(),
+                name: format!("room {}", i),
+                units: BTreeMap::new(),
+                retention_days: None,
+                expected_interval_secs: None,
+            };
+            (key_for(&mapping.sensor_id, &mapping.manufacturer), mapping)
+        })
+        .collect()
+}
+
+/// Time `publish` over `SECONDS` of traffic, after one warm-up second.
+fn time(messages: &[Vec<NormalizedRow>], mut publish: impl FnMut(&[NormalizedRow])) -> Duration {
+    messages.iter().for_each(|m| publish(m));
+    let started = Instant::now();
+    for _ in 0..SECONDS {
+        messages.iter().for_each(|m| publish(m));
+    }
+    started.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let per_message = elapsed / (SECONDS * RATE) as u32;
+    let busy = elapsed.as_secs_f64() / SECONDS as f64 * 100.0;
+    println!("{:<28} {:>8.2?}/message  {:>6.3}% of a core at {} msg/s", name, per_message, busy, RATE);
+}
+
+fn main() -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let _guard = runtime.enter();
+    let dir = std::env::temp_dir().join(format!("exporter-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let db_config = DbConfig { path: dir.join("bench.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+    let (db, _worker) = start_db_worker(&db_config, &Registry::new())?;
+    let enrichment_config = EnrichmentConfig {
+        labels: vec!["owner".to_string()],
+        rules: vec![EnrichmentRule { model: None, sensor_id: None, labels: [("owner".to_string(), "facilities".to_string())].into() }],
+        ..EnrichmentConfig::default()
+    };
+    let registry = Registry::new();
+    let log = Arc::new(LogLimiter::new(&LoggingConfig::default()));
+    let enrichment = Arc::new(Enrichment::new(&enrichment_config, db.clone(), log, &registry)?);
+    let gauges = SensorGauges::new(&registry, &MetricsConfig::default(), enrichment.clone())?;
+    let (messages, mappings) = (messages(), mappings());
+
+    report("cached series handles", time(&messages, |rows| gauges.observe(rows, &mappings)));
+
+    let uncached = GaugeVec::new(Opts::new("bench_uncached", "Readings"), &["sensor_id", "model", "site", "location", "owner"])?;
+    report(
+        "with_label_values per row",
+        time(&messages, |rows| {
+            for row in rows {
+                let location = mappings.get(&key_for(&row.sensor_id, &row.model)).map_or("", |m| m.name.as_str());
+                let owner = enrichment.values(&row.model, &row.sensor_id);
+                let labels = [row.sensor_id.as_str(), row.model.as_str(), row.site.as_str(), location, owner[0].as_str()];
+                uncached.with_label_values(&labels).set(black_box(row.value));
+            }
+        }),
+    );
+    runtime.block_on(db.close())?;
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,179 @@
+// Aggregator mode: a hub exporter replicating the databases of edge
+// exporters (one per building, say) through their `GET /api/sync`:
+//
+//   [[aggregator.sources]]
+//   site = "north"
+//   url = "http://edge-north:3000"
+//
+// One task per source pulls NDJSON pages of rows after the last replicated
+// `seq`, stores them in the local `measurements` table with the source's
+// `site` and advances the per-site cursor in `sync_cursors` in the same
+// transaction. Replicated rows get a fresh local `seq`, so the hub's own
+// `/api/sync` works and hubs can be chained (rows that already carry a site
+// keep it). Readings also update the per-sensor gauges with a `site` label,
+// and each source reports `integration_up{source="sync:<site>"}`.
+use crate::config::{AggregatorConfig, SyncSource};
+use crate::db::DbHandle;
+use crate::metrics::{IntegrationHealth, SensorGauges};
+use crate::mqtt_buffer::{measurement_code, rows_to_record_batch, NormalizedRow};
+use crate::shutdown::Tasks;
+use crate::state::Store;
+use crate::strict_start::StartGate;
+use chrono::NaiveDateTime;
+use prometheus::{IntCounterVec, Opts, Registry};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Shared state for the replication tasks, built in `server::run()`.
+#[derive(Clone)]
+pub struct AggregatorContext {
+    pub db: DbHandle,
+    pub store: Store,
+    pub gauges: Arc<SensorGauges>,
+    pub health: IntegrationHealth,
+    pub metrics: Arc<AggregatorMetrics>,
+    /// Set with `mqtt.strict_start`: nothing is replicated before it opens.
+    pub start: Option<Arc<StartGate>>,
+}
+
+pub struct AggregatorMetrics {
+    rows: IntCounterVec,
+    skipped: IntCounterVec,
+}
+
+impl AggregatorMetrics {
+    pub fn new(registry: &Registry) -> anyhow::Result<Self> {
+        let metrics = Self {
+            rows: IntCounterVec::new(Opts::new("aggregator_rows_total", "Rows replicated from edge exporters"), &["site"])?,
+            skipped: IntCounterVec::new(
+                Opts::new("aggregator_rows_skipped_total", "Replicated rows dropped because their measurement is unknown here"),
+                &["site"],
+            )?,
+        };
+        registry.register(Box::new(metrics.rows.clone()))?;
+        registry.register(Box::new(metrics.skipped.clone()))?;
+        Ok(metrics)
+    }
+}
+
+/// One NDJSON line of `/api/sync` (`handlers::MeasurementRow`).
+#[derive(Deserialize)]
+struct SyncedRow {
+    timestamp: NaiveDateTime,
+    sensor_id: String,
+    model: String,
+    measurement: String,
+    value: f64,
+    #[serde(default)]
+    topic: String,
+    seq: u64,
+    #[serde(default)]
+    site: String,
+    #[serde(default)]
+    probe: String,
+}
+
+/// `source` label of a site in `integration_*` metrics.
+pub fn source_label(site: &str) -> String {
+    format!("sync:{}", site)
+}
+
+/// Start one replication task per configured source.
+pub fn spawn_all(config: &AggregatorConfig, ctx: AggregatorContext, tasks: &Tasks) -> anyhow::Result<()> {
+    let http = reqwest::Client::builder().timeout(Duration::from_secs(60)).build()?;
+    for source in &config.sources {
+        info!("Replicating site {} from {}", source.site, source.url);
+        ctx.health.register_source(&source_label(&source.site));
+        tasks.spawn("aggregator", replicate(source.clone(), config.clone(), ctx.clone(), http.clone()));
+    }
+    Ok(())
+}
+
+/// Pull pages from `source` forever: back to back while full pages come
+/// in, every `interval_secs` once caught up or after an error.
+async fn replicate(source: SyncSource, config: AggregatorConfig, ctx: AggregatorContext, http: reqwest::Client) {
+    let label = source_label(&source.site);
+    let interval = Duration::from_secs(config.interval_secs.max(1));
+    if let Some(gate) = &ctx.start {
+        gate.opened().await;
+    }
+    let mut cursor = loop {
+        match ctx.db.sync_cursor(&source.site).await {
+            Ok(seq) => break seq,
+            Err(e) => {
+                warn!("Cannot read sync cursor of site {}: {}", source.site, e);
+                tokio::time::sleep(interval).await;
+            }
+        }
+    };
+    loop {
+        match pull_page(&source, cursor, config.batch_size, &ctx, &http).await {
+            Ok((received, next)) => {
+                ctx.health.set_up(&label, true);
+                cursor = next;
+                if received > 0 {
+                    ctx.health.success(&label);
+                }
+                if received >= config.batch_size {
+                    continue;
+                }
+            }
+            Err(e) => {
+                ctx.health.set_up(&label, false);
+                warn!("Replicating site {} failed: {}", source.site, e);
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Fetch and store one page after `cursor`. Returns the number of rows
+/// received and the new cursor.
+async fn pull_page(
+    source: &SyncSource,
+    cursor: u64,
+    limit: usize,
+    ctx: &AggregatorContext,
+    http: &reqwest::Client,
+) -> anyhow::Result<(usize, u64)> {
+    let url = format!("{}/api/sync", source.url.trim_end_matches('/'));
+    let mut request = http.get(&url).query(&[("since_seq", cursor.to_string()), ("limit", limit.to_string())]);
+    if let Some(token) = &source.bearer_token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await?.error_for_status()?;
+    let body = response.text().await?;
+
+    let mut received = 0;
+    let mut last_seq = cursor;
+    let mut rows = Vec::new();
+    for line in body.lines().filter(|l| !l.trim().is_empty()) {
+        let synced: SyncedRow = serde_json::from_str(line)?;
+        received += 1;
+        last_seq = last_seq.max(synced.seq);
+        let Some(measurement_type) = measurement_code(&synced.measurement) else {
+            ctx.metrics.skipped.with_label_values(&[&source.site]).inc();
+            continue;
+        };
+        rows.push(NormalizedRow {
+            timestamp: synced.timestamp,
+            sensor_id: synced.sensor_id,
+            model: synced.model,
+            measurement_type,
+            value: synced.value,
+            topic: synced.topic,
+            seq: 0,
+            site: if synced.site.is_empty() { source.site.clone() } else { synced.site },
+            probe: synced.probe,
+        });
+    }
+    if received == 0 {
+        return Ok((0, cursor));
+    }
+    ctx.db.merge_synced(&source.site, &source.url, rows_to_record_batch(&rows)?, last_seq).await?;
+    ctx.metrics.rows.with_label_values(&[&source.site]).inc_by(rows.len() as u64);
+    ctx.gauges.observe(&rows, &*ctx.store.read().await);
+    Ok((received, last_seq))
+}
@@ -0,0 +1,104 @@
+// Parquet archival. Slices of `measurements` are written with DuckDB's
+// `COPY (SELECT ...) TO '<file>.parquet'` into `archive.dir` and registered
+// in the `parquet_archive` manifest, so read paths keep serving them after
+// retention prunes the live rows. Exports are triggered through
+// `POST /api/export/parquet` or, with `archive.interval_hours` set, by a
+// background job that exports every completed UTC day not archived yet.
+// Each file records the slice it was cut from and the highest `seq` at the
+// time (see `parquet_archive`), so rows committed later, even with older
+// timestamps, are picked up by the next export, and read paths hide only
+// the live rows a file really holds:
+//
+//   [archive]
+//   dir = "/var/lib/exporter/archive"
+//   interval_hours = 24
+//
+// With object storage configured each new file is also uploaded (kind
+// `parquet`), like archives registered by hand.
+use crate::config::ArchiveConfig;
+use crate::db::{ArchiveEntry, DbHandle, ExportRequest};
+use crate::upload::{UploadKind, Uploader};
+use anyhow::Context;
+use chrono::Utc;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Resolve a file to register against `dir`: relative paths are taken as
+/// inside it, and the result must exist and lie within `dir` after symlinks
+/// and `..` are resolved, so the API can't make the server read arbitrary
+/// files.
+pub fn resolve_in_dir(dir: &Path, path: &str) -> anyhow::Result<PathBuf> {
+    let dir = std::fs::canonicalize(dir).with_context(|| format!("archive dir {}", dir.display()))?;
+    let file = std::fs::canonicalize(dir.join(path)).with_context(|| format!("archive file {}", path))?;
+    anyhow::ensure!(file.starts_with(&dir), "{} is outside the archive dir {}", path, dir.display());
+    Ok(file)
+}
+
+/// Export the rows of `[start_ms, end_ms)` (unbounded start when `start_ms`
+/// is `None`) that no archive holds yet into a new file in `dir`. Returns
+/// `None` when there are none.
+pub async fn export_slice(
+    db: &DbHandle,
+    dir: &Path,
+    start_ms: Option<i64>,
+    end_ms: i64,
+    uploader: Option<&Arc<Uploader>>,
+) -> anyhow::Result<Option<ArchiveEntry>> {
+    tokio::fs::create_dir_all(dir).await?;
+    let path = dir.join(format!("measurements-{}.parquet", Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+    let request = ExportRequest { start_ms, end_ms, path: path.to_string_lossy().into_owned() };
+    let Some(entry) = db.export(request).await? else { return Ok(None) };
+    info!("Exported {} rows to {}", entry.row_count, entry.path);
+    if let Some(uploader) = uploader {
+        let uploader = uploader.clone();
+        let path = std::path::PathBuf::from(&entry.path);
+        tokio::spawn(async move {
+            if let Err(e) = uploader.upload_file(&path, UploadKind::Parquet).await {
+                warn!("Uploading archive {} failed: {}", path.display(), e);
+            }
+        });
+    }
+    Ok(Some(entry))
+}
+
+/// Every `interval_hours`, export all rows from before the start of the
+/// current UTC day that no archive holds yet, late arrivals for archived
+/// days included. Returns immediately when the schedule is disabled.
+pub async fn run_scheduled(db: DbHandle, config: ArchiveConfig, uploader: Option<Arc<Uploader>>) {
+    let Some(hours) = config.interval_hours else { return };
+    let dir = std::path::PathBuf::from(&config.dir);
+    let mut interval = tokio::time::interval(Duration::from_secs(hours.max(1) * 3600));
+    loop {
+        interval.tick().await;
+        let today = Utc::now().date_naive().and_hms_opt(0, 0, 0).unwrap_or_default();
+        let end_ms = today.and_utc().timestamp_millis();
+        match export_slice(&db, &dir, None, end_ms, uploader.as_ref()).await {
+            Ok(Some(_)) => {}
+            Ok(None) => info!("Scheduled archive: nothing new before {}", today.date()),
+            Err(e) => error!("Scheduled archive failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_paths_must_stay_inside_the_archive_dir() {
+        let root = std::env::temp_dir().join(format!("exporter-resolve-test-{}", std::process::id()));
+        let dir = root.join("archive");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.parquet"), b"").unwrap();
+        std::fs::write(root.join("secret.parquet"), b"").unwrap();
+        let inside = std::fs::canonicalize(dir.join("a.parquet")).unwrap();
+        assert_eq!(resolve_in_dir(&dir, "a.parquet").unwrap(), inside);
+        assert_eq!(resolve_in_dir(&dir, inside.to_str().unwrap()).unwrap(), inside);
+        assert!(resolve_in_dir(&dir, "../secret.parquet").is_err());
+        assert!(resolve_in_dir(&dir, root.join("secret.parquet").to_str().unwrap()).is_err());
+        assert!(resolve_in_dir(&dir, "missing.parquet").is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
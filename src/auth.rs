@@ -0,0 +1,289 @@
+// Authentication of the HTTP API. Without it anyone who can reach the port
+// can rewrite mappings, so deployments outside a trusted network configure
+// static bearer tokens and/or basic auth users, each with a scope:
+//
+//   [http.auth]
+//   read_tokens = ["grafana-token"]
+//   write_tokens = ["automation-token"]
+//   public_metrics = true
+//
+//   [[http.auth.users]]
+//   username = "admin"
+//   password = "..."
+//   scope = "write"
+//
+// `read` covers every GET (metrics, queries, downloads, the UI) and the
+// POST endpoints that only query (`/api/parse-preview`, `/api/v1/read`,
+// `/api/jobs/query`); everything else needs `write`. The admin token counts
+// as a write credential, and admin endpoints still check it themselves.
+// With `public_metrics` the scrape and health endpoints stay open. CORS
+// preflight requests are never authenticated. Authentication is off while
+// no tokens or users are configured.
+//
+// Usage is tracked per credential, for sharing the API with housemates or
+// a small team. Named keys and users can have daily quotas:
+//
+//   [[http.auth.keys]]
+//   name = "alice"
+//   token = "..."
+//   scope = "read"
+//   requests_per_day = 10000
+//   bytes_per_day = 500000000
+//
+// Requests and response bytes are exported as
+// `http_auth_requests_total{key}` / `http_auth_response_bytes_total{key}`,
+// labelled by key name, username, `admin`, or `read_token_<n>` /
+// `write_token_<n>` for the unnamed token lists. A credential over one of
+// its quotas is answered `429 quota_exceeded` with `Retry-After` until the
+// next UTC midnight, counted in `http_auth_quota_exceeded_total{key}`.
+// Bytes are counted as they are sent, so the request that crosses the byte
+// quota still completes. Usage is kept in memory and starts over at a
+// restart.
+use crate::config::{AuthConfig, AuthScope};
+use crate::problem::Problem;
+use axum::{
+    body::{Body, HttpBody},
+    extract::State,
+    http::{header::{AUTHORIZATION, RETRY_AFTER, WWW_AUTHENTICATE}, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use chrono::{NaiveDate, Utc};
+use futures_util::StreamExt;
+use prometheus::{IntCounterVec, Opts, Registry};
+use std::sync::{Arc, Mutex};
+
+/// POST endpoints that only read.
+const READ_ONLY_POSTS: &[&str] = &["/api/parse-preview", "/api/v1/read", "/api/jobs/query", "/grafana/search", "/grafana/query"];
+
+/// Endpoints left open by `public_metrics`.
+const METRICS_PATHS: &[&str] = &["/metrics", "/metrics/aggregate", "/health", "/healthz/live", "/healthz/ready"];
+
+/// How a credential is presented.
+enum Secret {
+    Token(String),
+    Basic { username: String, password: String },
+}
+
+/// A configured credential with its usage.
+struct Credential {
+    /// `key` label of the usage metrics.
+    name: String,
+    secret: Secret,
+    scope: AuthScope,
+    requests_per_day: Option<u64>,
+    bytes_per_day: Option<u64>,
+    usage: Mutex<Usage>,
+}
+
+/// Usage of a credential on one UTC day.
+#[derive(Default)]
+struct Usage {
+    day: NaiveDate,
+    requests: u64,
+    bytes: u64,
+}
+
+impl Credential {
+    fn new(name: String, secret: Secret, scope: AuthScope, requests_per_day: Option<u64>, bytes_per_day: Option<u64>) -> Self {
+        Self { name, secret, scope, requests_per_day, bytes_per_day, usage: Mutex::new(Usage::default()) }
+    }
+
+    /// Count a request unless a quota of today is used up.
+    fn admit(&self) -> bool {
+        let today = Utc::now().date_naive();
+        let mut usage = self.usage.lock().unwrap();
+        if usage.day != today {
+            *usage = Usage { day: today, ..Usage::default() };
+        }
+        let over = self.requests_per_day.is_some_and(|quota| usage.requests >= quota)
+            || self.bytes_per_day.is_some_and(|quota| usage.bytes >= quota);
+        if !over {
+            usage.requests += 1;
+        }
+        !over
+    }
+
+    fn add_bytes(&self, bytes: u64) {
+        let today = Utc::now().date_naive();
+        let mut usage = self.usage.lock().unwrap();
+        if usage.day == today {
+            usage.bytes += bytes;
+        }
+    }
+}
+
+struct UsageMetrics {
+    requests: IntCounterVec,
+    bytes: IntCounterVec,
+    exceeded: IntCounterVec,
+}
+
+impl UsageMetrics {
+    fn new(registry: &Registry) -> anyhow::Result<Self> {
+        let metrics = Self {
+            requests: IntCounterVec::new(Opts::new("http_auth_requests_total", "Authenticated HTTP requests by credential"), &["key"])?,
+            bytes: IntCounterVec::new(
+                Opts::new("http_auth_response_bytes_total", "Response body bytes sent to authenticated requests by credential"),
+                &["key"],
+            )?,
+            exceeded: IntCounterVec::new(
+                Opts::new("http_auth_quota_exceeded_total", "Requests refused because the credential's daily quota was used up"),
+                &["key"],
+            )?,
+        };
+        registry.register(Box::new(metrics.requests.clone()))?;
+        registry.register(Box::new(metrics.bytes.clone()))?;
+        registry.register(Box::new(metrics.exceeded.clone()))?;
+        Ok(metrics)
+    }
+}
+
+pub struct Authenticator {
+    credentials: Vec<Arc<Credential>>,
+    /// Whether any Basic auth users are configured, for the challenge.
+    basic: bool,
+    public_metrics: bool,
+    metrics: UsageMetrics,
+}
+
+/// Compare in constant time so a secret can't be guessed byte by byte.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl Authenticator {
+    /// `None` when no credentials are configured.
+    pub fn new(config: &AuthConfig, admin_token: Option<&str>, registry: &Registry) -> anyhow::Result<Option<Self>> {
+        if config.read_tokens.is_empty() && config.write_tokens.is_empty() && config.keys.is_empty() && config.users.is_empty() {
+            return Ok(None);
+        }
+        let unnamed = |tokens: &[String], prefix: &str, scope| -> Vec<Credential> {
+            tokens
+                .iter()
+                .enumerate()
+                .map(|(i, t)| Credential::new(format!("{}_{}", prefix, i + 1), Secret::Token(t.clone()), scope, None, None))
+                .collect()
+        };
+        let mut credentials = unnamed(&config.read_tokens, "read_token", AuthScope::Read);
+        credentials.extend(unnamed(&config.write_tokens, "write_token", AuthScope::Write));
+        for key in &config.keys {
+            if key.name.is_empty() || key.token.is_empty() {
+                anyhow::bail!("http.auth.keys: name and token must not be empty");
+            }
+            let secret = Secret::Token(key.token.clone());
+            credentials.push(Credential::new(key.name.clone(), secret, key.scope, key.requests_per_day, key.bytes_per_day));
+        }
+        for user in &config.users {
+            let secret = Secret::Basic { username: user.username.clone(), password: user.password.clone() };
+            credentials.push(Credential::new(user.username.clone(), secret, user.scope, user.requests_per_day, user.bytes_per_day));
+        }
+        if let Some(token) = admin_token {
+            credentials.push(Credential::new("admin".to_string(), Secret::Token(token.to_string()), AuthScope::Write, None, None));
+        }
+        let mut names = std::collections::HashSet::new();
+        if let Some(duplicate) = credentials.iter().find(|c| !names.insert(c.name.as_str())) {
+            anyhow::bail!("http.auth: credential name {} is used twice", duplicate.name);
+        }
+        Ok(Some(Self {
+            basic: !config.users.is_empty(),
+            credentials: credentials.into_iter().map(Arc::new).collect(),
+            public_metrics: config.public_metrics,
+            metrics: UsageMetrics::new(registry)?,
+        }))
+    }
+
+    /// Scope a request needs, or `None` if it is open.
+    fn required_scope(&self, method: &Method, path: &str) -> Option<AuthScope> {
+        if method == Method::OPTIONS || (self.public_metrics && METRICS_PATHS.contains(&path)) {
+            return None;
+        }
+        if method == Method::GET || method == Method::HEAD || (method == Method::POST && READ_ONLY_POSTS.contains(&path)) {
+            Some(AuthScope::Read)
+        } else {
+            Some(AuthScope::Write)
+        }
+    }
+
+    /// The credential presented in an `Authorization` header, `None` if it
+    /// is missing or wrong. When several match, the widest scope wins.
+    fn credential(&self, authorization: &str) -> Option<&Arc<Credential>> {
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            // Check every token so the time taken doesn't reveal which matched.
+            return self
+                .credentials
+                .iter()
+                .filter(|c| matches!(&c.secret, Secret::Token(t) if constant_time_eq(t, token)))
+                .max_by_key(|c| c.scope);
+        }
+        let encoded = authorization.strip_prefix("Basic ")?;
+        let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        self.credentials
+            .iter()
+            .filter(|c| {
+                matches!(&c.secret, Secret::Basic { username: u, password: p }
+                    if constant_time_eq(u, username) & constant_time_eq(p, password))
+            })
+            .max_by_key(|c| c.scope)
+    }
+
+    fn unauthorized(&self) -> Response {
+        let challenge = if !self.basic { "Bearer" } else { "Basic realm=\"rust-to-mqtt-prometheus-exporter\"" };
+        (
+            StatusCode::UNAUTHORIZED,
+            [(WWW_AUTHENTICATE, HeaderValue::from_static(challenge))],
+            "missing or invalid credentials",
+        )
+            .into_response()
+    }
+}
+
+/// Seconds until the next UTC midnight, when quotas start over.
+fn until_midnight() -> i64 {
+    let now = Utc::now();
+    let midnight = (now.date_naive() + chrono::Days::new(1)).and_hms_opt(0, 0, 0).unwrap_or_default().and_utc();
+    (midnight - now).num_seconds().max(1)
+}
+
+/// Middleware checking the `Authorization` header against the scope the
+/// request needs: `401` without valid credentials, `403` when they only
+/// allow reading, `429` when their daily quota is used up. Counts the
+/// request and its response bytes for the credential.
+pub async fn require(State(auth): State<Arc<Authenticator>>, req: Request<Body>, next: Next) -> Response {
+    let Some(required) = auth.required_scope(req.method(), req.uri().path()) else {
+        return next.run(req).await;
+    };
+    let presented = req.headers().get(AUTHORIZATION).and_then(|v| v.to_str().ok()).and_then(|v| auth.credential(v));
+    let credential = match presented {
+        None => return auth.unauthorized(),
+        Some(c) if c.scope < required => return (StatusCode::FORBIDDEN, "credentials only allow reading").into_response(),
+        Some(c) => c.clone(),
+    };
+    if !credential.admit() {
+        auth.metrics.exceeded.with_label_values(&[credential.name.as_str()]).inc();
+        let detail = format!("daily quota of {} used up", credential.name);
+        let mut response = Problem::new(StatusCode::TOO_MANY_REQUESTS, "quota_exceeded", detail).into_response();
+        response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(until_midnight()));
+        return response;
+    }
+    auth.metrics.requests.with_label_values(&[credential.name.as_str()]).inc();
+    let response = next.run(req).await;
+    let bytes = auth.metrics.bytes.with_label_values(&[credential.name.as_str()]);
+    let (parts, body) = response.into_parts();
+    if let Some(len) = body.size_hint().exact() {
+        bytes.inc_by(len);
+        credential.add_bytes(len);
+        return Response::from_parts(parts, body);
+    }
+    // Streamed bodies are counted chunk by chunk as they are sent.
+    let body = body.into_data_stream().inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            bytes.inc_by(chunk.len() as u64);
+            credential.add_bytes(chunk.len() as u64);
+        }
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
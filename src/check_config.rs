@@ -0,0 +1,54 @@
+// Configuration check. `check-config` loads the configuration the way the
+// server does (defaults, config file, environment) and builds everything
+// that validates it without touching the database or the network: the
+// measurement registry, topic formats, model decoders, probes, sentinels,
+// composites, flush classes, sampling, rate limits, dedup, degree days,
+// authentication, the HTTPS certificate and the raw payload key. It prints
+// a summary and exits non-zero with the first error, so a deployment can
+// check a config before restarting the exporter with it:
+//
+// Run with: `rust-to-mqtt-prometheus-exporter check-config --config /etc/exporter/exporter.toml`
+use crate::auth::Authenticator;
+use crate::composite::Composites;
+use crate::config::Config;
+use crate::crypto::PayloadCipher;
+use crate::dedup::Deduplicator;
+use crate::degree_days::DegreeDays;
+use crate::mqtt_buffer::{install_measurement_types, measurement_types, FlushClass, Normalizer};
+use crate::rate_limit::RateLimiter;
+use crate::sampling::Sampler;
+use crate::sentinels::Sentinels;
+use std::path::Path;
+
+/// Entry point for the `check-config` command.
+pub async fn run_cli() -> anyhow::Result<()> {
+    let config = Config::load()?;
+    let registry = prometheus::Registry::new();
+    install_measurement_types(&config.measurements)?;
+    Normalizer::new(
+        &config.mqtt.formats,
+        &config.models,
+        &config.probes,
+        Sentinels::new(&config.sentinels, &registry)?,
+    )?;
+    Composites::new(&config.composites)?;
+    FlushClass::from_config(&config.flush)?;
+    Sampler::new(&config.sampling, &registry)?;
+    RateLimiter::new(&config.rate_limit, &registry)?;
+    Deduplicator::new(&config.dedup, &registry)?;
+    DegreeDays::new(&config.degree_days, &registry)?;
+    Authenticator::new(&config.http.auth, config.http.admin_token.as_deref(), &registry)?;
+    let tls = crate::serve::tls_acceptor(&config.http)?;
+    if let Some(path) = &config.raw.key_file {
+        PayloadCipher::from_key_file(Path::new(path))?;
+    }
+    let client_id = crate::mqtt::client_id(&config.mqtt)?;
+
+    println!("Configuration OK ({})", config.source.as_deref().unwrap_or("no config file"));
+    println!("  http:     {}{}", config.http.bind, if tls.is_some() { " (HTTPS)" } else { "" });
+    let topics = if config.mqtt.topics.is_empty() { "none".to_string() } else { config.mqtt.topics.join(", ") };
+    println!("  mqtt:     {}:{} as {}, topics {}", config.mqtt.host, config.mqtt.port, client_id, topics);
+    println!("  database: {}", config.duckdb.path);
+    println!("  measurement types: {}", measurement_types().len());
+    Ok(())
+}
@@ -0,0 +1,87 @@
+// Command line. Without a command the exporter runs as a service (`serve`);
+// the other commands are maintenance tools that read the same
+// configuration, do one thing and exit:
+//
+//   rust-to-mqtt-prometheus-exporter [--config FILE] [COMMAND] [OPTIONS]
+//
+// `--help` lists the commands, `<command> --help` their options. Settings
+// still come from defaults, the config file and the environment (see
+// `config`); `--config` names the file, for every command and for reloads.
+// Each command's options are declared next to its implementation.
+use crate::{check_config, config, export, import, import_log, query, schema, selftest, server, tail};
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(version, about = "Stores rtl_433 and Zigbee2MQTT readings from MQTT in DuckDB and exports them as Prometheus metrics")]
+pub struct Cli {
+    /// Config file, instead of `EXPORTER_CONFIG` or `./exporter.toml`.
+    #[arg(long, short, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the exporter (the default).
+    Serve,
+    /// Backfill rtl_433 JSON logs.
+    #[command(alias = "import-log")]
+    Import(import_log::Args),
+    /// Backfill a CSV export described by a column-mapping spec.
+    ImportCsv(import::Args),
+    /// Write stored readings to a Parquet or CSV file.
+    Export(export::Args),
+    /// Run one SQL statement against the database file.
+    Query(query::Args),
+    /// Print the database schema, tables and generated views.
+    Schema,
+    /// Follow the readings of a running exporter.
+    Tail(tail::Args),
+    /// Check a running exporter end to end.
+    Selftest(selftest::Args),
+    /// Validate the configuration and print a summary.
+    CheckConfig,
+}
+
+/// Parse the command line and run the command.
+pub async fn run() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    if let Some(path) = cli.config {
+        config::set_path(path);
+    }
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => server::run().await,
+        Command::Import(args) => import_log::run_cli(args).await,
+        Command::ImportCsv(args) => import::run_cli(args).await,
+        Command::Export(args) => export::run_cli(args).await,
+        Command::Query(args) => query::run_cli(args).await,
+        Command::Schema => schema::run_cli().await,
+        Command::Tail(args) => tail::run_cli(args).await,
+        Command::Selftest(args) => selftest::run_cli(args).await,
+        Command::CheckConfig => check_config::run_cli().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn the_command_line_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn commands_parse_with_their_aliases_and_options() {
+        let cli = Cli::try_parse_from(["exporter", "--config", "site.toml", "import-log", "events.json.gz"]).unwrap();
+        assert_eq!(cli.config, Some(PathBuf::from("site.toml")));
+        assert!(matches!(cli.command, Some(Command::Import(_))));
+        assert!(Cli::try_parse_from(["exporter"]).unwrap().command.is_none());
+        assert!(Cli::try_parse_from(["exporter", "export", "--format", "csv", "-c", "site.toml"]).is_ok());
+        assert!(Cli::try_parse_from(["exporter", "export", "--format", "json"]).is_err());
+        assert!(Cli::try_parse_from(["exporter", "import"]).is_err());
+    }
+}
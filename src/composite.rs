@@ -0,0 +1,298 @@
+// Composite (synthetic) sensors. Instead of repeating the same PromQL in
+// every dashboard, a derived series can be defined once in the config:
+//
+//   [[composites]]
+//   name = "greenhouse_delta"
+//   expression = "greenhouse.temperature_C - outdoor.temperature_C"
+//   measurement = "temperature_C"
+//
+// Operands are `<sensor>.<measurement>`, where `<sensor>` is a mapping name
+// (see `PUT /mapping`) or `model::sensor_id`; names containing other
+// characters than letters, digits, `_` and `:` are written in double quotes
+// (`"Acurite-Tower::1234".temperature_C`). A probe of a multi-probe sensor
+// (see `probes`) follows the measurement in brackets:
+// `pool.temperature_C[2]`. Expressions support numbers,
+// `+ - * /`, unary minus and parentheses.
+//
+// Whenever a message updates one of the operands, the expression is
+// evaluated over the latest value of every operand, and the result is
+// handled like a reading of model `composite` with the composite's name as
+// sensor id: it updates the gauges, is pushed to remote_write and stored in
+// DuckDB. Operands older than `max_age_secs` (or never seen) suppress the
+// update, as does a non-finite result such as a division by zero.
+use crate::config::CompositeConfig;
+use crate::mqtt_buffer::{measurement_code, NormalizedRow};
+use crate::state::{resolve_sensor, LatestReading, Mapping, ReadingKey, SensorKey};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+/// `model` of composite rows.
+pub const MODEL: &str = "composite";
+
+/// `topic` of composite rows.
+const TOPIC: &str = "composite";
+
+/// All configured composites, parsed and validated at startup.
+#[derive(Default)]
+pub struct Composites {
+    sensors: Vec<Composite>,
+}
+
+struct Composite {
+    name: String,
+    measurement_type: u8,
+    expression: Expr,
+    operands: Vec<Operand>,
+    max_age: Duration,
+}
+
+/// `sensor.measurement`, with the sensor resolved on every evaluation so
+/// mapping changes take effect without a restart.
+struct Operand {
+    sensor: String,
+    measurement_type: u8,
+    probe: String,
+}
+
+enum Expr {
+    Number(f64),
+    /// Index into `Composite::operands`.
+    Operand(usize),
+    Neg(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+}
+
+impl Composites {
+    pub fn new(configs: &[CompositeConfig]) -> anyhow::Result<Self> {
+        let mut names = HashSet::new();
+        let mut sensors = Vec::new();
+        for config in configs {
+            if config.name.is_empty() || !names.insert(config.name.as_str()) {
+                return Err(anyhow::anyhow!("composite names must be unique and non-empty: {:?}", config.name));
+            }
+            let measurement_type = measurement_code(&config.measurement)
+                .ok_or_else(|| anyhow::anyhow!("composite {}: unknown measurement {}", config.name, config.measurement))?;
+            let mut parser = Parser { input: config.expression.as_bytes(), pos: 0, operands: Vec::new() };
+            let expression = parser
+                .parse()
+                .map_err(|e| anyhow::anyhow!("composite {}: {} in {:?}", config.name, e, config.expression))?;
+            if parser.operands.is_empty() {
+                return Err(anyhow::anyhow!("composite {}: expression has no sensor operands", config.name));
+            }
+            sensors.push(Composite {
+                name: config.name.clone(),
+                measurement_type,
+                expression,
+                operands: parser.operands,
+                max_age: Duration::from_secs(config.max_age_secs),
+            });
+        }
+        Ok(Self { sensors })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sensors.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sensors.len()
+    }
+
+    /// Composite rows triggered by `rows`, which must already be part of
+    /// `latest`. Each composite with an operand among `rows` is evaluated
+    /// once.
+    pub fn evaluate(
+        &self,
+        rows: &[NormalizedRow],
+        latest: &HashMap<ReadingKey, LatestReading>,
+        mappings: &HashMap<String, Mapping>,
+        now: SystemTime,
+    ) -> Vec<NormalizedRow> {
+        let Some(first) = rows.first() else { return Vec::new() };
+        let mut derived = Vec::new();
+        for composite in &self.sensors {
+            let keys: Option<Vec<ReadingKey>> =
+                composite.operands.iter().map(|operand| operand.resolve(mappings)).collect();
+            let Some(keys) = keys else { continue };
+            let triggered = rows.iter().any(|row| {
+                keys.iter().any(|k| {
+                    k.measurement_type == row.measurement_type
+                        && k.sensor_id == row.sensor_id
+                        && k.model == row.model
+                        && k.probe == row.probe
+                })
+            });
+            if !triggered {
+                continue;
+            }
+            let values: Option<Vec<f64>> = keys
+                .iter()
+                .map(|key| {
+                    let reading = latest.get(key)?;
+                    let age = now.duration_since(reading.received_at).unwrap_or_default();
+                    (age <= composite.max_age).then_some(reading.value)
+                })
+                .collect();
+            let Some(values) = values else { continue };
+            let value = composite.expression.eval(&values);
+            if !value.is_finite() {
+                continue;
+            }
+            derived.push(NormalizedRow {
+                timestamp: first.timestamp,
+                sensor_id: composite.name.clone(),
+                model: MODEL.to_string(),
+                measurement_type: composite.measurement_type,
+                value,
+                topic: TOPIC.to_string(),
+                seq: 0,
+                site: String::new(),
+                probe: String::new(),
+            });
+        }
+        derived
+    }
+}
+
+impl Operand {
+    /// The reading this operand refers to, see `state::resolve_sensor`.
+    fn resolve(&self, mappings: &HashMap<String, Mapping>) -> Option<ReadingKey> {
+        let SensorKey { model, sensor_id } = resolve_sensor(&self.sensor, mappings)?;
+        Some(ReadingKey { model, sensor_id, measurement_type: self.measurement_type, probe: self.probe.clone() })
+    }
+}
+
+impl Expr {
+    fn eval(&self, values: &[f64]) -> f64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Operand(i) => values[*i],
+            Expr::Neg(e) => -e.eval(values),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(values), b.eval(values));
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    _ => a / b,
+                }
+            }
+        }
+    }
+}
+
+/// Recursive-descent parser for
+///   expr   = term (('+' | '-') term)*
+///   term   = factor (('*' | '/') factor)*
+///   factor = number | operand | '-' factor | '(' expr ')'
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    operands: Vec<Operand>,
+}
+
+impl Parser<'_> {
+    fn parse(&mut self) -> anyhow::Result<Expr> {
+        let expr = self.expr()?;
+        self.skip_space();
+        if self.pos < self.input.len() {
+            return Err(anyhow::anyhow!("unexpected {:?} at offset {}", self.input[self.pos] as char, self.pos));
+        }
+        Ok(expr)
+    }
+
+    fn expr(&mut self) -> anyhow::Result<Expr> {
+        let mut left = self.term()?;
+        while let Some(op) = self.eat_any(b"+-") {
+            left = Expr::Binary(op, Box::new(left), Box::new(self.term()?));
+        }
+        Ok(left)
+    }
+
+    fn term(&mut self) -> anyhow::Result<Expr> {
+        let mut left = self.factor()?;
+        while let Some(op) = self.eat_any(b"*/") {
+            left = Expr::Binary(op, Box::new(left), Box::new(self.factor()?));
+        }
+        Ok(left)
+    }
+
+    fn factor(&mut self) -> anyhow::Result<Expr> {
+        self.skip_space();
+        match self.input.get(self.pos) {
+            None => Err(anyhow::anyhow!("unexpected end of expression")),
+            Some(b'-') => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.factor()?)))
+            }
+            Some(b'(') => {
+                self.pos += 1;
+                let inner = self.expr()?;
+                if self.eat_any(b")").is_none() {
+                    return Err(anyhow::anyhow!("missing ')' at offset {}", self.pos));
+                }
+                Ok(inner)
+            }
+            Some(c) if c.is_ascii_digit() || *c == b'.' => {
+                let text = self.take_while(|c| c.is_ascii_digit() || c == b'.');
+                text.parse().map(Expr::Number).map_err(|_| anyhow::anyhow!("invalid number {}", text))
+            }
+            Some(_) => self.operand(),
+        }
+    }
+
+    fn operand(&mut self) -> anyhow::Result<Expr> {
+        let start = self.pos;
+        let sensor = if self.input[self.pos] == b'"' {
+            self.pos += 1;
+            let name = self.take_while(|c| c != b'"');
+            if self.input.get(self.pos) != Some(&b'"') {
+                return Err(anyhow::anyhow!("unterminated quote at offset {}", start));
+            }
+            self.pos += 1;
+            name
+        } else {
+            self.take_while(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b':')
+        };
+        if sensor.is_empty() || self.input.get(self.pos) != Some(&b'.') {
+            return Err(anyhow::anyhow!("expected <sensor>.<measurement> at offset {}", start));
+        }
+        self.pos += 1;
+        let key = self.take_while(|c| c.is_ascii_alphanumeric() || c == b'_');
+        let measurement_type = measurement_code(&key).ok_or_else(|| anyhow::anyhow!("unknown measurement {}", key))?;
+        let mut probe = String::new();
+        if self.input.get(self.pos) == Some(&b'[') {
+            self.pos += 1;
+            probe = self.take_while(|c| c != b']');
+            if probe.is_empty() || self.input.get(self.pos) != Some(&b']') {
+                return Err(anyhow::anyhow!("expected <sensor>.<measurement>[<probe>] at offset {}", start));
+            }
+            self.pos += 1;
+        }
+        self.operands.push(Operand { sensor, measurement_type, probe });
+        Ok(Expr::Operand(self.operands.len() - 1))
+    }
+
+    fn skip_space(&mut self) {
+        while self.input.get(self.pos).is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat_any(&mut self, chars: &[u8]) -> Option<char> {
+        self.skip_space();
+        let c = *self.input.get(self.pos)?;
+        chars.contains(&c).then(|| {
+            self.pos += 1;
+            c as char
+        })
+    }
+
+    fn take_while(&mut self, pred: impl Fn(u8) -> bool) -> String {
+        let start = self.pos;
+        while self.input.get(self.pos).is_some_and(|c| pred(*c)) {
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.input[start..self.pos]).into_owned()
+    }
+}
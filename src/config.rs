@@ -0,0 +1,1676 @@
+// Runtime configuration. Settings are layered: built-in defaults, then an
+// optional TOML file, then environment variables. A config file can be
+// shipped with an image or a host while a single deployment overrides one
+// or two values (broker host, credentials, ...) through the environment.
+//
+// The file is read from the path given with `--config` (see `cli`) or in
+// `EXPORTER_CONFIG`, or from `exporter.toml` in the working directory when
+// that exists. An explicitly named file that is missing or malformed is a
+// startup error.
+use crate::db::DbConfig;
+use crate::mqtt_buffer::{MeasurementType, FLUSH_MAX_BYTES, FLUSH_MAX_ROWS};
+use chrono::Weekday;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Config file looked up in the working directory when `EXPORTER_CONFIG`
+/// is not set.
+pub const DEFAULT_CONFIG_FILE: &str = "exporter.toml";
+
+/// Config file named on the command line, see `set_path`.
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Read the config file from `path` in every later `Config::load`,
+/// reloads included, instead of `EXPORTER_CONFIG`. Only the first call
+/// counts.
+pub fn set_path(path: PathBuf) {
+    let _ = CONFIG_PATH.set(path);
+}
+
+/// Complete exporter configuration.
+///
+/// ```toml
+/// [http]
+/// bind = "0.0.0.0:3000"
+///
+/// [mqtt]
+/// host = "broker.lan"
+/// topics = ["rtl_433/+/events", "zigbee2mqtt/#"]
+///
+/// [mqtt.formats]
+/// "zigbee2mqtt/#" = "zigbee2mqtt"
+///
+/// [duckdb]
+/// path = "/var/lib/exporter/exporter.duckdb"
+///
+/// [flush]
+/// threshold = 500
+/// interval_secs = 30
+/// missed_ticks = "skip"
+///
+/// [retention]
+/// days = 365
+///
+/// [s3]
+/// bucket = "exporter-backups"
+/// endpoint = "http://minio.lan:9000"
+///
+/// [raw]
+/// store = true
+/// key_file = "/run/secrets/raw_payload_key"
+///
+/// [metrics.rounding]
+/// temperature_C = 1
+///
+/// [[measurements]]
+/// key = "rain_mm"
+/// code = 11
+/// metric = "sensor_rain_millimeters_total"
+/// unit = "mm"
+/// ```
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub http: HttpConfig,
+    pub mqtt: MqttConfig,
+    pub duckdb: DbConfig,
+    pub flush: FlushConfig,
+    pub dedup: DedupConfig,
+    pub rate_limit: RateLimitConfig,
+    pub health: HealthConfig,
+    pub shutdown: ShutdownConfig,
+    pub backup: BackupConfig,
+    pub retention: RetentionConfig,
+    pub rollup: RollupConfig,
+    pub archive: ArchiveConfig,
+    pub jobs: JobsConfig,
+    pub aggregator: AggregatorConfig,
+    pub s3: S3Config,
+    pub raw: RawConfig,
+    pub wal: WalConfig,
+    pub radio: RadioConfig,
+    pub metrics: MetricsConfig,
+    pub sinks: SinksConfig,
+    pub remote_write: RemoteWriteConfig,
+    pub influx: InfluxConfig,
+    pub otel: OtelConfig,
+    pub logging: LoggingConfig,
+    /// Measurement types on top of the built-in ones, see
+    /// `mqtt_buffer::MeasurementType`.
+    pub measurements: Vec<MeasurementType>,
+    /// Computed series, see `composite`.
+    pub composites: Vec<CompositeConfig>,
+    /// Derived on/off states, see `states`.
+    pub states: Vec<StateConfig>,
+    /// Thinned storage for high-rate topics, see `sampling`.
+    pub sampling: Vec<SamplingRule>,
+    /// Decoders for rtl_433 device quirks by model, see `models`.
+    pub models: BTreeMap<String, Vec<String>>,
+    /// Payload values that are not readings, by model and measurement
+    /// key, see `sentinels`.
+    pub sentinels: BTreeMap<String, BTreeMap<String, SentinelRule>>,
+    /// Payload key patterns of further probes of a measurement, see
+    /// `probes`.
+    pub probes: Vec<ProbeRule>,
+    pub weather: WeatherConfig,
+    pub degree_days: DegreeDayConfig,
+    pub mapping: MappingConfig,
+    pub report: ReportConfig,
+    pub enrichment: EnrichmentConfig,
+    /// Config file the settings were loaded from, logged at startup.
+    #[serde(skip)]
+    pub source: Option<String>,
+    /// What a reload reads, see `reload`. `Config::load` sets `Load`.
+    #[serde(skip)]
+    pub reload: ReloadSource,
+}
+
+/// Where a reload (SIGHUP, `server::ServerHandle::reload`) reads the
+/// configuration from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ReloadSource {
+    /// Nothing: the configuration is kept, only the mappings are re-read.
+    #[default]
+    Fixed,
+    /// The config file and the environment, as `Config::load` at startup.
+    Load,
+    /// This config file, without the environment.
+    File(PathBuf),
+}
+
+impl ReloadSource {
+    /// The configuration to apply, `None` for `Fixed`.
+    pub fn load(&self) -> anyhow::Result<Option<Config>> {
+        match self {
+            Self::Fixed => Ok(None),
+            Self::Load => Config::load().map(Some),
+            Self::File(path) => Ok(Some(Config { reload: self.clone(), ..Config::from_file(path)? })),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Address the HTTP server listens on.
+    pub bind: String,
+    /// PEM certificate chain; with `tls_key_file` the server speaks HTTPS
+    /// instead of HTTP on `bind`.
+    pub tls_cert_file: Option<String>,
+    /// PEM private key of `tls_cert_file`.
+    pub tls_key_file: Option<String>,
+    /// Second, plain HTTP address redirecting every request to HTTPS, e.g.
+    /// `0.0.0.0:80`. Needs TLS.
+    pub redirect_bind: Option<String>,
+    /// Bearer token required by admin endpoints such as the raw payload
+    /// export. Admin endpoints are disabled while it is unset.
+    pub admin_token: Option<String>,
+    pub auth: AuthConfig,
+    /// Accept HTTP/2 (cleartext, prior knowledge) next to HTTP/1.1.
+    pub http2: bool,
+    /// Keep HTTP/1.1 connections open between requests.
+    pub keep_alive: bool,
+    /// Seconds between HTTP/2 keep-alive pings, `0` to send none.
+    pub keep_alive_interval_secs: u64,
+    /// Seconds to wait for a ping answer before closing the connection.
+    pub keep_alive_timeout_secs: u64,
+    /// Concurrent requests (streams) per HTTP/2 connection.
+    pub max_concurrent_streams: u32,
+    /// Open connections; further clients wait to be accepted. `0` for no
+    /// limit.
+    pub max_connections: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:3000".to_string(),
+            tls_cert_file: None,
+            tls_key_file: None,
+            redirect_bind: None,
+            admin_token: None,
+            auth: AuthConfig::default(),
+            http2: true,
+            keep_alive: true,
+            keep_alive_interval_secs: 0,
+            keep_alive_timeout_secs: 20,
+            max_concurrent_streams: 100,
+            max_connections: 0,
+        }
+    }
+}
+
+impl HttpConfig {
+    /// Base URL of this server for the `selftest` and `tail` commands:
+    /// `bind` with an unspecified address (`0.0.0.0`, `[::]`) replaced by
+    /// the loopback address of its family, `https` when TLS is configured.
+    pub fn local_url(&self) -> String {
+        let scheme = if self.tls_cert_file.is_some() { "https" } else { "http" };
+        let host = match self.bind.parse::<std::net::SocketAddr>() {
+            Ok(mut addr) => {
+                if addr.ip().is_unspecified() {
+                    let loopback: std::net::IpAddr =
+                        if addr.is_ipv4() { std::net::Ipv4Addr::LOCALHOST.into() } else { std::net::Ipv6Addr::LOCALHOST.into() };
+                    addr.set_ip(loopback);
+                }
+                addr.to_string()
+            }
+            // A host name, e.g. `localhost:3000`.
+            Err(_) => self.bind.clone(),
+        };
+        format!("{}://{}", scheme, host)
+    }
+}
+
+/// Authentication of the whole HTTP API, see `auth`. Off while no tokens
+/// or users are configured.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Bearer tokens that may read (metrics, queries, exports).
+    pub read_tokens: Vec<String>,
+    /// Bearer tokens that may also change state (mappings, ...).
+    pub write_tokens: Vec<String>,
+    /// Named bearer tokens, with optional quotas.
+    pub keys: Vec<AuthKey>,
+    /// Basic auth users.
+    pub users: Vec<AuthUser>,
+    /// Leave `/metrics`, `/metrics/aggregate` and `/health` open.
+    pub public_metrics: bool,
+}
+
+/// A bearer token whose usage is tracked under `name`.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthKey {
+    pub name: String,
+    pub token: String,
+    pub scope: AuthScope,
+    /// Requests allowed per UTC day.
+    #[serde(default)]
+    pub requests_per_day: Option<u64>,
+    /// Response bytes allowed per UTC day.
+    #[serde(default)]
+    pub bytes_per_day: Option<u64>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthUser {
+    pub username: String,
+    pub password: String,
+    pub scope: AuthScope,
+    #[serde(default)]
+    pub requests_per_day: Option<u64>,
+    #[serde(default)]
+    pub bytes_per_day: Option<u64>,
+}
+
+/// A list that may also be given as a single string.
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+/// What a credential may do; `write` includes `read`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthScope {
+    Read,
+    Write,
+}
+
+/// Broker connection and subscriptions. Credentials are only used when
+/// both `username` and `password` are set.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// Appended to `client_id` so several instances sharing a config get
+    /// distinct ids, see `mqtt::client_id`.
+    pub client_id_suffix: ClientIdSuffix,
+    /// Start a new session on every connect. With `false` the broker keeps
+    /// the subscriptions and queues QoS 1 messages while the exporter is
+    /// away.
+    pub clean_session: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Data topics (filters, wildcards allowed) to subscribe to. At least
+    /// one is required. Config files of older versions give a single
+    /// `topic`.
+    #[serde(alias = "topic", deserialize_with = "one_or_many")]
+    pub topics: Vec<String>,
+    /// Optional topic for operational commands, see `control`.
+    pub control_topic: Option<String>,
+    pub keep_alive_secs: u64,
+    /// Connect over TLS. Brokers usually listen on 8883 for this.
+    pub tls: bool,
+    /// PEM CA certificate(s) to verify the broker with. Without it the
+    /// platform's root certificates are used.
+    pub ca_file: Option<String>,
+    /// PEM client certificate and key for mutual TLS.
+    pub client_cert_file: Option<String>,
+    pub client_key_file: Option<String>,
+    /// Delay before the first reconnect attempt after a connection error;
+    /// doubles (with jitter) on every further failure.
+    pub reconnect_initial_secs: u64,
+    /// Upper bound of the reconnect delay.
+    pub reconnect_max_secs: u64,
+    /// Payload format by topic filter; topics matching none are rtl_433.
+    pub formats: BTreeMap<String, PayloadFormat>,
+    /// Hold back ingestion until mappings and database schema are
+    /// verified, see `strict_start`.
+    pub strict_start: bool,
+}
+
+/// Suffix of the MQTT client id.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientIdSuffix {
+    None,
+    /// `-<hostname>`, stable across restarts of the same host.
+    Hostname,
+}
+
+impl FromStr for ClientIdSuffix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ClientIdSuffix::None),
+            "hostname" => Ok(ClientIdSuffix::Hostname),
+            other => Err(format!("expected none or hostname, got {}", other)),
+        }
+    }
+}
+
+/// Which normalizer reads the payloads of a topic.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    /// rtl_433 JSON events, see `mqtt_buffer::Normalizer`.
+    Rtl433,
+    /// Zigbee2MQTT device states, see `zigbee2mqtt`.
+    Zigbee2mqtt,
+}
+
+impl FromStr for PayloadFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rtl433" => Ok(PayloadFormat::Rtl433),
+            "zigbee2mqtt" => Ok(PayloadFormat::Zigbee2mqtt),
+            other => Err(format!("expected rtl433 or zigbee2mqtt, got {}", other)),
+        }
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "rust_exporter_client".to_string(),
+            client_id_suffix: ClientIdSuffix::None,
+            clean_session: true,
+            username: None,
+            password: None,
+            topics: Vec::new(),
+            control_topic: None,
+            keep_alive_secs: 5,
+            tls: false,
+            ca_file: None,
+            client_cert_file: None,
+            client_key_file: None,
+            reconnect_initial_secs: 1,
+            reconnect_max_secs: 60,
+            formats: BTreeMap::new(),
+            strict_start: false,
+        }
+    }
+}
+
+/// When the ingest buffer is written to DuckDB.
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlushConfig {
+    /// Buffered rows that trigger a flush (`threshold` in older configs).
+    #[serde(alias = "threshold")]
+    pub max_rows: usize,
+    /// Approximate buffered bytes that trigger a flush, `0` for no limit.
+    pub max_bytes: usize,
+    /// Seconds the oldest buffered entry may wait before a flush, `0` to
+    /// rely on `interval_secs` alone.
+    pub max_age_secs: u64,
+    /// Seconds between periodic flushes, so quiet sensors still show up in
+    /// the database promptly.
+    pub interval_secs: u64,
+    /// What the periodic flush does about ticks missed while the host was
+    /// suspended or the exporter was starved.
+    pub missed_ticks: MissedTicks,
+    /// Groups of sensors buffered and flushed with their own limits.
+    pub classes: Vec<FlushClassConfig>,
+}
+
+/// A device class with its own flush limits, see `mqtt_buffer::FlushClass`.
+/// Unset limits are taken from `[flush]`.
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FlushClassConfig {
+    pub name: String,
+    /// Regex on the model.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Regex on the sensor id.
+    #[serde(default)]
+    pub sensor_id: Option<String>,
+    /// MQTT topic filter.
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub max_rows: Option<usize>,
+    #[serde(default)]
+    pub max_bytes: Option<usize>,
+    /// Seconds the oldest entry may wait; the class is then left out of the
+    /// periodic flush.
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+}
+
+/// Catch-up behaviour of the periodic flush, see `flush_timer`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MissedTicks {
+    /// Flush once, then continue on the original schedule.
+    Skip,
+    /// Flush once, then wait a full interval from now.
+    Delay,
+    /// Flush once per missed tick, back to back.
+    Burst,
+}
+
+impl FromStr for MissedTicks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(MissedTicks::Skip),
+            "delay" => Ok(MissedTicks::Delay),
+            "burst" => Ok(MissedTicks::Burst),
+            other => Err(format!("expected skip, delay or burst, got {}", other)),
+        }
+    }
+}
+
+impl Default for FlushConfig {
+    fn default() -> Self {
+        Self { max_rows: FLUSH_MAX_ROWS, max_bytes: FLUSH_MAX_BYTES, max_age_secs: 0, interval_secs: 30, missed_ticks: MissedTicks::Skip, classes: Vec::new() }
+    }
+}
+
+/// Dropping repeated transmissions, see `dedup`.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupConfig {
+    /// Width of the timestamp buckets readings are compared in, `0` (the
+    /// default) to store every reading.
+    pub window_secs: u64,
+}
+
+/// Per-sensor ingest limit, see `rate_limit`.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Messages a sensor may send per minute, `0` for no limit.
+    pub messages_per_minute: u32,
+    /// Sensors never limited, as `model::sensor_id` or mapping name.
+    pub exempt: Vec<String>,
+}
+
+/// Thresholds of the readiness probe, see `health`.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Seconds the DB worker may take to answer a ping.
+    pub db_timeout_secs: u64,
+    /// Buffered rows not yet written above which the exporter is not
+    /// ready, `0` for no limit.
+    pub max_backlog_rows: usize,
+    /// Same for the approximate buffered bytes.
+    pub max_backlog_bytes: usize,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self { db_timeout_secs: 5, max_backlog_rows: 50_000, max_backlog_bytes: 64 * 1024 * 1024 }
+    }
+}
+
+/// Timeouts of the shutdown phases, see `shutdown`.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Seconds the MQTT loop gets to finish the message at hand and
+    /// disconnect.
+    pub ingest_timeout_secs: u64,
+    /// Seconds the final flush of the ingest buffer may take.
+    pub flush_timeout_secs: u64,
+    /// Seconds writing the buffer to `spool_dir` may take when the final
+    /// flush failed.
+    pub spool_timeout_secs: u64,
+    /// Seconds saving the persisted counters may take, see `counters`.
+    pub counters_timeout_secs: u64,
+    /// Seconds writing the radio statistics of the current hour may take.
+    pub radio_timeout_secs: u64,
+    /// Directory the buffer is written to when the final flush failed;
+    /// restored into the database at the next start.
+    pub spool_dir: String,
+    /// File the gauge cache is kept in across graceful restarts, see
+    /// `warm`; empty disables.
+    pub state_file: String,
+    /// Seconds writing `state_file` may take.
+    pub state_timeout_secs: u64,
+    pub checkpoint_timeout_secs: u64,
+    /// Seconds the DB worker gets to finish queued commands and close the
+    /// database.
+    pub close_timeout_secs: u64,
+    /// Seconds open HTTP connections get to finish their requests.
+    pub http_timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            ingest_timeout_secs: 5,
+            flush_timeout_secs: 30,
+            spool_timeout_secs: 10,
+            counters_timeout_secs: 10,
+            radio_timeout_secs: 10,
+            spool_dir: "spool".to_string(),
+            state_file: "warm-state.json".to_string(),
+            state_timeout_secs: 10,
+            checkpoint_timeout_secs: 30,
+            close_timeout_secs: 10,
+            http_timeout_secs: 10,
+        }
+    }
+}
+
+/// Periodic Parquet backups; only active when object storage is configured.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    pub interval_hours: u64,
+    /// Local staging directory for snapshots before upload.
+    pub dir: String,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self { interval_hours: 24, dir: "backups".to_string() }
+    }
+}
+
+/// How long rows are kept in DuckDB, see `retention`. Disabled (keep
+/// forever) while `days` is unset.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub days: Option<u64>,
+    /// Hours between prune runs; the first runs at startup.
+    pub interval_hours: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self { days: None, interval_hours: 24 }
+    }
+}
+
+/// 5-minute / hourly rollup maintenance, see `rollup`.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RollupConfig {
+    /// Seconds between rollup runs; `0` disables them.
+    pub interval_secs: u64,
+}
+
+impl Default for RollupConfig {
+    fn default() -> Self {
+        Self { interval_secs: 300 }
+    }
+}
+
+/// Parquet archive exports, see `archive`.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveConfig {
+    /// Directory the exported Parquet files are written to.
+    pub dir: String,
+    /// Export completed days every this many hours; unset disables the
+    /// scheduled export (`POST /api/export/parquet` still works).
+    pub interval_hours: Option<u64>,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self { dir: "archive".to_string(), interval_hours: None }
+    }
+}
+
+/// Background query jobs, see `jobs`.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// Directory the result files are written to.
+    pub dir: String,
+    /// Finished jobs and their files are removed after this many hours.
+    pub keep_hours: u64,
+    /// Jobs that may wait for the DB worker at once; more are rejected.
+    pub max_pending: usize,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self { dir: "exports".to_string(), keep_hours: 24, max_pending: 4 }
+    }
+}
+
+/// Aggregator mode, see `aggregator`: replicate rows from other exporters'
+/// `/api/sync`. Disabled while `sources` is empty.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregatorConfig {
+    pub sources: Vec<SyncSource>,
+    /// Seconds between pulls once a source is caught up.
+    pub interval_secs: u64,
+    /// Rows requested per pull.
+    pub batch_size: usize,
+}
+
+impl Default for AggregatorConfig {
+    fn default() -> Self {
+        Self { sources: Vec::new(), interval_secs: 30, batch_size: 5000 }
+    }
+}
+
+/// One edge exporter to replicate from.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncSource {
+    /// Stored in the `site` column and exported as the `site` label.
+    pub site: String,
+    /// Base URL of the edge exporter, e.g. `http://edge-north:3000`.
+    pub url: String,
+    /// Sent as `Authorization: Bearer` when the edge sits behind an
+    /// authenticating proxy.
+    pub bearer_token: Option<String>,
+}
+
+/// A synthetic sensor computed from other sensors' latest values.
+///
+/// ```toml
+/// [[composites]]
+/// name = "greenhouse_delta"
+/// expression = "greenhouse.temperature_C - outdoor.temperature_C"
+/// measurement = "temperature_C"
+/// ```
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompositeConfig {
+    /// Stored and exported as the `sensor_id` (model `composite`).
+    pub name: String,
+    /// Arithmetic over `<sensor>.<measurement>` operands, see `composite`.
+    pub expression: String,
+    /// Measurement key the result is stored and exported as.
+    pub measurement: String,
+    /// Operands older than this are considered missing and the series is
+    /// not updated.
+    #[serde(default = "default_composite_max_age")]
+    pub max_age_secs: u64,
+}
+
+fn default_composite_max_age() -> u64 {
+    900
+}
+
+/// A derived on/off state with hysteresis, evaluated per sensor. Set
+/// either `on_below` and `off_above` or `on_above` and `off_below`.
+///
+/// ```toml
+/// [[states]]
+/// name = "heating_needed"
+/// measurement = "temperature_C"
+/// sensors = ["living_room", "bedroom"]
+/// on_below = 18.0
+/// off_above = 19.0
+/// ```
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StateConfig {
+    pub name: String,
+    pub measurement: String,
+    /// Mapping names or `model::sensor_id`; every sensor reporting
+    /// `measurement` when empty.
+    #[serde(default)]
+    pub sensors: Vec<String>,
+    pub on_below: Option<f64>,
+    pub off_above: Option<f64>,
+    pub on_above: Option<f64>,
+    pub off_below: Option<f64>,
+}
+
+/// Store only every `every`th message per sensor on `topic`, see
+/// `sampling`.
+///
+/// ```toml
+/// [[sampling]]
+/// topic = "rtl_433/power/#"
+/// every = 10
+/// ```
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SamplingRule {
+    /// MQTT topic filter.
+    pub topic: String,
+    pub every: u64,
+}
+
+/// Payload values of a measurement that are not readings, see
+/// `sentinels`.
+///
+/// ```toml
+/// [sentinels."Acurite-Tower"]
+/// humidity = { values = [0, 255], max = 100 }
+/// ```
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SentinelRule {
+    /// Values that are dropped.
+    pub values: Vec<f64>,
+    /// Values below are dropped, or clamped with `clamp`.
+    pub min: Option<f64>,
+    /// Values above are dropped, or clamped with `clamp`.
+    pub max: Option<f64>,
+    /// Move out-of-range values to the nearest bound instead of dropping
+    /// them.
+    pub clamp: bool,
+}
+
+/// Payload keys that are probes of a measurement, see `probes`.
+///
+/// ```toml
+/// [[probes]]
+/// pattern = "soil_moisture_(\\d+)"
+/// measurement = "moisture"
+/// ```
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProbeRule {
+    /// Regular expression matching whole keys; the first capture group is
+    /// the probe.
+    pub pattern: String,
+    /// Measurement key the probes are readings of.
+    pub measurement: String,
+}
+
+/// Weather-station series derived from configured measurement keys, see
+/// `weather`. Each part is off while its key is unset.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WeatherConfig {
+    /// Measurement key of a cumulative rain gauge counter, e.g. `rain_mm`.
+    pub rain: Option<String>,
+    /// Measurement key of the wind gust speed, e.g. `wind_max_m_s`.
+    pub gust: Option<String>,
+    /// Window of the gust maximum series.
+    pub gust_window_secs: u64,
+}
+
+impl Default for WeatherConfig {
+    fn default() -> Self {
+        Self { rain: None, gust: None, gust_window_secs: 600 }
+    }
+}
+
+/// Heating / cooling degree days of outdoor sensors, see `degree_days`.
+/// Off while `sensors` is empty.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DegreeDayConfig {
+    /// Mapping names or `model::sensor_id` of outdoor sensors.
+    pub sensors: Vec<String>,
+    /// Measurement key of the outdoor temperature.
+    pub measurement: String,
+    /// Daily means below this count as heating degree days.
+    pub heating_base: f64,
+    /// Daily means above this count as cooling degree days.
+    pub cooling_base: f64,
+    /// Seconds between recomputations of the current day.
+    pub interval_secs: u64,
+}
+
+impl Default for DegreeDayConfig {
+    fn default() -> Self {
+        Self {
+            sensors: Vec::new(),
+            measurement: "temperature_C".to_string(),
+            heating_base: 18.0,
+            cooling_base: 18.0,
+            interval_secs: 3600,
+        }
+    }
+}
+
+/// Where mappings are kept and validation of the mapping API.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MappingConfig {
+    /// JSON file the mappings are loaded from and saved to.
+    pub file: String,
+    /// Manufacturers (rtl_433 models) mappings may be created for besides
+    /// those heard since startup or already mapped.
+    pub manufacturers: Vec<String>,
+}
+
+impl Default for MappingConfig {
+    fn default() -> Self {
+        Self { file: "mappings.json".to_string(), manufacturers: Vec::new() }
+    }
+}
+
+/// Extra sensor labels from static rules and an HTTP inventory, see
+/// `enrichment`. Off while `labels` is empty.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnrichmentConfig {
+    /// Label names added to the per-sensor gauges, e.g. `asset_id`.
+    pub labels: Vec<String>,
+    /// Inventory lookup with `{model}` and `{sensor_id}` placeholders.
+    pub url: Option<String>,
+    /// Sent as `Authorization: Bearer`; prefer `ENRICHMENT_TOKEN(_FILE)`.
+    pub bearer_token: Option<String>,
+    /// How long an inventory answer is used before it is looked up again.
+    pub ttl_secs: u64,
+    pub timeout_secs: u64,
+    pub rules: Vec<EnrichmentRule>,
+}
+
+impl Default for EnrichmentConfig {
+    fn default() -> Self {
+        Self { labels: Vec::new(), url: None, bearer_token: None, ttl_secs: 3600, timeout_secs: 5, rules: Vec::new() }
+    }
+}
+
+/// Static labels for the sensors whose model and id match the regexes
+/// (both optional).
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnrichmentRule {
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub sensor_id: Option<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Scheduled summary reports, see `report`. Sent only when `webhook_url`
+/// or `smtp.host` is set.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportConfig {
+    /// Day of the week a report is sent on (`mon`, `tuesday`, ...).
+    pub weekday: Weekday,
+    /// Local hour of that day.
+    pub hour: u32,
+    /// Days covered by a report, ending when it is sent.
+    pub days: u64,
+    pub format: ReportFormat,
+    /// Measurement key whose minimum and maximum are reported.
+    pub measurement: String,
+    /// Silences longer than this count as data gaps.
+    pub gap_minutes: u64,
+    /// Receives `{"subject", "text", "format"}` as a JSON POST.
+    pub webhook_url: Option<String>,
+    pub smtp: SmtpConfig,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    Markdown,
+    Html,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "markdown" => Ok(ReportFormat::Markdown),
+            "html" => Ok(ReportFormat::Html),
+            other => Err(format!("expected markdown or html, got {}", other)),
+        }
+    }
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        Self {
+            weekday: Weekday::Mon,
+            hour: 8,
+            days: 7,
+            format: ReportFormat::Markdown,
+            measurement: "temperature_C".to_string(),
+            gap_minutes: 60,
+            webhook_url: None,
+            smtp: SmtpConfig::default(),
+        }
+    }
+}
+
+/// Mail delivery of reports. The password is best passed as
+/// `SMTP_PASSWORD(_FILE)`.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub host: Option<String>,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plain connection upgraded with STARTTLS (port 587).
+    Starttls,
+    /// TLS from the start (port 465).
+    Tls,
+    /// Unencrypted, for a relay on the local host or network.
+    None,
+}
+
+impl FromStr for SmtpSecurity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "starttls" => Ok(SmtpSecurity::Starttls),
+            "tls" => Ok(SmtpSecurity::Tls),
+            "none" => Ok(SmtpSecurity::None),
+            other => Err(format!("expected starttls, tls or none, got {}", other)),
+        }
+    }
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            host: None,
+            port: 587,
+            security: SmtpSecurity::Starttls,
+            username: None,
+            password: None,
+            from: "exporter@localhost".to_string(),
+            to: Vec::new(),
+        }
+    }
+}
+
+/// S3-compatible object storage for archive and backup uploads. Disabled
+/// unless `bucket` is set. Credentials are deliberately not part of the
+/// file; they come from the environment or secret files, see `upload`.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    pub bucket: Option<String>,
+    /// Endpoint URL for MinIO and other self-hosted stores.
+    pub endpoint: Option<String>,
+    pub region: String,
+    /// Key prefix prepended to every uploaded object.
+    pub prefix: String,
+    /// Remote copies kept per upload kind.
+    pub keep_copies: usize,
+    pub path_style: bool,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            bucket: None,
+            endpoint: None,
+            region: "us-east-1".to_string(),
+            prefix: String::new(),
+            keep_copies: 7,
+            path_style: true,
+        }
+    }
+}
+
+/// Storage of the original MQTT payloads next to the normalized rows.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RawConfig {
+    /// Keep every data payload in the `raw_messages` table.
+    pub store: bool,
+    /// File holding a 256-bit AES-GCM key (32 raw bytes or base64). When set,
+    /// stored payloads are encrypted; see `crypto`.
+    pub key_file: Option<String>,
+}
+
+/// Write-ahead log of the MQTT data messages, see `wal`.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WalConfig {
+    /// Directory of the log segments; unset disables the log.
+    pub dir: Option<String>,
+    /// Sync every appended message to disk instead of leaving it to the
+    /// page cache, which only a process crash survives.
+    pub sync: bool,
+}
+
+/// Radio statistics from the rtl_433 metadata, see `radio`.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RadioConfig {
+    /// Collect `freq`, `mod`, `rssi`, ... of the messages that carry them.
+    pub enabled: bool,
+    /// Seconds between writes of the hourly aggregates to `radio_stats`.
+    pub write_interval_secs: u64,
+}
+
+impl Default for RadioConfig {
+    fn default() -> Self {
+        Self { enabled: true, write_interval_secs: 60 }
+    }
+}
+
+/// Presentation of the per-sensor gauges on `/metrics`.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Decimal places per measurement key (e.g. `temperature_C = 1`). Only
+    /// the exported gauges are rounded, DuckDB keeps full precision; noisy
+    /// low-order digits otherwise defeat Prometheus' chunk compression.
+    pub rounding: HashMap<String, u32>,
+    /// Sensors silent for longer than this are reported by `sensor_stale`
+    /// and `GET /api/stale`.
+    pub stale_after_secs: u64,
+    /// Keep the ingest counters across restarts, see `counters`.
+    pub persist_counters: bool,
+    /// How often the persisted counters are saved.
+    pub counter_snapshot_secs: u64,
+    /// Seconds a gauge keeps the last reading of its series without a new
+    /// one; the series is removed after that. `0` keeps series until the
+    /// sensor is deleted.
+    pub max_age_secs: u64,
+    /// `max_age_secs` per measurement key (e.g. `battery_ok = 86400`).
+    pub max_age: HashMap<String, u64>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            rounding: HashMap::new(),
+            stale_after_secs: 3600,
+            persist_counters: true,
+            counter_snapshot_secs: 60,
+            max_age_secs: 0,
+            max_age: HashMap::new(),
+        }
+    }
+}
+
+/// Outputs of the ingest pipeline, see `sink`. remote_write and InfluxDB
+/// are enabled by their own `url`.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SinksConfig {
+    /// Store readings in DuckDB. Raw and rejected payloads are stored
+    /// regardless.
+    pub duckdb: bool,
+    /// Export the per-sensor gauges on `/metrics`.
+    pub gauges: bool,
+    /// Write readings as JSON lines to this file, `-` for stdout.
+    pub jsonl: Option<String>,
+}
+
+impl Default for SinksConfig {
+    fn default() -> Self {
+        Self { duckdb: true, gauges: true, jsonl: None }
+    }
+}
+
+/// Push readings to a Prometheus remote_write receiver, see
+/// `remote_write`. Disabled unless `url` is set.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteWriteConfig {
+    pub url: Option<String>,
+    /// Sent as `Authorization: Bearer`; prefer `REMOTE_WRITE_TOKEN(_FILE)`.
+    pub bearer_token: Option<String>,
+    /// Extra request headers, e.g. `X-Scope-OrgID` for Mimir tenants.
+    pub headers: HashMap<String, String>,
+    /// Samples per request, at least 1.
+    pub batch_size: usize,
+    /// Seconds between sends when no full batch is waiting.
+    pub interval_secs: u64,
+    /// Samples kept while the receiver is unreachable; the oldest are
+    /// dropped beyond this.
+    pub max_queue: usize,
+    pub timeout_secs: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl Default for RemoteWriteConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            bearer_token: None,
+            headers: HashMap::new(),
+            batch_size: 500,
+            interval_secs: 5,
+            max_queue: 100_000,
+            timeout_secs: 10,
+            max_backoff_secs: 60,
+        }
+    }
+}
+
+/// Write readings to InfluxDB 2.x in line protocol, see `influx`.
+/// Disabled unless `url` is set.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfluxConfig {
+    /// Base URL of the server, e.g. `http://influxdb:8086`.
+    pub url: Option<String>,
+    pub org: String,
+    pub bucket: String,
+    /// Sent as `Authorization: Token`; prefer `INFLUX_TOKEN(_FILE)`.
+    pub token: Option<String>,
+    /// Measurement name of the points.
+    pub measurement: String,
+    /// Points per request, at least 1.
+    pub batch_size: usize,
+    /// Seconds between sends when no full batch is waiting.
+    pub interval_secs: u64,
+    /// Points kept while the server is unreachable; the oldest are dropped
+    /// beyond this.
+    pub max_queue: usize,
+    pub timeout_secs: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            org: String::new(),
+            bucket: String::new(),
+            token: None,
+            measurement: "rtl_433".to_string(),
+            batch_size: 5000,
+            interval_secs: 5,
+            max_queue: 100_000,
+            timeout_secs: 10,
+            max_backoff_secs: 60,
+        }
+    }
+}
+
+/// OTLP export of the metrics and of ingest traces, see `otel`. Needs the
+/// `otel` feature; disabled unless `endpoint` is set.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtelConfig {
+    /// Collector URL, e.g. `http://otel-collector:4317` for gRPC or
+    /// `http://otel-collector:4318` for HTTP (`/v1/metrics` and
+    /// `/v1/traces` are appended).
+    pub endpoint: Option<String>,
+    pub protocol: OtelProtocol,
+    /// Extra request headers (gRPC metadata), e.g. for authentication.
+    pub headers: HashMap<String, String>,
+    /// `service.name` of the exported resource.
+    pub service_name: String,
+    /// Seconds between metric exports.
+    pub interval_secs: u64,
+    /// Export ingest traces next to the metrics.
+    pub traces: bool,
+    pub timeout_secs: u64,
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OtelProtocol {
+    /// OTLP/gRPC, usually port 4317.
+    Grpc,
+    /// OTLP/HTTP with protobuf bodies, usually port 4318.
+    Http,
+}
+
+impl FromStr for OtelProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grpc" => Ok(OtelProtocol::Grpc),
+            "http" | "http/protobuf" => Ok(OtelProtocol::Http),
+            other => Err(format!("expected grpc or http, got {}", other)),
+        }
+    }
+}
+
+impl Default for OtelConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            protocol: OtelProtocol::Grpc,
+            headers: HashMap::new(),
+            service_name: "rust-to-mqtt-prometheus-exporter".to_string(),
+            interval_secs: 60,
+            traces: true,
+            timeout_secs: 10,
+        }
+    }
+}
+
+/// Log output and rate limiting of high-frequency log lines (per-message
+/// ingest logs, rejected payloads), see `logging`.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `RUST_LOG`-style filter, e.g. `info,rust_to_mqtt_prometheus_exporter::mqtt=debug`.
+    /// The `RUST_LOG` environment variable takes precedence.
+    pub level: String,
+    pub format: LogFormat,
+    /// Lines logged per kind of event and window; `0` logs everything.
+    pub burst: u64,
+    pub window_secs: u64,
+    /// Log to stderr instead of stdout; set when the JSONL sink writes to
+    /// stdout.
+    #[serde(skip)]
+    pub stderr: bool,
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per event, for log shippers.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("expected text or json, got {}", other)),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self { level: "info".to_string(), format: LogFormat::Text, burst: 10, window_secs: 60, stderr: false }
+    }
+}
+
+impl Config {
+    /// Load defaults, the config file (if any) and environment overrides.
+    pub fn load() -> anyhow::Result<Self> {
+        let path = CONFIG_PATH.get().cloned().or_else(|| std::env::var_os("EXPORTER_CONFIG").map(PathBuf::from));
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        config.logging.stderr = config.sinks.jsonl.as_deref() == Some("-");
+        config.reload = ReloadSource::Load;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("cannot read config file {}: {}", path.display(), e))?;
+        let config: Self = toml::from_str(&raw)
+            .map_err(|e| anyhow::anyhow!("invalid config file {}: {}", path.display(), e))?;
+        Ok(Self { source: Some(path.display().to_string()), ..config })
+    }
+
+    /// Apply environment variable overrides on top of the file values.
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        override_value(&mut self.http.bind, "HTTP_BIND")?;
+        override_option(&mut self.http.tls_cert_file, "HTTP_TLS_CERT_FILE");
+        override_option(&mut self.http.tls_key_file, "HTTP_TLS_KEY_FILE");
+        override_option(&mut self.http.redirect_bind, "HTTP_REDIRECT_BIND");
+        override_flag(&mut self.http.http2, "HTTP_HTTP2")?;
+        override_flag(&mut self.http.keep_alive, "HTTP_KEEP_ALIVE")?;
+        override_value(&mut self.http.keep_alive_interval_secs, "HTTP_KEEP_ALIVE_INTERVAL_SECS")?;
+        override_value(&mut self.http.keep_alive_timeout_secs, "HTTP_KEEP_ALIVE_TIMEOUT_SECS")?;
+        override_value(&mut self.http.max_concurrent_streams, "HTTP_MAX_CONCURRENT_STREAMS")?;
+        override_value(&mut self.http.max_connections, "HTTP_MAX_CONNECTIONS")?;
+        // Comma-separated; usually from secret files.
+        if let Some(tokens) = env_or_file("HTTP_READ_TOKENS")? {
+            self.http.auth.read_tokens = tokens.split(',').map(str::trim).filter(|t| !t.is_empty()).map(String::from).collect();
+        }
+        if let Some(tokens) = env_or_file("HTTP_WRITE_TOKENS")? {
+            self.http.auth.write_tokens = tokens.split(',').map(str::trim).filter(|t| !t.is_empty()).map(String::from).collect();
+        }
+        override_flag(&mut self.http.auth.public_metrics, "HTTP_PUBLIC_METRICS")?;
+        if let Some(token) = env_or_file("ADMIN_TOKEN")? {
+            self.http.admin_token = Some(token);
+        }
+
+        override_value(&mut self.mqtt.host, "MQTT_HOST")?;
+        override_value(&mut self.mqtt.port, "MQTT_PORT")?;
+        override_value(&mut self.mqtt.client_id, "MQTT_CLIENT_ID")?;
+        override_value(&mut self.mqtt.client_id_suffix, "MQTT_CLIENT_ID_SUFFIX")?;
+        override_flag(&mut self.mqtt.clean_session, "MQTT_CLEAN_SESSION")?;
+        override_flag(&mut self.mqtt.strict_start, "MQTT_STRICT_START")?;
+        override_option(&mut self.mqtt.username, "MQTT_USER");
+        override_option(&mut self.mqtt.password, "MQTT_PASS");
+        if let Ok(v) = std::env::var("MQTT_TOPIC") {
+            self.mqtt.topics = v.split(',').map(str::trim).filter(|t| !t.is_empty()).map(String::from).collect();
+        }
+        override_option(&mut self.mqtt.control_topic, "MQTT_CONTROL_TOPIC");
+        override_value(&mut self.mqtt.keep_alive_secs, "MQTT_KEEP_ALIVE_SECS")?;
+        override_value(&mut self.mqtt.reconnect_initial_secs, "MQTT_RECONNECT_INITIAL_SECS")?;
+        override_value(&mut self.mqtt.reconnect_max_secs, "MQTT_RECONNECT_MAX_SECS")?;
+        if let Ok(v) = std::env::var("MQTT_FORMATS") {
+            // `zigbee2mqtt/#=zigbee2mqtt`
+            self.mqtt.formats = v
+                .split(',')
+                .map(str::trim)
+                .filter(|e| !e.is_empty())
+                .map(|entry| {
+                    let (filter, format) = entry
+                        .split_once('=')
+                        .ok_or_else(|| anyhow::anyhow!("MQTT_FORMATS entries must be filter=format, got {}", entry))?;
+                    let format = format.trim().parse().map_err(|e| anyhow::anyhow!("invalid MQTT_FORMATS entry {}: {}", entry, e))?;
+                    Ok((filter.trim().to_string(), format))
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        if let Ok(v) = std::env::var("MODEL_DECODERS") {
+            // `LaCrosse-TX141THBv2=channel_id,LaCrosse-TX141THBv2=new_battery`
+            self.models.clear();
+            for entry in v.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                let (model, decoder) = entry
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("MODEL_DECODERS entries must be model=decoder, got {}", entry))?;
+                self.models.entry(model.trim().to_string()).or_default().push(decoder.trim().to_string());
+            }
+        }
+        override_flag(&mut self.mqtt.tls, "MQTT_TLS")?;
+        override_option(&mut self.mqtt.ca_file, "MQTT_CA_FILE");
+        override_option(&mut self.mqtt.client_cert_file, "MQTT_CLIENT_CERT_FILE");
+        override_option(&mut self.mqtt.client_key_file, "MQTT_CLIENT_KEY_FILE");
+
+        override_value(&mut self.duckdb.path, "DUCKDB_PATH")?;
+        if let Ok(v) = std::env::var("DUCKDB_EXTENSIONS") {
+            self.duckdb.extensions = v.split(',').map(str::trim).filter(|e| !e.is_empty()).map(String::from).collect();
+        }
+        override_option(&mut self.duckdb.extension_directory, "DUCKDB_EXTENSION_DIR");
+        override_flag(&mut self.duckdb.offline, "DUCKDB_EXTENSIONS_OFFLINE")?;
+        override_value(&mut self.duckdb.lock_retry_secs, "DUCKDB_LOCK_RETRY_SECS")?;
+        override_value(&mut self.duckdb.queue_capacity, "DUCKDB_QUEUE_CAPACITY")?;
+        override_value(&mut self.duckdb.queue_full, "DUCKDB_QUEUE_FULL")?;
+        override_flag(&mut self.duckdb.latest_table, "DUCKDB_LATEST_TABLE")?;
+
+        override_value(&mut self.flush.max_rows, "FLUSH_THRESHOLD")?;
+        override_value(&mut self.flush.max_rows, "FLUSH_MAX_ROWS")?;
+        override_value(&mut self.flush.max_bytes, "FLUSH_MAX_BYTES")?;
+        override_value(&mut self.flush.max_age_secs, "FLUSH_MAX_AGE_SECS")?;
+        override_value(&mut self.flush.interval_secs, "FLUSH_INTERVAL_SECS")?;
+        override_value(&mut self.flush.missed_ticks, "FLUSH_MISSED_TICKS")?;
+        override_value(&mut self.dedup.window_secs, "DEDUP_WINDOW_SECS")?;
+        override_value(&mut self.rate_limit.messages_per_minute, "RATE_LIMIT_PER_MINUTE")?;
+        if let Ok(v) = std::env::var("RATE_LIMIT_EXEMPT") {
+            self.rate_limit.exempt = v.split(',').map(str::trim).filter(|s| !s.is_empty()).map(String::from).collect();
+        }
+        override_value(&mut self.health.db_timeout_secs, "HEALTH_DB_TIMEOUT_SECS")?;
+        override_value(&mut self.health.max_backlog_rows, "HEALTH_MAX_BACKLOG_ROWS")?;
+        override_value(&mut self.health.max_backlog_bytes, "HEALTH_MAX_BACKLOG_BYTES")?;
+        override_value(&mut self.shutdown.ingest_timeout_secs, "SHUTDOWN_INGEST_TIMEOUT_SECS")?;
+        override_value(&mut self.shutdown.flush_timeout_secs, "SHUTDOWN_FLUSH_TIMEOUT_SECS")?;
+        override_value(&mut self.shutdown.spool_timeout_secs, "SHUTDOWN_SPOOL_TIMEOUT_SECS")?;
+        override_value(&mut self.shutdown.spool_dir, "SHUTDOWN_SPOOL_DIR")?;
+        override_value(&mut self.shutdown.counters_timeout_secs, "SHUTDOWN_COUNTERS_TIMEOUT_SECS")?;
+        override_value(&mut self.shutdown.radio_timeout_secs, "SHUTDOWN_RADIO_TIMEOUT_SECS")?;
+        override_value(&mut self.shutdown.state_file, "SHUTDOWN_STATE_FILE")?;
+        override_value(&mut self.shutdown.state_timeout_secs, "SHUTDOWN_STATE_TIMEOUT_SECS")?;
+        override_value(&mut self.shutdown.checkpoint_timeout_secs, "SHUTDOWN_CHECKPOINT_TIMEOUT_SECS")?;
+        override_value(&mut self.shutdown.close_timeout_secs, "SHUTDOWN_CLOSE_TIMEOUT_SECS")?;
+        override_value(&mut self.shutdown.http_timeout_secs, "SHUTDOWN_HTTP_TIMEOUT_SECS")?;
+        override_value(&mut self.backup.interval_hours, "BACKUP_INTERVAL_HOURS")?;
+        override_value(&mut self.backup.dir, "BACKUP_DIR")?;
+
+        if let Ok(v) = std::env::var("RETENTION_DAYS") {
+            let days: u64 = v.trim().parse().map_err(|e| anyhow::anyhow!("invalid RETENTION_DAYS value {:?}: {}", v, e))?;
+            // `0` switches retention off again.
+            self.retention.days = (days > 0).then_some(days);
+        }
+        override_value(&mut self.retention.interval_hours, "RETENTION_INTERVAL_HOURS")?;
+        override_value(&mut self.rollup.interval_secs, "ROLLUP_INTERVAL_SECS")?;
+        override_option(&mut self.weather.rain, "WEATHER_RAIN");
+        override_option(&mut self.weather.gust, "WEATHER_GUST");
+        override_value(&mut self.weather.gust_window_secs, "WEATHER_GUST_WINDOW_SECS")?;
+        if let Ok(v) = std::env::var("DEGREE_DAY_SENSORS") {
+            self.degree_days.sensors = v.split(',').map(str::trim).filter(|s| !s.is_empty()).map(String::from).collect();
+        }
+        override_value(&mut self.degree_days.heating_base, "DEGREE_DAY_HEATING_BASE")?;
+        override_value(&mut self.degree_days.cooling_base, "DEGREE_DAY_COOLING_BASE")?;
+        override_value(&mut self.degree_days.interval_secs, "DEGREE_DAY_INTERVAL_SECS")?;
+        if let Ok(v) = std::env::var("MAPPING_MANUFACTURERS") {
+            self.mapping.manufacturers = v.split(',').map(str::trim).filter(|m| !m.is_empty()).map(String::from).collect();
+        }
+
+        if let Ok(v) = std::env::var("AGGREGATOR_SOURCES") {
+            // `north=http://edge-north:3000,south=http://edge-south:3000`
+            self.aggregator.sources = v
+                .split(',')
+                .map(str::trim)
+                .filter(|e| !e.is_empty())
+                .map(|entry| {
+                    let (site, url) = entry
+                        .split_once('=')
+                        .ok_or_else(|| anyhow::anyhow!("AGGREGATOR_SOURCES entries must be site=url, got {}", entry))?;
+                    Ok(SyncSource { site: site.trim().to_string(), url: url.trim().to_string(), bearer_token: None })
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        override_value(&mut self.aggregator.interval_secs, "AGGREGATOR_INTERVAL_SECS")?;
+
+        override_value(&mut self.mapping.file, "MAPPINGS_FILE")?;
+        override_value(&mut self.jobs.dir, "JOBS_DIR")?;
+        override_value(&mut self.jobs.keep_hours, "JOBS_KEEP_HOURS")?;
+        override_value(&mut self.jobs.max_pending, "JOBS_MAX_PENDING")?;
+        override_value(&mut self.archive.dir, "ARCHIVE_DIR")?;
+        if let Ok(v) = std::env::var("ARCHIVE_INTERVAL_HOURS") {
+            let hours: u64 = v.trim().parse().map_err(|e| anyhow::anyhow!("invalid ARCHIVE_INTERVAL_HOURS value {:?}: {}", v, e))?;
+            self.archive.interval_hours = (hours > 0).then_some(hours);
+        }
+
+        override_option(&mut self.s3.bucket, "S3_BUCKET");
+        override_option(&mut self.s3.endpoint, "S3_ENDPOINT");
+        override_value(&mut self.s3.region, "S3_REGION")?;
+        override_value(&mut self.s3.prefix, "S3_PREFIX")?;
+        override_value(&mut self.s3.keep_copies, "S3_KEEP_COPIES")?;
+        override_flag(&mut self.s3.path_style, "S3_PATH_STYLE")?;
+
+        override_flag(&mut self.raw.store, "RAW_PAYLOADS")?;
+        override_option(&mut self.raw.key_file, "RAW_KEY_FILE");
+        override_option(&mut self.wal.dir, "WAL_DIR");
+        override_flag(&mut self.wal.sync, "WAL_SYNC")?;
+        override_flag(&mut self.radio.enabled, "RADIO_STATS")?;
+        override_value(&mut self.radio.write_interval_secs, "RADIO_WRITE_INTERVAL_SECS")?;
+
+        override_flag(&mut self.sinks.duckdb, "SINK_DUCKDB")?;
+        override_flag(&mut self.sinks.gauges, "SINK_GAUGES")?;
+        override_option(&mut self.sinks.jsonl, "SINK_JSONL");
+
+        override_option(&mut self.remote_write.url, "REMOTE_WRITE_URL");
+        if let Some(token) = env_or_file("REMOTE_WRITE_TOKEN")? {
+            self.remote_write.bearer_token = Some(token);
+        }
+
+        override_option(&mut self.otel.endpoint, "OTEL_EXPORTER_OTLP_ENDPOINT");
+        override_value(&mut self.otel.protocol, "OTEL_EXPORTER_OTLP_PROTOCOL")?;
+        if let Some(v) = env_or_file("OTEL_EXPORTER_OTLP_HEADERS")? {
+            // `authorization=Basic abc,x-tenant=home`, as other OTel SDKs
+            // read it.
+            for entry in v.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                let (name, value) = entry
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("OTEL_EXPORTER_OTLP_HEADERS entries must be name=value, got {}", entry))?;
+                self.otel.headers.insert(name.trim().to_string(), value.trim().to_string());
+            }
+        }
+        override_value(&mut self.otel.service_name, "OTEL_SERVICE_NAME")?;
+        override_value(&mut self.otel.interval_secs, "OTEL_METRIC_EXPORT_INTERVAL_SECS")?;
+        override_flag(&mut self.otel.traces, "OTEL_TRACES")?;
+
+        override_option(&mut self.influx.url, "INFLUX_URL");
+        override_value(&mut self.influx.org, "INFLUX_ORG")?;
+        override_value(&mut self.influx.bucket, "INFLUX_BUCKET")?;
+        if let Some(token) = env_or_file("INFLUX_TOKEN")? {
+            self.influx.token = Some(token);
+        }
+
+        if let Ok(v) = std::env::var("ENRICHMENT_LABELS") {
+            self.enrichment.labels = v.split(',').map(str::trim).filter(|l| !l.is_empty()).map(String::from).collect();
+        }
+        override_option(&mut self.enrichment.url, "ENRICHMENT_URL");
+        if let Some(token) = env_or_file("ENRICHMENT_TOKEN")? {
+            self.enrichment.bearer_token = Some(token);
+        }
+        override_value(&mut self.enrichment.ttl_secs, "ENRICHMENT_TTL_SECS")?;
+
+        override_value(&mut self.report.weekday, "REPORT_WEEKDAY")?;
+        override_value(&mut self.report.hour, "REPORT_HOUR")?;
+        override_value(&mut self.report.days, "REPORT_DAYS")?;
+        override_value(&mut self.report.format, "REPORT_FORMAT")?;
+        override_value(&mut self.report.gap_minutes, "REPORT_GAP_MINUTES")?;
+        override_option(&mut self.report.webhook_url, "REPORT_WEBHOOK_URL");
+        override_option(&mut self.report.smtp.host, "SMTP_HOST");
+        override_value(&mut self.report.smtp.port, "SMTP_PORT")?;
+        override_value(&mut self.report.smtp.security, "SMTP_SECURITY")?;
+        override_option(&mut self.report.smtp.username, "SMTP_USER");
+        if let Some(password) = env_or_file("SMTP_PASSWORD")? {
+            self.report.smtp.password = Some(password);
+        }
+        override_value(&mut self.report.smtp.from, "SMTP_FROM")?;
+        if let Ok(v) = std::env::var("SMTP_TO") {
+            self.report.smtp.to = v.split(',').map(str::trim).filter(|t| !t.is_empty()).map(String::from).collect();
+        }
+
+        override_value(&mut self.logging.level, "RUST_LOG")?;
+        override_value(&mut self.logging.format, "LOG_FORMAT")?;
+        override_value(&mut self.logging.burst, "LOG_BURST")?;
+        override_value(&mut self.logging.window_secs, "LOG_WINDOW_SECS")?;
+
+        override_value(&mut self.metrics.stale_after_secs, "STALE_AFTER_SECS")?;
+        override_flag(&mut self.metrics.persist_counters, "METRICS_PERSIST_COUNTERS")?;
+        override_value(&mut self.metrics.counter_snapshot_secs, "METRICS_COUNTER_SNAPSHOT_SECS")?;
+        if let Ok(v) = std::env::var("METRICS_ROUNDING") {
+            // `temperature_C=1,humidity=0`
+            self.metrics.rounding = v
+                .split(',')
+                .map(str::trim)
+                .filter(|e| !e.is_empty())
+                .map(|entry| {
+                    let (key, places) = entry
+                        .split_once('=')
+                        .ok_or_else(|| anyhow::anyhow!("METRICS_ROUNDING entries must be key=decimals, got {}", entry))?;
+                    let places = places
+                        .trim()
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("invalid METRICS_ROUNDING decimals for {}", key))?;
+                    Ok((key.trim().to_string(), places))
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        override_value(&mut self.metrics.max_age_secs, "METRICS_MAX_AGE_SECS")?;
+        if let Ok(v) = std::env::var("METRICS_MAX_AGE") {
+            // `temperature_C=600,battery_ok=86400`
+            self.metrics.max_age = v
+                .split(',')
+                .map(str::trim)
+                .filter(|e| !e.is_empty())
+                .map(|entry| {
+                    let (key, secs) = entry
+                        .split_once('=')
+                        .ok_or_else(|| anyhow::anyhow!("METRICS_MAX_AGE entries must be key=seconds, got {}", entry))?;
+                    let secs = secs.trim().parse().map_err(|_| anyhow::anyhow!("invalid METRICS_MAX_AGE seconds for {}", key))?;
+                    Ok((key.trim().to_string(), secs))
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        Ok(())
+    }
+}
+
+fn override_value<T>(target: &mut T, name: &str) -> anyhow::Result<()>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if let Ok(raw) = std::env::var(name) {
+        *target = raw.trim().parse().map_err(|e| anyhow::anyhow!("invalid {} value {:?}: {}", name, raw, e))?;
+    }
+    Ok(())
+}
+
+/// Like `override_value` for switches: `1`, `true` or `yes` and `0`,
+/// `false` or `no`; anything else is an error rather than off.
+fn override_flag(target: &mut bool, name: &str) -> anyhow::Result<()> {
+    if let Ok(raw) = std::env::var(name) {
+        *target = parse_flag(&raw).ok_or_else(|| anyhow::anyhow!("invalid {} value {:?}: expected 1, true, yes, 0, false or no", name, raw))?;
+    }
+    Ok(())
+}
+
+fn parse_flag(raw: &str) -> Option<bool> {
+    match raw.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" => Some(true),
+        "0" | "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+fn override_option(target: &mut Option<String>, name: &str) {
+    if let Ok(v) = std::env::var(name) {
+        *target = Some(v);
+    }
+}
+
+/// Read an env var, or the contents of the file named by `<NAME>_FILE`
+/// (e.g. Docker/Kubernetes secrets).
+pub fn env_or_file(name: &str) -> anyhow::Result<Option<String>> {
+    if let Ok(v) = std::env::var(name) {
+        return Ok(Some(v));
+    }
+    match std::env::var(format!("{}_FILE", name)) {
+        Ok(path) => Ok(Some(std::fs::read_to_string(&path)?.trim().to_string())),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_reject_unknown_values() {
+        assert_eq!(parse_flag(" yes "), Some(true));
+        assert_eq!(parse_flag("TRUE"), Some(true));
+        assert_eq!(parse_flag("0"), Some(false));
+        assert_eq!(parse_flag("no"), Some(false));
+        assert_eq!(parse_flag("on"), None);
+        assert_eq!(parse_flag(""), None);
+    }
+
+    #[test]
+    fn local_urls_reach_the_bind_address_over_loopback() {
+        let url = |bind: &str, tls: bool| {
+            let http = HttpConfig { bind: bind.to_string(), tls_cert_file: tls.then(|| "cert.pem".to_string()), ..HttpConfig::default() };
+            http.local_url()
+        };
+        assert_eq!(url("0.0.0.0:3000", false), "http://127.0.0.1:3000");
+        assert_eq!(url("[::]:3000", true), "https://[::1]:3000");
+        assert_eq!(url("[fe80::1]:3000", false), "http://[fe80::1]:3000");
+        assert_eq!(url("192.168.1.10:8443", true), "https://192.168.1.10:8443");
+        assert_eq!(url("localhost:3000", false), "http://localhost:3000");
+    }
+
+    #[test]
+    fn older_configs_name_a_single_topic() {
+        let topics = |raw: &str| toml::from_str::<MqttConfig>(raw).unwrap().topics;
+        assert_eq!(topics("topic = \"rtl_433/events\""), vec!["rtl_433/events"]);
+        assert_eq!(topics("topics = [\"rtl_433/+/events\", \"zigbee/#\"]"), vec!["rtl_433/+/events", "zigbee/#"]);
+        assert!(toml::from_str::<MqttConfig>("topic = \"a\"\ntopics = [\"b\"]").is_err());
+    }
+}
@@ -0,0 +1,137 @@
+// Control-topic handling. When `MQTT_CONTROL_TOPIC` is set the exporter
+// subscribes to it alongside the data topic, and messages arriving there
+// bypass normalization and the ingest buffer entirely: they are parsed into
+// a `ControlCommand` and dispatched straight away so operational actions
+// (flush, checkpoint, reload, rotate) don't queue behind sensor traffic.
+use crate::db::DbHandle;
+use crate::mqtt_buffer::MqttBuffer;
+use crate::state::{reload_mappings, Store};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Commands accepted on the control topic. The payload may be the bare
+/// command name (`flush`) or a JSON object (`{"command": "flush"}`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlCommand {
+    /// Flush the ingest buffer to DuckDB immediately.
+    Flush,
+    /// Checkpoint the DuckDB database, on top of the commands asked for, so
+    /// a file-level backup can be triggered over MQTT as over HTTP
+    /// (`POST /api/admin/flush`).
+    Checkpoint,
+    /// Re-read the mappings file into the in-memory store.
+    Reload,
+    /// Start a new WAL segment, e.g. before copying the log away. The old
+    /// segment is deleted once its messages are written, as after a flush.
+    Rotate,
+}
+
+#[derive(Deserialize)]
+struct ControlMessage {
+    command: ControlCommand,
+}
+
+impl ControlCommand {
+    pub fn parse(payload: &[u8]) -> anyhow::Result<Self> {
+        let text = std::str::from_utf8(payload)?.trim();
+        if text.starts_with('{') {
+            let msg: ControlMessage = serde_json::from_str(text)?;
+            return Ok(msg.command);
+        }
+        match text.to_ascii_lowercase().as_str() {
+            "flush" => Ok(Self::Flush),
+            "checkpoint" => Ok(Self::Checkpoint),
+            "reload" => Ok(Self::Reload),
+            "rotate" => Ok(Self::Rotate),
+            other => Err(anyhow::anyhow!("unknown control command: {:?}", other)),
+        }
+    }
+}
+
+/// Everything a control command may act on.
+#[derive(Clone)]
+pub struct ControlContext {
+    pub buffer: Arc<MqttBuffer>,
+    pub db: DbHandle,
+    pub store: Store,
+    /// See `config::MappingConfig::file`.
+    pub mappings_file: String,
+}
+
+/// Parse and execute a control message, logging the outcome. Errors are
+/// reported but never propagated so a bad control message cannot stop the
+/// MQTT loop.
+pub async fn handle_control_message(payload: &[u8], ctx: &ControlContext) {
+    let command = match ControlCommand::parse(payload) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Ignoring control message: {}", e);
+            return;
+        }
+    };
+    info!("Control command received: {:?}", command);
+    if let Err(e) = execute(command, ctx).await {
+        warn!("Control command {:?} failed: {}", command, e);
+    }
+}
+
+async fn execute(command: ControlCommand, ctx: &ControlContext) -> anyhow::Result<()> {
+    match command {
+        ControlCommand::Flush => {
+            let n = ctx.buffer.flush(&ctx.db).await?;
+            info!("Control flush wrote {} rows", n);
+        }
+        ControlCommand::Checkpoint => ctx.db.flush().await?,
+        ControlCommand::Reload => {
+            let count = reload_mappings(&ctx.store, &ctx.mappings_file).await?;
+            info!("Reloaded {} mappings", count);
+        }
+        ControlCommand::Rotate => {
+            let wal = ctx.buffer.wal().ok_or_else(|| anyhow::anyhow!("no WAL to rotate; set wal.dir"))?;
+            let segment = wal.rotate();
+            info!("Control rotate started WAL segment {}", segment);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WalConfig;
+    use crate::db::{start_db_worker, DbConfig};
+    use crate::mqtt_buffer::FlushPolicy;
+    use crate::wal::Wal;
+    use prometheus::Registry;
+
+    #[test]
+    fn commands_are_names_or_json() {
+        assert_eq!(ControlCommand::parse(b" Rotate\n").unwrap(), ControlCommand::Rotate);
+        assert_eq!(ControlCommand::parse(br#"{"command": "checkpoint"}"#).unwrap(), ControlCommand::Checkpoint);
+        assert!(ControlCommand::parse(b"restart").is_err());
+        assert!(ControlCommand::parse(br#"{"command": "restart"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn rotate_starts_a_new_wal_segment() {
+        let dir = std::env::temp_dir().join(format!("exporter-control-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let registry = Registry::new();
+        let (db, _worker) = start_db_worker(&config, &registry).unwrap();
+        let buffer = Arc::new(MqttBuffer::new(&registry, FlushPolicy::default()).unwrap());
+        let ctx = ControlContext { buffer: buffer.clone(), db, store: Store::default(), mappings_file: String::new() };
+        assert!(execute(ControlCommand::Rotate, &ctx).await.is_err());
+
+        let wal = WalConfig { dir: Some(dir.join("wal").to_string_lossy().into_owned()), sync: false };
+        buffer.set_wal(Arc::new(Wal::open(&wal).unwrap().unwrap()));
+        let wal = buffer.wal().unwrap();
+        assert_eq!(wal.append("rtl_433/events", b"{}"), 0);
+        execute(ControlCommand::Rotate, &ctx).await.unwrap();
+        assert_eq!(wal.append("rtl_433/events", b"{}"), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+// Counters that survive restarts. Prometheus handles a counter reset fine
+// for `rate()`, but totals such as `mqtt_messages_total` lose their history
+// with every restart or upgrade. The ingest counters are saved to the
+// `counter_snapshots` table periodically and at shutdown, and added back
+// at startup before ingest begins:
+//
+//   [metrics]
+//   persist_counters = true
+//   counter_snapshot_secs = 60
+//
+// Only values saved by a graceful shutdown are restored. After a crash the
+// last periodic snapshot lags behind the value last scraped, and a counter
+// restored to it would go down a little, which Prometheus reads as a reset
+// followed by an increase of the whole restored value: a spike in every
+// `rate()`. The counters start from zero instead, an ordinary reset, and
+// the totals lose their history as they would without persistence.
+// `process_start_time_seconds` is exported either way so dashboards can
+// mark restarts. New counters are persisted by passing them to `track` /
+// `track_vec` before `restore`.
+use crate::config::MetricsConfig;
+use crate::db::{sql_literal, DbHandle};
+use crate::sink::unix_now;
+use prometheus::core::Collector;
+use prometheus::{Gauge, IntCounter, IntCounterVec, Registry};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+enum Tracked {
+    Counter(IntCounter),
+    Vec(IntCounterVec),
+}
+
+impl Tracked {
+    fn collector(&self) -> &dyn Collector {
+        match self {
+            Tracked::Counter(c) => c,
+            Tracked::Vec(v) => v,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    name: String,
+    /// JSON object of the label values.
+    labels: String,
+    value: f64,
+    at_shutdown: bool,
+}
+
+pub struct PersistedCounters {
+    enabled: bool,
+    interval: Duration,
+    db: DbHandle,
+    tracked: Vec<(String, Tracked)>,
+}
+
+impl PersistedCounters {
+    pub fn new(config: &MetricsConfig, db: DbHandle, registry: &Registry) -> anyhow::Result<Self> {
+        let start_time = Gauge::new("process_start_time_seconds", "Start time of the process since unix epoch in seconds")?;
+        start_time.set(unix_now().floor());
+        registry.register(Box::new(start_time))?;
+        Ok(Self {
+            enabled: config.persist_counters,
+            interval: Duration::from_secs(config.counter_snapshot_secs.max(1)),
+            db,
+            tracked: Vec::new(),
+        })
+    }
+
+    pub fn track(&mut self, counter: IntCounter) {
+        self.push(Tracked::Counter(counter));
+    }
+
+    pub fn track_vec(&mut self, counter: IntCounterVec) {
+        self.push(Tracked::Vec(counter));
+    }
+
+    fn push(&mut self, tracked: Tracked) {
+        let name = tracked.collector().desc().first().map(|d| d.fq_name.clone()).unwrap_or_default();
+        self.tracked.push((name, tracked));
+    }
+
+    /// Add the saved values to the tracked counters, if a graceful shutdown
+    /// saved them. Snapshots of counters no longer tracked, or with other
+    /// label names, are ignored.
+    pub async fn restore(&self) -> anyhow::Result<usize> {
+        if !self.enabled {
+            return Ok(0);
+        }
+        let snapshots: Vec<Snapshot> = self
+            .db
+            .query_as("SELECT name, labels, value, COALESCE(at_shutdown, true) AS at_shutdown FROM counter_snapshots", Vec::new())
+            .await?;
+        let snapshots: Vec<(&Tracked, Snapshot)> = snapshots
+            .into_iter()
+            .filter_map(|snapshot| {
+                let (_, tracked) = self.tracked.iter().find(|(name, _)| *name == snapshot.name)?;
+                Some((tracked, snapshot))
+            })
+            .collect();
+        if snapshots.iter().any(|(_, snapshot)| !snapshot.at_shutdown) {
+            warn!("The counters were last saved before an unclean shutdown; starting them from zero");
+            return Ok(0);
+        }
+        let mut restored = 0;
+        for (tracked, snapshot) in snapshots {
+            let value = snapshot.value.max(0.0) as u64;
+            match tracked {
+                Tracked::Counter(counter) => counter.inc_by(value),
+                Tracked::Vec(counter) => {
+                    let labels: HashMap<String, String> = serde_json::from_str(&snapshot.labels)?;
+                    let labels: HashMap<&str, &str> = labels.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+                    match counter.get_metric_with(&labels) {
+                        Ok(counter) => counter.inc_by(value),
+                        Err(_) => continue,
+                    }
+                }
+            }
+            restored += 1;
+        }
+        Ok(restored)
+    }
+
+    /// Save the current value of every tracked counter; `at_shutdown` marks
+    /// the final save of a graceful shutdown, the only one `restore` uses.
+    pub async fn save(&self, at_shutdown: bool) -> anyhow::Result<usize> {
+        if !self.enabled {
+            return Ok(0);
+        }
+        let mut values = Vec::new();
+        for (name, tracked) in &self.tracked {
+            for family in tracked.collector().collect() {
+                for metric in family.get_metric() {
+                    let labels: BTreeMap<&str, &str> =
+                        metric.get_label().iter().map(|l| (l.name(), l.value())).collect();
+                    values.push(format!(
+                        "({}, {}, {}, current_timestamp, {})",
+                        sql_literal(name),
+                        sql_literal(&serde_json::to_string(&labels)?),
+                        metric.get_counter().value(),
+                        at_shutdown
+                    ));
+                }
+            }
+        }
+        if values.is_empty() {
+            return Ok(0);
+        }
+        let n = values.len();
+        self.db
+            .execute(format!(
+                "INSERT OR REPLACE INTO counter_snapshots (name, labels, value, updated_at, at_shutdown) VALUES {}",
+                values.join(", ")
+            ))
+            .await?;
+        Ok(n)
+    }
+
+    /// Save the counters every `counter_snapshot_secs`.
+    pub async fn run(self: Arc<Self>) {
+        if !self.enabled {
+            return;
+        }
+        info!("Saving counters every {}s", self.interval.as_secs());
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.save(false).await {
+                warn!("Saving counters failed: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{start_db_worker, DbConfig};
+    use prometheus::Opts;
+
+    fn counters(db: &DbHandle) -> (PersistedCounters, IntCounter, IntCounterVec) {
+        let mut counters = PersistedCounters::new(&MetricsConfig::default(), db.clone(), &Registry::new()).unwrap();
+        let messages = IntCounter::new("mqtt_messages_total", "Messages").unwrap();
+        let errors = IntCounterVec::new(Opts::new("decode_errors_total", "Errors"), &["topic"]).unwrap();
+        counters.track(messages.clone());
+        counters.track_vec(errors.clone());
+        (counters, messages, errors)
+    }
+
+    #[tokio::test]
+    async fn only_a_graceful_shutdown_snapshot_is_restored() {
+        let dir = std::env::temp_dir().join(format!("exporter-counters-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let (db, _worker) = start_db_worker(&config, &Registry::new()).unwrap();
+
+        let (saved, messages, errors) = counters(&db);
+        messages.inc_by(42);
+        errors.with_label_values(&["rtl_433/events"]).inc_by(3);
+        assert_eq!(saved.save(true).await.unwrap(), 2);
+        let (restored, messages, errors) = counters(&db);
+        assert_eq!(restored.restore().await.unwrap(), 2);
+        assert_eq!(messages.get(), 42);
+        assert_eq!(errors.with_label_values(&["rtl_433/events"]).get(), 3);
+
+        // A periodic snapshot lags behind what was scraped before a crash.
+        messages.inc();
+        assert_eq!(restored.save(false).await.unwrap(), 2);
+        let (crashed, messages, errors) = counters(&db);
+        assert_eq!(crashed.restore().await.unwrap(), 0);
+        assert_eq!(messages.get(), 0);
+        assert_eq!(errors.with_label_values(&["rtl_433/events"]).get(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+// Column-level encryption for stored raw payloads. rtl_433 messages can
+// carry identifiers that point at people (car TPMS ids, ...), so when a key
+// is configured the `raw_json` column of `raw_messages` holds AES-256-GCM
+// ciphertext instead of the payload. Each value is `nonce || ciphertext`
+// with a fresh random 96-bit nonce; the GCM tag authenticates it so a
+// corrupted or foreign value fails to decrypt instead of yielding garbage.
+//
+// Decryption only happens in the authenticated admin export endpoint.
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::Engine;
+use std::path::Path;
+
+const NONCE_LEN: usize = 12;
+
+pub struct PayloadCipher {
+    cipher: Aes256Gcm,
+}
+
+impl PayloadCipher {
+    /// Load a 256-bit key from `path`. The file holds either the 32 raw key
+    /// bytes or their base64 encoding (e.g. from `openssl rand -base64 32`).
+    pub fn from_key_file(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read(path)
+            .map_err(|e| anyhow::anyhow!("cannot read payload key {}: {}", path.display(), e))?;
+        let key = if raw.len() == 32 {
+            raw
+        } else {
+            let text = String::from_utf8(raw).map_err(|_| anyhow::anyhow!("payload key must be 32 bytes or base64"))?;
+            base64::engine::general_purpose::STANDARD
+                .decode(text.trim())
+                .map_err(|e| anyhow::anyhow!("invalid base64 payload key: {}", e))?
+        };
+        if key.len() != 32 {
+            return Err(anyhow::anyhow!("payload key must be 256 bits, got {} bytes", key.len()));
+        }
+        Ok(Self { cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)) })
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow::anyhow!("payload encryption failed"))?;
+        let mut out = nonce.to_vec();
+        out.extend(ciphertext);
+        Ok(out)
+    }
+
+    pub fn decrypt(&self, value: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if value.len() < NONCE_LEN {
+            return Err(anyhow::anyhow!("encrypted payload is truncated"));
+        }
+        let (nonce, ciphertext) = value.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("payload decryption failed (wrong key or corrupted value)"))
+    }
+}
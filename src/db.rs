@@ -0,0 +1,3412 @@
+// DuckDB worker. DuckDB connections are blocking and not `Sync`, so all
+// database access is funnelled through a single dedicated OS thread that
+// owns the `Connection`. Async code talks to it through `DbHandle`, which
+// sends `DbCommand`s over a crossbeam channel and awaits the reply on a
+// tokio oneshot so no async task ever blocks on disk I/O. Reads without a
+// dedicated command go through `DbHandle::query_as`, which deserializes
+// each row into a serde struct by column name, or `query_arrow` for Arrow
+// batches.
+//
+// The channel is bounded so a slow disk cannot grow memory without limit:
+//
+//   [duckdb]
+//   queue_capacity = 1024
+//   queue_full = "block"     # or "shed"
+//
+// When `queue_capacity` commands are waiting, `block` makes senders wait
+// (asynchronously) for a free slot, which slows the flushes down until the
+// ingest buffer's limits and health checks take over; `shed` fails new
+// commands right away (HTTP requests get `503 db_busy`, a failed flush
+// keeps its rows for the next one). Closing the database always waits.
+// `db_queue_depth` and `db_jobs_dropped_total` show the backpressure.
+use crate::mqtt_buffer::{measurement_types, NormalizedRow, RawMessage};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use duckdb::arrow::array::{ArrayRef, UInt64Array};
+use duckdb::{arrow::record_batch::RecordBatch, params_from_iter, types::Value, Connection, InterruptHandle, OptionalExt};
+use prometheus::{IntCounter, IntGauge, Registry};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+use tracing::{error, info, warn};
+
+/// Default database file, relative to the working directory.
+pub const DEFAULT_DB_PATH: &str = "exporter.duckdb";
+
+/// How to open the database and which extensions to prepare. Loaded as the
+/// `[duckdb]` section of the config file, see `config`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbConfig {
+    pub path: String,
+    /// Extensions to `INSTALL` and `LOAD` at startup (e.g. `httpfs`, `json`,
+    /// `icu`). An entry ending in `.duckdb_extension` is loaded from that
+    /// file path directly.
+    pub extensions: Vec<String>,
+    /// Where DuckDB stores and looks up installed extensions.
+    pub extension_directory: Option<String>,
+    /// Skip `INSTALL` (no network access) and only `LOAD` extensions that
+    /// are already present in `extension_directory`.
+    pub offline: bool,
+    /// Keep `latest_measurements` (one row per series) up to date on every
+    /// append to `measurements`.
+    pub latest_table: bool,
+    /// How long to keep retrying when another process holds the database
+    /// file lock before giving up (the exporter then reports unhealthy).
+    pub lock_retry_secs: u64,
+    /// Commands that may wait for the worker.
+    pub queue_capacity: usize,
+    /// What happens to new commands while the queue is full.
+    pub queue_full: QueueFull,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueFull {
+    /// Wait for a free slot.
+    Block,
+    /// Fail the command.
+    Shed,
+}
+
+impl FromStr for QueueFull {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(QueueFull::Block),
+            "shed" => Ok(QueueFull::Shed),
+            other => Err(format!("expected block or shed, got {}", other)),
+        }
+    }
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self {
+            path: DEFAULT_DB_PATH.to_string(),
+            extensions: Vec::new(),
+            extension_directory: None,
+            offline: false,
+            latest_table: false,
+            lock_retry_secs: 120,
+            queue_capacity: 1024,
+            queue_full: QueueFull::Block,
+        }
+    }
+}
+
+/// Schema applied on every startup. Statements must be idempotent.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS measurements (
+    timestamp TIMESTAMP NOT NULL,
+    sensor_id VARCHAR NOT NULL,
+    model VARCHAR NOT NULL,
+    measurement_type UTINYINT NOT NULL,
+    value DOUBLE NOT NULL,
+    topic VARCHAR,
+    -- Assigned by the DB worker when the row is appended, see `Sequencer`.
+    seq UBIGINT,
+    -- Source exporter of rows replicated in aggregator mode, '' for local rows.
+    site VARCHAR,
+    -- Probe of a multi-probe sensor, '' for the sensor's own readings.
+    probe VARCHAR
+);
+-- Databases created before the originating topic / sequence / site / probe
+-- was recorded.
+ALTER TABLE measurements ADD COLUMN IF NOT EXISTS topic VARCHAR;
+ALTER TABLE measurements ADD COLUMN IF NOT EXISTS seq UBIGINT;
+ALTER TABLE measurements ADD COLUMN IF NOT EXISTS site VARCHAR;
+ALTER TABLE measurements ADD COLUMN IF NOT EXISTS probe VARCHAR;
+
+-- Aggregator mode: last `seq` replicated from each edge exporter.
+CREATE TABLE IF NOT EXISTS sync_cursors (
+    site VARCHAR PRIMARY KEY,
+    url VARCHAR NOT NULL,
+    last_seq UBIGINT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+-- Manifest of measurement slices exported to Parquet. Read paths union
+-- these files with the live table so pruning old rows doesn't lose history.
+-- A file holds the live rows with `slice_start <= timestamp < slice_end`
+-- (NULL start: unbounded) and `seq <= max_seq` that no earlier archive
+-- held; rows committed after the export have a larger `seq` and stay live
+-- until the next one. Without `max_seq` the file claims no live rows.
+CREATE TABLE IF NOT EXISTS parquet_archive (
+    path VARCHAR PRIMARY KEY,
+    min_timestamp TIMESTAMP NOT NULL,
+    max_timestamp TIMESTAMP NOT NULL,
+    row_count BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    slice_start TIMESTAMP,
+    slice_end TIMESTAMP,
+    max_seq UBIGINT
+);
+-- Archives written before slices were recorded: assume they hold the rows
+-- of their time span stored before they were written.
+ALTER TABLE parquet_archive ADD COLUMN IF NOT EXISTS slice_start TIMESTAMP;
+ALTER TABLE parquet_archive ADD COLUMN IF NOT EXISTS slice_end TIMESTAMP;
+ALTER TABLE parquet_archive ADD COLUMN IF NOT EXISTS max_seq UBIGINT;
+UPDATE parquet_archive
+SET slice_start = min_timestamp, slice_end = max_timestamp + INTERVAL 1 MICROSECOND, max_seq = epoch_us(created_at)
+WHERE slice_end IS NULL AND max_seq IS NULL;
+
+-- Original MQTT payloads, only written when raw storage is enabled.
+-- `raw_json` is AES-GCM ciphertext when `encrypted` is set.
+CREATE TABLE IF NOT EXISTS raw_messages (
+    received_at TIMESTAMP NOT NULL,
+    topic VARCHAR NOT NULL,
+    sensor_id VARCHAR,
+    model VARCHAR,
+    raw_json BLOB NOT NULL,
+    encrypted BOOLEAN NOT NULL
+);
+
+-- Payloads that could not be normalized (invalid JSON, no model/id, ...)
+-- with the error. `payload` is AES-GCM ciphertext when `encrypted` is set.
+-- `sensor_id` and `model` are the payload's `id` and `model`, when it has
+-- them, so a sensor's rejects are purged and renamed with it.
+CREATE TABLE IF NOT EXISTS rejected_messages (
+    received_at TIMESTAMP NOT NULL,
+    topic VARCHAR NOT NULL,
+    payload BLOB NOT NULL,
+    encrypted BOOLEAN NOT NULL,
+    reason VARCHAR NOT NULL,
+    sensor_id VARCHAR,
+    model VARCHAR
+);
+ALTER TABLE rejected_messages ADD COLUMN IF NOT EXISTS sensor_id VARCHAR;
+ALTER TABLE rejected_messages ADD COLUMN IF NOT EXISTS model VARCHAR;
+
+-- Sensor renames under way, see `DbHandle::rename_sensor`. Removed with
+-- the last step, so a rename cut short by a crash is resumed at startup.
+CREATE TABLE IF NOT EXISTS pending_renames (
+    model VARCHAR NOT NULL,
+    sensor_id VARCHAR NOT NULL,
+    new_model VARCHAR NOT NULL,
+    new_sensor_id VARCHAR NOT NULL,
+    merge BOOLEAN NOT NULL,
+    until_seq UBIGINT NOT NULL,
+    until_ms BIGINT NOT NULL,
+    PRIMARY KEY (model, sensor_id)
+);
+
+-- Record of destructive maintenance actions (purges, ...). `details` is JSON.
+CREATE TABLE IF NOT EXISTS audit_log (
+    performed_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    action VARCHAR NOT NULL,
+    model VARCHAR,
+    sensor_id VARCHAR,
+    details VARCHAR
+);
+
+-- Latest reading per series for SQL consumers that only need current
+-- values. Upserted on every append when `duckdb.latest_table` is set.
+CREATE TABLE IF NOT EXISTS latest_measurements (
+    sensor_id VARCHAR NOT NULL,
+    model VARCHAR NOT NULL,
+    measurement_type UTINYINT NOT NULL,
+    timestamp TIMESTAMP NOT NULL,
+    value DOUBLE NOT NULL,
+    topic VARCHAR,
+    probe VARCHAR NOT NULL DEFAULT '',
+    PRIMARY KEY (sensor_id, model, measurement_type, probe)
+);
+
+-- Rollups of `measurements` per 5 minutes / hour, maintained by
+-- `DbCommand::Rollup`. They survive retention pruning of the raw rows.
+CREATE TABLE IF NOT EXISTS measurements_5m (
+    bucket TIMESTAMP NOT NULL,
+    sensor_id VARCHAR NOT NULL,
+    model VARCHAR NOT NULL,
+    measurement_type UTINYINT NOT NULL,
+    site VARCHAR NOT NULL,
+    min DOUBLE NOT NULL,
+    max DOUBLE NOT NULL,
+    avg DOUBLE NOT NULL,
+    count BIGINT NOT NULL,
+    probe VARCHAR NOT NULL DEFAULT '',
+    PRIMARY KEY (bucket, sensor_id, model, measurement_type, site, probe)
+);
+CREATE TABLE IF NOT EXISTS measurements_1h (
+    bucket TIMESTAMP NOT NULL,
+    sensor_id VARCHAR NOT NULL,
+    model VARCHAR NOT NULL,
+    measurement_type UTINYINT NOT NULL,
+    site VARCHAR NOT NULL,
+    min DOUBLE NOT NULL,
+    max DOUBLE NOT NULL,
+    avg DOUBLE NOT NULL,
+    count BIGINT NOT NULL,
+    probe VARCHAR NOT NULL DEFAULT '',
+    PRIMARY KEY (bucket, sensor_id, model, measurement_type, site, probe)
+);
+-- Highest `seq` already folded into the rollups.
+CREATE TABLE IF NOT EXISTS rollup_state (
+    name VARCHAR PRIMARY KEY,
+    last_seq UBIGINT NOT NULL
+);
+
+-- Changes of derived on/off states, see `states`.
+CREATE TABLE IF NOT EXISTS state_transitions (
+    timestamp TIMESTAMP NOT NULL,
+    state VARCHAR NOT NULL,
+    model VARCHAR NOT NULL,
+    sensor_id VARCHAR NOT NULL,
+    active BOOLEAN NOT NULL,
+    value DOUBLE NOT NULL
+);
+
+-- Daily heating / cooling degree days per outdoor sensor, see `degree_days`.
+-- `day` is the host's local calendar day.
+CREATE TABLE IF NOT EXISTS degree_days (
+    day DATE NOT NULL,
+    model VARCHAR NOT NULL,
+    sensor_id VARCHAR NOT NULL,
+    mean DOUBLE NOT NULL,
+    heating DOUBLE NOT NULL,
+    cooling DOUBLE NOT NULL,
+    samples BIGINT NOT NULL,
+    PRIMARY KEY (day, model, sensor_id)
+);
+
+-- Events worth showing next to a sensor's history (battery replaced, ...).
+-- `linked_sensor_id` is the new id when a rolling id was re-linked.
+CREATE TABLE IF NOT EXISTS annotations (
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    model VARCHAR NOT NULL,
+    sensor_id VARCHAR NOT NULL,
+    kind VARCHAR NOT NULL,
+    note VARCHAR,
+    linked_sensor_id VARCHAR
+);
+
+-- Labels attached to sensors by `enrichment`, one row per change; the first
+-- row of a sensor is valid from the epoch. `measurements_labeled` joins
+-- every row with the labels its sensor had at the time.
+CREATE TABLE IF NOT EXISTS sensor_labels (
+    model VARCHAR NOT NULL,
+    sensor_id VARCHAR NOT NULL,
+    valid_from TIMESTAMP NOT NULL,
+    labels MAP(VARCHAR, VARCHAR) NOT NULL
+);
+CREATE OR REPLACE VIEW measurements_labeled AS
+SELECT m.*, l.labels FROM measurements m
+ASOF LEFT JOIN sensor_labels l ON m.model = l.model AND m.sensor_id = l.sensor_id AND m.timestamp >= l.valid_from;
+
+-- Last saved values of the counters kept across restarts, see `counters`.
+-- `labels` is a JSON object of the label values. `at_shutdown` is set when
+-- the value was saved by a graceful shutdown, NULL for snapshots of
+-- versions that didn't record it.
+CREATE TABLE IF NOT EXISTS counter_snapshots (
+    name VARCHAR NOT NULL,
+    labels VARCHAR NOT NULL,
+    value DOUBLE NOT NULL,
+    updated_at TIMESTAMP NOT NULL,
+    at_shutdown BOOLEAN,
+    PRIMARY KEY (name, labels)
+);
+ALTER TABLE counter_snapshots ADD COLUMN IF NOT EXISTS at_shutdown BOOLEAN;
+
+-- The measurement registry (built-ins and `[[measurements]]`), rewritten at
+-- every start so `measurement_type` codes can be joined to their names.
+-- `unit` is the canonical unit, which every stored value is in (mappings
+-- only convert on output, see `units`). `measurements_named` adds the name
+-- and unit to every row.
+CREATE TABLE IF NOT EXISTS measurement_types (
+    code UTINYINT PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    unit VARCHAR NOT NULL,
+    metric VARCHAR NOT NULL,
+    description VARCHAR NOT NULL
+);
+CREATE OR REPLACE VIEW measurements_named AS
+SELECT m.*, t.name AS measurement, t.unit FROM measurements m
+LEFT JOIN measurement_types t ON m.measurement_type = t.code;
+
+-- Hourly radio reception per sensor from the rtl_433 metadata, see `radio`.
+-- Sums with their counts so partial hours add up; NULL min/max when no
+-- message of the hour had a frequency.
+CREATE TABLE IF NOT EXISTS radio_stats (
+    hour TIMESTAMP NOT NULL,
+    model VARCHAR NOT NULL,
+    sensor_id VARCHAR NOT NULL,
+    modulation VARCHAR NOT NULL,
+    messages BIGINT NOT NULL,
+    freq_sum DOUBLE NOT NULL,
+    freq_n BIGINT NOT NULL,
+    freq_min DOUBLE,
+    freq_max DOUBLE,
+    freq2_sum DOUBLE NOT NULL,
+    freq2_n BIGINT NOT NULL,
+    rssi_sum DOUBLE NOT NULL,
+    rssi_n BIGINT NOT NULL,
+    snr_sum DOUBLE NOT NULL,
+    snr_n BIGINT NOT NULL,
+    noise_sum DOUBLE NOT NULL,
+    noise_n BIGINT NOT NULL,
+    PRIMARY KEY (hour, model, sensor_id, modulation)
+);
+";
+
+/// Label-like columns of `measurements` that can be filtered on.
+#[derive(Clone, Copy, Debug)]
+pub enum LabelColumn {
+    SensorId,
+    Model,
+    Probe,
+}
+
+impl LabelColumn {
+    fn sql(self) -> &'static str {
+        match self {
+            LabelColumn::SensorId => "sensor_id",
+            LabelColumn::Model => "model",
+            LabelColumn::Probe => "COALESCE(probe, '')",
+        }
+    }
+}
+
+/// A filter on a label column. Regexes must match the whole value, which is
+/// what Prometheus label matchers expect.
+#[derive(Clone, Debug)]
+pub enum LabelCondition {
+    Eq(LabelColumn, String),
+    Neq(LabelColumn, String),
+    Regex(LabelColumn, String),
+    NotRegex(LabelColumn, String),
+}
+
+/// Selection of stored measurements for read paths (remote read, query
+/// API). Bounds are inclusive epoch milliseconds.
+#[derive(Clone, Debug, Default)]
+pub struct MeasurementQuery {
+    pub start_ms: i64,
+    pub end_ms: i64,
+    /// Restrict to these type codes; empty means all types.
+    pub measurement_types: Vec<u8>,
+    pub conditions: Vec<LabelCondition>,
+}
+
+/// The query `DbCommand::Explain` shows the plan of.
+#[derive(Debug)]
+pub enum ExplainTarget {
+    /// A single read-only statement, checked by the caller.
+    Sql(String),
+    /// The query a `/api/measurements` page runs.
+    Rows(RowQuery),
+}
+
+/// A time-ordered page of measurements for the HTTP query API. At most
+/// `limit` rows are returned, oldest first, ordered by millisecond
+/// timestamp, `seq` and then the label columns, starting after `after`.
+#[derive(Clone, Debug)]
+pub struct RowQuery {
+    pub selection: MeasurementQuery,
+    pub limit: usize,
+    pub after: Option<RowCursor>,
+}
+
+/// The sort key of the last row of a `RowQuery` page, so the next page can
+/// seek past it instead of skipping rows with `OFFSET`. Rows stored before
+/// `seq` existed share `seq` 0, hence the label columns as tie-breakers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RowCursor {
+    pub timestamp_ms: i64,
+    pub seq: u64,
+    pub sensor_id: String,
+    pub model: String,
+    pub measurement_type: u8,
+    pub site: String,
+    pub probe: String,
+}
+
+impl RowCursor {
+    pub fn after(row: &NormalizedRow) -> Self {
+        Self {
+            timestamp_ms: row.timestamp.and_utc().timestamp_millis(),
+            seq: row.seq,
+            sensor_id: row.sensor_id.clone(),
+            model: row.model.clone(),
+            measurement_type: row.measurement_type,
+            site: row.site.clone(),
+            probe: row.probe.clone(),
+        }
+    }
+
+    /// Opaque URL-safe form handed to API clients.
+    pub fn encode(&self) -> String {
+        let key = (self.timestamp_ms, self.seq, &self.sensor_id, &self.model, self.measurement_type, &self.site, &self.probe);
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&key).unwrap_or_default())
+    }
+
+    pub fn decode(cursor: &str) -> anyhow::Result<Self> {
+        let json = URL_SAFE_NO_PAD.decode(cursor.trim())?;
+        let (timestamp_ms, seq, sensor_id, model, measurement_type, site, probe) = serde_json::from_slice(&json)?;
+        Ok(Self { timestamp_ms, seq, sensor_id, model, measurement_type, site, probe })
+    }
+}
+
+/// Bucket sizes of the rollup tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    FiveMinutes,
+    Hour,
+}
+
+impl Resolution {
+    pub const ALL: [Resolution; 2] = [Resolution::FiveMinutes, Resolution::Hour];
+
+    /// Parse the query API spelling (`5m`, `1h`).
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "5m" => Some(Resolution::FiveMinutes),
+            "1h" => Some(Resolution::Hour),
+            _ => None,
+        }
+    }
+
+    fn table(self) -> &'static str {
+        match self {
+            Resolution::FiveMinutes => "measurements_5m",
+            Resolution::Hour => "measurements_1h",
+        }
+    }
+
+    fn interval(self) -> &'static str {
+        match self {
+            Resolution::FiveMinutes => "INTERVAL 5 MINUTE",
+            Resolution::Hour => "INTERVAL 1 HOUR",
+        }
+    }
+}
+
+/// A bucket-ordered page of a rollup table for the HTTP query API.
+#[derive(Clone, Debug)]
+pub struct RollupQuery {
+    pub resolution: Resolution,
+    pub selection: MeasurementQuery,
+    pub limit: usize,
+}
+
+/// One row of a rollup table.
+#[derive(Clone, Debug)]
+pub struct RollupRow {
+    pub bucket: NaiveDateTime,
+    pub sensor_id: String,
+    pub model: String,
+    pub measurement_type: u8,
+    pub site: String,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub count: i64,
+    pub probe: String,
+}
+
+/// Raw rows aggregated into `step_ms` buckets for charting, including
+/// archived Parquet slices. `limit` buckets are returned, ordered by bucket
+/// and then the label columns, starting after `after`.
+#[derive(Clone, Debug)]
+pub struct SeriesQuery {
+    pub selection: MeasurementQuery,
+    pub step_ms: i64,
+    pub limit: usize,
+    pub after: Option<SeriesCursor>,
+}
+
+/// The sort key of the last bucket of a `SeriesQuery` page, the
+/// counterpart of `RowCursor`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeriesCursor {
+    pub bucket_ms: i64,
+    pub sensor_id: String,
+    pub model: String,
+    pub measurement_type: u8,
+    pub site: String,
+    pub probe: String,
+}
+
+impl SeriesCursor {
+    pub fn after(row: &RollupRow) -> Self {
+        Self {
+            bucket_ms: row.bucket.and_utc().timestamp_millis(),
+            sensor_id: row.sensor_id.clone(),
+            model: row.model.clone(),
+            measurement_type: row.measurement_type,
+            site: row.site.clone(),
+            probe: row.probe.clone(),
+        }
+    }
+
+    /// Opaque URL-safe form handed to API clients.
+    pub fn encode(&self) -> String {
+        let key = (self.bucket_ms, &self.sensor_id, &self.model, self.measurement_type, &self.site, &self.probe);
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&key).unwrap_or_default())
+    }
+
+    pub fn decode(cursor: &str) -> anyhow::Result<Self> {
+        let json = URL_SAFE_NO_PAD.decode(cursor.trim())?;
+        let (bucket_ms, sensor_id, model, measurement_type, site, probe) = serde_json::from_slice(&json)?;
+        Ok(Self { bucket_ms, sensor_id, model, measurement_type, site, probe })
+    }
+}
+
+/// Selection of stored raw payloads for the admin export. Bounds are
+/// inclusive epoch milliseconds.
+#[derive(Clone, Debug, Default)]
+pub struct RawQuery {
+    pub start_ms: i64,
+    pub end_ms: i64,
+    pub sensor_id: Option<String>,
+    pub model: Option<String>,
+}
+
+/// Removal of one sensor's stored data, e.g. when a tracked device changes
+/// owner, or of a window of bad readings. Rows from `from_ms` (inclusive)
+/// to `before_ms` (exclusive) are removed, without bounds everything. With
+/// `measurement_type` only that measurement's rows are; raw payloads,
+/// transitions and degree days are then kept.
+#[derive(Clone, Debug)]
+pub struct PurgeRequest {
+    pub model: String,
+    pub sensor_id: String,
+    pub from_ms: Option<i64>,
+    pub before_ms: Option<i64>,
+    pub measurement_type: Option<u8>,
+}
+
+/// What a purge removed; also stored as the audit record's details.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PurgeReport {
+    pub measurements: usize,
+    pub raw_messages: usize,
+    pub rejected_messages: usize,
+    pub archived_rows: usize,
+    pub archives_rewritten: usize,
+}
+
+/// Moving a sensor's history to another id, e.g. after a device was
+/// replaced and came back with a new one. With `merge` the target may have
+/// data already and the two histories are combined.
+#[derive(Clone, Debug)]
+pub struct RenameRequest {
+    pub model: String,
+    pub sensor_id: String,
+    pub new_model: String,
+    pub new_sensor_id: String,
+    pub merge: bool,
+}
+
+/// A rename that was started: rows of `measurements` stored up to
+/// `until_seq` and raw payloads received up to `until_ms` move.
+#[derive(Clone, Debug)]
+pub struct PendingRename {
+    pub request: RenameRequest,
+    pub until_seq: u64,
+    pub until_ms: i64,
+}
+
+/// Rows a rename moved; also stored as the audit record's details.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RenameReport {
+    pub measurements: usize,
+    pub raw_messages: usize,
+    pub rejected_messages: usize,
+    pub archived_rows: usize,
+    pub archives_rewritten: usize,
+}
+
+/// Rows moved per `DbCommand::RenameSensor`.
+const RENAME_CHUNK_ROWS: usize = 50_000;
+
+/// Rows removed by a retention prune, per table.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PruneReport {
+    pub measurements: usize,
+    pub raw_messages: usize,
+    pub rejected_messages: usize,
+}
+
+/// A sensor whose rows are kept for a different period than the rest.
+#[derive(Clone, Debug)]
+pub struct SensorRetention {
+    pub model: String,
+    pub sensor_id: String,
+    /// Rows before this (epoch ms) are deleted.
+    pub before_ms: i64,
+}
+
+/// A sensor event recorded in `annotations`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Annotation {
+    pub model: String,
+    pub sensor_id: String,
+    pub kind: String,
+    pub note: Option<String>,
+    pub linked_sensor_id: Option<String>,
+}
+
+/// A derived state switching on or off, recorded in `state_transitions`.
+#[derive(Clone, Debug, Serialize)]
+pub struct StateTransition {
+    pub timestamp: NaiveDateTime,
+    pub state: String,
+    pub model: String,
+    pub sensor_id: String,
+    pub active: bool,
+    /// The reading that caused the transition.
+    pub value: f64,
+}
+
+/// Selection of `state_transitions`; `start_ms`/`end_ms` are inclusive
+/// epoch milliseconds.
+#[derive(Clone, Debug)]
+pub struct TransitionQuery {
+    pub start_ms: i64,
+    pub end_ms: i64,
+    pub state: Option<String>,
+    pub limit: usize,
+}
+
+/// Days to (re)compute degree days for, with the UTC epoch milliseconds
+/// `[start_ms, end_ms)` each day covers.
+#[derive(Clone, Debug)]
+pub struct DegreeDayRequest {
+    pub model: String,
+    pub sensor_id: String,
+    pub measurement_type: u8,
+    pub heating_base: f64,
+    pub cooling_base: f64,
+    pub days: Vec<(NaiveDate, i64, i64)>,
+}
+
+/// One row of `degree_days`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DegreeDay {
+    pub day: NaiveDate,
+    pub model: String,
+    pub sensor_id: String,
+    pub mean: f64,
+    pub heating: f64,
+    pub cooling: f64,
+    pub samples: i64,
+}
+
+/// Selection of a summary report, see `report`; the period is
+/// `[start_ms, end_ms)` in epoch milliseconds.
+#[derive(Clone, Debug)]
+pub struct ReportQuery {
+    pub start_ms: i64,
+    pub end_ms: i64,
+    /// Measurement whose minimum and maximum are reported.
+    pub measurement_type: Option<u8>,
+    pub battery_type: Option<u8>,
+    /// Silences longer than this count as gaps.
+    pub gap_ms: i64,
+}
+
+/// What a locally ingested sensor sent during a report period.
+#[derive(Clone, Debug, Serialize)]
+pub struct SensorActivity {
+    pub model: String,
+    pub sensor_id: String,
+    /// Distinct message timestamps.
+    pub messages: i64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Last `battery_ok` reading of the period.
+    pub battery_ok: Option<f64>,
+    pub last_seen_ms: i64,
+    /// Oldest reading still stored.
+    pub first_seen_ms: i64,
+    /// Silences longer than the query's `gap_ms`, counting those at the
+    /// start (for sensors seen before) and the end of the period.
+    pub gaps: i64,
+    pub longest_gap_ms: i64,
+}
+
+/// Labels of one sensor, see `enrichment`.
+#[derive(Clone, Debug)]
+pub struct SensorLabels {
+    pub model: String,
+    pub sensor_id: String,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// An archived Parquet file and the live rows it holds: those from
+/// `start_micros` (inclusive, `None` for unbounded) to `end_micros`
+/// (exclusive) with a `seq` up to `max_seq`.
+struct ArchiveSlice {
+    path: String,
+    start_micros: Option<i64>,
+    end_micros: Option<i64>,
+    max_seq: Option<u64>,
+}
+
+impl ArchiveSlice {
+    /// Condition matching the `measurements` rows the file holds.
+    fn holds_sql(&self) -> String {
+        let (Some(end), Some(max_seq)) = (self.end_micros, self.max_seq) else { return "false".to_string() };
+        let start = self.start_micros.map_or(String::new(), |start| format!("timestamp >= make_timestamp({}) AND ", start));
+        format!("({}timestamp < make_timestamp({}) AND COALESCE(seq, 0) <= {})", start, end, max_seq)
+    }
+}
+
+/// Condition matching the `measurements` rows held by any of `archives`.
+fn archived_sql(archives: &[ArchiveSlice]) -> String {
+    if archives.is_empty() {
+        return "false".to_string();
+    }
+    let held: Vec<String> = archives.iter().map(ArchiveSlice::holds_sql).collect();
+    format!("({})", held.join(" OR "))
+}
+
+/// Epoch milliseconds to microseconds, clamped to what a TIMESTAMP can hold
+/// (years 0001-9999).
+fn ms_to_micros(ms: i64) -> i64 {
+    ms.clamp(-62_135_596_800_000, 253_402_300_799_999) * 1000
+}
+
+/// Quote a string as a SQL literal. Only used for values that can't be
+/// bound as parameters, such as file lists passed to `read_parquet`.
+pub fn sql_literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+impl MeasurementQuery {
+    /// Query bounds in microseconds. Clients like Prometheus may send
+    /// open-ended ranges, so they are clamped to what a TIMESTAMP can hold
+    /// (years 0001-9999) first.
+    fn bounds_micros(&self) -> (i64, i64) {
+        (ms_to_micros(self.start_ms), ms_to_micros(self.end_ms))
+    }
+
+    /// `WHERE` conditions (without the keyword) on `time_column` and the
+    /// label columns, with their positional parameters.
+    fn filter_sql(&self, time_column: &str) -> (String, Vec<Value>) {
+        let mut sql = format!(
+            "{0} >= make_timestamp(CAST(? AS BIGINT)) AND {0} <= make_timestamp(CAST(? AS BIGINT))",
+            time_column
+        );
+        let (start, end) = self.bounds_micros();
+        let mut params = vec![Value::BigInt(start), Value::BigInt(end)];
+        if !self.measurement_types.is_empty() {
+            let placeholders = vec!["?"; self.measurement_types.len()].join(", ");
+            sql.push_str(&format!(" AND measurement_type IN ({})", placeholders));
+            params.extend(self.measurement_types.iter().map(|t| Value::UTinyInt(*t)));
+        }
+        for condition in &self.conditions {
+            let (clause, column, value) = match condition {
+                LabelCondition::Eq(c, v) => ("{} = ?", c, v),
+                LabelCondition::Neq(c, v) => ("{} <> ?", c, v),
+                LabelCondition::Regex(c, v) => ("regexp_full_match({}, ?)", c, v),
+                LabelCondition::NotRegex(c, v) => ("NOT regexp_full_match({}, ?)", c, v),
+            };
+            sql.push_str(" AND ");
+            sql.push_str(&clause.replace("{}", column.sql()));
+            params.push(Value::Text(value.clone()));
+        }
+        (sql, params)
+    }
+
+    /// Build the SQL and its positional parameters. Rows are ordered by
+    /// series, then time, so callers can group them in one pass.
+    fn to_sql(&self, archives: &[ArchiveSlice]) -> (String, Vec<Value>) {
+        let (mut sql, params) = self.select_sql(archives);
+        sql.push_str(" ORDER BY sensor_id, model, measurement_type, site, probe, timestamp");
+        (sql, params)
+    }
+
+    /// The unordered selection shared by `to_sql` and `RowQuery`.
+    ///
+    /// When archived slices overlap the range they are unioned in, and live
+    /// rows an archive holds (see `ArchiveSlice`) are skipped, so rows
+    /// exported but not yet pruned aren't returned twice while rows that
+    /// arrived late for an archived time range still are. Archives written
+    /// before the `topic`, `seq`, `site` and `probe` columns existed read
+    /// them as NULL.
+    fn select_sql(&self, archives: &[ArchiveSlice]) -> (String, Vec<Value>) {
+        let source = if archives.is_empty() {
+            "measurements".to_string()
+        } else {
+            let files: Vec<String> = archives.iter().map(|a| sql_literal(&a.path)).collect();
+            format!(
+                "(SELECT timestamp, sensor_id, model, measurement_type, value, topic, seq, site, probe FROM measurements WHERE NOT {} \
+                 UNION ALL SELECT timestamp, sensor_id, model, measurement_type, value, topic, seq, site, probe \
+                 FROM (SELECT NULL::VARCHAR AS topic, NULL::UBIGINT AS seq, NULL::VARCHAR AS site, NULL::VARCHAR AS probe WHERE false \
+                 UNION ALL BY NAME SELECT * FROM read_parquet([{}], union_by_name = true)))",
+                archived_sql(archives),
+                files.join(", ")
+            )
+        };
+        let (filter, params) = self.filter_sql("timestamp");
+        let sql = format!(
+            "SELECT CAST(epoch_ms(timestamp) AS BIGINT), sensor_id, model, measurement_type, value, COALESCE(topic, ''), COALESCE(seq, 0), COALESCE(site, ''), \
+             COALESCE(probe, '') FROM {} WHERE {}",
+            source, filter
+        );
+        (sql, params)
+    }
+}
+
+/// One row of the `parquet_archive` manifest.
+#[derive(Clone, Debug, Serialize)]
+pub struct ArchiveEntry {
+    pub path: String,
+    pub min_timestamp: NaiveDateTime,
+    pub max_timestamp: NaiveDateTime,
+    pub row_count: i64,
+}
+
+/// A time slice of `measurements` to write to a Parquet file and register
+/// as an archive. `end_ms` is exclusive; without `start_ms` the slice has
+/// no lower bound. Only rows no archive holds yet are written.
+#[derive(Clone, Debug)]
+pub struct ExportRequest {
+    pub start_ms: Option<i64>,
+    pub end_ms: i64,
+    pub path: String,
+}
+
+/// File formats of query jobs (see `jobs`) and of `export`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Parquet,
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Parquet => "parquet",
+            ExportFormat::Csv => "csv",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+            ExportFormat::Csv => "text/csv",
+        }
+    }
+
+    fn copy_options(self) -> &'static str {
+        match self {
+            ExportFormat::Parquet => "FORMAT parquet, COMPRESSION zstd",
+            ExportFormat::Csv => "FORMAT csv, HEADER true",
+        }
+    }
+}
+
+/// Write the rows matching `selection` (archives included) to `path`.
+#[derive(Clone, Debug)]
+pub struct QueryExport {
+    pub selection: MeasurementQuery,
+    pub path: String,
+    pub format: ExportFormat,
+}
+
+/// Work items understood by the DB worker.
+pub enum DbCommand {
+    /// Run one or more statements that produce no rows (DDL, COPY, ...).
+    Execute(String),
+    /// Bulk-append an Arrow batch to `table` using DuckDB's appender.
+    Append { table: String, batch: RecordBatch },
+    /// Read measurement rows matching the query, including archived
+    /// Parquet slices that overlap its time range.
+    SelectMeasurements(MeasurementQuery),
+    /// Read one time-ordered page of measurement rows for the query API;
+    /// archives are included like for `SelectMeasurements`.
+    QueryPage(RowQuery),
+    /// Run a read-only query with positional parameters; answers with its
+    /// rows as Arrow batches.
+    QueryArrow { sql: String, params: Vec<Value> },
+    /// Like `QueryArrow`, answering with one JSON object per row keyed by
+    /// column name, for `DbHandle::query_as`.
+    QueryRecords { sql: String, params: Vec<Value> },
+    /// Read up to `limit` live measurement rows with `seq > after_seq`, in
+    /// `seq` order, for incremental sync.
+    SyncRows { after_seq: u64, limit: usize },
+    /// DuckDB's plan for a query, run with `EXPLAIN ANALYZE` when `analyze`
+    /// is set.
+    Explain { target: ExplainTarget, analyze: bool },
+    /// Aggregator mode: the last `seq` replicated from `site` (0 if none).
+    SyncCursor(String),
+    /// Aggregator mode: append rows replicated from `site` and advance its
+    /// cursor to `last_seq` in one transaction, so a crash can neither lose
+    /// nor duplicate a page.
+    MergeSynced { site: String, url: String, batch: RecordBatch, last_seq: u64 },
+    /// Record a rename in `pending_renames`, up to the rows stored so far.
+    BeginRename(RenameRequest),
+    /// Renames started but not finished, e.g. before a crash.
+    PendingRenames,
+    /// Fold rows added since the last run into the rollup tables.
+    Rollup,
+    /// Read one bucket-ordered page of a rollup table.
+    QueryRollups(RollupQuery),
+    /// Aggregate one bucket-ordered page of raw rows, see `SeriesQuery`.
+    QuerySeries(SeriesQuery),
+    /// Read stored raw payloads, still encrypted if they were stored so.
+    SelectRaw(RawQuery),
+    /// Add an existing Parquet file of measurements to the archive manifest.
+    RegisterArchive(String),
+    /// `COPY` a slice of `measurements` to Parquet and register the file in
+    /// the archive manifest. Answers with the new entry, or none when the
+    /// slice was empty and no file was written.
+    Export(ExportRequest),
+    /// List the archive manifest.
+    ListArchives,
+    /// Write a query result to a file; answers with the row count.
+    ExportQuery(QueryExport),
+    /// Record a sensor event in `annotations`.
+    Annotate(Annotation),
+    /// Append derived state changes to `state_transitions`.
+    RecordTransitions(Vec<StateTransition>),
+    /// Read state changes, oldest first.
+    QueryTransitions(TransitionQuery),
+    /// The most recent transition of every (state, sensor), to restore the
+    /// states at startup.
+    CurrentStates,
+    /// Store the degree days of the given days of one sensor.
+    ComputeDegreeDays(DegreeDayRequest),
+    /// Per-sensor aggregates of a report period.
+    Report(ReportQuery),
+    /// Append a change of a sensor's labels to `sensor_labels`.
+    RecordLabels(SensorLabels),
+    /// The current labels of every sensor in `sensor_labels`.
+    CurrentLabels,
+    /// Delete a sensor's rows everywhere they are stored and audit it.
+    PurgeSensor(PurgeRequest),
+    /// Row count of every table.
+    TableRows,
+    /// Whether measurements, live or rolled up, are stored for a sensor.
+    SensorHasData { model: String, sensor_id: String },
+    /// One step of a rename: up to `RENAME_CHUNK_ROWS` rows of
+    /// `measurements` (normalized up to `until_seq`) or `raw_messages`
+    /// (received up to `until_ms`), or, once none are left, the remaining
+    /// tables, the archives and the audit record.
+    RenameSensor(PendingRename),
+    /// Delete rows older than the cutoff (epoch ms) from the live tables
+    /// and checkpoint, see `retention`. Sensors listed in `sensors` use
+    /// their own cutoff instead; without a global cutoff only they are
+    /// pruned. With `archived_only` measurement rows no archive holds are
+    /// kept.
+    Prune { before_ms: Option<i64>, sensors: Vec<SensorRetention>, archived_only: bool },
+    /// Force a `CHECKPOINT` so the WAL is merged into the database file.
+    Checkpoint,
+    /// Round trip through the worker for health checks.
+    Ping,
+    /// Column names of a table in order, empty when it doesn't exist.
+    TableColumns(String),
+    /// Close the database and stop the worker, answered once the database
+    /// is closed. Commands sent afterwards fail.
+    Close,
+}
+
+impl DbCommand {
+    /// Commands that only read, so interrupting them loses nothing.
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            DbCommand::SelectMeasurements(_)
+                | DbCommand::QueryPage(_)
+                | DbCommand::QueryArrow { .. }
+                | DbCommand::QueryRecords { .. }
+                | DbCommand::Explain { .. }
+                | DbCommand::QueryRollups(_)
+                | DbCommand::QuerySeries(_)
+                | DbCommand::SelectRaw(_)
+                | DbCommand::ListArchives
+                | DbCommand::QueryTransitions(_)
+                | DbCommand::Report(_)
+                | DbCommand::TableRows
+        )
+    }
+}
+
+tokio::task_local! {
+    static ABANDONABLE: ();
+}
+
+/// Run `future`, interrupting the read-only command it waits for if
+/// `future` is dropped first, e.g. by a timeout or a closed connection.
+/// Without this an abandoned query keeps the worker busy to the end.
+pub async fn abandonable<F: Future>(future: F) -> F::Output {
+    ABANDONABLE.scope((), future).await
+}
+
+/// The abandonable job the worker is running, see `abandonable`.
+#[derive(Default)]
+struct Running {
+    /// Id of the running job, `0` while none is.
+    job: Mutex<u64>,
+    next_id: AtomicU64,
+    /// Set by the worker once the database is open.
+    interrupt: OnceLock<Arc<InterruptHandle>>,
+}
+
+/// Interrupts job `id` when dropped while the worker is still running it.
+struct Abandon<'a> {
+    running: &'a Running,
+    id: u64,
+}
+
+impl Drop for Abandon<'_> {
+    fn drop(&mut self) {
+        // Held while interrupting, so the worker can't move on to the next
+        // job in between.
+        let job = self.running.job.lock().unwrap();
+        if self.id != 0 && *job == self.id && let Some(interrupt) = self.running.interrupt.get() {
+            interrupt.interrupt();
+        }
+    }
+}
+
+/// Replies sent back from the DB worker.
+#[derive(Debug)]
+pub enum DbResponse {
+    Ok,
+    Appended(usize),
+    Measurements(Vec<NormalizedRow>),
+    RawMessages(Vec<RawMessage>),
+    Archives(Vec<ArchiveEntry>),
+    Purged(PurgeReport),
+    Exists(bool),
+    TableRows(BTreeMap<String, u64>),
+    /// Rows moved by one rename step; `done` after the last one.
+    Renamed { report: RenameReport, done: bool },
+    Pruned(PruneReport),
+    Renames(Vec<PendingRename>),
+    Seq(u64),
+    Rollups(Vec<RollupRow>),
+    Transitions(Vec<StateTransition>),
+    Batches(Vec<RecordBatch>),
+    Records(Vec<serde_json::Map<String, serde_json::Value>>),
+    Activity(Vec<SensorActivity>),
+    Labels(Vec<SensorLabels>),
+    Plan(String),
+    Columns(Vec<String>),
+    Error(String),
+}
+
+struct DbJob {
+    command: DbCommand,
+    reply: oneshot::Sender<DbResponse>,
+    /// Queue slot, released once the worker takes the job.
+    slot: OwnedSemaphorePermit,
+    /// The HTTP request the command was sent for, see `request_id`.
+    request_id: Option<String>,
+    /// Non-zero for an abandonable job, see `Running`.
+    id: u64,
+}
+
+/// Queue metrics, shared by the handles and the worker.
+#[derive(Clone)]
+struct QueueMetrics {
+    depth: IntGauge,
+    dropped: IntCounter,
+}
+
+impl QueueMetrics {
+    fn new(registry: &Registry) -> anyhow::Result<Self> {
+        let metrics = Self {
+            depth: IntGauge::new("db_queue_depth", "Commands waiting for the DuckDB worker")?,
+            dropped: IntCounter::new("db_jobs_dropped_total", "DuckDB commands failed because the queue was full")?,
+        };
+        registry.register(Box::new(metrics.depth.clone()))?;
+        registry.register(Box::new(metrics.dropped.clone()))?;
+        Ok(metrics)
+    }
+}
+
+/// Whether the worker has a usable connection.
+#[derive(Clone, Debug)]
+pub enum DbStatus {
+    /// Waiting for another process to release the database file lock.
+    /// Commands queue up until the database opens.
+    Opening,
+    Ready,
+    /// Gave up opening the database; every command fails with this reason.
+    Failed(String),
+}
+
+/// Cheap-to-clone handle used by async code to submit work to the worker.
+/// The worker thread exits once every handle has been dropped.
+#[derive(Clone)]
+pub struct DbHandle {
+    tx: Sender<DbJob>,
+    status: Arc<RwLock<DbStatus>>,
+    /// One permit per free place in the queue.
+    slots: Arc<Semaphore>,
+    queue_full: QueueFull,
+    metrics: QueueMetrics,
+    running: Arc<Running>,
+}
+
+impl DbHandle {
+    pub fn status(&self) -> DbStatus {
+        self.status.read().unwrap().clone()
+    }
+
+    /// Whether the queue is full, i.e. new commands wait or are shed.
+    pub fn is_saturated(&self) -> bool {
+        self.slots.available_permits() == 0
+    }
+
+    /// Submit a command and wait for the worker's reply. Waits for a queue
+    /// slot or fails right away when the queue is full, see `QueueFull`.
+    pub async fn send(&self, command: DbCommand) -> anyhow::Result<DbResponse> {
+        let slot = match (self.queue_full, &command) {
+            (QueueFull::Shed, command) if !matches!(command, DbCommand::Close) => {
+                self.slots.clone().try_acquire_owned().map_err(|_| {
+                    self.metrics.dropped.inc();
+                    anyhow::anyhow!("DB queue full, command dropped")
+                })?
+            }
+            _ => self.slots.clone().acquire_owned().await?,
+        };
+        let id = match command.is_read_only() && ABANDONABLE.try_with(|_| ()).is_ok() {
+            true => self.running.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            false => 0,
+        };
+        let (reply, rx) = oneshot::channel();
+        // Never blocks: there are no more slots than places in the channel.
+        self.tx
+            .send(DbJob { command, reply, slot, request_id: crate::request_id::current(), id })
+            .map_err(|_| anyhow::anyhow!("DB worker has shut down"))?;
+        self.metrics.depth.inc();
+        let _abandon = Abandon { running: &self.running, id };
+        Ok(rx.await?)
+    }
+
+    pub async fn execute(&self, sql: impl Into<String>) -> anyhow::Result<()> {
+        match self.send(DbCommand::Execute(sql.into())).await? {
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Append `batch` to `table`, returning the number of rows written.
+    pub async fn append(&self, table: &str, batch: RecordBatch) -> anyhow::Result<usize> {
+        let command = DbCommand::Append { table: table.to_string(), batch };
+        match self.send(command).await? {
+            DbResponse::Appended(n) => Ok(n),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Fetch stored rows matching `query`.
+    pub async fn select_measurements(&self, query: MeasurementQuery) -> anyhow::Result<Vec<NormalizedRow>> {
+        match self.send(DbCommand::SelectMeasurements(query)).await? {
+            DbResponse::Measurements(rows) => Ok(rows),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// DuckDB's query plan for `target`. With `analyze` the query is run and
+    /// the plan carries timings and row counts.
+    pub async fn explain(&self, target: ExplainTarget, analyze: bool) -> anyhow::Result<String> {
+        match self.send(DbCommand::Explain { target, analyze }).await? {
+            DbResponse::Plan(plan) => Ok(plan),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Run a read-only query with `?` placeholders bound to `params` and
+    /// return the result as Arrow batches.
+    pub async fn query_arrow(&self, sql: impl Into<String>, params: Vec<Value>) -> anyhow::Result<Vec<RecordBatch>> {
+        match self.send(DbCommand::QueryArrow { sql: sql.into(), params }).await? {
+            DbResponse::Batches(batches) => Ok(batches),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Run a read-only query like `query_arrow` and deserialize every row
+    /// into `T`, matching columns to fields by name. Timestamps and dates
+    /// arrive as ISO 8601 strings, so `chrono` types work as fields.
+    pub async fn query_as<T: DeserializeOwned>(&self, sql: impl Into<String>, params: Vec<Value>) -> anyhow::Result<Vec<T>> {
+        let records = match self.send(DbCommand::QueryRecords { sql: sql.into(), params }).await? {
+            DbResponse::Records(records) => records,
+            DbResponse::Error(e) => return Err(anyhow::anyhow!(e)),
+            other => return Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        };
+        records
+            .into_iter()
+            .map(|record| serde_json::from_value(serde_json::Value::Object(record)))
+            .collect::<Result<_, _>>()
+            .map_err(|e| anyhow::anyhow!("cannot read query row: {}", e))
+    }
+
+    /// Fetch up to `query.limit` measurement rows, oldest first.
+    pub async fn query_page(&self, query: RowQuery) -> anyhow::Result<Vec<NormalizedRow>> {
+        match self.send(DbCommand::QueryPage(query)).await? {
+            DbResponse::Measurements(rows) => Ok(rows),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Fetch the next page of rows added after `after_seq`.
+    pub async fn sync_rows(&self, after_seq: u64, limit: usize) -> anyhow::Result<Vec<NormalizedRow>> {
+        match self.send(DbCommand::SyncRows { after_seq, limit }).await? {
+            DbResponse::Measurements(rows) => Ok(rows),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Export a slice of `measurements` to Parquet, see `DbCommand::Export`.
+    pub async fn export(&self, request: ExportRequest) -> anyhow::Result<Option<ArchiveEntry>> {
+        match self.send(DbCommand::Export(request)).await? {
+            DbResponse::Archives(mut entries) => Ok(entries.pop()),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Run a query job's export, see `DbCommand::ExportQuery`.
+    pub async fn export_query(&self, export: QueryExport) -> anyhow::Result<usize> {
+        match self.send(DbCommand::ExportQuery(export)).await? {
+            DbResponse::Appended(n) => Ok(n),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Last `seq` replicated from `site`.
+    pub async fn sync_cursor(&self, site: &str) -> anyhow::Result<u64> {
+        match self.send(DbCommand::SyncCursor(site.to_string())).await? {
+            DbResponse::Seq(seq) => Ok(seq),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Store a page replicated from `site` and advance its cursor.
+    pub async fn merge_synced(&self, site: &str, url: &str, batch: RecordBatch, last_seq: u64) -> anyhow::Result<usize> {
+        let command = DbCommand::MergeSynced { site: site.to_string(), url: url.to_string(), batch, last_seq };
+        match self.send(command).await? {
+            DbResponse::Appended(n) => Ok(n),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Update the rollup tables; returns how many buckets were (re)written.
+    pub async fn rollup(&self) -> anyhow::Result<usize> {
+        match self.send(DbCommand::Rollup).await? {
+            DbResponse::Appended(n) => Ok(n),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Fetch a page of rollup rows matching `query`.
+    pub async fn query_rollups(&self, query: RollupQuery) -> anyhow::Result<Vec<RollupRow>> {
+        match self.send(DbCommand::QueryRollups(query)).await? {
+            DbResponse::Rollups(rows) => Ok(rows),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Fetch a page of buckets aggregated from raw rows matching `query`.
+    pub async fn query_series(&self, query: SeriesQuery) -> anyhow::Result<Vec<RollupRow>> {
+        match self.send(DbCommand::QuerySeries(query)).await? {
+            DbResponse::Rollups(rows) => Ok(rows),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Fetch stored raw payloads matching `query`.
+    pub async fn select_raw(&self, query: RawQuery) -> anyhow::Result<Vec<RawMessage>> {
+        match self.send(DbCommand::SelectRaw(query)).await? {
+            DbResponse::RawMessages(messages) => Ok(messages),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Register a Parquet file in the archive manifest, returning its entry.
+    pub async fn register_archive(&self, path: &str) -> anyhow::Result<ArchiveEntry> {
+        match self.send(DbCommand::RegisterArchive(path.to_string())).await? {
+            DbResponse::Archives(mut entries) if entries.len() == 1 => Ok(entries.remove(0)),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    pub async fn list_archives(&self) -> anyhow::Result<Vec<ArchiveEntry>> {
+        match self.send(DbCommand::ListArchives).await? {
+            DbResponse::Archives(entries) => Ok(entries),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    pub async fn annotate(&self, annotation: Annotation) -> anyhow::Result<()> {
+        match self.send(DbCommand::Annotate(annotation)).await? {
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn record_transitions(&self, transitions: Vec<StateTransition>) -> anyhow::Result<()> {
+        match self.send(DbCommand::RecordTransitions(transitions)).await? {
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// State changes matching `query`, oldest first.
+    pub async fn query_transitions(&self, query: TransitionQuery) -> anyhow::Result<Vec<StateTransition>> {
+        match self.send(DbCommand::QueryTransitions(query)).await? {
+            DbResponse::Transitions(transitions) => Ok(transitions),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// The last recorded transition of every (state, sensor).
+    pub async fn current_states(&self) -> anyhow::Result<Vec<StateTransition>> {
+        match self.send(DbCommand::CurrentStates).await? {
+            DbResponse::Transitions(transitions) => Ok(transitions),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Compute the daily mean of every day in `request` that has readings
+    /// and store its degree days, replacing earlier values.
+    pub async fn compute_degree_days(&self, request: DegreeDayRequest) -> anyhow::Result<usize> {
+        match self.send(DbCommand::ComputeDegreeDays(request)).await? {
+            DbResponse::Appended(n) => Ok(n),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Stored degree days from `since` on, oldest first.
+    pub async fn degree_days(&self, since: NaiveDate) -> anyhow::Result<Vec<DegreeDay>> {
+        self.query_as(
+            "SELECT day, model, sensor_id, mean, heating, cooling, samples FROM degree_days \
+             WHERE day >= CAST(? AS DATE) ORDER BY day, model, sensor_id",
+            vec![Value::Text(since.to_string())],
+        )
+        .await
+    }
+
+    /// Activity of every sensor with readings in the report period.
+    pub async fn report(&self, query: ReportQuery) -> anyhow::Result<Vec<SensorActivity>> {
+        match self.send(DbCommand::Report(query)).await? {
+            DbResponse::Activity(sensors) => Ok(sensors),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Record that a sensor's labels changed to `labels` now.
+    pub async fn record_labels(&self, labels: SensorLabels) -> anyhow::Result<()> {
+        match self.send(DbCommand::RecordLabels(labels)).await? {
+            DbResponse::Ok => Ok(()),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// The most recently recorded labels of every sensor.
+    pub async fn current_labels(&self) -> anyhow::Result<Vec<SensorLabels>> {
+        match self.send(DbCommand::CurrentLabels).await? {
+            DbResponse::Labels(labels) => Ok(labels),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Remove a sensor's data (measurements, raw payloads, archived rows)
+    /// and record the purge in `audit_log`.
+    pub async fn purge_sensor(&self, request: PurgeRequest) -> anyhow::Result<PurgeReport> {
+        match self.send(DbCommand::PurgeSensor(request)).await? {
+            DbResponse::Purged(report) => Ok(report),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Row count of every table, by name.
+    pub async fn table_rows(&self) -> anyhow::Result<BTreeMap<String, u64>> {
+        match self.send(DbCommand::TableRows).await? {
+            DbResponse::TableRows(tables) => Ok(tables),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Whether any measurements, live or rolled up, are stored for the
+    /// sensor.
+    pub async fn sensor_has_data(&self, model: &str, sensor_id: &str) -> anyhow::Result<bool> {
+        let command = DbCommand::SensorHasData { model: model.to_string(), sensor_id: sensor_id.to_string() };
+        match self.send(command).await? {
+            DbResponse::Exists(exists) => Ok(exists),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Start a rename: it is recorded in `pending_renames` together with
+    /// the rows it covers, those stored so far. Fails while the sensor is
+    /// being renamed already.
+    pub async fn begin_rename(&self, request: RenameRequest) -> anyhow::Result<PendingRename> {
+        match self.send(DbCommand::BeginRename(request)).await? {
+            DbResponse::Renames(mut renames) if renames.len() == 1 => Ok(renames.remove(0)),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Renames started but not finished, to resume at startup.
+    pub async fn pending_renames(&self) -> anyhow::Result<Vec<PendingRename>> {
+        match self.send(DbCommand::PendingRenames).await? {
+            DbResponse::Renames(renames) => Ok(renames),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Carry out a started rename: move the sensor's history to the other
+    /// id and record it in `audit_log`. Rows are rewritten in chunks, one
+    /// command each, so ingestion keeps going meanwhile; rows stored after
+    /// the rename started keep the old id. Every step can be repeated, so
+    /// an interrupted rename is finished by calling this again.
+    pub async fn rename_sensor(&self, pending: &PendingRename) -> anyhow::Result<RenameReport> {
+        let mut total = RenameReport::default();
+        loop {
+            match self.send(DbCommand::RenameSensor(pending.clone())).await? {
+                DbResponse::Renamed { report, done } => {
+                    total.measurements += report.measurements;
+                    total.raw_messages += report.raw_messages;
+                    total.archived_rows += report.archived_rows;
+                    total.archives_rewritten += report.archives_rewritten;
+                    if done {
+                        return Ok(total);
+                    }
+                }
+                DbResponse::Error(e) => return Err(anyhow::anyhow!(e)),
+                other => return Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+            }
+        }
+    }
+
+    /// Delete rows older than `before_ms` (epoch milliseconds), or than
+    /// their sensor's cutoff for the sensors in `sensors`. With
+    /// `archived_only` measurement rows no archive holds yet are kept.
+    pub async fn prune(&self, before_ms: Option<i64>, sensors: Vec<SensorRetention>, archived_only: bool) -> anyhow::Result<PruneReport> {
+        match self.send(DbCommand::Prune { before_ms, sensors, archived_only }).await? {
+            DbResponse::Pruned(report) => Ok(report),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Checkpoint the database so everything written so far is in the main
+    /// file (useful before backups and on shutdown).
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        match self.send(DbCommand::Checkpoint).await? {
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Close the database after the commands queued before this one, see
+    /// `DbCommand::Close`.
+    pub async fn close(&self) -> anyhow::Result<()> {
+        match self.send(DbCommand::Close).await? {
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Column names of `table` in order, empty when there is no such table.
+    pub async fn table_columns(&self, table: &str) -> anyhow::Result<Vec<String>> {
+        match self.send(DbCommand::TableColumns(table.to_string())).await? {
+            DbResponse::Columns(columns) => Ok(columns),
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(anyhow::anyhow!("unexpected DB response: {:?}", other)),
+        }
+    }
+
+    /// Wait for the worker to run a trivial query. Queued commands are
+    /// answered first, so this also measures how far behind the worker is.
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        match self.send(DbCommand::Ping).await? {
+            DbResponse::Error(e) => Err(anyhow::anyhow!(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Open the database, prepare extensions, apply the schema and start the
+/// worker thread. Opening happens on the caller's thread so startup errors
+/// are reported immediately instead of surfacing on the first command.
+///
+/// The exception is a file lock held by another process (a second exporter,
+/// an analyst's `duckdb` shell, ...): the worker then keeps retrying with
+/// backoff for `lock_retry_secs` while commands queue up, and afterwards
+/// reports `DbStatus::Failed` instead of taking the whole exporter down.
+pub fn start_db_worker(config: &DbConfig, registry: &Registry) -> anyhow::Result<(DbHandle, std::thread::JoinHandle<()>)> {
+    let first_attempt = match open_database(config) {
+        Ok(conn) => Some(conn),
+        Err(e) if is_lock_error(&e) => {
+            warn!("DuckDB database {} is locked, retrying for up to {}s: {}", config.path, config.lock_retry_secs, e);
+            None
+        }
+        Err(e) => return Err(e),
+    };
+    let status = Arc::new(RwLock::new(if first_attempt.is_some() { DbStatus::Ready } else { DbStatus::Opening }));
+
+    let capacity = config.queue_capacity.max(1);
+    let queue_full = config.queue_full;
+    let (tx, rx) = bounded::<DbJob>(capacity);
+    let metrics = QueueMetrics::new(registry)?;
+    let config = config.clone();
+    let worker_status = status.clone();
+    let worker_metrics = metrics.clone();
+    let running = Arc::new(Running::default());
+    let worker_running = running.clone();
+    let worker = std::thread::Builder::new()
+        .name("duckdb-worker".to_string())
+        .spawn(move || {
+            let conn = match first_attempt {
+                Some(conn) => conn,
+                None => match open_with_retry(&config) {
+                    Ok(conn) => {
+                        *worker_status.write().unwrap() = DbStatus::Ready;
+                        conn
+                    }
+                    Err(e) => {
+                        let reason = format!("database {} unavailable: {}", config.path, e);
+                        error!("Giving up on DuckDB: {}", reason);
+                        *worker_status.write().unwrap() = DbStatus::Failed(reason.clone());
+                        for job in rx {
+                            worker_metrics.depth.dec();
+                            let _ = job.reply.send(DbResponse::Error(reason.clone()));
+                        }
+                        return;
+                    }
+                },
+            };
+            run_worker(conn, config, rx, worker_metrics, &worker_running)
+        })?;
+
+    Ok((DbHandle { tx, status, slots: Arc::new(Semaphore::new(capacity)), queue_full, metrics, running }, worker))
+}
+
+fn open_database(config: &DbConfig) -> anyhow::Result<Connection> {
+    let conn = Connection::open(&config.path)?;
+    prepare_extensions(&conn, config)?;
+    conn.execute_batch(SCHEMA)?;
+    add_probe_keys(&conn)?;
+    store_measurement_types(&conn)?;
+    create_views(&conn)?;
+    if config.latest_table {
+        prepare_latest_table(&conn)?;
+    }
+    info!("Opened DuckDB database at {}", config.path);
+    Ok(conn)
+}
+
+/// Tables keyed by series, whose primary key gained the `probe` column.
+const PROBE_KEYED: [&str; 3] = ["latest_measurements", "measurements_5m", "measurements_1h"];
+
+/// Rebuild the `PROBE_KEYED` tables of databases created before probes had
+/// a column of their own, since DuckDB can't change a primary key in place.
+/// Their rows keep their values with an empty probe.
+fn add_probe_keys(conn: &Connection) -> anyhow::Result<()> {
+    for table in PROBE_KEYED {
+        let migrated: bool = conn.query_row(
+            "SELECT count(*) > 0 FROM duckdb_columns() WHERE schema_name = 'main' AND table_name = ? AND column_name = 'probe'",
+            [table],
+            |row| row.get(0),
+        )?;
+        if migrated {
+            continue;
+        }
+        info!("Adding the probe column to the key of {}", table);
+        conn.execute_batch(&format!(
+            "BEGIN TRANSACTION; \
+             ALTER TABLE {table} RENAME TO {table}_before_probes; \
+             {SCHEMA} \
+             INSERT INTO {table} BY NAME SELECT * FROM {table}_before_probes; \
+             DROP TABLE {table}_before_probes; \
+             COMMIT;"
+        ))?;
+    }
+    Ok(())
+}
+
+/// Open the database read-only for inspection next to a stopped server,
+/// see `query`. No schema changes, so it also opens databases of older
+/// versions as they are.
+pub fn open_read_only(config: &DbConfig) -> anyhow::Result<Connection> {
+    let flags = duckdb::Config::default().access_mode(duckdb::AccessMode::ReadOnly)?;
+    let conn = Connection::open_with_flags(&config.path, flags).map_err(|e| {
+        let e = anyhow::Error::from(e);
+        if is_lock_error(&e) {
+            anyhow::anyhow!("{}: the database is locked by the running exporter; stop it, or query a copy taken after POST /api/admin/flush", e)
+        } else {
+            anyhow::anyhow!("cannot open {} read-only: {}", config.path, e)
+        }
+    })?;
+    prepare_extensions(&conn, config)?;
+    Ok(conn)
+}
+
+/// Replace the rows of `measurement_types` with the current registry, so
+/// the table always describes the codes this configuration writes.
+fn store_measurement_types(conn: &Connection) -> anyhow::Result<()> {
+    let values: Vec<String> = measurement_types()
+        .iter()
+        .map(|t| {
+            format!(
+                "({}, {}, {}, {}, {})",
+                t.code,
+                sql_literal(&t.key),
+                sql_literal(&t.unit),
+                sql_literal(&t.metric),
+                sql_literal(&t.description)
+            )
+        })
+        .collect();
+    conn.execute_batch(&format!(
+        "BEGIN TRANSACTION; DELETE FROM measurement_types; INSERT INTO measurement_types VALUES {}; COMMIT",
+        values.join(", ")
+    ))?;
+    Ok(())
+}
+
+/// Views over `measurements` for queries without the `measurement_type`
+/// codes, generated from the measurement registry: `readings_<key>` with
+/// the rows of one type (`readings_temperature_C`: `timestamp`, `model`,
+/// `sensor_id`, `site`, `probe`, `value`), and `measurements_wide` with one row per
+/// message and a column per type (NULL where the message had none). Values
+/// are as stored, i.e. in the unit of the sensor's mapping if it has one.
+pub fn views_sql() -> String {
+    let mut sql = String::new();
+    for t in measurement_types() {
+        sql.push_str(&format!(
+            "CREATE OR REPLACE VIEW {} AS\nSELECT timestamp, model, sensor_id, site, probe, value FROM measurements WHERE measurement_type = {};\n",
+            sql_identifier(&format!("readings_{}", t.key)),
+            t.code
+        ));
+    }
+    let columns: Vec<String> = measurement_types()
+        .iter()
+        .map(|t| format!("    any_value(value) FILTER (WHERE measurement_type = {}) AS {}", t.code, sql_identifier(&t.key)))
+        .collect();
+    sql.push_str(&format!(
+        "CREATE OR REPLACE VIEW measurements_wide AS\nSELECT timestamp, model, sensor_id, site, probe,\n{}\nFROM measurements GROUP BY timestamp, model, sensor_id, site, probe;\n",
+        columns.join(",\n")
+    ));
+    sql
+}
+
+/// The whole schema as applied at startup, views included, e.g. for dbt
+/// sources; see `schema`.
+pub fn schema_sql() -> String {
+    format!("{}\n-- Generated from the measurement registry, see `db::views_sql`.\n{}", SCHEMA.trim(), views_sql())
+}
+
+/// (Re)create the views of `views_sql`, dropping `readings_<key>` views of
+/// measurement types that are no longer configured.
+fn create_views(conn: &Connection) -> anyhow::Result<()> {
+    let current: HashSet<String> =
+        measurement_types().iter().map(|t| format!("readings_{}", t.key).to_lowercase()).collect();
+    let mut stmt = conn.prepare(
+        "SELECT view_name FROM duckdb_views() WHERE NOT internal AND schema_name = 'main' AND starts_with(view_name, 'readings_')",
+    )?;
+    let existing: Vec<String> = stmt.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+    for view in existing.iter().filter(|v| !current.contains(&v.to_lowercase())) {
+        conn.execute_batch(&format!("DROP VIEW IF EXISTS {}", sql_identifier(view)))?;
+    }
+    conn.execute_batch(&views_sql())?;
+    Ok(())
+}
+
+/// `name` as a quoted SQL identifier.
+fn sql_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// DuckDB reports a lock held by another process as an IO error along the
+/// lines of `Could not set lock on file "...": Conflicting lock is held`.
+fn is_lock_error(e: &anyhow::Error) -> bool {
+    let msg = e.to_string();
+    msg.contains("Could not set lock") || msg.contains("Conflicting lock")
+}
+
+/// Retry `open_database` with exponential backoff (1s up to 30s) while the
+/// failure is a lock error and `lock_retry_secs` has not run out.
+fn open_with_retry(config: &DbConfig) -> anyhow::Result<Connection> {
+    let deadline = Instant::now() + Duration::from_secs(config.lock_retry_secs);
+    let mut delay = Duration::from_secs(1);
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(anyhow::anyhow!("still locked by another process after {}s", config.lock_retry_secs));
+        }
+        std::thread::sleep(delay.min(deadline - now));
+        match open_database(config) {
+            Ok(conn) => return Ok(conn),
+            Err(e) if is_lock_error(&e) => {
+                warn!("DuckDB database {} still locked: {}", config.path, e);
+                delay = (delay * 2).min(Duration::from_secs(30));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Install and load the configured extensions. An `INSTALL` failure is
+/// only a warning because an earlier install (or a pre-bundled copy in the
+/// extension directory) may still be loadable; a `LOAD` failure aborts
+/// startup since features depending on the extension would break later.
+fn prepare_extensions(conn: &Connection, config: &DbConfig) -> anyhow::Result<()> {
+    if let Some(dir) = &config.extension_directory {
+        conn.execute_batch(&format!("SET extension_directory = {}", sql_literal(dir)))?;
+    }
+    if config.offline {
+        conn.execute_batch("SET autoinstall_known_extensions = false")?;
+    }
+    for ext in &config.extensions {
+        if ext.ends_with(".duckdb_extension") {
+            conn.execute_batch(&format!("LOAD {}", sql_literal(ext)))
+                .map_err(|e| anyhow::anyhow!("failed to load DuckDB extension file {}: {}", ext, e))?;
+            info!("Loaded DuckDB extension from {}", ext);
+            continue;
+        }
+        if !ext.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(anyhow::anyhow!("invalid DuckDB extension name: {:?}", ext));
+        }
+        if !config.offline
+            && let Err(e) = conn.execute_batch(&format!("INSTALL {}", ext))
+        {
+            warn!("INSTALL {} failed, trying an existing copy: {}", ext, e);
+        }
+        conn.execute_batch(&format!("LOAD {}", ext)).map_err(|e| {
+            anyhow::anyhow!(
+                "failed to load DuckDB extension {}: {} (for offline hosts, put pre-downloaded extensions in DUCKDB_EXTENSION_DIR and set DUCKDB_EXTENSIONS_OFFLINE=1)",
+                ext,
+                e
+            )
+        })?;
+        info!("Loaded DuckDB extension {}", ext);
+    }
+    Ok(())
+}
+
+/// Create the staging table for `latest_measurements` upserts and fill
+/// `latest_measurements` from history if it is empty (first enabled, or
+/// wiped by hand).
+fn prepare_latest_table(conn: &Connection) -> anyhow::Result<()> {
+    conn.execute_batch("CREATE TEMP TABLE IF NOT EXISTS latest_staging AS SELECT * FROM measurements LIMIT 0")?;
+    let existing: i64 = conn.query_row("SELECT count(*) FROM latest_measurements", [], |row| row.get(0))?;
+    if existing == 0 {
+        let n = conn.execute(
+            "INSERT INTO latest_measurements \
+             SELECT sensor_id, model, measurement_type, max(timestamp), arg_max(value, timestamp), arg_max(topic, timestamp), \
+             COALESCE(probe, '') FROM measurements GROUP BY sensor_id, model, measurement_type, COALESCE(probe, '')",
+            [],
+        )?;
+        info!("Filled latest_measurements with {} series", n);
+    }
+    Ok(())
+}
+
+/// Append a batch of measurement rows and upsert the newest row per
+/// series into `latest_measurements`, in one transaction.
+fn append_measurements_with_latest(conn: &Connection, batch: RecordBatch) -> anyhow::Result<()> {
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let result = (|| -> anyhow::Result<()> {
+        let mut appender = conn.appender("measurements")?;
+        appender.append_record_batch(batch.clone())?;
+        appender.flush()?;
+        drop(appender);
+        let mut staging = conn.appender_to_catalog_and_db("latest_staging", "temp", "main")?;
+        staging.append_record_batch(batch)?;
+        staging.flush()?;
+        drop(staging);
+        // Rows may arrive out of order (imports, rtl_433 `time`), so only
+        // move a series forward in time.
+        conn.execute_batch(
+            "INSERT INTO latest_measurements \
+             SELECT sensor_id, model, measurement_type, max(timestamp), arg_max(value, timestamp), arg_max(topic, timestamp), probe \
+             FROM latest_staging GROUP BY sensor_id, model, measurement_type, probe \
+             ON CONFLICT (sensor_id, model, measurement_type, probe) DO UPDATE \
+             SET timestamp = excluded.timestamp, value = excluded.value, topic = excluded.topic \
+             WHERE excluded.timestamp >= latest_measurements.timestamp; \
+             DELETE FROM latest_staging;",
+        )?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => conn.execute_batch("COMMIT")?,
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK");
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Hands out row sequence numbers. Rows are numbered as the worker appends
+/// them, not when they are normalized, so `seq` follows commit order: once
+/// a consumer (sync, rollups, archives) has seen seq N, no row with a lower
+/// number can appear later. Numbers are the current time in microseconds,
+/// or the last one + 1 when that is not larger, starting above the highest
+/// stored value so a clock that went backwards cannot repeat them.
+struct Sequencer {
+    last: u64,
+}
+
+impl Sequencer {
+    fn load(conn: &Connection) -> anyhow::Result<Self> {
+        let last: Option<u64> = conn.query_row("SELECT max(seq) FROM measurements", [], |row| row.get(0))?;
+        Ok(Self { last: last.unwrap_or_default() })
+    }
+
+    fn next(&mut self) -> u64 {
+        let now = chrono::Utc::now().timestamp_micros().max(0) as u64;
+        self.last = now.max(self.last + 1);
+        self.last
+    }
+
+    /// Replace the `seq` column of a measurements batch with new numbers.
+    fn stamp(&mut self, batch: RecordBatch) -> anyhow::Result<RecordBatch> {
+        let Ok(index) = batch.schema().index_of("seq") else { return Ok(batch) };
+        let mut columns: Vec<ArrayRef> = batch.columns().to_vec();
+        columns[index] = Arc::new(UInt64Array::from_iter_values((0..batch.num_rows()).map(|_| self.next())));
+        Ok(RecordBatch::try_new(batch.schema(), columns)?)
+    }
+}
+
+fn run_worker(conn: Connection, config: DbConfig, rx: Receiver<DbJob>, metrics: QueueMetrics, running: &Running) {
+    let _ = running.interrupt.set(conn.interrupt_handle());
+    let mut sequencer = match Sequencer::load(&conn) {
+        Ok(sequencer) => sequencer,
+        Err(e) => {
+            error!("Cannot read the highest stored seq, numbering from the clock: {}", e);
+            Sequencer { last: 0 }
+        }
+    };
+    for job in rx.iter() {
+        metrics.depth.dec();
+        drop(job.slot);
+        if let DbCommand::Close = job.command {
+            let response = match conn.close() {
+                Ok(()) => DbResponse::Ok,
+                Err((_, e)) => DbResponse::Error(e.to_string()),
+            };
+            info!("DB worker stopped, database closed");
+            let _ = job.reply.send(response);
+            return;
+        }
+        if job.id != 0 {
+            // Given up on while it waited in the queue.
+            if job.reply.is_closed() {
+                continue;
+            }
+            *running.job.lock().unwrap() = job.id;
+        }
+        let result = handle_command(&conn, &config, &mut sequencer, job.command);
+        if job.id != 0 {
+            *running.job.lock().unwrap() = 0;
+        }
+        let response = match result {
+            Ok(r) => r,
+            Err(e) => {
+                // Other callers log failures themselves; a request only
+                // reports them, so tie its failure to the request here.
+                if let Some(request_id) = &job.request_id {
+                    warn!(request_id = %request_id, "DB command failed: {}", e);
+                }
+                DbResponse::Error(e.to_string())
+            }
+        };
+        // The requester may have given up waiting; that's not an error here.
+        let _ = job.reply.send(response);
+    }
+    info!("DB worker stopped");
+}
+
+/// SQL and parameters of a `RowQuery` page.
+fn row_query_sql(conn: &Connection, mut query: RowQuery) -> anyhow::Result<(String, Vec<Value>)> {
+    // Rows before the cursor's millisecond can't follow it.
+    if let Some(cursor) = &query.after {
+        query.selection.start_ms = query.selection.start_ms.max(cursor.timestamp_ms);
+    }
+    let (start, end) = query.selection.bounds_micros();
+    let archives = overlapping_archives(conn, start, end)?;
+    let (inner, mut params) = query.selection.select_sql(&archives);
+    let mut sql =
+        format!("SELECT * FROM ({}) q(ts_ms, sensor_id, model, measurement_type, value, topic, seq, site, probe)", inner);
+    if let Some(cursor) = &query.after {
+        sql.push_str(" WHERE (ts_ms, seq, sensor_id, model, measurement_type, site, probe) > (?, ?, ?, ?, ?, ?, ?)");
+        params.extend([
+            Value::BigInt(cursor.timestamp_ms),
+            Value::UBigInt(cursor.seq),
+            Value::Text(cursor.sensor_id.clone()),
+            Value::Text(cursor.model.clone()),
+            Value::UTinyInt(cursor.measurement_type),
+            Value::Text(cursor.site.clone()),
+            Value::Text(cursor.probe.clone()),
+        ]);
+    }
+    sql.push_str(" ORDER BY ts_ms, seq, sensor_id, model, measurement_type, site, probe LIMIT ?");
+    params.push(Value::BigInt(query.limit.min(i64::MAX as usize) as i64));
+    Ok((sql, params))
+}
+
+/// SQL of a `SeriesQuery` page. Buckets come from DuckDB's `time_bucket`,
+/// so they are aligned to multiples of the step from 2000-01-03 (a Monday;
+/// the epoch for steps dividing a day).
+fn series_query_sql(conn: &Connection, mut query: SeriesQuery) -> anyhow::Result<(String, Vec<Value>)> {
+    // Rows before the cursor's bucket can't be in a later one.
+    if let Some(cursor) = &query.after {
+        query.selection.start_ms = query.selection.start_ms.max(cursor.bucket_ms);
+    }
+    let (start, end) = query.selection.bounds_micros();
+    let archives = overlapping_archives(conn, start, end)?;
+    let (inner, inner_params) = query.selection.select_sql(&archives);
+    let mut sql = format!(
+        "SELECT bucket_ms, sensor_id, model, measurement_type, site, min(value), max(value), avg(value), count(*), probe \
+         FROM (SELECT CAST(epoch_ms(time_bucket(to_milliseconds(CAST(? AS BIGINT)), make_timestamp(ts_ms * 1000))) AS BIGINT) \
+               AS bucket_ms, sensor_id, model, measurement_type, site, probe, value \
+               FROM ({}) q(ts_ms, sensor_id, model, measurement_type, value, topic, seq, site, probe))",
+        inner
+    );
+    let mut params = vec![Value::BigInt(query.step_ms)];
+    params.extend(inner_params);
+    if let Some(cursor) = &query.after {
+        sql.push_str(" WHERE (bucket_ms, sensor_id, model, measurement_type, site, probe) > (?, ?, ?, ?, ?, ?)");
+        params.extend([
+            Value::BigInt(cursor.bucket_ms),
+            Value::Text(cursor.sensor_id.clone()),
+            Value::Text(cursor.model.clone()),
+            Value::UTinyInt(cursor.measurement_type),
+            Value::Text(cursor.site.clone()),
+            Value::Text(cursor.probe.clone()),
+        ]);
+    }
+    sql.push_str(" GROUP BY ALL ORDER BY bucket_ms, sensor_id, model, measurement_type, site, probe LIMIT ?");
+    params.push(Value::BigInt(query.limit.min(i64::MAX as usize) as i64));
+    Ok((sql, params))
+}
+
+fn handle_command(conn: &Connection, config: &DbConfig, sequencer: &mut Sequencer, command: DbCommand) -> anyhow::Result<DbResponse> {
+    match command {
+        DbCommand::Execute(sql) => {
+            conn.execute_batch(&sql)?;
+            Ok(DbResponse::Ok)
+        }
+        DbCommand::Append { table, batch } => {
+            let n = batch.num_rows();
+            let batch = if table == "measurements" { sequencer.stamp(batch)? } else { batch };
+            if table == "measurements" && config.latest_table {
+                append_measurements_with_latest(conn, batch)?;
+                return Ok(DbResponse::Appended(n));
+            }
+            let mut appender = conn.appender(&table)?;
+            appender.append_record_batch(batch)?;
+            appender.flush()?;
+            Ok(DbResponse::Appended(n))
+        }
+        DbCommand::SelectMeasurements(query) => {
+            let (start, end) = query.bounds_micros();
+            let archives = overlapping_archives(conn, start, end)?;
+            let (sql, params) = query.to_sql(&archives);
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(params_from_iter(params), measurement_row)?;
+            Ok(DbResponse::Measurements(rows.collect::<Result<_, _>>()?))
+        }
+        DbCommand::QueryPage(query) => {
+            let (sql, params) = row_query_sql(conn, query)?;
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(params_from_iter(params), measurement_row)?;
+            Ok(DbResponse::Measurements(rows.collect::<Result<_, _>>()?))
+        }
+        DbCommand::QueryArrow { sql, params } => {
+            let mut stmt = conn.prepare(&sql)?;
+            Ok(DbResponse::Batches(stmt.query_arrow(params_from_iter(params))?.collect()))
+        }
+        DbCommand::QueryRecords { sql, params } => {
+            let mut stmt = conn.prepare(&sql)?;
+            let mut rows = stmt.query(params_from_iter(params))?;
+            let columns = rows.as_ref().map(|stmt| stmt.column_names()).unwrap_or_default();
+            let mut records = Vec::new();
+            while let Some(row) = rows.next()? {
+                let mut record = serde_json::Map::new();
+                for (i, column) in columns.iter().enumerate() {
+                    record.insert(column.clone(), json_value(row.get(i)?));
+                }
+                records.push(record);
+            }
+            Ok(DbResponse::Records(records))
+        }
+        DbCommand::Explain { target, analyze } => {
+            let (sql, params) = match target {
+                ExplainTarget::Sql(sql) => (sql, Vec::new()),
+                ExplainTarget::Rows(query) => row_query_sql(conn, query)?,
+            };
+            let explain = if analyze { "EXPLAIN ANALYZE" } else { "EXPLAIN" };
+            let mut stmt = conn.prepare(&format!("{} {}", explain, sql))?;
+            // Rows of (explain_key, explain_value); the plan is the value.
+            let parts = stmt.query_map(params_from_iter(params), |row| row.get::<_, String>(1))?;
+            Ok(DbResponse::Plan(parts.collect::<Result<Vec<_>, _>>()?.join("\n")))
+        }
+        DbCommand::SyncRows { after_seq, limit } => {
+            // Archives are left out: sync replicates the live table, and
+            // archived slices predate any consumer worth resuming.
+            let mut stmt = conn.prepare(
+                "SELECT CAST(epoch_ms(timestamp) AS BIGINT), sensor_id, model, measurement_type, value, COALESCE(topic, ''), seq, COALESCE(site, ''), \
+                 COALESCE(probe, '') FROM measurements WHERE seq > ? ORDER BY seq LIMIT ?",
+            )?;
+            let rows = stmt.query_map(
+                params_from_iter([Value::UBigInt(after_seq), Value::BigInt(limit.min(i64::MAX as usize) as i64)]),
+                measurement_row,
+            )?;
+            Ok(DbResponse::Measurements(rows.collect::<Result<_, _>>()?))
+        }
+        DbCommand::SyncCursor(site) => {
+            let seq: Option<u64> = conn
+                .query_row("SELECT last_seq FROM sync_cursors WHERE site = ?", [&site], |row| row.get(0))
+                .optional()?;
+            Ok(DbResponse::Seq(seq.unwrap_or_default()))
+        }
+        DbCommand::MergeSynced { site, url, batch, last_seq } => {
+            let n = batch.num_rows();
+            let batch = sequencer.stamp(batch)?;
+            conn.execute_batch("BEGIN TRANSACTION")?;
+            let result = (|| -> anyhow::Result<()> {
+                let mut appender = conn.appender("measurements")?;
+                appender.append_record_batch(batch)?;
+                appender.flush()?;
+                drop(appender);
+                conn.execute(
+                    "INSERT INTO sync_cursors (site, url, last_seq) VALUES (?, ?, ?) \
+                     ON CONFLICT (site) DO UPDATE SET url = excluded.url, last_seq = excluded.last_seq, updated_at = now()",
+                    duckdb::params![site, url, last_seq],
+                )?;
+                Ok(())
+            })();
+            match result {
+                Ok(()) => conn.execute_batch("COMMIT")?,
+                Err(e) => {
+                    let _ = conn.execute_batch("ROLLBACK");
+                    return Err(e);
+                }
+            }
+            Ok(DbResponse::Appended(n))
+        }
+        DbCommand::BeginRename(request) => {
+            let pending = PendingRename { request, until_seq: sequencer.last, until_ms: chrono::Utc::now().timestamp_millis() };
+            let r = &pending.request;
+            conn.execute(
+                "INSERT INTO pending_renames VALUES (?, ?, ?, ?, ?, ?, ?)",
+                duckdb::params![r.model, r.sensor_id, r.new_model, r.new_sensor_id, r.merge, pending.until_seq, pending.until_ms],
+            )
+            .map_err(|e| match e {
+                duckdb::Error::DuckDBFailure(_, Some(message)) if message.contains("Constraint Error") => {
+                    anyhow::anyhow!("a rename of {}::{} is already under way", r.model, r.sensor_id)
+                }
+                e => e.into(),
+            })?;
+            Ok(DbResponse::Renames(vec![pending]))
+        }
+        DbCommand::PendingRenames => {
+            let mut stmt = conn.prepare(
+                "SELECT model, sensor_id, new_model, new_sensor_id, merge, until_seq, until_ms FROM pending_renames ORDER BY model, sensor_id",
+            )?;
+            let renames = stmt.query_map([], |row| {
+                Ok(PendingRename {
+                    request: RenameRequest {
+                        model: row.get(0)?,
+                        sensor_id: row.get(1)?,
+                        new_model: row.get(2)?,
+                        new_sensor_id: row.get(3)?,
+                        merge: row.get(4)?,
+                    },
+                    until_seq: row.get(5)?,
+                    until_ms: row.get(6)?,
+                })
+            })?;
+            Ok(DbResponse::Renames(renames.collect::<Result<_, _>>()?))
+        }
+        DbCommand::Rollup => Ok(DbResponse::Appended(rollup(conn)?)),
+        DbCommand::QueryRollups(query) => {
+            let (filter, mut params) = query.selection.filter_sql("bucket");
+            let sql = format!(
+                "SELECT CAST(epoch_ms(bucket) AS BIGINT), sensor_id, model, measurement_type, site, min, max, avg, count, probe \
+                 FROM {} WHERE {} ORDER BY bucket, sensor_id, model, measurement_type, site, probe LIMIT ?",
+                query.resolution.table(),
+                filter
+            );
+            params.push(Value::BigInt(query.limit.min(i64::MAX as usize) as i64));
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(params_from_iter(params), rollup_row)?;
+            Ok(DbResponse::Rollups(rows.collect::<Result<_, _>>()?))
+        }
+        DbCommand::QuerySeries(query) => {
+            let (sql, params) = series_query_sql(conn, query)?;
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(params_from_iter(params), rollup_row)?;
+            Ok(DbResponse::Rollups(rows.collect::<Result<_, _>>()?))
+        }
+        DbCommand::SelectRaw(query) => {
+            let mut stmt = conn.prepare(
+                "SELECT CAST(epoch_us(received_at) AS BIGINT), topic, sensor_id, model, raw_json, encrypted \
+                 FROM raw_messages \
+                 WHERE received_at >= make_timestamp(CAST(? AS BIGINT)) \
+                 AND received_at <= make_timestamp(CAST(? AS BIGINT)) \
+                 AND (CAST(? AS VARCHAR) IS NULL OR sensor_id = ?) \
+                 AND (CAST(? AS VARCHAR) IS NULL OR model = ?) \
+                 ORDER BY received_at",
+            )?;
+            let params: Vec<Value> = vec![
+                Value::BigInt(ms_to_micros(query.start_ms)),
+                Value::BigInt(ms_to_micros(query.end_ms)),
+                query.sensor_id.clone().map_or(Value::Null, Value::Text),
+                query.sensor_id.map_or(Value::Null, Value::Text),
+                query.model.clone().map_or(Value::Null, Value::Text),
+                query.model.map_or(Value::Null, Value::Text),
+            ];
+            let messages = stmt.query_map(params_from_iter(params), |row| {
+                let us: i64 = row.get(0)?;
+                Ok(RawMessage {
+                    received_at: DateTime::from_timestamp_micros(us).unwrap_or_default().naive_utc(),
+                    topic: row.get(1)?,
+                    sensor_id: row.get(2)?,
+                    model: row.get(3)?,
+                    raw_json: row.get(4)?,
+                    encrypted: row.get(5)?,
+                })
+            })?;
+            Ok(DbResponse::RawMessages(messages.collect::<Result<_, _>>()?))
+        }
+        DbCommand::RegisterArchive(path) => Ok(DbResponse::Archives(register_archive(conn, &path, None)?)),
+        DbCommand::Export(request) => {
+            let slice = ArchiveSlice {
+                path: request.path,
+                start_micros: request.start_ms.map(ms_to_micros),
+                end_micros: Some(ms_to_micros(request.end_ms)),
+                max_seq: Some(sequencer.last),
+            };
+            // Rows already held by an archive are not exported again,
+            // rows that arrived late for an archived range are.
+            let (start, end) = (slice.start_micros.unwrap_or(i64::MIN), slice.end_micros.unwrap_or(i64::MAX));
+            let unarchived =
+                format!("{} AND NOT {}", slice.holds_sql(), archived_sql(&overlapping_archives(conn, start, end)?));
+            let rows: i64 =
+                conn.query_row(&format!("SELECT count(*) FROM measurements WHERE {}", unarchived), [], |row| row.get(0))?;
+            if rows == 0 {
+                return Ok(DbResponse::Archives(Vec::new()));
+            }
+            conn.execute_batch(&format!(
+                "COPY (SELECT * FROM measurements WHERE {} ORDER BY timestamp) TO {} (FORMAT parquet, COMPRESSION zstd)",
+                unarchived,
+                sql_literal(&slice.path)
+            ))?;
+            Ok(DbResponse::Archives(register_archive(conn, &slice.path, Some(&slice))?))
+        }
+        DbCommand::ListArchives => Ok(DbResponse::Archives(list_archives(conn, None)?)),
+        DbCommand::ExportQuery(export) => {
+            let (start, end) = export.selection.bounds_micros();
+            let archives = overlapping_archives(conn, start, end)?;
+            let (sql, params) = export.selection.select_sql(&archives);
+            // `select_sql` reads the timestamp as epoch ms for `measurement_row`.
+            let copy = format!(
+                "COPY (SELECT epoch_ms(ts_ms) AS timestamp, sensor_id, model, measurement_type, t.name AS measurement, value, topic, seq, site, probe \
+                 FROM ({}) q(ts_ms, sensor_id, model, measurement_type, value, topic, seq, site, probe) \
+                 LEFT JOIN measurement_types t ON q.measurement_type = t.code \
+                 ORDER BY timestamp, sensor_id, model, measurement_type) TO {} ({})",
+                sql,
+                sql_literal(&export.path),
+                export.format.copy_options()
+            );
+            let written = conn.execute(&copy, params_from_iter(params))?;
+            Ok(DbResponse::Appended(written))
+        }
+        DbCommand::Annotate(a) => {
+            conn.execute(
+                "INSERT INTO annotations (model, sensor_id, kind, note, linked_sensor_id) VALUES (?, ?, ?, ?, ?)",
+                duckdb::params![a.model, a.sensor_id, a.kind, a.note, a.linked_sensor_id],
+            )?;
+            Ok(DbResponse::Ok)
+        }
+        DbCommand::RecordTransitions(transitions) => {
+            let mut stmt = conn.prepare(
+                "INSERT INTO state_transitions VALUES (make_timestamp(CAST(? AS BIGINT)), ?, ?, ?, ?, ?)",
+            )?;
+            for t in &transitions {
+                let micros = t.timestamp.and_utc().timestamp_micros();
+                stmt.execute(duckdb::params![micros, t.state, t.model, t.sensor_id, t.active, t.value])?;
+            }
+            Ok(DbResponse::Appended(transitions.len()))
+        }
+        DbCommand::QueryTransitions(query) => {
+            let mut stmt = conn.prepare(
+                "SELECT CAST(epoch_ms(timestamp) AS BIGINT), state, model, sensor_id, active, value FROM state_transitions \
+                 WHERE timestamp >= make_timestamp(CAST(? AS BIGINT)) AND timestamp <= make_timestamp(CAST(? AS BIGINT)) \
+                 AND (? IS NULL OR state = ?) ORDER BY timestamp LIMIT ?",
+            )?;
+            let state = query.state.as_deref();
+            let params = duckdb::params![
+                ms_to_micros(query.start_ms),
+                ms_to_micros(query.end_ms),
+                state,
+                state,
+                query.limit.min(i64::MAX as usize) as i64
+            ];
+            let rows = stmt.query_map(params, state_transition)?;
+            Ok(DbResponse::Transitions(rows.collect::<Result<_, _>>()?))
+        }
+        DbCommand::CurrentStates => {
+            let mut stmt = conn.prepare(
+                "SELECT CAST(epoch_ms(timestamp) AS BIGINT), state, model, sensor_id, active, value FROM state_transitions \
+                 QUALIFY row_number() OVER (PARTITION BY state, model, sensor_id ORDER BY timestamp DESC) = 1",
+            )?;
+            let rows = stmt.query_map([], state_transition)?;
+            Ok(DbResponse::Transitions(rows.collect::<Result<_, _>>()?))
+        }
+        DbCommand::ComputeDegreeDays(request) => {
+            let (Some(start_ms), Some(end_ms)) =
+                (request.days.iter().map(|d| d.1).min(), request.days.iter().map(|d| d.2).max())
+            else {
+                return Ok(DbResponse::Appended(0));
+            };
+            // Over `select_sql`, so days whose readings were archived keep
+            // their values when they are recomputed.
+            let selection = MeasurementQuery {
+                start_ms,
+                end_ms: end_ms - 1,
+                measurement_types: vec![request.measurement_type],
+                conditions: vec![
+                    LabelCondition::Eq(LabelColumn::Model, request.model.clone()),
+                    LabelCondition::Eq(LabelColumn::SensorId, request.sensor_id.clone()),
+                    LabelCondition::Eq(LabelColumn::Probe, String::new()),
+                ],
+            };
+            let (start, end) = selection.bounds_micros();
+            let archives = overlapping_archives(conn, start, end)?;
+            let (inner, inner_params) = selection.select_sql(&archives);
+            // The day ranges are numbers and formatted dates only.
+            let days: Vec<String> =
+                request.days.iter().map(|(day, start, end)| format!("('{}', {}, {})", day, start, end)).collect();
+            let sql = format!(
+                "INSERT OR REPLACE INTO degree_days \
+                 SELECT CAST(d.day AS DATE), m.model, m.sensor_id, avg(m.value), \
+                 greatest(? - avg(m.value), 0), greatest(avg(m.value) - ?, 0), count(*) \
+                 FROM (VALUES {}) d(day, start_ms, end_ms) \
+                 JOIN ({}) m(ts_ms, sensor_id, model, measurement_type, value, topic, seq, site, probe) \
+                 ON m.ts_ms >= d.start_ms AND m.ts_ms < d.end_ms \
+                 WHERE m.site = '' \
+                 GROUP BY d.day, m.model, m.sensor_id",
+                days.join(", "),
+                inner
+            );
+            let mut params = vec![Value::Double(request.heating_base), Value::Double(request.cooling_base)];
+            params.extend(inner_params);
+            let written = conn.execute(&sql, params_from_iter(params))?;
+            Ok(DbResponse::Appended(written))
+        }
+        DbCommand::Report(query) => Ok(DbResponse::Activity(report(conn, &query)?)),
+        DbCommand::RecordLabels(sensor) => {
+            let (names, values): (Vec<&String>, Vec<&String>) = sensor.labels.iter().unzip();
+            let list = |n: usize| vec!["?"; n].join(", ");
+            let sql = format!(
+                "INSERT INTO sensor_labels SELECT ?, ?, \
+                 CASE WHEN EXISTS (SELECT 1 FROM sensor_labels WHERE model = ? AND sensor_id = ?) \
+                 THEN make_timestamp(CAST(? AS BIGINT)) ELSE TIMESTAMP '1970-01-01' END, \
+                 MAP([{}]::VARCHAR[], [{}]::VARCHAR[])",
+                list(names.len()),
+                list(values.len())
+            );
+            let now = chrono::Utc::now().timestamp_micros().to_string();
+            let mut params: Vec<&String> = vec![&sensor.model, &sensor.sensor_id, &sensor.model, &sensor.sensor_id, &now];
+            params.extend(names);
+            params.extend(values);
+            conn.execute(&sql, params_from_iter(params))?;
+            Ok(DbResponse::Ok)
+        }
+        DbCommand::CurrentLabels => {
+            let mut stmt = conn.prepare(
+                "SELECT model, sensor_id, unnest(map_keys(labels)), unnest(map_values(labels)) FROM ( \
+                     SELECT * FROM sensor_labels \
+                     QUALIFY row_number() OVER (PARTITION BY model, sensor_id ORDER BY valid_from DESC) = 1)",
+            )?;
+            let mut rows = stmt.query([])?;
+            let mut current: Vec<SensorLabels> = Vec::new();
+            while let Some(row) = rows.next()? {
+                let (model, sensor_id): (String, String) = (row.get(0)?, row.get(1)?);
+                let (name, value): (String, String) = (row.get(2)?, row.get(3)?);
+                match current.last_mut() {
+                    Some(last) if last.model == model && last.sensor_id == sensor_id => {
+                        last.labels.insert(name, value);
+                    }
+                    _ => current.push(SensorLabels { model, sensor_id, labels: BTreeMap::from([(name, value)]) }),
+                }
+            }
+            Ok(DbResponse::Labels(current))
+        }
+        DbCommand::PurgeSensor(request) => Ok(DbResponse::Purged(purge_sensor(conn, &request)?)),
+        DbCommand::TableRows => {
+            let mut stmt = conn.prepare("SELECT table_name FROM duckdb_tables() WHERE schema_name = 'main' AND NOT temporary")?;
+            let names: Vec<String> = stmt.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+            let mut tables = BTreeMap::new();
+            for name in names {
+                let rows: u64 = conn.query_row(&format!("SELECT count(*) FROM {}", sql_identifier(&name)), [], |row| row.get(0))?;
+                tables.insert(name, rows);
+            }
+            Ok(DbResponse::TableRows(tables))
+        }
+        DbCommand::SensorHasData { model, sensor_id } => {
+            let exists: bool = conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM measurements WHERE model = ?1 AND sensor_id = ?2) \
+                 OR EXISTS (SELECT 1 FROM measurements_1h WHERE model = ?1 AND sensor_id = ?2) \
+                 OR EXISTS (SELECT 1 FROM latest_measurements WHERE model = ?1 AND sensor_id = ?2)",
+                [&model, &sensor_id],
+                |row| row.get(0),
+            )?;
+            Ok(DbResponse::Exists(exists))
+        }
+        DbCommand::RenameSensor(pending) => {
+            let (report, done) = rename_sensor_step(conn, &pending)?;
+            Ok(DbResponse::Renamed { report, done })
+        }
+        DbCommand::Prune { before_ms, sensors, archived_only } => {
+            Ok(DbResponse::Pruned(prune(conn, before_ms, &sensors, archived_only)?))
+        }
+        DbCommand::Checkpoint => {
+            conn.execute_batch("CHECKPOINT")?;
+            Ok(DbResponse::Ok)
+        }
+        DbCommand::Ping => {
+            conn.execute_batch("SELECT 1")?;
+            Ok(DbResponse::Ok)
+        }
+        DbCommand::TableColumns(table) => {
+            let mut stmt = conn.prepare(
+                "SELECT column_name FROM duckdb_columns() WHERE schema_name = 'main' AND table_name = ? ORDER BY column_index",
+            )?;
+            let columns = stmt.query_map([table], |row| row.get(0))?.collect::<Result<_, _>>()?;
+            Ok(DbResponse::Columns(columns))
+        }
+        DbCommand::Close => unreachable!("handled by run_worker"),
+    }
+}
+
+/// Add (or refresh) a Parquet file in the archive manifest.
+/// Add a Parquet file to the manifest. `slice` describes the live rows an
+/// export wrote to it; a file registered by hand is assumed to hold the
+/// live rows of its time span up to its highest `seq` (none if it has no
+/// `seq` column).
+fn register_archive(conn: &Connection, path: &str, slice: Option<&ArchiveSlice>) -> anyhow::Result<Vec<ArchiveEntry>> {
+    let path = std::fs::canonicalize(path)?.to_string_lossy().into_owned();
+    let file = sql_literal(&path);
+    let holds = match slice {
+        Some(slice) => format!(
+            "{}, make_timestamp({}), {}",
+            slice.start_micros.map_or("NULL".to_string(), |start| format!("make_timestamp({})", start)),
+            slice.end_micros.unwrap_or(i64::MAX),
+            slice.max_seq.map_or("NULL".to_string(), |seq| seq.to_string())
+        ),
+        None => "min(timestamp), max(timestamp) + INTERVAL 1 MICROSECOND, max(seq)".to_string(),
+    };
+    conn.execute(
+        &format!(
+            "INSERT OR REPLACE INTO parquet_archive (path, min_timestamp, max_timestamp, row_count, slice_start, slice_end, max_seq) \
+             SELECT ?, min(timestamp), max(timestamp), count(*), {} \
+             FROM (SELECT NULL::UBIGINT AS seq WHERE false UNION ALL BY NAME SELECT * FROM read_parquet({}))",
+            holds, file
+        ),
+        [&path],
+    )?;
+    list_archives(conn, Some(&path))
+}
+
+fn list_archives(conn: &Connection, path: Option<&str>) -> anyhow::Result<Vec<ArchiveEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT path, CAST(epoch_us(min_timestamp) AS BIGINT), CAST(epoch_us(max_timestamp) AS BIGINT), row_count \
+         FROM parquet_archive WHERE CAST(? AS VARCHAR) IS NULL OR path = ? ORDER BY min_timestamp",
+    )?;
+    let rows = stmt.query_map([path, path], |row| {
+        let micros = |us: i64| DateTime::from_timestamp_micros(us).unwrap_or_default().naive_utc();
+        Ok(ArchiveEntry {
+            path: row.get(0)?,
+            min_timestamp: micros(row.get(1)?),
+            max_timestamp: micros(row.get(2)?),
+            row_count: row.get(3)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// A DuckDB value as JSON for `DbHandle::query_as`. Timestamps, dates and
+/// times become the ISO 8601 strings `chrono` deserializes, values that JSON
+/// cannot hold (huge integers, decimals, intervals) strings or floats.
+pub fn json_value(value: Value) -> serde_json::Value {
+    use serde_json::Value as Json;
+    match value {
+        Value::Null => Json::Null,
+        Value::Boolean(b) => Json::Bool(b),
+        Value::TinyInt(n) => n.into(),
+        Value::SmallInt(n) => n.into(),
+        Value::Int(n) => n.into(),
+        Value::BigInt(n) => n.into(),
+        Value::HugeInt(n) => i64::try_from(n).map(Json::from).unwrap_or_else(|_| Json::String(n.to_string())),
+        Value::UTinyInt(n) => n.into(),
+        Value::USmallInt(n) => n.into(),
+        Value::UInt(n) => n.into(),
+        Value::UBigInt(n) => n.into(),
+        Value::Float(f) => serde_json::Number::from_f64(f as f64).map_or(Json::Null, Json::Number),
+        Value::Double(f) => serde_json::Number::from_f64(f).map_or(Json::Null, Json::Number),
+        Value::Decimal(d) => {
+            d.to_string().parse().ok().and_then(serde_json::Number::from_f64).map_or(Json::Null, Json::Number)
+        }
+        Value::Timestamp(unit, t) => DateTime::from_timestamp_micros(unit.to_micros(t))
+            .map_or(Json::Null, |t| Json::String(t.naive_utc().format("%Y-%m-%dT%H:%M:%S%.f").to_string())),
+        Value::Date32(days) => NaiveDate::from_epoch_days(days).map_or(Json::Null, |d| Json::String(d.to_string())),
+        Value::Time64(unit, t) => {
+            let micros = unit.to_micros(t);
+            chrono::NaiveTime::from_num_seconds_from_midnight_opt(
+                (micros / 1_000_000) as u32,
+                (micros % 1_000_000 * 1000) as u32,
+            )
+            .map_or(Json::Null, |t| Json::String(t.to_string()))
+        }
+        Value::Interval { months, days, nanos } => Json::String(format!("{} months {} days {} ns", months, days, nanos)),
+        Value::Text(s) | Value::Enum(s) => Json::String(s),
+        Value::Blob(bytes) => bytes.into(),
+        Value::List(values) | Value::Array(values) => Json::Array(values.into_iter().map(json_value).collect()),
+        Value::Struct(fields) => {
+            Json::Object(fields.iter().map(|(name, value)| (name.clone(), json_value(value.clone()))).collect())
+        }
+        Value::Map(entries) => Json::Object(
+            entries
+                .iter()
+                .map(|(key, value)| {
+                    let key = match json_value(key.clone()) {
+                        Json::String(s) => s,
+                        other => other.to_string(),
+                    };
+                    (key, json_value(value.clone()))
+                })
+                .collect(),
+        ),
+        Value::Union(value) => json_value(*value),
+    }
+}
+
+/// A rollup table row, or a `SeriesQuery` bucket, with the bucket as epoch
+/// milliseconds.
+fn rollup_row(row: &duckdb::Row) -> duckdb::Result<RollupRow> {
+    let ms: i64 = row.get(0)?;
+    Ok(RollupRow {
+        bucket: DateTime::from_timestamp_millis(ms).unwrap_or_default().naive_utc(),
+        sensor_id: row.get(1)?,
+        model: row.get(2)?,
+        measurement_type: row.get(3)?,
+        site: row.get(4)?,
+        min: row.get(5)?,
+        max: row.get(6)?,
+        avg: row.get(7)?,
+        count: row.get(8)?,
+        probe: row.get(9)?,
+    })
+}
+
+/// Map a row selected by `MeasurementQuery::select_sql`.
+fn measurement_row(row: &duckdb::Row) -> duckdb::Result<NormalizedRow> {
+    let ms: i64 = row.get(0)?;
+    Ok(NormalizedRow {
+        timestamp: DateTime::from_timestamp_millis(ms).unwrap_or_default().naive_utc(),
+        sensor_id: row.get(1)?,
+        model: row.get(2)?,
+        measurement_type: row.get(3)?,
+        value: row.get(4)?,
+        topic: row.get(5)?,
+        seq: row.get(6)?,
+        site: row.get(7)?,
+        probe: row.get(8)?,
+    })
+}
+
+/// Archived slices overlapping `[start, end]` (microseconds) whose files
+/// still exist. Missing files are skipped with a warning rather than failing
+/// every query that touches their range.
+fn overlapping_archives(conn: &Connection, start: i64, end: i64) -> anyhow::Result<Vec<ArchiveSlice>> {
+    let mut stmt = conn.prepare(
+        "SELECT path, CAST(epoch_us(slice_start) AS BIGINT), CAST(epoch_us(slice_end) AS BIGINT), max_seq \
+         FROM parquet_archive \
+         WHERE max_timestamp >= make_timestamp(CAST(? AS BIGINT)) AND min_timestamp <= make_timestamp(CAST(? AS BIGINT))",
+    )?;
+    let slices = stmt.query_map([start, end], |row| {
+        Ok(ArchiveSlice { path: row.get(0)?, start_micros: row.get(1)?, end_micros: row.get(2)?, max_seq: row.get(3)? })
+    })?;
+    let mut existing = Vec::new();
+    for slice in slices {
+        let slice = slice?;
+        if std::path::Path::new(&slice.path).exists() {
+            existing.push(slice);
+        } else {
+            warn!("Archived Parquet file missing, skipping: {}", slice.path);
+        }
+    }
+    Ok(existing)
+}
+
+fn state_transition(row: &duckdb::Row) -> duckdb::Result<StateTransition> {
+    let ms: i64 = row.get(0)?;
+    Ok(StateTransition {
+        timestamp: DateTime::from_timestamp_millis(ms).unwrap_or_default().naive_utc(),
+        state: row.get(1)?,
+        model: row.get(2)?,
+        sensor_id: row.get(3)?,
+        active: row.get(4)?,
+        value: row.get(5)?,
+    })
+}
+
+/// Fold rows with a `seq` above the stored watermark into the rollup
+/// buckets, so late and out-of-order rows end up in the right bucket. The
+/// new rows are aggregated on their own and merged into the existing
+/// buckets (min/max combined, count added, avg weighted) rather than
+/// recomputing whole buckets from `measurements`: retention may already
+/// have pruned the older rows of a bucket, which live on only in the
+/// rollup. The first run (no watermark yet) rebuilds everything, which also
+/// covers rows stored before sequences existed. One transaction.
+fn rollup(conn: &Connection) -> anyhow::Result<usize> {
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let result = (|| -> anyhow::Result<usize> {
+        let last: Option<u64> = conn
+            .query_row("SELECT last_seq FROM rollup_state WHERE name = 'measurements'", [], |row| row.get(0))
+            .optional()?;
+        let upto: Option<u64> = conn.query_row("SELECT max(seq) FROM measurements", [], |row| row.get(0))?;
+        let upto = upto.unwrap_or_default();
+        let delta = match last {
+            None => "true".to_string(),
+            Some(last) if last < upto => format!("seq > {} AND seq <= {}", last, upto),
+            Some(_) => "false".to_string(),
+        };
+        let mut written = 0;
+        for resolution in Resolution::ALL {
+            let (table, interval) = (resolution.table(), resolution.interval());
+            if last.is_none() {
+                conn.execute(&format!("DELETE FROM {}", table), [])?;
+            }
+            written += conn.execute(
+                &format!(
+                    "INSERT INTO {table} \
+                     SELECT time_bucket({interval}, timestamp), sensor_id, model, measurement_type, COALESCE(site, ''), \
+                     min(value), max(value), avg(value), count(*), COALESCE(probe, '') \
+                     FROM measurements WHERE {delta} GROUP BY ALL \
+                     ON CONFLICT (bucket, sensor_id, model, measurement_type, site, probe) DO UPDATE SET \
+                     min = least({table}.min, excluded.min), max = greatest({table}.max, excluded.max), \
+                     avg = ({table}.avg * {table}.count + excluded.avg * excluded.count) / ({table}.count + excluded.count), \
+                     count = {table}.count + excluded.count"
+                ),
+                [],
+            )?;
+        }
+        conn.execute(
+            "INSERT OR REPLACE INTO rollup_state (name, last_seq) VALUES ('measurements', ?)",
+            [upto.max(last.unwrap_or_default())],
+        )?;
+        Ok(written)
+    })();
+    match result {
+        Ok(written) => {
+            conn.execute_batch("COMMIT")?;
+            Ok(written)
+        }
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK");
+            Err(e)
+        }
+    }
+}
+
+/// Retention: delete old rows from the live tables in one transaction, then
+/// `CHECKPOINT` so DuckDB can reuse the freed blocks (DuckDB's `VACUUM`
+/// does not reclaim space). `latest_measurements` keeps one row per series
+/// and is left alone.
+fn prune(conn: &Connection, before_ms: Option<i64>, sensors: &[SensorRetention], archived_only: bool) -> anyhow::Result<PruneReport> {
+    let global = before_ms.map(|ms| format!("make_timestamp({})", ms_to_micros(ms)));
+    // Per-row cutoff: the sensor's own, else the global one. NULL keeps the row.
+    let cutoff = if sensors.is_empty() {
+        global.clone().unwrap_or_else(|| "NULL".to_string())
+    } else {
+        let cases: String = sensors
+            .iter()
+            .map(|sensor| {
+                format!(
+                    " WHEN model = {} AND sensor_id = {} THEN make_timestamp({})",
+                    sql_literal(&sensor.model),
+                    sql_literal(&sensor.sensor_id),
+                    ms_to_micros(sensor.before_ms)
+                )
+            })
+            .collect();
+        format!("CASE{} ELSE {} END", cases, global.as_deref().unwrap_or("NULL"))
+    };
+
+    let mut report = PruneReport::default();
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let result = (|| -> anyhow::Result<()> {
+        let mut measurements = format!("timestamp < {}", cutoff);
+        if archived_only {
+            let archives = overlapping_archives(conn, ms_to_micros(i64::MIN), ms_to_micros(i64::MAX))?;
+            measurements.push_str(&format!(" AND {}", archived_sql(&archives)));
+        }
+        report.measurements = conn.execute(&format!("DELETE FROM measurements WHERE {}", measurements), [])?;
+        report.raw_messages = conn.execute(&format!("DELETE FROM raw_messages WHERE received_at < {}", cutoff), [])?;
+        report.rejected_messages = conn.execute(&format!("DELETE FROM rejected_messages WHERE received_at < {}", cutoff), [])?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => conn.execute_batch("COMMIT")?,
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK");
+            return Err(e);
+        }
+    }
+    conn.execute_batch("CHECKPOINT")?;
+    Ok(report)
+}
+
+/// Aggregate the live, locally ingested rows of a report period per sensor.
+/// Gaps are measured between consecutive message timestamps, with the end
+/// of the period (and its start for sensors that existed before) as extra
+/// points so a sensor going quiet counts as well.
+fn report(conn: &Connection, query: &ReportQuery) -> anyhow::Result<Vec<SensorActivity>> {
+    let (start_us, end_us) = (ms_to_micros(query.start_ms), ms_to_micros(query.end_ms));
+    let mut stmt = conn.prepare(
+        "WITH period AS ( \
+             SELECT model, sensor_id, measurement_type, COALESCE(probe, '') AS probe, value, timestamp FROM measurements \
+             WHERE timestamp >= make_timestamp(?) AND timestamp < make_timestamp(?) AND COALESCE(site, '') = ''), \
+         seen AS ( \
+             SELECT model, sensor_id, min(timestamp) AS first_ts FROM measurements \
+             WHERE COALESCE(site, '') = '' GROUP BY model, sensor_id), \
+         stats AS ( \
+             SELECT model, sensor_id, count(DISTINCT timestamp) AS messages, \
+             min(value) FILTER (WHERE measurement_type = ? AND probe = '') AS min, \
+             max(value) FILTER (WHERE measurement_type = ? AND probe = '') AS max, \
+             arg_max(value, timestamp) FILTER (WHERE measurement_type = ? AND probe = '') AS battery_ok, \
+             max(timestamp) AS last_ts \
+             FROM period GROUP BY model, sensor_id), \
+         times AS ( \
+             SELECT model, sensor_id, timestamp FROM period \
+             UNION SELECT model, sensor_id, make_timestamp(?) FROM stats \
+             UNION SELECT s.model, s.sensor_id, make_timestamp(?) FROM stats s JOIN seen f USING (model, sensor_id) \
+             WHERE f.first_ts < make_timestamp(?)), \
+         gaps AS ( \
+             SELECT model, sensor_id, count(*) FILTER (WHERE gap_ms > ?) AS gaps, max(gap_ms) AS longest FROM ( \
+                 SELECT model, sensor_id, \
+                 epoch_ms(timestamp) - epoch_ms(lag(timestamp) OVER (PARTITION BY model, sensor_id ORDER BY timestamp)) AS gap_ms \
+                 FROM times) \
+             GROUP BY model, sensor_id) \
+         SELECT s.model, s.sensor_id, s.messages, s.min, s.max, s.battery_ok, \
+         CAST(epoch_ms(s.last_ts) AS BIGINT), CAST(epoch_ms(f.first_ts) AS BIGINT), \
+         COALESCE(g.gaps, 0), COALESCE(g.longest, 0) \
+         FROM stats s JOIN seen f USING (model, sensor_id) LEFT JOIN gaps g USING (model, sensor_id) \
+         ORDER BY s.model, s.sensor_id",
+    )?;
+    let rows = stmt.query_map(
+        duckdb::params![
+            start_us,
+            end_us,
+            query.measurement_type,
+            query.measurement_type,
+            query.battery_type,
+            end_us,
+            start_us,
+            start_us,
+            query.gap_ms
+        ],
+        |row| {
+            Ok(SensorActivity {
+                model: row.get(0)?,
+                sensor_id: row.get(1)?,
+                messages: row.get(2)?,
+                min: row.get(3)?,
+                max: row.get(4)?,
+                battery_ok: row.get(5)?,
+                last_seen_ms: row.get(6)?,
+                first_seen_ms: row.get(7)?,
+                gaps: row.get(8)?,
+                longest_gap_ms: row.get(9)?,
+            })
+        },
+    )?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// One step of `DbHandle::rename_sensor`. The big tables are moved a chunk
+/// of rows at a time, each chunk committed on its own. The last
+/// step rewrites the archives like `purge_sensor`, then moves the rest in
+/// one transaction: tables keyed by sensor combine both histories where
+/// they overlap (rollups and radio stats are added up, the newer latest
+/// value and the target's degree days win), and on a merge the target keeps
+/// its own enrichment labels.
+fn rename_sensor_step(conn: &Connection, pending: &PendingRename) -> anyhow::Result<(RenameReport, bool)> {
+    let PendingRename { request, until_seq, until_ms } = pending;
+    let sensor = |model: &str, sensor_id: &str| format!("model = {} AND sensor_id = {}", sql_literal(model), sql_literal(sensor_id));
+    let old = sensor(&request.model, &request.sensor_id);
+    let new = sensor(&request.new_model, &request.new_sensor_id);
+    let (new_model, new_sensor_id) = (sql_literal(&request.new_model), sql_literal(&request.new_sensor_id));
+    let mut report = RenameReport::default();
+
+    // Rows stored since the rename started are left alone, so a sensor
+    // that keeps reporting cannot keep it going.
+    let started = [
+        ("measurements", format!("(seq IS NULL OR seq <= {})", until_seq)),
+        ("raw_messages", format!("received_at <= make_timestamp({})", ms_to_micros(*until_ms))),
+        ("rejected_messages", format!("received_at <= make_timestamp({})", ms_to_micros(*until_ms))),
+    ];
+    for (table, started) in started {
+        let moved = conn.execute(
+            &format!(
+                "UPDATE {table} SET model = {new_model}, sensor_id = {new_sensor_id} \
+                 WHERE rowid IN (SELECT rowid FROM {table} WHERE {old} AND {started} LIMIT {RENAME_CHUNK_ROWS})"
+            ),
+            [],
+        )?;
+        if moved > 0 {
+            match table {
+                "measurements" => report.measurements = moved,
+                "raw_messages" => report.raw_messages = moved,
+                _ => report.rejected_messages = moved,
+            }
+            return Ok((report, false));
+        }
+    }
+
+    for archive in list_archives(conn, None)? {
+        if !std::path::Path::new(&archive.path).exists() {
+            warn!("Archived Parquet file missing, not renamed: {}", archive.path);
+            continue;
+        }
+        let file = sql_literal(&archive.path);
+        let matching: i64 = conn.query_row(&format!("SELECT count(*) FROM read_parquet({}) WHERE {}", file, old), [], |row| row.get(0))?;
+        if matching == 0 {
+            continue;
+        }
+        let tmp = format!("{}.rename-tmp", archive.path);
+        conn.execute_batch(&format!(
+            "COPY (SELECT * REPLACE ( \
+                 CASE WHEN {old} THEN {new_model} ELSE model END AS model, \
+                 CASE WHEN {old} THEN {new_sensor_id} ELSE sensor_id END AS sensor_id) \
+             FROM read_parquet({file})) TO {tmp} (FORMAT parquet, COMPRESSION zstd)",
+            tmp = sql_literal(&tmp)
+        ))?;
+        std::fs::rename(&tmp, &archive.path)?;
+        report.archived_rows += matching as usize;
+        report.archives_rewritten += 1;
+    }
+
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let result = (|| -> anyhow::Result<()> {
+        conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO latest_measurements \
+                 SELECT {new_sensor_id}, {new_model}, measurement_type, timestamp, value, topic, probe FROM latest_measurements o \
+                 WHERE {old} AND NOT EXISTS (SELECT 1 FROM latest_measurements t \
+                     WHERE t.model = {new_model} AND t.sensor_id = {new_sensor_id} \
+                     AND t.measurement_type = o.measurement_type AND t.probe = o.probe AND t.timestamp >= o.timestamp)"
+            ),
+            [],
+        )?;
+        for table in ["measurements_5m", "measurements_1h"] {
+            conn.execute(
+                &format!(
+                    "INSERT OR REPLACE INTO {table} \
+                     SELECT bucket, {new_sensor_id}, {new_model}, measurement_type, site, \
+                         min(min), max(max), sum(avg * count) / sum(count), sum(count), probe \
+                     FROM {table} WHERE ({old}) OR ({new}) GROUP BY bucket, measurement_type, site, probe"
+                ),
+                [],
+            )?;
+        }
+        conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO radio_stats \
+                 SELECT hour, {new_model}, {new_sensor_id}, modulation, sum(messages), \
+                     sum(freq_sum), sum(freq_n), min(freq_min), max(freq_max), sum(freq2_sum), sum(freq2_n), \
+                     sum(rssi_sum), sum(rssi_n), sum(snr_sum), sum(snr_n), sum(noise_sum), sum(noise_n) \
+                 FROM radio_stats WHERE ({old}) OR ({new}) GROUP BY hour, modulation"
+            ),
+            [],
+        )?;
+        conn.execute(
+            &format!(
+                "INSERT OR IGNORE INTO degree_days \
+                 SELECT day, {new_model}, {new_sensor_id}, mean, heating, cooling, samples FROM degree_days WHERE {old}"
+            ),
+            [],
+        )?;
+        for table in ["latest_measurements", "measurements_5m", "measurements_1h", "radio_stats", "degree_days"] {
+            conn.execute(&format!("DELETE FROM {} WHERE {}", table, old), [])?;
+        }
+        let target_labelled: bool = conn.query_row(&format!("SELECT EXISTS (SELECT 1 FROM sensor_labels WHERE {})", new), [], |row| row.get(0))?;
+        if target_labelled {
+            conn.execute(&format!("DELETE FROM sensor_labels WHERE {}", old), [])?;
+        }
+        for table in ["sensor_labels", "state_transitions", "annotations"] {
+            conn.execute(&format!("UPDATE {table} SET model = {new_model}, sensor_id = {new_sensor_id} WHERE {old}"), [])?;
+        }
+        let action = if request.merge { "merge_sensor" } else { "rename_sensor" };
+        let details = serde_json::json!({
+            "to": { "model": &request.new_model, "sensor_id": &request.new_sensor_id },
+            "archived_rows": report.archived_rows,
+            "archives_rewritten": report.archives_rewritten,
+        });
+        conn.execute(
+            "INSERT INTO audit_log (action, model, sensor_id, details) VALUES (?, ?, ?, ?)",
+            [action, &request.model, &request.sensor_id, &details.to_string()],
+        )?;
+        conn.execute("DELETE FROM pending_renames WHERE model = ? AND sensor_id = ?", [&request.model, &request.sensor_id])?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => conn.execute_batch("COMMIT")?,
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK");
+            return Err(e);
+        }
+    }
+    Ok((report, true))
+}
+
+/// Delete a sensor's rows from the live tables and rewrite archived Parquet
+/// files without them. Archives go first: if rewriting fails nothing has
+/// been deleted yet and the purge can simply be retried. The table deletes
+/// and the audit record share one transaction. Rollup buckets overlapping
+/// the window are rebuilt from the rows left in `measurements`.
+fn purge_sensor(conn: &Connection, request: &PurgeRequest) -> anyhow::Result<PurgeReport> {
+    let sensor = format!(
+        "model = {} AND sensor_id = {}",
+        sql_literal(&request.model),
+        sql_literal(&request.sensor_id)
+    );
+    let from = request.from_ms.map(|ms| format!("make_timestamp({})", ms_to_micros(ms)));
+    let before = request.before_ms.map(|ms| format!("make_timestamp({})", ms_to_micros(ms)));
+    // Matching rows, given the name of the table's time column.
+    let predicate = |time_column: &str| {
+        let mut predicate = sensor.clone();
+        if let Some(from) = &from {
+            predicate.push_str(&format!(" AND {} >= {}", time_column, from));
+        }
+        if let Some(before) = &before {
+            predicate.push_str(&format!(" AND {} < {}", time_column, before));
+        }
+        predicate
+    };
+    // Same for tables with a `measurement_type` column.
+    let typed = |time_column: &str| match request.measurement_type {
+        Some(code) => format!("{} AND measurement_type = {}", predicate(time_column), code),
+        None => predicate(time_column),
+    };
+    let mut report = PurgeReport::default();
+
+    let archive_predicate = typed("timestamp");
+    for archive in list_archives(conn, None)? {
+        if !std::path::Path::new(&archive.path).exists() {
+            warn!("Archived Parquet file missing, not purged: {}", archive.path);
+            continue;
+        }
+        let file = sql_literal(&archive.path);
+        let matching: i64 = conn.query_row(
+            &format!("SELECT count(*) FROM read_parquet({}) WHERE {}", file, archive_predicate),
+            [],
+            |row| row.get(0),
+        )?;
+        if matching == 0 {
+            continue;
+        }
+        let tmp = format!("{}.purge-tmp", archive.path);
+        conn.execute_batch(&format!(
+            "COPY (SELECT * FROM read_parquet({}) WHERE NOT ({})) TO {} (FORMAT parquet, COMPRESSION zstd)",
+            file,
+            archive_predicate,
+            sql_literal(&tmp)
+        ))?;
+        std::fs::rename(&tmp, &archive.path)?;
+        if archive.row_count == matching {
+            std::fs::remove_file(&archive.path)?;
+            conn.execute("DELETE FROM parquet_archive WHERE path = ?", [&archive.path])?;
+        } else {
+            conn.execute(
+                &format!(
+                    "UPDATE parquet_archive SET min_timestamp = s.min_ts, max_timestamp = s.max_ts, row_count = s.n \
+                     FROM (SELECT min(timestamp) AS min_ts, max(timestamp) AS max_ts, count(*) AS n FROM read_parquet({})) s \
+                     WHERE path = ?",
+                    file
+                ),
+                [&archive.path],
+            )?;
+        }
+        report.archived_rows += matching as usize;
+        report.archives_rewritten += 1;
+    }
+
+    conn.execute_batch("BEGIN TRANSACTION")?;
+    let result = (|| -> anyhow::Result<()> {
+        report.measurements = conn.execute(&format!("DELETE FROM measurements WHERE {}", typed("timestamp")), [])?;
+        conn.execute(&format!("DELETE FROM latest_measurements WHERE {}", typed("timestamp")), [])?;
+        for resolution in Resolution::ALL {
+            let (table, interval) = (resolution.table(), resolution.interval());
+            // Only buckets lying wholly inside the window. Partial buckets at
+            // its edges are kept as they are: the measurements they were
+            // built from may be pruned already, so they cannot be rebuilt.
+            let mut inside = match request.measurement_type {
+                Some(code) => format!("{} AND measurement_type = {}", sensor, code),
+                None => sensor.clone(),
+            };
+            if let Some(from) = &from {
+                inside.push_str(&format!(" AND bucket >= {}", from));
+            }
+            if let Some(before) = &before {
+                inside.push_str(&format!(" AND bucket + {} <= {}", interval, before));
+            }
+            conn.execute(&format!("DELETE FROM {} WHERE {}", table, inside), [])?;
+        }
+        if request.measurement_type.is_none() {
+            report.raw_messages = conn.execute(&format!("DELETE FROM raw_messages WHERE {}", predicate("received_at")), [])?;
+            report.rejected_messages =
+                conn.execute(&format!("DELETE FROM rejected_messages WHERE {}", predicate("received_at")), [])?;
+            conn.execute(&format!("DELETE FROM state_transitions WHERE {}", predicate("timestamp")), [])?;
+            conn.execute(&format!("DELETE FROM degree_days WHERE {}", predicate("day")), [])?;
+            conn.execute(&format!("DELETE FROM radio_stats WHERE {}", predicate("hour")), [])?;
+            if request.from_ms.is_none() && request.before_ms.is_none() {
+                conn.execute(&format!("DELETE FROM sensor_labels WHERE {}", sensor), [])?;
+            }
+        }
+        let details = serde_json::json!({
+            "from_ms": request.from_ms,
+            "before_ms": request.before_ms,
+            "measurement_type": request.measurement_type,
+            "removed": &report,
+        });
+        conn.execute(
+            "INSERT INTO audit_log (action, model, sensor_id, details) VALUES ('purge_sensor', ?, ?, ?)",
+            [&request.model, &request.sensor_id, &details.to_string()],
+        )?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => conn.execute_batch("COMMIT")?,
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK");
+            return Err(e);
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt_buffer::rows_to_record_batch;
+
+    fn conn() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        conn
+    }
+
+    fn row(sensor_id: &str, timestamp: &str, measurement_type: u8, value: f64) -> NormalizedRow {
+        NormalizedRow {
+            timestamp: NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S").unwrap(),
+            sensor_id: sensor_id.to_string(),
+            model: "Acurite-Tower".to_string(),
+            measurement_type,
+            value,
+            topic: "rtl_433/events".to_string(),
+            seq: 0,
+            site: String::new(),
+            probe: String::new(),
+        }
+    }
+
+    /// Run `command` on `conn` the way the worker does.
+    fn run(conn: &Connection, sequencer: &mut Sequencer, command: DbCommand) -> DbResponse {
+        handle_command(conn, &DbConfig::default(), sequencer, command).unwrap()
+    }
+
+    fn append(conn: &Connection, sequencer: &mut Sequencer, rows: &[NormalizedRow]) {
+        let batch = rows_to_record_batch(rows).unwrap();
+        run(conn, sequencer, DbCommand::Append { table: "measurements".to_string(), batch });
+    }
+
+    fn seqs(batch: &RecordBatch) -> Vec<u64> {
+        let index = batch.schema().index_of("seq").unwrap();
+        batch.column(index).as_any().downcast_ref::<UInt64Array>().unwrap().values().to_vec()
+    }
+
+    #[test]
+    fn sequences_follow_append_order_and_stored_rows() {
+        let conn = conn();
+        conn.execute_batch("INSERT INTO measurements VALUES (TIMESTAMP '2024-03-01 08:00:00', '1', 'm', 1, 1.0, 't', 9000000000000000000, '', '')")
+            .unwrap();
+        let mut sequencer = Sequencer::load(&conn).unwrap();
+        let first = seqs(&sequencer.stamp(rows_to_record_batch(&vec![row("1", "2024-03-01 09:00:00", 1, 1.0); 3]).unwrap()).unwrap());
+        let second = seqs(&sequencer.stamp(rows_to_record_batch(&[row("1", "2024-03-01 07:00:00", 1, 1.0)]).unwrap()).unwrap());
+        assert_eq!(first, vec![9000000000000000001, 9000000000000000002, 9000000000000000003]);
+        assert_eq!(second, vec![9000000000000000004]);
+        assert_eq!(sequencer.last, 9000000000000000004);
+    }
+
+    #[test]
+    fn sync_resumes_after_rows_committed_later_with_older_timestamps() {
+        let conn = conn();
+        let mut sequencer = Sequencer::load(&conn).unwrap();
+        append(&conn, &mut sequencer, &[row("1", "2024-03-01 09:00:00", 1, 1.0)]);
+        let DbResponse::Measurements(page) = run(&conn, &mut sequencer, DbCommand::SyncRows { after_seq: 0, limit: 10 }) else {
+            panic!("expected rows")
+        };
+        let cursor = page.last().unwrap().seq;
+        // A slow flush of rows normalized earlier commits after the read.
+        append(&conn, &mut sequencer, &[row("2", "2024-03-01 08:00:00", 1, 2.0)]);
+        let DbResponse::Measurements(page) = run(&conn, &mut sequencer, DbCommand::SyncRows { after_seq: cursor, limit: 10 }) else {
+            panic!("expected rows")
+        };
+        assert_eq!(page.iter().map(|r| r.sensor_id.as_str()).collect::<Vec<_>>(), vec!["2"]);
+    }
+
+    fn buckets(conn: &Connection, table: &str) -> Vec<(String, f64, f64, f64, i64)> {
+        let mut stmt = conn
+            .prepare(&format!("SELECT strftime(bucket, '%H:%M'), min, max, avg, count FROM {} ORDER BY bucket, measurement_type", table))
+            .unwrap();
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn rollup_merges_late_rows_into_buckets_whose_rows_were_pruned() {
+        let conn = conn();
+        let mut sequencer = Sequencer::load(&conn).unwrap();
+        append(&conn, &mut sequencer, &[row("1", "2024-03-01 08:00:00", 1, 10.0), row("1", "2024-03-01 08:01:00", 1, 20.0)]);
+        rollup(&conn).unwrap();
+        assert_eq!(buckets(&conn, "measurements_5m"), vec![("08:00".to_string(), 10.0, 20.0, 15.0, 2)]);
+        // Retention prunes the raw rows; a late reading for the bucket follows.
+        conn.execute_batch("DELETE FROM measurements").unwrap();
+        append(&conn, &mut sequencer, &[row("1", "2024-03-01 08:02:00", 1, 60.0)]);
+        rollup(&conn).unwrap();
+        assert_eq!(buckets(&conn, "measurements_5m"), vec![("08:00".to_string(), 10.0, 60.0, 30.0, 3)]);
+        assert_eq!(buckets(&conn, "measurements_1h"), vec![("08:00".to_string(), 10.0, 60.0, 30.0, 3)]);
+        // Nothing new: nothing changes.
+        assert_eq!(rollup(&conn).unwrap(), 0);
+        assert_eq!(buckets(&conn, "measurements_5m"), vec![("08:00".to_string(), 10.0, 60.0, 30.0, 3)]);
+    }
+
+    fn values(conn: &Connection, sql: &str) -> Vec<f64> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        stmt.query_map([], |row| row.get(0)).unwrap().collect::<Result<_, _>>().unwrap()
+    }
+
+    fn selected(conn: &Connection) -> Vec<f64> {
+        let query = MeasurementQuery { start_ms: i64::MIN, end_ms: i64::MAX, measurement_types: Vec::new(), conditions: Vec::new() };
+        let archives = overlapping_archives(conn, ms_to_micros(i64::MIN), ms_to_micros(i64::MAX)).unwrap();
+        let (sql, params) = query.select_sql(&archives);
+        let mut stmt = conn.prepare(&format!("SELECT v FROM ({}) q(ts, s, m, t, v, topic, seq, site, probe) ORDER BY v", sql)).unwrap();
+        stmt.query_map(params_from_iter(params), |row| row.get(0)).unwrap().collect::<Result<_, _>>().unwrap()
+    }
+
+    fn export(conn: &Connection, sequencer: &mut Sequencer, dir: &std::path::Path, name: &str, end: &str) -> Option<i64> {
+        let end_ms = NaiveDateTime::parse_from_str(end, "%Y-%m-%d %H:%M:%S").unwrap().and_utc().timestamp_millis();
+        let path = dir.join(name).to_string_lossy().into_owned();
+        match run(conn, sequencer, DbCommand::Export(ExportRequest { start_ms: None, end_ms, path })) {
+            DbResponse::Archives(entries) => entries.first().map(|e| e.row_count),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn late_rows_for_an_archived_day_are_archived_by_the_next_export_and_never_pruned_before() {
+        let dir = std::env::temp_dir().join(format!("exporter-archive-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let conn = conn();
+        let mut sequencer = Sequencer::load(&conn).unwrap();
+        append(&conn, &mut sequencer, &[row("1", "2024-03-01 08:00:00", 1, 1.0), row("1", "2024-03-02 08:00:00", 1, 2.0)]);
+        assert_eq!(export(&conn, &mut sequencer, &dir, "a.parquet", "2024-03-02 00:00:00"), Some(1));
+        // Arrives late for the archived day.
+        append(&conn, &mut sequencer, &[row("1", "2024-03-01 09:00:00", 1, 3.0)]);
+        // Read paths: the archived row once, the late row from the live table.
+        assert_eq!(selected(&conn), vec![1.0, 2.0, 3.0]);
+        // Retention with scheduled archiving keeps the late row.
+        let cutoff = NaiveDateTime::parse_from_str("2024-03-02 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap().and_utc().timestamp_millis();
+        prune(&conn, Some(cutoff), &[], true).unwrap();
+        assert_eq!(values(&conn, "SELECT value FROM measurements ORDER BY value"), vec![2.0, 3.0]);
+        assert_eq!(selected(&conn), vec![1.0, 2.0, 3.0]);
+        // The next export takes only the late row; then it can be pruned.
+        assert_eq!(export(&conn, &mut sequencer, &dir, "b.parquet", "2024-03-02 00:00:00"), Some(1));
+        assert_eq!(export(&conn, &mut sequencer, &dir, "c.parquet", "2024-03-02 00:00:00"), None);
+        prune(&conn, Some(cutoff), &[], true).unwrap();
+        assert_eq!(values(&conn, "SELECT value FROM measurements ORDER BY value"), vec![2.0]);
+        assert_eq!(selected(&conn), vec![1.0, 2.0, 3.0]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn degree_days_include_archived_readings() {
+        let dir = std::env::temp_dir().join(format!("exporter-degree-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let conn = conn();
+        let mut sequencer = Sequencer::load(&conn).unwrap();
+        append(&conn, &mut sequencer, &[row("1", "2024-03-01 06:00:00", 1, 4.0), row("1", "2024-03-01 18:00:00", 1, 8.0)]);
+        assert_eq!(export(&conn, &mut sequencer, &dir, "a.parquet", "2024-03-01 12:00:00"), Some(1));
+        let cutoff = NaiveDateTime::parse_from_str("2024-03-01 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap().and_utc().timestamp_millis();
+        prune(&conn, Some(cutoff), &[], true).unwrap();
+        assert_eq!(values(&conn, "SELECT value FROM measurements"), vec![8.0]);
+
+        let day = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let start = day.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp_millis();
+        let request = DegreeDayRequest {
+            model: "Acurite-Tower".to_string(),
+            sensor_id: "1".to_string(),
+            measurement_type: 1,
+            heating_base: 15.5,
+            cooling_base: 22.0,
+            days: vec![(day, start, start + 86_400_000)],
+        };
+        run(&conn, &mut sequencer, DbCommand::ComputeDegreeDays(request));
+        assert_eq!(values(&conn, "SELECT mean FROM degree_days"), vec![6.0]);
+        assert_eq!(values(&conn, "SELECT heating FROM degree_days"), vec![9.5]);
+        assert_eq!(values(&conn, "SELECT samples::DOUBLE FROM degree_days"), vec![2.0]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn rename_request(sensor_id: &str, new_sensor_id: &str, merge: bool) -> RenameRequest {
+        RenameRequest {
+            model: "Acurite-Tower".to_string(),
+            sensor_id: sensor_id.to_string(),
+            new_model: "Acurite-Tower".to_string(),
+            new_sensor_id: new_sensor_id.to_string(),
+            merge,
+        }
+    }
+
+    fn begin_rename(conn: &Connection, sequencer: &mut Sequencer, request: RenameRequest) -> PendingRename {
+        match run(conn, sequencer, DbCommand::BeginRename(request)) {
+            DbResponse::Renames(mut renames) => renames.remove(0),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    /// Run the steps of a started rename until it is done.
+    fn finish_rename(conn: &Connection, sequencer: &mut Sequencer, pending: &PendingRename) {
+        while let DbResponse::Renamed { done: false, .. } = run(conn, sequencer, DbCommand::RenameSensor(pending.clone())) {}
+    }
+
+    fn pending_renames(conn: &Connection, sequencer: &mut Sequencer) -> Vec<String> {
+        match run(conn, sequencer, DbCommand::PendingRenames) {
+            DbResponse::Renames(renames) => renames.into_iter().map(|p| p.request.sensor_id).collect(),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rename_moves_rows_stored_before_it_started_and_records_it() {
+        let conn = conn();
+        let mut sequencer = Sequencer::load(&conn).unwrap();
+        append(&conn, &mut sequencer, &[row("1", "2024-03-01 08:00:00", 1, 10.0), row("1", "2024-03-01 08:01:00", 1, 20.0)]);
+        rollup(&conn).unwrap();
+        let pending = begin_rename(&conn, &mut sequencer, rename_request("1", "2", false));
+        assert_eq!(pending_renames(&conn, &mut sequencer), vec!["1"]);
+        // Stored after the rename started: keeps the old id.
+        append(&conn, &mut sequencer, &[row("1", "2024-03-01 09:00:00", 1, 30.0)]);
+        finish_rename(&conn, &mut sequencer, &pending);
+        assert_eq!(values(&conn, "SELECT value FROM measurements WHERE sensor_id = '2' ORDER BY value"), vec![10.0, 20.0]);
+        assert_eq!(values(&conn, "SELECT value FROM measurements WHERE sensor_id = '1'"), vec![30.0]);
+        assert_eq!(values(&conn, "SELECT count FROM measurements_5m WHERE sensor_id = '2'"), vec![2.0]);
+        assert_eq!(values(&conn, "SELECT count(*) FROM audit_log WHERE action = 'rename_sensor' AND sensor_id = '1'"), vec![1.0]);
+        assert!(pending_renames(&conn, &mut sequencer).is_empty());
+    }
+
+    #[test]
+    fn merge_combines_rollup_buckets_of_both_sensors() {
+        let conn = conn();
+        let mut sequencer = Sequencer::load(&conn).unwrap();
+        append(&conn, &mut sequencer, &[row("1", "2024-03-01 08:00:00", 1, 10.0), row("2", "2024-03-01 08:01:00", 1, 30.0)]);
+        rollup(&conn).unwrap();
+        let pending = begin_rename(&conn, &mut sequencer, rename_request("1", "2", true));
+        finish_rename(&conn, &mut sequencer, &pending);
+        assert_eq!(buckets(&conn, "measurements_5m"), vec![("08:00".to_string(), 10.0, 30.0, 20.0, 2)]);
+        assert_eq!(values(&conn, "SELECT count(*) FROM measurements WHERE sensor_id = '2'"), vec![2.0]);
+        assert_eq!(values(&conn, "SELECT count(*) FROM audit_log WHERE action = 'merge_sensor'"), vec![1.0]);
+    }
+
+    #[test]
+    fn an_interrupted_rename_stays_pending_and_cannot_be_started_twice() {
+        let conn = conn();
+        let mut sequencer = Sequencer::load(&conn).unwrap();
+        append(&conn, &mut sequencer, &[row("1", "2024-03-01 08:00:00", 1, 10.0)]);
+        let pending = begin_rename(&conn, &mut sequencer, rename_request("1", "2", false));
+        // One chunk moved, then the process stops.
+        let DbResponse::Renamed { done: false, .. } = run(&conn, &mut sequencer, DbCommand::RenameSensor(pending.clone())) else {
+            panic!("expected a partial rename")
+        };
+        assert!(handle_command(&conn, &DbConfig::default(), &mut sequencer, DbCommand::BeginRename(rename_request("1", "3", false))).is_err());
+        let DbResponse::Renames(resumed) = run(&conn, &mut sequencer, DbCommand::PendingRenames) else { panic!("expected renames") };
+        assert_eq!(resumed.len(), 1);
+        assert_eq!((resumed[0].until_seq, resumed[0].until_ms), (pending.until_seq, pending.until_ms));
+        finish_rename(&conn, &mut sequencer, &resumed[0]);
+        assert_eq!(values(&conn, "SELECT count(*) FROM measurements WHERE sensor_id = '2'"), vec![1.0]);
+        assert!(pending_renames(&conn, &mut sequencer).is_empty());
+    }
+
+    #[test]
+    fn purging_a_window_drops_only_the_buckets_inside_it() {
+        let conn = conn();
+        let mut sequencer = Sequencer::load(&conn).unwrap();
+        append(
+            &conn,
+            &mut sequencer,
+            &[row("1", "2024-03-01 08:01:00", 1, 10.0), row("1", "2024-03-01 08:06:00", 1, 99.0), row("1", "2024-03-01 08:11:00", 1, 20.0)],
+        );
+        rollup(&conn).unwrap();
+        // Retention pruned the raw rows the edge buckets were built from.
+        conn.execute_batch("DELETE FROM measurements WHERE value <> 99").unwrap();
+        let at = |time: &str| NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").unwrap().and_utc().timestamp_millis();
+        let request = PurgeRequest {
+            model: "Acurite-Tower".to_string(),
+            sensor_id: "1".to_string(),
+            from_ms: Some(at("2024-03-01 08:04:00")),
+            before_ms: Some(at("2024-03-01 08:12:00")),
+            measurement_type: None,
+        };
+        run(&conn, &mut sequencer, DbCommand::PurgeSensor(request));
+        // 08:05 lies wholly inside the window; the partial 08:00 and 08:10
+        // buckets and the hour keep their totals.
+        assert_eq!(
+            buckets(&conn, "measurements_5m"),
+            vec![("08:00".to_string(), 10.0, 10.0, 10.0, 1), ("08:10".to_string(), 20.0, 20.0, 20.0, 1)]
+        );
+        assert_eq!(buckets(&conn, "measurements_1h"), vec![("08:00".to_string(), 10.0, 99.0, 43.0, 3)]);
+        assert!(values(&conn, "SELECT value FROM measurements").is_empty());
+    }
+
+    #[test]
+    fn purging_a_sensor_removes_its_radio_statistics() {
+        let conn = conn();
+        let mut sequencer = Sequencer::load(&conn).unwrap();
+        for sensor_id in ["1", "2"] {
+            conn.execute(
+                "INSERT INTO radio_stats VALUES (TIMESTAMP '2024-03-01 08:00:00', 'Acurite-Tower', ?, 'ASK', 3, 0, 0, NULL, NULL, 0, 0, 0, 0, 0, 0, 0, 0)",
+                [sensor_id],
+            )
+            .unwrap();
+        }
+        let request = PurgeRequest { model: "Acurite-Tower".to_string(), sensor_id: "1".to_string(), from_ms: None, before_ms: None, measurement_type: None };
+        run(&conn, &mut sequencer, DbCommand::PurgeSensor(request));
+        assert_eq!(values(&conn, "SELECT sensor_id::DOUBLE FROM radio_stats"), vec![2.0]);
+    }
+
+    #[test]
+    fn rejected_payloads_go_with_their_sensor() {
+        use crate::mqtt_buffer::{rejected_to_record_batch, RejectedMessage};
+        let conn = conn();
+        let mut sequencer = Sequencer::load(&conn).unwrap();
+        let rejected = |sensor_id: Option<&str>| RejectedMessage {
+            received_at: NaiveDateTime::parse_from_str("2024-03-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap(),
+            topic: "rtl_433/events".to_string(),
+            payload: b"{}".to_vec(),
+            encrypted: false,
+            reason: "unknown key".to_string(),
+            sensor_id: sensor_id.map(str::to_string),
+            model: sensor_id.map(|_| "Acurite-Tower".to_string()),
+        };
+        let batch = rejected_to_record_batch(&[rejected(Some("1")), rejected(Some("2")), rejected(Some("4")), rejected(None)]).unwrap();
+        run(&conn, &mut sequencer, DbCommand::Append { table: "rejected_messages".to_string(), batch });
+        let sensors = |conn: &Connection| values(conn, "SELECT COALESCE(sensor_id, '0')::DOUBLE FROM rejected_messages ORDER BY 1");
+
+        let pending = begin_rename(&conn, &mut sequencer, rename_request("1", "3", false));
+        finish_rename(&conn, &mut sequencer, &pending);
+        assert_eq!(sensors(&conn), vec![0.0, 2.0, 3.0, 4.0]);
+        let request = PurgeRequest { model: "Acurite-Tower".to_string(), sensor_id: "2".to_string(), from_ms: None, before_ms: None, measurement_type: None };
+        let DbResponse::Purged(report) = run(&conn, &mut sequencer, DbCommand::PurgeSensor(request)) else { panic!("expected a purge report") };
+        assert_eq!(report.rejected_messages, 1);
+        assert_eq!(sensors(&conn), vec![0.0, 3.0, 4.0]);
+        // A sensor's retention override applies to its rejects too.
+        let before_ms = chrono::Utc::now().timestamp_millis();
+        let sensors_retention = vec![SensorRetention { model: "Acurite-Tower".to_string(), sensor_id: "3".to_string(), before_ms }];
+        let report = prune(&conn, None, &sensors_retention, false).unwrap();
+        assert_eq!(report.rejected_messages, 1);
+        assert_eq!(sensors(&conn), vec![0.0, 4.0]);
+    }
+
+    #[test]
+    fn probes_are_series_of_their_own() {
+        let conn = conn();
+        let mut sequencer = Sequencer::load(&conn).unwrap();
+        let probe = NormalizedRow { probe: "1".to_string(), ..row("1", "2024-03-01 08:00:00", 1, 20.0) };
+        append(&conn, &mut sequencer, &[row("1", "2024-03-01 08:00:00", 1, 10.0), probe]);
+        run(&conn, &mut sequencer, DbCommand::Rollup);
+        assert_eq!(values(&conn, "SELECT avg FROM measurements_5m ORDER BY probe"), vec![10.0, 20.0]);
+    }
+
+    #[test]
+    fn series_tables_of_older_databases_get_the_probe_key() {
+        let conn = Connection::open_in_memory().unwrap();
+        for table in ["measurements_5m", "measurements_1h"] {
+            conn.execute_batch(&format!(
+                "CREATE TABLE {table} (bucket TIMESTAMP NOT NULL, sensor_id VARCHAR NOT NULL, model VARCHAR NOT NULL, \
+                 measurement_type UTINYINT NOT NULL, site VARCHAR NOT NULL, min DOUBLE NOT NULL, max DOUBLE NOT NULL, \
+                 avg DOUBLE NOT NULL, count BIGINT NOT NULL, PRIMARY KEY (bucket, sensor_id, model, measurement_type, site)); \
+                 INSERT INTO {table} VALUES (TIMESTAMP '2024-03-01 08:00:00', '1', 'm', 1, '', 10, 20, 15, 2);"
+            ))
+            .unwrap();
+        }
+        conn.execute_batch(SCHEMA).unwrap();
+        add_probe_keys(&conn).unwrap();
+        add_probe_keys(&conn).unwrap();
+        for table in ["measurements_5m", "measurements_1h"] {
+            assert_eq!(buckets(&conn, table), vec![("08:00".to_string(), 10.0, 20.0, 15.0, 2)]);
+            conn.execute_batch(&format!("INSERT INTO {table} VALUES (TIMESTAMP '2024-03-01 08:00:00', '1', 'm', 1, '', 30, 30, 30, 1, '2')"))
+                .unwrap();
+            assert_eq!(values(&conn, &format!("SELECT count FROM {table} ORDER BY probe")), vec![2.0, 1.0]);
+        }
+    }
+
+    #[tokio::test]
+    async fn an_abandoned_query_is_interrupted() {
+        let dir = std::env::temp_dir().join(format!("exporter-abandon-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DbConfig { path: dir.join("db.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let (db, _worker) = start_db_worker(&config, &Registry::new()).unwrap();
+
+        let slow = abandonable(db.query_arrow("SELECT sum(range) FROM range(1000000000000)", Vec::new()));
+        assert!(tokio::time::timeout(Duration::from_millis(300), slow).await.is_err());
+        let started = Instant::now();
+        db.ping().await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        assert_eq!(*db.running.job.lock().unwrap(), 0);
+        drop(db);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
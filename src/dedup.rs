@@ -0,0 +1,62 @@
+// Duplicate suppression. rtl_433 often decodes the same radio transmission
+// two to four times within a second (sensors repeat each packet for
+// reliability) and publishes every copy. Readings are therefore compared
+// per sensor, model, measurement and probe within timestamp buckets:
+//
+//   [dedup]
+//   window_secs = 2                # default 0 stores every reading
+//
+// Only the first reading of a bucket is kept; later ones are dropped before
+// they reach the gauges, the buffer or raw payload storage. A message whose
+// readings were all dropped is counted in
+// `mqtt_messages_deduplicated_total`. Copies that straddle a bucket boundary
+// are both kept. Only the last bucket per series is remembered, so memory
+// stays proportional to the number of series.
+use crate::config::DedupConfig;
+use crate::mqtt_buffer::NormalizedRow;
+use prometheus::{IntCounter, Registry};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+type SeriesKey = (String, String, u8, String);
+
+pub struct Deduplicator {
+    window_ms: i64,
+    /// Last timestamp bucket seen per series.
+    buckets: Mutex<HashMap<SeriesKey, i64>>,
+    deduplicated_total: IntCounter,
+}
+
+impl Deduplicator {
+    pub fn new(config: &DedupConfig, registry: &Registry) -> anyhow::Result<Self> {
+        let deduplicated_total = IntCounter::new(
+            "mqtt_messages_deduplicated_total",
+            "MQTT data messages dropped as repeats of a reading already received",
+        )?;
+        registry.register(Box::new(deduplicated_total.clone()))?;
+        Ok(Self {
+            window_ms: config.window_secs.saturating_mul(1000).min(i64::MAX as u64) as i64,
+            buckets: Mutex::new(HashMap::new()),
+            deduplicated_total,
+        })
+    }
+
+    /// Remove readings whose series already had one in the same bucket.
+    /// Returns `false` when every reading of the message was a repeat.
+    pub fn retain_new(&self, rows: &mut Vec<NormalizedRow>) -> bool {
+        if self.window_ms == 0 || rows.is_empty() {
+            return true;
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        rows.retain(|row| {
+            let bucket = row.timestamp.and_utc().timestamp_millis().div_euclid(self.window_ms);
+            let key = (row.model.clone(), row.sensor_id.clone(), row.measurement_type, row.probe.clone());
+            buckets.insert(key, bucket) != Some(bucket)
+        });
+        if rows.is_empty() {
+            self.deduplicated_total.inc();
+            return false;
+        }
+        true
+    }
+}
@@ -0,0 +1,160 @@
+// Heating and cooling degree days, for energy analysis without exporting
+// the raw series first. For each configured outdoor sensor the mean of a
+// local calendar day's readings is compared against two base temperatures:
+//
+//   [degree_days]
+//   sensors = ["outdoor"]          # mapping names or model::sensor_id
+//   measurement = "temperature_C"
+//   heating_base = 18.0
+//   cooling_base = 18.0
+//   interval_secs = 3600
+//
+// A day's heating degree days are `max(heating_base - mean, 0)`, its
+// cooling degree days `max(mean - cooling_base, 0)`, both in the unit of
+// the measurement. Days are stored in the `degree_days` table. Every run
+// recomputes yesterday and today plus the days of the current year that
+// have no row yet, so the first run backfills the year; earlier years are
+// not computed. The running totals of today (partial), the current month
+// and the current year are exported as
+// `degree_days_heating|cooling{sensor_id,model,period}`. Only locally
+// ingested readings are used.
+use crate::config::DegreeDayConfig;
+use crate::db::{DbHandle, DegreeDayRequest};
+use crate::mqtt_buffer::measurement_code;
+use crate::state::{resolve_sensor, Store};
+use chrono::{Datelike, Local, NaiveDate, TimeZone};
+use prometheus::{GaugeVec, Opts, Registry};
+use std::collections::HashSet;
+use std::time::Duration;
+use tracing::{debug, error, warn};
+
+pub struct DegreeDays {
+    config: DegreeDayConfig,
+    measurement_type: u8,
+    heating: GaugeVec,
+    cooling: GaugeVec,
+}
+
+/// UTC epoch milliseconds of the local midnight starting `day` (UTC
+/// midnight if a DST change skips it).
+fn local_midnight_ms(day: NaiveDate) -> i64 {
+    let midnight = day.and_hms_opt(0, 0, 0).unwrap_or_default();
+    Local
+        .from_local_datetime(&midnight)
+        .earliest()
+        .map(|t| t.timestamp_millis())
+        .unwrap_or_else(|| midnight.and_utc().timestamp_millis())
+}
+
+impl DegreeDays {
+    pub fn new(config: &DegreeDayConfig, registry: &Registry) -> anyhow::Result<Self> {
+        let measurement_type = measurement_code(&config.measurement)
+            .ok_or_else(|| anyhow::anyhow!("degree_days: unknown measurement {}", config.measurement))?;
+        let labels = &["sensor_id", "model", "period"];
+        let degree_days = Self {
+            config: config.clone(),
+            measurement_type,
+            heating: GaugeVec::new(
+                Opts::new("degree_days_heating", "Heating degree days of today, this month and this year (period)"),
+                labels,
+            )?,
+            cooling: GaugeVec::new(
+                Opts::new("degree_days_cooling", "Cooling degree days of today, this month and this year (period)"),
+                labels,
+            )?,
+        };
+        if degree_days.is_enabled() {
+            registry.register(Box::new(degree_days.heating.clone()))?;
+            registry.register(Box::new(degree_days.cooling.clone()))?;
+        }
+        Ok(degree_days)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.config.sensors.is_empty()
+    }
+
+    /// Compute the missing and recent days of every sensor and refresh the
+    /// running totals.
+    async fn update(&self, db: &DbHandle, store: &Store) -> anyhow::Result<()> {
+        let today = Local::now().date_naive();
+        let year_start = NaiveDate::from_ymd_opt(today.year(), 1, 1).unwrap_or(today);
+        let sensors: Vec<_> = {
+            let mappings = store.read().await;
+            self.config
+                .sensors
+                .iter()
+                .filter_map(|reference| {
+                    let key = resolve_sensor(reference, &mappings);
+                    if key.is_none() {
+                        warn!("Degree days: no mapping named {}", reference);
+                    }
+                    key
+                })
+                .collect()
+        };
+
+        let stored = db.degree_days(year_start).await?;
+        let mut written = 0;
+        for sensor in &sensors {
+            let done: HashSet<NaiveDate> = stored
+                .iter()
+                .filter(|d| d.model == sensor.model && d.sensor_id == sensor.sensor_id)
+                .map(|d| d.day)
+                .collect();
+            let days = year_start
+                .iter_days()
+                .take_while(|day| *day <= today)
+                .filter(|day| !done.contains(day) || *day >= today - chrono::Duration::days(1))
+                .filter_map(|day| Some((day, local_midnight_ms(day), local_midnight_ms(day.succ_opt()?))))
+                .collect();
+            written += db
+                .compute_degree_days(DegreeDayRequest {
+                    model: sensor.model.clone(),
+                    sensor_id: sensor.sensor_id.clone(),
+                    measurement_type: self.measurement_type,
+                    heating_base: self.config.heating_base,
+                    cooling_base: self.config.cooling_base,
+                    days,
+                })
+                .await?;
+        }
+        debug!("Degree days: stored {} days", written);
+
+        let stored = db.degree_days(year_start).await?;
+        self.heating.reset();
+        self.cooling.reset();
+        for sensor in &sensors {
+            let mut totals = [(0.0, 0.0); 3];
+            for day in stored.iter().filter(|d| d.model == sensor.model && d.sensor_id == sensor.sensor_id) {
+                let periods = [day.day == today, day.day.month() == today.month(), true];
+                for (total, _) in totals.iter_mut().zip(periods).filter(|(_, p)| *p) {
+                    total.0 += day.heating;
+                    total.1 += day.cooling;
+                }
+            }
+            for (period, (heating, cooling)) in ["today", "month", "year"].into_iter().zip(totals) {
+                let labels = [sensor.sensor_id.as_str(), sensor.model.as_str(), period];
+                self.heating.with_label_values(&labels).set(heating);
+                self.cooling.with_label_values(&labels).set(cooling);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Update the degree days on startup and then every `interval_secs`.
+/// Returns immediately when no sensors are configured.
+pub async fn run(degree_days: DegreeDays, db: DbHandle, store: Store) {
+    if !degree_days.is_enabled() {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(degree_days.config.interval_secs.max(60)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(e) = degree_days.update(&db, &store).await {
+            error!("Degree days failed: {}", e);
+        }
+    }
+}
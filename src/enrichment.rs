@@ -0,0 +1,388 @@
+// Label enrichment for fleet deployments: sensors get extra labels such as
+// `asset_id` or `owner` from static rules and/or an external inventory
+// (CMDB) looked up over HTTP:
+//
+//   [enrichment]
+//   labels = ["asset_id", "owner"]
+//   url = "https://cmdb.example.com/api/sensors/{model}/{sensor_id}"
+//   ttl_secs = 3600
+//
+//   [[enrichment.rules]]
+//   model = "^Acurite-"            # regexes, both optional
+//   labels = { owner = "facilities" }
+//
+// Every matching rule applies, later ones overriding earlier ones, and the
+// inventory overrides the rules. The inventory answers with a JSON object
+// whose top-level members named like a label are taken (strings, numbers
+// and booleans); `404` means no labels. Answers are cached for `ttl_secs`;
+// lookups run in the background, so a sensor's first readings after
+// startup may lack its inventory labels, and a failed lookup keeps the
+// previous answer and is retried after a minute.
+//
+// The labels are added to the per-sensor gauges (empty when unknown) and
+// every change is recorded in the `sensor_labels` table, which the
+// `measurements_labeled` view joins to the stored rows by time. The last
+// recorded labels are loaded at startup, so a restart doesn't drop them
+// while the inventory is asked again. Lookups are counted in
+// `enrichment_lookups_total{result="ok|not_found|error"}`.
+use crate::config::EnrichmentConfig;
+use crate::db::{DbHandle, SensorLabels};
+use crate::logging::{warn_limited, LogLimiter};
+use crate::state::SensorKey;
+use prometheus::{IntCounterVec, Opts, Registry};
+use regex_lite::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// Retry delay after a failed inventory lookup.
+const RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Label names the gauges already carry.
+const RESERVED: &[&str] = &["sensor_id", "model", "site", "probe", "location"];
+
+struct Rule {
+    model: Option<Regex>,
+    sensor_id: Option<Regex>,
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Default)]
+struct Cached {
+    /// Labels from the inventory.
+    inventory: BTreeMap<String, String>,
+    /// Whether the inventory has answered (or labels were loaded).
+    known: bool,
+    refresh_at: Option<Instant>,
+    pending: bool,
+    /// Labels last written to `sensor_labels`.
+    recorded: Option<BTreeMap<String, String>>,
+    /// What `values` returns until the inventory answers again.
+    values: Option<Arc<[String]>>,
+}
+
+/// Cached labels by model and then sensor id, so lookups by `&str` need no
+/// key allocated per message.
+type Cache = HashMap<String, HashMap<String, Cached>>;
+
+/// The cache entry of a sensor, created when missing.
+fn cached<'a>(cache: &'a mut Cache, model: &str, sensor_id: &str) -> &'a mut Cached {
+    if !cache.get(model).is_some_and(|sensors| sensors.contains_key(sensor_id)) {
+        cache.entry(model.to_string()).or_default().insert(sensor_id.to_string(), Cached::default());
+    }
+    cache.get_mut(model).and_then(|sensors| sensors.get_mut(sensor_id)).expect("entry was just created")
+}
+
+pub struct Enrichment {
+    labels: Vec<String>,
+    url: Option<String>,
+    ttl: Duration,
+    rules: Vec<Rule>,
+    http: reqwest::Client,
+    db: DbHandle,
+    cache: Mutex<Cache>,
+    /// `values` without labels configured.
+    none: Arc<[String]>,
+    log: Arc<LogLimiter>,
+    lookups: IntCounterVec,
+}
+
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__")
+}
+
+/// Percent-encode a URL path segment.
+fn encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl Enrichment {
+    /// Validate the configuration and register the lookup counter. Label
+    /// names must be valid Prometheus label names not used by the gauges,
+    /// and rules may only set configured labels.
+    pub fn new(config: &EnrichmentConfig, db: DbHandle, log: Arc<LogLimiter>, registry: &Registry) -> anyhow::Result<Self> {
+        for name in &config.labels {
+            if !is_label_name(name) || RESERVED.contains(&name.as_str()) {
+                anyhow::bail!("enrichment: invalid label name {}", name);
+            }
+        }
+        let compile = |pattern: &Option<String>| -> anyhow::Result<Option<Regex>> {
+            pattern
+                .as_deref()
+                .map(|p| Regex::new(p).map_err(|e| anyhow::anyhow!("enrichment: invalid rule regex {}: {}", p, e)))
+                .transpose()
+        };
+        let mut rules = Vec::new();
+        for rule in &config.rules {
+            if let Some(name) = rule.labels.keys().find(|name| !config.labels.contains(name)) {
+                anyhow::bail!("enrichment: rule sets {}, which is not in enrichment.labels", name);
+            }
+            rules.push(Rule { model: compile(&rule.model)?, sensor_id: compile(&rule.sensor_id)?, labels: rule.labels.clone() });
+        }
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(token) = &config.bearer_token {
+            headers.insert(reqwest::header::AUTHORIZATION, format!("Bearer {}", token).parse()?);
+        }
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs.max(1)))
+            .default_headers(headers)
+            .build()?;
+        let lookups = IntCounterVec::new(
+            Opts::new("enrichment_lookups_total", "Inventory lookups of sensor labels by result"),
+            &["result"],
+        )?;
+        if !config.labels.is_empty() && config.url.is_some() {
+            registry.register(Box::new(lookups.clone()))?;
+        }
+        Ok(Self {
+            labels: config.labels.clone(),
+            url: config.url.clone(),
+            ttl: Duration::from_secs(config.ttl_secs.max(1)),
+            rules,
+            http,
+            db,
+            cache: Mutex::new(HashMap::new()),
+            none: Arc::from(Vec::new()),
+            log,
+            lookups,
+        })
+    }
+
+    /// Names of the extra labels, in the order of `values`.
+    pub fn names(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// Fill the cache with the labels recorded before the last restart.
+    /// Runs in the background so a locked database doesn't delay startup.
+    pub async fn load(&self) -> anyhow::Result<()> {
+        if self.labels.is_empty() {
+            return Ok(());
+        }
+        let stored = self.db.current_labels().await?;
+        let mut cache = self.cache.lock().unwrap();
+        for sensor in &stored {
+            // Sensors heard while loading keep what they already have.
+            let cached = cached(&mut cache, &sensor.model, &sensor.sensor_id);
+            if !cached.known {
+                cached.inventory = sensor.labels.clone();
+                cached.known = true;
+                cached.values = None;
+            }
+            cached.recorded.get_or_insert_with(|| sensor.labels.clone());
+        }
+        info!("Loaded labels of {} sensors", stored.len());
+        Ok(())
+    }
+
+    fn rule_labels(&self, model: &str, sensor_id: &str) -> BTreeMap<String, String> {
+        let mut labels = BTreeMap::new();
+        for rule in &self.rules {
+            let matches = |regex: &Option<Regex>, value: &str| regex.as_ref().is_none_or(|r| r.is_match(value));
+            if matches(&rule.model, model) && matches(&rule.sensor_id, sensor_id) {
+                labels.extend(rule.labels.clone());
+            }
+        }
+        labels
+    }
+
+    /// Values of the extra labels of a sensor, empty where unknown. Starts
+    /// an inventory lookup when the cached answer is missing or expired and
+    /// records changed labels. The values are cached per sensor, so a known
+    /// sensor's messages only take a reference.
+    pub fn values(self: &Arc<Self>, model: &str, sensor_id: &str) -> Arc<[String]> {
+        if self.labels.is_empty() {
+            return self.none.clone();
+        }
+        let mut cache = self.cache.lock().unwrap();
+        let cached = cached(&mut cache, model, sensor_id);
+        if self.url.is_some() && !cached.pending && cached.refresh_at.is_none_or(|at| at <= Instant::now()) {
+            cached.pending = true;
+            let key = SensorKey { model: model.to_string(), sensor_id: sensor_id.to_string() };
+            tokio::spawn(self.clone().lookup(key));
+        }
+        if let Some(values) = &cached.values {
+            return values.clone();
+        }
+        let mut labels = self.rule_labels(model, sensor_id);
+        labels.extend(cached.inventory.clone());
+        let values: Arc<[String]> = self.labels.iter().map(|name| labels.get(name).cloned().unwrap_or_default()).collect();
+        // Wait for the first inventory answer so a sensor's history
+        // doesn't start with rule-only labels.
+        if cached.known || self.url.is_none() {
+            cached.values = Some(values.clone());
+            if cached.recorded.as_ref() != Some(&labels) {
+                cached.recorded = Some(labels.clone());
+                let db = self.db.clone();
+                let sensor = SensorLabels { model: model.to_string(), sensor_id: sensor_id.to_string(), labels };
+                tokio::spawn(async move {
+                    if let Err(e) = db.record_labels(sensor).await {
+                        error!("Recording sensor labels failed: {}", e);
+                    }
+                });
+            }
+        }
+        values
+    }
+
+    /// `values` without looking anything up or recording labels, for
+    /// previews: the cached values, or the rule labels (and any inventory
+    /// answer) of a sensor not labelled yet.
+    pub fn peek(&self, model: &str, sensor_id: &str) -> Vec<String> {
+        let cache = self.cache.lock().unwrap();
+        let cached = cache.get(model).and_then(|sensors| sensors.get(sensor_id));
+        if let Some(values) = cached.and_then(|cached| cached.values.as_ref()) {
+            return values.to_vec();
+        }
+        let mut labels = self.rule_labels(model, sensor_id);
+        labels.extend(cached.map(|cached| cached.inventory.clone()).unwrap_or_default());
+        self.labels.iter().map(|name| labels.get(name).cloned().unwrap_or_default()).collect()
+    }
+
+    async fn lookup(self: Arc<Self>, key: SensorKey) {
+        let result = self.fetch(&key).await;
+        let mut cache = self.cache.lock().unwrap();
+        let cached = cached(&mut cache, &key.model, &key.sensor_id);
+        cached.pending = false;
+        match result {
+            Ok(inventory) => {
+                self.lookups.with_label_values(&[if inventory.is_some() { "ok" } else { "not_found" }]).inc();
+                cached.inventory = inventory.unwrap_or_default();
+                cached.known = true;
+                cached.values = None;
+                cached.refresh_at = Some(Instant::now() + self.ttl);
+            }
+            Err(e) => {
+                self.lookups.with_label_values(&["error"]).inc();
+                warn_limited!(self.log, "enrichment", "Label lookup for {} {} failed: {}", key.model, key.sensor_id, e);
+                cached.refresh_at = Some(Instant::now() + RETRY_AFTER.min(self.ttl));
+            }
+        }
+    }
+
+    /// The configured labels found in the inventory, `None` on `404`.
+    async fn fetch(&self, key: &SensorKey) -> anyhow::Result<Option<BTreeMap<String, String>>> {
+        let Some(template) = &self.url else { return Ok(None) };
+        let url = template.replace("{model}", &encode(&key.model)).replace("{sensor_id}", &encode(&key.sensor_id));
+        let response = self.http.get(&url).send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let body: serde_json::Value = serde_json::from_slice(&response.error_for_status()?.bytes().await?)?;
+        let object = body.as_object().ok_or_else(|| anyhow::anyhow!("expected a JSON object"))?;
+        let labels = self
+            .labels
+            .iter()
+            .filter_map(|name| {
+                let value = match object.get(name)? {
+                    serde_json::Value::String(s) => s.clone(),
+                    serde_json::Value::Number(n) => n.to_string(),
+                    serde_json::Value::Bool(b) => b.to_string(),
+                    _ => return None,
+                };
+                Some((name.clone(), value))
+            })
+            .collect();
+        Ok(Some(labels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{EnrichmentRule, LoggingConfig};
+    use crate::db::{start_db_worker, DbConfig};
+
+    fn rule(model: Option<&str>, sensor_id: Option<&str>, labels: &[(&str, &str)]) -> EnrichmentRule {
+        EnrichmentRule {
+            model: model.map(str::to_string),
+            sensor_id: sensor_id.map(str::to_string),
+            labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
+
+    fn config(rules: Vec<EnrichmentRule>) -> EnrichmentConfig {
+        EnrichmentConfig { labels: vec!["asset_id".to_string(), "owner".to_string()], rules, ..EnrichmentConfig::default() }
+    }
+
+    fn build(config: &EnrichmentConfig, db: &DbHandle) -> anyhow::Result<Enrichment> {
+        let log = Arc::new(LogLimiter::new(&LoggingConfig::default()));
+        Enrichment::new(config, db.clone(), log, &Registry::new())
+    }
+
+    #[test]
+    fn label_names_and_url_segments() {
+        for name in ["owner", "_x", "asset_id2"] {
+            assert!(is_label_name(name), "{}", name);
+        }
+        for name in ["", "2x", "__meta", "asset-id", "ownér"] {
+            assert!(!is_label_name(name), "{}", name);
+        }
+        assert_eq!(encode("Acurite-Tower"), "Acurite-Tower");
+        assert_eq!(encode("a/b c"), "a%2Fb%20c");
+        assert_eq!(encode("é"), "%C3%A9");
+    }
+
+    #[tokio::test]
+    async fn rules_label_sensors_and_loaded_labels_override_them() {
+        let dir = std::env::temp_dir().join(format!("exporter-enrichment-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let (db, _worker) = start_db_worker(&db_config, &Registry::new()).unwrap();
+
+        let mut reserved = config(Vec::new());
+        reserved.labels.push("site".to_string());
+        assert!(build(&reserved, &db).is_err());
+        assert!(build(&config(vec![rule(None, None, &[("room", "attic")])]), &db).is_err());
+        assert!(build(&config(vec![rule(Some("("), None, &[])]), &db).is_err());
+        let none = build(&EnrichmentConfig::default(), &db).map(Arc::new).unwrap();
+        assert!(none.values("Acurite-Tower", "1").is_empty());
+
+        // Every matching rule applies, later ones overriding earlier ones.
+        let rules = vec![
+            rule(Some("^Acurite-"), None, &[("owner", "facilities"), ("asset_id", "A")]),
+            rule(None, Some("^2$"), &[("owner", "lab")]),
+        ];
+        let enrichment = Arc::new(build(&config(rules.clone()), &db).unwrap());
+        assert_eq!(enrichment.names(), ["asset_id", "owner"]);
+        assert_eq!(&*enrichment.values("Acurite-Tower", "1"), ["A", "facilities"]);
+        assert_eq!(&*enrichment.values("Acurite-Tower", "2"), ["A", "lab"]);
+        assert_eq!(&*enrichment.values("Oregon-THGR122N", "1"), ["", ""]);
+
+        // The labels are recorded in the background; a sensor without any
+        // has nothing to load.
+        let mut recorded = Vec::new();
+        for _ in 0..100 {
+            recorded = db.current_labels().await.unwrap();
+            if recorded.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut owners: Vec<_> = recorded.iter().map(|s| (s.sensor_id.as_str(), s.labels.get("owner").cloned())).collect();
+        owners.sort();
+        assert_eq!(owners, [("1", Some("facilities".to_string())), ("2", Some("lab".to_string()))]);
+
+        // Loaded labels stand in for the inventory, which overrides rules.
+        let mut labels = BTreeMap::new();
+        labels.insert("asset_id".to_string(), "B".to_string());
+        db.record_labels(SensorLabels { model: "Acurite-Tower".to_string(), sensor_id: "1".to_string(), labels })
+            .await
+            .unwrap();
+        let restarted = Arc::new(build(&config(rules), &db).unwrap());
+        restarted.load().await.unwrap();
+        assert_eq!(&*restarted.values("Acurite-Tower", "1"), ["B", "facilities"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
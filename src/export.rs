@@ -0,0 +1,98 @@
+// Offline export. `export` writes stored readings, archived Parquet slices
+// included, to a Parquet (default) or CSV file, the same file a query job
+// (see `jobs`) produces, without a running server:
+//
+// Run with: `rust-to-mqtt-prometheus-exporter export [--format parquet|csv] [--start DATE] [--end DATE] [--sensor ID] [--model MODEL] [--type KEY]... [file]`
+//
+// `--start` and `--end` take a date (`2024-03-01`, the whole day) or an RFC
+// 3339 time and are inclusive. The file defaults to `measurements.parquet`
+// (or `.csv`). Like `import`, it opens the database itself, so stop the
+// server first, or use `POST /api/jobs/query` against a running one.
+use crate::config::Config;
+use crate::db::{self, ExportFormat, LabelColumn, LabelCondition, MeasurementQuery, QueryExport};
+use crate::mqtt_buffer::{install_measurement_types, measurement_code};
+use chrono::{DateTime, NaiveDate, NaiveTime};
+
+/// Options of the `export` command.
+#[derive(clap::Args)]
+pub struct Args {
+    /// File format.
+    #[arg(long, value_enum, default_value = "parquet")]
+    format: Format,
+    /// Oldest reading: a date or an RFC 3339 time.
+    #[arg(long)]
+    start: Option<String>,
+    /// Newest reading: a date (up to its end) or an RFC 3339 time.
+    #[arg(long)]
+    end: Option<String>,
+    /// Only readings of this sensor id.
+    #[arg(long, value_name = "ID")]
+    sensor: Option<String>,
+    /// Only readings of this model.
+    #[arg(long)]
+    model: Option<String>,
+    /// Only this measurement key; repeat for several.
+    #[arg(long = "type", value_name = "KEY")]
+    measurements: Vec<String>,
+    /// Output file; defaults to `measurements.<format>`.
+    #[arg(value_name = "FILE")]
+    output: Option<String>,
+}
+
+/// `--format`; the DB layer's `ExportFormat` stays free of clap.
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Format {
+    Parquet,
+    Csv,
+}
+
+impl From<Format> for ExportFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Parquet => ExportFormat::Parquet,
+            Format::Csv => ExportFormat::Csv,
+        }
+    }
+}
+
+/// Epoch milliseconds of `raw`; a date means its first millisecond, or its
+/// last with `end_of_day`.
+fn parse_time(raw: &str, end_of_day: bool) -> anyhow::Result<i64> {
+    if let Ok(date) = NaiveDate::parse_from_str(raw, "%Y-%m-%d") {
+        let time = if end_of_day { NaiveTime::from_hms_milli_opt(23, 59, 59, 999) } else { Some(NaiveTime::MIN) };
+        return Ok(date.and_time(time.unwrap_or_default()).and_utc().timestamp_millis());
+    }
+    let time = DateTime::parse_from_rfc3339(raw).map_err(|e| anyhow::anyhow!("{:?} is neither a date nor an RFC 3339 time: {}", raw, e))?;
+    Ok(time.timestamp_millis())
+}
+
+/// Entry point for the `export` command.
+pub async fn run_cli(args: Args) -> anyhow::Result<()> {
+    let config = Config::load()?;
+    crate::logging::init(&config.logging)?;
+    install_measurement_types(&config.measurements)?;
+    let mut measurement_types = Vec::new();
+    for key in &args.measurements {
+        measurement_types.push(measurement_code(key).ok_or_else(|| anyhow::anyhow!("unknown measurement {}", key))?);
+    }
+    let mut conditions = Vec::new();
+    if let Some(sensor_id) = args.sensor {
+        conditions.push(LabelCondition::Eq(LabelColumn::SensorId, sensor_id));
+    }
+    if let Some(model) = args.model {
+        conditions.push(LabelCondition::Eq(LabelColumn::Model, model));
+    }
+    let selection = MeasurementQuery {
+        start_ms: args.start.as_deref().map(|s| parse_time(s, false)).transpose()?.unwrap_or(i64::MIN),
+        end_ms: args.end.as_deref().map(|s| parse_time(s, true)).transpose()?.unwrap_or(i64::MAX),
+        measurement_types,
+        conditions,
+    };
+    let format = ExportFormat::from(args.format);
+    let path = args.output.unwrap_or_else(|| format!("measurements.{}", format.extension()));
+
+    let (db, _worker) = db::start_db_worker(&config.duckdb, &prometheus::Registry::new())?;
+    let rows = db.export_query(QueryExport { selection, path: path.clone(), format }).await?;
+    println!("Exported {} rows to {}", rows, path);
+    Ok(())
+}
@@ -0,0 +1,223 @@
+// The periodic flush. It runs on monotonic deadlines instead of a plain
+// `tokio::time::interval`, which fires a burst of ticks after a laptop or
+// SBC resumes from suspend or the runtime was starved for a while. Each
+// tick notices how far behind schedule it ran and what to do about the
+// missed ticks is configurable:
+//
+//   [flush]
+//   interval_secs = 30
+//   missed_ticks = "skip"    # or "delay" / "burst"
+//
+// `skip` (default) flushes once and continues on the original schedule,
+// `delay` flushes once and waits a full interval from then, `burst` flushes
+// once per missed tick, back to back (at most `MAX_BURST`), as the old
+// interval did. The monotonic clock stands still while the host is
+// suspended, so a suspend shows up as the wall clock getting ahead of it and
+// is counted the same way; a step of the wall clock looks alike.
+//
+// Ticks that ran at least an interval late are logged and counted in
+// `ingest_flush_catchups_total`, the ticks they missed in
+// `ingest_flush_missed_ticks_total`. Besides the ticks, the buffer is
+// checked once a second so age limits hold between messages; flush classes
+// with their own age limit are only written by that check.
+use crate::config::{FlushConfig, MissedTicks};
+use crate::db::DbHandle;
+use crate::mqtt_buffer::{FlushScope, MqttBuffer};
+use chrono::Utc;
+use prometheus::{IntCounter, Registry};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{error, info, warn};
+
+/// Most flushes a `burst` catch-up runs back to back.
+const MAX_BURST: u32 = 100;
+
+/// Period and catch-up behaviour of the periodic flush, reloadable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlushSchedule {
+    pub period: Duration,
+    pub missed_ticks: MissedTicks,
+}
+
+impl FlushSchedule {
+    pub fn from_config(config: &FlushConfig) -> Self {
+        Self { period: Duration::from_secs(config.interval_secs.max(1)), missed_ticks: config.missed_ticks }
+    }
+}
+
+pub struct FlushTimerMetrics {
+    catchups: IntCounter,
+    missed: IntCounter,
+}
+
+impl FlushTimerMetrics {
+    pub fn new(registry: &Registry) -> anyhow::Result<Self> {
+        let metrics = Self {
+            catchups: IntCounter::new(
+                "ingest_flush_catchups_total",
+                "Periodic flushes that ran at least one interval behind schedule",
+            )?,
+            missed: IntCounter::new("ingest_flush_missed_ticks_total", "Periodic flush ticks missed while behind schedule")?,
+        };
+        registry.register(Box::new(metrics.catchups.clone()))?;
+        registry.register(Box::new(metrics.missed.clone()))?;
+        Ok(metrics)
+    }
+}
+
+/// What a tick does about the ticks it missed.
+#[derive(Debug, PartialEq, Eq)]
+struct CatchUp {
+    missed: u32,
+    next: Instant,
+    /// Flushes a burst owes after this one.
+    owed: u32,
+}
+
+/// The catch-up of a tick due at `deadline` that ran at `now`, the host
+/// having been suspended for `suspended` since the previous tick.
+fn catch_up(schedule: FlushSchedule, deadline: Instant, now: Instant, suspended: Duration) -> CatchUp {
+    let period = schedule.period;
+    let late = now.saturating_duration_since(deadline);
+    let missed = ((late + suspended).as_secs_f64() / period.as_secs_f64()) as u32;
+    let (next, owed) = match schedule.missed_ticks {
+        // The next deadline on the original grid after now.
+        MissedTicks::Skip => (deadline + period * (late.as_secs_f64() / period.as_secs_f64()) as u32 + period, 0),
+        MissedTicks::Delay => (now + period, 0),
+        MissedTicks::Burst => {
+            let owed = missed.min(MAX_BURST);
+            (if owed > 0 { now } else { deadline + period }, owed)
+        }
+    };
+    CatchUp { missed, next, owed }
+}
+
+/// Flush the buffer on the schedule, even if no limit of the flush policy
+/// is reached (see `FlushScope::Periodic`), and once a second the classes
+/// whose age limit passed between messages. Waits for an in-flight flush
+/// instead of skipping so no tick is lost.
+pub async fn run(
+    buffer: Arc<MqttBuffer>,
+    db: DbHandle,
+    mut schedule: watch::Receiver<FlushSchedule>,
+    metrics: FlushTimerMetrics,
+    stop: Arc<Notify>,
+) {
+    let mut current = *schedule.borrow_and_update();
+    let mut deadline = Instant::now() + current.period;
+    // Monotonic and wall clock at the previous tick.
+    let mut last = (Instant::now(), Utc::now());
+    // Flushes a burst still owes.
+    let mut owed = 0;
+    let mut check = tokio::time::interval(Duration::from_secs(1));
+    check.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        let scope = tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => {
+                let now = Instant::now();
+                if owed > 0 {
+                    owed -= 1;
+                    deadline = if owed > 0 { now } else { now + current.period };
+                } else {
+                    let wall = Utc::now();
+                    let late = now.saturating_duration_since(deadline);
+                    let suspended = (wall - last.1).to_std().unwrap_or_default().saturating_sub(now.saturating_duration_since(last.0));
+                    last = (now, wall);
+                    let catch_up = catch_up(current, deadline, now, suspended);
+                    if catch_up.missed > 0 {
+                        metrics.catchups.inc();
+                        metrics.missed.inc_by(catch_up.missed as u64);
+                        warn!(
+                            "Periodic flush ran {}s behind schedule ({} ticks missed; host suspended or overloaded?), catching up with {:?}",
+                            (late + suspended).as_secs(),
+                            catch_up.missed,
+                            current.missed_ticks
+                        );
+                    }
+                    deadline = catch_up.next;
+                    owed = catch_up.owed;
+                }
+                FlushScope::Periodic
+            }
+            _ = stop.notified() => return,
+            Ok(()) = schedule.changed() => {
+                current = *schedule.borrow_and_update();
+                deadline = Instant::now() + current.period;
+                last = (Instant::now(), Utc::now());
+                owed = 0;
+                continue;
+            }
+            _ = check.tick() => {
+                if !buffer.is_due() {
+                    continue;
+                }
+                FlushScope::Due
+            }
+        };
+        match buffer.flush_scope(&db, scope).await {
+            Ok(0) => {}
+            Ok(n) => info!("Flushed {} rows to DuckDB", n),
+            Err(e) => error!("Periodic flush failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_secs(30);
+
+    fn schedule(missed_ticks: MissedTicks) -> FlushSchedule {
+        FlushSchedule { period: PERIOD, missed_ticks }
+    }
+
+    #[test]
+    fn a_tick_on_time_keeps_the_schedule() {
+        let deadline = Instant::now();
+        let now = deadline + Duration::from_millis(5);
+        for missed_ticks in [MissedTicks::Skip, MissedTicks::Delay, MissedTicks::Burst] {
+            let expected = if missed_ticks == MissedTicks::Delay { now + PERIOD } else { deadline + PERIOD };
+            assert_eq!(
+                catch_up(schedule(missed_ticks), deadline, now, Duration::ZERO),
+                CatchUp { missed: 0, next: expected, owed: 0 }
+            );
+        }
+    }
+
+    #[test]
+    fn late_ticks_are_caught_up_as_configured() {
+        let deadline = Instant::now();
+        let now = deadline + Duration::from_secs(100);
+        // Skip stays on the original grid, the first deadline after now.
+        assert_eq!(
+            catch_up(schedule(MissedTicks::Skip), deadline, now, Duration::ZERO),
+            CatchUp { missed: 3, next: deadline + Duration::from_secs(120), owed: 0 }
+        );
+        assert_eq!(
+            catch_up(schedule(MissedTicks::Delay), deadline, now, Duration::ZERO),
+            CatchUp { missed: 3, next: now + PERIOD, owed: 0 }
+        );
+        assert_eq!(
+            catch_up(schedule(MissedTicks::Burst), deadline, now, Duration::ZERO),
+            CatchUp { missed: 3, next: now, owed: 3 }
+        );
+    }
+
+    #[test]
+    fn suspends_count_as_missed_ticks_and_bursts_are_bounded() {
+        let deadline = Instant::now();
+        // The monotonic clock stood still for a day.
+        let day = Duration::from_secs(86400);
+        assert_eq!(
+            catch_up(schedule(MissedTicks::Skip), deadline, deadline, day),
+            CatchUp { missed: 2880, next: deadline + PERIOD, owed: 0 }
+        );
+        assert_eq!(
+            catch_up(schedule(MissedTicks::Burst), deadline, deadline, day),
+            CatchUp { missed: 2880, next: deadline, owed: MAX_BURST }
+        );
+    }
+}
@@ -0,0 +1,336 @@
+// Grafana JSON datasource API, so Grafana can graph stored history straight
+// from DuckDB without a separate TSDB. Compatible with the SimpleJSON /
+// JSON datasource plugins (URL `http://exporter:3000/grafana`) and usable
+// from Infinity as a JSON source:
+//
+//   GET  /grafana          connection test
+//   POST /grafana/search   {"target": "Acurite"} -> matching series
+//   POST /grafana/query    {"range": {"from": ..., "to": ...},
+//                           "targets": [{"target": "Acurite-Tower/1234/temperature_C"}],
+//                           "maxDataPoints": 1000}
+//
+// A target names one stored series as `model/sensor_id/measurement`; the
+// model is everything before the first `/`, the measurement everything
+// after the last. A probe of a multi-probe sensor follows the measurement in
+// brackets (`Acurite-Tower/1234/temperature_C[2]`). Search lists the series of the hourly rollups plus those
+// with rows not rolled up yet, instead of scanning every stored row. Queries
+// average `measurements` (and overlapping Parquet archives) for that sensor
+// and type within the range into `time_bucket` buckets in DuckDB, as
+// `/api/series` does, sized so there are about `maxDataPoints` of them;
+// each point is stamped with its bucket's start.
+// `timeserie` targets return `[value, epoch_ms]` datapoints, `table`
+// targets a Time/Value table. Values are in the sensor's mapping unit, like
+// the gauges (see `units`).
+use crate::db::{DbHandle, LabelColumn, LabelCondition, MeasurementQuery, RollupRow, SeriesQuery};
+use crate::mqtt_buffer::{measurement_code, measurement_name};
+use crate::problem::{self, Problem};
+use crate::state::{key_for, Store};
+use crate::units;
+use axum::{extract::Extension, Json};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+/// Series offered by `/grafana/search` at most.
+const SEARCH_LIMIT: usize = 1000;
+
+/// Datapoints per series when Grafana doesn't send `maxDataPoints`.
+const DEFAULT_MAX_POINTS: usize = 1000;
+
+/// Stored series: those of `measurements_1h`, which outlives retention, and
+/// those of rows the rollup has not reached yet (all rows while rollups
+/// are off).
+const SERIES_SQL: &str = "SELECT DISTINCT model, sensor_id, measurement_type, probe FROM (\
+     SELECT model, sensor_id, measurement_type, probe FROM measurements_1h \
+     UNION ALL \
+     SELECT model, sensor_id, measurement_type, COALESCE(probe, '') FROM measurements \
+     WHERE seq > COALESCE((SELECT last_seq FROM rollup_state WHERE name = 'measurements'), 0)) \
+     ORDER BY model, sensor_id, measurement_type, probe";
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct SearchRequest {
+    /// Case-insensitive substring of the series names to offer.
+    target: String,
+}
+
+#[derive(Deserialize)]
+pub struct QueryRequest {
+    range: TimeRange,
+    targets: Vec<Target>,
+    #[serde(rename = "maxDataPoints")]
+    max_data_points: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct TimeRange {
+    /// RFC 3339, e.g. `2026-10-01T00:00:00.000Z`.
+    from: String,
+    to: String,
+}
+
+#[derive(Deserialize)]
+pub struct Target {
+    target: String,
+    #[serde(rename = "type", default = "default_type")]
+    kind: String,
+    /// Hidden targets are skipped.
+    #[serde(default)]
+    hide: bool,
+}
+
+fn default_type() -> String {
+    "timeserie".to_string()
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum QueryResult {
+    TimeSeries { target: String, datapoints: Vec<(f64, i64)> },
+    Table { columns: Vec<Column>, rows: Vec<(i64, f64)>, r#type: &'static str },
+}
+
+#[derive(Serialize)]
+pub struct Column {
+    text: &'static str,
+    r#type: &'static str,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct SeriesName {
+    model: String,
+    sensor_id: String,
+    measurement_type: u8,
+    probe: String,
+}
+
+impl SeriesName {
+    /// The series a target names, or the reason it names none.
+    fn parse(target: &str) -> Result<Self, String> {
+        let (model, rest) = target.split_once('/').ok_or("expected model/sensor_id/measurement")?;
+        let (sensor_id, measurement) = rest.rsplit_once('/').ok_or("expected model/sensor_id/measurement")?;
+        let (measurement, probe) = match measurement.strip_suffix(']').and_then(|m| m.split_once('[')) {
+            Some((measurement, probe)) => (measurement, probe),
+            None => (measurement, ""),
+        };
+        let measurement_type = measurement_code(measurement).ok_or_else(|| format!("unknown measurement {}", measurement))?;
+        Ok(Self { model: model.to_string(), sensor_id: sensor_id.to_string(), measurement_type, probe: probe.to_string() })
+    }
+
+    /// The target naming this series, `None` for unknown measurement types.
+    fn target(&self) -> Option<String> {
+        let measurement = measurement_name(self.measurement_type)?;
+        Some(match self.probe.as_str() {
+            "" => format!("{}/{}/{}", self.model, self.sensor_id, measurement),
+            probe => format!("{}/{}/{}[{}]", self.model, self.sensor_id, measurement, probe),
+        })
+    }
+
+    /// The query selecting the series' rows within the range.
+    fn query(&self, start_ms: i64, end_ms: i64) -> MeasurementQuery {
+        MeasurementQuery {
+            start_ms,
+            end_ms,
+            measurement_types: vec![self.measurement_type],
+            conditions: vec![
+                LabelCondition::Eq(LabelColumn::Model, self.model.clone()),
+                LabelCondition::Eq(LabelColumn::SensorId, self.sensor_id.clone()),
+                LabelCondition::Eq(LabelColumn::Probe, self.probe.clone()),
+            ],
+        }
+    }
+}
+
+fn parse_time(value: &str) -> Result<i64, Problem> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.timestamp_millis())
+        .map_err(|e| Problem::invalid(format!("invalid range {}: {}", value, e)))
+}
+
+/// Bucket width in milliseconds giving about `max_points` buckets over the
+/// range (one more where the range straddles bucket boundaries).
+fn step_ms(start_ms: i64, end_ms: i64, max_points: usize) -> i64 {
+    let points = max_points.max(1) as i64;
+    ((end_ms - start_ms).max(1) + points - 1) / points
+}
+
+/// One `[value, epoch_ms]` point per bucket. Buckets of several sites (in
+/// aggregator mode) come one after the other and are averaged by count.
+fn datapoints(buckets: Vec<RollupRow>) -> Vec<(f64, i64)> {
+    let mut points: Vec<(f64, i64)> = Vec::with_capacity(buckets.len());
+    let mut weight = 0;
+    for bucket in buckets {
+        let ts = bucket.bucket.and_utc().timestamp_millis();
+        match points.last_mut() {
+            Some((avg, last)) if *last == ts => {
+                *avg = (*avg * weight as f64 + bucket.avg * bucket.count as f64) / (weight + bucket.count) as f64;
+                weight += bucket.count;
+            }
+            _ => {
+                points.push((bucket.avg, ts));
+                weight = bucket.count;
+            }
+        }
+    }
+    points
+}
+
+/// `GET /grafana`: lets Grafana's "Save & test" succeed.
+pub async fn test_handler() -> &'static str {
+    "OK"
+}
+
+/// `POST /grafana/search`: names of the stored series containing `target`.
+pub async fn search_handler(
+    Extension(db): Extension<DbHandle>,
+    body: Option<Json<SearchRequest>>,
+) -> Result<Json<Vec<String>>, Problem> {
+    let filter = body.map(|Json(b)| b.target).unwrap_or_default().to_lowercase();
+    let series: Vec<SeriesName> = problem::query(&db, db.query_as(SERIES_SQL, Vec::new())).await?;
+    let names = series
+        .into_iter()
+        .filter_map(|s| s.target())
+        .filter(|name| name.to_lowercase().contains(&filter))
+        .take(SEARCH_LIMIT)
+        .collect();
+    Ok(Json(names))
+}
+
+/// `POST /grafana/query`: each target's readings within the range, averaged
+/// into buckets.
+pub async fn query_handler(
+    Extension(db): Extension<DbHandle>,
+    Extension(store): Extension<Store>,
+    Json(request): Json<QueryRequest>,
+) -> Result<Json<Vec<QueryResult>>, Problem> {
+    let start_ms = parse_time(&request.range.from)?;
+    let end_ms = parse_time(&request.range.to)?;
+    let step_ms = step_ms(start_ms, end_ms, request.max_data_points.unwrap_or(DEFAULT_MAX_POINTS));
+    let mut results = Vec::new();
+    for target in request.targets.iter().filter(|t| !t.hide && !t.target.is_empty()) {
+        let name = SeriesName::parse(&target.target).map_err(|e| Problem::invalid(format!("target {}: {}", target.target, e)))?;
+        let series = SeriesQuery { selection: name.query(start_ms, end_ms), step_ms, limit: usize::MAX, after: None };
+        let buckets = problem::query(&db, db.query_series(series)).await?;
+        let mapping = store.read().await.get(&key_for(&name.sensor_id, &name.model)).cloned();
+        let points: Vec<(f64, i64)> = datapoints(buckets)
+            .into_iter()
+            .map(|(value, ts)| (units::to_mapped_unit(value, name.measurement_type, mapping.as_ref()), ts))
+            .collect();
+        results.push(match target.kind.as_str() {
+            "table" => QueryResult::Table {
+                columns: vec![Column { text: "Time", r#type: "time" }, Column { text: "Value", r#type: "number" }],
+                rows: points.into_iter().map(|(value, ts)| (ts, value)).collect(),
+                r#type: "table",
+            },
+            _ => QueryResult::TimeSeries { target: target.target.clone(), datapoints: points },
+        });
+    }
+    Ok(Json(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{start_db_worker, DbConfig};
+    use crate::mqtt_buffer::{rows_to_record_batch, NormalizedRow};
+    use chrono::NaiveDateTime;
+
+    fn time(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn bucket(at: &str, site: &str, avg: f64, count: i64) -> RollupRow {
+        RollupRow {
+            bucket: time(at),
+            sensor_id: "1".to_string(),
+            model: "Acurite-Tower".to_string(),
+            measurement_type: 1,
+            site: site.to_string(),
+            min: avg,
+            max: avg,
+            avg,
+            count,
+            probe: String::new(),
+        }
+    }
+
+    fn row(sensor_id: &str, at: &str, value: f64) -> NormalizedRow {
+        NormalizedRow {
+            timestamp: time(at),
+            sensor_id: sensor_id.to_string(),
+            model: "Acurite-Tower".to_string(),
+            measurement_type: measurement_code("temperature_C").unwrap(),
+            value,
+            topic: "rtl_433/events".to_string(),
+            seq: 0,
+            site: String::new(),
+            probe: String::new(),
+        }
+    }
+
+    #[test]
+    fn targets_name_model_sensor_and_measurement() {
+        let name = SeriesName::parse("Fineoffset/WH24/7/temperature_C").unwrap();
+        assert_eq!((name.model.as_str(), name.sensor_id.as_str()), ("Fineoffset", "WH24/7"));
+        assert_eq!(Some(name.measurement_type), measurement_code("temperature_C"));
+        assert!(SeriesName::parse("Acurite-Tower/1").is_err());
+        assert_eq!(SeriesName::parse("Acurite-Tower/1/nope").unwrap_err(), "unknown measurement nope");
+        let name = SeriesName::parse("Inkbird-ITH20R/42/temperature_C[2]").unwrap();
+        assert_eq!((name.sensor_id.as_str(), name.probe.as_str()), ("42", "2"));
+        assert_eq!(name.target().as_deref(), Some("Inkbird-ITH20R/42/temperature_C[2]"));
+    }
+
+    #[test]
+    fn steps_spread_the_range_over_the_points() {
+        assert_eq!(step_ms(0, 3_600_000, 60), 60_000);
+        assert_eq!(step_ms(0, 1_000, 3), 334);
+        assert_eq!(step_ms(0, 0, 0), 1);
+    }
+
+    #[test]
+    fn buckets_of_several_sites_are_averaged_by_count() {
+        let points = datapoints(vec![
+            bucket("2024-03-01 08:00:00", "", 10.0, 1),
+            bucket("2024-03-01 08:00:00", "north", 20.0, 3),
+            bucket("2024-03-01 08:05:00", "", 5.0, 2),
+        ]);
+        assert_eq!(points, vec![(17.5, time("2024-03-01 08:00:00").and_utc().timestamp_millis()), (5.0, time("2024-03-01 08:05:00").and_utc().timestamp_millis())]);
+    }
+
+    #[tokio::test]
+    async fn search_and_query_read_rollups_and_buckets() {
+        let dir = std::env::temp_dir().join(format!("exporter-grafana-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let (db, _worker) = start_db_worker(&config, &prometheus::Registry::new()).unwrap();
+        let append = |rows: Vec<NormalizedRow>| db.append("measurements", rows_to_record_batch(&rows).unwrap());
+        append(vec![row("1", "2024-03-01 08:00:00", 10.0), row("1", "2024-03-01 08:01:00", 20.0), row("1", "2024-03-01 08:06:00", 30.0)]).await.unwrap();
+        db.rollup().await.unwrap();
+        // Retention pruned the rolled-up rows; sensor 2 is not rolled up yet.
+        db.execute("DELETE FROM measurements").await.unwrap();
+        append(vec![row("2", "2024-03-01 09:00:00", 40.0), NormalizedRow { probe: "1".to_string(), ..row("2", "2024-03-01 09:00:00", 45.0) }])
+            .await
+            .unwrap();
+
+        let store: Store = Default::default();
+        let Json(names) = search_handler(Extension(db.clone()), None).await.unwrap();
+        assert_eq!(
+            names,
+            vec!["Acurite-Tower/1/temperature_C", "Acurite-Tower/2/temperature_C", "Acurite-Tower/2/temperature_C[1]"]
+        );
+        let Json(names) = search_handler(Extension(db.clone()), Some(Json(SearchRequest { target: "[1]".to_string() }))).await.unwrap();
+        assert_eq!(names, vec!["Acurite-Tower/2/temperature_C[1]"]);
+
+        append(vec![row("3", "2024-03-01 08:00:00", 10.0), row("3", "2024-03-01 08:01:00", 20.0), row("3", "2024-03-01 08:06:00", 30.0)]).await.unwrap();
+        let request = QueryRequest {
+            range: TimeRange { from: "2024-03-01T08:00:00Z".to_string(), to: "2024-03-01T08:10:00Z".to_string() },
+            targets: vec![Target { target: "Acurite-Tower/3/temperature_C".to_string(), kind: default_type(), hide: false }],
+            max_data_points: Some(2),
+        };
+        let Json(results) = query_handler(Extension(db.clone()), Extension(store), Json(request)).await.unwrap();
+        let [QueryResult::TimeSeries { datapoints, .. }] = &results[..] else { panic!("expected one time series") };
+        let at = |value: &str| time(value).and_utc().timestamp_millis();
+        assert_eq!(datapoints, &vec![(15.0, at("2024-03-01 08:00:00")), (30.0, at("2024-03-01 08:05:00"))]);
+        db.close().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
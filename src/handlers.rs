@@ -1,10 +1,32 @@
 // HTTP handlers for the service. These are thin wrappers around the shared
-// `Store` and the Prometheus `Registry`. They intentionally do minimal
-// validation to keep the example concise — add validation as needed.
-use crate::state::{key_for, save_mappings, Mapping, Store};
-use axum::{body::Body, extract::Extension, http::{HeaderMap, Request, StatusCode, header::CONTENT_TYPE, HeaderValue}, response::IntoResponse, Json};
-use prometheus::{Encoder, Registry, TextEncoder};
+// `Store` and the Prometheus `Registry`. Failures are returned as
+// `problem::Problem` (`application/problem+json`). Mapping changes are
+// validated and rejected with a `400` listing the offending fields; most
+// other endpoints only check what they need to run their query.
+use crate::archive;
+use crate::auth::constant_time_eq;
+use crate::config::{ArchiveConfig, MappingConfig};
+use crate::crypto::PayloadCipher;
+use crate::db::{Annotation, ArchiveEntry, DbHandle, DbStatus, ExplainTarget, ExportFormat, LabelColumn, LabelCondition, MeasurementQuery, PendingRename, PurgeReport, PurgeRequest, RawQuery, RenameReport, RenameRequest, Resolution, RollupQuery, RollupRow, RowCursor, RowQuery, SeriesCursor, SeriesQuery, StateTransition, TransitionQuery};
+use crate::jobs::{Job, JobStatus, Jobs};
+use crate::logging::LogFilter;
+use crate::metrics::{SensorFreshness, SensorGauges};
+use crate::problem::{self, FieldError, Problem};
+use crate::mqtt_buffer::{measurement_code, measurement_name, measurement_types, rows_to_named_record_batch, MqttBuffer, NormalizedRow, Normalizer};
+use crate::states::States;
+use crate::status::Status;
+use crate::units::{self, UnitInfo};
+use crate::upload::{UploadKind, Uploader};
+use crate::weather::{Weather, WeatherSummary};
+use crate::state::{key_for, save_mappings, LastSeen, LatestReadings, Mapping, SensorKey, Store, TopicStats, TOPIC_WINDOW_MINUTES};
+use axum::{body::Body, extract::{Extension, Path, Query}, http::{HeaderMap, HeaderName, Request, StatusCode, header::{AUTHORIZATION, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, LOCATION}, HeaderValue}, response::IntoResponse, Json};
+use arrow::ipc::writer::StreamWriter;
+use prometheus::{Encoder, GaugeVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
 
 /// Return all mappings as JSON array. This performs a read-lock and clones the
 /// values so the handler does not keep the lock across await points.
@@ -14,40 +36,1657 @@ pub async fn list_mappings(Extension(store): Extension<Store>) -> Json<Vec<Mappi
     Json(vec)
 }
 
+fn invalid(fields: Vec<FieldError>) -> Problem {
+    Problem::invalid_fields("invalid mapping", fields)
+}
+
+fn field_error(field: impl Into<String>, message: impl Into<String>) -> FieldError {
+    FieldError::new(field, message)
+}
+
+fn save_failed(e: impl std::fmt::Display) -> Problem {
+    Problem::internal(format!("saving mappings failed: {}", e))
+}
+
+/// Check a mapping before it is stored: the sensor id and name must be
+/// non-empty, the manufacturer known (heard since startup, already mapped,
+/// `composite` or listed under `[mapping] manufacturers`) and every unit
+/// keyed by a known measurement and convertible from its unit. A
+/// `retention_days` override must keep at least one day, an expected
+/// interval must be at least one second.
+async fn validate_mapping(
+    mapping: &Mapping,
+    map: &HashMap<String, Mapping>,
+    last_seen: &LastSeen,
+    config: &MappingConfig,
+) -> Result<(), Problem> {
+    let mut fields = Vec::new();
+    if mapping.sensor_id.trim().is_empty() {
+        fields.push(field_error("sensor_id", "must not be empty"));
+    }
+    if mapping.name.trim().is_empty() {
+        fields.push(field_error("name", "must not be empty"));
+    }
+    let manufacturer = mapping.manufacturer.as_str();
+    if manufacturer.trim().is_empty() {
+        fields.push(field_error("manufacturer", "must not be empty"));
+    } else {
+        let known = manufacturer == crate::composite::MODEL
+            || config.manufacturers.iter().any(|m| m == manufacturer)
+            || last_seen.read().await.keys().any(|k| k.model == manufacturer)
+            || map.values().any(|m| m.manufacturer == manufacturer);
+        if !known {
+            fields.push(field_error("manufacturer", format!("unknown manufacturer {}", manufacturer)));
+        }
+    }
+    for (measurement, unit) in &mapping.units {
+        let field = format!("units.{}", measurement);
+        match measurement_types().iter().find(|t| &t.key == measurement) {
+            None => fields.push(field_error(field, format!("unknown measurement {}", measurement))),
+            Some(t) if !units::is_convertible(&t.unit, unit) => {
+                let known: Vec<&str> = units::symbols().filter(|u| units::is_convertible(&t.unit, u)).collect();
+                let message = if known.is_empty() {
+                    format!("{} has no unit to convert", measurement)
+                } else {
+                    format!("cannot convert {} to {} (expected one of {})", t.unit, unit, known.join(", "))
+                };
+                fields.push(field_error(field, message));
+            }
+            Some(_) => {}
+        }
+    }
+    if mapping.retention_days == Some(0) {
+        fields.push(field_error("retention_days", "must be at least 1"));
+    }
+    if mapping.expected_interval_secs == Some(0) {
+        fields.push(field_error("expected_interval_secs", "must be at least 1"));
+    }
+    if fields.is_empty() { Ok(()) } else { Err(invalid(fields)) }
+}
+
 /// Insert or update a mapping. Expects a JSON body matching `Mapping`.
-/// Returns `201 Created` on success. In a production service you'd validate
-/// fields and possibly return `400 Bad Request` for invalid payloads.
-pub async fn put_mapping(Extension(store): Extension<Store>, Json(payload): Json<Mapping>) -> Result<StatusCode, (StatusCode, String)> {
-    let key = key_for(&payload.sensor_id, &payload.manufacturer);
+/// Returns `201 Created` on success and `400 Bad Request` listing the
+/// rejected fields otherwise.
+pub async fn put_mapping(
+    Extension(store): Extension<Store>,
+    Extension(last_seen): Extension<LastSeen>,
+    Extension(config): Extension<MappingConfig>,
+    Json(payload): Json<Mapping>,
+) -> Result<StatusCode, Problem> {
     {
         let mut map = store.write().await;
-        map.insert(key, payload);
+        validate_mapping(&payload, &map, &last_seen, &config).await?;
+        map.insert(key_for(&payload.sensor_id, &payload.manufacturer), payload);
     }
     // Persist immediately for this simple example. Consider batching in
     // high-throughput scenarios or moving persistence to a DB.
-    save_mappings(&store).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    save_mappings(&store, &config.file).await.map_err(save_failed)?;
     Ok(StatusCode::CREATED)
 }
 
+/// Query parameters of `PATCH` / `DELETE /mapping/{sensor_id}`.
+#[derive(Deserialize, Default)]
+pub struct MappingTarget {
+    /// Required only when several manufacturers map the same sensor id.
+    pub manufacturer: Option<String>,
+}
+
+/// Fields of a mapping to change; omitted fields are kept. `units` replaces
+/// the whole map and `"retention_days": null` removes the override (likewise
+/// `expected_interval_secs`).
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MappingPatch {
+    pub name: Option<String>,
+    pub units: Option<BTreeMap<String, String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub retention_days: Option<Option<u64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub expected_interval_secs: Option<Option<u64>>,
+}
+
+/// Tells an explicit `null` (`Some(None)`) from an omitted field (`None`).
+fn present<'de, D: serde::Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> Result<Option<Option<T>>, D::Error> {
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Store key of the mapping addressed by a sensor id and optional
+/// manufacturer.
+fn find_mapping(
+    map: &HashMap<String, Mapping>,
+    sensor_id: &str,
+    target: &MappingTarget,
+) -> Result<String, Problem> {
+    if sensor_id.trim().is_empty() {
+        return Err(invalid(vec![field_error("sensor_id", "must not be empty")]));
+    }
+    let mut matches: Vec<&Mapping> = map
+        .values()
+        .filter(|m| m.sensor_id == sensor_id && target.manufacturer.as_ref().is_none_or(|want| &m.manufacturer == want))
+        .collect();
+    match matches.len() {
+        0 => Err(Problem::not_found(format!("no mapping for {}", sensor_id))),
+        1 => {
+            let m = matches.remove(0);
+            Ok(key_for(&m.sensor_id, &m.manufacturer))
+        }
+        _ => {
+            let mut manufacturers: Vec<&str> = matches.iter().map(|m| m.manufacturer.as_str()).collect();
+            manufacturers.sort();
+            Err(invalid(vec![field_error(
+                "manufacturer",
+                format!("sensor id {} is mapped for {}; pass ?manufacturer=", sensor_id, manufacturers.join(", ")),
+            )]))
+        }
+    }
+}
+
+/// Change the name, units, retention or expected interval of an existing
+/// mapping and return it.
+pub async fn patch_mapping(
+    Extension(store): Extension<Store>,
+    Extension(last_seen): Extension<LastSeen>,
+    Extension(config): Extension<MappingConfig>,
+    Path(sensor_id): Path<String>,
+    Query(target): Query<MappingTarget>,
+    Json(patch): Json<MappingPatch>,
+) -> Result<Json<Mapping>, Problem> {
+    // One write lock from lookup to insert, so concurrent changes of the
+    // same mapping don't overwrite each other.
+    let mut map = store.write().await;
+    let key = find_mapping(&map, &sensor_id, &target)?;
+    let mut mapping = map[&key].clone();
+    if let Some(name) = patch.name {
+        mapping.name = name;
+    }
+    if let Some(units) = patch.units {
+        mapping.units = units;
+    }
+    if let Some(retention_days) = patch.retention_days {
+        mapping.retention_days = retention_days;
+    }
+    if let Some(expected_interval_secs) = patch.expected_interval_secs {
+        mapping.expected_interval_secs = expected_interval_secs;
+    }
+    validate_mapping(&mapping, &map, &last_seen, &config).await?;
+    map.insert(key, mapping.clone());
+    drop(map);
+    save_mappings(&store, &config.file).await.map_err(save_failed)?;
+    Ok(Json(mapping))
+}
+
+/// Remove a mapping. Returns `204 No Content`, or `404` if there is none.
+pub async fn delete_mapping(
+    Extension(store): Extension<Store>,
+    Extension(config): Extension<MappingConfig>,
+    Path(sensor_id): Path<String>,
+    Query(target): Query<MappingTarget>,
+) -> Result<StatusCode, Problem> {
+    {
+        let mut map = store.write().await;
+        let key = find_mapping(&map, &sensor_id, &target)?;
+        map.remove(&key);
+    }
+    save_mappings(&store, &config.file).await.map_err(save_failed)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 /// Expose Prometheus text-format metrics gathered from the provided
 /// `Registry` extension. This returns the body and an (empty) header map so
 /// the caller can set the appropriate `Content-Type` if needed.
 pub async fn metrics_handler(Extension(registry): Extension<Arc<Registry>>) -> (HeaderMap, String) {
+    (HeaderMap::new(), encode_registry(&registry))
+}
+
+fn encode_registry(registry: &Registry) -> String {
     let encoder = TextEncoder::new();
     let metric_families = registry.gather();
     let mut buffer = Vec::new();
     encoder.encode(&metric_families, &mut buffer).unwrap_or_default();
-    let body = String::from_utf8_lossy(&buffer).to_string();
-    (HeaderMap::new(), body)
+    String::from_utf8_lossy(&buffer).to_string()
+}
+
+/// Readings older than this are left out of `/metrics/aggregate` so a dead
+/// sensor doesn't pin the site minimum/maximum forever.
+const AGGREGATE_MAX_AGE: Duration = Duration::from_secs(15 * 60);
+
+/// Site-level rollups for federation: min/max/avg of the latest reading of
+/// every sensor, per measurement, plus the number of contributing sensors.
+/// The only label is `measurement`, so cardinality stays constant no matter
+/// how many devices are deployed. The series are built into a throwaway
+/// `Registry` on each scrape from `LatestReadings`.
+pub async fn aggregate_metrics_handler(Extension(latest): Extension<LatestReadings>) -> Result<(HeaderMap, String), Problem> {
+    let internal = Problem::internal;
+    let registry = Registry::new();
+    let min = GaugeVec::new(Opts::new("site_measurement_min", "Minimum latest reading across sensors"), &["measurement"]).map_err(internal)?;
+    let max = GaugeVec::new(Opts::new("site_measurement_max", "Maximum latest reading across sensors"), &["measurement"]).map_err(internal)?;
+    let avg = GaugeVec::new(Opts::new("site_measurement_avg", "Average latest reading across sensors"), &["measurement"]).map_err(internal)?;
+    let sensors = IntGaugeVec::new(Opts::new("site_measurement_sensors", "Sensors contributing to the site rollup"), &["measurement"]).map_err(internal)?;
+    registry.register(Box::new(min.clone())).map_err(internal)?;
+    registry.register(Box::new(max.clone())).map_err(internal)?;
+    registry.register(Box::new(avg.clone())).map_err(internal)?;
+    registry.register(Box::new(sensors.clone())).map_err(internal)?;
+
+    // measurement -> (min, max, sum, count)
+    let mut rollups: BTreeMap<&'static str, (f64, f64, f64, i64)> = BTreeMap::new();
+    let now = SystemTime::now();
+    {
+        let latest = latest.read().await;
+        for (key, reading) in latest.iter() {
+            let fresh = now.duration_since(reading.received_at).map(|age| age <= AGGREGATE_MAX_AGE).unwrap_or(true);
+            let Some(name) = measurement_name(key.measurement_type).filter(|_| fresh) else { continue };
+            let entry = rollups.entry(name).or_insert((f64::INFINITY, f64::NEG_INFINITY, 0.0, 0));
+            entry.0 = entry.0.min(reading.value);
+            entry.1 = entry.1.max(reading.value);
+            entry.2 += reading.value;
+            entry.3 += 1;
+        }
+    }
+    for (name, (lo, hi, sum, count)) in rollups {
+        min.with_label_values(&[name]).set(lo);
+        max.with_label_values(&[name]).set(hi);
+        avg.with_label_values(&[name]).set(sum / count as f64);
+        sensors.with_label_values(&[name]).set(count);
+    }
+
+    Ok((HeaderMap::new(), encode_registry(&registry)))
+}
+
+/// A measurement type with the display hints of its canonical unit.
+#[derive(Serialize)]
+pub struct MeasurementTypeInfo {
+    key: &'static str,
+    code: u8,
+    metric: &'static str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    description: &'static str,
+    #[serde(flatten)]
+    unit: UnitInfo,
+}
+
+/// The measurement registry (built-ins and `[[measurements]]`), so clients
+/// can label axes before any rows arrive. Mapped sensors may store a
+/// measurement in another unit; `/api/measurements` reports that per row.
+pub async fn list_measurement_types() -> Json<Vec<MeasurementTypeInfo>> {
+    let types = measurement_types()
+        .iter()
+        .map(|t| MeasurementTypeInfo {
+            key: &t.key,
+            code: t.code,
+            metric: &t.metric,
+            description: &t.description,
+            unit: units::info(&t.unit),
+        })
+        .collect();
+    Json(types)
+}
+
+/// Request body for `POST /api/parse-preview`. `payload` may be a JSON
+/// object (as rtl_433 publishes it) or a string containing the raw payload.
+#[derive(Deserialize)]
+pub struct ParsePreviewRequest {
+    pub topic: String,
+    pub payload: serde_json::Value,
+}
+
+/// A metric sample the MQTT loop would record for the previewed message.
+#[derive(Serialize)]
+pub struct PreviewMetric {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+}
+
+#[derive(Serialize)]
+pub struct ParsePreviewResponse {
+    pub topic: String,
+    pub rows: Vec<PreviewRow>,
+    pub metrics: Vec<PreviewMetric>,
+}
+
+#[derive(Serialize)]
+pub struct PreviewRow {
+    #[serde(flatten)]
+    pub row: NormalizedRow,
+    pub measurement: Option<&'static str>,
+}
+
+/// Run a payload through the same normalization the MQTT loop uses without
+/// buffering or storing anything. `metrics` lists the samples the message
+/// would record: the message counters and the per-sensor gauges, with the
+/// labels, units and rounding they would get. Returns `400 Bad Request` with
+/// the parser error if the payload would be rejected.
+pub async fn parse_preview(
+    Extension(normalizer): Extension<Arc<Normalizer>>,
+    Extension(gauges): Extension<Arc<SensorGauges>>,
+    Extension(store): Extension<Store>,
+    Extension(status): Extension<Arc<Status>>,
+    Json(req): Json<ParsePreviewRequest>,
+) -> Result<Json<ParsePreviewResponse>, Problem> {
+    let raw = match req.payload {
+        serde_json::Value::String(s) => s,
+        other => other.to_string(),
+    };
+    let rows = normalizer.normalize(&req.topic, raw.as_bytes()).map_err(|e| Problem::invalid(e.to_string()))?;
+
+    let mut metrics = vec![PreviewMetric { name: "mqtt_messages_total".to_string(), labels: BTreeMap::new(), value: 1.0 }];
+    for filter in status.topics.borrow().iter().filter(|f| rumqttc::matches(&req.topic, f)) {
+        let labels = BTreeMap::from([("topic".to_string(), filter.clone())]);
+        metrics.push(PreviewMetric { name: "mqtt_topic_messages_total".to_string(), labels, value: 1.0 });
+    }
+    {
+        let mappings = store.read().await;
+        let mapped = units::in_mapping_units(&rows, &mappings);
+        for (name, labels, value) in gauges.preview(&mapped, &mappings) {
+            metrics.push(PreviewMetric { name: name.to_string(), labels, value });
+        }
+    }
+    let rows = rows
+        .into_iter()
+        .map(|row| PreviewRow { measurement: measurement_name(row.measurement_type), row })
+        .collect();
+    Ok(Json(ParsePreviewResponse { topic: req.topic, rows, metrics }))
+}
+
+#[derive(Deserialize)]
+pub struct RegisterArchiveRequest {
+    pub path: String,
+}
+
+/// List the Parquet archive manifest.
+pub async fn list_archives(Extension(db): Extension<DbHandle>) -> Result<Json<Vec<ArchiveEntry>>, Problem> {
+    let entries = problem::query(&db, db.list_archives()).await?;
+    Ok(Json(entries))
+}
+
+/// Register an existing Parquet file (with the `measurements` columns) in
+/// the archive manifest so read paths include it. The file is read by the
+/// server process, so `path` is resolved on the exporter's filesystem,
+/// relative to `archive.dir`, and must lie within it. When object storage
+/// is configured the file is also uploaded in the background. Requires the
+/// admin token.
+pub async fn register_archive(
+    Extension(db): Extension<DbHandle>,
+    Extension(uploader): Extension<Option<Arc<Uploader>>>,
+    Extension(archive): Extension<ArchiveConfig>,
+    Extension(admin): Extension<AdminAccess>,
+    headers: HeaderMap,
+    Json(req): Json<RegisterArchiveRequest>,
+) -> Result<(StatusCode, Json<ArchiveEntry>), Problem> {
+    authorize_admin(&admin, &headers)?;
+    let path = archive::resolve_in_dir(std::path::Path::new(&archive.dir), &req.path)
+        .map_err(|e| Problem::invalid(format!("{:#}", e)))?;
+    let entry = db.register_archive(&path.to_string_lossy()).await.map_err(|e| Problem::invalid(e.to_string()))?;
+    if let Some(uploader) = uploader {
+        let path = std::path::PathBuf::from(&entry.path);
+        tokio::spawn(async move {
+            if let Err(e) = uploader.upload_file(&path, UploadKind::Parquet).await {
+                warn!("Uploading archive {} failed: {}", path.display(), e);
+            }
+        });
+    }
+    Ok((StatusCode::CREATED, Json(entry)))
+}
+
+#[derive(Deserialize, Default)]
+pub struct ExportParquetRequest {
+    /// Epoch milliseconds, inclusive. Unbounded by default.
+    pub start: Option<i64>,
+    /// Epoch milliseconds, exclusive. Defaults to now.
+    pub end: Option<i64>,
+}
+
+/// Export a slice of `measurements` to a Parquet file in `archive.dir` and
+/// register it as an archive. `201` with the new manifest entry, or `204`
+/// when the slice is empty. Requires the admin token.
+pub async fn export_parquet(
+    Extension(db): Extension<DbHandle>,
+    Extension(uploader): Extension<Option<Arc<Uploader>>>,
+    Extension(archive): Extension<ArchiveConfig>,
+    Extension(admin): Extension<AdminAccess>,
+    headers: HeaderMap,
+    body: Option<Json<ExportParquetRequest>>,
+) -> Result<axum::response::Response, Problem> {
+    authorize_admin(&admin, &headers)?;
+    let req = body.map(|Json(r)| r).unwrap_or_default();
+    let end = req.end.unwrap_or_else(|| chrono::Utc::now().timestamp_millis());
+    if req.start.is_some_and(|start| start >= end) {
+        return Err(Problem::invalid("start must be before end"));
+    }
+    let dir = std::path::Path::new(&archive.dir);
+    match archive::export_slice(&db, dir, req.start, end, uploader.as_ref()).await {
+        Ok(Some(entry)) => Ok((StatusCode::CREATED, Json(entry)).into_response()),
+        Ok(None) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(e) => Err(Problem::db(&db, e)),
+    }
+}
+
+#[derive(Serialize)]
+pub struct TopicSummary {
+    topic: String,
+    /// Messages over the last hour.
+    messages: u64,
+    messages_per_minute: f64,
+    avg_payload_bytes: f64,
+    last_message_at: chrono::DateTime<chrono::Utc>,
+    last_message_age_secs: u64,
+}
+
+/// Traffic per observed MQTT topic over the last hour, sorted by topic.
+/// Topics that went quiet are still listed with zero messages.
+pub async fn topic_stats(Extension(stats): Extension<TopicStats>) -> Json<Vec<TopicSummary>> {
+    let now = SystemTime::now();
+    let stats = stats.read().await;
+    let mut summaries: Vec<TopicSummary> = stats
+        .iter()
+        .map(|(topic, activity)| {
+            let (messages, bytes) = activity.window_totals(now);
+            TopicSummary {
+                topic: topic.clone(),
+                messages,
+                messages_per_minute: messages as f64 / TOPIC_WINDOW_MINUTES as f64,
+                avg_payload_bytes: if messages > 0 { bytes as f64 / messages as f64 } else { 0.0 },
+                last_message_at: activity.last_message.into(),
+                last_message_age_secs: now.duration_since(activity.last_message).map(|d| d.as_secs()).unwrap_or(0),
+            }
+        })
+        .collect();
+    summaries.sort_by(|a, b| a.topic.cmp(&b.topic));
+    Json(summaries)
+}
+
+#[derive(Deserialize)]
+pub struct StaleParams {
+    /// Seconds of silence after which a sensor counts as stale; defaults to
+    /// `metrics.stale_after_secs`.
+    pub threshold: Option<u64>,
+}
+
+/// Query parameters for `GET /api/sensors`.
+#[derive(Deserialize)]
+pub struct SensorListParams {
+    /// Only sensors without a mapping.
+    #[serde(default)]
+    pub unmapped: bool,
+}
+
+/// A sensor with stored readings.
+#[derive(Deserialize, Serialize)]
+pub struct SeenSensor {
+    pub model: String,
+    pub sensor_id: String,
+    /// Edge exporter of replicated sensors, see aggregator mode.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub site: String,
+    pub first_seen: chrono::NaiveDateTime,
+    pub last_seen: chrono::NaiveDateTime,
+    /// Readings stored, including those since archived or pruned.
+    pub readings: i64,
+    /// Keys of the measurements the sensor reported.
+    pub measurements: Vec<String>,
+    #[serde(default)]
+    pub mapped: bool,
+    /// Mapped name, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// The sensors of the hourly rollups, which outlive retention and
+/// archiving, and of the rows not rolled up yet.
+const SENSORS_SQL: &str = "SELECT s.model, s.sensor_id, s.site, min(s.first_seen) AS first_seen, max(s.last_seen) AS last_seen, \
+     sum(s.readings)::BIGINT AS readings, list_sort(list(DISTINCT COALESCE(t.name, s.measurement_type::VARCHAR))) AS measurements \
+     FROM (\
+     SELECT model, sensor_id, site, measurement_type, min(bucket) AS first_seen, max(bucket) AS last_seen, sum(count) AS readings \
+     FROM measurements_1h GROUP BY ALL \
+     UNION ALL \
+     SELECT model, sensor_id, COALESCE(site, ''), measurement_type, min(timestamp), max(timestamp), count(*) FROM measurements \
+     WHERE seq > COALESCE((SELECT last_seq FROM rollup_state WHERE name = 'measurements'), 0) GROUP BY ALL) s \
+     LEFT JOIN measurement_types t ON s.measurement_type = t.code \
+     GROUP BY s.model, s.sensor_id, s.site ORDER BY last_seen DESC, s.model, s.sensor_id";
+
+/// Every sensor/model combination with stored readings, most recently
+/// seen first, with whether it is mapped, so unmapped sensors can be
+/// offered for labelling. Rolled-up readings only count with the start of
+/// their hour, so `first_seen` and `last_seen` are exact for recent rows
+/// and to the hour otherwise.
+pub async fn list_sensors(
+    Extension(db): Extension<DbHandle>,
+    Extension(store): Extension<Store>,
+    Query(params): Query<SensorListParams>,
+) -> Result<Json<Vec<SeenSensor>>, Problem> {
+    let mut sensors: Vec<SeenSensor> = problem::query(&db, db.query_as(SENSORS_SQL, Vec::new())).await?;
+    let mappings = store.read().await;
+    for sensor in &mut sensors {
+        sensor.name = mappings.get(&key_for(&sensor.sensor_id, &sensor.model)).map(|m| m.name.clone());
+        sensor.mapped = sensor.name.is_some();
+    }
+    if params.unmapped {
+        sensors.retain(|sensor| !sensor.mapped);
+    }
+    Ok(Json(sensors))
+}
+
+#[derive(Serialize)]
+pub struct StaleSensor {
+    model: String,
+    sensor_id: String,
+    /// Mapped name, if any.
+    name: Option<String>,
+    last_seen_at: chrono::DateTime<chrono::Utc>,
+    silent_secs: u64,
+}
+
+/// Sensors that have not sent a message for longer than the threshold,
+/// longest silence first. Only sensors seen since the exporter started are
+/// known.
+pub async fn stale_sensors(
+    Extension(last_seen): Extension<LastSeen>,
+    Extension(freshness): Extension<Arc<SensorFreshness>>,
+    Extension(store): Extension<Store>,
+    Query(params): Query<StaleParams>,
+) -> Json<Vec<StaleSensor>> {
+    let threshold = params.threshold.map(Duration::from_secs).unwrap_or(freshness.threshold);
+    let now = SystemTime::now();
+    let mappings = store.read().await;
+    let mut stale: Vec<StaleSensor> = last_seen
+        .read()
+        .await
+        .iter()
+        .filter_map(|(key, at)| {
+            let silent = now.duration_since(*at).unwrap_or(Duration::ZERO);
+            (silent > threshold).then(|| StaleSensor {
+                model: key.model.clone(),
+                sensor_id: key.sensor_id.clone(),
+                name: mappings.get(&key_for(&key.sensor_id, &key.model)).map(|m| m.name.clone()),
+                last_seen_at: (*at).into(),
+                silent_secs: silent.as_secs(),
+            })
+        })
+        .collect();
+    stale.sort_by_key(|s| std::cmp::Reverse(s.silent_secs));
+    Json(stale)
+}
+
+#[derive(Serialize)]
+pub struct StateSummary {
+    state: String,
+    model: String,
+    sensor_id: String,
+    /// Mapped name, if any.
+    name: Option<String>,
+    active: bool,
+    since: chrono::NaiveDateTime,
+    /// The reading that set the current value.
+    value: f64,
+}
+
+/// Current value of every derived state per sensor.
+pub async fn list_states(
+    Extension(states): Extension<Arc<States>>,
+    Extension(store): Extension<Store>,
+) -> Json<Vec<StateSummary>> {
+    let mappings = store.read().await;
+    let summaries = states
+        .snapshot()
+        .into_iter()
+        .map(|(state, key, current)| StateSummary {
+            name: mappings.get(&key_for(&key.sensor_id, &key.model)).map(|m| m.name.clone()),
+            state,
+            model: key.model,
+            sensor_id: key.sensor_id,
+            active: current.active,
+            since: current.since,
+            value: current.value,
+        })
+        .collect();
+    Json(summaries)
+}
+
+/// Current rain and gust values per weather-station sensor.
+pub async fn weather_summary(Extension(weather): Extension<Arc<Weather>>) -> Json<Vec<WeatherSummary>> {
+    Json(weather.snapshot())
+}
+
+/// Query parameters for `GET /api/states/transitions`; `start`/`end` are
+/// epoch milliseconds and default to the whole history.
+#[derive(Deserialize)]
+pub struct TransitionParams {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub state: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Recorded state changes, oldest first.
+pub async fn state_transitions(
+    Extension(db): Extension<DbHandle>,
+    Query(params): Query<TransitionParams>,
+) -> Result<Json<Vec<StateTransition>>, Problem> {
+    let query = TransitionQuery {
+        start_ms: params.start.unwrap_or(i64::MIN),
+        end_ms: params.end.unwrap_or(i64::MAX),
+        state: params.state,
+        limit: params.limit.unwrap_or(MEASUREMENTS_MAX_LIMIT).clamp(1, MEASUREMENTS_MAX_LIMIT),
+    };
+    let transitions = problem::query(&db, db.query_transitions(query)).await?;
+    Ok(Json(transitions))
+}
+
+/// Rows per `GET /api/measurements` response unless `limit` asks for fewer.
+const MEASUREMENTS_MAX_LIMIT: usize = 10_000;
+
+/// Query parameters for `GET /api/measurements`. `start`/`end` are epoch
+/// milliseconds and default to the whole history; `measurement` is a
+/// comma-separated list of measurement keys (e.g. `temperature_C,humidity`).
+#[derive(Deserialize)]
+pub struct MeasurementsParams {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub sensor_id: Option<String>,
+    pub model: Option<String>,
+    pub measurement: Option<String>,
+    pub limit: Option<usize>,
+    /// `json` (default), `compact` for rows as arrays, or `arrow` for an
+    /// Arrow IPC stream.
+    pub format: Option<String>,
+    /// Comma-separated JSON columns to return, e.g. `timestamp,value`.
+    pub fields: Option<String>,
+    /// `5m` or `1h` to read min/max/avg rollups instead of raw rows.
+    pub resolution: Option<String>,
+    /// `next_cursor` of the previous page (raw rows only).
+    pub cursor: Option<String>,
+}
+
+/// JSON representation of a stored row, shared with `sync`.
+#[derive(Serialize)]
+pub struct MeasurementRow {
+    timestamp: chrono::NaiveDateTime,
+    sensor_id: String,
+    model: String,
+    measurement: &'static str,
+    value: f64,
+    topic: String,
+    seq: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    site: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    probe: String,
+    /// Display symbol of the unit `value` is in; only `/api/measurements`
+    /// fills it in.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    unit: String,
+}
+
+impl MeasurementRow {
+    /// A row already in its mapping's unit (see `units::apply_mapping_units`),
+    /// labelled with that unit.
+    pub fn with_unit(row: NormalizedRow, mappings: &HashMap<String, Mapping>) -> Self {
+        let unit = units::mapped_unit(row.measurement_type, mappings.get(&key_for(&row.sensor_id, &row.model)));
+        Self { unit, ..Self::from(row) }
+    }
+}
+
+impl From<NormalizedRow> for MeasurementRow {
+    fn from(row: NormalizedRow) -> Self {
+        Self {
+            timestamp: row.timestamp,
+            measurement: measurement_name(row.measurement_type).unwrap_or("unknown"),
+            sensor_id: row.sensor_id,
+            model: row.model,
+            value: row.value,
+            topic: row.topic,
+            seq: row.seq,
+            site: row.site,
+            probe: row.probe,
+            unit: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MeasurementsResponse<T> {
+    /// Column names of `format=compact` rows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<Vec<String>>,
+    rows: Vec<T>,
+    /// More rows matched than `limit`.
+    truncated: bool,
+    /// Pass as `cursor` to read the next page of raw rows or series
+    /// buckets.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    /// Display hints for every `unit` in `rows`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    units: BTreeMap<String, UnitInfo>,
+}
+
+/// JSON representation of a rollup bucket.
+#[derive(Serialize)]
+struct RollupBucket {
+    bucket: chrono::NaiveDateTime,
+    sensor_id: String,
+    model: String,
+    measurement: &'static str,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    site: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    probe: String,
+    min: f64,
+    max: f64,
+    avg: f64,
+    count: i64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    unit: String,
+}
+
+impl RollupBucket {
+    /// A stored (canonical) bucket converted into its mapping's unit. The
+    /// conversions are linear and increasing, so min, max and avg stay
+    /// what they are.
+    fn with_unit(row: RollupRow, mappings: &HashMap<String, Mapping>) -> Self {
+        let mapping = mappings.get(&key_for(&row.sensor_id, &row.model));
+        let convert = |value| units::to_mapped_unit(value, row.measurement_type, mapping);
+        Self {
+            unit: units::mapped_unit(row.measurement_type, mapping),
+            min: convert(row.min),
+            max: convert(row.max),
+            avg: convert(row.avg),
+            bucket: row.bucket,
+            measurement: measurement_name(row.measurement_type).unwrap_or("unknown"),
+            sensor_id: row.sensor_id,
+            model: row.model,
+            site: row.site,
+            probe: row.probe,
+            count: row.count,
+        }
+    }
+}
+
+/// The selection of `/api/measurements` and query jobs: an epoch ms range,
+/// exact sensor id / model and a comma-separated list of measurement keys.
+fn measurement_selection(
+    start: Option<i64>,
+    end: Option<i64>,
+    sensor_id: Option<String>,
+    model: Option<String>,
+    measurement: Option<&str>,
+) -> Result<MeasurementQuery, Problem> {
+    let mut measurement_types = Vec::new();
+    for key in measurement.iter().flat_map(|m| m.split(',')).map(str::trim).filter(|k| !k.is_empty()) {
+        let code = measurement_code(key).ok_or_else(|| Problem::invalid(format!("unknown measurement: {}", key)))?;
+        measurement_types.push(code);
+    }
+    let mut conditions = Vec::new();
+    if let Some(sensor_id) = sensor_id {
+        conditions.push(LabelCondition::Eq(LabelColumn::SensorId, sensor_id));
+    }
+    if let Some(model) = model {
+        conditions.push(LabelCondition::Eq(LabelColumn::Model, model));
+    }
+    Ok(MeasurementQuery {
+        start_ms: start.unwrap_or(i64::MIN),
+        end_ms: end.unwrap_or(i64::MAX),
+        measurement_types,
+        conditions,
+    })
+}
+
+/// Columns of raw rows and rollup buckets in JSON responses, in order.
+const ROW_FIELDS: &[&str] = &["timestamp", "sensor_id", "model", "measurement", "value", "topic", "seq", "site", "probe", "unit"];
+const BUCKET_FIELDS: &[&str] = &["bucket", "sensor_id", "model", "measurement", "site", "probe", "min", "max", "avg", "count", "unit"];
+
+/// Display hints for the distinct units of a response's rows.
+fn unit_hints<'a>(units: impl Iterator<Item = &'a str>) -> BTreeMap<String, UnitInfo> {
+    units.filter(|u| !u.is_empty()).map(|u| (u.to_string(), units::info(u))).collect()
+}
+
+/// Column selection and layout of a JSON measurements response.
+struct Shape {
+    /// Selected columns; `None` keeps every column of the row objects.
+    fields: Option<Vec<String>>,
+    /// Rows as arrays in `fields` order instead of objects.
+    compact: bool,
+}
+
+impl Shape {
+    fn parse(fields: Option<&str>, compact: bool, available: &[&str]) -> Result<Self, Problem> {
+        let Some(fields) = fields else {
+            let fields = compact.then(|| available.iter().map(|f| f.to_string()).collect());
+            return Ok(Self { fields, compact });
+        };
+        let mut selected = Vec::new();
+        for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            if !available.contains(&field) {
+                let message = format!("unknown field: {} (expected {})", field, available.join(", "));
+                return Err(Problem::invalid(message));
+            }
+            if !selected.iter().any(|f| f == field) {
+                selected.push(field.to_string());
+            }
+        }
+        if selected.is_empty() {
+            return Err(Problem::invalid("fields must name at least one column"));
+        }
+        Ok(Self { fields: Some(selected), compact })
+    }
+
+    fn respond<T: Serialize>(
+        self,
+        rows: Vec<T>,
+        truncated: bool,
+        next_cursor: Option<String>,
+        units: BTreeMap<String, UnitInfo>,
+    ) -> axum::response::Response {
+        let Some(fields) = self.fields else {
+            return Json(MeasurementsResponse { fields: None, rows, truncated, next_cursor, units }).into_response();
+        };
+        // Hints only matter when the rows carry their unit.
+        let units = if fields.iter().any(|f| f == "unit") { units } else { BTreeMap::new() };
+        let rows = rows
+            .into_iter()
+            .map(|row| {
+                let serde_json::Value::Object(mut object) = serde_json::to_value(row).unwrap_or_default() else {
+                    return serde_json::Value::Null;
+                };
+                // Omitted columns (an empty `site`) become null in arrays.
+                let values = fields.iter().map(|f| (f, object.remove(f.as_str()).unwrap_or_default()));
+                if self.compact {
+                    serde_json::Value::Array(values.map(|(_, v)| v).collect())
+                } else {
+                    serde_json::Value::Object(values.filter(|(_, v)| !v.is_null()).map(|(f, v)| (f.clone(), v)).collect())
+                }
+            })
+            .collect();
+        let fields = self.compact.then_some(fields);
+        Json(MeasurementsResponse { fields, rows, truncated, next_cursor, units }).into_response()
+    }
+}
+
+/// Stored measurements, oldest first, including archived Parquet slices.
+/// Returns JSON by default or an Arrow IPC stream with the `measurements`
+/// table schema plus a `measurement` name column for `format=arrow`.
+/// `fields=` keeps only the listed JSON
+/// columns and `format=compact` returns rows as arrays with the column
+/// names listed once. Truncated pages carry a `next_cursor` (`x-next-cursor`
+/// header for Arrow) to continue from. With `resolution=5m|1h` the rollup
+/// buckets are returned instead (JSON only). JSON rows carry the `unit`
+/// their value is in under the sensor's current mapping, with display hints
+/// for each unit under `units`.
+pub async fn query_measurements(
+    Extension(db): Extension<DbHandle>,
+    Extension(store): Extension<Store>,
+    Query(params): Query<MeasurementsParams>,
+) -> Result<axum::response::Response, Problem> {
+    let selection = measurement_selection(params.start, params.end, params.sensor_id, params.model, params.measurement.as_deref())?;
+    let limit = params.limit.unwrap_or(MEASUREMENTS_MAX_LIMIT).clamp(1, MEASUREMENTS_MAX_LIMIT);
+    let format = params.format.as_deref().unwrap_or("json");
+    if !matches!(format, "json" | "compact" | "arrow") {
+        return Err(Problem::invalid(format!("unknown format: {} (expected json, compact or arrow)", format)));
+    }
+    let compact = format == "compact";
+
+    if let Some(resolution) = params.resolution.as_deref().filter(|r| *r != "raw") {
+        let resolution = Resolution::parse(resolution)
+            .ok_or_else(|| Problem::invalid(format!("unknown resolution: {} (expected raw, 5m or 1h)", resolution)))?;
+        if format == "arrow" {
+            return Err(Problem::invalid("rollups are only available as json"));
+        }
+        if params.cursor.is_some() {
+            return Err(Problem::invalid("cursor is only supported for raw rows"));
+        }
+        let shape = Shape::parse(params.fields.as_deref(), compact, BUCKET_FIELDS)?;
+        let query = RollupQuery { resolution, selection, limit: limit + 1 };
+        let mut rows = problem::query(&db, db.query_rollups(query)).await?;
+        let truncated = rows.len() > limit;
+        rows.truncate(limit);
+        let mappings = store.read().await;
+        let rows: Vec<RollupBucket> = rows.into_iter().map(|row| RollupBucket::with_unit(row, &mappings)).collect();
+        let units = unit_hints(rows.iter().map(|row| row.unit.as_str()));
+        return Ok(shape.respond(rows, truncated, None, units));
+    }
+
+    if format == "arrow" && params.fields.is_some() {
+        return Err(Problem::invalid("fields is only supported for json and compact"));
+    }
+    let shape = Shape::parse(params.fields.as_deref(), compact, ROW_FIELDS)?;
+    let after = match params.cursor.as_deref() {
+        Some(cursor) => Some(RowCursor::decode(cursor).map_err(|_| Problem::invalid("invalid cursor"))?),
+        None => None,
+    };
+    // One extra row tells whether the page was cut short.
+    let query = RowQuery { selection, limit: limit + 1, after };
+    let mut rows = problem::query(&db, db.query_page(query)).await?;
+    let truncated = rows.len() > limit;
+    rows.truncate(limit);
+    let next_cursor = if truncated { rows.last().map(|row| RowCursor::after(row).encode()) } else { None };
+
+    if format != "arrow" {
+        let mappings = store.read().await;
+        units::apply_mapping_units(&mut rows, &mappings);
+        let rows: Vec<MeasurementRow> = rows.into_iter().map(|row| MeasurementRow::with_unit(row, &mappings)).collect();
+        let units = unit_hints(rows.iter().map(|row| row.unit.as_str()));
+        return Ok(shape.respond(rows, truncated, next_cursor, units));
+    }
+    let batch = rows_to_named_record_batch(&rows).map_err(Problem::internal)?;
+    let mut body = Vec::new();
+    {
+        let mut writer = StreamWriter::try_new(&mut body, &batch.schema()).map_err(Problem::internal)?;
+        writer.write(&batch).map_err(Problem::internal)?;
+        writer.finish().map_err(Problem::internal)?;
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/vnd.apache.arrow.stream"));
+    headers.insert(
+        HeaderName::from_static("x-truncated"),
+        HeaderValue::from_static(if truncated { "true" } else { "false" }),
+    );
+    if let Some(cursor) = next_cursor.and_then(|c| HeaderValue::from_str(&c).ok()) {
+        headers.insert(HeaderName::from_static("x-next-cursor"), cursor);
+    }
+    Ok((headers, body).into_response())
+}
+
+/// Buckets per `GET /api/series` response unless `limit` asks for fewer.
+const SERIES_MAX_LIMIT: usize = 10_000;
+
+/// Range of `GET /api/series` without `from`.
+const SERIES_DEFAULT_RANGE_MS: i64 = 24 * 3600 * 1000;
+
+/// Query parameters for `GET /api/series`. `from`/`to` are epoch
+/// milliseconds, `to` defaults to now and `from` to a day before it.
+#[derive(Deserialize)]
+pub struct SeriesParams {
+    pub sensor_id: Option<String>,
+    pub model: Option<String>,
+    /// Comma-separated measurement keys.
+    pub measurement: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    /// Bucket size: seconds, or a number with `s`, `m`, `h` or `d`.
+    pub step: Option<String>,
+    pub limit: Option<usize>,
+    /// `json` (default) or `compact` for rows as arrays.
+    pub format: Option<String>,
+    /// Comma-separated JSON columns to return, e.g. `bucket,avg`.
+    pub fields: Option<String>,
+    /// `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+}
+
+/// Parse a `step` such as `300`, `30s`, `5m`, `1h` or `1d` into
+/// milliseconds.
+fn parse_step(step: &str) -> Option<i64> {
+    let step = step.trim();
+    let (number, unit_ms) = match step.char_indices().last()? {
+        (i, 's') => (&step[..i], 1000),
+        (i, 'm') => (&step[..i], 60 * 1000),
+        (i, 'h') => (&step[..i], 3600 * 1000),
+        (i, 'd') => (&step[..i], 24 * 3600 * 1000),
+        _ => (step, 1000),
+    };
+    number.parse::<i64>().ok().filter(|n| *n > 0)?.checked_mul(unit_ms)
+}
+
+/// Min/max/avg of one sensor's raw readings in `step` buckets for charts,
+/// computed from the stored rows (archived Parquet slices included) rather
+/// than the fixed rollup tables. Buckets are ordered by time and then
+/// series; truncated pages carry a `next_cursor` to continue from, so long
+/// ranges are read page by page. Rows have the `/api/measurements` rollup
+/// layout and honour `fields` and `format=compact` the same way.
+pub async fn query_series(
+    Extension(db): Extension<DbHandle>,
+    Extension(store): Extension<Store>,
+    Query(params): Query<SeriesParams>,
+) -> Result<axum::response::Response, Problem> {
+    let sensor_id = params.sensor_id.filter(|s| !s.is_empty()).ok_or_else(|| Problem::invalid("sensor_id is required"))?;
+    if params.measurement.as_deref().is_none_or(|m| m.trim().is_empty()) {
+        return Err(Problem::invalid("measurement is required"));
+    }
+    let step = params.step.as_deref().ok_or_else(|| Problem::invalid("step is required"))?;
+    let step_ms = parse_step(step).ok_or_else(|| Problem::invalid(format!("invalid step: {} (e.g. 30s, 5m, 1h or 1d)", step)))?;
+    let to = params.to.unwrap_or_else(|| chrono::Utc::now().timestamp_millis());
+    let from = params.from.unwrap_or(to.saturating_sub(SERIES_DEFAULT_RANGE_MS));
+    if from > to {
+        return Err(Problem::invalid("from must not be after to"));
+    }
+    let selection = measurement_selection(Some(from), Some(to), Some(sensor_id), params.model, params.measurement.as_deref())?;
+    let format = params.format.as_deref().unwrap_or("json");
+    if !matches!(format, "json" | "compact") {
+        return Err(Problem::invalid(format!("unknown format: {} (expected json or compact)", format)));
+    }
+    let shape = Shape::parse(params.fields.as_deref(), format == "compact", BUCKET_FIELDS)?;
+    let after = match params.cursor.as_deref() {
+        Some(cursor) => Some(SeriesCursor::decode(cursor).map_err(|_| Problem::invalid("invalid cursor"))?),
+        None => None,
+    };
+    let limit = params.limit.unwrap_or(SERIES_MAX_LIMIT).clamp(1, SERIES_MAX_LIMIT);
+    // One extra bucket tells whether the page was cut short.
+    let query = SeriesQuery { selection, step_ms, limit: limit + 1, after };
+    let mut rows = problem::query(&db, db.query_series(query)).await?;
+    let truncated = rows.len() > limit;
+    rows.truncate(limit);
+    let next_cursor = if truncated { rows.last().map(|row| SeriesCursor::after(row).encode()) } else { None };
+    let mappings = store.read().await;
+    let rows: Vec<RollupBucket> = rows.into_iter().map(|row| RollupBucket::with_unit(row, &mappings)).collect();
+    let units = unit_hints(rows.iter().map(|row| row.unit.as_str()));
+    Ok(shape.respond(rows, truncated, next_cursor, units))
+}
+
+/// Body of `POST /api/jobs/query`; the selection matches
+/// `/api/measurements`.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct QueryJobRequest {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub sensor_id: Option<String>,
+    pub model: Option<String>,
+    pub measurement: Option<String>,
+    /// `parquet` (default) or `csv`.
+    pub format: Option<ExportFormat>,
+}
+
+/// Submit a query job. `202 Accepted` with the job and a `Location` header
+/// to poll, or `503` while too many jobs are pending.
+pub async fn submit_query_job(
+    Extension(db): Extension<DbHandle>,
+    Extension(jobs): Extension<Arc<Jobs>>,
+    body: Option<Json<QueryJobRequest>>,
+) -> Result<axum::response::Response, Problem> {
+    let req = body.map(|Json(r)| r).unwrap_or_default();
+    let selection = measurement_selection(req.start, req.end, req.sensor_id, req.model, req.measurement.as_deref())?;
+    let job = jobs
+        .submit(db, selection, req.format.unwrap_or(ExportFormat::Parquet))
+        .map_err(|e| Problem::new(StatusCode::SERVICE_UNAVAILABLE, "busy", e.to_string()))?;
+    let location = HeaderValue::from_str(&format!("/api/jobs/{}", job.id)).map_err(Problem::internal)?;
+    Ok((StatusCode::ACCEPTED, [(LOCATION, location)], Json(job)).into_response())
+}
+
+/// Status of a query job, `404` for unknown or expired ids.
+pub async fn get_job(Extension(jobs): Extension<Arc<Jobs>>, Path(id): Path<String>) -> Result<Json<Job>, Problem> {
+    jobs.get(&id).map(Json).ok_or_else(|| Problem::not_found(format!("no job {}", id)))
+}
+
+/// The result file of a finished job, streamed from disk. `409` while the
+/// job is pending or when it failed.
+pub async fn download_job(
+    Extension(jobs): Extension<Arc<Jobs>>,
+    Path(id): Path<String>,
+) -> Result<axum::response::Response, Problem> {
+    let job = jobs.get(&id).ok_or_else(|| Problem::not_found(format!("no job {}", id)))?;
+    if job.status != JobStatus::Done {
+        return Err(Problem::new(StatusCode::CONFLICT, "conflict", format!("job {} is {:?}", id, job.status).to_lowercase()));
+    }
+    let file = tokio::fs::File::open(&job.path).await.map_err(|e| Problem::new(StatusCode::GONE, "gone", e.to_string()))?;
+    let body = futures_util::stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut chunk = vec![0; 64 * 1024];
+        match tokio::io::AsyncReadExt::read(&mut file, &mut chunk).await {
+            Ok(0) => None,
+            Ok(n) => {
+                chunk.truncate(n);
+                Some((Ok(axum::body::Bytes::from(chunk)), Some(file)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    let disposition = format!("attachment; filename=\"{}.{}\"", job.id, job.format.extension());
+    Ok((
+        [
+            (CONTENT_TYPE, HeaderValue::from_static(job.format.content_type())),
+            (CONTENT_DISPOSITION, HeaderValue::from_str(&disposition).map_err(Problem::internal)?),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response())
+}
+
+/// What admin endpoints need: the bearer token callers must present and
+/// the key for encrypted raw payloads.
+#[derive(Clone)]
+pub struct AdminAccess {
+    pub token: Option<String>,
+    pub cipher: Option<Arc<PayloadCipher>>,
+}
+
+/// Check the `Authorization: Bearer` header against the admin token. Admin
+/// endpoints answer `403 Forbidden` while no token is configured.
+pub(crate) fn authorize_admin(admin: &AdminAccess, headers: &HeaderMap) -> Result<(), Problem> {
+    let Some(expected) = &admin.token else {
+        return Err(Problem::new(StatusCode::FORBIDDEN, "forbidden", "admin endpoints are disabled; set http.admin_token"));
+    };
+    let presented = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("");
+    if constant_time_eq(presented, expected) {
+        Ok(())
+    } else {
+        Err(Problem::new(StatusCode::UNAUTHORIZED, "unauthorized", "invalid or missing admin token"))
+    }
+}
+
+/// The active log filter (`RUST_LOG` syntax). Requires the admin token.
+pub async fn get_log_filter(
+    Extension(filter): Extension<Arc<LogFilter>>,
+    Extension(admin): Extension<AdminAccess>,
+    headers: HeaderMap,
+) -> Result<String, Problem> {
+    authorize_admin(&admin, &headers)?;
+    Ok(filter.current())
+}
+
+/// Replace the log filter with the directives in the request body, e.g.
+/// `info,rust_to_mqtt_prometheus_exporter::mqtt=debug` to trace ingestion.
+/// The change lasts until the next restart. Requires the admin token.
+pub async fn put_log_filter(
+    Extension(filter): Extension<Arc<LogFilter>>,
+    Extension(admin): Extension<AdminAccess>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<String, Problem> {
+    authorize_admin(&admin, &headers)?;
+    filter.set(body.trim()).map_err(|e| Problem::invalid(e.to_string()))?;
+    info!("Log filter set to {}", filter.current());
+    Ok(filter.current())
+}
+
+#[derive(Serialize)]
+pub struct FlushResponse {
+    /// Measurement rows written by the flush.
+    pub rows: usize,
+    /// Rows buffered again by the time the checkpoint finished.
+    pub pending_rows: usize,
+}
+
+/// Flush the ingest buffer and checkpoint the database, so the DuckDB
+/// file holds everything received so far, e.g. before copying it for a
+/// backup. The same as the `flush` and `checkpoint` control commands.
+/// Requires the admin token.
+pub async fn admin_flush(
+    Extension(db): Extension<DbHandle>,
+    Extension(buffer): Extension<Arc<MqttBuffer>>,
+    Extension(admin): Extension<AdminAccess>,
+    headers: HeaderMap,
+) -> Result<Json<FlushResponse>, Problem> {
+    authorize_admin(&admin, &headers)?;
+    let rows = problem::query(&db, buffer.flush(&db)).await?;
+    problem::query(&db, db.flush()).await?;
+    info!("Admin flush wrote {} rows and checkpointed the database", rows);
+    Ok(Json(FlushResponse { rows, pending_rows: buffer.backlog().0 }))
+}
+
+/// Query parameters for `GET /api/admin/raw`. `start`/`end` are epoch
+/// milliseconds and default to the whole history.
+#[derive(Deserialize)]
+pub struct RawExportParams {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub sensor_id: Option<String>,
+    pub model: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RawExportRow {
+    received_at: chrono::NaiveDateTime,
+    topic: String,
+    sensor_id: Option<String>,
+    model: Option<String>,
+    /// The payload as JSON when it parses, otherwise as a string.
+    payload: serde_json::Value,
+}
+
+/// Export stored raw payloads as NDJSON, decrypting them on the way out.
+/// This is the only place encrypted payloads are readable, so it requires
+/// the admin token.
+pub async fn export_raw(
+    Extension(db): Extension<DbHandle>,
+    Extension(admin): Extension<AdminAccess>,
+    headers: HeaderMap,
+    Query(params): Query<RawExportParams>,
+) -> Result<impl IntoResponse, Problem> {
+    authorize_admin(&admin, &headers)?;
+    let query = RawQuery {
+        start_ms: params.start.unwrap_or(i64::MIN),
+        end_ms: params.end.unwrap_or(i64::MAX),
+        sensor_id: params.sensor_id,
+        model: params.model,
+    };
+    let messages = problem::query(&db, db.select_raw(query)).await?;
+
+    let mut body = String::new();
+    for message in messages {
+        let payload = if message.encrypted {
+            let cipher = admin
+                .cipher
+                .as_ref()
+                .ok_or_else(|| Problem::internal("encrypted payloads stored but no raw.key_file configured"))?;
+            cipher.decrypt(&message.raw_json).map_err(Problem::internal)?
+        } else {
+            message.raw_json
+        };
+        let payload = serde_json::from_slice(&payload)
+            .unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(&payload).into_owned()));
+        let row = RawExportRow {
+            received_at: message.received_at,
+            topic: message.topic,
+            sensor_id: message.sensor_id,
+            model: message.model,
+            payload,
+        };
+        body.push_str(&serde_json::to_string(&row).map_err(Problem::internal)?);
+        body.push('\n');
+    }
+    Ok(([(CONTENT_TYPE, HeaderValue::from_static("application/x-ndjson"))], body))
+}
+
+/// Body of `POST /api/admin/explain`: either a single read-only statement
+/// or the parameters of a `/api/measurements` request (raw rows; `format`,
+/// `fields` and `resolution` are ignored).
+#[derive(Deserialize)]
+pub struct ExplainRequest {
+    pub sql: Option<String>,
+    pub measurements: Option<MeasurementsParams>,
+    /// Run the query and include timings and row counts.
+    #[serde(default)]
+    pub analyze: bool,
+}
+
+/// Check that `sql` is one statement that only reads: `EXPLAIN ANALYZE`
+/// runs it. Returns it without a trailing `;`.
+fn read_only_statement(sql: &str) -> Result<String, Problem> {
+    let sql = sql.trim().trim_end_matches(';').trim_end();
+    if sql.contains(';') {
+        return Err(Problem::invalid("sql must be a single statement"));
+    }
+    let keyword = sql.split(|c: char| !c.is_ascii_alphabetic()).next().unwrap_or("").to_ascii_uppercase();
+    if !matches!(keyword.as_str(), "SELECT" | "WITH" | "FROM") {
+        return Err(Problem::invalid("sql must be a SELECT, WITH or FROM query"));
+    }
+    Ok(sql.to_string())
+}
+
+/// DuckDB's query plan (`EXPLAIN`, or `EXPLAIN ANALYZE` with `analyze`) for
+/// a query, as text, to see why a dashboard query is slow on the live
+/// database. Requires the admin token.
+pub async fn explain(
+    Extension(db): Extension<DbHandle>,
+    Extension(admin): Extension<AdminAccess>,
+    headers: HeaderMap,
+    Json(req): Json<ExplainRequest>,
+) -> Result<impl IntoResponse, Problem> {
+    authorize_admin(&admin, &headers)?;
+    let target = match (req.sql, req.measurements) {
+        (Some(sql), None) => ExplainTarget::Sql(read_only_statement(&sql)?),
+        (None, Some(params)) => {
+            let selection =
+                measurement_selection(params.start, params.end, params.sensor_id, params.model, params.measurement.as_deref())?;
+            let limit = params.limit.unwrap_or(MEASUREMENTS_MAX_LIMIT).clamp(1, MEASUREMENTS_MAX_LIMIT);
+            let after = match params.cursor.as_deref() {
+                Some(cursor) => Some(RowCursor::decode(cursor).map_err(|_| Problem::invalid("invalid cursor"))?),
+                None => None,
+            };
+            ExplainTarget::Rows(RowQuery { selection, limit: limit + 1, after })
+        }
+        _ => return Err(Problem::invalid("expected exactly one of sql or measurements")),
+    };
+    let plan = problem::query(&db, db.explain(target, req.analyze)).await?;
+    Ok(([(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"))], plan))
+}
+
+#[derive(Deserialize)]
+pub struct PurgeParams {
+    /// Only remove rows from this time on (epoch milliseconds).
+    pub from: Option<i64>,
+    /// Only remove rows older than this (epoch milliseconds).
+    pub before: Option<i64>,
+    /// Only remove the rows of this measurement key.
+    pub measurement: Option<String>,
+}
+
+/// Remove what is stored for one sensor: measurements, raw payloads and
+/// rows in archived Parquet files, all of it or a window of bad readings
+/// (`from` / `before`), optionally of one measurement only. The purge is
+/// recorded in `audit_log`. Gauges and latest readings showing a purged
+/// reading are dropped until the sensor sends again, and so is its last-seen
+/// time when that was purged. Buffered readings are stored first, so they
+/// are purged too instead of being written after the purge. Requires the
+/// admin token.
+#[allow(clippy::too_many_arguments)]
+pub async fn purge_sensor(
+    Extension(db): Extension<DbHandle>,
+    Extension(admin): Extension<AdminAccess>,
+    Extension(latest): Extension<LatestReadings>,
+    Extension(gauges): Extension<Arc<SensorGauges>>,
+    Extension(last_seen): Extension<LastSeen>,
+    Extension(freshness): Extension<Arc<SensorFreshness>>,
+    Extension(buffer): Extension<Arc<MqttBuffer>>,
+    headers: HeaderMap,
+    Path((model, sensor_id)): Path<(String, String)>,
+    Query(params): Query<PurgeParams>,
+) -> Result<Json<PurgeReport>, Problem> {
+    authorize_admin(&admin, &headers)?;
+    if let (Some(from), Some(before)) = (params.from, params.before)
+        && from >= before
+    {
+        return Err(Problem::invalid("from must be before before"));
+    }
+    let measurement_type = match params.measurement.as_deref() {
+        Some(key) => Some(measurement_code(key).ok_or_else(|| Problem::invalid(format!("unknown measurement: {}", key)))?),
+        None => None,
+    };
+    let request = PurgeRequest { model, sensor_id, from_ms: params.from, before_ms: params.before, measurement_type };
+    info!(
+        "Purging data of sensor {}::{} (from: {:?}, before: {:?}, measurement: {:?})",
+        request.model, request.sensor_id, request.from_ms, request.before_ms, params.measurement
+    );
+    // Flushing also retires the WAL segments of the buffered messages, so a
+    // restart doesn't replay them.
+    buffer.flush(&db).await.map_err(|e| Problem::db(&db, e))?;
+    let report = db.purge_sensor(request.clone()).await.map_err(|e| Problem::db(&db, e))?;
+    let PurgeRequest { model, sensor_id, from_ms, before_ms, measurement_type } = request;
+    gauges.remove_readings(&model, &sensor_id, measurement_type, from_ms, before_ms);
+    latest.write().await.retain(|key, reading| {
+        !(key.model == model
+            && key.sensor_id == sensor_id
+            && measurement_type.is_none_or(|t| t == key.measurement_type)
+            && in_window(reading.received_at, from_ms, before_ms))
+    });
+    if measurement_type.is_none() {
+        // Its last message was purged; don't flag the sensor as stale.
+        let key = SensorKey { model, sensor_id };
+        let mut last_seen = last_seen.write().await;
+        if last_seen.get(&key).is_some_and(|at| in_window(*at, from_ms, before_ms)) {
+            last_seen.remove(&key);
+            freshness.remove(&key);
+        }
+    }
+    Ok(Json(report))
+}
+
+/// Whether `time` lies from `from_ms` (inclusive) to `before_ms`
+/// (exclusive), epoch milliseconds; an open end matches everything.
+fn in_window(time: SystemTime, from_ms: Option<i64>, before_ms: Option<i64>) -> bool {
+    let ms = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+    from_ms.is_none_or(|from| ms >= from) && before_ms.is_none_or(|before| ms < before)
+}
+
+#[derive(Deserialize)]
+pub struct RenameSensorRequest {
+    /// Id the history moves to.
+    pub sensor_id: String,
+    /// Model the history moves to; unchanged when omitted.
+    pub model: Option<String>,
+    /// Allow a target that has data already; the histories are combined.
+    #[serde(default)]
+    pub merge: bool,
+}
+
+#[derive(Serialize)]
+pub struct RenameSensorResponse {
+    #[serde(flatten)]
+    pub moved: RenameReport,
+    /// Whether the target had data already.
+    pub merged: bool,
+    /// The target's mapping: the moved one, or the one it already had.
+    pub mapping: Option<Mapping>,
+    /// Gauge series of the old id removed.
+    pub cleared_series: usize,
+}
+
+/// What a rename touches besides the database, see `run_rename`.
+#[derive(Clone)]
+pub struct RenameContext {
+    pub db: DbHandle,
+    pub store: Store,
+    /// See `config::MappingConfig::file`.
+    pub mappings_file: String,
+    pub latest: LatestReadings,
+    pub gauges: Arc<SensorGauges>,
+    pub last_seen: LastSeen,
+    pub freshness: Arc<SensorFreshness>,
+}
+
+/// A rename carried out by `run_rename`.
+pub struct RenameOutcome {
+    pub moved: RenameReport,
+    pub mapping: Option<Mapping>,
+    pub cleared_series: usize,
+}
+
+/// Carry out a started rename. The mapping moves first and every step can
+/// be repeated, so running this again finishes an interrupted rename.
+pub async fn run_rename(ctx: &RenameContext, pending: &PendingRename) -> anyhow::Result<RenameOutcome> {
+    let RenameRequest { model, sensor_id, new_model, new_sensor_id, .. } = &pending.request;
+    let (mapping, remapped) = {
+        let mut map = ctx.store.write().await;
+        let new_key = key_for(new_sensor_id, new_model);
+        let old = map.remove(&key_for(sensor_id, model));
+        let remapped = old.is_some();
+        if let Some(old) = old
+            && !map.contains_key(&new_key)
+        {
+            map.insert(new_key.clone(), Mapping { sensor_id: new_sensor_id.clone(), manufacturer: new_model.clone(), ..old });
+        }
+        (map.get(&new_key).cloned(), remapped)
+    };
+    if remapped {
+        save_mappings(&ctx.store, &ctx.mappings_file).await?;
+    }
+
+    let moved = ctx.db.rename_sensor(pending).await?;
+
+    let cleared_series = ctx.gauges.remove_sensor(model, sensor_id, None);
+    ctx.latest.write().await.retain(|k, _| !(k.model == *model && k.sensor_id == *sensor_id));
+    let key = SensorKey { model: model.clone(), sensor_id: sensor_id.clone() };
+    ctx.last_seen.write().await.remove(&key);
+    ctx.freshness.remove(&key);
+    Ok(RenameOutcome { moved, mapping, cleared_series })
+}
+
+/// Finish the renames a crash or shutdown interrupted.
+pub async fn resume_renames(ctx: RenameContext) {
+    let pending = match ctx.db.pending_renames().await {
+        Ok(pending) => pending,
+        Err(e) => {
+            warn!("Cannot list interrupted sensor renames: {}", e);
+            return;
+        }
+    };
+    for pending in pending {
+        let r = &pending.request;
+        info!("Resuming the rename of sensor {}::{} to {}::{}", r.model, r.sensor_id, r.new_model, r.new_sensor_id);
+        if let Err(e) = run_rename(&ctx, &pending).await {
+            warn!("Resuming the rename of sensor {}::{} failed: {}", r.model, r.sensor_id, e);
+        }
+    }
+}
+
+/// Move everything stored for a sensor to another id (and model), e.g. when
+/// a replaced device should continue the old one's history: rows, rollups,
+/// archived files, transitions, annotations and labels. A target with data
+/// is refused unless `merge` is set. The mapping moves along unless the
+/// target has one, and the old id's series are retired. The move is
+/// recorded in `audit_log`. It runs as its own task, so a client going away
+/// does not cut it short, and a rename interrupted by a restart is resumed
+/// at startup. Requires the admin token.
+#[allow(clippy::too_many_arguments)]
+pub async fn rename_sensor(
+    Extension(db): Extension<DbHandle>,
+    Extension(admin): Extension<AdminAccess>,
+    Extension(store): Extension<Store>,
+    Extension(latest): Extension<LatestReadings>,
+    Extension(gauges): Extension<Arc<SensorGauges>>,
+    Extension(last_seen): Extension<LastSeen>,
+    Extension(freshness): Extension<Arc<SensorFreshness>>,
+    Extension(buffer): Extension<Arc<MqttBuffer>>,
+    Extension(config): Extension<MappingConfig>,
+    headers: HeaderMap,
+    Path((model, sensor_id)): Path<(String, String)>,
+    Json(req): Json<RenameSensorRequest>,
+) -> Result<Json<RenameSensorResponse>, Problem> {
+    authorize_admin(&admin, &headers)?;
+    let new_model = req.model.unwrap_or_else(|| model.clone());
+    if req.sensor_id.trim().is_empty() || new_model.trim().is_empty() {
+        return Err(Problem::invalid("sensor_id and model must not be empty"));
+    }
+    if new_model == model && req.sensor_id == sensor_id {
+        return Err(Problem::invalid("the target is the sensor itself"));
+    }
+    // Buffered rows are stored first: they count as data and are covered by
+    // the rename.
+    buffer.flush(&db).await.map_err(|e| Problem::db(&db, e))?;
+    let pending = db.pending_renames().await.map_err(|e| Problem::db(&db, e))?;
+    if pending.iter().any(|p| p.request.model == model && p.request.sensor_id == sensor_id) {
+        return Err(Problem::new(StatusCode::CONFLICT, "conflict", format!("{}::{} is being renamed already", model, sensor_id)));
+    }
+    if !db.sensor_has_data(&model, &sensor_id).await.map_err(|e| Problem::db(&db, e))? {
+        return Err(Problem::not_found(format!("no data stored for {}::{}", model, sensor_id)));
+    }
+    let merged = db.sensor_has_data(&new_model, &req.sensor_id).await.map_err(|e| Problem::db(&db, e))?;
+    if merged && !req.merge {
+        return Err(Problem::new(
+            StatusCode::CONFLICT,
+            "conflict",
+            format!("{}::{} has data already; set merge to combine the histories", new_model, req.sensor_id),
+        ));
+    }
+
+    let request = RenameRequest {
+        model: model.clone(),
+        sensor_id: sensor_id.clone(),
+        new_model: new_model.clone(),
+        new_sensor_id: req.sensor_id.clone(),
+        merge: merged,
+    };
+    info!("Moving the history of sensor {}::{} to {}::{}", model, sensor_id, new_model, req.sensor_id);
+    let ctx = RenameContext { db: db.clone(), store, mappings_file: config.file, latest, gauges, last_seen, freshness };
+    let job = tokio::spawn(async move {
+        let pending = ctx.db.begin_rename(request).await?;
+        run_rename(&ctx, &pending).await
+    });
+    let outcome = job
+        .await
+        .map_err(|e| Problem::internal(format!("rename task failed: {}", e)))?
+        .map_err(|e| Problem::db(&db, e))?;
+    Ok(Json(RenameSensorResponse { moved: outcome.moved, merged, mapping: outcome.mapping, cleared_series: outcome.cleared_series }))
+}
+
+#[derive(Deserialize, Default)]
+pub struct BatteryReplacedRequest {
+    pub note: Option<String>,
+    /// New rolling id the sensor came back with after the battery swap.
+    /// Its mapping takes over the old id's friendly name.
+    pub new_sensor_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BatteryReplacedResponse {
+    pub annotation: Annotation,
+    /// The mapping now pointing at the new id, if one was linked.
+    pub mapping: Option<Mapping>,
+    /// Gauge series removed so low-battery alerts resolve.
+    pub cleared_series: usize,
+}
+
+/// Record a battery replacement for a sensor in one call: an annotation is
+/// stored, the sensor's `sensor_battery_ok` series is dropped so a pending
+/// low-battery alert resolves (the next message re-creates it), and when
+/// `new_sensor_id` is given the mapping moves to the new rolling id and the
+/// old id's series are retired. A new id that has a mapping of its own is
+/// refused with `409 Conflict`. Like the other mapping changes this needs
+/// write credentials rather than the admin token, as it deletes no data.
+#[allow(clippy::too_many_arguments)]
+pub async fn battery_replaced(
+    Extension(db): Extension<DbHandle>,
+    Extension(store): Extension<Store>,
+    Extension(config): Extension<MappingConfig>,
+    Extension(latest): Extension<LatestReadings>,
+    Extension(gauges): Extension<Arc<SensorGauges>>,
+    Extension(last_seen): Extension<LastSeen>,
+    Extension(freshness): Extension<Arc<SensorFreshness>>,
+    Path((model, sensor_id)): Path<(String, String)>,
+    body: Option<Json<BatteryReplacedRequest>>,
+) -> Result<(StatusCode, Json<BatteryReplacedResponse>), Problem> {
+    let req = body.map(|Json(r)| r).unwrap_or_default();
+    if req.new_sensor_id.as_deref().is_some_and(|id| id.trim().is_empty() || id == sensor_id) {
+        return Err(Problem::invalid("new_sensor_id must be a different, non-empty id"));
+    }
+
+    let mut mapping = None;
+    if let Some(new_id) = &req.new_sensor_id {
+        let mut map = store.write().await;
+        if !map.contains_key(&key_for(&sensor_id, &model)) {
+            return Err(Problem::not_found(format!("no mapping for {}::{} to link", model, sensor_id)));
+        }
+        if map.contains_key(&key_for(new_id, &model)) {
+            return Err(Problem::new(
+                StatusCode::CONFLICT,
+                "conflict",
+                format!("{}::{} is mapped already; delete its mapping first", model, new_id),
+            ));
+        }
+        let old = map.remove(&key_for(&sensor_id, &model)).expect("checked above");
+        let linked = Mapping { sensor_id: new_id.clone(), ..old };
+        map.insert(key_for(new_id, &model), linked.clone());
+        mapping = Some(linked);
+    }
+    if mapping.is_some() {
+        save_mappings(&store, &config.file).await.map_err(save_failed)?;
+    }
+
+    // Linking retires every series of the old id; otherwise only the
+    // battery series is reset.
+    let battery = measurement_code("battery_ok");
+    let only = if mapping.is_some() { None } else { battery };
+    let cleared_series = gauges.remove_sensor(&model, &sensor_id, only);
+    latest
+        .write()
+        .await
+        .retain(|k, _| !(k.model == model && k.sensor_id == sensor_id && only.is_none_or(|t| t == k.measurement_type)));
+    if only.is_none() {
+        // The old id will never report again; don't flag it as stale.
+        let key = SensorKey { model: model.clone(), sensor_id: sensor_id.clone() };
+        last_seen.write().await.remove(&key);
+        freshness.remove(&key);
+    }
+
+    let annotation = Annotation {
+        model,
+        sensor_id,
+        kind: "battery_replaced".to_string(),
+        note: req.note,
+        linked_sensor_id: req.new_sensor_id,
+    };
+    db.annotate(annotation.clone()).await.map_err(|e| Problem::db(&db, e))?;
+    info!("Battery replaced for {}::{}", annotation.model, annotation.sensor_id);
+    Ok((StatusCode::CREATED, Json(BatteryReplacedResponse { annotation, mapping, cleared_series })))
+}
+
+/// Readiness: `200 ok` once the database is open, `503` while waiting for
+/// another process to release its lock or after giving up on it.
+pub async fn health(Extension(db): Extension<DbHandle>) -> Result<&'static str, Problem> {
+    let unavailable = |detail: String| Problem::new(StatusCode::SERVICE_UNAVAILABLE, "db_unavailable", detail);
+    match db.status() {
+        DbStatus::Ready => Ok("ok"),
+        DbStatus::Opening => Err(unavailable("database is locked by another process, retrying".to_string())),
+        DbStatus::Failed(reason) => Err(unavailable(reason)),
+    }
 }
 
+/// Directory of the built single-page app.
+pub const UI_DIR: &str = "ui/dist";
+
 /// Serve the built single-page app under `ui/dist`. The handler maps `/` to
 /// `ui/dist/index.html` and otherwise attempts to read the requested file.
 /// This is intentionally small — for production you might use a static file
-/// server or embed assets in the binary.
+/// server or embed assets in the binary. Fingerprinted files under
+/// `assets/` are cached for good; everything else is revalidated so a new
+/// `index.html` is picked up right after a deploy.
 pub async fn spa_handler(req: Request<Body>) -> impl IntoResponse {
     let path = req.uri().path();
-    let rel = if path == "/" { "ui/dist/index.html".to_string() } else { format!("ui/dist{}", path) };
+    let rel = if path == "/" { format!("{}/index.html", UI_DIR) } else { format!("{}{}", UI_DIR, path) };
 
     match tokio::fs::read(&rel).await {
         Ok(bytes) => {
@@ -66,8 +1705,355 @@ pub async fn spa_handler(req: Request<Body>) -> impl IntoResponse {
             };
             let mut headers = HeaderMap::new();
             headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            let cache_control = if path.starts_with("/assets/") { "public, max-age=31536000, immutable" } else { "no-cache" };
+            headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
             (headers, bytes).into_response()
         }
         Err(_) => (StatusCode::NOT_FOUND, "Not Found").into_response(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt_buffer::measurement_code;
+
+    fn mappings(unit: &str) -> HashMap<String, Mapping> {
+        let mapping = Mapping {
+            sensor_id: "42".to_string(),
+            manufacturer: "Acurite-Tower".to_string(),
+            name: "porch".to_string(),
+            units: [("temperature_C".to_string(), unit.to_string())].into(),
+            retention_days: None,
+            expected_interval_secs: None,
+        };
+        HashMap::from([(key_for("42", "Acurite-Tower"), mapping)])
+    }
+
+    fn bucket() -> RollupRow {
+        RollupRow {
+            bucket: chrono::NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            sensor_id: "42".to_string(),
+            model: "Acurite-Tower".to_string(),
+            measurement_type: measurement_code("temperature_C").unwrap(),
+            site: String::new(),
+            probe: String::new(),
+            min: 10.0,
+            max: 30.0,
+            avg: 20.0,
+            count: 3,
+        }
+    }
+
+    #[test]
+    fn stored_buckets_follow_the_current_mapping_unit() {
+        let bucket_in = |unit: &str| {
+            let b = RollupBucket::with_unit(bucket(), &mappings(unit));
+            (b.unit, b.min, b.max, b.avg)
+        };
+        assert_eq!(bucket_in("F"), ("°F".to_string(), 50.0, 86.0, 68.0));
+        // Changing the mapping relabels and converts the same stored data.
+        assert_eq!(bucket_in("K"), ("K".to_string(), 283.15, 303.15, 293.15));
+        let unmapped = RollupBucket::with_unit(bucket(), &HashMap::new());
+        assert_eq!((unmapped.unit.as_str(), unmapped.avg), ("°C", 20.0));
+    }
+
+    #[test]
+    fn steps_take_seconds_or_a_unit_suffix() {
+        assert_eq!(parse_step("300"), Some(300_000));
+        assert_eq!(parse_step(" 30s "), Some(30_000));
+        assert_eq!(parse_step("5m"), Some(300_000));
+        assert_eq!(parse_step("1h"), Some(3_600_000));
+        assert_eq!(parse_step("1d"), Some(86_400_000));
+        for invalid in ["", "0", "-5m", "m", "1w", "1.5h", "9223372036854775807d"] {
+            assert_eq!(parse_step(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_patches_of_a_mapping_all_apply() {
+        let dir = std::env::temp_dir().join(format!("exporter-patch-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = MappingConfig { file: dir.join("mappings.json").to_string_lossy().into_owned(), ..MappingConfig::default() };
+        let store: Store = Arc::new(tokio::sync::RwLock::new(mappings("C")));
+        let last_seen = LastSeen::default();
+        let patch = |patch: MappingPatch| {
+            let (store, last_seen, config) = (store.clone(), last_seen.clone(), config.clone());
+            tokio::spawn(async move {
+                let target = Query(MappingTarget::default());
+                patch_mapping(Extension(store), Extension(last_seen), Extension(config), Path("42".to_string()), target, Json(patch)).await
+            })
+        };
+        let empty = || MappingPatch { name: None, units: None, retention_days: None, expected_interval_secs: None };
+        // Validation waits for `last_seen`, so both patches are in flight
+        // before either is written.
+        let held = last_seen.write().await;
+        let renamed = patch(MappingPatch { name: Some("deck".to_string()), ..empty() });
+        let kept = patch(MappingPatch { retention_days: Some(Some(30)), ..empty() });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        drop(held);
+        assert!(renamed.await.unwrap().is_ok());
+        assert!(kept.await.unwrap().is_ok());
+
+        let map = store.read().await;
+        let mapping = &map[&key_for("42", "Acurite-Tower")];
+        assert_eq!((mapping.name.as_str(), mapping.retention_days), ("deck", Some(30)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sensors_are_listed_from_rollups_and_new_rows() {
+        use crate::db::{start_db_worker, DbConfig};
+        use crate::mqtt_buffer::rows_to_record_batch;
+        let dir = std::env::temp_dir().join(format!("exporter-handlers-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let (db, _worker) = start_db_worker(&config, &prometheus::Registry::new()).unwrap();
+        let row = |sensor_id: &str, time: &str, key: &str| NormalizedRow {
+            timestamp: chrono::NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").unwrap(),
+            sensor_id: sensor_id.to_string(),
+            model: "Acurite-Tower".to_string(),
+            measurement_type: measurement_code(key).unwrap(),
+            value: 20.0,
+            topic: "rtl_433/events".to_string(),
+            seq: 0,
+            site: String::new(),
+            probe: String::new(),
+        };
+        let append = |rows: Vec<NormalizedRow>| db.append("measurements", rows_to_record_batch(&rows).unwrap());
+        append(vec![row("42", "2024-03-01 08:10:00", "temperature_C"), row("42", "2024-03-01 08:20:00", "humidity")]).await.unwrap();
+        db.rollup().await.unwrap();
+        // Retention pruned the rolled-up rows.
+        db.execute("DELETE FROM measurements").await.unwrap();
+        append(vec![row("42", "2024-03-01 09:30:00", "temperature_C"), row("7", "2024-03-01 10:05:00", "temperature_C")]).await.unwrap();
+
+        let store: Store = Arc::new(tokio::sync::RwLock::new(mappings("C")));
+        let listed = |unmapped| list_sensors(Extension(db.clone()), Extension(store.clone()), Query(SensorListParams { unmapped }));
+        let Json(sensors) = listed(false).await.unwrap();
+        let summary: Vec<_> = sensors
+            .iter()
+            .map(|s| (s.sensor_id.as_str(), s.first_seen.to_string(), s.last_seen.to_string(), s.readings, s.measurements.join(","), s.name.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("7", "2024-03-01 10:05:00".to_string(), "2024-03-01 10:05:00".to_string(), 1, "temperature_C".to_string(), None),
+                ("42", "2024-03-01 08:00:00".to_string(), "2024-03-01 09:30:00".to_string(), 3, "humidity,temperature_C".to_string(), Some("porch")),
+            ]
+        );
+        let Json(sensors) = listed(true).await.unwrap();
+        assert_eq!(sensors.iter().map(|s| s.sensor_id.as_str()).collect::<Vec<_>>(), vec!["7"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn purge_windows_include_from_and_exclude_before() {
+        let at = |ms: u64| UNIX_EPOCH + Duration::from_millis(ms);
+        assert!(in_window(at(1_000), None, None));
+        assert!(in_window(at(1_000), Some(1_000), Some(2_000)));
+        assert!(!in_window(at(2_000), Some(1_000), Some(2_000)));
+        assert!(!in_window(at(999), Some(1_000), None));
+        assert!(in_window(at(999), None, Some(1_000)));
+
+        // Bad readings still buffered are purged with the stored ones.
+        let from = chrono::NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_opt(8, 0, 0).unwrap();
+        let params = PurgeParams {
+            from: Some(from.and_utc().timestamp_millis()),
+            before: Some(from.and_utc().timestamp_millis() + 3_600_000),
+            measurement: None,
+        };
+        let rows = vec![
+            buffered("42", "2024-03-01 07:59:59"),
+            buffered("42", "2024-03-01 08:00:00"),
+            buffered("42", "2024-03-01 08:59:59"),
+            buffered("42", "2024-03-01 09:00:00"),
+        ];
+        assert_eq!(purge_buffered("purge-window", rows, params).await, vec![("42".to_string(), 2)]);
+    }
+
+    /// Purge `42` with `params` while `buffered` waits in the ingest buffer,
+    /// and return which sensors still have data.
+    async fn purge_buffered(name: &str, buffered: Vec<NormalizedRow>, params: PurgeParams) -> Vec<(String, i64)> {
+        use crate::config::{EnrichmentConfig, LoggingConfig, MetricsConfig};
+        use crate::db::{start_db_worker, DbConfig};
+        use crate::enrichment::Enrichment;
+        use crate::logging::LogLimiter;
+        use crate::mqtt_buffer::FlushPolicy;
+        let dir = std::env::temp_dir().join(format!("exporter-{}-test-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let registry = Registry::new();
+        let (db, _worker) = start_db_worker(&config, &registry).unwrap();
+        let log = Arc::new(LogLimiter::new(&LoggingConfig::default()));
+        let enrichment = Arc::new(Enrichment::new(&EnrichmentConfig::default(), db.clone(), log, &registry).unwrap());
+        let gauges = Arc::new(SensorGauges::new(&registry, &MetricsConfig::default(), enrichment).unwrap());
+        let freshness = Arc::new(SensorFreshness::new(&registry, Duration::from_secs(3600)).unwrap());
+        let buffer = Arc::new(MqttBuffer::new(&registry, FlushPolicy::default()).unwrap());
+        buffer.push(buffered, std::time::Instant::now());
+
+        let admin = AdminAccess { token: Some("s3cret".to_string()), cipher: None };
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer s3cret"));
+        let path = Path(("Acurite-Tower".to_string(), "42".to_string()));
+        let purged = purge_sensor(
+            Extension(db.clone()),
+            Extension(admin),
+            Extension(LatestReadings::default()),
+            Extension(gauges),
+            Extension(LastSeen::default()),
+            Extension(freshness),
+            Extension(buffer.clone()),
+            headers,
+            path,
+            Query(params),
+        )
+        .await;
+        assert!(purged.is_ok());
+        // Nothing purged comes back with the next flush.
+        buffer.flush(&db).await.unwrap();
+
+        #[derive(Deserialize)]
+        struct Stored {
+            sensor_id: String,
+            rows: i64,
+        }
+        let stored: Vec<Stored> = db
+            .query_as("SELECT sensor_id, count(*) AS rows FROM measurements GROUP BY sensor_id ORDER BY sensor_id", Vec::new())
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        stored.into_iter().map(|s| (s.sensor_id, s.rows)).collect()
+    }
+
+    fn buffered(sensor_id: &str, time: &str) -> NormalizedRow {
+        NormalizedRow {
+            timestamp: chrono::NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").unwrap(),
+            sensor_id: sensor_id.to_string(),
+            model: "Acurite-Tower".to_string(),
+            measurement_type: measurement_code("temperature_C").unwrap(),
+            value: 20.0,
+            topic: "rtl_433/events".to_string(),
+            seq: 0,
+            site: String::new(),
+            probe: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn purges_include_buffered_readings() {
+        let rows = vec![buffered("42", "2024-03-01 08:00:00"), buffered("7", "2024-03-01 08:00:00")];
+        let params = PurgeParams { from: None, before: None, measurement: None };
+        assert_eq!(purge_buffered("purge", rows, params).await, vec![("7".to_string(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn previews_list_the_gauges_a_payload_sets() {
+        use crate::config::{EnrichmentConfig, LoggingConfig, MetricsConfig};
+        use crate::db::{start_db_worker, DbConfig};
+        use crate::enrichment::Enrichment;
+        use crate::logging::LogLimiter;
+        use crate::metrics::IntegrationHealth;
+        use crate::mqtt_buffer::FlushPolicy;
+        use crate::sentinels::Sentinels;
+        use crate::version::Version;
+        let dir = std::env::temp_dir().join(format!("exporter-preview-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let registry = Registry::new();
+        let (db, _worker) = start_db_worker(&config, &registry).unwrap();
+        let log = Arc::new(LogLimiter::new(&LoggingConfig::default()));
+        let enrichment = Arc::new(Enrichment::new(&EnrichmentConfig::default(), db.clone(), log, &registry).unwrap());
+        let metrics = MetricsConfig { rounding: HashMap::from([("temperature_C".to_string(), 1)]), ..MetricsConfig::default() };
+        let gauges = Arc::new(SensorGauges::new(&registry, &metrics, enrichment).unwrap());
+        let normalizer = Arc::new(Normalizer::new(&BTreeMap::new(), &BTreeMap::new(), &[], Sentinels::default()).unwrap());
+        let (_topics, subscribed) = tokio::sync::watch::channel(vec!["rtl_433/#".to_string(), "zigbee/#".to_string()]);
+        let status = Arc::new(Status {
+            started_at: chrono::Utc::now(),
+            version: Arc::new(Version::detect(std::path::Path::new(UI_DIR))),
+            db: db.clone(),
+            db_path: config.path.clone(),
+            buffer: Arc::new(MqttBuffer::new(&registry, FlushPolicy::default()).unwrap()),
+            integration: IntegrationHealth::new(&registry).unwrap(),
+            mqtt_enabled: true,
+            broker: "localhost:1883".to_string(),
+            control_topic: None,
+            topics: subscribed,
+        });
+        let store: Store = Arc::new(tokio::sync::RwLock::new(mappings("C")));
+        let req = ParsePreviewRequest {
+            topic: "rtl_433/events".to_string(),
+            payload: serde_json::json!({"time": "2024-03-01 08:00:00", "model": "Acurite-Tower", "id": 42, "temperature_C": 21.54}),
+        };
+        let Json(preview) =
+            parse_preview(Extension(normalizer), Extension(gauges.clone()), Extension(store), Extension(status), Json(req)).await.unwrap();
+        let samples: Vec<_> = preview.metrics.iter().map(|m| (m.name.as_str(), m.labels.clone(), m.value)).collect();
+        let labels = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<BTreeMap<_, _>>();
+        assert_eq!(
+            samples,
+            vec![
+                ("mqtt_messages_total", BTreeMap::new(), 1.0),
+                ("mqtt_topic_messages_total", labels(&[("topic", "rtl_433/#")]), 1.0),
+                (
+                    "sensor_temperature_celsius",
+                    labels(&[("location", "porch"), ("model", "Acurite-Tower"), ("probe", ""), ("sensor_id", "42"), ("site", "")]),
+                    21.5
+                ),
+            ]
+        );
+        // Nothing was set.
+        assert!(gauges.rows().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn battery_swaps_do_not_overwrite_another_mapping() {
+        use crate::config::{EnrichmentConfig, LoggingConfig, MetricsConfig};
+        use crate::db::{start_db_worker, DbConfig};
+        use crate::enrichment::Enrichment;
+        use crate::logging::LogLimiter;
+        let dir = std::env::temp_dir().join(format!("exporter-battery-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let registry = Registry::new();
+        let (db, _worker) = start_db_worker(&config, &registry).unwrap();
+        let log = Arc::new(LogLimiter::new(&LoggingConfig::default()));
+        let enrichment = Arc::new(Enrichment::new(&EnrichmentConfig::default(), db.clone(), log, &registry).unwrap());
+        let gauges = Arc::new(SensorGauges::new(&registry, &MetricsConfig::default(), enrichment).unwrap());
+        let freshness = Arc::new(SensorFreshness::new(&registry, Duration::from_secs(3600)).unwrap());
+        let mapping_config = MappingConfig { file: dir.join("mappings.json").to_string_lossy().into_owned(), ..MappingConfig::default() };
+        let mut map = mappings("C");
+        let shed = Mapping { sensor_id: "43".to_string(), name: "shed".to_string(), ..map[&key_for("42", "Acurite-Tower")].clone() };
+        map.insert(key_for("43", "Acurite-Tower"), shed);
+        let store: Store = Arc::new(tokio::sync::RwLock::new(map));
+        let swap = |new_id: &str| {
+            let req = BatteryReplacedRequest { note: None, new_sensor_id: Some(new_id.to_string()) };
+            battery_replaced(
+                Extension(db.clone()),
+                Extension(store.clone()),
+                Extension(mapping_config.clone()),
+                Extension(LatestReadings::default()),
+                Extension(gauges.clone()),
+                Extension(LastSeen::default()),
+                Extension(freshness.clone()),
+                Path(("Acurite-Tower".to_string(), "42".to_string())),
+                Some(Json(req)),
+            )
+        };
+
+        let refused = swap("43").await.err().unwrap();
+        assert_eq!(refused.into_response().status(), StatusCode::CONFLICT);
+        let names = |map: &HashMap<String, Mapping>| {
+            let mut names: Vec<_> = map.values().map(|m| (m.sensor_id.clone(), m.name.clone())).collect();
+            names.sort();
+            names
+        };
+        let both = vec![("42".to_string(), "porch".to_string()), ("43".to_string(), "shed".to_string())];
+        assert_eq!(names(&*store.read().await), both);
+
+        let (status, _) = swap("44").await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let moved = vec![("43".to_string(), "shed".to_string()), ("44".to_string(), "porch".to_string())];
+        assert_eq!(names(&*store.read().await), moved);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+// Kubernetes-style probes. `/health` only reports whether the database is
+// open; these check the pieces ingestion depends on:
+//
+//   GET /healthz/live    the DB worker thread is running and the database
+//                        has not been given up on (a restart may help)
+//   GET /healthz/ready   additionally: the MQTT broker is connected, the
+//                        DB worker answers a ping in time and the ingest
+//                        buffer is not backed up, and with
+//                        `mqtt.strict_start` the startup checks passed
+//                        (see `strict_start`)
+//
+// Both answer `200` with `{"status": "ok", "checks": {...}}` or `503` with a
+// problem document (`not_live` / `not_ready`) whose `errors` name each
+// failed check. Thresholds:
+//
+//   [health]
+//   db_timeout_secs = 5
+//   max_backlog_rows = 50000         # 0 = no limit
+//   max_backlog_bytes = 67108864
+//
+// The ping queues behind other database commands, so a long export can make
+// the exporter briefly unready; liveness never depends on the broker or on
+// the worker being idle, so a broker outage does not get the pod restarted.
+use crate::config::HealthConfig;
+use crate::db::{DbHandle, DbStatus};
+use crate::metrics::IntegrationHealth;
+use crate::mqtt;
+use crate::mqtt_buffer::MqttBuffer;
+use crate::problem::{FieldError, Problem};
+use crate::strict_start::StartGate;
+use axum::{extract::Extension, http::StatusCode, Json};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+pub struct Health {
+    pub config: HealthConfig,
+    pub db: DbHandle,
+    pub db_worker: JoinHandle<()>,
+    pub buffer: Arc<MqttBuffer>,
+    pub integration: IntegrationHealth,
+    /// Whether readiness requires a broker connection.
+    pub mqtt_enabled: bool,
+    /// Set with `mqtt.strict_start`; not ready until ingestion may begin.
+    pub start: Option<Arc<StartGate>>,
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    status: &'static str,
+    /// Check name -> what it found.
+    checks: BTreeMap<&'static str, String>,
+}
+
+/// Outcome of the checks of one probe.
+#[derive(Default)]
+struct Checks {
+    passed: BTreeMap<&'static str, String>,
+    failed: Vec<FieldError>,
+}
+
+impl Checks {
+    fn record(&mut self, name: &'static str, result: Result<String, String>) {
+        match result {
+            Ok(detail) => {
+                self.passed.insert(name, detail);
+            }
+            Err(reason) => self.failed.push(FieldError::new(name, reason)),
+        }
+    }
+
+    fn into_response(self, code: &'static str) -> Result<Json<HealthReport>, Problem> {
+        if self.failed.is_empty() {
+            return Ok(Json(HealthReport { status: "ok", checks: self.passed }));
+        }
+        let detail = self.failed.iter().map(|f| format!("{}: {}", f.field, f.message)).collect::<Vec<_>>().join("; ");
+        Err(Problem::new(StatusCode::SERVICE_UNAVAILABLE, code, detail).with_errors(self.failed))
+    }
+}
+
+impl Health {
+    fn check_worker(&self) -> Result<String, String> {
+        if self.db_worker.is_finished() {
+            return Err("DB worker thread has stopped".to_string());
+        }
+        match self.db.status() {
+            DbStatus::Failed(reason) => Err(reason),
+            DbStatus::Opening => Ok("waiting for the database lock".to_string()),
+            DbStatus::Ready => Ok("running".to_string()),
+        }
+    }
+
+    async fn check_db(&self) -> Result<String, String> {
+        match self.db.status() {
+            DbStatus::Ready => {}
+            DbStatus::Opening => return Err("database is locked by another process, retrying".to_string()),
+            DbStatus::Failed(reason) => return Err(reason),
+        }
+        let timeout = Duration::from_secs(self.config.db_timeout_secs.max(1));
+        match tokio::time::timeout(timeout, self.db.ping()).await {
+            Ok(Ok(())) => Ok("ok".to_string()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("DB worker did not answer within {}s", timeout.as_secs())),
+        }
+    }
+
+    fn check_mqtt(&self) -> Result<String, String> {
+        if self.integration.is_up(mqtt::SOURCE) {
+            Ok("connected".to_string())
+        } else {
+            Err("not connected to the broker".to_string())
+        }
+    }
+
+    fn check_buffer(&self) -> Result<String, String> {
+        let (rows, bytes) = self.buffer.backlog();
+        let detail = format!("{} rows, {} bytes buffered", rows, bytes);
+        let over = |limit: usize, value: usize| limit > 0 && value > limit;
+        if over(self.config.max_backlog_rows, rows) || over(self.config.max_backlog_bytes, bytes) {
+            Err(format!("{} (limits {} rows, {} bytes)", detail, self.config.max_backlog_rows, self.config.max_backlog_bytes))
+        } else {
+            Ok(detail)
+        }
+    }
+}
+
+/// Liveness probe: `503 not_live` when only a restart can help.
+pub async fn live(Extension(health): Extension<Arc<Health>>) -> Result<Json<HealthReport>, Problem> {
+    let mut checks = Checks::default();
+    checks.record("db_worker", health.check_worker());
+    checks.into_response("not_live")
+}
+
+/// Readiness probe: `503 not_ready` while ingestion or queries would not
+/// work.
+pub async fn ready(Extension(health): Extension<Arc<Health>>) -> Result<Json<HealthReport>, Problem> {
+    let mut checks = Checks::default();
+    checks.record("db", health.check_db().await);
+    if health.mqtt_enabled {
+        checks.record("mqtt", health.check_mqtt());
+    }
+    checks.record("buffer", health.check_buffer());
+    if let Some(gate) = &health.start {
+        checks.record("startup", gate.status());
+    }
+    checks.into_response("not_ready")
+}
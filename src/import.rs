@@ -0,0 +1,309 @@
+// Historical CSV import. Other systems (Home Assistant's recorder, weewx
+// archive exports, ...) produce CSV files with their own column layouts, so
+// the importer is driven by a small JSON column-mapping spec that says which
+// columns hold the timestamp, sensor identity and measurement values, and
+// in which units. Values are converted to the canonical unit of their
+// measurement key and appended to the `measurements` table in batches.
+//
+// Run with: `rust-to-mqtt-prometheus-exporter import-csv <file.csv> <spec.json>`
+use crate::config::Config;
+use crate::db::{self, DbHandle};
+use crate::mqtt_buffer::{install_measurement_types, measurement_code, measurement_types, rows_to_record_batch, NormalizedRow};
+use crate::units;
+use chrono::{DateTime, NaiveDateTime};
+use serde::Deserialize;
+
+/// Rows appended per DuckDB batch.
+const IMPORT_BATCH_SIZE: usize = 10_000;
+
+/// Column-mapping spec for one CSV layout.
+///
+/// ```json
+/// {
+///   "timestamp": { "column": "dateTime", "format": "unix" },
+///   "sensor_id": { "value": "outdoor" },
+///   "model": { "value": "weewx" },
+///   "measurements": [
+///     { "column": "outTemp", "key": "temperature_C", "unit": "F" },
+///     { "column": "barometer", "key": "pressure_kPa", "unit": "inHg" }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct CsvImportSpec {
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+    pub timestamp: TimestampColumn,
+    pub sensor_id: FieldSource,
+    pub model: FieldSource,
+    pub measurements: Vec<MeasurementColumn>,
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+/// Where the timestamp comes from. `format` is `unix` (epoch seconds),
+/// `rfc3339` (converted to UTC), or a chrono `strftime` pattern applied
+/// as-is. Defaults to `%Y-%m-%d %H:%M:%S`, the rtl_433 format.
+#[derive(Debug, Deserialize)]
+pub struct TimestampColumn {
+    pub column: String,
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+/// A value taken either from a column or a constant.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum FieldSource {
+    Column { column: String },
+    Value { value: String },
+}
+
+/// One measurement column. `unit` defaults to the canonical unit of `key`.
+/// `when` restricts the column to rows where another column has a given
+/// value, which is how long-format exports (one entity per row, like the
+/// Home Assistant recorder) are mapped.
+#[derive(Debug, Deserialize)]
+pub struct MeasurementColumn {
+    pub column: String,
+    pub key: String,
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub when: Option<ColumnMatch>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ColumnMatch {
+    pub column: String,
+    pub equals: String,
+}
+
+/// Outcome of an import run.
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub records: usize,
+    pub rows_written: usize,
+    pub values_skipped: usize,
+}
+
+/// Resolved column indexes so the per-record loop doesn't look up headers.
+struct ResolvedSpec<'a> {
+    timestamp: usize,
+    timestamp_format: Option<&'a str>,
+    sensor_id: ResolvedField<'a>,
+    model: ResolvedField<'a>,
+    measurements: Vec<ResolvedMeasurement<'a>>,
+}
+
+enum ResolvedField<'a> {
+    Column(usize),
+    Value(&'a str),
+}
+
+struct ResolvedMeasurement<'a> {
+    column: usize,
+    code: u8,
+    key: &'a str,
+    unit: Option<&'a str>,
+    when: Option<(usize, &'a str)>,
+}
+
+impl<'a> ResolvedField<'a> {
+    fn get(&self, record: &'a csv::StringRecord) -> &'a str {
+        match self {
+            ResolvedField::Column(i) => record.get(*i).unwrap_or(""),
+            ResolvedField::Value(v) => v,
+        }
+    }
+}
+
+fn column_index(headers: &csv::StringRecord, name: &str) -> anyhow::Result<usize> {
+    headers
+        .iter()
+        .position(|h| h == name)
+        .ok_or_else(|| anyhow::anyhow!("column {:?} not found in CSV header", name))
+}
+
+impl CsvImportSpec {
+    fn resolve<'a>(&'a self, headers: &csv::StringRecord) -> anyhow::Result<ResolvedSpec<'a>> {
+        let field = |source: &'a FieldSource| -> anyhow::Result<ResolvedField<'a>> {
+            Ok(match source {
+                FieldSource::Column { column } => ResolvedField::Column(column_index(headers, column)?),
+                FieldSource::Value { value } => ResolvedField::Value(value),
+            })
+        };
+        let mut measurements = Vec::with_capacity(self.measurements.len());
+        for m in &self.measurements {
+            let code = measurement_code(&m.key)
+                .ok_or_else(|| anyhow::anyhow!("unknown measurement key {:?}", m.key))?;
+            // A unit without a conversion fails here, before any batch is
+            // appended, rather than at the first row that has a value.
+            to_canonical_unit(&m.key, m.unit.as_deref(), 0.0)?;
+            let when = match &m.when {
+                Some(w) => Some((column_index(headers, &w.column)?, w.equals.as_str())),
+                None => None,
+            };
+            measurements.push(ResolvedMeasurement {
+                column: column_index(headers, &m.column)?,
+                code,
+                key: &m.key,
+                unit: m.unit.as_deref(),
+                when,
+            });
+        }
+        Ok(ResolvedSpec {
+            timestamp: column_index(headers, &self.timestamp.column)?,
+            timestamp_format: self.timestamp.format.as_deref(),
+            sensor_id: field(&self.sensor_id)?,
+            model: field(&self.model)?,
+            measurements,
+        })
+    }
+}
+
+fn parse_timestamp(raw: &str, format: Option<&str>) -> anyhow::Result<NaiveDateTime> {
+    match format {
+        Some("unix") => {
+            let secs: f64 = raw.trim().parse()?;
+            DateTime::from_timestamp_micros((secs * 1_000_000.0) as i64)
+                .map(|dt| dt.naive_utc())
+                .ok_or_else(|| anyhow::anyhow!("timestamp {:?} out of range", raw))
+        }
+        Some("rfc3339") => Ok(DateTime::parse_from_rfc3339(raw.trim())?.naive_utc()),
+        Some(pattern) => Ok(NaiveDateTime::parse_from_str(raw.trim(), pattern)?),
+        None => Ok(NaiveDateTime::parse_from_str(raw.trim(), "%Y-%m-%d %H:%M:%S")?),
+    }
+}
+
+/// Convert `value` from `unit` into the canonical unit of measurement
+/// `key` (its declared unit, see `units`). Unitless measurements such as
+/// `battery_ok` are taken as they are.
+pub fn to_canonical_unit(key: &str, unit: Option<&str>, value: f64) -> anyhow::Result<f64> {
+    let Some(unit) = unit else { return Ok(value) };
+    let canonical = measurement_types().iter().find(|t| t.key == key).map_or("", |t| t.unit.as_str());
+    if canonical.is_empty() {
+        return Ok(value);
+    }
+    units::convert(value, unit, canonical).ok_or_else(|| anyhow::anyhow!("no conversion from {:?} for {:?}", unit, key))
+}
+
+/// Import `csv_path` according to `spec`, appending rows through `db`.
+pub async fn import_csv(csv_path: &str, spec: &CsvImportSpec, db: &DbHandle) -> anyhow::Result<ImportSummary> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(u8::try_from(spec.delimiter).map_err(|_| anyhow::anyhow!("delimiter must be ASCII"))?)
+        .from_path(csv_path)?;
+    let headers = reader.headers()?.clone();
+    let resolved = spec.resolve(&headers)?;
+
+    let mut summary = ImportSummary::default();
+    let mut batch: Vec<NormalizedRow> = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    for record in reader.records() {
+        let record = record?;
+        summary.records += 1;
+        let timestamp = match record.get(resolved.timestamp).map(|raw| parse_timestamp(raw, resolved.timestamp_format)) {
+            Some(Ok(ts)) => ts,
+            _ => {
+                summary.values_skipped += resolved.measurements.len();
+                continue;
+            }
+        };
+        let sensor_id = resolved.sensor_id.get(&record);
+        let model = resolved.model.get(&record);
+
+        for m in &resolved.measurements {
+            if let Some((col, expected)) = m.when
+                && record.get(col) != Some(expected)
+            {
+                continue;
+            }
+            // Exports often contain placeholders like `unavailable` or empty
+            // cells; those are skipped rather than failing the import.
+            let value = match record.get(m.column).and_then(|v| v.trim().parse::<f64>().ok()) {
+                Some(v) => to_canonical_unit(m.key, m.unit, v)?,
+                None => {
+                    summary.values_skipped += 1;
+                    continue;
+                }
+            };
+            batch.push(NormalizedRow {
+                timestamp,
+                sensor_id: sensor_id.to_string(),
+                model: model.to_string(),
+                measurement_type: m.code,
+                value,
+                topic: "import-csv".to_string(),
+                seq: 0,
+                site: String::new(),
+                probe: String::new(),
+            });
+        }
+
+        if batch.len() >= IMPORT_BATCH_SIZE {
+            summary.rows_written += db.append("measurements", rows_to_record_batch(&batch)?).await?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        summary.rows_written += db.append("measurements", rows_to_record_batch(&batch)?).await?;
+    }
+    db.flush().await?;
+    Ok(summary)
+}
+
+/// Options of the `import-csv` command.
+#[derive(clap::Args)]
+pub struct Args {
+    /// The CSV file.
+    csv: String,
+    /// The JSON column-mapping spec.
+    spec: String,
+}
+
+/// Entry point for the `import-csv` command. Opens the database configured
+/// via `DUCKDB_PATH` directly, so it must not be run while the server holds
+/// the same file open.
+pub async fn run_cli(args: Args) -> anyhow::Result<()> {
+    let spec: CsvImportSpec = serde_json::from_str(&tokio::fs::read_to_string(&args.spec).await?)?;
+
+    let config = Config::load()?;
+    crate::logging::init(&config.logging)?;
+    install_measurement_types(&config.measurements)?;
+    let (db, _worker) = db::start_db_worker(&config.duckdb, &prometheus::Registry::new())?;
+    let summary = import_csv(&args.csv, &spec, &db).await?;
+    println!(
+        "Imported {} CSV records: {} rows written, {} values skipped",
+        summary.records, summary.rows_written, summary.values_skipped
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(unit: &str) -> CsvImportSpec {
+        serde_json::from_value(serde_json::json!({
+            "timestamp": { "column": "dateTime", "format": "unix" },
+            "sensor_id": { "value": "outdoor" },
+            "model": { "value": "weewx" },
+            "measurements": [
+                { "column": "outHumidity", "key": "humidity" },
+                { "column": "outTemp", "key": "temperature_C", "unit": unit }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn units_are_checked_when_the_spec_is_resolved() {
+        let headers = csv::StringRecord::from(vec!["dateTime", "outTemp", "outHumidity"]);
+        let fahrenheit = spec("F");
+        assert_eq!(fahrenheit.resolve(&headers).unwrap().measurements.len(), 2);
+        let error = spec("inHg").resolve(&headers).err().unwrap();
+        assert_eq!(error.to_string(), r#"no conversion from "inHg" for "temperature_C""#);
+        assert!(spec("furlongs").resolve(&headers).is_err());
+    }
+}
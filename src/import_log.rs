@@ -0,0 +1,262 @@
+// Backfill from rtl_433 JSON logs. rtl_433 run with `-F json` (or
+// `-F json:<file>`) writes one JSON event per line; `import` reads such
+// files, plain or gzip-compressed (`.gz`, as left by logrotate), and
+// appends their readings to the `measurements` table (`import-log` is an
+// alias):
+//
+// Run with: `rust-to-mqtt-prometheus-exporter import [--topic TOPIC] <file>...`
+//
+// Lines go through the normalizer of the live ingest with the configured
+// `models`, `probes` and `sentinels`, then `dedup` (which compares event
+// timestamps, so repeated transmissions in the log are dropped as they
+// would have been live). Rows are stored in canonical units like live ones.
+// Readings already stored (same series and timestamp, archives included)
+// are skipped, so a file can be imported again, e.g. after an interrupted
+// run or when a rotated log overlaps the previous one.
+// Each row's timestamp is the event's `time` field; lines without one in
+// rtl_433's default `YYYY-MM-DD HH:MM:SS` format are skipped, as are lines
+// that aren't events (banners, `-M stats` reports). Rows are stored with
+// `topic` as topic (default `import-log`). Progress goes to stderr every
+// few seconds. Like `import-csv`, it opens the database itself, so stop the
+// server first.
+use crate::config::Config;
+use crate::db::{self, DbHandle, MeasurementQuery};
+use crate::dedup::Deduplicator;
+use crate::mqtt_buffer::{install_measurement_types, rows_to_record_batch, NormalizedRow, Normalizer};
+use crate::sentinels::Sentinels;
+use chrono::NaiveDateTime;
+use flate2::read::MultiGzDecoder;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::{BufRead, BufReader, Read};
+use std::time::{Duration, Instant};
+
+/// Rows appended per DuckDB batch.
+const IMPORT_BATCH_SIZE: usize = 10_000;
+
+/// How often progress is reported.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Outcome of an import run.
+#[derive(Debug, Default)]
+pub struct LogImportSummary {
+    pub lines: usize,
+    pub rows_written: usize,
+    /// Events whose readings were all repeats.
+    pub duplicates: usize,
+    /// Readings left out because the database already had them.
+    pub rows_already_stored: usize,
+    /// Lines that aren't events or have no usable `time`.
+    pub lines_skipped: usize,
+}
+
+/// The part of an event checked before normalizing it.
+#[derive(Deserialize)]
+struct EventTime {
+    time: Option<String>,
+}
+
+/// What the lines of every file go through.
+pub struct LogImporter<'a> {
+    pub normalizer: &'a Normalizer,
+    pub dedup: &'a Deduplicator,
+    pub topic: &'a str,
+}
+
+impl LogImporter<'_> {
+    /// Import one log file, appending rows through `db`.
+    pub async fn import(&self, path: &str, db: &DbHandle, summary: &mut LogImportSummary) -> anyhow::Result<()> {
+        let file = std::fs::File::open(path).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        let reader: Box<dyn Read> = if path.ends_with(".gz") { Box::new(MultiGzDecoder::new(file)) } else { Box::new(file) };
+        let mut progress = Instant::now();
+        let mut batch: Vec<NormalizedRow> = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        for line in BufReader::new(reader).split(b'\n') {
+            let line = line.map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+            summary.lines += 1;
+            let line = line.trim_ascii();
+            if line.is_empty() {
+                continue;
+            }
+            // The normalizer falls back to the current time, which would put
+            // old events at the moment of the import.
+            let timed = serde_json::from_slice::<EventTime>(line).ok().and_then(|e| e.time).is_some_and(|time| {
+                NaiveDateTime::parse_from_str(&time, "%Y-%m-%d %H:%M:%S").is_ok()
+            });
+            let mut rows = match self.normalizer.normalize(self.topic, line) {
+                Ok(rows) if timed => rows,
+                _ => {
+                    summary.lines_skipped += 1;
+                    continue;
+                }
+            };
+            if !self.dedup.retain_new(&mut rows) {
+                summary.duplicates += 1;
+                continue;
+            }
+            batch.extend(rows);
+            if batch.len() >= IMPORT_BATCH_SIZE {
+                self.append(db, &mut batch, summary).await?;
+            }
+            if progress.elapsed() >= PROGRESS_INTERVAL {
+                eprintln!("{}: {} lines, {} rows written", path, summary.lines, summary.rows_written + batch.len());
+                progress = Instant::now();
+            }
+        }
+        if !batch.is_empty() {
+            self.append(db, &mut batch, summary).await?;
+        }
+        Ok(())
+    }
+
+    /// Append the rows of `batch` the database doesn't have yet and clear it.
+    async fn append(&self, db: &DbHandle, batch: &mut Vec<NormalizedRow>, summary: &mut LogImportSummary) -> anyhow::Result<()> {
+        let (Some(first), Some(last)) = (batch.iter().map(|r| r.timestamp).min(), batch.iter().map(|r| r.timestamp).max()) else {
+            return Ok(());
+        };
+        let measurement_types: BTreeSet<u8> = batch.iter().map(|r| r.measurement_type).collect();
+        let stored = db
+            .select_measurements(MeasurementQuery {
+                start_ms: first.and_utc().timestamp_millis(),
+                end_ms: last.and_utc().timestamp_millis(),
+                measurement_types: measurement_types.into_iter().collect(),
+                conditions: Vec::new(),
+            })
+            .await?;
+        let stored: HashSet<_> = stored.iter().map(series_reading).collect();
+        let before = batch.len();
+        batch.retain(|row| !stored.contains(&series_reading(row)));
+        summary.rows_already_stored += before - batch.len();
+        if !batch.is_empty() {
+            summary.rows_written += db.append("measurements", rows_to_record_batch(batch)?).await?;
+        }
+        batch.clear();
+        Ok(())
+    }
+}
+
+/// What makes a reading the same as a stored one.
+fn series_reading(row: &NormalizedRow) -> (&str, &str, u8, &str, NaiveDateTime) {
+    (&row.model, &row.sensor_id, row.measurement_type, &row.probe, row.timestamp)
+}
+
+/// Options of the `import` command.
+#[derive(clap::Args)]
+pub struct Args {
+    /// Topic stored with the rows.
+    #[arg(long, default_value = "import-log")]
+    topic: String,
+    /// rtl_433 JSON logs, gzip-compressed when named `.gz`.
+    #[arg(required = true, value_name = "FILE")]
+    paths: Vec<String>,
+}
+
+/// Entry point for the `import` command.
+pub async fn run_cli(args: Args) -> anyhow::Result<()> {
+    let Args { topic, paths } = args;
+
+    let config = Config::load()?;
+    crate::logging::init(&config.logging)?;
+    install_measurement_types(&config.measurements)?;
+    let registry = prometheus::Registry::new();
+    // Every line is an rtl_433 event, whatever `mqtt.formats` says about
+    // the topic.
+    let normalizer = Normalizer::new(
+        &BTreeMap::new(),
+        &config.models,
+        &config.probes,
+        Sentinels::new(&config.sentinels, &registry)?,
+    )?;
+    let dedup = Deduplicator::new(&config.dedup, &registry)?;
+    let importer = LogImporter { normalizer: &normalizer, dedup: &dedup, topic: &topic };
+
+    let (db, _worker) = db::start_db_worker(&config.duckdb, &registry)?;
+    let mut summary = LogImportSummary::default();
+    for path in &paths {
+        let before = summary.rows_written;
+        importer.import(path, &db, &mut summary).await?;
+        eprintln!("{}: done, {} rows written", path, summary.rows_written - before);
+    }
+    db.flush().await?;
+    println!(
+        "Imported {} lines from {} files: {} rows written, {} already stored, {} duplicate events, {} lines skipped",
+        summary.lines,
+        paths.len(),
+        summary.rows_written,
+        summary.rows_already_stored,
+        summary.duplicates,
+        summary.lines_skipped
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DedupConfig;
+    use crate::db::{start_db_worker, DbConfig};
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    const LOG: &str = r#"rtl_433 version 23.11 branch master
+{"time" : "2024-03-01 08:00:00", "model" : "Acurite-Tower", "id" : 1, "temperature_C" : 21.5, "humidity" : 40}
+{"time" : "2024-03-01 08:00:00", "model" : "Acurite-Tower", "id" : 1, "temperature_C" : 21.5, "humidity" : 40}
+
+{"time" : "2024-03-01 08:01:00", "model" : "Acurite-Tower", "id" : 1, "temperature_C" : 21.6}
+{"model" : "Acurite-Tower", "id" : 1, "temperature_C" : 21.7}
+{"time" : "1709280120", "model" : "Acurite-Tower", "id" : 1, "temperature_C" : 21.8}
+"#;
+
+    async fn import(db: &DbHandle, path: &std::path::Path) -> LogImportSummary {
+        let normalizer = Normalizer::new(&BTreeMap::new(), &BTreeMap::new(), &[], Sentinels::default()).unwrap();
+        let dedup = Deduplicator::new(&DedupConfig { window_secs: 2 }, &prometheus::Registry::new()).unwrap();
+        let importer = LogImporter { normalizer: &normalizer, dedup: &dedup, topic: "import-log" };
+        let mut summary = LogImportSummary::default();
+        importer.import(&path.to_string_lossy(), db, &mut summary).await.unwrap();
+        summary
+    }
+
+    #[tokio::test]
+    async fn imports_timed_events_once_from_plain_and_gzip_logs() {
+        let dir = std::env::temp_dir().join(format!("exporter-import-log-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let (db, _worker) = start_db_worker(&config, &prometheus::Registry::new()).unwrap();
+        let plain = dir.join("events.json");
+        std::fs::write(&plain, LOG).unwrap();
+        let gzip = dir.join("events.json.1.gz");
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(LOG.as_bytes()).unwrap();
+        std::fs::write(&gzip, encoder.finish().unwrap()).unwrap();
+
+        // The repeated transmission is dropped by dedup; the banner, the
+        // event without `time` and the one with a unix `time` are skipped.
+        let summary = import(&db, &plain).await;
+        assert_eq!(
+            (summary.lines, summary.rows_written, summary.duplicates, summary.lines_skipped, summary.rows_already_stored),
+            (7, 3, 1, 3, 0)
+        );
+        let everything = MeasurementQuery { start_ms: i64::MIN, end_ms: i64::MAX, measurement_types: Vec::new(), conditions: Vec::new() };
+        let mut stored: Vec<(String, f64)> = db
+            .select_measurements(everything.clone())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|row| (row.timestamp.to_string(), row.value))
+            .collect();
+        stored.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            stored,
+            vec![
+                ("2024-03-01 08:00:00".to_string(), 21.5),
+                ("2024-03-01 08:00:00".to_string(), 40.0),
+                ("2024-03-01 08:01:00".to_string(), 21.6),
+            ]
+        );
+
+        // The same events again, from a rotated and compressed copy.
+        let summary = import(&db, &gzip).await;
+        assert_eq!((summary.lines, summary.rows_written, summary.rows_already_stored), (7, 0, 3));
+        assert_eq!(db.select_measurements(everything).await.unwrap().len(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+// InfluxDB output, for users moving from Telegraf who want to keep their
+// InfluxDB dashboards while both pipelines run side by side. Every
+// normalized reading is written in line protocol to the v2 write API
+// (InfluxDB 2.x, or 3.x / 1.8+ through their v2 compatibility endpoint):
+//
+//   [influx]
+//   url = "http://influxdb:8086"
+//   org = "home"
+//   bucket = "sensors"
+//   measurement = "rtl_433"
+//
+// The token comes from `INFLUX_TOKEN(_FILE)`. The readings of one message
+// become one point: tags `model`, `sensor_id` (and `site` for replicated
+// rows, `probe` for the probes of multi-probe sensors), one float field per measurement type (`temperature_C=21.5`) and
+// the reading's own timestamp in nanoseconds. Queueing, batching and
+// retries work like `remote_write`: points are sent by a background task,
+// 5xx/429 responses and network errors are retried with backoff, other
+// 4xx responses drop the batch, and beyond `max_queue` the oldest queued
+// points are dropped and counted (the same `sink::RetryQueue`).
+use crate::config::InfluxConfig;
+use crate::mqtt_buffer::{measurement_name, NormalizedRow};
+use crate::sink::{unix_now, Readings, RetryQueue, SendError, Sink};
+use prometheus::{Gauge, IntCounter, IntCounterVec, IntGauge, Opts, Registry};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+struct InfluxMetrics {
+    points_sent: IntCounter,
+    points_dropped: IntCounter,
+    requests: IntCounterVec,
+    queue_length: IntGauge,
+    last_success: Gauge,
+}
+
+impl InfluxMetrics {
+    fn new(registry: &Registry) -> anyhow::Result<Self> {
+        let metrics = Self {
+            points_sent: IntCounter::new("influx_points_sent_total", "Points accepted by InfluxDB")?,
+            points_dropped: IntCounter::new(
+                "influx_points_dropped_total",
+                "Points dropped because the queue was full or InfluxDB rejected them",
+            )?,
+            requests: IntCounterVec::new(Opts::new("influx_requests_total", "InfluxDB write requests by result"), &["result"])?,
+            queue_length: IntGauge::new("influx_queue_points", "Points waiting to be written")?,
+            last_success: Gauge::new(
+                "influx_last_success_timestamp_seconds",
+                "Unix time of the last successful InfluxDB write",
+            )?,
+        };
+        registry.register(Box::new(metrics.points_sent.clone()))?;
+        registry.register(Box::new(metrics.points_dropped.clone()))?;
+        registry.register(Box::new(metrics.requests.clone()))?;
+        registry.register(Box::new(metrics.queue_length.clone()))?;
+        registry.register(Box::new(metrics.last_success.clone()))?;
+        Ok(metrics)
+    }
+}
+
+/// Escape `chars` in a measurement, tag or field key or a tag value.
+fn escape(out: &mut String, value: &str, chars: &[char]) {
+    for c in value.chars() {
+        if chars.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+const KEY_CHARS: &[char] = &[',', '=', ' '];
+
+/// Model, probe, sensor id, site and timestamp (ns) of a point.
+type PointKey<'a> = (&'a str, &'a str, &'a str, &'a str, i64);
+
+/// Line protocol for `rows`, one line per sensor and timestamp. Rows of
+/// unknown measurement types and non-finite values are skipped, as
+/// InfluxDB rejects them.
+pub fn lines(measurement: &str, rows: &[NormalizedRow]) -> Vec<String> {
+    let mut points: BTreeMap<PointKey, Vec<(&str, f64)>> = BTreeMap::new();
+    for row in rows {
+        let (Some(field), Some(ns)) = (measurement_name(row.measurement_type), row.timestamp.and_utc().timestamp_nanos_opt())
+        else {
+            continue;
+        };
+        if row.value.is_finite() {
+            points.entry((&row.model, &row.probe, &row.sensor_id, &row.site, ns)).or_default().push((field, row.value));
+        }
+    }
+    points
+        .into_iter()
+        .map(|((model, probe, sensor_id, site, ns), fields)| {
+            let mut line = String::new();
+            escape(&mut line, measurement, &[',', ' ']);
+            // Tags sorted by key, as InfluxDB recommends; empty tag values
+            // are not allowed.
+            for (key, value) in [("model", model), ("probe", probe), ("sensor_id", sensor_id), ("site", site)] {
+                if !value.is_empty() {
+                    line.push(',');
+                    line.push_str(key);
+                    line.push('=');
+                    escape(&mut line, value, KEY_CHARS);
+                }
+            }
+            for (i, (field, value)) in fields.into_iter().enumerate() {
+                line.push(if i == 0 { ' ' } else { ',' });
+                escape(&mut line, field, KEY_CHARS);
+                // `{:?}` keeps the decimal point, so integral values stay
+                // floats instead of clashing with an integer field type.
+                let _ = write!(line, "={:?}", value);
+            }
+            let _ = write!(line, " {}", ns);
+            line
+        })
+        .collect()
+}
+
+/// Queue shared by the MQTT loop (producer) and the sender task.
+pub struct InfluxWriter {
+    config: InfluxConfig,
+    write_url: String,
+    queue: RetryQueue<String>,
+    http: reqwest::Client,
+    metrics: InfluxMetrics,
+}
+
+impl InfluxWriter {
+    /// Build the writer when `influx.url` is configured. The sender task is
+    /// started separately with `run`.
+    pub fn new(config: &InfluxConfig, registry: &Registry) -> anyhow::Result<Option<Self>> {
+        let Some(url) = &config.url else { return Ok(None) };
+        if config.org.is_empty() || config.bucket.is_empty() {
+            anyhow::bail!("influx: org and bucket must be set");
+        }
+        if config.measurement.is_empty() {
+            anyhow::bail!("influx: measurement must not be empty");
+        }
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs.max(1)))
+            .build()?;
+        let metrics = InfluxMetrics::new(registry)?;
+        let queue = RetryQueue::new(
+            config.batch_size,
+            config.max_queue,
+            metrics.queue_length.clone(),
+            metrics.points_dropped.clone(),
+        )
+        .map_err(|e| anyhow::anyhow!("influx: {}", e))?;
+        Ok(Some(Self {
+            config: config.clone(),
+            write_url: format!("{}/api/v2/write", url.trim_end_matches('/')),
+            queue,
+            http,
+            metrics,
+        }))
+    }
+
+    /// Sender loop: every `interval_secs` (or as soon as a batch is full)
+    /// write up to `batch_size` points. A batch that fails with a retryable
+    /// error goes back to the front of the queue and is retried with
+    /// backoff.
+    pub async fn run(self: Arc<Self>) {
+        let interval = Duration::from_secs(self.config.interval_secs.max(1));
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            self.queue.ready(interval).await;
+            loop {
+                let batch = self.queue.take();
+                if batch.is_empty() {
+                    break;
+                }
+                let points = batch.len();
+                match self.send(batch.join("\n")).await {
+                    Ok(()) => {
+                        self.metrics.requests.with_label_values(&["success"]).inc();
+                        self.metrics.points_sent.inc_by(points as u64);
+                        self.metrics.last_success.set(unix_now());
+                        self.queue.sent();
+                        backoff = Duration::from_secs(1);
+                    }
+                    Err(SendError::Rejected(e)) => {
+                        warn!("InfluxDB rejected {} points: {}", points, e);
+                        self.metrics.requests.with_label_values(&["rejected"]).inc();
+                        self.metrics.points_dropped.inc_by(points as u64);
+                        self.queue.sent();
+                    }
+                    Err(SendError::Retry(e)) => {
+                        warn!("InfluxDB write failed, retrying in {:?}: {}", backoff, e);
+                        self.metrics.requests.with_label_values(&["retry"]).inc();
+                        self.queue.retry(batch);
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(Duration::from_secs(self.config.max_backoff_secs.max(1)));
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send(&self, body: String) -> Result<(), SendError> {
+        let mut builder = self
+            .http
+            .post(&self.write_url)
+            .query(&[("org", self.config.org.as_str()), ("bucket", self.config.bucket.as_str()), ("precision", "ns")])
+            .header("content-type", "text/plain; charset=utf-8")
+            .body(body);
+        if let Some(token) = &self.config.token {
+            builder = builder.header("authorization", format!("Token {}", token));
+        }
+        let response = builder.send().await.map_err(|e| SendError::Retry(e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let detail = format!("{}: {}", status, response.text().await.unwrap_or_default().trim());
+        Err(SendError::from_status(status, detail))
+    }
+}
+
+impl Sink for InfluxWriter {
+    fn name(&self) -> &'static str {
+        "influx"
+    }
+
+    /// Queue the readings' points. Never blocks; drops the oldest points
+    /// when the queue is full.
+    fn push(&self, readings: &Readings) {
+        let lines = lines(&self.config.measurement, readings.rows);
+        if !lines.is_empty() {
+            self.queue.push(lines);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt_buffer::measurement_code;
+    use chrono::NaiveDate;
+
+    fn row(model: &str, sensor_id: &str, probe: &str, key: &str, value: f64) -> NormalizedRow {
+        NormalizedRow {
+            timestamp: NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_opt(8, 0, 0).unwrap(),
+            sensor_id: sensor_id.to_string(),
+            model: model.to_string(),
+            measurement_type: measurement_code(key).unwrap(),
+            value,
+            topic: "rtl_433/events".to_string(),
+            seq: 0,
+            site: String::new(),
+            probe: probe.to_string(),
+        }
+    }
+
+    #[test]
+    fn readings_of_a_message_become_one_point() {
+        let mut replicated = row("Acurite-Tower", "1", "", "humidity", 40.0);
+        replicated.site = "cabin".to_string();
+        let rows = [
+            row("Acurite-Tower", "1", "", "temperature_C", 21.5),
+            row("Acurite-Tower", "1", "", "humidity", 40.0),
+            row("Acurite-Tower", "1", "", "pressure_kPa", f64::NAN),
+            replicated,
+            row("Inkbird ITH-20R", "a=b,c", "2", "temperature_C", -3.0),
+        ];
+        assert_eq!(
+            lines("rtl 433,x", &rows),
+            [
+                "rtl\\ 433\\,x,model=Acurite-Tower,sensor_id=1 temperature_C=21.5,humidity=40.0 1709280000000000000",
+                "rtl\\ 433\\,x,model=Acurite-Tower,sensor_id=1,site=cabin humidity=40.0 1709280000000000000",
+                "rtl\\ 433\\,x,model=Inkbird\\ ITH-20R,probe=2,sensor_id=a\\=b\\,c temperature_C=-3.0 1709280000000000000",
+            ]
+        );
+    }
+
+    #[test]
+    fn non_finite_values_and_unknown_types_are_skipped() {
+        let mut unknown = row("Acurite-Tower", "1", "", "humidity", 40.0);
+        unknown.measurement_type = u8::MAX;
+        let rows = [row("Acurite-Tower", "1", "", "temperature_C", f64::INFINITY), unknown];
+        assert!(lines("rtl_433", &rows).is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+// Background query jobs for heavy analytical exports. A request that would
+// run longer than an HTTP client or proxy is willing to wait is submitted
+// with `POST /api/jobs/query` instead; it answers `202 Accepted` with a job
+// id right away, the DB worker writes the result to a file in `jobs.dir`,
+// and the client polls `GET /api/jobs/{id}` until the job is `done` (or
+// `failed`), then fetches the file from the job's `download` link:
+//
+//   [jobs]
+//   dir = "exports"
+//   keep_hours = 24
+//   max_pending = 4
+//
+// Results have the columns of the `measurements` table, as Parquet or CSV.
+// Jobs live in memory only: finished jobs and their files are removed
+// after `keep_hours`, and files left behind by a previous run are deleted
+// at startup. Ids are random, so a job is only reachable by its submitter.
+use crate::config::JobsConfig;
+use crate::db::{DbHandle, ExportFormat, MeasurementQuery, QueryExport};
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn, Instrument};
+
+/// Prefix of result files, so cleanup never touches anything else.
+const FILE_PREFIX: &str = "job-";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting for or running on the DB worker.
+    Pending,
+    Done,
+    Failed,
+}
+
+/// A query job as reported by `GET /api/jobs/{id}`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    pub format: ExportFormat,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Where to fetch the result once the job is done.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<String>,
+    /// The request that submitted the job, see `request_id`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+pub struct Jobs {
+    dir: PathBuf,
+    keep: chrono::Duration,
+    max_pending: usize,
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl Jobs {
+    pub fn new(config: &JobsConfig) -> Self {
+        Self {
+            dir: PathBuf::from(&config.dir),
+            keep: chrono::Duration::hours(config.keep_hours.min(i64::MAX as u64) as i64),
+            max_pending: config.max_pending.max(1),
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Queue an export of `selection` on the DB worker. Fails when
+    /// `max_pending` jobs are already waiting.
+    pub fn submit(self: &Arc<Self>, db: DbHandle, selection: MeasurementQuery, format: ExportFormat) -> anyhow::Result<Job> {
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        let id: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let path = self.dir.join(format!("{}{}.{}", FILE_PREFIX, id, format.extension()));
+        let job = Job {
+            id: id.clone(),
+            status: JobStatus::Pending,
+            format,
+            created_at: Utc::now(),
+            finished_at: None,
+            rows: None,
+            error: None,
+            download: None,
+            request_id: crate::request_id::current(),
+            path: path.clone(),
+        };
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            let pending = jobs.values().filter(|j| j.status == JobStatus::Pending).count();
+            if pending >= self.max_pending {
+                return Err(anyhow::anyhow!("{} query jobs are already pending; retry later", pending));
+            }
+            jobs.insert(id.clone(), job.clone());
+        }
+
+        let jobs = self.clone();
+        // The job runs with its request's id and in its span, so its DB
+        // command and log lines can be traced back to the submission.
+        let request_id = job.request_id.clone();
+        let work = async move {
+            let result = match tokio::fs::create_dir_all(&jobs.dir).await {
+                Ok(()) => {
+                    let export = QueryExport { selection, path: path.to_string_lossy().into_owned(), format };
+                    db.export_query(export).await
+                }
+                Err(e) => Err(anyhow::anyhow!("cannot create {}: {}", jobs.dir.display(), e)),
+            };
+            let mut all = jobs.jobs.lock().unwrap();
+            let Some(job) = all.get_mut(&id) else { return };
+            job.finished_at = Some(Utc::now());
+            match result {
+                Ok(rows) => {
+                    info!("Query job {} wrote {} rows to {}", id, rows, path.display());
+                    job.status = JobStatus::Done;
+                    job.rows = Some(rows);
+                    job.download = Some(format!("/api/jobs/{}/download", id));
+                }
+                Err(e) => {
+                    warn!("Query job {} failed: {}", id, e);
+                    job.status = JobStatus::Failed;
+                    job.error = Some(e.to_string());
+                }
+            }
+        };
+        tokio::spawn(crate::request_id::scope(request_id, work).in_current_span());
+        Ok(job)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    /// Forget finished jobs older than `keep_hours` and delete their files.
+    fn expire(&self) {
+        let cutoff = Utc::now() - self.keep;
+        let expired: Vec<Job> = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let ids: Vec<String> = jobs
+                .values()
+                .filter(|j| j.finished_at.is_some_and(|t| t < cutoff))
+                .map(|j| j.id.clone())
+                .collect();
+            ids.iter().filter_map(|id| jobs.remove(id)).collect()
+        };
+        for job in expired {
+            if let Err(e) = std::fs::remove_file(&job.path)
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                warn!("Cannot remove expired job file {}: {}", job.path.display(), e);
+            }
+        }
+    }
+
+    /// Delete result files of a previous run; their jobs are gone.
+    fn remove_orphans(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else { return };
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with(FILE_PREFIX)
+                && let Err(e) = std::fs::remove_file(entry.path())
+            {
+                warn!("Cannot remove old job file {}: {}", entry.path().display(), e);
+            }
+        }
+    }
+}
+
+/// Remove old result files at startup, then expire finished jobs every ten
+/// minutes.
+pub async fn run(jobs: Arc<Jobs>) {
+    jobs.remove_orphans();
+    let mut interval = tokio::time::interval(Duration::from_secs(600));
+    loop {
+        interval.tick().await;
+        jobs.expire();
+    }
+}
@@ -0,0 +1,72 @@
+// `lib.rs` exposes the exporter as a library so other binaries can embed
+// the ingestion pipeline (the normalizers in `mqtt_buffer` and
+// `zigbee2mqtt`, the DuckDB worker in `db`, the MQTT loop in `mqtt`) or
+// start the whole service with `server::run_with()` and a `config::Config`
+// built in code instead of read from the environment, e.g. from
+// integration tests. Each responsibility lives in its own module under
+// `src/`, declared below, so it is isolated and easier to navigate / test.
+pub mod config;
+pub mod state;
+pub mod handlers;
+pub mod problem;
+pub mod mqtt;
+pub mod mqtt_buffer;
+pub mod zigbee2mqtt;
+pub mod models;
+pub mod sentinels;
+pub mod probes;
+pub mod dedup;
+pub mod rate_limit;
+pub mod enrichment;
+pub mod flush_timer;
+pub mod health;
+pub mod units;
+pub mod metrics;
+pub mod counters;
+pub mod db;
+pub mod archive;
+pub mod auth;
+pub mod crypto;
+pub mod composite;
+pub mod control;
+pub mod cli;
+pub mod check_config;
+pub mod import;
+pub mod import_log;
+pub mod export;
+pub mod query;
+pub mod schema;
+pub mod tail;
+pub mod selftest;
+pub mod remote_read;
+pub mod remote_write;
+pub mod request_id;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod grafana;
+pub mod influx;
+pub mod sink;
+pub mod live;
+pub mod sampling;
+pub mod report;
+pub mod retention;
+pub mod rollup;
+pub mod states;
+pub mod weather;
+pub mod radio;
+pub mod degree_days;
+pub mod jobs;
+pub mod sync;
+pub mod aggregator;
+pub mod upload;
+pub mod logging;
+pub mod serve;
+pub mod shutdown;
+pub mod spool;
+pub mod warm;
+pub mod wal;
+pub mod reload;
+pub mod strict_start;
+pub mod status;
+pub mod version;
+pub mod server;
@@ -0,0 +1,267 @@
+// Live feed of the ingested readings. `GET /api/stream` is a Server-Sent
+// Events stream of every reading as it is ingested, the rows the sinks get
+// (after dedup, in the units of the sensor's mapping), one `reading` event
+// per row in the `/api/sync` JSON format:
+//
+//   curl -N 'http://localhost:3000/api/stream?sensor_id=19&measurement=temperature'
+//
+// `sensor_id`, `model` and `measurement` narrow the feed; `measurement`
+// matches a key (`temperature_C`) or the part before its unit suffix
+// (`temperature`). A client that reads too slowly misses readings and gets
+// a `lagged` event with how many. The feed costs nothing while nobody is
+// subscribed. Streams end at shutdown, before HTTP stops. `tail` prints the
+// feed in a terminal, see `tail`.
+//
+// Clients without an SSE parser ask for newline-delimited JSON instead,
+// with `Accept: application/x-ndjson` or `?format=ndjson`:
+//
+//   curl -sN --compressed 'http://localhost:3000/api/stream?format=ndjson' | jq .value
+//
+// Every line is a reading, or `{"lagged":<missed>}`; an empty line is sent
+// every 15 seconds while nothing else is, so proxies keep the connection and
+// clients notice when it is gone. With `Accept-Encoding: gzip` the stream
+// is compressed, flushed after every line so readings aren't held back.
+use crate::handlers::MeasurementRow;
+use crate::mqtt_buffer::measurement_types;
+use crate::problem::Problem;
+use crate::sink::{Readings, Sink};
+use axum::body::{Body, Bytes};
+use axum::extract::{Extension, Query};
+use axum::http::{header, HeaderMap};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use flate2::write::GzEncoder;
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Readings a subscriber may fall behind by before it misses some.
+const LIVE_CAPACITY: usize = 1024;
+
+/// Idle time after which an NDJSON stream sends an empty line.
+const HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// One ingested reading, serialized once for every subscriber.
+pub struct LiveReading {
+    pub model: String,
+    pub sensor_id: String,
+    pub measurement_type: u8,
+    pub json: String,
+}
+
+pub struct LiveFeed {
+    /// Taken at shutdown, which ends the streams.
+    sender: Mutex<Option<broadcast::Sender<Arc<LiveReading>>>>,
+}
+
+impl Default for LiveFeed {
+    fn default() -> Self {
+        Self { sender: Mutex::new(Some(broadcast::channel(LIVE_CAPACITY).0)) }
+    }
+}
+
+impl LiveFeed {
+    fn subscribe(&self) -> Option<broadcast::Receiver<Arc<LiveReading>>> {
+        self.sender.lock().unwrap().as_ref().map(broadcast::Sender::subscribe)
+    }
+
+    /// End every stream once it has sent what it was given.
+    pub fn close(&self) {
+        self.sender.lock().unwrap().take();
+    }
+}
+
+impl Sink for LiveFeed {
+    fn name(&self) -> &'static str {
+        "live"
+    }
+
+    fn push(&self, readings: &Readings) {
+        let sender = self.sender.lock().unwrap();
+        let Some(sender) = sender.as_ref().filter(|s| s.receiver_count() > 0) else { return };
+        for row in readings.rows {
+            let json = serde_json::to_string(&MeasurementRow::with_unit(row.clone(), readings.mappings)).unwrap_or_default();
+            let reading = LiveReading {
+                model: row.model.clone(),
+                sensor_id: row.sensor_id.clone(),
+                measurement_type: row.measurement_type,
+                json,
+            };
+            let _ = sender.send(Arc::new(reading));
+        }
+    }
+}
+
+/// Query parameters for `GET /api/stream`.
+#[derive(Default, Deserialize)]
+pub struct StreamParams {
+    pub sensor_id: Option<String>,
+    pub model: Option<String>,
+    /// A measurement key, or the part of keys before their unit suffix.
+    pub measurement: Option<String>,
+    /// `ndjson` for newline-delimited JSON instead of Server-Sent Events.
+    pub format: Option<String>,
+}
+
+/// Which readings a stream sends.
+pub struct StreamFilter {
+    sensor_id: Option<String>,
+    model: Option<String>,
+    measurement_types: Option<HashSet<u8>>,
+}
+
+impl StreamFilter {
+    pub fn new(params: StreamParams) -> Result<Self, Problem> {
+        let measurement_types = match params.measurement.as_deref() {
+            None => None,
+            Some(name) => {
+                let prefix = format!("{}_", name);
+                let codes: HashSet<u8> = measurement_types()
+                    .iter()
+                    .filter(|t| t.key == name || t.key.starts_with(&prefix))
+                    .map(|t| t.code)
+                    .collect();
+                if codes.is_empty() {
+                    return Err(Problem::invalid(format!("unknown measurement {}", name)));
+                }
+                Some(codes)
+            }
+        };
+        Ok(Self { sensor_id: params.sensor_id, model: params.model, measurement_types })
+    }
+
+    pub fn matches(&self, reading: &LiveReading) -> bool {
+        self.sensor_id.as_ref().is_none_or(|id| *id == reading.sensor_id)
+            && self.model.as_ref().is_none_or(|model| *model == reading.model)
+            && self.measurement_types.as_ref().is_none_or(|codes| codes.contains(&reading.measurement_type))
+    }
+}
+
+/// A live item of a stream: a matching reading, or how many readings a slow
+/// subscriber missed.
+pub enum LiveItem {
+    Reading(Arc<LiveReading>),
+    Lagged(u64),
+}
+
+/// The feed's items that pass `filter`, ending with the feed.
+pub fn subscribe(feed: &LiveFeed, filter: StreamFilter) -> impl Stream<Item = LiveItem> + Send + use<> {
+    futures_util::stream::unfold((feed.subscribe(), filter), |(mut receiver, filter)| async move {
+        let rx = receiver.as_mut()?;
+        loop {
+            match rx.recv().await {
+                Ok(reading) if filter.matches(&reading) => return Some((LiveItem::Reading(reading), (receiver, filter))),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => return Some((LiveItem::Lagged(missed), (receiver, filter))),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// `GET /api/stream`: the live readings as Server-Sent Events, or as NDJSON
+/// when the client asks for it.
+pub async fn stream_handler(
+    Extension(feed): Extension<Arc<LiveFeed>>,
+    Query(mut params): Query<StreamParams>,
+    headers: HeaderMap,
+) -> Result<Response, Problem> {
+    let ndjson = match params.format.take().as_deref() {
+        None => header_contains(&headers, header::ACCEPT, "application/x-ndjson"),
+        Some("ndjson") => true,
+        Some("sse") => false,
+        Some(other) => return Err(Problem::invalid(format!("unknown format {}, expected sse or ndjson", other))),
+    };
+    let filter = StreamFilter::new(params)?;
+    let items = subscribe(&feed, filter);
+    if ndjson {
+        let gzip = header_contains(&headers, header::ACCEPT_ENCODING, "gzip");
+        return Ok(ndjson_response(items, gzip));
+    }
+    let events = items.map(|item| {
+        Ok::<_, Infallible>(match item {
+            LiveItem::Reading(reading) => Event::default().event("reading").data(reading.json.as_str()),
+            LiveItem::Lagged(missed) => Event::default().event("lagged").data(format!("{{\"missed\":{}}}", missed)),
+        })
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()).into_response())
+}
+
+/// Whether the comma-separated values of `name` include `value` (without
+/// `q=0`).
+fn header_contains(headers: &HeaderMap, name: header::HeaderName, value: &str) -> bool {
+    headers.get_all(name).iter().filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')).any(|entry| {
+        let mut parts = entry.split(';').map(str::trim);
+        parts.next().is_some_and(|v| v.eq_ignore_ascii_case(value)) && !parts.any(|p| p.replace(' ', "") == "q=0")
+    })
+}
+
+struct NdjsonStream {
+    items: Pin<Box<dyn Stream<Item = LiveItem> + Send>>,
+    heartbeat: tokio::time::Interval,
+    /// Set with `Accept-Encoding: gzip`; taken to finish the stream.
+    gzip: Option<GzEncoder<Vec<u8>>>,
+    done: bool,
+}
+
+fn ndjson_response(items: impl Stream<Item = LiveItem> + Send + 'static, gzip: bool) -> Response {
+    let start = tokio::time::Instant::now() + HEARTBEAT;
+    let mut heartbeat = tokio::time::interval_at(start, HEARTBEAT);
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let state = NdjsonStream {
+        items: Box::pin(items),
+        heartbeat,
+        gzip: gzip.then(|| GzEncoder::new(Vec::new(), flate2::Compression::default())),
+        done: false,
+    };
+    let body = futures_util::stream::unfold(state, |mut s| async move {
+        if s.done {
+            return None;
+        }
+        let line = tokio::select! {
+            item = s.items.next() => match item {
+                Some(LiveItem::Reading(reading)) => Some(format!("{}\n", reading.json)),
+                Some(LiveItem::Lagged(missed)) => Some(format!("{{\"lagged\":{}}}\n", missed)),
+                None => None,
+            },
+            _ = s.heartbeat.tick() => Some("\n".to_string()),
+        };
+        if line.is_some() {
+            s.heartbeat.reset();
+        }
+        let bytes = match (line, s.gzip.as_mut()) {
+            (Some(line), None) => Bytes::from(line),
+            (None, None) => return None,
+            // A sync flush after every line, so the client can decompress
+            // it right away.
+            (Some(line), Some(gzip)) => match gzip.write_all(line.as_bytes()).and_then(|_| gzip.flush()) {
+                Ok(()) => Bytes::from(std::mem::take(gzip.get_mut())),
+                Err(e) => return Some((Err(e), s)),
+            },
+            (None, Some(_)) => {
+                s.done = true;
+                match s.gzip.take().map(GzEncoder::finish) {
+                    Some(Ok(trailer)) => Bytes::from(trailer),
+                    Some(Err(e)) => return Some((Err(e), s)),
+                    None => return None,
+                }
+            }
+        };
+        Some((Ok(bytes), s))
+    });
+    let mut response = Body::from_stream(body).into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/x-ndjson"));
+    headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-cache"));
+    headers.insert(header::VARY, header::HeaderValue::from_static("accept, accept-encoding"));
+    if gzip {
+        headers.insert(header::CONTENT_ENCODING, header::HeaderValue::from_static("gzip"));
+    }
+    response
+}
@@ -0,0 +1,187 @@
+// Logging. Everything is logged through `tracing`; `init` installs the
+// subscriber with the `logging.level` filter (`RUST_LOG` syntax, the
+// environment variable wins) and either human-readable or JSON output
+// (`logging.format`) on stdout, or on stderr when the JSONL sink writes
+// readings to stdout (see `sink`). The filter can be replaced at runtime through
+// `PUT /api/admin/log-filter`, e.g. to turn on the per-message `ingest`
+// debug spans of one module while troubleshooting, and back off again.
+// With the `otel` feature, `init_traced` also exports spans over OTLP; the
+// filter only applies to the log output, see `otel`.
+//
+// Warnings emitted per MQTT message are rate limited. Under burst traffic a
+// line per message becomes a bottleneck of its own and floods journald, so
+// such lines go through a `LogLimiter`: per kind of event only
+// the first `logging.burst` lines of each `logging.window_secs` window are
+// printed. The first line printed after a window in which lines were
+// suppressed carries a note like
+// `(logged 10 of 500 similar events in the last 60s)`.
+//
+// Use the `warn_limited!` macro, which only formats the message when it is
+// actually logged.
+use crate::config::{LogFormat, LoggingConfig};
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+#[cfg(feature = "otel")]
+use tracing::Subscriber;
+#[cfg(not(feature = "otel"))]
+use tracing_subscriber::layer::Identity;
+#[cfg(feature = "otel")]
+use tracing_subscriber::{registry::LookupSpan, Layer};
+use tracing_subscriber::{fmt, prelude::*, reload, EnvFilter, Registry};
+
+/// Handle to the active log filter, for changing it at runtime.
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    directives: Mutex<String>,
+}
+
+impl LogFilter {
+    /// The filter directives currently in effect.
+    pub fn current(&self) -> String {
+        self.directives.lock().unwrap().clone()
+    }
+
+    /// Replace the filter; invalid directives leave the current one active.
+    pub fn set(&self, directives: &str) -> anyhow::Result<()> {
+        let filter = EnvFilter::try_new(directives).map_err(|e| anyhow::anyhow!("invalid log filter {:?}: {}", directives, e))?;
+        self.handle.reload(filter)?;
+        *self.directives.lock().unwrap() = directives.to_string();
+        Ok(())
+    }
+}
+
+/// Install the global `tracing` subscriber. Call once, right after the
+/// configuration is loaded.
+pub fn init(config: &LoggingConfig) -> anyhow::Result<LogFilter> {
+    install(config, None)
+}
+
+/// `init`, also exporting spans with `tracer`, see `otel`. The log filter
+/// doesn't apply to them.
+#[cfg(feature = "otel")]
+pub fn init_traced(config: &LoggingConfig, tracer: Option<Tracer>) -> anyhow::Result<LogFilter> {
+    install(config, tracer)
+}
+
+#[cfg(feature = "otel")]
+type Tracer = opentelemetry_sdk::trace::SdkTracer;
+#[cfg(not(feature = "otel"))]
+type Tracer = std::convert::Infallible;
+
+#[cfg(feature = "otel")]
+fn traces<S: Subscriber + for<'a> LookupSpan<'a>>(tracer: Option<Tracer>) -> Option<impl Layer<S>> {
+    tracer.map(crate::otel::layer)
+}
+
+#[cfg(not(feature = "otel"))]
+fn traces(_: Option<Tracer>) -> Option<Identity> {
+    None
+}
+
+fn install(config: &LoggingConfig, tracer: Option<Tracer>) -> anyhow::Result<LogFilter> {
+    let filter = EnvFilter::try_new(&config.level)
+        .map_err(|e| anyhow::anyhow!("invalid log filter {:?}: {}", config.level, e))?;
+    // A filter of the log output only, so traces can have spans it leaves out.
+    let (filter, handle) = reload::Layer::new(filter);
+    let registry = tracing_subscriber::registry();
+    match (config.format, config.stderr) {
+        // No colour codes when the log goes to journald or a file.
+        (LogFormat::Text, false) => registry
+            .with(fmt::layer().with_ansi(std::io::stdout().is_terminal()).with_filter(filter))
+            .with(traces(tracer))
+            .try_init()?,
+        (LogFormat::Text, true) => registry
+            .with(fmt::layer().with_ansi(std::io::stderr().is_terminal()).with_writer(std::io::stderr).with_filter(filter))
+            .with(traces(tracer))
+            .try_init()?,
+        (LogFormat::Json, false) => registry
+            .with(fmt::layer().json().with_current_span(true).with_span_list(false).with_filter(filter))
+            .with(traces(tracer))
+            .try_init()?,
+        (LogFormat::Json, true) => registry
+            .with(
+                fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(false)
+                    .with_writer(std::io::stderr)
+                    .with_filter(filter),
+            )
+            .with(traces(tracer))
+            .try_init()?,
+    }
+    Ok(LogFilter { handle, directives: Mutex::new(config.level.clone()) })
+}
+
+/// A filter for an embedding binary that installs its own subscriber. It
+/// only reports the configured level; changing it at runtime fails.
+pub fn detached(config: &LoggingConfig) -> anyhow::Result<LogFilter> {
+    let filter = EnvFilter::try_new(&config.level)
+        .map_err(|e| anyhow::anyhow!("invalid log filter {:?}: {}", config.level, e))?;
+    let (_layer, handle) = reload::Layer::<EnvFilter, Registry>::new(filter);
+    Ok(LogFilter { handle, directives: Mutex::new(config.level.clone()) })
+}
+
+pub struct LogLimiter {
+    burst: u64,
+    window: Duration,
+    windows: Mutex<HashMap<&'static str, Window>>,
+}
+
+struct Window {
+    started: Instant,
+    seen: u64,
+    /// Note for the next printed line about the previous window.
+    pending_note: Option<String>,
+}
+
+impl LogLimiter {
+    pub fn new(config: &LoggingConfig) -> Self {
+        Self {
+            burst: config.burst,
+            window: Duration::from_secs(config.window_secs.max(1)),
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count an event of `kind`. Returns the suffix to append when the line
+    /// should be printed (usually empty), `None` when it is suppressed.
+    pub fn check(&self, kind: &'static str) -> Option<String> {
+        if self.burst == 0 {
+            return Some(String::new());
+        }
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(kind).or_insert(Window { started: now, seen: 0, pending_note: None });
+        if now.duration_since(window.started) >= self.window {
+            if window.seen > self.burst {
+                window.pending_note = Some(format!(
+                    " (logged {} of {} similar events in the last {}s)",
+                    self.burst,
+                    window.seen,
+                    self.window.as_secs()
+                ));
+            }
+            window.started = now;
+            window.seen = 0;
+        }
+        window.seen += 1;
+        if window.seen > self.burst {
+            return None;
+        }
+        Some(window.pending_note.take().unwrap_or_default())
+    }
+}
+
+/// `tracing::warn!` through a `LogLimiter`: `warn_limited!(limiter, "kind", "fmt", args..)`.
+macro_rules! warn_limited {
+    ($limiter:expr, $kind:expr, $($arg:tt)*) => {
+        if let Some(note) = $limiter.check($kind) {
+            tracing::warn!("{}{}", format_args!($($arg)*), note);
+        }
+    };
+}
+
+pub(crate) use warn_limited;
@@ -1,15 +1,16 @@
-// `main.rs` is intentionally tiny: it only declares modules and delegates
-// execution to `server::run()`. The real implementation lives in the
-// `server`, `state`, `handlers`, and `mqtt` modules under `src/` so each
-// responsibility is isolated and easier to navigate / test.
-mod state;
-mod handlers;
-mod mqtt;
-mod server;
+// `main.rs` is intentionally tiny: it hands the command line to
+// `cli::run()`, which runs the service (`server::run()`) or a maintenance
+// command such as `import`, `import-csv`, `export`, `query`, `schema`,
+// `tail`, `selftest` or `check-config`. Everything else lives in the
+// library crate, see `lib.rs`.
+use rust_to_mqtt_prometheus_exporter::cli;
 
-/// Start the service. Keep `main` minimal so hot-reloads, tests, and
-/// integration points can import `server::run()` directly if needed.
+/// Start the service. Keep `main` minimal; embedders and tests call
+/// `server::run_with()` from the library instead.
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    server::run().await
+    // Both rustls crypto backends end up enabled through dependencies, so
+    // pick one explicitly before any TLS connection (MQTT, S3) is made.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    cli::run().await
 }
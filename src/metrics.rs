@@ -0,0 +1,615 @@
+// Per-sensor Prometheus gauges. The MQTT loop publishes every normalized
+// reading as a gauge named after its measurement type (see `metric_name`),
+// labelled with the sensor identity, the `site` it was replicated from in
+// aggregator mode and the `probe` of a multi-probe sensor (both empty, and
+// therefore dropped by Prometheus, for local readings and the sensor's own
+// readings) and, when a `Mapping` exists for the sensor, its friendly name
+// as `location`, followed by the labels of `enrichment`. The gauges live in
+// the shared registry served on `/metrics`. Values can be rounded per
+// measurement type (`metrics.rounding`) before they are set; stored rows are
+// unaffected. Each series keeps its gauge handle, so a message only looks up
+// label values when a series is new or its labels changed.
+//
+// A gauge shows the last reading of its series between transmissions, so
+// scrapes more frequent than the sensors' interval see no gaps. With a
+// maximum age a series that got no reading for that long is removed, so a
+// sensor that died or moved away doesn't linger with its last value:
+//
+//   [metrics]
+//   max_age_secs = 900             # 0 (default) keeps series
+//
+//   [metrics.max_age]
+//   battery_ok = 86400             # per measurement key
+//
+// Expiry is checked every 10 seconds and counted in
+// `sensor_gauge_series_expired_total`; the next reading of the sensor
+// brings the series back.
+//
+// Next to them live the exporter's own health metrics: per-source ingestion
+// status, per-sensor freshness and HTTP request latencies. Mapped sensors
+// with an `expected_interval_secs` also get
+// `sensor_expected_messages_total` (growing by one per interval) and
+// `sensor_received_messages_total` (messages after dedup), so
+// `1 - rate(received) / rate(expected)` shows their packet loss.
+use crate::config::MetricsConfig;
+use crate::enrichment::Enrichment;
+use crate::mqtt_buffer::{measurement_code, measurement_types, metric_name, NormalizedRow};
+use crate::sink::{Readings, Sink};
+use crate::state::{mapping_for, Mapping, ReadingKey, SensorKey};
+use axum::{
+    body::Body,
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use prometheus::{CounterVec, Gauge, GaugeVec, IntCounter, IntGauge, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub struct SensorGauges {
+    gauges: HashMap<u8, GaugeVec>,
+    /// The row each series was last published with, its `location` and
+    /// enrichment label values and its gauge handle. When a mapping is renamed or a label changes
+    /// the old series is removed instead of lingering next to the new one
+    /// with a stale value.
+    series: Mutex<Series>,
+    enrichment: Arc<Enrichment>,
+    /// Decimal places per measurement type; absent types are exported as is.
+    rounding: HashMap<u8, i32>,
+    /// Maximum age per measurement type; absent types are kept.
+    max_age: HashMap<u8, Duration>,
+    expired: IntCounter,
+}
+
+/// The published series by site and reading, and the key their lookups
+/// are built in, so a message of a known series allocates nothing.
+#[derive(Default)]
+struct Series {
+    published: HashMap<(String, ReadingKey), Published>,
+    key: (String, ReadingKey),
+}
+
+struct Published {
+    labels: Vec<String>,
+    /// The series' child of its gauge vector.
+    gauge: Gauge,
+    row: NormalizedRow,
+    /// When the series got its reading, for `max_age`.
+    updated: SystemTime,
+}
+
+impl SensorGauges {
+    /// Create one gauge vector per known measurement type in `registry`.
+    /// Fails on rounding or max age rules for unknown measurement keys.
+    pub fn new(registry: &Registry, config: &MetricsConfig, enrichment: Arc<Enrichment>) -> anyhow::Result<Self> {
+        let mut rounding = HashMap::new();
+        for (key, places) in &config.rounding {
+            let code = measurement_code(key)
+                .ok_or_else(|| anyhow::anyhow!("unknown measurement key in metrics.rounding: {}", key))?;
+            rounding.insert(code, (*places).min(15) as i32);
+        }
+        let mut max_age = HashMap::new();
+        for t in measurement_types() {
+            let secs = config.max_age.get(&t.key).copied().unwrap_or(config.max_age_secs);
+            if secs > 0 {
+                max_age.insert(t.code, Duration::from_secs(secs));
+            }
+        }
+        if let Some(key) = config.max_age.keys().find(|key| measurement_code(key).is_none()) {
+            anyhow::bail!("unknown measurement key in metrics.max_age: {}", key);
+        }
+        let expired = IntCounter::new(
+            "sensor_gauge_series_expired_total",
+            "Gauge series removed because their sensor sent no reading within the maximum age",
+        )?;
+        registry.register(Box::new(expired.clone()))?;
+        let mut labels = vec!["sensor_id", "model", "site", "probe", "location"];
+        labels.extend(enrichment.names().iter().map(String::as_str));
+        let mut gauges = HashMap::new();
+        for t in measurement_types() {
+            let gauge = GaugeVec::new(Opts::new(&t.metric, format!("Latest {} reading per sensor", t.key)), &labels)?;
+            registry.register(Box::new(gauge.clone()))?;
+            gauges.insert(t.code, gauge);
+        }
+        Ok(Self { gauges, series: Mutex::new(Series::default()), enrichment, rounding, max_age, expired })
+    }
+
+    /// Set the gauges for `rows`, resolving `location` through `mappings`.
+    /// Sensors without a mapping get an empty `location`.
+    pub fn observe(&self, rows: &[NormalizedRow], mappings: &HashMap<String, Mapping>) {
+        self.publish(rows, mappings, |_| SystemTime::now());
+    }
+
+    /// `observe` for rows saved at the last shutdown (see `warm`): their age
+    /// counts from their own timestamp.
+    pub fn restore(&self, rows: &[NormalizedRow], mappings: &HashMap<String, Mapping>) {
+        self.publish(rows, mappings, |row| SystemTime::from(row.timestamp.and_utc()));
+    }
+
+    fn publish(&self, rows: &[NormalizedRow], mappings: &HashMap<String, Mapping>, updated: impl Fn(&NormalizedRow) -> SystemTime) {
+        let mut series = self.series.lock().unwrap();
+        let Series { published, key } = &mut *series;
+        // The readings of a message share their sensor, and its labels.
+        let mut sensor: Option<(&NormalizedRow, &str, Arc<[String]>)> = None;
+        for row in rows {
+            let Some(gauge) = self.gauges.get(&row.measurement_type) else {
+                continue;
+            };
+            let (location, enriched) = match &sensor {
+                Some((first, location, enriched)) if first.sensor_id == row.sensor_id && first.model == row.model => (*location, enriched.clone()),
+                _ => {
+                    let location = mapping_for(mappings, &row.sensor_id, &row.model).map_or("", |m| m.name.as_str());
+                    let enriched = self.enrichment.values(&row.model, &row.sensor_id);
+                    sensor = Some((row, location, enriched.clone()));
+                    (location, enriched)
+                }
+            };
+            key.0.clone_from(&row.site);
+            key.1.model.clone_from(&row.model);
+            key.1.sensor_id.clone_from(&row.sensor_id);
+            key.1.measurement_type = row.measurement_type;
+            key.1.probe.clone_from(&row.probe);
+            // The common case: the series exists with the same labels, and
+            // its cached handle is set without hashing the label values.
+            if let Some(series) = published.get_mut(&*key)
+                && series.labels[0] == location
+                && series.labels[1..] == enriched[..]
+            {
+                series.gauge.set(self.rounded(row));
+                // Only these differ from the key's fields.
+                series.row.timestamp = row.timestamp;
+                series.row.value = row.value;
+                series.row.seq = row.seq;
+                series.row.topic.clone_from(&row.topic);
+                series.updated = updated(row);
+                continue;
+            }
+            if let Some(previous) = published.get(&*key) {
+                let _ = gauge.remove_label_values(&series_labels(row, &previous.labels));
+            }
+            let mut labels = vec![location.to_string()];
+            labels.extend(enriched.iter().cloned());
+            let handle = gauge.with_label_values(&series_labels(row, &labels));
+            handle.set(self.rounded(row));
+            published.insert(key.clone(), Published { labels, gauge: handle, row: row.clone(), updated: updated(row) });
+        }
+    }
+
+    fn rounded(&self, row: &NormalizedRow) -> f64 {
+        match self.rounding.get(&row.measurement_type) {
+            Some(places) => {
+                let scale = 10f64.powi(*places);
+                (row.value * scale).round() / scale
+            }
+            None => row.value,
+        }
+    }
+
+    /// Remove a local sensor's series, either all of them or only the given
+    /// measurement type. Returns how many series were removed.
+    pub fn remove_sensor(&self, model: &str, sensor_id: &str, measurement_type: Option<u8>) -> usize {
+        self.remove_readings(model, sensor_id, measurement_type, None, None)
+    }
+
+    /// `remove_sensor` for the series whose shown reading was taken from
+    /// `from_ms` (inclusive) to `before_ms` (exclusive), epoch milliseconds,
+    /// after a purge deleted it. Returns how many series were removed.
+    pub fn remove_readings(
+        &self,
+        model: &str,
+        sensor_id: &str,
+        measurement_type: Option<u8>,
+        from_ms: Option<i64>,
+        before_ms: Option<i64>,
+    ) -> usize {
+        let published = &mut self.series.lock().unwrap().published;
+        let mut removed = 0;
+        published.retain(|(site, key), series| {
+            let taken = series.row.timestamp.and_utc().timestamp_millis();
+            let matches = site.is_empty()
+                && key.model == model
+                && key.sensor_id == sensor_id
+                && measurement_type.is_none_or(|t| t == key.measurement_type)
+                && from_ms.is_none_or(|from| taken >= from)
+                && before_ms.is_none_or(|before| taken < before);
+            if !matches {
+                return true;
+            }
+            if let Some(gauge) = self.gauges.get(&key.measurement_type)
+                && gauge.remove_label_values(&series_labels(&series.row, &series.labels)).is_ok()
+            {
+                removed += 1;
+            }
+            false
+        });
+        removed
+    }
+
+    /// Remove the series older than the maximum age of their measurement
+    /// type. Returns how many were removed.
+    pub fn expire(&self, now: SystemTime) -> usize {
+        if self.max_age.is_empty() {
+            return 0;
+        }
+        let published = &mut self.series.lock().unwrap().published;
+        let before = published.len();
+        published.retain(|(_, key), series| {
+            let Some(max_age) = self.max_age.get(&key.measurement_type) else { return true };
+            if now.duration_since(series.updated).unwrap_or_default() <= *max_age {
+                return true;
+            }
+            if let Some(gauge) = self.gauges.get(&key.measurement_type) {
+                let _ = gauge.remove_label_values(&series_labels(&series.row, &series.labels));
+            }
+            false
+        });
+        let removed = before - published.len();
+        self.expired.inc_by(removed as u64);
+        removed
+    }
+
+    /// The gauge samples `observe` would set for `rows`, by metric name and
+    /// label, without setting them; see `handlers::parse_preview`.
+    pub fn preview(&self, rows: &[NormalizedRow], mappings: &HashMap<String, Mapping>) -> Vec<(&'static str, BTreeMap<String, String>, f64)> {
+        let mut names = vec!["sensor_id", "model", "site", "probe", "location"];
+        names.extend(self.enrichment.names().iter().map(String::as_str));
+        rows.iter()
+            .filter(|row| self.gauges.contains_key(&row.measurement_type))
+            .filter_map(|row| {
+                let location = mapping_for(mappings, &row.sensor_id, &row.model).map_or("", |m| m.name.as_str());
+                let mut rest = vec![location.to_string()];
+                rest.extend(self.enrichment.peek(&row.model, &row.sensor_id));
+                let labels = names.iter().map(|n| n.to_string()).zip(series_labels(row, &rest).into_iter().map(String::from)).collect();
+                Some((metric_name(row.measurement_type)?, labels, self.rounded(row)))
+            })
+            .collect()
+    }
+
+    /// The rows the gauges currently show, see `warm`.
+    pub fn rows(&self) -> Vec<NormalizedRow> {
+        self.series.lock().unwrap().published.values().map(|series| series.row.clone()).collect()
+    }
+}
+
+impl Sink for SensorGauges {
+    fn name(&self) -> &'static str {
+        "gauges"
+    }
+
+    fn push(&self, readings: &Readings) {
+        self.observe(readings.rows, readings.mappings);
+    }
+}
+
+/// Per-source ingestion health: `integration_up{source}` is 1 while the
+/// source is connected, `integration_last_success_timestamp_seconds{source}`
+/// is the time of the last reading it delivered. Each ingestion path
+/// (currently only `mqtt`) reports under its own `source` label so mixed
+/// deployments can alert per source.
+#[derive(Clone)]
+pub struct IntegrationHealth {
+    up: IntGaugeVec,
+    last_success: GaugeVec,
+}
+
+impl IntegrationHealth {
+    pub fn new(registry: &Registry) -> anyhow::Result<Self> {
+        let up = IntGaugeVec::new(Opts::new("integration_up", "Whether the ingestion source is connected"), &["source"])?;
+        let last_success = GaugeVec::new(
+            Opts::new("integration_last_success_timestamp_seconds", "Unix time of the last reading from the ingestion source"),
+            &["source"],
+        )?;
+        registry.register(Box::new(up.clone()))?;
+        registry.register(Box::new(last_success.clone()))?;
+        Ok(Self { up, last_success })
+    }
+
+    /// Declare `source` so its series exist (as down) before it first
+    /// connects; an absent series would not trigger `integration_up == 0`.
+    pub fn register_source(&self, source: &str) {
+        self.up.with_label_values(&[source]).set(0);
+    }
+
+    pub fn set_up(&self, source: &str, up: bool) {
+        self.up.with_label_values(&[source]).set(up as i64);
+    }
+
+    pub fn is_up(&self, source: &str) -> bool {
+        self.up.with_label_values(&[source]).get() == 1
+    }
+
+    pub fn success(&self, source: &str) {
+        self.last_success.with_label_values(&[source]).set(unix_seconds(SystemTime::now()));
+    }
+}
+
+/// Per-sensor freshness: `sensor_last_seen_timestamp_seconds` is set on every
+/// message, `sensor_stale` (1 when silent for longer than the threshold) is
+/// recomputed periodically from `state::LastSeen` by `refresh`.
+pub struct SensorFreshness {
+    last_seen: GaugeVec,
+    stale: IntGaugeVec,
+    /// The two series of every sensor seen, so a message doesn't look them
+    /// up by label values.
+    series: Mutex<HashMap<SensorKey, (Gauge, IntGauge)>>,
+    pub threshold: Duration,
+}
+
+impl SensorFreshness {
+    pub fn new(registry: &Registry, threshold: Duration) -> anyhow::Result<Self> {
+        let last_seen = GaugeVec::new(
+            Opts::new("sensor_last_seen_timestamp_seconds", "Unix time of the last message from the sensor"),
+            &["sensor_id", "model"],
+        )?;
+        let stale = IntGaugeVec::new(
+            Opts::new("sensor_stale", "Whether the sensor has been silent for longer than the staleness threshold"),
+            &["sensor_id", "model"],
+        )?;
+        registry.register(Box::new(last_seen.clone()))?;
+        registry.register(Box::new(stale.clone()))?;
+        Ok(Self { last_seen, stale, series: Mutex::new(HashMap::new()), threshold })
+    }
+
+    pub fn seen(&self, key: &SensorKey, at: SystemTime) {
+        let mut series = self.series.lock().unwrap();
+        if !series.contains_key(key) {
+            series.insert(key.clone(), self.handles(key));
+        }
+        let (last_seen, stale) = &series[key];
+        last_seen.set(unix_seconds(at));
+        stale.set(0);
+    }
+
+    fn handles(&self, key: &SensorKey) -> (Gauge, IntGauge) {
+        let labels = [key.sensor_id.as_str(), key.model.as_str()];
+        (self.last_seen.with_label_values(&labels), self.stale.with_label_values(&labels))
+    }
+
+    /// Recompute `sensor_stale` for every known sensor.
+    pub fn refresh(&self, last_seen: &HashMap<SensorKey, SystemTime>, now: SystemTime) {
+        let mut series = self.series.lock().unwrap();
+        for (key, at) in last_seen {
+            let silent = now.duration_since(*at).unwrap_or(Duration::ZERO);
+            let (_, stale) = series.entry(key.clone()).or_insert_with(|| self.handles(key));
+            stale.set((silent > self.threshold) as i64);
+        }
+    }
+
+    /// Drop a sensor's series, e.g. after its rolling id was re-linked.
+    pub fn remove(&self, key: &SensorKey) {
+        self.series.lock().unwrap().remove(key);
+        let _ = self.last_seen.remove_label_values(&[&key.sensor_id, &key.model]);
+        let _ = self.stale.remove_label_values(&[&key.sensor_id, &key.model]);
+    }
+}
+
+/// Expected and received messages of the mapped sensors that declare an
+/// `expected_interval_secs`.
+pub struct SensorExpectation {
+    expected: CounterVec,
+    received: IntCounterVec,
+    /// Sensors currently exported, (sensor_id, model).
+    exported: Mutex<HashSet<(String, String)>>,
+}
+
+impl SensorExpectation {
+    pub fn new(registry: &Registry) -> anyhow::Result<Self> {
+        let expected = CounterVec::new(
+            Opts::new("sensor_expected_messages_total", "Messages the sensor should have sent given its expected interval"),
+            &["sensor_id", "model"],
+        )?;
+        let received = IntCounterVec::new(
+            Opts::new("sensor_received_messages_total", "Messages received from sensors with an expected interval"),
+            &["sensor_id", "model"],
+        )?;
+        registry.register(Box::new(expected.clone()))?;
+        registry.register(Box::new(received.clone()))?;
+        Ok(Self { expected, received, exported: Mutex::new(HashSet::new()) })
+    }
+
+    /// Count a message whose sensor has an expected interval.
+    pub fn observe(&self, rows: &[NormalizedRow], mappings: &HashMap<String, Mapping>) {
+        let Some(row) = rows.first().filter(|row| row.site.is_empty()) else { return };
+        let expected = mapping_for(mappings, &row.sensor_id, &row.model).and_then(|m| m.expected_interval_secs);
+        if expected.is_some() {
+            self.received.with_label_values(&[&row.sensor_id, &row.model]).inc();
+        }
+    }
+
+    /// Add the messages expected within `elapsed`, and drop the series of
+    /// sensors whose mapping or interval is gone.
+    pub fn advance(&self, mappings: &HashMap<String, Mapping>, elapsed: Duration) {
+        let mut exported = self.exported.lock().unwrap();
+        let mut current = HashSet::new();
+        for mapping in mappings.values() {
+            let Some(interval) = mapping.expected_interval_secs.filter(|i| *i > 0) else { continue };
+            let labels = [mapping.sensor_id.as_str(), mapping.manufacturer.as_str()];
+            self.expected.with_label_values(&labels).inc_by(elapsed.as_secs_f64() / interval as f64);
+            // Start received at 0 so the ratio exists before the first message.
+            self.received.with_label_values(&labels);
+            current.insert((mapping.sensor_id.clone(), mapping.manufacturer.clone()));
+        }
+        for (sensor_id, model) in exported.difference(&current) {
+            let _ = self.expected.remove_label_values(&[sensor_id, model]);
+            let _ = self.received.remove_label_values(&[sensor_id, model]);
+        }
+        *exported = current;
+    }
+}
+
+/// Latency of HTTP requests, `http_request_duration_seconds{method, route,
+/// status}`. `route` is the matched route pattern (`/api/jobs/{id}`), or
+/// `other` for the UI and unknown paths, so ids don't multiply the series.
+pub struct HttpMetrics {
+    duration: HistogramVec,
+}
+
+impl HttpMetrics {
+    pub fn new(registry: &Registry) -> anyhow::Result<Self> {
+        let duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time taken to answer HTTP requests"),
+            &["method", "route", "status"],
+        )?;
+        registry.register(Box::new(duration.clone()))?;
+        Ok(Self { duration })
+    }
+}
+
+/// Middleware observing `http_request_duration_seconds`. Streamed bodies
+/// (downloads, exports) count until their headers are sent.
+pub async fn track_http(State(metrics): State<Arc<HttpMetrics>>, req: Request<Body>, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = req.extensions().get::<MatchedPath>().map(|p| p.as_str().to_string()).unwrap_or_else(|| "other".to_string());
+    let started = Instant::now();
+    let response = next.run(req).await;
+    metrics
+        .duration
+        .with_label_values(&[method.as_str(), route.as_str(), response.status().as_str()])
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+/// Label values of a gauge series: the identity of `row`'s series followed
+/// by `location` and the enrichment labels.
+fn series_labels<'a>(row: &'a NormalizedRow, rest: &'a [String]) -> Vec<&'a str> {
+    [&row.sensor_id, &row.model, &row.site, &row.probe].into_iter().map(String::as_str).chain(rest.iter().map(String::as_str)).collect()
+}
+
+fn unix_seconds(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{EnrichmentConfig, LoggingConfig};
+    use crate::db::{start_db_worker, DbConfig, DbHandle};
+    use crate::logging::LogLimiter;
+
+    fn gauges(config: &MetricsConfig, db: DbHandle) -> SensorGauges {
+        let registry = Registry::new();
+        let log = Arc::new(LogLimiter::new(&LoggingConfig::default()));
+        let enrichment = Arc::new(Enrichment::new(&EnrichmentConfig::default(), db, log, &registry).unwrap());
+        SensorGauges::new(&registry, config, enrichment).unwrap()
+    }
+
+    fn row(sensor_id: &str, measurement_type: u8, timestamp: &str) -> NormalizedRow {
+        NormalizedRow {
+            timestamp: chrono::NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S").unwrap(),
+            sensor_id: sensor_id.to_string(),
+            model: "Acurite-Tower".to_string(),
+            measurement_type,
+            value: 21.5,
+            topic: "rtl_433/events".to_string(),
+            seq: 0,
+            site: String::new(),
+            probe: String::new(),
+        }
+    }
+
+    fn shown(gauges: &SensorGauges) -> Vec<(String, u8)> {
+        let mut rows: Vec<_> = gauges.rows().into_iter().map(|row| (row.sensor_id, row.measurement_type)).collect();
+        rows.sort();
+        rows
+    }
+
+    fn with_db(test: impl FnOnce(DbHandle)) {
+        let dir = std::env::temp_dir().join(format!("exporter-metrics-test-{}-{:?}", std::process::id(), std::thread::current().id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let (db, _worker) = start_db_worker(&config, &Registry::new()).unwrap();
+        test(db);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn purged_readings_leave_the_gauges() {
+        with_db(|db| {
+            let gauges = gauges(&MetricsConfig::default(), db);
+            let rows = [
+                row("1", 1, "2024-03-01 08:00:00"),
+                row("1", 2, "2024-03-01 09:00:00"),
+                row("2", 1, "2024-03-01 08:00:00"),
+            ];
+            gauges.observe(&rows, &HashMap::new());
+            let from = chrono::NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_opt(8, 30, 0).unwrap();
+            let from_ms = from.and_utc().timestamp_millis();
+            // Only sensor 1's reading taken inside the window goes.
+            assert_eq!(gauges.remove_readings("Acurite-Tower", "1", None, Some(from_ms), None), 1);
+            assert_eq!(shown(&gauges), vec![("1".to_string(), 1), ("2".to_string(), 1)]);
+            assert_eq!(gauges.remove_readings("Acurite-Tower", "1", Some(2), None, None), 0);
+            assert_eq!(gauges.remove_sensor("Acurite-Tower", "1", None), 1);
+            assert_eq!(shown(&gauges), vec![("2".to_string(), 1)]);
+        });
+    }
+
+    fn mapping(sensor_id: &str, expected_interval_secs: Option<u64>) -> (String, Mapping) {
+        let mapping = Mapping {
+            sensor_id: sensor_id.to_string(),
+            manufacturer: "Acurite-Tower".to_string(),
+            name: format!("Room {}", sensor_id),
+            units: Default::default(),
+            retention_days: None,
+            expected_interval_secs,
+        };
+        (format!("Acurite-Tower::{}", sensor_id), mapping)
+    }
+
+    #[test]
+    fn expected_messages_follow_the_mapped_intervals() {
+        let expectation = SensorExpectation::new(&Registry::new()).unwrap();
+        let mut mappings: HashMap<String, Mapping> =
+            [mapping("1", Some(30)), mapping("2", None), mapping("3", Some(0))].into_iter().collect();
+        expectation.advance(&mappings, Duration::from_secs(45));
+        expectation.advance(&mappings, Duration::from_secs(15));
+        expectation.observe(&[row("1", 1, "2024-03-01 08:00:00")], &mappings);
+        expectation.observe(&[row("2", 1, "2024-03-01 08:00:00")], &mappings);
+        assert_eq!(expectation.expected.with_label_values(&["1", "Acurite-Tower"]).get(), 2.0);
+        assert_eq!(expectation.received.with_label_values(&["1", "Acurite-Tower"]).get(), 1);
+        // Sensors without a (positive) interval get no series.
+        let exported = |expectation: &SensorExpectation| {
+            let mut sensors: Vec<String> = expectation.exported.lock().unwrap().iter().map(|(id, _)| id.clone()).collect();
+            sensors.sort();
+            sensors
+        };
+        assert_eq!(exported(&expectation), ["1"]);
+
+        // Dropping the interval drops the series; a new one starts at zero.
+        mappings.extend([mapping("1", None), mapping("2", Some(60))]);
+        expectation.advance(&mappings, Duration::from_secs(30));
+        assert_eq!(exported(&expectation), ["2"]);
+        assert_eq!(expectation.expected.with_label_values(&["1", "Acurite-Tower"]).get(), 0.0);
+        assert_eq!(expectation.expected.with_label_values(&["2", "Acurite-Tower"]).get(), 0.5);
+        assert_eq!(expectation.received.with_label_values(&["2", "Acurite-Tower"]).get(), 0);
+    }
+
+    #[test]
+    fn series_expire_after_the_maximum_age_of_their_type() {
+        with_db(|db| {
+            let battery = measurement_code("battery_ok").unwrap();
+            let rows = [row("1", 1, "2024-03-01 08:00:00"), row("1", battery, "2024-03-01 08:00:00")];
+            let now = SystemTime::now();
+
+            // Without a maximum age nothing expires.
+            let kept = gauges(&MetricsConfig::default(), db.clone());
+            kept.observe(&rows, &HashMap::new());
+            assert_eq!(kept.expire(now + Duration::from_secs(86400 * 365)), 0);
+
+            let config = MetricsConfig {
+                max_age_secs: 600,
+                max_age: [("battery_ok".to_string(), 86400)].into_iter().collect(),
+                ..MetricsConfig::default()
+            };
+            let expiring = gauges(&config, db);
+            expiring.observe(&rows, &HashMap::new());
+            assert_eq!(expiring.expire(now + Duration::from_secs(300)), 0);
+            assert_eq!(expiring.expire(now + Duration::from_secs(3600)), 1);
+            assert_eq!(shown(&expiring), vec![("1".to_string(), battery)]);
+            assert_eq!(expiring.expire(now + Duration::from_secs(90000)), 1);
+            assert!(shown(&expiring).is_empty());
+            assert_eq!(expiring.expired.get(), 2);
+        });
+    }
+}
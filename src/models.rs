@@ -0,0 +1,148 @@
+// Per-model decoders for rtl_433 device quirks. The generic normalizer in
+// `mqtt_buffer` reads every payload key named like a measurement type; some
+// devices need more than that. Decoders are enabled per `model` string and
+// run, in the listed order, on the rows of every rtl_433 message of that
+// model before dedup and everything else sees them:
+//
+//   [models]
+//   "LaCrosse-TX141THBv2" = ["channel_id", "new_battery"]
+//   "Toyota" = ["status_bits"]
+//
+// `channel_id` appends the `channel` to the sensor id (`1234-A`), for
+// models whose ids are not unique across channels or change with every
+// battery swap while the channel switch stays put. `new_battery` stores
+// `battery_ok = 1` for messages flagged `newbattery` that carry no battery
+// status of their own (LaCrosse sends the flag after a battery change).
+// `status_bits` splits an integer (or `0x` hex string) `status` or `flags`
+// member into one 0/1 row per bit, stored as `status_bit<n>` /
+// `flags_bit<n>` for the bits declared under `[[measurements]]`, e.g. the
+// low-pressure or fast-leak bits of TPMS sensors. Changing the decoders of
+// a model changes its stored series (`channel_id` starts new sensor ids).
+// New decoders implement `ModelDecoder` and are added to `DECODERS`.
+use crate::mqtt_buffer::{measurement_code, NormalizedRow};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+
+/// An rtl_433 message being normalized.
+pub struct Message<'a> {
+    pub payload: &'a Map<String, Value>,
+    /// Timestamp, model, sensor id and topic of the message, the template
+    /// for added rows.
+    pub base: NormalizedRow,
+    pub rows: Vec<NormalizedRow>,
+}
+
+impl Message<'_> {
+    /// Add a row of the measurement `key`, if that type is known.
+    pub fn push(&mut self, key: &str, value: f64) {
+        if let Some(measurement_type) = measurement_code(key) {
+            self.rows.push(NormalizedRow { measurement_type, value, seq: 0, ..self.base.clone() });
+        }
+    }
+}
+
+pub trait ModelDecoder: Send + Sync {
+    /// Name used in `[models]`.
+    fn name(&self) -> &'static str;
+
+    /// Correct or extend the rows of a message.
+    fn decode(&self, message: &mut Message);
+}
+
+/// Every available decoder.
+const DECODERS: &[&dyn ModelDecoder] = &[&ChannelId, &NewBattery, &StatusBits];
+
+struct ChannelId;
+
+impl ModelDecoder for ChannelId {
+    fn name(&self) -> &'static str {
+        "channel_id"
+    }
+
+    fn decode(&self, message: &mut Message) {
+        let channel = match message.payload.get("channel") {
+            Some(Value::String(s)) if !s.is_empty() => s.clone(),
+            Some(Value::Number(n)) => n.to_string(),
+            _ => return,
+        };
+        let sensor_id = format!("{}-{}", message.base.sensor_id, channel);
+        for row in &mut message.rows {
+            row.sensor_id.clone_from(&sensor_id);
+        }
+        message.base.sensor_id = sensor_id;
+    }
+}
+
+struct NewBattery;
+
+impl ModelDecoder for NewBattery {
+    fn name(&self) -> &'static str {
+        "new_battery"
+    }
+
+    fn decode(&self, message: &mut Message) {
+        let flagged = message.payload.get("newbattery").and_then(Value::as_f64).is_some_and(|v| v != 0.0);
+        if flagged && !message.payload.contains_key("battery_ok") {
+            message.push("battery_ok", 1.0);
+        }
+    }
+}
+
+struct StatusBits;
+
+impl ModelDecoder for StatusBits {
+    fn name(&self) -> &'static str {
+        "status_bits"
+    }
+
+    fn decode(&self, message: &mut Message) {
+        for field in ["status", "flags"] {
+            let bits = match message.payload.get(field) {
+                Some(Value::Number(n)) => n.as_u64(),
+                Some(Value::String(s)) => match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+                    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                    None => s.parse().ok(),
+                },
+                _ => None,
+            };
+            let Some(bits) = bits else { continue };
+            for bit in 0..64 {
+                message.push(&format!("{}_bit{}", field, bit), ((bits >> bit) & 1) as f64);
+            }
+        }
+    }
+}
+
+/// The decoders enabled per model.
+#[derive(Default)]
+pub struct Models {
+    decoders: HashMap<String, Vec<&'static dyn ModelDecoder>>,
+}
+
+impl Models {
+    pub fn new(config: &BTreeMap<String, Vec<String>>) -> anyhow::Result<Self> {
+        let mut decoders = HashMap::new();
+        for (model, names) in config {
+            let list = names
+                .iter()
+                .map(|name| {
+                    DECODERS.iter().copied().find(|d| d.name() == name).ok_or_else(|| {
+                        let known: Vec<_> = DECODERS.iter().map(|d| d.name()).collect();
+                        anyhow::anyhow!("models.{}: unknown decoder {} (expected one of {})", model, name, known.join(", "))
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            decoders.insert(model.clone(), list);
+        }
+        Ok(Self { decoders })
+    }
+
+    /// Run the decoders of the message's model.
+    pub fn decode(&self, message: &mut Message) {
+        if let Some(decoders) = self.decoders.get(&message.base.model) {
+            for decoder in decoders {
+                decoder.decode(message);
+            }
+        }
+    }
+}
@@ -1,111 +1,673 @@
-// MQTT background task. This connects to the broker using `rumqttc` and
-// subscribes to the configured topic namespace. For each incoming message
-// we increment the provided `IntCounter` and print the event. In a real
-// implementation you'd persist raw messages to DuckDB/DuckLake and perform
-// structured parsing/validation.
-use prometheus::IntCounter;
-use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
+// MQTT background task. This connects to the broker using `rumqttc`
+// (optionally over TLS / mutual TLS) and subscribes to the configured topic
+// filters. For each incoming message we increment the provided `IntCounter`
+// and the per-filter counter, normalize the payload and hand the resulting
+// rows to the enabled output sinks (`sink`): the shared `MqttBuffer`, which
+// is flushed to DuckDB once its flush policy says so
+// (`mqtt_buffer::FlushPolicy`), the per-sensor gauges in `metrics`,
+// remote_write and so on. With raw storage enabled the original payload is
+// kept too. Payloads that fail to normalize
+// are counted and dead-lettered to `rejected_messages` instead of being
+// dropped silently. Messages on the optional
+// control topic skip that pipeline and are dispatched to `control`.
+// Each data message is handled in a debug-level `ingest` span (topic, size)
+// with debug events for the payload and the resulting rows; warnings per
+// message are rate limited through `logging::LogLimiter`.
+// After a connection error the loop waits with exponential backoff plus
+// jitter before rumqttc reconnects, and resubscribes once the broker
+// acknowledges the new connection; the connection state is exported as
+// `mqtt_connected`, `mqtt_reconnects_total` and `mqtt_connection_errors_total`.
+// Configured composite sensors are evaluated against `LatestReadings` right
+// after each message and their rows follow the same path, including the
+// derived on/off states of `states` and the rain / gust series of `weather`.
+// Repeated transmissions of the same reading are dropped first, see `dedup`,
+// and readings are converted to the units of their mapping before they
+// reach the gauges and the buffer, see `units`. On high-rate topics only
+// every Nth message is stored, see `sampling`. The rtl_433 radio metadata
+// (frequency, modulation, signal levels) is collected by `radio`.
+// The client id is `mqtt.client_id`, optionally suffixed with the host name
+// (`client_id_suffix = "hostname"`) so two instances never take over each
+// other's session. With `clean_session = false` the broker keeps the
+// session: subscriptions survive and QoS 1 messages published while the
+// exporter restarts are delivered once it reconnects. Messages are
+// acknowledged on receipt, so the ones still in memory when the process
+// dies are lost unless `wal.dir` is set; the final flush at a graceful
+// shutdown stores them. With the write-ahead log each data message is
+// logged before it is normalized and `replay_wal` feeds what a crashed
+// process left behind through the same pipeline (`ingest`) at startup, see
+// `wal`.
+use crate::composite::Composites;
+use crate::config::{ClientIdSuffix, Config, MqttConfig};
+use crate::control::{handle_control_message, ControlContext};
+use crate::crypto::PayloadCipher;
+use crate::db::DbHandle;
+use crate::dedup::Deduplicator;
+use crate::logging::{warn_limited, LogLimiter};
+use crate::metrics::{IntegrationHealth, SensorExpectation, SensorFreshness};
+use crate::radio::RadioStats;
+use crate::rate_limit::RateLimiter;
+use crate::sampling::Sampler;
+use crate::sink::{Readings, Sink};
+use crate::states::States;
+use crate::units::in_mapping_units;
+use crate::weather::Weather;
+use crate::mqtt_buffer::{MqttBuffer, Normalizer, NormalizedRow, RawMessage, RejectedMessage};
+use crate::state::{LastSeen, LatestReading, LatestReadings, ReadingKey, SensorKey, Store, TopicActivity, TopicStats};
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Registry};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, Outgoing, QoS, SubscribeFilter, TlsConfiguration, Transport};
+use std::hash::{BuildHasher, RandomState};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tracing::{debug, debug_span, info, warn, Instrument};
+
+/// `source` label of the MQTT loop in `integration_*` metrics.
+pub const SOURCE: &str = "mqtt";
+
+/// Broker connection metrics of the MQTT loop.
+pub struct ConnectionMetrics {
+    connected: IntGauge,
+    reconnects: IntCounter,
+    errors: IntCounter,
+}
+
+impl ConnectionMetrics {
+    pub fn new(registry: &Registry) -> anyhow::Result<Self> {
+        let metrics = Self {
+            connected: IntGauge::new("mqtt_connected", "1 while connected to the MQTT broker")?,
+            reconnects: IntCounter::new("mqtt_reconnects_total", "Successful reconnections to the MQTT broker")?,
+            errors: IntCounter::new("mqtt_connection_errors_total", "MQTT connection errors (failed attempts and lost connections)")?,
+        };
+        registry.register(Box::new(metrics.connected.clone()))?;
+        registry.register(Box::new(metrics.reconnects.clone()))?;
+        registry.register(Box::new(metrics.errors.clone()))?;
+        Ok(metrics)
+    }
+}
+
+/// Reconnection state machine: `Connecting` until the first ConnAck,
+/// `Connected`, and `Backoff` after an error, where the delay doubles per
+/// consecutive failure up to the configured maximum.
+enum ConnectionState {
+    Connecting,
+    Connected,
+    Backoff { delay: Duration },
+}
+
+impl ConnectionState {
+    /// The broker acknowledged a connection. Returns whether it was a
+    /// reconnect rather than the initial connection.
+    fn on_connected(&mut self, ever_connected: &mut bool) -> bool {
+        *self = ConnectionState::Connected;
+        std::mem::replace(ever_connected, true)
+    }
+
+    /// A connection attempt failed or the connection was lost. Returns the
+    /// delay to wait before the next attempt.
+    fn on_error(&mut self, mqtt: &MqttConfig) -> Duration {
+        let initial = Duration::from_secs(mqtt.reconnect_initial_secs.max(1));
+        let max = Duration::from_secs(mqtt.reconnect_max_secs).max(initial);
+        let delay = match self {
+            ConnectionState::Backoff { delay } => (*delay * 2).min(max),
+            ConnectionState::Connecting | ConnectionState::Connected => initial,
+        };
+        *self = ConnectionState::Backoff { delay };
+        with_jitter(delay)
+    }
+}
+
+/// `delay` shortened by up to half at random, so many exporters restarted
+/// together don't hit the broker in lockstep.
+fn with_jitter(delay: Duration) -> Duration {
+    let random = RandomState::new().hash_one(std::time::SystemTime::now());
+    delay / 2 + delay.mul_f64((random % 1000) as f64 / 2000.0)
+}
+
+/// Shared state fed by the MQTT loop, built once in `server::run()`.
+pub struct IngestContext {
+    pub counter: IntCounter,
+    pub connection: ConnectionMetrics,
+    /// Data messages per subscribed topic filter (`topic` label).
+    pub topic_counter: IntCounterVec,
+    /// Data messages that failed normalization.
+    pub rejected_counter: IntCounter,
+    /// Drops repeated transmissions before anything else sees them.
+    pub dedup: Arc<Deduplicator>,
+    /// Picks the payload format of each topic.
+    pub normalizer: Arc<Normalizer>,
+    pub buffer: Arc<MqttBuffer>,
+    pub db: DbHandle,
+    pub store: Store,
+    pub latest: LatestReadings,
+    pub topic_stats: TopicStats,
+    pub last_seen: LastSeen,
+    pub freshness: Arc<SensorFreshness>,
+    pub expectation: Arc<SensorExpectation>,
+    /// Frequency / signal statistics of the rtl_433 messages.
+    pub radio: Arc<RadioStats>,
+    pub composites: Arc<Composites>,
+    pub states: Arc<States>,
+    pub weather: Arc<Weather>,
+    /// Enabled outputs (DuckDB, gauges, remote_write, ...), see `sink`.
+    pub sinks: Vec<Arc<dyn Sink>>,
+    /// Drops the messages of sensors over the per-sensor limit.
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Decides which messages of high-rate topics are stored.
+    pub sampler: Arc<Sampler>,
+    /// Reported as `source="mqtt"`.
+    pub health: IntegrationHealth,
+    /// Encrypts stored raw payloads (when `raw.store` is set) and
+    /// dead-lettered payloads.
+    pub cipher: Option<Arc<PayloadCipher>>,
+    /// Notified on SIGHUP: TLS certificates are re-read and the connection
+    /// is re-established with them.
+    pub reload: Arc<Notify>,
+    /// Data topic filters, replaced when a reload changes `mqtt.topics`.
+    pub topics: watch::Receiver<Vec<String>>,
+    /// Notified at shutdown: the loop disconnects from the broker and
+    /// returns.
+    pub stop: Arc<Notify>,
+    pub log: Arc<LogLimiter>,
+}
 
 /// Start a long-running MQTT loop. This function never returns unless an
-/// unrecoverable error occurs. It is intended to be spawned with
+/// unrecoverable error occurs or `stop` is notified. It is intended to be spawned with
 /// `tokio::task::spawn` from `server::run()` so it runs in the background.
-pub async fn start_mqtt_loop(counter: IntCounter) -> anyhow::Result<()> {
-    // Create MQTT options from environment variables. Check for host,
-    // port, username, and password; use defaults if not provided.
-    // Not all fields are required; we default to localhost:1883
-    // with no authentication if env vars are missing.
+pub async fn start_mqtt_loop(config: Config, ctx: IngestContext) -> anyhow::Result<()> {
+    let IngestContext { counter, connection, topic_counter, buffer, db, store, topic_stats, health, reload, stop, log, .. } = &ctx;
+    let mut topics = ctx.topics.clone();
+    let mqtt = &config.mqtt;
+    let mut subscribed = topics.borrow_and_update().clone();
+    if subscribed.is_empty() {
+        return Err(anyhow::anyhow!("at least one MQTT topic must be configured (mqtt.topics or MQTT_TOPIC)"));
+    }
+    let control_topic = mqtt.control_topic.clone();
 
-    let mut mqttoptions: MqttOptions;
+    let client_id = client_id(mqtt)?;
+    let mut mqttoptions = mqtt_options(mqtt, &client_id)?;
+    mqttoptions.set_clean_session(mqtt.clean_session);
+    if mqtt.clean_session {
+        info!("MQTT client id {}", client_id);
+    } else {
+        info!("MQTT client id {}, resuming its persistent session", client_id);
+    }
+    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+    let control_ctx =
+        ControlContext { buffer: buffer.clone(), db: db.clone(), store: store.clone(), mappings_file: config.mapping.file.clone() };
+    let mut state = ConnectionState::Connecting;
+    let mut ever_connected = false;
 
-    // Read credentials from environment and set them if both present.
-    // This keeps defaults simple (no auth) while enabling secure
-    // deployments by setting the env vars.
-    let mqtt_host = std::env::var("MQTT_HOST").ok();
-    let mqtt_port = std::env::var("MQTT_PORT").ok();
-    let mqtt_user = std::env::var("MQTT_USER").ok();
-    let mqtt_pass = std::env::var("MQTT_PASS").ok();
-    let mqtt_topic = std::env::var("MQTT_TOPIC").ok();
-
-    match (mqtt_host, mqtt_port) {
-        // No host or port: default to localhost:1883
-        (None, None) => {
-            mqttoptions = MqttOptions::new("rust_exporter_client", "localhost", 1883);
-            println!("Connecting to MQTT broker at localhost:1883");
-        }
-        // Host and port provided, use both
-        (Some(host), Some(port)) => {
-            match port.trim().parse::<u16>() {
-                Ok(p) => {
-                    mqttoptions = MqttOptions::new("rust_exporter_client", &host, p);
-                    println!("Connecting to MQTT broker at {}:{}", host, p);
+    loop {
+        let event = tokio::select! {
+            event = eventloop.poll() => event,
+            _ = reload.notified() => {
+                if mqtt.tls {
+                    match tls_transport(mqtt) {
+                        Ok(transport) => {
+                            eventloop.mqtt_options.set_transport(transport);
+                            // Drop the connection; the next poll reconnects
+                            // with the new certificates.
+                            eventloop.clean();
+                            info!("Reloaded MQTT TLS certificates, reconnecting");
+                        }
+                        Err(e) => warn!("Keeping current MQTT TLS certificates: {}", e),
+                    }
                 }
-                Err(e) => {
-                    return Err(anyhow::anyhow!("Invalid MQTT_PORT value, expected a number, got: {}", e));
+                continue;
+            }
+            Ok(()) = topics.changed() => {
+                let next = topics.borrow_and_update().clone();
+                // While disconnected the next ConnAck subscribes to `next`.
+                if matches!(state, ConnectionState::Connected) {
+                    // An UNSUBSCRIBE takes one filter, so they are queued
+                    // from a task that waits for room in the client's
+                    // request channel while this loop keeps polling.
+                    let gone: Vec<String> = subscribed.iter().filter(|t| !next.contains(t)).cloned().collect();
+                    let unsubscriber = client.clone();
+                    tokio::spawn(async move {
+                        for topic in gone {
+                            match unsubscriber.unsubscribe(topic.as_str()).await {
+                                Ok(()) => info!("Unsubscribing from MQTT topic: {}", topic),
+                                Err(e) => warn!("Cannot unsubscribe from MQTT topic {}: {}", topic, e),
+                            }
+                        }
+                    });
+                    let added: Vec<String> = next.iter().filter(|t| !subscribed.contains(t)).cloned().collect();
+                    subscribe(&client, &added);
                 }
+                subscribed = next;
+                continue;
+            }
+            _ = stop.notified() => {
+                // Messages are handled inline, so the one at hand is
+                // already in the buffer.
+                if matches!(state, ConnectionState::Connected) && client.try_disconnect().is_ok() {
+                    while let Ok(event) = eventloop.poll().await {
+                        if let Event::Outgoing(Outgoing::Disconnect) = event {
+                            break;
+                        }
+                    }
+                }
+                info!("Disconnected from the MQTT broker");
+                return Ok(());
+            }
+        };
+        match event {
+            Ok(Event::Incoming(Incoming::ConnAck(ack))) => {
+                counter.inc();
+                health.set_up(SOURCE, true);
+                connection.connected.set(1);
+                if state.on_connected(&mut ever_connected) {
+                    connection.reconnects.inc();
+                    info!("Reconnected to MQTT broker: {:?}", ack);
+                } else {
+                    info!("Connected to MQTT broker: {:?}", ack);
+                }
+                if ack.session_present {
+                    info!("Resumed persistent MQTT session");
+                }
+                // Subscribe on every (re)connect, the broker does not keep
+                // subscriptions of a clean session (or may have expired a
+                // persistent one).
+                let mut filters = subscribed.clone();
+                filters.extend(control_topic.clone());
+                subscribe(&client, &filters);
+            }
+            Ok(Event::Incoming(Incoming::Publish(p))) if control_topic.as_deref() == Some(p.topic.as_str()) => {
+                counter.inc();
+                record_topic(topic_stats, &p.topic, p.payload.len()).await;
+                // Priority lane: handle right away in its own task so the
+                // command neither waits for nor blocks sensor traffic.
+                let ctx = control_ctx.clone();
+                tokio::spawn(async move { handle_control_message(&p.payload, &ctx).await });
+            }
+            Ok(Event::Incoming(Incoming::Publish(p))) => {
+                counter.inc();
+                let span = debug_span!("ingest", topic = %p.topic, bytes = p.payload.len());
+                async {
+                    debug!(payload = %String::from_utf8_lossy(&p.payload), "received");
+                    record_topic(topic_stats, &p.topic, p.payload.len()).await;
+                    // Labelled by filter rather than the concrete topic to keep
+                    // the series count bounded with wildcard subscriptions.
+                    for filter in subscribed.iter().filter(|f| rumqttc::matches(&p.topic, f)) {
+                        topic_counter.with_label_values(&[filter.as_str()]).inc();
+                    }
+                    buffer.log(&p.topic, &p.payload);
+                    ingest(&config, &ctx, &p.topic, &p.payload, Origin::Live).await;
+                    if buffer.is_due() {
+                        // Flush in the background so the event loop keeps
+                        // polling; `try_flush` skips if one is already running.
+                        // The flush span is part of this message's trace.
+                        let buffer = buffer.clone();
+                        let db = db.clone();
+                        let log = log.clone();
+                        tokio::spawn(
+                            async move {
+                                if let Some(Err(e)) = buffer.try_flush(&db).await {
+                                    warn_limited!(log, "flush", "Buffer flush failed: {}", e);
+                                }
+                            }
+                            .in_current_span(),
+                        );
+                    }
+                }
+                .instrument(span)
+                .await;
+            }
+            Ok(Event::Incoming(i)) => {
+                // Other incoming events (e.g., SubAck, PingResp)
+                // Mostly ignore but log for visibility
+                counter.inc();
+                debug!("Incoming = {i:?}");
+            }
+            Ok(Event::Outgoing(o)) => {
+                counter.inc();
+                debug!("Outgoing = {o:?}");
+            }
+            Err(e) => {
+                // Back off on errors to avoid hammering the broker; the
+                // next poll reconnects.
+                health.set_up(SOURCE, false);
+                connection.connected.set(0);
+                connection.errors.inc();
+                if matches!(state, ConnectionState::Connected) {
+                    warn!("Lost connection to MQTT broker: {}", e);
+                }
+                let delay = state.on_error(mqtt);
+                warn!("MQTT connection error, retrying in {:.1}s: {}", delay.as_secs_f64(), e);
+                tokio::time::sleep(delay).await;
             }
-            println!("Connecting to MQTT broker at {}:{}", host, port);
-        }
-        // Only host provided, use default port 1883
-        (Some(host), None) => {
-            mqttoptions = MqttOptions::new("rust_exporter_client", &host, 1883);
-            println!("Connecting to MQTT broker at {}:1883", host);
         }
-        (None, Some(_)) => {
-            return Err(anyhow::anyhow!("MQTT_HOST must be set if MQTT_PORT is provided"));
+    }
+}
+
+/// Subscribe to `topics` with a single SUBSCRIBE, so any number of filters
+/// takes one slot of the client's request channel. Logs instead of failing
+/// so a subscription the client can't take right now doesn't end the loop.
+fn subscribe(client: &AsyncClient, topics: &[String]) {
+    if topics.is_empty() {
+        return;
+    }
+    let filters = topics.iter().map(|topic| SubscribeFilter::new(topic.clone(), QoS::AtLeastOnce));
+    match client.try_subscribe_many(filters) {
+        Ok(()) => info!("Subscribing to MQTT topics: {}", topics.join(", ")),
+        Err(e) => warn!("Cannot subscribe to MQTT topics {}: {}", topics.join(", "), e),
+    }
+}
+
+async fn record_topic(stats: &TopicStats, topic: &str, payload_bytes: usize) {
+    let now = std::time::SystemTime::now();
+    let mut stats = stats.write().await;
+    match stats.get_mut(topic) {
+        Some(activity) => activity.record(now, payload_bytes),
+        None => {
+            let mut activity = TopicActivity::new(now);
+            activity.record(now, payload_bytes);
+            stats.insert(topic.to_string(), activity);
         }
     }
+}
+
+/// The client id the ingest loop connects with.
+pub fn client_id(mqtt: &MqttConfig) -> anyhow::Result<String> {
+    if mqtt.client_id.is_empty() {
+        anyhow::bail!("mqtt.client_id must not be empty");
+    }
+    match mqtt.client_id_suffix {
+        ClientIdSuffix::None => Ok(mqtt.client_id.clone()),
+        ClientIdSuffix::Hostname => Ok(format!("{}-{}", mqtt.client_id, hostname()?)),
+    }
+}
+
+/// Host name from `HOSTNAME` or the kernel, limited to characters every
+/// broker accepts in client ids.
+fn hostname() -> anyhow::Result<String> {
+    let name = match std::env::var("HOSTNAME") {
+        Ok(name) if !name.trim().is_empty() => name,
+        _ => std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+            .map_err(|e| anyhow::anyhow!("cannot determine the host name for mqtt.client_id_suffix: {}", e))?,
+    };
+    let name: String = name.trim().chars().filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')).collect();
+    if name.is_empty() {
+        anyhow::bail!("cannot determine the host name for mqtt.client_id_suffix");
+    }
+    Ok(name)
+}
 
-    mqttoptions.set_keep_alive(std::time::Duration::from_secs(5));
+/// Connection options for the configured broker (address, keep-alive, TLS
+/// and credentials) under `client_id`.
+pub fn mqtt_options(mqtt: &MqttConfig, client_id: &str) -> anyhow::Result<MqttOptions> {
+    let mut mqttoptions = MqttOptions::new(client_id, &mqtt.host, mqtt.port);
+    info!("Connecting to MQTT broker at {}:{}", mqtt.host, mqtt.port);
+    mqttoptions.set_keep_alive(std::time::Duration::from_secs(mqtt.keep_alive_secs));
+    if mqtt.tls {
+        mqttoptions.set_transport(tls_transport(mqtt)?);
+        info!("Using TLS for the MQTT connection");
+    }
 
-    match (mqtt_user, mqtt_pass) {
-        (Some(user), Some(pass)) => {            
-            mqttoptions.set_credentials(&user, &pass);
-            println!("Using MQTT credentials from environment {}:*******", user);
+    // Only authenticate when both halves of the credentials are present.
+    // This keeps defaults simple (no auth) while enabling secure
+    // deployments by setting the values.
+    match (&mqtt.username, &mqtt.password) {
+        (Some(user), Some(pass)) => {
+            mqttoptions.set_credentials(user, pass);
+            info!("Using MQTT credentials {}:*******", user);
         }
         (Some(_), None) | (None, Some(_)) => {
             // Warn but continue without credentials if only one is set.
-            eprintln!("MQTT credentials incomplete: both MQTT_USER and MQTT_PASS must be set to enable auth");
+            warn!("MQTT credentials incomplete: both username and password must be set to enable auth");
         }
         (None, None) => {
             // No credentials configured; proceed unauthenticated.
-            println!("No MQTT credentials provided; connecting without authentication");
+            info!("No MQTT credentials provided; connecting without authentication");
         }
     }
+    Ok(mqttoptions)
+}
 
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+/// Build the TLS transport from the configured certificate files. Without a
+/// CA file the platform's root certificates are used.
+fn tls_transport(mqtt: &MqttConfig) -> anyhow::Result<Transport> {
+    let read = |path: &String| std::fs::read(path).map_err(|e| anyhow::anyhow!("cannot read {}: {}", path, e));
+    let client_auth = match (&mqtt.client_cert_file, &mqtt.client_key_file) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        (None, None) => None,
+        _ => return Err(anyhow::anyhow!("mutual TLS needs both client_cert_file and client_key_file")),
+    };
+    let tls = match (&mqtt.ca_file, client_auth) {
+        (Some(ca), client_auth) => TlsConfiguration::Simple { ca: read(ca)?, alpn: None, client_auth },
+        (None, None) => TlsConfiguration::default(),
+        (None, Some(_)) => return Err(anyhow::anyhow!("mutual TLS needs ca_file as well")),
+    };
+    Ok(Transport::tls_with_config(tls))
+}
 
-    match mqtt_topic {
-        Some(topic) => {
-            client.subscribe(&topic, QoS::AtLeastOnce).await?;
-            println!("Subscribing to MQTT topic: {}", topic);
+/// Where a data message handed to `ingest` comes from.
+#[derive(Clone, Copy)]
+enum Origin {
+    /// Just received from the broker.
+    Live,
+    /// Read back from the WAL; received at that time.
+    Replayed(NaiveDateTime),
+}
+
+/// Take one data message through the pipeline: normalization, dedup, rate
+/// limit, sampling, raw storage, latest readings, composites, derived
+/// series and the sinks, or the dead letters when it doesn't normalize. The
+/// caller logs it to the WAL first. Replayed messages keep their logged
+/// receive time and skip the radio statistics and the rate limit, which
+/// measure arrival as it happens.
+async fn ingest(config: &Config, ctx: &IngestContext, topic: &str, payload: &[u8], origin: Origin) {
+    let received = std::time::Instant::now();
+    let log = &ctx.log;
+    let before = Utc::now().naive_utc();
+    let mut normalized = debug_span!("normalize").in_scope(|| ctx.normalizer.normalize(topic, payload));
+    let received_at = match origin {
+        Origin::Live => {
+            if normalized.is_ok() {
+                ctx.radio.observe(payload);
+            }
+            before
         }
-        None => {
-            return Err(anyhow::anyhow!("MQTT_TOPIC environment variable must be set to subscribe to topics"));
+        Origin::Replayed(received_at) => {
+            // Rows stamped while normalizing had no time in the payload.
+            let after = Utc::now().naive_utc();
+            if let Ok(rows) = &mut normalized {
+                for row in rows.iter_mut().filter(|row| (before..=after).contains(&row.timestamp)) {
+                    row.timestamp = received_at;
+                }
+            }
+            received_at
         }
+    };
+    if let Ok(rows) = &mut normalized
+        && !ctx.dedup.retain_new(rows)
+    {
+        debug!("duplicate transmission, dropped");
+        return;
     }
-
-    loop {
-        match eventloop.poll().await {
-            Ok(Event::Incoming(Incoming::Publish(p))) => {
-                counter.inc();
-                println!("Topic: {}, Payload: {:?}", p.topic, p.payload);
-                // TODO: Persist message to DuckDB/DuckLake here and compute Prometheus metrics
+    if matches!(origin, Origin::Live)
+        && ctx.rate_limiter.is_enabled()
+        && let Ok(rows) = &normalized
+        && !ctx.rate_limiter.allow(rows, &*ctx.store.read().await)
+    {
+        let row = &rows[0];
+        warn_limited!(log, "rate_limit", "Sensor {}::{} is over the rate limit, message dropped", row.model, row.sensor_id);
+        return;
+    }
+    let keep = normalized.as_deref().map_or(true, |rows| ctx.sampler.keep(topic, rows));
+    if !keep {
+        debug!("sampled out, not stored");
+    }
+    if config.raw.store && keep {
+        let rows = normalized.as_deref().unwrap_or_default();
+        match raw_message(topic, payload, rows, ctx.cipher.as_deref()) {
+            Ok(message) => ctx.buffer.push_raw(RawMessage { received_at, ..message }),
+            Err(e) => warn_limited!(log, "raw", "Dropping raw payload on {}: {}", topic, e),
+        }
+    }
+    match normalized {
+        Ok(mut rows) => {
+            for row in &rows {
+                debug!(model = %row.model, sensor_id = %row.sensor_id, measurement_type = row.measurement_type, value = row.value, "normalized");
             }
-            Ok(Event::Incoming(i)) => {
-                // Other incoming events (e.g., ConnAck, SubAck)
-                // Mostly ignore but log for visibility
-                counter.inc();
-                println!("Incoming = {i:?}");
+            let received_at = std::time::SystemTime::from(received_at.and_utc());
+            {
+                let mut latest = ctx.latest.write().await;
+                let record = |latest: &mut std::collections::HashMap<_, _>, rows: &[NormalizedRow]| {
+                    for row in rows {
+                        let key = ReadingKey {
+                            model: row.model.clone(),
+                            sensor_id: row.sensor_id.clone(),
+                            measurement_type: row.measurement_type,
+                            probe: row.probe.clone(),
+                        };
+                        latest.insert(key, LatestReading { value: row.value, received_at });
+                    }
+                };
+                record(&mut latest, &rows);
+                if !ctx.composites.is_empty() {
+                    let derived = ctx.composites.evaluate(&rows, &latest, &*ctx.store.read().await, received_at);
+                    for row in &derived {
+                        debug!(composite = %row.sensor_id, value = row.value, "composite evaluated");
+                    }
+                    record(&mut latest, &derived);
+                    rows.extend(derived);
+                }
             }
-            Ok(Event::Outgoing(o)) => {
-                counter.inc();
-                println!("Outgoing = {o:?}");
+            if let Some(row) = rows.first() {
+                let key = SensorKey { model: row.model.clone(), sensor_id: row.sensor_id.clone() };
+                ctx.freshness.seen(&key, received_at);
+                ctx.last_seen.write().await.insert(key, received_at);
             }
-            Err(e) => {
-                // Back off on errors to avoid busy loops.
-                eprintln!("mqtt loop error: {}", e);
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            ctx.weather.observe(&rows);
+            {
+                // Derived series and storage work in canonical
+                // units; the other outputs use the mapping's.
+                let mappings = ctx.store.read().await;
+                ctx.states.observe(&rows, &mappings);
+                ctx.expectation.observe(&rows, &mappings);
+                let mapped = in_mapping_units(&rows, &mappings);
+                let readings = Readings { rows: &mapped, canonical: &rows, received, mappings: &mappings, store: keep };
+                for sink in &ctx.sinks {
+                    sink.push(&readings);
+                }
+            }
+            ctx.health.success(SOURCE);
+        }
+        Err(e) => {
+            warn_limited!(log, "normalize", "Failed to normalize payload on {}: {}", topic, e);
+            ctx.rejected_counter.inc();
+            match rejected_message(topic, payload, &e, ctx.cipher.as_deref()) {
+                Ok(message) => ctx.buffer.push_rejected(RejectedMessage { received_at, ..message }),
+                Err(e) => warn_limited!(log, "rejected", "Dropping rejected payload on {}: {}", topic, e),
             }
         }
     }
 }
+
+/// Replay the WAL segments left by a crashed process and flush what they
+/// add to the buffer; called before the loop starts. The messages go
+/// through `ingest` like live ones, sinks included, with the time they were
+/// logged. Returns the number of messages replayed.
+pub async fn replay_wal(config: &Config, ctx: &IngestContext) -> anyhow::Result<usize> {
+    let Some(wal) = ctx.buffer.wal().cloned() else { return Ok(0) };
+    let mut messages = 0;
+    for &segment in wal.leftover() {
+        ctx.buffer.replaying(Some(segment));
+        for record in wal.read(segment)? {
+            let payload = match record.payload() {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("Skipping undecodable payload in WAL segment {}: {}", segment, e);
+                    continue;
+                }
+            };
+            let Some(received_at) = DateTime::from_timestamp_millis(record.received_ms).map(|t| t.naive_utc()) else { continue };
+            messages += 1;
+            ingest(config, ctx, &record.topic, &payload, Origin::Replayed(received_at)).await;
+        }
+    }
+    // Live messages stamp their own segments.
+    ctx.buffer.replaying(None);
+    if messages > 0 {
+        ctx.buffer.flush(&ctx.db).await?;
+    }
+    Ok(messages)
+}
+
+/// Wrap a data payload for `raw_messages`, encrypting it when a cipher is
+/// configured. The sensor identity is taken from the normalized rows.
+fn raw_message(topic: &str, payload: &[u8], rows: &[NormalizedRow], cipher: Option<&PayloadCipher>) -> anyhow::Result<RawMessage> {
+    let (raw_json, encrypted) = match cipher {
+        Some(cipher) => (cipher.encrypt(payload)?, true),
+        None => (payload.to_vec(), false),
+    };
+    Ok(RawMessage {
+        received_at: Utc::now().naive_utc(),
+        topic: topic.to_string(),
+        sensor_id: rows.first().map(|r| r.sensor_id.clone()),
+        model: rows.first().map(|r| r.model.clone()),
+        raw_json,
+        encrypted,
+    })
+}
+
+/// Wrap a payload that failed normalization for `rejected_messages`,
+/// encrypted like raw payloads when a cipher is configured.
+fn rejected_message(topic: &str, payload: &[u8], reason: &anyhow::Error, cipher: Option<&PayloadCipher>) -> anyhow::Result<RejectedMessage> {
+    let (sensor_id, model) = payload_sensor(payload);
+    let (payload, encrypted) = match cipher {
+        Some(cipher) => (cipher.encrypt(payload)?, true),
+        None => (payload.to_vec(), false),
+    };
+    Ok(RejectedMessage {
+        received_at: Utc::now().naive_utc(),
+        topic: topic.to_string(),
+        payload,
+        encrypted,
+        reason: reason.to_string(),
+        sensor_id,
+        model,
+    })
+}
+
+/// The `id` and `model` of a payload, as far as it has them. Decoders that
+/// derive the id (`models`) are not run; they may be what failed.
+fn payload_sensor(payload: &[u8]) -> (Option<String>, Option<String>) {
+    let Ok(serde_json::Value::Object(obj)) = serde_json::from_slice(payload) else { return (None, None) };
+    let sensor_id = match obj.get("id") {
+        Some(serde_json::Value::String(s)) => Some(s.clone()),
+        Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+        _ => None,
+    };
+    (sensor_id, obj.get("model").and_then(serde_json::Value::as_str).map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::Request;
+
+    #[test]
+    fn many_topics_take_one_subscribe() {
+        // The loop's channel capacity, with nothing polling it.
+        let (client, mut eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10);
+        let topics: Vec<String> = (0..25).map(|i| format!("rtl_433/site{}/#", i)).collect();
+        subscribe(&client, &topics);
+        subscribe(&client, &[]);
+        // Moves what the client queued into `pending`.
+        eventloop.clean();
+        let queued: Vec<Vec<String>> = eventloop
+            .pending
+            .iter()
+            .map(|request| match request {
+                Request::Subscribe(subscribe) => subscribe.filters.iter().map(|f| f.path.clone()).collect(),
+                other => panic!("unexpected request {:?}", other),
+            })
+            .collect();
+        assert_eq!(queued, vec![topics]);
+    }
+}
@@ -0,0 +1,1226 @@
+// Ingest buffer sitting between the MQTT loop and DuckDB. Incoming rtl_433
+// JSON payloads are normalized into one `NormalizedRow` per measurement and
+// collected in memory, then bulk-appended to the `measurements` table as an
+// Arrow batch. Batching keeps the number of small writes (and SD card wear)
+// low compared to inserting every message individually. When raw payload
+// storage is enabled the original messages travel alongside the rows and
+// are appended to `raw_messages` in the same flush. Payloads that cannot be
+// normalized go to `rejected_messages` the same way.
+//
+// The buffer is double-buffered: ingestion only ever touches the *active*
+// vector behind a short-lived mutex, while a flush swaps it with the
+// *flushing* vector and writes that out. Ingestion therefore never waits for
+// a flush to complete, and the flushing vector's lock guarantees at most one
+// flush is in flight.
+//
+// When to flush is decided by a `FlushPolicy` built from `[flush]`: the MQTT
+// loop flushes once the active side holds `max_rows` rows or about
+// `max_bytes` bytes, or its oldest entry has waited `max_age_secs`; the
+// periodic task flushes every `interval_secs` regardless. Device classes
+// can have their own limits and are buffered separately:
+//
+//   [[flush.classes]]
+//   name = "power"
+//   model = "^Efergy"        # regexes on model / sensor_id, topic filter
+//   max_age_secs = 10
+//
+//   [[flush.classes]]
+//   name = "weather"
+//   topic = "rtl_433/+/weather"
+//   max_age_secs = 300
+//
+// A class with its own `max_age_secs` is left out of the periodic flush,
+// so it is written when one of its limits is reached (or at shutdown);
+// unset limits are taken from `[flush]`. The buffered rows
+// and bytes are exported as `ingest_buffer_rows` / `ingest_buffer_bytes`.
+// Histograms cover the time taken by flushes
+// (`ingest_flush_duration_seconds`), the measurement rows each wrote
+// (`ingest_flush_rows`), every DuckDB append (`duckdb_append_duration_seconds`
+// by table, including time queued behind other database commands) and the
+// time from receiving an MQTT message to its rows being written
+// (`ingest_latency_seconds`, one observation per message).
+//
+// Rows get their `seq` from the DB worker when they are appended, see
+// `db::Sequencer`; until then it is 0.
+use crate::config::{FlushConfig, PayloadFormat, ProbeRule};
+use crate::db::DbHandle;
+use crate::models::{Message, Models};
+use crate::probes::Probes;
+use crate::sentinels::Sentinels;
+use crate::sink::{Readings, Sink};
+use crate::wal::Wal;
+use crate::zigbee2mqtt;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use duckdb::arrow::{
+    array::{BinaryArray, BooleanArray, Float64Array, StringArray, TimestampMicrosecondArray, UInt64Array, UInt8Array},
+    datatypes::{DataType, Field, Schema, TimeUnit},
+    record_batch::RecordBatch,
+};
+use prometheus::{exponential_buckets, Histogram, HistogramOpts, HistogramVec, IntCounter, IntGauge, Registry};
+use regex_lite::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{debug_span, Instrument};
+
+/// Default number of buffered rows that triggers a flush; see
+/// `FlushPolicy`.
+pub const FLUSH_MAX_ROWS: usize = 500;
+
+/// Default approximate buffer size that triggers a flush.
+pub const FLUSH_MAX_BYTES: usize = 4 * 1024 * 1024;
+
+/// Built-in measurements: payload key, type code, metric name and unit.
+/// `[[measurements]]` in the config adds more at startup.
+const BUILTIN_MEASUREMENTS: &[(&str, u8, &str, &str, &str)] = &[
+    ("temperature_C", 1, "sensor_temperature_celsius", "°C", "Air temperature"),
+    ("humidity", 2, "sensor_humidity_percent", "%", "Relative humidity"),
+    ("pressure_kPa", 3, "sensor_pressure_kilopascals", "kPa", "Pressure (barometric, or tyre pressure of TPMS sensors)"),
+    ("battery_ok", 4, "sensor_battery_ok", "", "Battery status, 1 = ok, 0 = low"),
+    // Zigbee2MQTT, see `zigbee2mqtt`.
+    ("battery", 200, "sensor_battery_percent", "%", "Battery charge"),
+    ("linkquality", 201, "sensor_link_quality", "", "Zigbee link quality (LQI, 0-255)"),
+    ("occupancy", 202, "sensor_occupancy", "", "Occupancy detected, 1 = yes"),
+    ("action", 203, "sensor_action", "", "Button or switch action, the action code"),
+];
+
+/// A quantity stored as measurements: the rtl_433 payload key it is read
+/// from and the numeric type code written to the `measurement_type` column.
+///
+/// ```toml
+/// [[measurements]]
+/// key = "wind_avg_m_s"
+/// code = 10
+/// metric = "sensor_wind_speed_kmh"
+/// unit = "km/h"
+/// scale = 3.6
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeasurementType {
+    pub key: String,
+    pub code: u8,
+    /// Prometheus metric name; defaults to `sensor_<key>`.
+    #[serde(default)]
+    pub metric: String,
+    #[serde(default)]
+    pub unit: String,
+    /// Stored value is `raw * scale + offset`, for payload keys that are
+    /// not in the unit the series should be kept in.
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+    /// Free text for the `measurement_types` table and
+    /// `/api/measurement-types`.
+    #[serde(default)]
+    pub description: String,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+static MEASUREMENT_TYPES: OnceLock<Vec<MeasurementType>> = OnceLock::new();
+
+fn builtin_measurements() -> Vec<MeasurementType> {
+    BUILTIN_MEASUREMENTS
+        .iter()
+        .map(|(key, code, metric, unit, description)| MeasurementType {
+            key: key.to_string(),
+            code: *code,
+            metric: metric.to_string(),
+            unit: unit.to_string(),
+            scale: 1.0,
+            offset: 0.0,
+            description: description.to_string(),
+        })
+        .collect()
+}
+
+/// Add configured measurement types to the built-in ones. Must run once at
+/// startup before anything normalizes or looks up measurement types; keys
+/// and codes have to be unique because codes are what's stored.
+pub fn install_measurement_types(extra: &[MeasurementType]) -> anyhow::Result<()> {
+    let mut types = builtin_measurements();
+    for t in extra {
+        if let Some(existing) = types.iter().find(|e| e.key == t.key || e.code == t.code) {
+            return Err(anyhow::anyhow!(
+                "measurement {} (code {}) clashes with {} (code {})",
+                t.key, t.code, existing.key, existing.code
+            ));
+        }
+        let mut t = t.clone();
+        if t.metric.is_empty() {
+            let key: String = t.key.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect();
+            t.metric = format!("sensor_{}", key);
+        }
+        types.push(t);
+    }
+    MEASUREMENT_TYPES
+        .set(types)
+        .map_err(|_| anyhow::anyhow!("measurement types are already initialized"))
+}
+
+/// Every known measurement type, built-in ones first.
+pub fn measurement_types() -> &'static [MeasurementType] {
+    MEASUREMENT_TYPES.get_or_init(builtin_measurements)
+}
+
+/// One measurement extracted from a sensor message.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NormalizedRow {
+    pub timestamp: NaiveDateTime,
+    pub sensor_id: String,
+    pub model: String,
+    pub measurement_type: u8,
+    pub value: f64,
+    /// MQTT topic the message arrived on (`import-csv` for imported rows,
+    /// empty for rows stored before topics were recorded).
+    pub topic: String,
+    /// Row sequence number, increasing in commit order. Assigned by the DB
+    /// worker when the row is appended; `0` until then and for rows stored
+    /// before sequences existed.
+    pub seq: u64,
+    /// Edge exporter the row was replicated from in aggregator mode, empty
+    /// for rows ingested locally.
+    pub site: String,
+    /// Probe of a multi-probe sensor (`1` for `temperature_1_C`, see
+    /// `probes`), empty for the sensor's own readings.
+    #[serde(default)]
+    pub probe: String,
+}
+
+/// An original MQTT payload as stored in `raw_messages`. `raw_json` holds
+/// `crypto::PayloadCipher` output when `encrypted` is set.
+#[derive(Clone, Debug)]
+pub struct RawMessage {
+    pub received_at: NaiveDateTime,
+    pub topic: String,
+    pub sensor_id: Option<String>,
+    pub model: Option<String>,
+    pub raw_json: Vec<u8>,
+    pub encrypted: bool,
+}
+
+/// A payload that could not be normalized, kept in `rejected_messages`
+/// with the reason so it can be inspected later. `payload` is
+/// `crypto::PayloadCipher` output when `encrypted` is set.
+#[derive(Clone, Debug)]
+pub struct RejectedMessage {
+    pub received_at: NaiveDateTime,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub encrypted: bool,
+    pub reason: String,
+    pub sensor_id: Option<String>,
+    pub model: Option<String>,
+}
+
+/// Payload key for a measurement type code, e.g. `1` -> `temperature_C`.
+pub fn measurement_name(code: u8) -> Option<&'static str> {
+    measurement_types().iter().find(|t| t.code == code).map(|t| t.key.as_str())
+}
+
+/// Type code for a payload key, e.g. `battery_ok` -> `4`.
+pub fn measurement_code(name: &str) -> Option<u8> {
+    measurement_types().iter().find(|t| t.key == name).map(|t| t.code)
+}
+
+/// Prometheus metric name used when a measurement type is exposed as a
+/// series, following the base-unit naming conventions.
+pub fn metric_name(code: u8) -> Option<&'static str> {
+    measurement_types().iter().find(|t| t.code == code).map(|t| t.metric.as_str())
+}
+
+/// Turn a single rtl_433 JSON payload received on `topic` into zero or more
+/// rows. The `time` field is used as the timestamp when present (rtl_433
+/// emits `YYYY-MM-DD HH:MM:SS`); otherwise the receive time is used. The
+/// sentinel rules apply to every reading, followed by the decoders of the
+/// payload's model and the probe patterns.
+fn normalize_rtl433(
+    topic: &str,
+    payload: &[u8],
+    models: &Models,
+    probes: &Probes,
+    sentinels: &Sentinels,
+) -> anyhow::Result<Vec<NormalizedRow>> {
+    let v: Value = serde_json::from_slice(payload)?;
+    let obj = v
+        .as_object()
+        .ok_or_else(|| anyhow::anyhow!("payload is not a JSON object"))?;
+
+    let model = obj
+        .get("model")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow::anyhow!("payload has no `model` field"))?
+        .to_string();
+    let sensor_id = match obj.get("id") {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Number(n)) => n.to_string(),
+        _ => return Err(anyhow::anyhow!("payload has no `id` field")),
+    };
+    let timestamp = obj
+        .get("time")
+        .and_then(Value::as_str)
+        .and_then(|t| NaiveDateTime::parse_from_str(t, "%Y-%m-%d %H:%M:%S").ok())
+        .unwrap_or_else(|| Utc::now().naive_utc());
+    let base = NormalizedRow {
+        timestamp,
+        sensor_id,
+        model,
+        measurement_type: 0,
+        value: 0.0,
+        topic: topic.to_string(),
+        seq: 0,
+        site: String::new(),
+        probe: String::new(),
+    };
+
+    let rows = measurement_types()
+        .iter()
+        .filter_map(|t| {
+            let value = obj.get(&t.key).and_then(Value::as_f64)?;
+            sentinels.check(&base.model, &t.key, value).map(|value| NormalizedRow {
+                measurement_type: t.code,
+                value: value * t.scale + t.offset,
+                seq: 0,
+                ..base.clone()
+            })
+        })
+        .collect();
+    let mut message = Message { payload: obj, base, rows };
+    models.decode(&mut message);
+    probes.decode(&mut message, sentinels);
+    Ok(message.rows)
+}
+
+/// Picks the normalizer of a topic from `mqtt.formats`: the longest
+/// matching filter decides, topics matching none are rtl_433, with the
+/// per-model decoders of `models`, the probe patterns of `probes` and the
+/// sentinel rules of `sentinels`.
+pub struct Normalizer {
+    /// Filters, longest first, with their format and Zigbee2MQTT base topic.
+    formats: Vec<(String, PayloadFormat, String)>,
+    models: Models,
+    probes: Probes,
+    sentinels: Sentinels,
+}
+
+impl Normalizer {
+    pub fn new(
+        formats: &BTreeMap<String, PayloadFormat>,
+        models: &BTreeMap<String, Vec<String>>,
+        probes: &[ProbeRule],
+        sentinels: Sentinels,
+    ) -> anyhow::Result<Self> {
+        let mut formats: Vec<_> = formats
+            .iter()
+            .map(|(filter, format)| {
+                if !rumqttc::valid_filter(filter) {
+                    anyhow::bail!("invalid topic filter in mqtt.formats: {}", filter);
+                }
+                Ok((filter.clone(), *format, zigbee2mqtt::base_topic(filter)))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        formats.sort_by_key(|(filter, _, _)| std::cmp::Reverse(filter.len()));
+        Ok(Self { formats, models: Models::new(models)?, probes: Probes::new(probes)?, sentinels })
+    }
+
+    /// Normalize a message received on `topic` with the topic's format.
+    pub fn normalize(&self, topic: &str, payload: &[u8]) -> anyhow::Result<Vec<NormalizedRow>> {
+        match self.formats.iter().find(|(filter, _, _)| rumqttc::matches(topic, filter)) {
+            Some((_, PayloadFormat::Zigbee2mqtt, base)) => zigbee2mqtt::normalize(base, topic, payload, &self.sentinels),
+            _ => normalize_rtl433(topic, payload, &self.models, &self.probes, &self.sentinels),
+        }
+    }
+}
+
+/// Convert rows into an Arrow batch matching the `measurements` table.
+pub fn rows_to_record_batch(rows: &[NormalizedRow]) -> anyhow::Result<RecordBatch> {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("timestamp", DataType::Timestamp(TimeUnit::Microsecond, None), false),
+        Field::new("sensor_id", DataType::Utf8, false),
+        Field::new("model", DataType::Utf8, false),
+        Field::new("measurement_type", DataType::UInt8, false),
+        Field::new("value", DataType::Float64, false),
+        Field::new("topic", DataType::Utf8, false),
+        Field::new("seq", DataType::UInt64, false),
+        Field::new("site", DataType::Utf8, false),
+        Field::new("probe", DataType::Utf8, false),
+    ]));
+    let batch = RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(TimestampMicrosecondArray::from_iter_values(
+                rows.iter().map(|r| r.timestamp.and_utc().timestamp_micros()),
+            )),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|r| r.sensor_id.as_str()))),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|r| r.model.as_str()))),
+            Arc::new(UInt8Array::from_iter_values(rows.iter().map(|r| r.measurement_type))),
+            Arc::new(Float64Array::from_iter_values(rows.iter().map(|r| r.value))),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|r| r.topic.as_str()))),
+            Arc::new(UInt64Array::from_iter_values(rows.iter().map(|r| r.seq))),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|r| r.site.as_str()))),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|r| r.probe.as_str()))),
+        ],
+    )?;
+    Ok(batch)
+}
+
+/// `rows_to_record_batch` with a `measurement` column holding the key of
+/// each row's `measurement_type`, for readers without the
+/// `measurement_types` table.
+pub fn rows_to_named_record_batch(rows: &[NormalizedRow]) -> anyhow::Result<RecordBatch> {
+    let batch = rows_to_record_batch(rows)?;
+    let mut fields: Vec<Field> = batch.schema().fields().iter().map(|f| f.as_ref().clone()).collect();
+    fields.push(Field::new("measurement", DataType::Utf8, true));
+    let mut columns = batch.columns().to_vec();
+    columns.push(Arc::new(rows.iter().map(|r| measurement_name(r.measurement_type)).collect::<StringArray>()));
+    Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)?)
+}
+
+/// Convert raw messages into an Arrow batch matching `raw_messages`.
+pub fn raw_to_record_batch(messages: &[RawMessage]) -> anyhow::Result<RecordBatch> {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("received_at", DataType::Timestamp(TimeUnit::Microsecond, None), false),
+        Field::new("topic", DataType::Utf8, false),
+        Field::new("sensor_id", DataType::Utf8, true),
+        Field::new("model", DataType::Utf8, true),
+        Field::new("raw_json", DataType::Binary, false),
+        Field::new("encrypted", DataType::Boolean, false),
+    ]));
+    let batch = RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(TimestampMicrosecondArray::from_iter_values(
+                messages.iter().map(|m| m.received_at.and_utc().timestamp_micros()),
+            )),
+            Arc::new(StringArray::from_iter_values(messages.iter().map(|m| m.topic.as_str()))),
+            Arc::new(StringArray::from_iter(messages.iter().map(|m| m.sensor_id.as_deref()))),
+            Arc::new(StringArray::from_iter(messages.iter().map(|m| m.model.as_deref()))),
+            Arc::new(BinaryArray::from_iter_values(messages.iter().map(|m| m.raw_json.as_slice()))),
+            Arc::new(BooleanArray::from_iter(messages.iter().map(|m| Some(m.encrypted)))),
+        ],
+    )?;
+    Ok(batch)
+}
+
+/// Convert rejected messages into an Arrow batch matching
+/// `rejected_messages`.
+pub fn rejected_to_record_batch(messages: &[RejectedMessage]) -> anyhow::Result<RecordBatch> {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("received_at", DataType::Timestamp(TimeUnit::Microsecond, None), false),
+        Field::new("topic", DataType::Utf8, false),
+        Field::new("payload", DataType::Binary, false),
+        Field::new("encrypted", DataType::Boolean, false),
+        Field::new("reason", DataType::Utf8, false),
+        Field::new("sensor_id", DataType::Utf8, true),
+        Field::new("model", DataType::Utf8, true),
+    ]));
+    let batch = RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(TimestampMicrosecondArray::from_iter_values(
+                messages.iter().map(|m| m.received_at.and_utc().timestamp_micros()),
+            )),
+            Arc::new(StringArray::from_iter_values(messages.iter().map(|m| m.topic.as_str()))),
+            Arc::new(BinaryArray::from_iter_values(messages.iter().map(|m| m.payload.as_slice()))),
+            Arc::new(BooleanArray::from_iter(messages.iter().map(|m| Some(m.encrypted)))),
+            Arc::new(StringArray::from_iter_values(messages.iter().map(|m| m.reason.as_str()))),
+            Arc::new(StringArray::from_iter(messages.iter().map(|m| m.sensor_id.as_deref()))),
+            Arc::new(StringArray::from_iter(messages.iter().map(|m| m.model.as_deref()))),
+        ],
+    )?;
+    Ok(batch)
+}
+
+/// When the buffer should be written out, independent of the exporter's
+/// metrics. Built from `config::FlushConfig` at startup and on reload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlushPolicy {
+    /// Buffered measurement rows that trigger a flush.
+    pub max_rows: usize,
+    /// Approximate buffered bytes that trigger a flush, `0` for no limit.
+    pub max_bytes: usize,
+    /// How long the oldest buffered entry may wait.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self { max_rows: FLUSH_MAX_ROWS, max_bytes: FLUSH_MAX_BYTES, max_age: None }
+    }
+}
+
+impl FlushPolicy {
+    pub fn from_config(config: &FlushConfig) -> Self {
+        Self {
+            max_rows: config.max_rows,
+            max_bytes: config.max_bytes,
+            max_age: (config.max_age_secs > 0).then(|| Duration::from_secs(config.max_age_secs)),
+        }
+    }
+
+    /// Whether a buffer holding `rows` measurement rows and `bytes` bytes,
+    /// whose oldest entry has waited `age` (`None` when empty), must be
+    /// flushed.
+    pub fn is_due(&self, rows: usize, bytes: usize, age: Option<Duration>) -> bool {
+        let Some(age) = age else { return false };
+        (rows > 0 && rows >= self.max_rows)
+            || (self.max_bytes > 0 && bytes >= self.max_bytes)
+            || self.max_age.is_some_and(|max| age >= max)
+    }
+}
+
+/// A device class buffered on its own with its own flush limits, so e.g.
+/// power meters can be written every 10 seconds while weather sensors wait
+/// five minutes. Entries of a message go to the first class matching its
+/// topic, model and sensor id; everything else (and rejected payloads)
+/// uses `[flush]`.
+pub struct FlushClass {
+    pub name: String,
+    model: Option<Regex>,
+    sensor_id: Option<Regex>,
+    topic: Option<String>,
+    pub policy: FlushPolicy,
+}
+
+impl FlushClass {
+    /// The classes of `[[flush.classes]]`, unset limits taken from
+    /// `[flush]`.
+    pub fn from_config(config: &FlushConfig) -> anyhow::Result<Vec<Self>> {
+        let compile = |pattern: &Option<String>| -> anyhow::Result<Option<Regex>> {
+            pattern
+                .as_deref()
+                .map(|p| Regex::new(p).map_err(|e| anyhow::anyhow!("flush class: invalid regex {}: {}", p, e)))
+                .transpose()
+        };
+        config
+            .classes
+            .iter()
+            .map(|class| {
+                if let Some(filter) = &class.topic
+                    && !rumqttc::valid_filter(filter)
+                {
+                    anyhow::bail!("flush class {}: invalid topic filter {}", class.name, filter);
+                }
+                let max_age_secs = class.max_age_secs.unwrap_or(config.max_age_secs);
+                Ok(Self {
+                    name: class.name.clone(),
+                    model: compile(&class.model)?,
+                    sensor_id: compile(&class.sensor_id)?,
+                    topic: class.topic.clone(),
+                    policy: FlushPolicy {
+                        max_rows: class.max_rows.unwrap_or(config.max_rows),
+                        max_bytes: class.max_bytes.unwrap_or(config.max_bytes),
+                        max_age: (max_age_secs > 0).then(|| Duration::from_secs(max_age_secs)),
+                    },
+                })
+            })
+            .collect()
+    }
+
+    fn matches(&self, topic: &str, model: Option<&str>, sensor_id: Option<&str>) -> bool {
+        let matches = |regex: &Option<Regex>, value: Option<&str>| match regex {
+            Some(regex) => value.is_some_and(|v| regex.is_match(v)),
+            None => true,
+        };
+        self.topic.as_ref().is_none_or(|filter| rumqttc::matches(topic, filter))
+            && matches(&self.model, model)
+            && matches(&self.sensor_id, sensor_id)
+    }
+}
+
+/// Which buffered entries a flush writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushScope {
+    /// Everything, e.g. at shutdown.
+    All,
+    /// Classes that reached a limit of their policy.
+    Due,
+    /// The periodic flush: everything except classes with their own age
+    /// limit that haven't reached a limit yet.
+    Periodic,
+}
+
+/// Entries not being flushed yet, per flush class.
+#[derive(Default)]
+struct Active {
+    /// Entries matching no class.
+    default: Pending,
+    classes: Vec<(FlushClass, Pending)>,
+}
+
+impl Active {
+    fn pending(&mut self, topic: &str, model: Option<&str>, sensor_id: Option<&str>) -> &mut Pending {
+        match self.classes.iter_mut().find(|(class, _)| class.matches(topic, model, sensor_id)) {
+            Some((_, pending)) => pending,
+            None => &mut self.default,
+        }
+    }
+}
+
+fn is_due(policy: &FlushPolicy, pending: &Pending) -> bool {
+    policy.is_due(pending.rows.len(), pending.bytes, pending.since.map(|t| t.elapsed()))
+}
+
+/// Approximate memory held by a buffered entry.
+fn row_bytes(row: &NormalizedRow) -> usize {
+    std::mem::size_of::<NormalizedRow>() + row.sensor_id.len() + row.model.len() + row.topic.len() + row.site.len() + row.probe.len()
+}
+
+fn raw_bytes(message: &RawMessage) -> usize {
+    std::mem::size_of::<RawMessage>()
+        + message.topic.len()
+        + message.sensor_id.as_ref().map_or(0, String::len)
+        + message.model.as_ref().map_or(0, String::len)
+        + message.raw_json.len()
+}
+
+fn rejected_bytes(message: &RejectedMessage) -> usize {
+    std::mem::size_of::<RejectedMessage>()
+        + message.topic.len()
+        + message.payload.len()
+        + message.reason.len()
+        + message.sensor_id.as_ref().map_or(0, String::len)
+        + message.model.as_ref().map_or(0, String::len)
+}
+
+/// Rows, raw and rejected messages waiting for the same flush.
+#[derive(Default)]
+struct Pending {
+    rows: Vec<NormalizedRow>,
+    raw: Vec<RawMessage>,
+    rejected: Vec<RejectedMessage>,
+    /// Approximate size of everything above.
+    bytes: usize,
+    /// When the oldest entry was added.
+    since: Option<Instant>,
+    /// When the messages behind `rows` were received, one per message.
+    received: Vec<Instant>,
+    /// Oldest WAL segment holding a message of these entries.
+    wal_segment: Option<u64>,
+}
+
+impl Pending {
+    fn is_empty(&self) -> bool {
+        self.rows.is_empty() && self.raw.is_empty() && self.rejected.is_empty()
+    }
+
+    fn added(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        self.since.get_or_insert_with(Instant::now);
+    }
+
+    /// Note the WAL segment of the message just added.
+    fn logged(&mut self, segment: Option<u64>) {
+        self.wal_segment = min_segment(self.wal_segment, segment);
+    }
+
+    /// Move everything from `other` in, keeping the older timestamp.
+    fn append(&mut self, other: &mut Pending) {
+        self.rows.append(&mut other.rows);
+        self.raw.append(&mut other.raw);
+        self.rejected.append(&mut other.rejected);
+        self.received.append(&mut other.received);
+        self.bytes += std::mem::take(&mut other.bytes);
+        self.since = match (self.since, other.since.take()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.wal_segment = min_segment(self.wal_segment, other.wal_segment.take());
+    }
+}
+
+fn min_segment(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+impl Sink for MqttBuffer {
+    fn name(&self) -> &'static str {
+        "duckdb"
+    }
+
+    fn push(&self, readings: &Readings) {
+        if readings.store {
+            MqttBuffer::push(self, readings.canonical.to_vec(), readings.received);
+        }
+    }
+}
+
+/// Entries taken out of the buffer without being written, see
+/// `MqttBuffer::take_unwritten`.
+#[derive(Default)]
+pub struct Unwritten {
+    pub rows: Vec<NormalizedRow>,
+    pub raw: Vec<RawMessage>,
+    pub rejected: Vec<RejectedMessage>,
+    /// A flush still held its entries, so they are not included.
+    pub in_flight: bool,
+}
+
+impl Unwritten {
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty() && self.raw.is_empty() && self.rejected.is_empty()
+    }
+}
+
+/// Double-buffered row store shared by the MQTT loop, the periodic flush
+/// task and the shutdown path.
+pub struct MqttBuffer {
+    /// Policy of the entries matching no flush class.
+    policy: Mutex<FlushPolicy>,
+    active: Mutex<Active>,
+    flushing: tokio::sync::Mutex<Pending>,
+    swaps_total: IntCounter,
+    flush_overlaps_total: IntCounter,
+    depth_rows: IntGauge,
+    depth_bytes: IntGauge,
+    flush_duration: Histogram,
+    flush_rows: Histogram,
+    append_duration: HistogramVec,
+    latency: Histogram,
+    wal: OnceLock<Arc<Wal>>,
+    /// WAL segment of the message being ingested, stamped on what it adds.
+    logged: Mutex<Option<u64>>,
+    /// End of the last flush that wrote anything.
+    last_flush: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl MqttBuffer {
+    /// Create an empty buffer and register its metrics in `registry`.
+    pub fn new(registry: &Registry, policy: FlushPolicy) -> anyhow::Result<Self> {
+        let swaps_total = IntCounter::new("ingest_buffer_swaps_total", "Active/flushing buffer swaps performed")?;
+        let flush_overlaps_total = IntCounter::new(
+            "ingest_flush_overlaps_total",
+            "Flush requests made while another flush was still in progress",
+        )?;
+        let depth_rows = IntGauge::new("ingest_buffer_rows", "Measurement rows buffered and not yet written to DuckDB")?;
+        let depth_bytes = IntGauge::new("ingest_buffer_bytes", "Approximate size of everything buffered and not yet written")?;
+        let flush_duration = Histogram::with_opts(HistogramOpts::new(
+            "ingest_flush_duration_seconds",
+            "Time taken by flushes that wrote to DuckDB",
+        ))?;
+        registry.register(Box::new(swaps_total.clone()))?;
+        registry.register(Box::new(flush_overlaps_total.clone()))?;
+        registry.register(Box::new(depth_rows.clone()))?;
+        registry.register(Box::new(depth_bytes.clone()))?;
+        let flush_rows = Histogram::with_opts(
+            HistogramOpts::new("ingest_flush_rows", "Measurement rows written per flush").buckets(exponential_buckets(1.0, 4.0, 10)?),
+        )?;
+        let append_duration = HistogramVec::new(
+            HistogramOpts::new("duckdb_append_duration_seconds", "Time taken by appends to DuckDB, including queueing on the DB worker"),
+            &["table"],
+        )?;
+        let latency = Histogram::with_opts(
+            HistogramOpts::new("ingest_latency_seconds", "Time from receiving an MQTT message to its rows being written to DuckDB")
+                .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0]),
+        )?;
+        registry.register(Box::new(flush_duration.clone()))?;
+        registry.register(Box::new(flush_rows.clone()))?;
+        registry.register(Box::new(append_duration.clone()))?;
+        registry.register(Box::new(latency.clone()))?;
+        Ok(Self {
+            policy: Mutex::new(policy),
+            active: Mutex::new(Active::default()),
+            flushing: tokio::sync::Mutex::new(Pending::default()),
+            swaps_total,
+            flush_overlaps_total,
+            depth_rows,
+            depth_bytes,
+            flush_duration,
+            flush_rows,
+            append_duration,
+            latency,
+            wal: OnceLock::new(),
+            logged: Mutex::new(None),
+            last_flush: Mutex::new(None),
+        })
+    }
+
+    /// Add the rows of a message received at `received` to the active
+    /// buffer. Never waits on a running flush.
+    pub fn push(&self, rows: Vec<NormalizedRow>, received: Instant) {
+        if rows.is_empty() {
+            return;
+        }
+        let bytes: usize = rows.iter().map(row_bytes).sum();
+        self.depth_rows.add(rows.len() as i64);
+        self.depth_bytes.add(bytes as i64);
+        let mut active = self.active.lock().unwrap();
+        let first = &rows[0];
+        let pending = active.pending(&first.topic, Some(&first.model), Some(&first.sensor_id));
+        pending.rows.extend(rows);
+        pending.received.push(received);
+        pending.added(bytes);
+        pending.logged(*self.logged.lock().unwrap());
+    }
+
+    /// Queue an original payload for `raw_messages`.
+    pub fn push_raw(&self, message: RawMessage) {
+        let bytes = raw_bytes(&message);
+        self.depth_bytes.add(bytes as i64);
+        let mut active = self.active.lock().unwrap();
+        let pending = active.pending(&message.topic, message.model.as_deref(), message.sensor_id.as_deref());
+        pending.raw.push(message);
+        pending.added(bytes);
+        pending.logged(*self.logged.lock().unwrap());
+    }
+
+    /// Queue a payload that failed normalization for `rejected_messages`.
+    pub fn push_rejected(&self, message: RejectedMessage) {
+        let bytes = rejected_bytes(&message);
+        self.depth_bytes.add(bytes as i64);
+        let mut active = self.active.lock().unwrap();
+        active.default.rejected.push(message);
+        active.default.added(bytes);
+        active.default.logged(*self.logged.lock().unwrap());
+    }
+
+    /// Log every message to `wal` from now on. Each flush starts a new
+    /// segment and deletes the ones nothing buffered comes from any more.
+    pub fn set_wal(&self, wal: Arc<Wal>) {
+        let _ = self.wal.set(wal);
+    }
+
+    pub fn wal(&self) -> Option<&Arc<Wal>> {
+        self.wal.get()
+    }
+
+    /// Append a message to the WAL, if there is one, before it is
+    /// normalized. What it adds to the buffer is kept in the log until
+    /// written.
+    pub fn log(&self, topic: &str, payload: &[u8]) {
+        if let Some(wal) = self.wal.get() {
+            *self.logged.lock().unwrap() = Some(wal.append(topic, payload));
+        }
+    }
+
+    /// Stamp what is added from now on with an existing segment, for
+    /// messages replayed from it; `None` once the replay is done.
+    pub fn replaying(&self, segment: Option<u64>) {
+        *self.logged.lock().unwrap() = segment;
+    }
+
+    /// Empty the WAL once everything buffered is written or spooled, so the
+    /// next start replays nothing.
+    pub fn clear_wal(&self) {
+        if let Some(wal) = self.wal.get() {
+            wal.clear();
+        }
+    }
+
+    /// Whether the active buffer has reached a limit of the flush policy.
+    pub fn policy(&self) -> FlushPolicy {
+        *self.policy.lock().unwrap()
+    }
+
+    /// Replace the flush policy; takes effect with the next message.
+    pub fn set_policy(&self, policy: FlushPolicy) {
+        *self.policy.lock().unwrap() = policy;
+    }
+
+    /// Replace the flush classes. Entries buffered for a class that no
+    /// longer exists move to the default one.
+    pub fn set_classes(&self, classes: Vec<FlushClass>) {
+        let mut active = self.active.lock().unwrap();
+        let mut old = std::mem::take(&mut active.classes);
+        active.classes = classes
+            .into_iter()
+            .map(|class| {
+                let pending = old
+                    .iter()
+                    .position(|(c, _)| c.name == class.name)
+                    .map(|i| old.swap_remove(i).1)
+                    .unwrap_or_default();
+                (class, pending)
+            })
+            .collect();
+        for (_, mut pending) in old {
+            active.default.append(&mut pending);
+        }
+    }
+
+    /// Rows and approximate bytes buffered and not yet written.
+    pub fn backlog(&self) -> (usize, usize) {
+        (self.depth_rows.get().max(0) as usize, self.depth_bytes.get().max(0) as usize)
+    }
+
+    /// When a flush last wrote to the database.
+    pub fn last_flush(&self) -> Option<DateTime<Utc>> {
+        *self.last_flush.lock().unwrap()
+    }
+
+    /// Whether any class has reached a limit of its policy.
+    pub fn is_due(&self) -> bool {
+        let active = self.active.lock().unwrap();
+        let policy = *self.policy.lock().unwrap();
+        is_due(&policy, &active.default) || active.classes.iter().any(|(class, pending)| is_due(&class.policy, pending))
+    }
+
+    /// Flush the due classes unless another flush is already running, in
+    /// which case the overlap is counted and `None` is returned. Used by the
+    /// MQTT loop so policy-triggered flushes never pile up.
+    pub async fn try_flush(&self, db: &DbHandle) -> Option<anyhow::Result<usize>> {
+        match self.flushing.try_lock() {
+            Ok(flushing) => Some(self.flush_locked(flushing, db, FlushScope::Due).instrument(debug_span!("flush")).await),
+            Err(_) => {
+                self.flush_overlaps_total.inc();
+                None
+            }
+        }
+    }
+
+    /// Flush everything, waiting for any in-flight flush to finish first.
+    /// Used at shutdown and on request, where the rows must not be skipped.
+    pub async fn flush(&self, db: &DbHandle) -> anyhow::Result<usize> {
+        self.flush_scope(db, FlushScope::All).await
+    }
+
+    /// Flush the entries `scope` selects, waiting for any in-flight flush.
+    pub async fn flush_scope(&self, db: &DbHandle, scope: FlushScope) -> anyhow::Result<usize> {
+        let flushing = match self.flushing.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                self.flush_overlaps_total.inc();
+                self.flushing.lock().await
+            }
+        };
+        self.flush_locked(flushing, db, scope).instrument(debug_span!("flush")).await
+    }
+
+    /// Remove everything not yet written: what a failed or interrupted flush
+    /// left on the flushing side and the active entries of every class.
+    /// Used at shutdown when the final flush failed, see `spool`. Does not
+    /// wait for a flush that is still running (e.g. the periodic one, stuck
+    /// on a locked database): its entries stay with it and `in_flight` is
+    /// set.
+    pub fn take_unwritten(&self) -> Unwritten {
+        let mut taken = Pending::default();
+        let flushing = self.flushing.try_lock();
+        let in_flight = flushing.is_err();
+        if let Ok(mut flushing) = flushing {
+            taken.append(&mut flushing);
+        }
+        {
+            let mut active = self.active.lock().unwrap();
+            let Active { default, classes } = &mut *active;
+            taken.append(default);
+            for (_, pending) in classes.iter_mut() {
+                taken.append(pending);
+            }
+        }
+        self.depth_rows.sub(taken.rows.len() as i64);
+        self.depth_bytes.sub(taken.bytes as i64);
+        Unwritten { rows: taken.rows, raw: taken.raw, rejected: taken.rejected, in_flight }
+    }
+
+    /// Move a class's entries to the flushing side.
+    fn take(&self, flushing: &mut Pending, pending: &mut Pending) {
+        if flushing.is_empty() {
+            std::mem::swap(pending, flushing);
+            self.swaps_total.inc();
+        } else {
+            // A previous flush failed and left rows behind (or another
+            // class was taken already); write them together.
+            flushing.append(pending);
+        }
+    }
+
+    async fn flush_locked(
+        &self,
+        mut flushing: tokio::sync::MutexGuard<'_, Pending>,
+        db: &DbHandle,
+        scope: FlushScope,
+    ) -> anyhow::Result<usize> {
+        {
+            let policy = *self.policy.lock().unwrap();
+            let mut active = self.active.lock().unwrap();
+            let Active { default, classes } = &mut *active;
+            if scope != FlushScope::Due || is_due(&policy, default) {
+                self.take(&mut flushing, default);
+            }
+            for (class, pending) in classes.iter_mut() {
+                let take = match scope {
+                    FlushScope::All => true,
+                    FlushScope::Periodic => class.policy.max_age.is_none() || is_due(&class.policy, pending),
+                    FlushScope::Due => is_due(&class.policy, pending),
+                };
+                if take {
+                    self.take(&mut flushing, pending);
+                }
+            }
+        }
+        if flushing.is_empty() {
+            return Ok(0);
+        }
+        // Messages logged from here on are in the new segment, apart
+        // from the one being ingested, which `logged` still points to.
+        if let Some(wal) = self.wal.get() {
+            wal.rotate();
+        }
+
+        let timer = self.flush_duration.start_timer();
+        let mut n = 0;
+        if !flushing.rows.is_empty() {
+            let batch = rows_to_record_batch(&flushing.rows)?;
+            n = self.append(db, "measurements", batch).await?;
+            let bytes: usize = flushing.rows.iter().map(row_bytes).sum();
+            let rows = flushing.rows.len();
+            self.written(&mut flushing, rows, bytes);
+            self.flush_rows.observe(rows as f64);
+            for received in flushing.received.drain(..) {
+                self.latency.observe(received.elapsed().as_secs_f64());
+            }
+            flushing.rows.clear();
+        }
+        if !flushing.raw.is_empty() {
+            let batch = raw_to_record_batch(&flushing.raw)?;
+            self.append(db, "raw_messages", batch).await?;
+            let bytes: usize = flushing.raw.iter().map(raw_bytes).sum();
+            self.written(&mut flushing, 0, bytes);
+            flushing.raw.clear();
+        }
+        if !flushing.rejected.is_empty() {
+            let batch = rejected_to_record_batch(&flushing.rejected)?;
+            self.append(db, "rejected_messages", batch).await?;
+            let bytes: usize = flushing.rejected.iter().map(rejected_bytes).sum();
+            self.written(&mut flushing, 0, bytes);
+            flushing.rejected.clear();
+        }
+        flushing.since = None;
+        flushing.wal_segment = None;
+        timer.observe_duration();
+        *self.last_flush.lock().unwrap() = Some(Utc::now());
+        self.truncate_wal();
+        Ok(n)
+    }
+
+    /// Delete the WAL segments older than anything still buffered.
+    fn truncate_wal(&self) {
+        let Some(wal) = self.wal.get() else { return };
+        let keep_from = {
+            let active = self.active.lock().unwrap();
+            let oldest = active.classes.iter().fold(active.default.wal_segment, |oldest, (_, p)| min_segment(oldest, p.wal_segment));
+            min_segment(oldest, *self.logged.lock().unwrap())
+        };
+        wal.truncate(keep_from.unwrap_or(u64::MAX));
+    }
+
+    async fn append(&self, db: &DbHandle, table: &str, batch: RecordBatch) -> anyhow::Result<usize> {
+        let _timer = self.append_duration.with_label_values(&[table]).start_timer();
+        let span = debug_span!("db_insert", table, rows = batch.num_rows());
+        db.append(table, batch).instrument(span).await
+    }
+
+    /// Account for entries that reached the database.
+    fn written(&self, flushing: &mut Pending, rows: usize, bytes: usize) {
+        flushing.bytes = flushing.bytes.saturating_sub(bytes);
+        self.depth_rows.sub(rows as i64);
+        self.depth_bytes.sub(bytes as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FlushClassConfig, MissedTicks, SentinelRule};
+
+    fn row(sensor_id: &str) -> NormalizedRow {
+        NormalizedRow {
+            timestamp: Utc::now().naive_utc(),
+            sensor_id: sensor_id.to_string(),
+            model: "Acurite-Tower".to_string(),
+            measurement_type: 1,
+            value: 21.5,
+            topic: "rtl_433/events".to_string(),
+            seq: 0,
+            site: String::new(),
+            probe: String::new(),
+        }
+    }
+
+    fn buffer(policy: FlushPolicy) -> MqttBuffer {
+        MqttBuffer::new(&Registry::new(), policy).unwrap()
+    }
+
+    #[test]
+    fn empty_buffer_is_never_due() {
+        let policy = FlushPolicy { max_rows: 0, max_bytes: 1, max_age: Some(Duration::ZERO) };
+        assert!(!policy.is_due(0, 0, None));
+    }
+
+    #[test]
+    fn row_limit() {
+        let policy = FlushPolicy { max_rows: 3, max_bytes: 0, max_age: None };
+        assert!(!policy.is_due(2, 10_000_000, Some(Duration::from_secs(3600))));
+        assert!(policy.is_due(3, 0, Some(Duration::ZERO)));
+    }
+
+    #[test]
+    fn byte_limit() {
+        let policy = FlushPolicy { max_rows: usize::MAX, max_bytes: 1000, max_age: None };
+        assert!(!policy.is_due(1, 999, Some(Duration::ZERO)));
+        assert!(policy.is_due(1, 1000, Some(Duration::ZERO)));
+        // Raw and rejected messages count towards the size without rows.
+        assert!(policy.is_due(0, 1000, Some(Duration::ZERO)));
+    }
+
+    #[test]
+    fn age_limit() {
+        let policy = FlushPolicy { max_rows: usize::MAX, max_bytes: 0, max_age: Some(Duration::from_secs(5)) };
+        assert!(!policy.is_due(1, 1, Some(Duration::from_secs(4))));
+        assert!(policy.is_due(1, 1, Some(Duration::from_secs(5))));
+    }
+
+    #[test]
+    fn from_config_disables_zero_age() {
+        let config = FlushConfig { max_rows: 10, max_bytes: 0, max_age_secs: 0, interval_secs: 30, missed_ticks: MissedTicks::Skip, classes: Vec::new() };
+        assert_eq!(FlushPolicy::from_config(&config), FlushPolicy { max_rows: 10, max_bytes: 0, max_age: None });
+        let config = FlushConfig { max_age_secs: 2, ..config };
+        assert_eq!(FlushPolicy::from_config(&config).max_age, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn buffer_reports_depth_and_due() {
+        let buffer = buffer(FlushPolicy { max_rows: 2, max_bytes: 0, max_age: None });
+        assert!(!buffer.is_due());
+        buffer.push(vec![row("1")], Instant::now());
+        assert!(!buffer.is_due());
+        assert_eq!(buffer.depth_rows.get(), 1);
+        buffer.push(vec![row("2")], Instant::now());
+        assert!(buffer.is_due());
+        assert_eq!(buffer.depth_rows.get(), 2);
+        assert_eq!(buffer.depth_bytes.get() as usize, 2 * row_bytes(&row("1")));
+    }
+
+    #[test]
+    fn buffer_byte_limit_counts_rejected_payloads() {
+        let buffer = buffer(FlushPolicy { max_rows: usize::MAX, max_bytes: 1024, max_age: None });
+        buffer.push_rejected(RejectedMessage {
+            received_at: Utc::now().naive_utc(),
+            topic: "rtl_433/events".to_string(),
+            payload: vec![b'x'; 2048],
+            encrypted: false,
+            reason: "invalid JSON".to_string(),
+            sensor_id: None,
+            model: None,
+        });
+        assert_eq!(buffer.depth_rows.get(), 0);
+        assert!(buffer.is_due());
+    }
+
+    #[test]
+    fn classes_are_buffered_separately() {
+        let buffer = buffer(FlushPolicy { max_rows: 2, max_bytes: 0, max_age: None });
+        let config = FlushConfig {
+            classes: vec![FlushClassConfig {
+                name: "power".to_string(),
+                model: Some("^Efergy".to_string()),
+                sensor_id: None,
+                topic: None,
+                max_rows: Some(1),
+                max_bytes: None,
+                max_age_secs: None,
+            }],
+            ..FlushConfig::default()
+        };
+        buffer.set_classes(FlushClass::from_config(&config).unwrap());
+        buffer.push(vec![row("1")], Instant::now());
+        assert!(!buffer.is_due());
+        let mut power = row("2");
+        power.model = "Efergy-e2CT".to_string();
+        buffer.push(vec![power], Instant::now());
+        assert!(buffer.is_due());
+        let active = buffer.active.lock().unwrap();
+        assert_eq!(active.default.rows.len(), 1);
+        assert_eq!(active.classes[0].1.rows.len(), 1);
+    }
+
+    #[test]
+    fn append_keeps_oldest_timestamp() {
+        let mut older = Pending::default();
+        older.added(10);
+        let first = older.since;
+        std::thread::sleep(Duration::from_millis(2));
+        let mut newer = Pending::default();
+        newer.added(5);
+        newer.append(&mut older);
+        assert_eq!(newer.since, first);
+        assert_eq!(newer.bytes, 15);
+        assert_eq!(older.bytes, 0);
+        assert!(older.since.is_none());
+    }
+
+    #[tokio::test]
+    async fn the_duckdb_sink_buffers_canonical_rows() {
+        let buffer = buffer(FlushPolicy { max_rows: usize::MAX, max_bytes: 0, max_age: None });
+        let canonical = vec![row("1")];
+        let mapped = vec![NormalizedRow { value: 70.7, ..row("1") }];
+        let mappings = std::collections::HashMap::new();
+        let readings = Readings { rows: &mapped, canonical: &canonical, received: Instant::now(), mappings: &mappings, store: true };
+        Sink::push(&buffer, &readings);
+        let unwritten = buffer.take_unwritten();
+        assert_eq!(unwritten.rows.iter().map(|r| r.value).collect::<Vec<_>>(), vec![21.5]);
+    }
+
+    #[tokio::test]
+    async fn take_unwritten_does_not_wait_for_a_running_flush() {
+        let buffer = buffer(FlushPolicy { max_rows: usize::MAX, max_bytes: 0, max_age: None });
+        let running = buffer.flushing.lock().await;
+        buffer.push(vec![row("1")], Instant::now());
+        let unwritten = buffer.take_unwritten();
+        assert!(unwritten.in_flight);
+        assert_eq!(unwritten.rows.len(), 1);
+        drop(running);
+        assert!(!buffer.take_unwritten().in_flight);
+    }
+
+    /// A normalizer reading `zigbee2mqtt/#` as Zigbee2MQTT, with `rules`
+    /// as `[sentinels]`.
+    fn normalizer(rules: &[(&str, &str, SentinelRule)]) -> Normalizer {
+        let mut config: BTreeMap<String, BTreeMap<String, SentinelRule>> = BTreeMap::new();
+        for (model, key, rule) in rules {
+            config.entry(model.to_string()).or_default().insert(key.to_string(), rule.clone());
+        }
+        let sentinels = Sentinels::new(&config, &Registry::new()).unwrap();
+        let formats = BTreeMap::from([("zigbee2mqtt/#".to_string(), PayloadFormat::Zigbee2mqtt)]);
+        Normalizer::new(&formats, &BTreeMap::new(), &[], sentinels).unwrap()
+    }
+
+    fn values(rows: &[NormalizedRow]) -> Vec<(&str, f64)> {
+        let mut values: Vec<_> = rows.iter().map(|r| (measurement_name(r.measurement_type).unwrap_or_default(), r.value)).collect();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        values
+    }
+
+    #[test]
+    fn sentinels_apply_to_rtl433_readings_and_probes() {
+        let dropped = SentinelRule { values: vec![-999.0], ..SentinelRule::default() };
+        let clamped = SentinelRule { min: Some(0.0), max: Some(100.0), clamp: true, ..SentinelRule::default() };
+        let normalizer = normalizer(&[("Acurite-Tower", "temperature_C", dropped), ("*", "humidity", clamped)]);
+        let payload = br#"{"model": "Acurite-Tower", "id": 1, "temperature_C": -999, "temperature_1_C": -999, "humidity": 104}"#;
+        assert_eq!(values(&normalizer.normalize("rtl_433/events", payload).unwrap()), vec![("humidity", 100.0)]);
+        // Rules of other models don't apply.
+        let payload = br#"{"model": "Oregon-THGR810", "id": 1, "temperature_C": -999}"#;
+        assert_eq!(values(&normalizer.normalize("rtl_433/events", payload).unwrap()), vec![("temperature_C", -999.0)]);
+    }
+
+    #[test]
+    fn sentinels_apply_to_zigbee2mqtt_readings_in_their_unit() {
+        let dropped = SentinelRule { values: vec![0.0], ..SentinelRule::default() };
+        let range = SentinelRule { min: Some(80.0), max: Some(110.0), ..SentinelRule::default() };
+        let normalizer = normalizer(&[("zigbee2mqtt", "humidity", dropped), ("*", "pressure_kPa", range)]);
+        let payload = br#"{"humidity": 0, "pressure": 1013, "temperature": 21.5}"#;
+        let rows = normalizer.normalize("zigbee2mqtt/balcony", payload).unwrap();
+        assert_eq!(values(&rows), vec![("pressure_kPa", 101.3), ("temperature_C", 21.5)]);
+        let rows = normalizer.normalize("zigbee2mqtt/balcony", br#"{"pressure": 5}"#).unwrap();
+        assert!(rows.is_empty());
+    }
+}
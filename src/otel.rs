@@ -0,0 +1,442 @@
+// OpenTelemetry export, built with the `otel` cargo feature
+// (`cargo build --release --features otel`). In a setup built around an
+// OpenTelemetry collector instead of a Prometheus server, the exporter
+// pushes its metrics, and traces of the ingest path, over OTLP:
+//
+//   [otel]
+//   endpoint = "http://otel-collector:4317"
+//   protocol = "grpc"          # or "http", usually on port 4318
+//   interval_secs = 60
+//
+// The metrics are the ones `/metrics` serves, which keeps working: every
+// `interval_secs` the Prometheus registry is gathered and sent as OTLP
+// cumulative sums (counters), gauges, histograms and summaries, under the
+// same names and with the labels as attributes, so dashboards move between
+// the two without renaming. Traces are the `tracing` spans of the ingest
+// path: `ingest` for each MQTT message with `normalize` below it, and
+// `flush` with a `db_insert` per table written. A flush is part of the
+// trace of the message that made the buffer due, which thus spans receive
+// → normalize → insert; flushes on the timer or on request are traces of
+// their own. Spans are exported whatever the log filter, in batches; what
+// is pending at shutdown is sent before exiting, after a last metrics
+// export. gRPC endpoints may use `https://`, with the webpki roots.
+use crate::config::{OtelConfig, OtelProtocol};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{WithExportConfig, WithHttpConfig, WithTonicConfig};
+use opentelemetry_proto::tonic::collector::metrics::v1::{metrics_service_client::MetricsServiceClient, ExportMetricsServiceRequest};
+use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::{
+    metric::Data, number_data_point, summary_data_point::ValueAtQuantile, AggregationTemporality, Gauge, Histogram,
+    HistogramDataPoint, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics, Sum, Summary, SummaryDataPoint,
+};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::Registry;
+use prost::Message;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tonic::transport::{Channel, ClientTlsConfig};
+use tracing::{info, warn, Level, Subscriber};
+use tracing_subscriber::{filter::filter_fn, registry::LookupSpan, Layer};
+
+const SCOPE: &str = env!("CARGO_PKG_NAME");
+
+/// Set by `tracer`, shut down (which sends the pending spans) by `shutdown`.
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// The tracer for `logging::init_traced`; `None` unless an endpoint is set
+/// and traces are enabled. Call within the runtime: the gRPC channel is
+/// driven by it.
+pub fn tracer(config: &OtelConfig) -> anyhow::Result<Option<SdkTracer>> {
+    let Some(endpoint) = config.endpoint.as_deref().filter(|_| config.traces) else { return Ok(None) };
+    let timeout = Duration::from_secs(config.timeout_secs.max(1));
+    let exporter = match config.protocol {
+        OtelProtocol::Grpc => opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_channel(channel(endpoint, timeout)?)
+            .with_metadata(metadata(config)?)
+            .with_timeout(timeout)
+            .build()?,
+        OtelProtocol::Http => opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(signal_url(endpoint, "traces"))
+            .with_headers(config.headers.clone())
+            .with_timeout(timeout)
+            .build()?,
+    };
+    let resource = opentelemetry_sdk::Resource::builder()
+        .with_service_name(config.service_name.clone())
+        .with_attribute(opentelemetry::KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+        .build();
+    let provider = SdkTracerProvider::builder().with_batch_exporter(exporter).with_resource(resource).build();
+    let tracer = provider.tracer(SCOPE);
+    let _ = PROVIDER.set(provider);
+    Ok(Some(tracer))
+}
+
+/// The layer sending this crate's spans to `tracer`, with the events of
+/// level info and above as span events. Per-message debug events (the
+/// payload) are left out, and so are the spans of dependencies, which
+/// include the exporting itself.
+pub fn layer<S>(tracer: SdkTracer) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let crate_name = env!("CARGO_CRATE_NAME");
+    tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(filter_fn(move |meta| meta.target().starts_with(crate_name) && (meta.is_span() || *meta.level() <= Level::INFO)))
+}
+
+enum Transport {
+    Grpc { client: Box<MetricsServiceClient<Channel>>, metadata: MetadataMap },
+    Http { client: reqwest::Client, url: String },
+}
+
+/// Periodic export of the registry's metrics.
+pub struct MetricsExporter {
+    registry: Arc<Registry>,
+    transport: Transport,
+    interval: Duration,
+    resource: Resource,
+    /// Start time of the cumulative series, in Unix nanoseconds.
+    started: u64,
+}
+
+impl MetricsExporter {
+    /// `None` unless an endpoint is set.
+    pub fn new(config: &OtelConfig, registry: Arc<Registry>) -> anyhow::Result<Option<Self>> {
+        let Some(endpoint) = config.endpoint.as_deref() else { return Ok(None) };
+        let timeout = Duration::from_secs(config.timeout_secs.max(1));
+        let transport = match config.protocol {
+            OtelProtocol::Grpc => Transport::Grpc {
+                client: Box::new(MetricsServiceClient::new(channel(endpoint, timeout)?)),
+                metadata: metadata(config)?,
+            },
+            OtelProtocol::Http => {
+                let mut headers = reqwest::header::HeaderMap::new();
+                for (name, value) in &config.headers {
+                    headers.insert(
+                        reqwest::header::HeaderName::from_bytes(name.as_bytes())?,
+                        reqwest::header::HeaderValue::from_str(value)?,
+                    );
+                }
+                let client = reqwest::Client::builder().timeout(timeout).default_headers(headers).build()?;
+                Transport::Http { client, url: signal_url(endpoint, "metrics") }
+            }
+        };
+        let resource = Resource {
+            attributes: vec![
+                attribute("service.name", &config.service_name),
+                attribute("service.version", env!("CARGO_PKG_VERSION")),
+            ],
+            ..Default::default()
+        };
+        Ok(Some(Self {
+            registry,
+            transport,
+            interval: Duration::from_secs(config.interval_secs.max(1)),
+            resource,
+            started: unix_nanos(SystemTime::now()),
+        }))
+    }
+
+    /// Export every `interval_secs`. A failed export is logged; the next
+    /// one sends the current values, so nothing is queued.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.export().await {
+                warn!("OTLP metrics export failed: {}", e);
+            }
+        }
+    }
+
+    pub async fn export(&self) -> anyhow::Result<()> {
+        let request = self.request(&self.registry.gather());
+        match &self.transport {
+            Transport::Grpc { client, metadata } => {
+                let mut request = tonic::Request::new(request);
+                *request.metadata_mut() = metadata.clone();
+                client.as_ref().clone().export(request).await?;
+            }
+            Transport::Http { client, url } => {
+                let response = client
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+                    .body(request.encode_to_vec())
+                    .send()
+                    .await?;
+                if !response.status().is_success() {
+                    let status = response.status();
+                    anyhow::bail!("{} answered {}", url, status);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&self, families: &[MetricFamily]) -> ExportMetricsServiceRequest {
+        let now = unix_nanos(SystemTime::now());
+        let metrics = families.iter().filter_map(|family| metric(family, self.started, now)).collect();
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(self.resource.clone()),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(InstrumentationScope {
+                        name: SCOPE.to_string(),
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                        ..Default::default()
+                    }),
+                    metrics,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        }
+    }
+}
+
+/// Last metrics export and the pending spans, at shutdown.
+pub async fn shutdown(metrics: Option<&MetricsExporter>) {
+    if let Some(metrics) = metrics
+        && let Err(e) = metrics.export().await
+    {
+        warn!("Final OTLP metrics export failed: {}", e);
+    }
+    if let Some(provider) = PROVIDER.get() {
+        let provider = provider.clone();
+        // Blocks until the batch is sent (or times out).
+        match tokio::task::spawn_blocking(move || provider.shutdown()).await {
+            Ok(Ok(())) => info!("Stopped the OTLP span export"),
+            Ok(Err(e)) => warn!("Sending the pending OTLP spans failed: {}", e),
+            Err(e) => warn!("Sending the pending OTLP spans failed: {}", e),
+        }
+    }
+}
+
+/// A family as OTLP metric, with a data point per label set.
+fn metric(family: &MetricFamily, start: u64, now: u64) -> Option<Metric> {
+    let attributes = |m: &prometheus::proto::Metric| m.get_label().iter().map(|l| attribute(l.name(), l.value())).collect();
+    let number = |m: &prometheus::proto::Metric, value: f64| NumberDataPoint {
+        attributes: attributes(m),
+        start_time_unix_nano: start,
+        time_unix_nano: now,
+        value: Some(number_data_point::Value::AsDouble(value)),
+        ..Default::default()
+    };
+    let metrics = family.get_metric();
+    let data = match family.get_field_type() {
+        MetricType::COUNTER => Data::Sum(Sum {
+            data_points: metrics.iter().map(|m| number(m, m.get_counter().value())).collect(),
+            aggregation_temporality: AggregationTemporality::Cumulative as i32,
+            is_monotonic: true,
+        }),
+        MetricType::GAUGE => Data::Gauge(Gauge { data_points: metrics.iter().map(|m| number(m, m.get_gauge().value())).collect() }),
+        // The registry has none, and their values are not exposed.
+        MetricType::UNTYPED => return None,
+        MetricType::HISTOGRAM => Data::Histogram(Histogram {
+            data_points: metrics
+                .iter()
+                .map(|m| {
+                    let h = m.get_histogram();
+                    // Prometheus buckets are cumulative and leave out +Inf;
+                    // OTLP counts per bucket, the last one up to +Inf.
+                    let mut bucket_counts = Vec::new();
+                    let mut below = 0;
+                    for bucket in h.get_bucket() {
+                        bucket_counts.push(bucket.cumulative_count() - below);
+                        below = bucket.cumulative_count();
+                    }
+                    bucket_counts.push(h.get_sample_count().saturating_sub(below));
+                    HistogramDataPoint {
+                        attributes: attributes(m),
+                        start_time_unix_nano: start,
+                        time_unix_nano: now,
+                        count: h.get_sample_count(),
+                        sum: Some(h.get_sample_sum()),
+                        bucket_counts,
+                        explicit_bounds: h.get_bucket().iter().map(|b| b.upper_bound()).collect(),
+                        ..Default::default()
+                    }
+                })
+                .collect(),
+            aggregation_temporality: AggregationTemporality::Cumulative as i32,
+        }),
+        MetricType::SUMMARY => Data::Summary(Summary {
+            data_points: metrics
+                .iter()
+                .map(|m| {
+                    let s = m.get_summary();
+                    SummaryDataPoint {
+                        attributes: attributes(m),
+                        start_time_unix_nano: start,
+                        time_unix_nano: now,
+                        count: s.sample_count(),
+                        sum: s.sample_sum(),
+                        quantile_values: s
+                            .get_quantile()
+                            .iter()
+                            .map(|q| ValueAtQuantile { quantile: q.quantile(), value: q.value() })
+                            .collect(),
+                        ..Default::default()
+                    }
+                })
+                .collect(),
+        }),
+    };
+    Some(Metric { name: family.name().to_string(), description: family.help().to_string(), data: Some(data), ..Default::default() })
+}
+
+fn attribute(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue { value: Some(any_value::Value::StringValue(value.to_string())) }),
+        ..Default::default()
+    }
+}
+
+/// A lazily connecting channel, reconnecting after errors.
+fn channel(endpoint: &str, timeout: Duration) -> anyhow::Result<Channel> {
+    let mut channel = Channel::from_shared(endpoint.to_string())
+        .map_err(|e| anyhow::anyhow!("invalid otel endpoint {}: {}", endpoint, e))?
+        .timeout(timeout);
+    if endpoint.starts_with("https://") {
+        channel = channel.tls_config(ClientTlsConfig::new().with_webpki_roots())?;
+    }
+    Ok(channel.connect_lazy())
+}
+
+fn metadata(config: &OtelConfig) -> anyhow::Result<MetadataMap> {
+    let mut metadata = MetadataMap::new();
+    for (name, value) in &config.headers {
+        let key = MetadataKey::from_bytes(name.to_lowercase().as_bytes())
+            .map_err(|_| anyhow::anyhow!("invalid otel header name {}", name))?;
+        let value = MetadataValue::try_from(value.as_str()).map_err(|_| anyhow::anyhow!("invalid value of otel header {}", name))?;
+        metadata.insert(key, value);
+    }
+    Ok(metadata)
+}
+
+/// `endpoint` with the OTLP/HTTP path of `signal` (`metrics`, `traces`).
+fn signal_url(endpoint: &str, signal: &str) -> String {
+    format!("{}/v1/{}", endpoint.trim_end_matches('/'), signal)
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts};
+
+    fn number(value: f64, labels: &[(&str, &str)]) -> NumberDataPoint {
+        NumberDataPoint {
+            attributes: labels.iter().map(|(k, v)| attribute(k, v)).collect(),
+            start_time_unix_nano: 1,
+            time_unix_nano: 2,
+            value: Some(number_data_point::Value::AsDouble(value)),
+            ..Default::default()
+        }
+    }
+
+    fn histogram(count: u64, sum: f64, bucket_counts: Vec<u64>, explicit_bounds: Vec<f64>) -> HistogramDataPoint {
+        HistogramDataPoint {
+            attributes: vec![attribute("table", "measurements")],
+            start_time_unix_nano: 1,
+            time_unix_nano: 2,
+            count,
+            sum: Some(sum),
+            bucket_counts,
+            explicit_bounds,
+            ..Default::default()
+        }
+    }
+
+    fn summary() -> MetricFamily {
+        let mut quantile = prometheus::proto::Quantile::default();
+        quantile.set_quantile(0.5);
+        quantile.set_value(0.25);
+        let mut summary = prometheus::proto::Summary::default();
+        summary.set_sample_count(3);
+        summary.set_sample_sum(1.5);
+        summary.set_quantile(vec![quantile]);
+        let mut metric = prometheus::proto::Metric::default();
+        metric.set_summary(summary);
+        let mut family = MetricFamily::default();
+        family.set_name("request_seconds".to_string());
+        family.set_help("Requests".to_string());
+        family.set_field_type(MetricType::SUMMARY);
+        family.set_metric(vec![metric]);
+        family
+    }
+
+    #[test]
+    fn prometheus_families_become_cumulative_otlp_metrics() {
+        let registry = Registry::new();
+        let messages = IntCounterVec::new(Opts::new("messages_total", "Messages"), &["topic"]).unwrap();
+        let depth = IntGauge::new("buffer_rows", "Rows").unwrap();
+        let flushes =
+            HistogramVec::new(HistogramOpts::new("flush_seconds", "Flushes").buckets(vec![0.1, 1.0, 10.0]), &["table"]).unwrap();
+        for collector in [Box::new(messages.clone()) as Box<dyn prometheus::core::Collector>, Box::new(depth.clone()), Box::new(flushes.clone())] {
+            registry.register(collector).unwrap();
+        }
+        messages.with_label_values(&["rtl_433/events"]).inc_by(3);
+        depth.set(7);
+        let flush = flushes.with_label_values(&["measurements"]);
+        for seconds in [0.05, 0.5, 0.7, 20.0] {
+            flush.observe(seconds);
+        }
+        let families = registry.gather();
+        let family = |name: &str| families.iter().find(|f| f.name() == name).unwrap().clone();
+
+        let cases = [
+            (
+                family("messages_total"),
+                Data::Sum(Sum {
+                    data_points: vec![number(3.0, &[("topic", "rtl_433/events")])],
+                    aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                    is_monotonic: true,
+                }),
+            ),
+            (family("buffer_rows"), Data::Gauge(Gauge { data_points: vec![number(7.0, &[])] })),
+            (
+                // Cumulative buckets (1, 3, 3) of 4 samples: one per bucket
+                // up to 1s, none up to 10s and the last above every bound.
+                family("flush_seconds"),
+                Data::Histogram(Histogram {
+                    data_points: vec![histogram(4, 21.25, vec![1, 2, 0, 1], vec![0.1, 1.0, 10.0])],
+                    aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                }),
+            ),
+            (
+                summary(),
+                Data::Summary(Summary {
+                    data_points: vec![SummaryDataPoint {
+                        start_time_unix_nano: 1,
+                        time_unix_nano: 2,
+                        count: 3,
+                        sum: 1.5,
+                        quantile_values: vec![ValueAtQuantile { quantile: 0.5, value: 0.25 }],
+                        ..Default::default()
+                    }],
+                }),
+            ),
+        ];
+        for (family, expected) in cases {
+            let metric = metric(&family, 1, 2).unwrap();
+            assert_eq!((metric.name.as_str(), metric.description.as_str()), (family.name(), family.help()));
+            assert_eq!(metric.data, Some(expected), "{}", family.name());
+        }
+
+        let mut untyped = MetricFamily::default();
+        untyped.set_field_type(MetricType::UNTYPED);
+        assert!(metric(&untyped, 1, 2).is_none());
+    }
+}
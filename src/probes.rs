@@ -0,0 +1,153 @@
+// Several measurements of the same type in one message. Dual-probe sensors
+// (indoor/outdoor thermometers, pool and BBQ probes) report
+// `temperature_1_C` and `temperature_2_C` instead of `temperature_C`. Key
+// patterns map such payload keys to a measurement, the first capture
+// group naming the probe:
+//
+//   [[probes]]
+//   pattern = "soil_moisture_(\\d+)"
+//   measurement = "moisture"
+//
+// `temperature_<n>_C` and `humidity_<n>` are recognized without
+// configuration. Patterns match whole keys and are tried in order after
+// the built-in ones; keys that are measurements themselves are never
+// probes. Probe rows keep the sensor's id and carry the probe in their
+// `probe` column, which is part of the series key next to `site`: dedup,
+// the latest readings, rollups and queries keep the probes apart, and the
+// gauges, remote write and InfluxDB get a `probe` label (empty, so
+// dropped, for the sensor's own readings). The sensor's mapping names and
+// locates all its probes. The sentinel rules of the measurement apply, and
+// probe rows are added after the model decoders ran, so `channel_id`
+// yields sensor `1234-A` with probe `1`.
+use crate::config::ProbeRule;
+use crate::models::Message;
+use crate::mqtt_buffer::{measurement_code, measurement_types, NormalizedRow};
+use crate::sentinels::Sentinels;
+use regex_lite::Regex;
+
+/// Patterns recognized without configuration, as `(pattern, measurement)`.
+const BUILTIN_PROBES: &[(&str, &str)] = &[(r"temperature_(\d+)_C", "temperature_C"), (r"humidity_(\d+)", "humidity")];
+
+struct Probe {
+    pattern: Regex,
+    measurement: String,
+}
+
+pub struct Probes {
+    probes: Vec<Probe>,
+}
+
+impl Default for Probes {
+    /// The built-in patterns only.
+    fn default() -> Self {
+        Self::new(&[]).expect("built-in probe patterns are valid")
+    }
+}
+
+impl Probes {
+    pub fn new(config: &[ProbeRule]) -> anyhow::Result<Self> {
+        let builtin = BUILTIN_PROBES.iter().map(|(pattern, measurement)| (pattern.to_string(), measurement.to_string()));
+        let configured = config.iter().map(|rule| (rule.pattern.clone(), rule.measurement.clone()));
+        let probes = builtin
+            .chain(configured)
+            .map(|(pattern, measurement)| {
+                let regex = Regex::new(&format!("^(?:{})$", pattern))
+                    .map_err(|e| anyhow::anyhow!("probes: invalid pattern {}: {}", pattern, e))?;
+                if regex.captures_len() < 2 {
+                    anyhow::bail!("probes: pattern {} has no capture group for the probe", pattern);
+                }
+                if measurement_code(&measurement).is_none() {
+                    anyhow::bail!("probes: unknown measurement {} for pattern {}", measurement, pattern);
+                }
+                Ok(Probe { pattern: regex, measurement })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { probes })
+    }
+
+    /// Add a row for every payload key that is a probe of a measurement.
+    pub fn decode(&self, message: &mut Message, sentinels: &Sentinels) {
+        for (key, value) in message.payload {
+            let Some(value) = value.as_f64() else { continue };
+            if measurement_code(key).is_some() {
+                continue;
+            }
+            let Some((probe, measurement)) = self.probes.iter().find_map(|p| {
+                let probe = p.pattern.captures(key)?.get(1)?.as_str();
+                Some((probe, &p.measurement))
+            }) else {
+                continue;
+            };
+            let Some(t) = measurement_types().iter().find(|t| t.key == *measurement) else { continue };
+            let Some(value) = sentinels.check(&message.base.model, &t.key, value) else { continue };
+            message.rows.push(NormalizedRow {
+                measurement_type: t.code,
+                value: value * t.scale + t.offset,
+                seq: 0,
+                probe: probe.to_string(),
+                ..message.base.clone()
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt_buffer::{measurement_code, Normalizer};
+    use std::collections::BTreeMap;
+
+    /// `(probe, measurement type, value)` of the rows of a payload.
+    fn readings(rows: &[NormalizedRow]) -> Vec<(&str, u8, f64)> {
+        let mut readings: Vec<_> = rows.iter().map(|r| (r.probe.as_str(), r.measurement_type, r.value)).collect();
+        readings.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        readings
+    }
+
+    #[test]
+    fn builtin_patterns_keep_the_sensor_id_and_set_the_probe() {
+        let payload = br#"{"model": "Inkbird-ITH20R", "id": 42, "temperature_C": 20.0,
+            "temperature_1_C": 21.5, "temperature_2_C": 4.0, "humidity_1": 40.0, "temperature_x_C": 9.0}"#;
+        let normalizer = Normalizer::new(&BTreeMap::new(), &BTreeMap::new(), &[], Sentinels::default()).unwrap();
+        let rows = normalizer.normalize("rtl_433/events", payload).unwrap();
+        assert!(rows.iter().all(|r| r.sensor_id == "42"));
+        let (temperature, humidity) = (measurement_code("temperature_C").unwrap(), measurement_code("humidity").unwrap());
+        assert_eq!(
+            readings(&rows),
+            vec![("", temperature, 20.0), ("1", temperature, 21.5), ("1", humidity, 40.0), ("2", temperature, 4.0)]
+        );
+    }
+
+    #[test]
+    fn configured_patterns_follow_the_builtin_ones() {
+        let rule = ProbeRule { pattern: r"soil_moisture_(\d+)".to_string(), measurement: "humidity".to_string() };
+        let probes = Probes::new(&[rule]).unwrap();
+        let payload = serde_json::json!({"soil_moisture_3": 55.0, "humidity": 60.0});
+        let mut message = Message {
+            payload: payload.as_object().unwrap(),
+            base: NormalizedRow {
+                timestamp: chrono::NaiveDateTime::default(),
+                sensor_id: "7".to_string(),
+                model: "Fineoffset-WH51".to_string(),
+                measurement_type: 0,
+                value: 0.0,
+                topic: String::new(),
+                seq: 0,
+                site: String::new(),
+                probe: String::new(),
+            },
+            rows: Vec::new(),
+        };
+        probes.decode(&mut message, &Sentinels::default());
+        // `humidity` is a measurement of its own, not a probe.
+        assert_eq!(readings(&message.rows), vec![("3", measurement_code("humidity").unwrap(), 55.0)]);
+    }
+
+    #[test]
+    fn patterns_need_a_capture_group_and_a_known_measurement() {
+        let rule = |pattern: &str, measurement: &str| ProbeRule { pattern: pattern.to_string(), measurement: measurement.to_string() };
+        assert!(Probes::new(&[rule("soil_moisture", "humidity")]).is_err());
+        assert!(Probes::new(&[rule("soil_(\\d+)", "nope")]).is_err());
+        assert!(Probes::new(&[rule("soil_(", "humidity")]).is_err());
+    }
+}
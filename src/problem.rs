@@ -0,0 +1,179 @@
+// Error responses of the HTTP API. Every failure is answered with an
+// RFC 7807 `application/problem+json` body carrying a machine-readable
+// `code` next to the standard members, e.g.
+//
+//   {"type": "about:blank", "title": "Not Found", "status": 404,
+//    "code": "not_found", "detail": "no job 1f2e..."}
+//
+// Validation failures add `errors`, one `{field, message}` per rejected
+// field, and every problem carries the `request_id` of its request (see
+// `request_id`) to quote when reporting it. Handlers return `Problem`
+// directly; the `problem_json` middleware converts the plain-text errors
+// produced elsewhere (extractor rejections, authentication, unknown
+// routes) so clients only have to handle one shape.
+// Codes: `validation_error`, `unauthorized`, `forbidden`, `not_found`,
+// `method_not_allowed`, `conflict`, `gone`, `payload_too_large`,
+// `unsupported_media_type`, `quota_exceeded`, `busy`, `not_live`,
+// `not_ready`, `db_unavailable`, `db_busy` (`503`, the DB worker's queue
+// is full; retry later), `db_timeout`, `db_error`, `delivery_failed` and
+// `internal_error`.
+use crate::db::{self, DbHandle, DbStatus};
+use axum::{
+    body::Body,
+    http::{header::CONTENT_TYPE, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::future::Future;
+use std::time::Duration;
+
+pub const CONTENT_TYPE_PROBLEM: &str = "application/problem+json";
+
+/// How long a read-only query may take before the request gives up with
+/// `db_timeout`. The worker then interrupts the query, see `db::abandonable`.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest plain-text error body `problem_json` takes over as `detail`.
+const MAX_DETAIL_BYTES: usize = 64 * 1024;
+
+/// One rejected field of a request.
+#[derive(Clone, Debug, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self { field: field.into(), message: message.into() }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    #[serde(serialize_with = "serialize_status")]
+    status: StatusCode,
+    code: &'static str,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    detail: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<Box<str>>,
+}
+
+fn serialize_status<S: serde::Serializer>(status: &StatusCode, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u16(status.as_u16())
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, code: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status,
+            code,
+            detail: detail.into(),
+            errors: Vec::new(),
+            request_id: crate::request_id::current().map(String::into_boxed_str),
+        }
+    }
+
+    /// `400 validation_error`.
+    pub fn invalid(detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "validation_error", detail)
+    }
+
+    /// `400 validation_error` listing the rejected fields.
+    pub fn invalid_fields(detail: impl Into<String>, errors: Vec<FieldError>) -> Self {
+        Self::invalid(detail).with_errors(errors)
+    }
+
+    /// The problem with `errors` listing what caused it.
+    pub fn with_errors(self, errors: Vec<FieldError>) -> Self {
+        Self { errors, ..self }
+    }
+
+    pub fn not_found(detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", detail)
+    }
+
+    pub fn internal(e: impl std::fmt::Display) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", e.to_string())
+    }
+
+    /// A failed database command: `503 db_unavailable` while the database
+    /// is not open, `503 db_busy` while its queue is full, `500 db_error`
+    /// otherwise.
+    pub fn db(db: &DbHandle, e: impl std::fmt::Display) -> Self {
+        match db.status() {
+            DbStatus::Ready if db.is_saturated() => Self::new(StatusCode::SERVICE_UNAVAILABLE, "db_busy", e.to_string()),
+            DbStatus::Ready => Self::new(StatusCode::INTERNAL_SERVER_ERROR, "db_error", e.to_string()),
+            DbStatus::Opening | DbStatus::Failed(_) => Self::new(StatusCode::SERVICE_UNAVAILABLE, "db_unavailable", e.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let body = serde_json::to_vec(&self).unwrap_or_default();
+        (self.status, [(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_PROBLEM))], body).into_response()
+    }
+}
+
+/// Run a read-only database query, giving up after `QUERY_TIMEOUT`.
+pub async fn query<T>(db: &DbHandle, query: impl Future<Output = anyhow::Result<T>>) -> Result<T, Problem> {
+    match tokio::time::timeout(QUERY_TIMEOUT, db::abandonable(query)).await {
+        Ok(result) => result.map_err(|e| Problem::db(db, e)),
+        Err(_) => Err(Problem::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            "db_timeout",
+            format!("query did not finish within {}s; narrow the range or use /api/jobs/query", QUERY_TIMEOUT.as_secs()),
+        )),
+    }
+}
+
+/// Code for an error status that was not produced as a `Problem`.
+fn code_for(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => "validation_error",
+        StatusCode::UNAUTHORIZED => "unauthorized",
+        StatusCode::FORBIDDEN => "forbidden",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::METHOD_NOT_ALLOWED => "method_not_allowed",
+        StatusCode::CONFLICT => "conflict",
+        StatusCode::GONE => "gone",
+        StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_media_type",
+        StatusCode::SERVICE_UNAVAILABLE => "busy",
+        _ => "internal_error",
+    }
+}
+
+/// Middleware turning error responses that are not problem documents yet
+/// into one, with the original body as `detail`. Headers such as
+/// `WWW-Authenticate` are kept.
+pub async fn problem_json(req: Request<Body>, next: Next) -> Response {
+    let response = next.run(req).await;
+    let status = response.status();
+    let is_problem = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(CONTENT_TYPE_PROBLEM.as_bytes()));
+    if !(status.is_client_error() || status.is_server_error()) || is_problem {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let detail = match axum::body::to_bytes(body, MAX_DETAIL_BYTES).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).trim().to_string(),
+        Err(_) => String::new(),
+    };
+    let problem = Problem::new(status, code_for(status), detail).into_response();
+    parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+    parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_PROBLEM));
+    Response::from_parts(parts, problem.into_body())
+}
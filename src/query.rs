@@ -0,0 +1,102 @@
+// Local inspection of the database without the duckdb CLI. `query` opens
+// the configured database file read-only, runs one SQL statement and prints
+// the result as an aligned table (default), CSV or a JSON array of objects
+// (one per line, columns in query order):
+//
+// Run with: `rust-to-mqtt-prometheus-exporter query [--format table|csv|json] "SELECT ..."`
+//
+// DuckDB lets only one process open a database file that is being written,
+// so against a running exporter this fails with the lock error; query a copy
+// taken after `POST /api/admin/flush` instead. Logs go to stderr so CSV and
+// JSON output can be piped.
+use crate::config::Config;
+use crate::db::{self, json_value};
+use duckdb::types::Value;
+use serde_json::Value as Json;
+use std::io::Write;
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum OutputFormat {
+    Table,
+    Csv,
+    Json,
+}
+
+/// Options of the `query` command.
+#[derive(clap::Args)]
+pub struct Args {
+    /// Output format.
+    #[arg(long, value_enum, default_value = "table")]
+    format: OutputFormat,
+    /// One SQL statement.
+    sql: String,
+}
+
+/// Entry point for the `query` command.
+pub async fn run_cli(args: Args) -> anyhow::Result<()> {
+    let Args { format, sql } = args;
+    let mut config = Config::load()?;
+    config.logging.stderr = true;
+    crate::logging::init(&config.logging)?;
+    let conn = db::open_read_only(&config.duckdb)?;
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query([])?;
+    let columns = rows.as_ref().map(|stmt| stmt.column_names()).unwrap_or_default();
+    let mut records = Vec::new();
+    while let Some(row) = rows.next()? {
+        let record: Vec<Json> =
+            (0..columns.len()).map(|i| row.get::<_, Value>(i).map(json_value)).collect::<Result<_, _>>()?;
+        records.push(record);
+    }
+    let mut out = std::io::stdout().lock();
+    match format {
+        OutputFormat::Table => write_table(&mut out, &columns, &records)?,
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(&mut out);
+            writer.write_record(&columns)?;
+            for record in &records {
+                writer.write_record(record.iter().map(cell))?;
+            }
+            writer.flush()?;
+        }
+        OutputFormat::Json => {
+            // Written by hand to keep the columns in query order.
+            writeln!(out, "[")?;
+            for (i, record) in records.iter().enumerate() {
+                let fields: Vec<String> =
+                    columns.iter().zip(record).map(|(name, value)| format!("{}: {}", Json::from(name.as_str()), value)).collect();
+                writeln!(out, "  {{{}}}{}", fields.join(", "), if i + 1 < records.len() { "," } else { "" })?;
+            }
+            writeln!(out, "]")?;
+        }
+    }
+    Ok(())
+}
+
+/// A value as table or CSV cell: strings without quotes, NULL empty.
+fn cell(value: &Json) -> String {
+    match value {
+        Json::Null => String::new(),
+        Json::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn write_table(out: &mut impl Write, columns: &[String], records: &[Vec<Json>]) -> std::io::Result<()> {
+    let cells: Vec<Vec<String>> = records.iter().map(|record| record.iter().map(cell).collect()).collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, name)| cells.iter().map(|row| row[i].chars().count()).chain([name.chars().count()]).max().unwrap_or(0))
+        .collect();
+    let line = |out: &mut dyn Write, row: &[String]| {
+        let padded: Vec<String> = row.iter().zip(&widths).map(|(c, w)| format!("{:<w$}", c, w = *w)).collect();
+        writeln!(out, "{}", padded.join(" | ").trim_end())
+    };
+    line(out, columns)?;
+    writeln!(out, "{}", widths.iter().map(|w| "-".repeat(*w)).collect::<Vec<_>>().join("-+-"))?;
+    for row in &cells {
+        line(out, row)?;
+    }
+    writeln!(out, "({} row{})", cells.len(), if cells.len() == 1 { "" } else { "s" })
+}
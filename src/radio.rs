@@ -0,0 +1,346 @@
+// Radio statistics from the rtl_433 metadata. With `-M level` rtl_433 adds
+// the centre frequency (`freq`, or `freq1`/`freq2` for FSK), the
+// modulation (`mod`) and `rssi` / `snr` / `noise` to every decoded message,
+// which the normalizer ignores. To compare antenna placements (or find a
+// sensor drifting off its band) they are collected per sensor and hour:
+//
+//   [radio]
+//   enabled = true
+//   write_interval_secs = 60
+//
+// The hourly aggregates go to the `radio_stats` table: message count and
+// sum / min / max of the frequency, sums of FSK `freq2`, `rssi`, `snr` and
+// `noise` with their counts (divide for averages; partial hours add up). A
+// distribution summary per band (315, 345, 433.92, 868.3 and 915 MHz, else
+// `other`) is exported as `radio_messages_total{band,modulation}` and the
+// histograms `radio_frequency_offset_khz{band}`, `radio_rssi_db{band}` and
+// `radio_snr_db{band}`, and `GET /api/radio?hours=24` summarizes the
+// recent hours per sensor. Every received message counts, repeats that
+// dedup drops included, as each is a reception of its own. Messages
+// without the metadata are skipped; aggregates not yet written when the
+// process dies are lost.
+use crate::config::RadioConfig;
+use crate::db::{sql_literal, DbHandle};
+use crate::problem::{self, Problem};
+use axum::extract::{Extension, Query};
+use axum::Json;
+use chrono::{DurationRound, NaiveDateTime, TimeDelta, Utc};
+use duckdb::types::Value;
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Nominal centre frequencies (MHz) of the bands rtl_433 listens on.
+const BANDS: &[(&str, f64)] = &[("315", 315.0), ("345", 345.0), ("433", 433.92), ("868", 868.3), ("915", 915.0)];
+
+/// Distance (MHz) from a nominal frequency still counted as its band.
+const BAND_WIDTH_MHZ: f64 = 5.0;
+
+/// The rtl_433 fields read here; everything else is the normalizer's.
+#[derive(Deserialize)]
+struct Metadata {
+    model: Option<String>,
+    id: Option<serde_json::Value>,
+    freq: Option<f64>,
+    freq1: Option<f64>,
+    freq2: Option<f64>,
+    #[serde(rename = "mod")]
+    modulation: Option<String>,
+    rssi: Option<f64>,
+    snr: Option<f64>,
+    noise: Option<f64>,
+}
+
+/// Sum and count of an optional field.
+#[derive(Clone, Copy, Default)]
+struct Sum {
+    sum: f64,
+    n: u64,
+}
+
+impl Sum {
+    fn add(&mut self, value: Option<f64>) {
+        if let Some(value) = value {
+            self.sum += value;
+            self.n += 1;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Aggregate {
+    messages: u64,
+    freq: Sum,
+    freq_min: Option<f64>,
+    freq_max: Option<f64>,
+    freq2: Sum,
+    rssi: Sum,
+    snr: Sum,
+    noise: Sum,
+}
+
+#[derive(Clone, Eq, Hash, PartialEq)]
+struct Bucket {
+    hour: NaiveDateTime,
+    model: String,
+    sensor_id: String,
+    modulation: String,
+}
+
+/// The band of a frequency and its offset from the band's nominal
+/// frequency in kHz.
+fn band(freq: f64) -> (&'static str, Option<f64>) {
+    BANDS
+        .iter()
+        .find(|(_, nominal)| (freq - nominal).abs() <= BAND_WIDTH_MHZ)
+        .map_or(("other", None), |(name, nominal)| (*name, Some((freq - nominal) * 1000.0)))
+}
+
+pub struct RadioStats {
+    config: RadioConfig,
+    db: DbHandle,
+    pending: Mutex<HashMap<Bucket, Aggregate>>,
+    messages: IntCounterVec,
+    offset: HistogramVec,
+    rssi: HistogramVec,
+    snr: HistogramVec,
+}
+
+impl RadioStats {
+    pub fn new(config: &RadioConfig, db: DbHandle, registry: &Registry) -> anyhow::Result<Self> {
+        let stats = Self {
+            config: config.clone(),
+            db,
+            pending: Mutex::new(HashMap::new()),
+            messages: IntCounterVec::new(
+                Opts::new("radio_messages_total", "rtl_433 messages carrying radio metadata, by band and modulation"),
+                &["band", "modulation"],
+            )?,
+            offset: HistogramVec::new(
+                HistogramOpts::new("radio_frequency_offset_khz", "Offset of the received frequency from the band's nominal one")
+                    .buckets(vec![-200.0, -100.0, -50.0, -25.0, -10.0, 0.0, 10.0, 25.0, 50.0, 100.0, 200.0]),
+                &["band"],
+            )?,
+            rssi: HistogramVec::new(
+                HistogramOpts::new("radio_rssi_db", "Received signal strength reported by rtl_433")
+                    .buckets(vec![-30.0, -25.0, -20.0, -15.0, -10.0, -5.0, -2.0, 0.0]),
+                &["band"],
+            )?,
+            snr: HistogramVec::new(
+                HistogramOpts::new("radio_snr_db", "Signal-to-noise ratio reported by rtl_433")
+                    .buckets(vec![3.0, 6.0, 10.0, 15.0, 20.0, 25.0, 30.0, 40.0]),
+                &["band"],
+            )?,
+        };
+        registry.register(Box::new(stats.messages.clone()))?;
+        registry.register(Box::new(stats.offset.clone()))?;
+        registry.register(Box::new(stats.rssi.clone()))?;
+        registry.register(Box::new(stats.snr.clone()))?;
+        Ok(stats)
+    }
+
+    /// Count the radio metadata of an rtl_433 payload, if it has any.
+    pub fn observe(&self, payload: &[u8]) {
+        if !self.config.enabled {
+            return;
+        }
+        let Ok(meta) = serde_json::from_slice::<Metadata>(payload) else { return };
+        let freq = meta.freq.or(meta.freq1);
+        if freq.is_none() && meta.modulation.is_none() {
+            return;
+        }
+        let (Some(model), Some(id)) = (meta.model, meta.id) else { return };
+        let sensor_id = match id {
+            serde_json::Value::String(s) => s,
+            other => other.to_string(),
+        };
+        let modulation = meta.modulation.unwrap_or_default();
+        let (band, offset) = freq.map_or(("other", None), band);
+        self.messages.with_label_values(&[band, &modulation]).inc();
+        if let Some(offset) = offset {
+            self.offset.with_label_values(&[band]).observe(offset);
+        }
+        if let Some(rssi) = meta.rssi {
+            self.rssi.with_label_values(&[band]).observe(rssi);
+        }
+        if let Some(snr) = meta.snr {
+            self.snr.with_label_values(&[band]).observe(snr);
+        }
+
+        let now = Utc::now().naive_utc();
+        let hour = now.duration_trunc(TimeDelta::hours(1)).unwrap_or(now);
+        let mut pending = self.pending.lock().unwrap();
+        let aggregate = pending.entry(Bucket { hour, model, sensor_id, modulation }).or_default();
+        aggregate.messages += 1;
+        aggregate.freq.add(freq);
+        if let Some(freq) = freq {
+            aggregate.freq_min = Some(aggregate.freq_min.map_or(freq, |min| min.min(freq)));
+            aggregate.freq_max = Some(aggregate.freq_max.map_or(freq, |max| max.max(freq)));
+        }
+        aggregate.freq2.add(meta.freq2);
+        aggregate.rssi.add(meta.rssi);
+        aggregate.snr.add(meta.snr);
+        aggregate.noise.add(meta.noise);
+    }
+
+    /// Add the aggregates collected since the last write to `radio_stats`.
+    /// They are kept for the next write when this fails.
+    pub async fn write(&self) -> anyhow::Result<usize> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        let optional = |v: Option<f64>| v.map_or_else(|| "NULL".to_string(), |v| v.to_string());
+        let values: Vec<String> = pending
+            .iter()
+            .map(|(bucket, a)| {
+                format!(
+                    "({}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {})",
+                    sql_literal(&bucket.hour.format("%Y-%m-%d %H:%M:%S").to_string()),
+                    sql_literal(&bucket.model),
+                    sql_literal(&bucket.sensor_id),
+                    sql_literal(&bucket.modulation),
+                    a.messages,
+                    a.freq.sum,
+                    a.freq.n,
+                    optional(a.freq_min),
+                    optional(a.freq_max),
+                    a.freq2.sum,
+                    a.freq2.n,
+                    a.rssi.sum,
+                    a.rssi.n,
+                    a.snr.sum,
+                    a.snr.n,
+                    a.noise.sum,
+                    a.noise.n
+                )
+            })
+            .collect();
+        let sql = format!(
+            "INSERT INTO radio_stats VALUES {} ON CONFLICT (hour, model, sensor_id, modulation) DO UPDATE SET \
+             messages = radio_stats.messages + excluded.messages, \
+             freq_sum = radio_stats.freq_sum + excluded.freq_sum, freq_n = radio_stats.freq_n + excluded.freq_n, \
+             freq_min = least(radio_stats.freq_min, excluded.freq_min), \
+             freq_max = greatest(radio_stats.freq_max, excluded.freq_max), \
+             freq2_sum = radio_stats.freq2_sum + excluded.freq2_sum, freq2_n = radio_stats.freq2_n + excluded.freq2_n, \
+             rssi_sum = radio_stats.rssi_sum + excluded.rssi_sum, rssi_n = radio_stats.rssi_n + excluded.rssi_n, \
+             snr_sum = radio_stats.snr_sum + excluded.snr_sum, snr_n = radio_stats.snr_n + excluded.snr_n, \
+             noise_sum = radio_stats.noise_sum + excluded.noise_sum, noise_n = radio_stats.noise_n + excluded.noise_n",
+            values.join(", ")
+        );
+        if let Err(e) = self.db.execute(sql).await {
+            // Merge back so nothing is lost to a busy or locked database.
+            let mut current = self.pending.lock().unwrap();
+            for (bucket, a) in pending {
+                let merged = current.entry(bucket).or_default();
+                merged.messages += a.messages;
+                for (into, from) in [
+                    (&mut merged.freq, a.freq),
+                    (&mut merged.freq2, a.freq2),
+                    (&mut merged.rssi, a.rssi),
+                    (&mut merged.snr, a.snr),
+                    (&mut merged.noise, a.noise),
+                ] {
+                    into.sum += from.sum;
+                    into.n += from.n;
+                }
+                merged.freq_min = [merged.freq_min, a.freq_min].into_iter().flatten().reduce(f64::min);
+                merged.freq_max = [merged.freq_max, a.freq_max].into_iter().flatten().reduce(f64::max);
+            }
+            return Err(e);
+        }
+        Ok(values.len())
+    }
+
+    /// Write the aggregates every `write_interval_secs`.
+    pub async fn run(self: Arc<Self>) {
+        if !self.config.enabled {
+            return;
+        }
+        info!("Writing radio statistics every {}s", self.config.write_interval_secs.max(1));
+        let mut ticker = tokio::time::interval(Duration::from_secs(self.config.write_interval_secs.max(1)));
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.write().await {
+                warn!("Writing radio statistics failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Query parameters for `GET /api/radio`.
+#[derive(Deserialize)]
+pub struct RadioParams {
+    /// Hours to summarize, counting the current one; defaults to 24.
+    pub hours: Option<u32>,
+}
+
+/// Radio reception of one sensor and modulation over the requested hours.
+#[derive(Deserialize, Serialize)]
+pub struct RadioSummary {
+    pub model: String,
+    pub sensor_id: String,
+    pub modulation: String,
+    pub messages: i64,
+    pub freq_avg: Option<f64>,
+    pub freq_min: Option<f64>,
+    pub freq_max: Option<f64>,
+    pub freq2_avg: Option<f64>,
+    pub rssi_avg: Option<f64>,
+    pub snr_avg: Option<f64>,
+    pub noise_avg: Option<f64>,
+}
+
+/// Longest period `GET /api/radio` summarizes, a leap year.
+const MAX_HOURS: u32 = 366 * 24;
+
+/// `GET /api/radio`: per-sensor summary of the stored hourly aggregates,
+/// busiest first. The current hour's unwritten aggregates are not included.
+pub async fn radio_handler(
+    Extension(db): Extension<DbHandle>,
+    Query(params): Query<RadioParams>,
+) -> Result<Json<Vec<RadioSummary>>, Problem> {
+    let hours = params.hours.unwrap_or(24);
+    if !(1..=MAX_HOURS).contains(&hours) {
+        return Err(Problem::invalid(format!("hours must be between 1 and {}", MAX_HOURS)));
+    }
+    let sql = "SELECT model, sensor_id, modulation, sum(messages)::BIGINT AS messages, \
+               sum(freq_sum) / nullif(sum(freq_n), 0) AS freq_avg, min(freq_min) AS freq_min, max(freq_max) AS freq_max, \
+               sum(freq2_sum) / nullif(sum(freq2_n), 0) AS freq2_avg, \
+               sum(rssi_sum) / nullif(sum(rssi_n), 0) AS rssi_avg, \
+               sum(snr_sum) / nullif(sum(snr_n), 0) AS snr_avg, \
+               sum(noise_sum) / nullif(sum(noise_n), 0) AS noise_avg \
+               FROM radio_stats WHERE hour >= ?::TIMESTAMP \
+               GROUP BY model, sensor_id, modulation ORDER BY messages DESC, model, sensor_id";
+    let now = Utc::now().naive_utc();
+    let since = now.duration_trunc(TimeDelta::hours(1)).unwrap_or(now) - TimeDelta::hours(hours as i64 - 1);
+    let params = vec![Value::Text(since.format("%Y-%m-%d %H:%M:%S").to_string())];
+    let summaries = problem::query(&db, db.query_as(sql, params)).await?;
+    Ok(Json(summaries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frequencies_fall_into_the_nearest_band_with_their_offset() {
+        let (name, offset) = band(433.95);
+        assert_eq!(name, "433");
+        assert!((offset.unwrap() - 30.0).abs() < 1e-6);
+        let (name, offset) = band(868.2);
+        assert_eq!(name, "868");
+        assert!((offset.unwrap() + 100.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn frequencies_between_bands_are_other() {
+        assert_eq!(band(400.0), ("other", None));
+        assert_eq!(band(433.92 + BAND_WIDTH_MHZ + 0.01), ("other", None));
+        assert_eq!(band(433.92 + BAND_WIDTH_MHZ - 0.01).0, "433");
+    }
+}
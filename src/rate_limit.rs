@@ -0,0 +1,159 @@
+// Per-sensor ingest limit. A misbehaving transmitter (a stuck button, a
+// neighbour's device with a broken timer) can send many messages a second
+// and dominate storage. With a limit set, each sensor may send
+// `messages_per_minute` messages, with bursts of up to that many:
+//
+//   [rate_limit]
+//   messages_per_minute = 30
+//   exempt = ["Rainforest-EMU::1234", "Power meter"]   # model::sensor_id or mapping name
+//
+// A message over the limit is dropped like a duplicate: it is neither stored
+// nor exported, and doesn't update the sensor's last-seen time. Drops are
+// counted in `mqtt_messages_rate_limited_total{sensor_id}` and logged (rate
+// limited themselves). The limit is a token bucket per sensor, refilled
+// continuously, checked after dedup so repeated transmissions of one
+// message don't count. Exempt names are resolved against the current
+// mappings, so renames apply without a restart. Messages replayed from the
+// WAL after a crash are not limited again.
+use crate::config::RateLimitConfig;
+use crate::mqtt_buffer::NormalizedRow;
+use crate::state::{resolve_sensor, Mapping, SensorKey};
+use prometheus::{IntCounterVec, Opts, Registry};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often buckets of sensors that have gone quiet are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets {
+    sensors: HashMap<SensorKey, Bucket>,
+    pruned: Instant,
+}
+
+pub struct RateLimiter {
+    /// Bucket size and refill per minute; `0` disables the limit.
+    per_minute: f64,
+    exempt: Vec<String>,
+    buckets: Mutex<Buckets>,
+    limited: IntCounterVec,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, registry: &Registry) -> anyhow::Result<Self> {
+        let limited = IntCounterVec::new(
+            Opts::new("mqtt_messages_rate_limited_total", "Messages dropped because their sensor exceeded the rate limit"),
+            &["sensor_id"],
+        )?;
+        registry.register(Box::new(limited.clone()))?;
+        Ok(Self {
+            per_minute: config.messages_per_minute as f64,
+            exempt: config.exempt.clone(),
+            buckets: Mutex::new(Buckets { sensors: HashMap::new(), pruned: Instant::now() }),
+            limited,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.per_minute > 0.0
+    }
+
+    /// Whether the message with `rows` may be ingested; counts it against
+    /// its sensor's limit.
+    pub fn allow(&self, rows: &[NormalizedRow], mappings: &HashMap<String, Mapping>) -> bool {
+        self.allow_at(rows, mappings, Instant::now())
+    }
+
+    fn allow_at(&self, rows: &[NormalizedRow], mappings: &HashMap<String, Mapping>, now: Instant) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let Some(row) = rows.first() else { return true };
+        let key = SensorKey { model: row.model.clone(), sensor_id: row.sensor_id.clone() };
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.duration_since(buckets.pruned) >= PRUNE_INTERVAL {
+            // A bucket untouched for a minute is full again, the same as a
+            // new one.
+            buckets.sensors.retain(|_, bucket| now.duration_since(bucket.updated) < PRUNE_INTERVAL);
+            buckets.pruned = now;
+        }
+        let bucket = buckets.sensors.entry(key).or_insert(Bucket { tokens: self.per_minute, updated: now });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() / 60.0 * self.per_minute;
+        bucket.tokens = (bucket.tokens + refill).min(self.per_minute);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+        // Exemptions are only resolved for sensors over the limit.
+        let exempt = self.exempt.iter().any(|reference| {
+            resolve_sensor(reference, mappings).is_some_and(|k| k.model == row.model && k.sensor_id == row.sensor_id)
+        });
+        if exempt {
+            return true;
+        }
+        self.limited.with_label_values(&[row.sensor_id.as_str()]).inc();
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(messages_per_minute: u32, exempt: &[&str]) -> RateLimiter {
+        let config = RateLimitConfig { messages_per_minute, exempt: exempt.iter().map(|e| e.to_string()).collect() };
+        RateLimiter::new(&config, &Registry::new()).unwrap()
+    }
+
+    fn message(sensor_id: &str) -> Vec<NormalizedRow> {
+        vec![NormalizedRow {
+            timestamp: chrono::Utc::now().naive_utc(),
+            sensor_id: sensor_id.to_string(),
+            model: "Acurite-Tower".to_string(),
+            measurement_type: 1,
+            value: 21.5,
+            topic: "rtl_433/events".to_string(),
+            seq: 0,
+            site: String::new(),
+            probe: String::new(),
+        }]
+    }
+
+    /// How many of `n` messages of `sensor_id` sent at `at` get through.
+    fn allowed(limiter: &RateLimiter, sensor_id: &str, n: usize, at: Instant) -> usize {
+        (0..n).filter(|_| limiter.allow_at(&message(sensor_id), &HashMap::new(), at)).count()
+    }
+
+    #[test]
+    fn bursts_up_to_the_limit_then_refills_continuously() {
+        let limiter = limiter(30, &[]);
+        let start = Instant::now();
+        assert_eq!(allowed(&limiter, "1", 40, start), 30);
+        // Other sensors have buckets of their own.
+        assert_eq!(allowed(&limiter, "2", 1, start), 1);
+        // 30 per minute is one token every two seconds.
+        assert_eq!(allowed(&limiter, "1", 5, start + Duration::from_secs(1)), 0);
+        assert_eq!(allowed(&limiter, "1", 5, start + Duration::from_secs(2)), 1);
+        assert_eq!(allowed(&limiter, "1", 5, start + Duration::from_secs(8)), 3);
+        // A long pause refills the bucket, but never beyond its size.
+        assert_eq!(allowed(&limiter, "1", 40, start + Duration::from_secs(3600)), 30);
+        assert_eq!(limiter.limited.with_label_values(&["1"]).get(), 10 + 5 + 4 + 2 + 10);
+    }
+
+    #[test]
+    fn exempt_and_unlimited_sensors_always_pass() {
+        let start = Instant::now();
+        let exempting = limiter(1, &["Acurite-Tower::1"]);
+        assert_eq!(allowed(&exempting, "1", 10, start), 10);
+        assert_eq!(allowed(&exempting, "2", 10, start), 1);
+        let unlimited = limiter(0, &[]);
+        assert!(!unlimited.is_enabled());
+        assert_eq!(allowed(&unlimited, "1", 100, start), 100);
+    }
+}
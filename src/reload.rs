@@ -0,0 +1,110 @@
+// Live reload on SIGHUP. Besides checkpointing the database, the exporter
+// re-reads the mappings file and the configuration (file and environment,
+// as at startup, or what `config::ReloadSource` says for an embedded
+// exporter) and applies what can change while running:
+//
+//   - mappings: names, units and retention overrides
+//   - `mqtt.topics`: new filters are subscribed, removed ones unsubscribed
+//   - `[flush]`: thresholds, device classes, the periodic flush interval
+//     and its catch-up behaviour
+//   - MQTT TLS certificates: re-read, followed by a reconnect
+//
+// Anything else (listen address, broker, database, measurement types, ...)
+// needs a restart; a few of the common ones are named in a warning when
+// they changed. An invalid config file is reported and the running
+// configuration is kept.
+use crate::config::Config;
+use crate::flush_timer::FlushSchedule;
+use crate::mqtt_buffer::{FlushClass, FlushPolicy, MqttBuffer};
+use crate::state::{reload_mappings, Store};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{watch, Notify};
+use tracing::{error, info, warn};
+
+pub struct Reloader {
+    /// The configuration currently applied.
+    pub current: Mutex<Config>,
+    pub store: Store,
+    pub buffer: Arc<MqttBuffer>,
+    /// Topic filters of the MQTT loop.
+    pub topics: watch::Sender<Vec<String>>,
+    /// Period and catch-up behaviour of the periodic flush.
+    pub flush_schedule: watch::Sender<FlushSchedule>,
+    /// Tells the MQTT loop to re-read its TLS certificates.
+    pub tls: Arc<Notify>,
+    /// Whether the MQTT loop runs at all.
+    pub mqtt_enabled: bool,
+}
+
+impl Reloader {
+    pub async fn reload(&self) {
+        let (mappings_file, source) = {
+            let current = self.current.lock().unwrap();
+            (current.mapping.file.clone(), current.reload.clone())
+        };
+        match reload_mappings(&self.store, &mappings_file).await {
+            Ok(count) => info!("Reloaded {} mappings", count),
+            Err(e) => error!("Keeping current mappings: {}", e),
+        }
+        self.tls.notify_one();
+        let mut next = match source.load() {
+            Ok(Some(config)) => config,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Keeping current configuration: {}", e);
+                return;
+            }
+        };
+        let mut current = self.current.lock().unwrap();
+
+        if next.mqtt.topics != current.mqtt.topics {
+            if !self.mqtt_enabled {
+                warn!("MQTT is not running; restart to subscribe to {}", next.mqtt.topics.join(", "));
+            } else if next.mqtt.topics.is_empty() {
+                warn!("Keeping MQTT topics {}: at least one is required", current.mqtt.topics.join(", "));
+                next.mqtt.topics = current.mqtt.topics.clone();
+            } else {
+                info!("MQTT topics changed to {}", next.mqtt.topics.join(", "));
+                self.topics.send_replace(next.mqtt.topics.clone());
+            }
+        }
+
+        let policy = FlushPolicy::from_config(&next.flush);
+        if policy != self.buffer.policy() {
+            info!("Flush policy changed to {:?}", policy);
+            self.buffer.set_policy(policy);
+        }
+        // Unset class limits come from `[flush]`, so any change counts.
+        if next.flush != current.flush && !(next.flush.classes.is_empty() && current.flush.classes.is_empty()) {
+            match FlushClass::from_config(&next.flush) {
+                Ok(classes) => {
+                    info!("Flush classes changed to [{}]", classes.iter().map(|c| c.name.as_str()).collect::<Vec<_>>().join(", "));
+                    self.buffer.set_classes(classes);
+                }
+                Err(e) => {
+                    error!("Keeping current flush classes: {}", e);
+                    next.flush.classes = current.flush.classes.clone();
+                }
+            }
+        }
+        let schedule = FlushSchedule::from_config(&next.flush);
+        if self.flush_schedule.send_replace(schedule) != schedule {
+            info!("Periodic flush changed to every {}s, {:?} missed ticks", schedule.period.as_secs(), schedule.missed_ticks);
+        }
+
+        let restart_only = [
+            ("http.bind", current.http.bind != next.http.bind),
+            ("mqtt.host", current.mqtt.host != next.mqtt.host),
+            ("mqtt.port", current.mqtt.port != next.mqtt.port),
+            ("mqtt.client_id", current.mqtt.client_id != next.mqtt.client_id),
+            ("mqtt.control_topic", current.mqtt.control_topic != next.mqtt.control_topic),
+            ("duckdb.path", current.duckdb.path != next.duckdb.path),
+        ];
+        let pending: Vec<&str> = restart_only.iter().filter(|(_, changed)| *changed).map(|(name, _)| *name).collect();
+        if !pending.is_empty() {
+            warn!("Changed settings take effect after a restart: {}", pending.join(", "));
+        }
+        *current = next;
+        info!("Configuration reloaded");
+    }
+}
@@ -0,0 +1,236 @@
+// Prometheus remote_read endpoint backed by DuckDB. Prometheus (or Grafana
+// through Prometheus) can be configured with this exporter as a
+// `remote_read` target to transparently query history that has aged out of
+// its local retention:
+//
+//   remote_read:
+//     - url: http://exporter:3000/api/v1/read
+//       read_recent: false
+//
+// Every stored measurement type is exposed as one metric (see
+// `mqtt_buffer::metric_name`) labelled with `sensor_id` and `model`. Only
+// the `SAMPLES` response type is implemented; Prometheus falls back to it
+// when streamed chunks are not offered. Stored timestamps are interpreted as
+// UTC, and values are converted into the sensor's mapping unit like the
+// gauges and remote_write (see `units`).
+use crate::db::{DbHandle, LabelColumn, LabelCondition, MeasurementQuery};
+use crate::mqtt_buffer::{measurement_types, metric_name};
+use crate::problem::{self, Problem};
+use crate::state::{Mapping, Store};
+use crate::units;
+use axum::{
+    body::Bytes,
+    extract::Extension,
+    http::{header::CONTENT_TYPE, HeaderMap, HeaderValue},
+};
+use std::collections::HashMap;
+
+/// Protobuf messages from Prometheus' `prompb` package, limited to the
+/// fields needed for sample-based remote read.
+pub mod prompb {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ReadRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub queries: Vec<Query>,
+        #[prost(enumeration = "ResponseType", repeated, tag = "2")]
+        pub accepted_response_types: Vec<i32>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum ResponseType {
+        Samples = 0,
+        StreamedXorChunks = 1,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Query {
+        #[prost(int64, tag = "1")]
+        pub start_timestamp_ms: i64,
+        #[prost(int64, tag = "2")]
+        pub end_timestamp_ms: i64,
+        #[prost(message, repeated, tag = "3")]
+        pub matchers: Vec<LabelMatcher>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LabelMatcher {
+        #[prost(enumeration = "MatcherType", tag = "1")]
+        pub r#type: i32,
+        #[prost(string, tag = "2")]
+        pub name: String,
+        #[prost(string, tag = "3")]
+        pub value: String,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum MatcherType {
+        Eq = 0,
+        Neq = 1,
+        Re = 2,
+        Nre = 3,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ReadResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub results: Vec<QueryResult>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct QueryResult {
+        #[prost(message, repeated, tag = "1")]
+        pub timeseries: Vec<TimeSeries>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TimeSeries {
+        #[prost(message, repeated, tag = "1")]
+        pub labels: Vec<Label>,
+        #[prost(message, repeated, tag = "2")]
+        pub samples: Vec<Sample>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Label {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub value: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Sample {
+        #[prost(double, tag = "1")]
+        pub value: f64,
+        #[prost(int64, tag = "2")]
+        pub timestamp: i64,
+    }
+}
+
+use prompb::{Label, LabelMatcher, MatcherType, QueryResult, ReadRequest, ReadResponse, Sample, TimeSeries};
+use prost::Message;
+
+/// Evaluate a matcher against a value in Rust, used for `__name__` which
+/// is not a column. Regexes are anchored like Prometheus does.
+fn matches(matcher: &LabelMatcher, value: &str) -> anyhow::Result<bool> {
+    let anchored = || regex_lite::Regex::new(&format!("^(?:{})$", matcher.value));
+    Ok(match MatcherType::try_from(matcher.r#type)? {
+        MatcherType::Eq => value == matcher.value,
+        MatcherType::Neq => value != matcher.value,
+        MatcherType::Re => anchored()?.is_match(value),
+        MatcherType::Nre => !anchored()?.is_match(value),
+    })
+}
+
+/// Translate one remote-read query into a `MeasurementQuery`. Returns
+/// `None` when the matchers can never select any of our series.
+fn to_measurement_query(query: &prompb::Query) -> anyhow::Result<Option<MeasurementQuery>> {
+    let mut measurement_types: Vec<u8> = measurement_types().iter().map(|t| t.code).collect();
+    let mut conditions = Vec::new();
+    for matcher in &query.matchers {
+        let column = match matcher.name.as_str() {
+            "__name__" => {
+                let mut kept = Vec::new();
+                for code in measurement_types {
+                    if matches(matcher, metric_name(code).unwrap_or_default())? {
+                        kept.push(code);
+                    }
+                }
+                measurement_types = kept;
+                continue;
+            }
+            "sensor_id" => LabelColumn::SensorId,
+            "model" => LabelColumn::Model,
+            "probe" => LabelColumn::Probe,
+            // Any other label is absent from our series, i.e. the empty string.
+            _ => {
+                if !matches(matcher, "")? {
+                    return Ok(None);
+                }
+                continue;
+            }
+        };
+        let value = matcher.value.clone();
+        conditions.push(match MatcherType::try_from(matcher.r#type)? {
+            MatcherType::Eq => LabelCondition::Eq(column, value),
+            MatcherType::Neq => LabelCondition::Neq(column, value),
+            MatcherType::Re => LabelCondition::Regex(column, value),
+            MatcherType::Nre => LabelCondition::NotRegex(column, value),
+        });
+    }
+    if measurement_types.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(MeasurementQuery {
+        start_ms: query.start_timestamp_ms,
+        end_ms: query.end_timestamp_ms,
+        measurement_types,
+        conditions,
+    }))
+}
+
+async fn run_query(db: &DbHandle, mappings: &HashMap<String, Mapping>, query: &prompb::Query) -> anyhow::Result<QueryResult> {
+    let Some(mq) = to_measurement_query(query)? else {
+        return Ok(QueryResult::default());
+    };
+    let mut rows = db.select_measurements(mq).await?;
+    units::apply_mapping_units(&mut rows, mappings);
+
+    // Rows arrive ordered by series, so a new series starts whenever the
+    // (sensor_id, model, measurement_type, site, probe) tuple changes.
+    // Replicated rows (aggregator mode) carry a `site` label and probes of
+    // multi-probe sensors a `probe` label, other rows don't.
+    let mut timeseries: Vec<TimeSeries> = Vec::new();
+    let mut current: Option<(String, String, u8, String, String)> = None;
+    for row in rows {
+        let key = (row.sensor_id, row.model, row.measurement_type, row.site, row.probe);
+        if current.as_ref() != Some(&key) {
+            let label = |name: &str, value: &str| Label { name: name.to_string(), value: value.to_string() };
+            let mut labels = vec![
+                label("__name__", metric_name(key.2).unwrap_or_default()),
+                label("model", &key.1),
+            ];
+            if !key.4.is_empty() {
+                labels.push(label("probe", &key.4));
+            }
+            labels.push(label("sensor_id", &key.0));
+            if !key.3.is_empty() {
+                labels.push(label("site", &key.3));
+            }
+            timeseries.push(TimeSeries { labels, samples: Vec::new() });
+            current = Some(key);
+        }
+        if let Some(series) = timeseries.last_mut() {
+            series.samples.push(Sample { value: row.value, timestamp: row.timestamp.and_utc().timestamp_millis() });
+        }
+    }
+    Ok(QueryResult { timeseries })
+}
+
+/// `POST /api/v1/read`: snappy-compressed protobuf `ReadRequest` in,
+/// snappy-compressed `ReadResponse` out.
+pub async fn remote_read_handler(
+    Extension(db): Extension<DbHandle>,
+    Extension(store): Extension<Store>,
+    body: Bytes,
+) -> Result<(HeaderMap, Vec<u8>), Problem> {
+    let raw = snap::raw::Decoder::new().decompress_vec(&body).map_err(|e| Problem::invalid(e.to_string()))?;
+    let request = ReadRequest::decode(raw.as_slice()).map_err(|e| Problem::invalid(e.to_string()))?;
+
+    let mappings = store.read().await.clone();
+    let mut response = ReadResponse::default();
+    for query in &request.queries {
+        let result = problem::query(&db, run_query(&db, &mappings, query)).await?;
+        response.results.push(result);
+    }
+
+    let encoded = snap::raw::Encoder::new()
+        .compress_vec(&response.encode_to_vec())
+        .map_err(Problem::internal)?;
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-protobuf"));
+    headers.insert("content-encoding", HeaderValue::from_static("snappy"));
+    Ok((headers, encoded))
+}
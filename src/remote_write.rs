@@ -0,0 +1,229 @@
+// Prometheus remote_write output. Besides being scraped, the exporter can
+// push every normalized reading to a remote_write receiver (Mimir, Thanos
+// Receive, VictoriaMetrics, Prometheus with the receiver enabled):
+//
+//   [remote_write]
+//   url = "http://mimir:9009/api/v1/push"
+//
+// Series use the same names and labels as remote read (`metric_name`,
+// `sensor_id`, `model` and `probe` for the probes of multi-probe sensors),
+// with the reading's own timestamp. Samples are
+// queued in memory and sent in batches by a background task as
+// snappy-compressed protobuf `WriteRequest`s. Failed requests are retried
+// with backoff while the receiver is unavailable; when the queue is full
+// the oldest queued samples are dropped and counted (never the batch being
+// sent, see `sink::RetryQueue`), so a long outage costs memory only up to
+// `max_queue`.
+use crate::config::RemoteWriteConfig;
+use crate::mqtt_buffer::{metric_name, NormalizedRow};
+use crate::remote_read::prompb::{Label, Sample, TimeSeries};
+use crate::sink::{unix_now, Readings, RetryQueue, SendError, Sink};
+use prometheus::{Gauge, IntCounter, IntCounterVec, IntGauge, Opts, Registry};
+use prost::Message;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// `prompb.WriteRequest`, without the optional metadata.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+/// One queued sample; labels are resolved when the batch is built.
+struct QueuedSample {
+    metric: &'static str,
+    sensor_id: String,
+    model: String,
+    probe: String,
+    timestamp_ms: i64,
+    value: f64,
+}
+
+struct WriterMetrics {
+    samples_sent: IntCounter,
+    samples_dropped: IntCounter,
+    requests: IntCounterVec,
+    queue_length: IntGauge,
+    last_success: Gauge,
+}
+
+impl WriterMetrics {
+    fn new(registry: &Registry) -> anyhow::Result<Self> {
+        let metrics = Self {
+            samples_sent: IntCounter::new("remote_write_samples_sent_total", "Samples accepted by the remote_write receiver")?,
+            samples_dropped: IntCounter::new(
+                "remote_write_samples_dropped_total",
+                "Samples dropped because the queue was full or the receiver rejected them",
+            )?,
+            requests: IntCounterVec::new(
+                Opts::new("remote_write_requests_total", "remote_write requests by result"),
+                &["result"],
+            )?,
+            queue_length: IntGauge::new("remote_write_queue_samples", "Samples waiting to be sent")?,
+            last_success: Gauge::new(
+                "remote_write_last_success_timestamp_seconds",
+                "Unix time of the last successful remote_write request",
+            )?,
+        };
+        registry.register(Box::new(metrics.samples_sent.clone()))?;
+        registry.register(Box::new(metrics.samples_dropped.clone()))?;
+        registry.register(Box::new(metrics.requests.clone()))?;
+        registry.register(Box::new(metrics.queue_length.clone()))?;
+        registry.register(Box::new(metrics.last_success.clone()))?;
+        Ok(metrics)
+    }
+}
+
+/// A batch of samples grouped into series.
+fn write_request(batch: &[QueuedSample]) -> WriteRequest {
+    let mut series: BTreeMap<(&str, &str, &str, &str), Vec<Sample>> = BTreeMap::new();
+    for sample in batch {
+        series
+            .entry((sample.metric, sample.sensor_id.as_str(), sample.model.as_str(), sample.probe.as_str()))
+            .or_default()
+            .push(Sample { value: sample.value, timestamp: sample.timestamp_ms });
+    }
+    let label = |name: &str, value: &str| Label { name: name.to_string(), value: value.to_string() };
+    let timeseries = series
+        .into_iter()
+        .map(|((metric, sensor_id, model, probe), mut samples)| {
+            samples.sort_by_key(|s| s.timestamp);
+            // Labels must be sorted by name.
+            let mut labels = vec![label("__name__", metric), label("model", model)];
+            if !probe.is_empty() {
+                labels.push(label("probe", probe));
+            }
+            labels.push(label("sensor_id", sensor_id));
+            TimeSeries { labels, samples }
+        })
+        .collect();
+    WriteRequest { timeseries }
+}
+
+/// Queue shared by the MQTT loop (producer) and the sender task.
+pub struct RemoteWriter {
+    config: RemoteWriteConfig,
+    queue: RetryQueue<QueuedSample>,
+    http: reqwest::Client,
+    metrics: WriterMetrics,
+}
+
+impl RemoteWriter {
+    /// Build the writer when `remote_write.url` is configured. The sender
+    /// task is started separately with `run`.
+    pub fn new(config: &RemoteWriteConfig, registry: &Registry) -> anyhow::Result<Option<Self>> {
+        if config.url.is_none() {
+            return Ok(None);
+        }
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build()?;
+        let metrics = WriterMetrics::new(registry)?;
+        let queue = RetryQueue::new(
+            config.batch_size,
+            config.max_queue,
+            metrics.queue_length.clone(),
+            metrics.samples_dropped.clone(),
+        )
+        .map_err(|e| anyhow::anyhow!("remote_write: {}", e))?;
+        Ok(Some(Self { config: config.clone(), queue, http, metrics }))
+    }
+
+    /// Queue rows for sending. Never blocks; drops the oldest samples when
+    /// the queue is full.
+    fn queue(&self, rows: &[NormalizedRow]) {
+        self.queue.push(rows.iter().filter_map(|row| {
+            Some(QueuedSample {
+                metric: metric_name(row.measurement_type)?,
+                sensor_id: row.sensor_id.clone(),
+                model: row.model.clone(),
+                probe: row.probe.clone(),
+                timestamp_ms: row.timestamp.and_utc().timestamp_millis(),
+                value: row.value,
+            })
+        }));
+    }
+
+    /// Sender loop: every `interval_secs` (or as soon as a batch is full)
+    /// send up to `batch_size` samples. A batch that fails with a retryable
+    /// error goes back to the front of the queue and is retried with
+    /// backoff.
+    pub async fn run(self: Arc<Self>) {
+        let interval = Duration::from_secs(self.config.interval_secs.max(1));
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            self.queue.ready(interval).await;
+            loop {
+                let batch = self.queue.take();
+                if batch.is_empty() {
+                    break;
+                }
+                let samples = batch.len();
+                match self.send(&write_request(&batch)).await {
+                    Ok(()) => {
+                        self.metrics.requests.with_label_values(&["success"]).inc();
+                        self.metrics.samples_sent.inc_by(samples as u64);
+                        self.metrics.last_success.set(unix_now());
+                        self.queue.sent();
+                        backoff = Duration::from_secs(1);
+                    }
+                    Err(SendError::Rejected(e)) => {
+                        // Retrying a request the receiver considers invalid
+                        // (e.g. out-of-order samples) would block the queue.
+                        warn!("remote_write rejected {} samples: {}", samples, e);
+                        self.metrics.requests.with_label_values(&["rejected"]).inc();
+                        self.metrics.samples_dropped.inc_by(samples as u64);
+                        self.queue.sent();
+                    }
+                    Err(SendError::Retry(e)) => {
+                        warn!("remote_write failed, retrying in {:?}: {}", backoff, e);
+                        self.metrics.requests.with_label_values(&["retry"]).inc();
+                        self.queue.retry(batch);
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(Duration::from_secs(self.config.max_backoff_secs.max(1)));
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send(&self, request: &WriteRequest) -> Result<(), SendError> {
+        let url = self.config.url.as_deref().unwrap_or_default();
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .map_err(|e| SendError::Rejected(e.to_string()))?;
+        let mut builder = self
+            .http
+            .post(url)
+            .header("content-type", "application/x-protobuf")
+            .header("content-encoding", "snappy")
+            .header("x-prometheus-remote-write-version", "0.1.0")
+            .body(body);
+        if let Some(token) = &self.config.bearer_token {
+            builder = builder.bearer_auth(token);
+        }
+        for (name, value) in &self.config.headers {
+            builder = builder.header(name, value);
+        }
+        let response = builder.send().await.map_err(|e| SendError::Retry(e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let detail = format!("{}: {}", status, response.text().await.unwrap_or_default().trim());
+        Err(SendError::from_status(status, detail))
+    }
+}
+
+impl Sink for RemoteWriter {
+    fn name(&self) -> &'static str {
+        "remote_write"
+    }
+
+    fn push(&self, readings: &Readings) {
+        self.queue(readings.rows);
+    }
+}
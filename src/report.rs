@@ -0,0 +1,364 @@
+// Weekly summary reports: the temperature range of every sensor, low
+// batteries, data gaps and sensors heard for the first time, computed from
+// DuckDB and sent as Markdown or HTML to a webhook and/or by mail:
+//
+//   [report]
+//   weekday = "mon"                 # sent on Mondays ...
+//   hour = 8                        # ... at 08:00 local time
+//   days = 7                        # covering the week before
+//   format = "markdown"             # or "html"
+//   measurement = "temperature_C"
+//   gap_minutes = 60
+//   webhook_url = "https://hooks.slack.com/services/..."
+//
+//   [report.smtp]
+//   host = "smtp.example.com"
+//   security = "starttls"           # or "tls", "none"
+//   username = "exporter"
+//   from = "exporter@example.com"
+//   to = ["me@example.com"]
+//
+// The webhook receives `{"subject", "text", "format"}`, which Slack and
+// Mattermost incoming webhooks display as they are; mail is sent as
+// `text/plain` (Markdown) or `text/html`. Nothing is sent unless a webhook
+// or SMTP host is configured, and a failed delivery is logged and not
+// retried. Reports can be previewed with `GET /api/report?days=&format=`
+// (1 to 366 days) and sent right away with `POST /api/report/send`, which
+// requires the admin token.
+//
+// Only locally ingested rows still in the `measurements` table are used, so
+// "new" means first reading still stored and a period longer than the
+// retention reports less than it covers. Mapped sensors without any reading
+// in the period are listed under the gaps. Temperatures are shown in the
+// unit their mapping stores them in.
+use crate::config::{ReportConfig, ReportFormat, SmtpConfig, SmtpSecurity};
+use crate::db::{DbHandle, ReportQuery, SensorActivity};
+use crate::handlers::{authorize_admin, AdminAccess};
+use crate::mqtt_buffer::measurement_code;
+use crate::problem::{self, Problem};
+use crate::state::{key_for, Mapping, Store};
+use crate::units;
+use axum::{
+    extract::{Extension, Query},
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Datelike, Local, TimeZone};
+use lettre::{
+    message::header::ContentType, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// Last `battery_ok` readings below this count as a low battery.
+const LOW_BATTERY: f64 = 0.5;
+
+pub struct Reporter {
+    config: ReportConfig,
+    db: DbHandle,
+    store: Store,
+    http: reqwest::Client,
+}
+
+/// A rendered report.
+pub struct Report {
+    pub subject: String,
+    pub text: String,
+    pub format: ReportFormat,
+}
+
+/// One table of a report.
+struct Section {
+    title: &'static str,
+    headers: &'static [&'static str],
+    rows: Vec<Vec<String>>,
+    /// Shown instead of an empty table.
+    empty: &'static str,
+}
+
+fn format_time(ms: i64) -> String {
+    Local.timestamp_millis_opt(ms).single().map(|t| t.format("%a %Y-%m-%d %H:%M").to_string()).unwrap_or_default()
+}
+
+fn format_duration(ms: i64) -> String {
+    let minutes = ms / 60_000;
+    match (minutes / 1440, minutes % 1440 / 60, minutes % 60) {
+        (0, 0, m) => format!("{}m", m),
+        (0, h, m) => format!("{}h {}m", h, m),
+        (d, h, _) => format!("{}d {}h", d, h),
+    }
+}
+
+fn format_value(value: Option<f64>, unit: &str) -> String {
+    let Some(value) = value else { return String::new() };
+    let decimals = units::info(unit).decimals.unwrap_or(1) as usize;
+    format!("{:.*} {}", decimals, value, unit).trim_end().to_string()
+}
+
+/// Local start of the hour `hour` on the next `weekday` after `now`.
+fn next_run(now: DateTime<Local>, config: &ReportConfig) -> DateTime<Local> {
+    (0..=7)
+        .filter_map(|days| now.date_naive().checked_add_days(chrono::Days::new(days)))
+        .filter(|day| day.weekday() == config.weekday)
+        .filter_map(|day| Local.from_local_datetime(&day.and_hms_opt(config.hour.min(23), 0, 0)?).earliest())
+        .find(|at| *at > now)
+        .unwrap_or_else(|| now + chrono::Duration::days(7))
+}
+
+/// Longest period a report covers.
+const MAX_DAYS: u64 = 366;
+
+impl Reporter {
+    pub fn new(config: &ReportConfig, db: DbHandle, store: Store) -> anyhow::Result<Self> {
+        if measurement_code(&config.measurement).is_none() {
+            anyhow::bail!("report: unknown measurement {}", config.measurement);
+        }
+        let http = reqwest::Client::builder().timeout(Duration::from_secs(30)).build()?;
+        Ok(Self { config: config.clone(), db, store, http })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.webhook_url.is_some() || self.config.smtp.host.is_some()
+    }
+
+    /// The report of the `days` before `end`, at most `MAX_DAYS`.
+    pub async fn build(&self, end: DateTime<Local>, days: u64, format: ReportFormat) -> anyhow::Result<Report> {
+        let start = end - chrono::Duration::days(days.clamp(1, MAX_DAYS) as i64);
+        let measurement_type = measurement_code(&self.config.measurement);
+        let query = ReportQuery {
+            start_ms: start.timestamp_millis(),
+            end_ms: end.timestamp_millis(),
+            measurement_type,
+            battery_type: measurement_code("battery_ok"),
+            gap_ms: self.config.gap_minutes.max(1) as i64 * 60_000,
+        };
+        let sensors = self.db.report(query.clone()).await?;
+        let mappings = self.store.read().await.clone();
+        let sections = sections(&sensors, &mappings, &query);
+        let subject = format!("Sensor report {} to {}", start.format("%Y-%m-%d"), end.format("%Y-%m-%d"));
+        let text = match format {
+            ReportFormat::Markdown => markdown(&subject, &sections),
+            ReportFormat::Html => html(&subject, &sections),
+        };
+        Ok(Report { subject, text, format })
+    }
+
+    /// Send `report` to every configured destination.
+    pub async fn deliver(&self, report: &Report) -> anyhow::Result<()> {
+        if let Some(url) = &self.config.webhook_url {
+            let format = match report.format {
+                ReportFormat::Markdown => "markdown",
+                ReportFormat::Html => "html",
+            };
+            let body = serde_json::json!({"subject": report.subject, "text": report.text, "format": format});
+            self.http
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&body)?)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        if let Some(host) = &self.config.smtp.host {
+            send_mail(host, &self.config.smtp, report).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn send_mail(host: &str, smtp: &SmtpConfig, report: &Report) -> anyhow::Result<()> {
+    if smtp.to.is_empty() {
+        anyhow::bail!("report.smtp.to lists no recipients");
+    }
+    let builder = match smtp.security {
+        SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+    };
+    let mut builder = builder.port(smtp.port);
+    if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    let mut message = Message::builder().from(smtp.from.parse()?).subject(&report.subject);
+    for to in &smtp.to {
+        message = message.to(to.parse()?);
+    }
+    let content_type = match report.format {
+        ReportFormat::Markdown => ContentType::TEXT_PLAIN,
+        ReportFormat::Html => ContentType::TEXT_HTML,
+    };
+    builder.build().send(message.header(content_type).body(report.text.clone())?).await?;
+    Ok(())
+}
+
+fn sections(sensors: &[SensorActivity], mappings: &HashMap<String, Mapping>, query: &ReportQuery) -> Vec<Section> {
+    let mapping = |s: &SensorActivity| mappings.get(&key_for(&s.sensor_id, &s.model));
+    let name = |s: &SensorActivity| match mapping(s) {
+        Some(m) => m.name.clone(),
+        None => format!("{} {}", s.model, s.sensor_id),
+    };
+
+    let mut temperatures = Section { title: "Temperatures", headers: &["Sensor", "Min", "Max"], rows: Vec::new(), empty: "No readings." };
+    let mut batteries = Section { title: "Battery warnings", headers: &["Sensor", "Last seen"], rows: Vec::new(), empty: "All batteries ok." };
+    let mut gaps = Section { title: "Data gaps", headers: &["Sensor", "Gaps", "Longest", "Last seen"], rows: Vec::new(), empty: "No gaps." };
+    let mut new = Section { title: "New sensors", headers: &["Sensor", "First seen", "Messages"], rows: Vec::new(), empty: "No new sensors." };
+
+    for sensor in sensors {
+        if let Some(code) = query.measurement_type
+            && (sensor.min.is_some() || sensor.max.is_some())
+        {
+            let unit = units::mapped_unit(code, mapping(sensor));
+            let convert = |value: Option<f64>| value.map(|v| units::to_mapped_unit(v, code, mapping(sensor)));
+            temperatures.rows.push(vec![name(sensor), format_value(convert(sensor.min), &unit), format_value(convert(sensor.max), &unit)]);
+        }
+        if sensor.battery_ok.is_some_and(|b| b < LOW_BATTERY) {
+            batteries.rows.push(vec![name(sensor), format_time(sensor.last_seen_ms)]);
+        }
+        if sensor.gaps > 0 {
+            gaps.rows.push(vec![
+                name(sensor),
+                sensor.gaps.to_string(),
+                format_duration(sensor.longest_gap_ms),
+                format_time(sensor.last_seen_ms),
+            ]);
+        }
+        if sensor.first_seen_ms >= query.start_ms {
+            let label = if mapping(sensor).is_some() { name(sensor) } else { format!("{} (unmapped)", name(sensor)) };
+            new.rows.push(vec![label, format_time(sensor.first_seen_ms), sensor.messages.to_string()]);
+        }
+    }
+    let active: HashSet<String> = sensors.iter().map(|s| key_for(&s.sensor_id, &s.model)).collect();
+    let mut silent: Vec<&Mapping> = mappings
+        .iter()
+        .filter(|(key, m)| m.manufacturer != "composite" && !active.contains(*key))
+        .map(|(_, m)| m)
+        .collect();
+    silent.sort_by(|a, b| a.name.cmp(&b.name));
+    for mapping in silent {
+        gaps.rows.push(vec![mapping.name.clone(), "-".to_string(), "whole period".to_string(), String::new()]);
+    }
+    vec![temperatures, batteries, gaps, new]
+}
+
+fn markdown(subject: &str, sections: &[Section]) -> String {
+    let cell = |c: &str| c.replace('|', "\\|");
+    let mut out = format!("# {}\n", subject);
+    for section in sections {
+        out.push_str(&format!("\n## {}\n\n", section.title));
+        if section.rows.is_empty() {
+            out.push_str(&format!("{}\n", section.empty));
+            continue;
+        }
+        out.push_str(&format!("| {} |\n", section.headers.join(" | ")));
+        out.push_str(&format!("|{}\n", "---|".repeat(section.headers.len())));
+        for row in &section.rows {
+            out.push_str(&format!("| {} |\n", row.iter().map(|c| cell(c)).collect::<Vec<_>>().join(" | ")));
+        }
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn html(subject: &str, sections: &[Section]) -> String {
+    let mut out = format!("<!doctype html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head><body>\n<h1>{0}</h1>\n", escape_html(subject));
+    for section in sections {
+        out.push_str(&format!("<h2>{}</h2>\n", section.title));
+        if section.rows.is_empty() {
+            out.push_str(&format!("<p>{}</p>\n", section.empty));
+            continue;
+        }
+        out.push_str("<table border=\"1\" cellpadding=\"4\" cellspacing=\"0\">\n<tr>");
+        for header in section.headers {
+            out.push_str(&format!("<th>{}</th>", header));
+        }
+        out.push_str("</tr>\n");
+        for row in &section.rows {
+            out.push_str("<tr>");
+            for cell in row {
+                out.push_str(&format!("<td>{}</td>", escape_html(cell)));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n");
+    }
+    out.push_str("</body></html>\n");
+    out
+}
+
+/// Send a report every `weekday` at `hour`. Returns immediately when no
+/// destination is configured.
+pub async fn run(reporter: Arc<Reporter>) {
+    if !reporter.is_enabled() {
+        return;
+    }
+    loop {
+        let at = next_run(Local::now(), &reporter.config);
+        info!("Next report at {}", at.format("%Y-%m-%d %H:%M"));
+        tokio::time::sleep((at - Local::now()).to_std().unwrap_or_default()).await;
+        let sent = match reporter.build(at, reporter.config.days, reporter.config.format).await {
+            Ok(report) => reporter.deliver(&report).await,
+            Err(e) => Err(e),
+        };
+        match sent {
+            Ok(()) => info!("Report sent"),
+            Err(e) => error!("Report failed: {}", e),
+        }
+    }
+}
+
+/// Query parameters of `GET /api/report`; both default to `[report]`.
+#[derive(Deserialize)]
+pub struct ReportParams {
+    pub days: Option<u64>,
+    pub format: Option<ReportFormat>,
+}
+
+/// Preview the report of the period up to now.
+pub async fn report_handler(
+    Extension(reporter): Extension<Arc<Reporter>>,
+    Query(params): Query<ReportParams>,
+) -> Result<Response, Problem> {
+    let days = params.days.unwrap_or(reporter.config.days);
+    if !(1..=MAX_DAYS).contains(&days) {
+        return Err(Problem::invalid(format!("days must be between 1 and {}", MAX_DAYS)));
+    }
+    let format = params.format.unwrap_or(reporter.config.format);
+    let report = problem::query(&reporter.db, reporter.build(Local::now(), days, format)).await?;
+    let content_type = match report.format {
+        ReportFormat::Markdown => "text/markdown; charset=utf-8",
+        ReportFormat::Html => "text/html; charset=utf-8",
+    };
+    Ok(([(CONTENT_TYPE, content_type)], report.text).into_response())
+}
+
+#[derive(Serialize)]
+pub struct SendResult {
+    subject: String,
+}
+
+/// Build and deliver the scheduled report now. Requires the admin token.
+pub async fn send_handler(
+    Extension(reporter): Extension<Arc<Reporter>>,
+    Extension(admin): Extension<AdminAccess>,
+    headers: HeaderMap,
+) -> Result<Json<SendResult>, Problem> {
+    authorize_admin(&admin, &headers)?;
+    if !reporter.is_enabled() {
+        return Err(Problem::new(StatusCode::CONFLICT, "conflict", "no report destination configured (report.webhook_url or report.smtp.host)"));
+    }
+    let report = problem::query(&reporter.db, reporter.build(Local::now(), reporter.config.days, reporter.config.format)).await?;
+    reporter
+        .deliver(&report)
+        .await
+        .map_err(|e| Problem::new(StatusCode::BAD_GATEWAY, "delivery_failed", e.to_string()))?;
+    info!("Report sent on request");
+    Ok(Json(SendResult { subject: report.subject }))
+}
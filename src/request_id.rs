@@ -0,0 +1,112 @@
+// Request correlation ids. Every HTTP request gets an id: the caller's
+// `X-Request-Id` when it sends a usable one (1 to 128 visible ASCII
+// characters), otherwise a random one. The id is
+//
+//   - returned in the `X-Request-Id` response header,
+//   - a field of the `request` span around the request, so every log line
+//     written while handling it carries `request{id=...}` (text) or the
+//     span (JSON), at the default `info` level and above it,
+//   - `request_id` of problem+json errors, see `problem`,
+//   - attached to the DB commands the request sends: the worker logs
+//     commands that fail with it, so a failing call can be followed into
+//     the worker, and query jobs (see `jobs`) keep it, log with it and
+//     report it.
+//
+// The id lives in a task-local for the duration of the request; work a
+// handler spawns gets it with `scope`.
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use axum::{
+    body::Body,
+    http::{HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::future::Future;
+use tracing::{info_span, Instrument};
+
+pub const HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest caller-provided id that is kept.
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Id of the request being handled, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(String::clone).ok()
+}
+
+/// Run `future` with `id` as the current request id.
+pub async fn scope<F: Future>(id: Option<String>, future: F) -> F::Output {
+    match id {
+        Some(id) => REQUEST_ID.scope(id, future).await,
+        None => future.await,
+    }
+}
+
+fn generate() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The caller's id, if it is one to keep: visible ASCII, not too long.
+fn usable(value: &HeaderValue) -> Option<&str> {
+    value.to_str().ok().filter(|v| (1..=MAX_LEN).contains(&v.len()) && v.bytes().all(|b| b.is_ascii_graphic()))
+}
+
+/// Middleware assigning the request id; mounted outermost so everything
+/// else runs with it.
+pub async fn request_id(req: Request<Body>, next: Next) -> Response {
+    let id = req.headers().get(&HEADER).and_then(usable).map_or_else(generate, str::to_string);
+    let span = info_span!("request", id = %id, method = %req.method(), path = %req.uri().path());
+    let header = HeaderValue::from_str(&id).ok();
+    let mut response = REQUEST_ID.scope(id, next.run(req).instrument(span)).await;
+    if let Some(header) = header {
+        response.headers_mut().insert(HEADER, header);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::get, Router};
+
+    #[test]
+    fn only_short_visible_ascii_ids_are_kept() {
+        let usable = |id: &[u8]| usable(&HeaderValue::from_bytes(id).unwrap()).map(str::to_string);
+        assert_eq!(usable(b"abc-123"), Some("abc-123".to_string()));
+        assert_eq!(usable(&[b'a'; MAX_LEN]).map(|id| id.len()), Some(MAX_LEN));
+        assert_eq!(usable(&[b'a'; MAX_LEN + 1]), None);
+        assert_eq!(usable(b""), None);
+        assert_eq!(usable(b"two words"), None);
+        assert_eq!(usable("caf\u{e9}".as_bytes()), None);
+        assert_eq!(generate().len(), 32);
+        assert_ne!(generate(), generate());
+    }
+
+    #[tokio::test]
+    async fn the_id_reaches_the_handler_and_the_response() {
+        // What the handler sees, also from work it hands on with `scope`.
+        let app = Router::new()
+            .route("/", get(|| async { scope(current(), async { current().unwrap_or_default() }).await }))
+            .layer(middleware::from_fn(request_id));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let http = reqwest::Client::new();
+
+        let response = http.get(&url).header(&HEADER, "trace-42").send().await.unwrap();
+        assert_eq!(response.headers()[&HEADER], "trace-42");
+        assert_eq!(response.text().await.unwrap(), "trace-42");
+
+        let response = http.get(&url).header(&HEADER, "not usable").send().await.unwrap();
+        let id = response.headers()[&HEADER].to_str().unwrap().to_string();
+        assert_eq!(id.len(), 32);
+        assert_eq!(response.text().await.unwrap(), id);
+        assert_eq!(current(), None);
+    }
+}
@@ -0,0 +1,129 @@
+// Data retention. Without it `measurements` (and the raw / rejected payload
+// tables) grow forever. With `retention.days` set, a background task
+// periodically deletes rows older than that through the DB worker and
+// checkpoints so the freed blocks are reused:
+//
+//   [retention]
+//   days = 365
+//   interval_hours = 24
+//
+// A mapping's `retention_days` overrides `days` for that sensor, in either
+// direction: keep TPMS tyre sensors for a week and the weather station for
+// five years with
+//
+//   {"sensor_id": "1a2b3c", "manufacturer": "Toyota", "name": "front left",
+//    "retention_days": 7}
+//
+// Overrides also apply without a global `days`, in which case only the
+// overridden sensors are pruned. Mappings are read at every run, so changes
+// take effect at the next one.
+//
+// Parquet archives are not touched; they are the long-term store. With
+// scheduled archiving (`archive.interval_hours`) measurement rows are only
+// pruned once an archive holds them, so rows that arrived late for a day
+// that was already archived wait for the next archive run instead of being
+// lost. Pruned rows and the duration of the last run are exported as
+// metrics.
+use crate::config::RetentionConfig;
+use crate::db::{DbHandle, PruneReport, SensorRetention};
+use crate::state::Store;
+use prometheus::{Gauge, IntCounterVec, Opts, Registry};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+pub struct RetentionMetrics {
+    pruned: IntCounterVec,
+    duration: Gauge,
+    last_run: Gauge,
+}
+
+impl RetentionMetrics {
+    pub fn new(registry: &Registry) -> anyhow::Result<Self> {
+        let metrics = Self {
+            pruned: IntCounterVec::new(
+                Opts::new("retention_pruned_rows_total", "Rows deleted by retention pruning"),
+                &["table"],
+            )?,
+            duration: Gauge::new("retention_prune_duration_seconds", "Duration of the last retention prune")?,
+            last_run: Gauge::new(
+                "retention_last_prune_timestamp_seconds",
+                "Unix time of the last successful retention prune",
+            )?,
+        };
+        registry.register(Box::new(metrics.pruned.clone()))?;
+        registry.register(Box::new(metrics.duration.clone()))?;
+        registry.register(Box::new(metrics.last_run.clone()))?;
+        Ok(metrics)
+    }
+
+    fn record(&self, report: &PruneReport, took: Duration) {
+        self.pruned.with_label_values(&["measurements"]).inc_by(report.measurements as u64);
+        self.pruned.with_label_values(&["raw_messages"]).inc_by(report.raw_messages as u64);
+        self.pruned.with_label_values(&["rejected_messages"]).inc_by(report.rejected_messages as u64);
+        self.duration.set(took.as_secs_f64());
+        self.last_run.set(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default());
+    }
+}
+
+/// Epoch ms `days` ago.
+fn cutoff_ms(days: u64) -> i64 {
+    let cutoff = SystemTime::now().checked_sub(Duration::from_secs(days.saturating_mul(86_400))).unwrap_or(UNIX_EPOCH);
+    cutoff.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or_default()
+}
+
+/// Prune on startup and then every `interval_hours`. Runs are skipped
+/// while neither `days` nor any mapping's `retention_days` is set. With
+/// `archived_only` unarchived measurement rows are kept.
+pub async fn run(db: DbHandle, config: RetentionConfig, metrics: RetentionMetrics, store: Store, archived_only: bool) {
+    let period = Duration::from_secs(config.interval_hours.max(1).saturating_mul(3600));
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let sensors: Vec<SensorRetention> = store
+            .read()
+            .await
+            .values()
+            .filter_map(|m| {
+                let days = m.retention_days?;
+                Some(SensorRetention { model: m.manufacturer.clone(), sensor_id: m.sensor_id.clone(), before_ms: cutoff_ms(days) })
+            })
+            .collect();
+        if config.days.is_none() && sensors.is_empty() {
+            continue;
+        }
+        let overrides = sensors.len();
+        let started = Instant::now();
+        match db.prune(config.days.map(cutoff_ms), sensors, archived_only).await {
+            Ok(report) => {
+                let took = started.elapsed();
+                metrics.record(&report, took);
+                let days = config.days.map_or_else(|| "no global limit".to_string(), |d| format!("{} days", d));
+                info!(
+                    "Retention: pruned {} measurements, {} raw and {} rejected messages ({}, {} sensor overrides) in {:.1}s",
+                    report.measurements,
+                    report.raw_messages,
+                    report.rejected_messages,
+                    days,
+                    overrides,
+                    took.as_secs_f64()
+                );
+            }
+            Err(e) => error!("Retention prune failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cutoffs_never_underflow() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        assert!((now - cutoff_ms(0)).abs() < 60_000);
+        assert!((now - 86_400_000 - cutoff_ms(1)).abs() < 60_000);
+        // Further back than the epoch, or than a SystemTime reaches.
+        assert_eq!(cutoff_ms(1_000_000), 0);
+        assert_eq!(cutoff_ms(u64::MAX), 0);
+    }
+}
@@ -0,0 +1,67 @@
+// Continuous aggregation. Dashboards looking at months of data do not need
+// every raw reading, so a background task keeps two rollup tables up to
+// date, `measurements_5m` and `measurements_1h`, with min/max/avg/count per
+// sensor, measurement type and bucket:
+//
+//   [rollup]
+//   interval_secs = 300
+//
+// Each run aggregates only the rows added since the previous run (tracked
+// by `seq`, which follows commit order) and merges them into their
+// buckets, so late readings are folded into the right bucket without
+// recomputing it from raw rows that retention may have pruned. The query API reads them with
+// `GET /api/measurements?resolution=5m|1h`. Rollups are not pruned by
+// retention and outlive the raw rows they were computed from.
+use crate::config::RollupConfig;
+use crate::db::DbHandle;
+use prometheus::{Gauge, IntCounter, Registry};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+pub struct RollupMetrics {
+    buckets: IntCounter,
+    duration: Gauge,
+    last_run: Gauge,
+}
+
+impl RollupMetrics {
+    pub fn new(registry: &Registry) -> anyhow::Result<Self> {
+        let metrics = Self {
+            buckets: IntCounter::new("rollup_buckets_written_total", "Rollup buckets (re)computed")?,
+            duration: Gauge::new("rollup_duration_seconds", "Duration of the last rollup run")?,
+            last_run: Gauge::new("rollup_last_run_timestamp_seconds", "Unix time of the last successful rollup run")?,
+        };
+        registry.register(Box::new(metrics.buckets.clone()))?;
+        registry.register(Box::new(metrics.duration.clone()))?;
+        registry.register(Box::new(metrics.last_run.clone()))?;
+        Ok(metrics)
+    }
+}
+
+/// Roll up on startup and then every `interval_secs`. Returns immediately
+/// when rollups are disabled.
+pub async fn run(db: DbHandle, config: RollupConfig, metrics: RollupMetrics) {
+    if config.interval_secs == 0 {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let started = Instant::now();
+        match db.rollup().await {
+            Ok(buckets) => {
+                let took = started.elapsed();
+                metrics.buckets.inc_by(buckets as u64);
+                metrics.duration.set(took.as_secs_f64());
+                metrics
+                    .last_run
+                    .set(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default());
+                if buckets > 0 {
+                    info!("Rollup: wrote {} buckets in {:.1}s", buckets, took.as_secs_f64());
+                }
+            }
+            Err(e) => error!("Rollup failed: {}", e),
+        }
+    }
+}
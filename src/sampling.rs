@@ -0,0 +1,117 @@
+// Sampled storage for high-rate topics. Power meters and similar devices
+// can send several messages a second, which nobody needs to keep at full
+// resolution for years. A sampling rule stores only every Nth message of
+// each sensor on matching topics:
+//
+//   [[sampling]]
+//   topic = "rtl_433/power/#"    # MQTT topic filter
+//   every = 10
+//
+// The first message of a sensor is stored, then every `every`th after it,
+// counted per sensor so sensors sharing a topic are thinned alike. Skipped
+// messages still update the gauges, composites, states and every other
+// sink; only the DuckDB buffer and the raw payload store leave them out, so
+// storage grows at a predictable fraction of the message rate. The first
+// matching rule applies; payloads that fail to normalize are always
+// dead-lettered. Skipped messages are counted in
+// `ingest_sampled_out_total{topic}`, labelled by the rule's filter.
+use crate::config::SamplingRule;
+use crate::mqtt_buffer::NormalizedRow;
+use crate::state::SensorKey;
+use prometheus::{IntCounterVec, Opts, Registry};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub struct Sampler {
+    rules: Vec<SamplingRule>,
+    /// Messages seen per (rule, sensor) since its last stored one.
+    seen: Mutex<HashMap<(usize, SensorKey), u64>>,
+    sampled_out: IntCounterVec,
+}
+
+impl Sampler {
+    pub fn new(rules: &[SamplingRule], registry: &Registry) -> anyhow::Result<Self> {
+        for rule in rules {
+            if !rumqttc::valid_filter(&rule.topic) {
+                anyhow::bail!("sampling: invalid topic filter {}", rule.topic);
+            }
+            if rule.every == 0 {
+                anyhow::bail!("sampling {}: every must be at least 1", rule.topic);
+            }
+        }
+        let sampled_out = IntCounterVec::new(
+            Opts::new("ingest_sampled_out_total", "Messages not stored because of a sampling rule, by topic filter"),
+            &["topic"],
+        )?;
+        registry.register(Box::new(sampled_out.clone()))?;
+        Ok(Self { rules: rules.to_vec(), seen: Mutex::new(HashMap::new()), sampled_out })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether the rows of a message on `topic` are stored. Messages on
+    /// topics without a rule always are.
+    pub fn keep(&self, topic: &str, rows: &[NormalizedRow]) -> bool {
+        let Some((index, rule)) = self.rules.iter().enumerate().find(|(_, rule)| rumqttc::matches(topic, &rule.topic))
+        else {
+            return true;
+        };
+        let Some(row) = rows.first() else { return true };
+        let key = SensorKey { model: row.model.clone(), sensor_id: row.sensor_id.clone() };
+        let mut seen = self.seen.lock().unwrap();
+        let count = seen.entry((index, key)).or_default();
+        let keep = *count == 0;
+        *count = (*count + 1) % rule.every;
+        if !keep {
+            self.sampled_out.with_label_values(&[rule.topic.as_str()]).inc();
+        }
+        keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(topic: &str, every: u64) -> SamplingRule {
+        SamplingRule { topic: topic.to_string(), every }
+    }
+
+    fn message(sensor_id: &str) -> Vec<NormalizedRow> {
+        vec![NormalizedRow {
+            timestamp: chrono::Utc::now().naive_utc(),
+            sensor_id: sensor_id.to_string(),
+            model: "Efergy-e2CT".to_string(),
+            measurement_type: 1,
+            value: 230.0,
+            topic: "rtl_433/power/events".to_string(),
+            seq: 0,
+            site: String::new(),
+            probe: String::new(),
+        }]
+    }
+
+    #[test]
+    fn every_nth_message_of_each_sensor_is_kept() {
+        let registry = Registry::new();
+        let sampler = Sampler::new(&[rule("rtl_433/power/#", 3), rule("rtl_433/#", 1)], &registry).unwrap();
+        let kept: Vec<bool> = (0..7).map(|_| sampler.keep("rtl_433/power/events", &message("1"))).collect();
+        assert_eq!(kept, [true, false, false, true, false, false, true]);
+        // Sensors sharing a topic are counted apart.
+        assert!(sampler.keep("rtl_433/power/events", &message("2")));
+        // The first matching rule applies; topics without one are kept.
+        assert!((0..3).all(|_| sampler.keep("rtl_433/events", &message("1"))));
+        assert!(sampler.keep("weather/events", &message("1")));
+        assert!(sampler.keep("rtl_433/power/events", &[]));
+        assert_eq!(sampler.sampled_out.with_label_values(&["rtl_433/power/#"]).get(), 4);
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!(Sampler::new(&[rule("rtl_433/#/power", 10)], &Registry::new()).is_err());
+        assert!(Sampler::new(&[rule("rtl_433/#", 0)], &Registry::new()).is_err());
+        assert!(Sampler::new(&[], &Registry::new()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,22 @@
+// Schema export. `schema` prints the SQL the exporter applies to its
+// database at startup: the tables, and the views generated from the
+// measurement registry (built-ins plus `[[measurements]]`, see
+// `db::views_sql`), e.g. to declare dbt sources or document the file for
+// analysts querying it directly:
+//
+// Run with: `rust-to-mqtt-prometheus-exporter schema > schema.sql`
+//
+// The views (`readings_<key>` per measurement type, `measurements_wide`
+// with a column per type) are recreated at every start, so they follow
+// changes to `[[measurements]]`. Nothing is opened or written.
+use crate::config::Config;
+use crate::db;
+use crate::mqtt_buffer::install_measurement_types;
+
+/// Entry point for the `schema` command.
+pub async fn run_cli() -> anyhow::Result<()> {
+    let config = Config::load()?;
+    install_measurement_types(&config.measurements)?;
+    print!("{}", db::schema_sql());
+    Ok(())
+}
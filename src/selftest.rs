@@ -0,0 +1,132 @@
+// Post-deploy self-test. `selftest` connects to the configured broker as a
+// separate client, publishes a synthetic rtl_433-style message on a topic
+// the running exporter subscribes to, and then checks over HTTP that the
+// reading shows up on `/metrics` and, once flushed, in DuckDB via
+// `/api/measurements`. The process exits non-zero if any step fails, so it
+// can gate a deployment.
+//
+// Run with: `rust-to-mqtt-prometheus-exporter selftest [http://host:port]`
+use crate::config::Config;
+use crate::mqtt::mqtt_options;
+use rumqttc::{AsyncClient, Event, Incoming, QoS};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// `model` of the synthetic readings, so they are easy to recognise and
+/// purge.
+const SELFTEST_MODEL: &str = "selftest";
+/// How long the reading may take to show up on `/metrics`.
+const METRICS_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Options of the `selftest` command.
+#[derive(clap::Args)]
+pub struct Args {
+    /// Base URL of the exporter; defaults to the configured `http.bind`
+    /// address.
+    base_url: Option<String>,
+}
+
+/// Entry point for the `selftest` command.
+pub async fn run_cli(args: Args) -> anyhow::Result<()> {
+    let config = Config::load()?;
+    crate::logging::init(&config.logging)?;
+    let base_url = match args.base_url {
+        None => config.http.local_url(),
+        Some(url) => url.trim_end_matches('/').to_string(),
+    };
+    let topic = loopback_topic(&config.mqtt.topics)
+        .ok_or_else(|| anyhow::anyhow!("at least one MQTT topic must be configured (mqtt.topics or MQTT_TOPIC)"))?;
+    let nonce = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let sensor_id = format!("selftest-{}", nonce);
+    // With HTTP authentication on, read with the first configured token.
+    let mut headers = reqwest::header::HeaderMap::new();
+    let auth = &config.http.auth;
+    if let Some(token) = auth.read_tokens.iter().chain(&auth.write_tokens).chain(auth.keys.iter().map(|k| &k.token)).chain(&config.http.admin_token).next() {
+        headers.insert(reqwest::header::AUTHORIZATION, format!("Bearer {}", token).parse()?);
+    }
+    let http = reqwest::Client::builder().timeout(Duration::from_secs(10)).default_headers(headers).build()?;
+
+    let payload = serde_json::json!({ "model": SELFTEST_MODEL, "id": sensor_id, "temperature_C": 21.5 });
+    let client_id = format!("{}-selftest", crate::mqtt::client_id(&config.mqtt)?);
+    publish(&config, &client_id, &topic, payload.to_string().into_bytes()).await?;
+    println!("ok: published synthetic reading for {} on {}", sensor_id, topic);
+
+    let needle = format!("sensor_id=\"{}\"", sensor_id);
+    let deadline = Instant::now() + METRICS_TIMEOUT;
+    loop {
+        let body = http.get(format!("{}/metrics", base_url)).send().await?.error_for_status()?.text().await?;
+        if body.lines().any(|l| l.starts_with("sensor_temperature_celsius{") && l.contains(&needle)) {
+            break;
+        }
+        if Instant::now() > deadline {
+            return Err(anyhow::anyhow!("reading did not appear on {}/metrics within {:?}", base_url, METRICS_TIMEOUT));
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    println!("ok: reading exported on /metrics");
+
+    // Ask for a flush through the control topic when there is one,
+    // otherwise wait for the periodic flush.
+    if let Some(control) = &config.mqtt.control_topic {
+        publish(&config, &client_id, control, b"flush".to_vec()).await?;
+    }
+    let stored_timeout = METRICS_TIMEOUT + Duration::from_secs(config.flush.interval_secs);
+    let deadline = Instant::now() + stored_timeout;
+    let url = format!("{}/api/measurements", base_url);
+    loop {
+        let body: serde_json::Value = serde_json::from_str(
+            &http
+                .get(&url)
+                .query(&[("model", SELFTEST_MODEL), ("sensor_id", sensor_id.as_str())])
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?,
+        )?;
+        if body["rows"].as_array().is_some_and(|rows| !rows.is_empty()) {
+            break;
+        }
+        if Instant::now() > deadline {
+            return Err(anyhow::anyhow!("reading was not stored in DuckDB within {:?}", stored_timeout));
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    println!("ok: reading stored in DuckDB");
+
+    // Remove the synthetic rows again when the admin API is available.
+    if let Some(token) = &config.http.admin_token {
+        http.delete(format!("{}/api/sensors/{}/{}/data", base_url, SELFTEST_MODEL, sensor_id))
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()?;
+        println!("ok: synthetic rows purged");
+    }
+    println!("selftest passed");
+    Ok(())
+}
+
+/// A concrete topic matched by the first configured filter, with wildcard
+/// levels replaced by `selftest`.
+fn loopback_topic(filters: &[String]) -> Option<String> {
+    let filter = filters.first()?;
+    let levels: Vec<&str> = filter.split('/').map(|l| if l == "+" || l == "#" { "selftest" } else { l }).collect();
+    Some(levels.join("/"))
+}
+
+/// Publish one message and wait until the broker acknowledged it.
+async fn publish(config: &Config, client_id: &str, topic: &str, payload: Vec<u8>) -> anyhow::Result<()> {
+    let (client, mut eventloop) = AsyncClient::new(mqtt_options(&config.mqtt, client_id)?, 10);
+    client.publish(topic, QoS::AtLeastOnce, false, payload).await?;
+    let acked = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let Event::Incoming(Incoming::PubAck(_)) = eventloop.poll().await? {
+                return anyhow::Ok(());
+            }
+        }
+    });
+    acked.await.map_err(|_| anyhow::anyhow!("broker did not acknowledge the publish on {}", topic))??;
+    client.disconnect().await?;
+    let _ = tokio::time::timeout(Duration::from_secs(1), eventloop.poll()).await;
+    Ok(())
+}
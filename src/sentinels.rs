@@ -0,0 +1,92 @@
+// Sentinel values. Some sensors report a fixed value instead of a reading
+// when they are faulty (humidity 0 or 255, temperature -999) or drift
+// past the physical range (humidity above 100), and a single such row
+// ruins every chart scale. Rules per rtl_433 model and measurement key,
+// with `"*"` for every model, say which raw payload values are not
+// readings:
+//
+//   [sentinels."Acurite-Tower"]
+//   humidity = { values = [0, 255] }
+//   temperature_C = { values = [-999] }
+//
+//   [sentinels."*"]
+//   humidity = { min = 0, max = 100, clamp = true }
+//
+// A value listed under `values` is dropped: the message's other readings
+// are kept, this one is neither stored nor exported. A value outside
+// `min` / `max` is dropped too, or moved to the nearest bound with
+// `clamp = true`. Model rules take precedence over the `"*"` ones for the
+// same key. Rules compare the payload value before a measurement's
+// `scale` / `offset`. Readings handled this way are counted in
+// `sentinel_readings_total{model,measurement,action}`.
+use crate::config::SentinelRule;
+use crate::mqtt_buffer::measurement_code;
+use prometheus::{IntCounterVec, Opts, Registry};
+use std::collections::{BTreeMap, HashMap};
+
+/// Model of the rules applying to every model.
+const ANY_MODEL: &str = "*";
+
+#[derive(Default)]
+pub struct Sentinels {
+    /// Rules by model, then measurement key.
+    rules: HashMap<String, HashMap<String, SentinelRule>>,
+    counter: Option<IntCounterVec>,
+}
+
+impl Sentinels {
+    pub fn new(config: &BTreeMap<String, BTreeMap<String, SentinelRule>>, registry: &Registry) -> anyhow::Result<Self> {
+        let mut rules = HashMap::new();
+        for (model, keys) in config {
+            for (key, rule) in keys {
+                if measurement_code(key).is_none() {
+                    anyhow::bail!("sentinels.{}: unknown measurement {}", model, key);
+                }
+                if let (Some(min), Some(max)) = (rule.min, rule.max)
+                    && min > max
+                {
+                    anyhow::bail!("sentinels.{}.{}: min {} is above max {}", model, key, min, max);
+                }
+                if rule.clamp && rule.min.is_none() && rule.max.is_none() {
+                    anyhow::bail!("sentinels.{}.{}: clamp needs min or max", model, key);
+                }
+            }
+            rules.insert(model.clone(), keys.iter().map(|(k, r)| (k.clone(), r.clone())).collect());
+        }
+        let counter = IntCounterVec::new(
+            Opts::new("sentinel_readings_total", "Readings recognized as sentinel or out-of-range values, by action"),
+            &["model", "measurement", "action"],
+        )?;
+        registry.register(Box::new(counter.clone()))?;
+        Ok(Self { rules, counter: Some(counter) })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The value to store for the payload value of `key`, or `None` when it
+    /// is not a reading.
+    pub fn check(&self, model: &str, key: &str, value: f64) -> Option<f64> {
+        if self.rules.is_empty() {
+            return Some(value);
+        }
+        let rule = [model, ANY_MODEL].iter().find_map(|m| self.rules.get(*m).and_then(|keys| keys.get(key)));
+        let Some(rule) = rule else { return Some(value) };
+        let (checked, action) = if rule.values.contains(&value) {
+            (None, "dropped")
+        } else if rule.min.is_some_and(|min| value < min) || rule.max.is_some_and(|max| value > max) {
+            if rule.clamp {
+                (Some(value.clamp(rule.min.unwrap_or(f64::MIN), rule.max.unwrap_or(f64::MAX))), "clamped")
+            } else {
+                (None, "dropped")
+            }
+        } else {
+            return Some(value);
+        };
+        if let Some(counter) = &self.counter {
+            counter.with_label_values(&[model, key, action]).inc();
+        }
+        checked
+    }
+}
@@ -0,0 +1,207 @@
+// HTTP connection handling. The router is served by hyper's automatic
+// HTTP/1.1 + HTTP/2 connection builder instead of `axum::serve`, so the
+// protocol, keep-alive and concurrency limits can be tuned:
+//
+//   [http]
+//   http2 = true                    # cleartext HTTP/2 (prior knowledge)
+//   keep_alive = true               # HTTP/1.1 persistent connections
+//   keep_alive_interval_secs = 30   # HTTP/2 pings, 0 = none
+//   keep_alive_timeout_secs = 20
+//   max_concurrent_streams = 100    # per HTTP/2 connection
+//   max_connections = 0             # 0 = unlimited
+//
+// With HTTP/2 a client such as Grafana runs its parallel queries and
+// streams over a single multiplexed connection. Connections beyond
+// `max_connections` stay in the listen backlog until one closes. At
+// shutdown open connections get `shutdown.http_timeout_secs` to finish
+// their requests; this is the last phase of `shutdown`.
+//
+// To expose the UI and API on a LAN without a reverse proxy, the server can
+// speak HTTPS (rustls, HTTP/2 negotiated with ALPN) and answer plain HTTP on
+// a second address with redirects:
+//
+//   [http]
+//   bind = "0.0.0.0:443"
+//   tls_cert_file = "/etc/exporter/cert.pem"   # chain, leaf first
+//   tls_key_file = "/etc/exporter/key.pem"     # PKCS#8, PKCS#1 or SEC1
+//   redirect_bind = "0.0.0.0:80"
+//
+// The redirect keeps the requested host and path and points at the HTTPS
+// port (`308`, so clients repeat the method and body). The certificate is
+// read at startup; replacing it needs a restart.
+use crate::config::HttpConfig;
+use crate::problem::Problem;
+use crate::shutdown;
+use axum::extract::State;
+use axum::http::{header::HOST, uri::Authority, HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use hyper_util::service::TowerToHyperService;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{Notify, Semaphore};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, warn};
+
+/// Time a client gets to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn builder(config: &HttpConfig) -> Builder<TokioExecutor> {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder.http1().keep_alive(config.keep_alive).timer(TokioTimer::new());
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(config.max_concurrent_streams.max(1))
+        .keep_alive_interval((config.keep_alive_interval_secs > 0).then(|| Duration::from_secs(config.keep_alive_interval_secs)))
+        .keep_alive_timeout(Duration::from_secs(config.keep_alive_timeout_secs.max(1)));
+    if config.http2 { builder } else { builder.http1_only() }
+}
+
+/// TLS settings from `tls_cert_file` and `tls_key_file`; `None` to serve
+/// plain HTTP.
+pub fn tls_acceptor(config: &HttpConfig) -> anyhow::Result<Option<TlsAcceptor>> {
+    let (cert_file, key_file) = match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) if config.redirect_bind.is_some() => return Err(anyhow::anyhow!("redirect_bind needs tls_cert_file and tls_key_file")),
+        (None, None) => return Ok(None),
+        _ => return Err(anyhow::anyhow!("HTTPS needs both tls_cert_file and tls_key_file")),
+    };
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("cannot read certificates from {}: {}", cert_file, e))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("no certificate in {}", cert_file));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(|e| anyhow::anyhow!("cannot read private key from {}: {}", key_file, e))?;
+    let mut tls = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| anyhow::anyhow!("invalid certificate or key in {} / {}: {}", cert_file, key_file, e))?;
+    tls.alpn_protocols = if config.http2 { vec![b"h2".to_vec(), b"http/1.1".to_vec()] } else { vec![b"http/1.1".to_vec()] };
+    Ok(Some(TlsAcceptor::from(Arc::new(tls))))
+}
+
+/// Router of the `redirect_bind` listener: every request is sent to the
+/// same host and path on `https_port`.
+pub fn redirect_router(https_port: u16) -> Router {
+    Router::new().fallback(redirect_to_https).with_state(https_port)
+}
+
+async fn redirect_to_https(State(https_port): State<u16>, headers: HeaderMap, uri: Uri) -> Response {
+    let host = headers
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<Authority>().ok())
+        .or_else(|| uri.authority().cloned());
+    let Some(host) = host else {
+        return Problem::new(StatusCode::BAD_REQUEST, "missing_host", "use HTTPS").into_response();
+    };
+    let port = if https_port == 443 { String::new() } else { format!(":{}", https_port) };
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    Redirect::permanent(&format!("https://{}{}{}", host.host(), port, path)).into_response()
+}
+
+/// Serve `app` on `listener` until `shutdown` is notified, then wait up to
+/// `grace` for open connections. Returns whether they all finished. With
+/// `tls` connections are HTTPS.
+pub async fn serve(listener: TcpListener, app: Router, config: &HttpConfig, tls: Option<TlsAcceptor>, shutdown: Arc<Notify>, grace: Duration) -> anyhow::Result<bool> {
+    let builder = Arc::new(builder(config));
+    let slots = (config.max_connections > 0).then(|| Arc::new(Semaphore::new(config.max_connections)));
+    let graceful = GracefulShutdown::new();
+    let stop = shutdown.notified();
+    tokio::pin!(stop);
+
+    loop {
+        let permit = match &slots {
+            Some(slots) => tokio::select! {
+                permit = slots.clone().acquire_owned() => Some(permit?),
+                _ = &mut stop => break,
+            },
+            None => None,
+        };
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually out of file descriptors; back off briefly.
+                    warn!("Cannot accept HTTP connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = &mut stop => break,
+        };
+        let (builder, app, tls, watcher) = (builder.clone(), app.clone(), tls.clone(), graceful.watcher());
+        tokio::spawn(async move {
+            let result = match tls {
+                Some(tls) => match tokio::time::timeout(HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+                    Ok(Ok(stream)) => serve_connection(&builder, stream, app, watcher).await,
+                    Ok(Err(e)) => Err(format!("TLS handshake failed: {}", e).into()),
+                    Err(_) => Err("TLS handshake timed out".into()),
+                },
+                None => serve_connection(&builder, stream, app, watcher).await,
+            };
+            if let Err(e) = result {
+                debug!("HTTP connection from {} closed: {}", peer, e);
+            }
+            drop(permit);
+        });
+    }
+
+    let open = graceful.count();
+    Ok(shutdown::phase("stop HTTP", grace, async {
+        graceful.shutdown().await;
+        Ok(format!("{} open connections finished", open))
+    })
+    .await)
+}
+
+async fn serve_connection<I>(builder: &Builder<TokioExecutor>, io: I, app: Router, watcher: Watcher) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Without upgrades: the upgradeable connection ignores `http1_only`.
+    let connection = builder.serve_connection(TokioIo::new(io), TowerToHyperService::new(app));
+    watcher.watch(connection.into_owned()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header::LOCATION, HeaderValue};
+
+    async fn redirect(https_port: u16, host: Option<&str>, uri: &str) -> (StatusCode, Option<String>) {
+        let mut headers = HeaderMap::new();
+        if let Some(host) = host {
+            headers.insert(HOST, HeaderValue::from_str(host).unwrap());
+        }
+        let response = redirect_to_https(State(https_port), headers, uri.parse().unwrap()).await;
+        let location = response.headers().get(LOCATION).map(|l| l.to_str().unwrap().to_string());
+        (response.status(), location)
+    }
+
+    #[tokio::test]
+    async fn redirects_keep_host_and_path_on_the_https_port() {
+        assert_eq!(
+            redirect(443, Some("exporter.lan"), "/api/status?x=1").await,
+            (StatusCode::PERMANENT_REDIRECT, Some("https://exporter.lan/api/status?x=1".to_string()))
+        );
+        // The plain HTTP port in Host is replaced by the HTTPS one.
+        assert_eq!(
+            redirect(8443, Some("exporter.lan:80"), "/").await,
+            (StatusCode::PERMANENT_REDIRECT, Some("https://exporter.lan:8443/".to_string()))
+        );
+        assert_eq!(
+            redirect(443, None, "http://10.0.0.5/metrics").await,
+            (StatusCode::PERMANENT_REDIRECT, Some("https://10.0.0.5/metrics".to_string()))
+        );
+        assert_eq!(redirect(443, None, "/metrics").await, (StatusCode::BAD_REQUEST, None));
+    }
+}
@@ -1,60 +1,593 @@
-// `server.rs` composes the HTTP application: it loads initial state,
-// registers Prometheus metrics, starts the MQTT background task, and
-// mounts HTTP handlers and middleware.
-use crate::{handlers, mqtt, state::{load_mappings, Store}};
-use axum::{routing::{get, put}, Router, Extension};
-use prometheus::{Registry, IntCounter};
+// `server.rs` composes the HTTP application: it loads the configuration and
+// initial state, starts the DuckDB worker, registers Prometheus metrics,
+// starts the MQTT background task and the flush and other background tasks,
+// and mounts HTTP handlers and middleware (`run_with`). `run` adds the
+// config file, environment and signal handling of the binary.
+use crate::{aggregator, archive, auth::{self, Authenticator}, composite::Composites, config::{BackupConfig, Config}, counters::PersistedCounters, grafana, crypto::PayloadCipher, db::{self, DbHandle}, dedup::Deduplicator, enrichment::Enrichment, flush_timer::{self, FlushSchedule, FlushTimerMetrics}, handlers, health::Health, logging::{self, LogFilter, LogLimiter}, metrics::{self, HttpMetrics, IntegrationHealth, SensorExpectation, SensorFreshness, SensorGauges}, mqtt, problem, radio::{self, RadioStats}, rate_limit::RateLimiter, reload::Reloader, remote_read, remote_write::RemoteWriter, request_id, influx::InfluxWriter, live::{self, LiveFeed}, sink::{JsonlSink, Sink}, sampling::Sampler, sentinels::Sentinels, report::{self, Reporter}, retention, rollup, serve, shutdown::{Shutdown, Tasks}, spool, status::{self, Status}, degree_days::{self, DegreeDays}, jobs::{self, Jobs}, states::States, sync, weather::{self, Weather}, upload::{self, Uploader}, version::{self, Version}, warm::WarmState, wal::Wal, mqtt_buffer::{install_measurement_types, FlushClass, FlushPolicy, MqttBuffer, Normalizer}, state::{load_mappings, LastSeen, LatestReadings, Store, TopicStats}, strict_start::StartGate};
+use axum::{routing::{delete, get, patch, post, put}, Router, Extension};
+use prometheus::{Registry, IntCounter, IntCounterVec, Opts};
 use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Notify};
 use tokio::task;
 use axum::middleware::{self, Next};
 use axum::response::Response;
 use axum::http::{Request, Method, HeaderValue, StatusCode};
+use tracing::{debug, error, info, warn};
 
+/// Load the configuration from the config file and environment, install
+/// the global log subscriber and run until SIGINT/SIGTERM; SIGHUP reloads.
+pub async fn run() -> anyhow::Result<()> {
+    let config = Config::load()?;
+    #[cfg(feature = "otel")]
+    let log_filter = logging::init_traced(&config.logging, crate::otel::tracer(&config.otel)?)?;
+    #[cfg(not(feature = "otel"))]
+    let log_filter = logging::init(&config.logging)?;
+    let mut server = run_with(config, log_filter).await?;
+    let (mut sigterm, mut sighup) = (signal(SignalKind::terminate())?, signal(SignalKind::hangup())?);
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => {
+                info!("SIGHUP received, checkpointing database and reloading");
+                server.reload().await;
+            }
+            _ = sigterm.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+            stopped = server.stopped() => return stopped,
+        }
+    }
+    server.shutdown().await
+}
 
+/// A running exporter, see `run_with`.
+pub struct ServerHandle {
+    local_addr: std::net::SocketAddr,
+    db: DbHandle,
+    reloader: Reloader,
+    shutdown: Arc<Shutdown>,
+    /// Serves HTTP until the shutdown phases are done.
+    http: task::JoinHandle<anyhow::Result<()>>,
+}
 
-pub async fn run() -> anyhow::Result<()> {
-    let initial = load_mappings().await.unwrap_or_default();
+impl ServerHandle {
+    /// Where HTTP is served, e.g. the port picked for `http.bind` port 0.
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.local_addr
+    }
+
+    /// Checkpoint the database and reload the mappings and the
+    /// configuration (`Config::reload`), like SIGHUP does.
+    pub async fn reload(&self) {
+        if let Err(e) = self.db.flush().await {
+            error!("Checkpoint failed: {}", e);
+        }
+        self.reloader.reload().await;
+    }
+
+    /// Wait until HTTP serving ends on its own, i.e. with an error.
+    pub async fn stopped(&mut self) -> anyhow::Result<()> {
+        (&mut self.http).await?
+    }
+
+    /// Run the shutdown phases and wait until the exporter has stopped.
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        self.shutdown.run().await;
+        self.http.await?
+    }
+}
+
+/// Start the exporter with a configuration built by the caller and return
+/// once it is serving. Nothing is read from the environment, no signal
+/// handlers are installed and no global state is set apart from the
+/// measurement types; the caller stops it with `ServerHandle::shutdown`.
+/// `log_filter` comes from `logging::init`, or `logging::detached` when the
+/// embedding binary installs its own subscriber. Measurement types are
+/// process-wide, so this can run once per process.
+pub async fn run_with(config: Config, log_filter: LogFilter) -> anyhow::Result<ServerHandle> {
+    let started_at = chrono::Utc::now();
+    let log_filter = Arc::new(log_filter);
+    if let Some(path) = &config.source {
+        info!("Loaded configuration from {}", path);
+    }
+    install_measurement_types(&config.measurements)?;
+    let composites = Composites::new(&config.composites)?;
+    if !composites.is_empty() {
+        info!("Evaluating {} composite sensors", composites.len());
+    }
+    let initial = load_mappings(&config.mapping.file).await.unwrap_or_default();
     let store: Store = Arc::new(tokio::sync::RwLock::new(initial));
+    let latest: LatestReadings = Arc::new(tokio::sync::RwLock::new(Default::default()));
+    let topic_stats: TopicStats = Arc::new(tokio::sync::RwLock::new(Default::default()));
+    let last_seen: LastSeen = Arc::new(tokio::sync::RwLock::new(Default::default()));
 
     let registry = Arc::new(Registry::new());
+    let (db, db_worker) = db::start_db_worker(&config.duckdb, &registry)?;
+    let tasks = Arc::new(Tasks::default());
+    let start_gate = config.mqtt.strict_start.then(|| Arc::new(StartGate::default()));
+    if let Some(gate) = start_gate.clone() {
+        info!("Strict start: holding back ingestion until mappings and database schema are verified");
+        let (db, store, mappings_file) = (db.clone(), store.clone(), config.mapping.file.clone());
+        tasks.spawn("startup checks", async move { gate.verify(&db, &store, &mappings_file).await });
+    }
+    {
+        let db = db.clone();
+        let dir = config.shutdown.spool_dir.clone();
+        let start = start_gate.clone();
+        tasks.spawn("spool restore", async move {
+            if let Some(gate) = start {
+                gate.opened().await;
+            }
+            if let Err(e) = spool::restore(std::path::Path::new(&dir), &db).await {
+                error!("Restoring spooled rows failed: {}", e);
+            }
+        });
+    }
     let messages_counter = IntCounter::new("mqtt_messages_total", "Total MQTT messages received").unwrap();
     registry.register(Box::new(messages_counter.clone())).ok();
+    let topic_counter = IntCounterVec::new(
+        Opts::new("mqtt_topic_messages_total", "MQTT data messages received per subscribed topic filter"),
+        &["topic"],
+    )?;
+    registry.register(Box::new(topic_counter.clone()))?;
+    let rejected_counter = IntCounter::new("mqtt_messages_rejected_total", "MQTT data messages that could not be normalized")?;
+    registry.register(Box::new(rejected_counter.clone()))?;
+    let mut counters = PersistedCounters::new(&config.metrics, db.clone(), &registry)?;
+    counters.track(messages_counter.clone());
+    counters.track_vec(topic_counter.clone());
+    counters.track(rejected_counter.clone());
+    match counters.restore().await {
+        Ok(0) => {}
+        Ok(n) => info!("Restored {} counters", n),
+        Err(e) => warn!("Cannot restore counters, starting from zero: {}", e),
+    }
+    let counters = Arc::new(counters);
+    tasks.spawn("counters", counters.clone().run());
+    let buffer = Arc::new(MqttBuffer::new(&registry, FlushPolicy::from_config(&config.flush))?);
+    buffer.set_classes(FlushClass::from_config(&config.flush)?);
+    if let Some(wal) = Wal::open(&config.wal)? {
+        info!("Logging MQTT messages to {} before ingest", wal.dir().display());
+        buffer.set_wal(Arc::new(wal));
+    }
+    let log = Arc::new(LogLimiter::new(&config.logging));
+    let enrichment = Arc::new(Enrichment::new(&config.enrichment, db.clone(), log.clone(), &registry)?);
+    {
+        let enrichment = enrichment.clone();
+        tasks.spawn("enrichment", async move {
+            if let Err(e) = enrichment.load().await {
+                error!("Loading sensor labels failed: {}", e);
+            }
+        });
+    }
+    let gauges = Arc::new(SensorGauges::new(&registry, &config.metrics, enrichment)?);
+    let http_metrics = Arc::new(HttpMetrics::new(&registry)?);
+    let freshness = Arc::new(SensorFreshness::new(
+        &registry,
+        std::time::Duration::from_secs(config.metrics.stale_after_secs),
+    )?);
+    let expectation = Arc::new(SensorExpectation::new(&registry)?);
+    let radio = Arc::new(RadioStats::new(&config.radio, db.clone(), &registry)?);
+    tasks.spawn("radio", radio.clone().run());
+    let health = IntegrationHealth::new(&registry)?;
+    // A hub replicating other exporters may run without a broker.
+    let mqtt_enabled = !config.mqtt.topics.is_empty() || config.aggregator.sources.is_empty();
+    if mqtt_enabled {
+        health.register_source(mqtt::SOURCE);
+    }
+    let mut sinks: Vec<Arc<dyn Sink>> = Vec::new();
+    if config.sinks.gauges {
+        sinks.push(gauges.clone());
+    }
+    if let Some(writer) = RemoteWriter::new(&config.remote_write, &registry)?.map(Arc::new) {
+        info!("Pushing readings to remote_write endpoint {}", config.remote_write.url.as_deref().unwrap_or_default());
+        tasks.spawn("remote_write", writer.clone().run());
+        sinks.push(writer);
+    }
+    #[cfg(feature = "otel")]
+    let otel = crate::otel::MetricsExporter::new(&config.otel, registry.clone())?.map(Arc::new);
+    #[cfg(feature = "otel")]
+    if let Some(exporter) = &otel {
+        info!("Exporting metrics over OTLP to {}", config.otel.endpoint.as_deref().unwrap_or_default());
+        tasks.spawn("otel", exporter.clone().run());
+    }
+    #[cfg(not(feature = "otel"))]
+    if config.otel.endpoint.is_some() {
+        warn!("otel.endpoint is set, but this build has no OTLP export (cargo feature otel)");
+    }
+    if let Some(writer) = InfluxWriter::new(&config.influx, &registry)?.map(Arc::new) {
+        info!(
+            "Writing readings to InfluxDB at {} (bucket {})",
+            config.influx.url.as_deref().unwrap_or_default(),
+            config.influx.bucket
+        );
+        tasks.spawn("influx", writer.clone().run());
+        sinks.push(writer);
+    }
+    if let Some(jsonl) = JsonlSink::new(&config.sinks)? {
+        sinks.push(Arc::new(jsonl));
+    }
+    if config.sinks.duckdb {
+        sinks.push(buffer.clone());
+    }
+    let live = Arc::new(LiveFeed::default());
+    sinks.push(live.clone());
+    info!("Ingest sinks: {}", sinks.iter().map(|s| s.name()).collect::<Vec<_>>().join(", "));
+    let uploader = Uploader::new(&config.s3, &registry)?.map(Arc::new);
+    let retention_metrics = retention::RetentionMetrics::new(&registry)?;
+    let states = Arc::new(States::new(&config.states, &registry, db.clone())?);
+    if let Err(e) = states.restore().await {
+        warn!("Cannot restore derived states, starting from scratch: {}", e);
+    }
+    let weather = Arc::new(Weather::new(&config.weather, &registry)?);
+    if let Err(e) = weather.restore(&db).await {
+        warn!("Cannot replay today's weather readings: {}", e);
+    }
+    tasks.spawn("weather", weather::run(weather.clone()));
+    let rollup_metrics = rollup::RollupMetrics::new(&registry)?;
+    let degree_days = DegreeDays::new(&config.degree_days, &registry)?;
+
+    let cipher = match &config.raw.key_file {
+        Some(path) => Some(Arc::new(PayloadCipher::from_key_file(std::path::Path::new(path))?)),
+        None => None,
+    };
+    if config.raw.store {
+        let mode = if cipher.is_some() { "encrypted" } else { "plaintext" };
+        info!("Storing raw MQTT payloads ({})", mode);
+    }
 
-    let mqtt_counter = messages_counter.clone();
-    task::spawn(async move {
-        if let Err(e) = mqtt::start_mqtt_loop(mqtt_counter).await {
-            eprintln!("MQTT task ended: {}", e);
+    let reload = Arc::new(Notify::new());
+    let stop_mqtt = Arc::new(Notify::new());
+    let normalizer = Arc::new(Normalizer::new(
+        &config.mqtt.formats,
+        &config.models,
+        &config.probes,
+        Sentinels::new(&config.sentinels, &registry)?,
+    )?);
+    let (topics_tx, topics_rx) = watch::channel(config.mqtt.topics.clone());
+    let sampler = Sampler::new(&config.sampling, &registry)?;
+    if !sampler.is_empty() {
+        info!("Sampling storage of {} topic filters", config.sampling.len());
+    }
+    let rate_limiter = RateLimiter::new(&config.rate_limit, &registry)?;
+    if rate_limiter.is_enabled() {
+        info!("Limiting every sensor to {} messages a minute", config.rate_limit.messages_per_minute);
+    }
+    let warm = Arc::new(WarmState::new(
+        &config.shutdown.state_file,
+        gauges.clone(),
+        freshness.clone(),
+        last_seen.clone(),
+        latest.clone(),
+    ));
+    match warm.restore(&store).await {
+        Ok(0) => {}
+        Ok(n) => info!("Restored {} gauge series from {}", n, config.shutdown.state_file),
+        Err(e) => warn!("Cannot restore the gauge cache, starting empty: {}", e),
+    }
+    let ingest = mqtt::IngestContext {
+        counter: messages_counter.clone(),
+        connection: mqtt::ConnectionMetrics::new(&registry)?,
+        topic_counter,
+        rejected_counter,
+        dedup: Arc::new(Deduplicator::new(&config.dedup, &registry)?),
+        normalizer: normalizer.clone(),
+        buffer: buffer.clone(),
+        db: db.clone(),
+        store: store.clone(),
+        latest: latest.clone(),
+        topic_stats: topic_stats.clone(),
+        last_seen: last_seen.clone(),
+        freshness: freshness.clone(),
+        expectation: expectation.clone(),
+        radio: radio.clone(),
+        composites: Arc::new(composites),
+        states: states.clone(),
+        weather: weather.clone(),
+        sinks,
+        rate_limiter: Arc::new(rate_limiter),
+        sampler: Arc::new(sampler),
+        health: health.clone(),
+        cipher: cipher.clone(),
+        reload: reload.clone(),
+        topics: topics_rx,
+        stop: stop_mqtt.clone(),
+        log,
+    };
+    if start_gate.is_none() {
+        replay_wal(&config, &ingest).await;
+    }
+    aggregator::spawn_all(
+        &config.aggregator,
+        aggregator::AggregatorContext {
+            db: db.clone(),
+            store: store.clone(),
+            gauges: gauges.clone(),
+            health: health.clone(),
+            metrics: Arc::new(aggregator::AggregatorMetrics::new(&registry)?),
+            start: start_gate.clone(),
+        },
+        &tasks,
+    )?;
+    let probes = Arc::new(Health {
+        config: config.health.clone(),
+        db: db.clone(),
+        db_worker,
+        buffer: buffer.clone(),
+        integration: health.clone(),
+        mqtt_enabled,
+        start: start_gate.clone(),
+    });
+    let mqtt_task = if mqtt_enabled {
+        let mqtt_config = config.clone();
+        Some(task::spawn(async move {
+            if let Some(gate) = start_gate {
+                gate.opened().await;
+                replay_wal(&mqtt_config, &ingest).await;
+            }
+            if let Err(e) = mqtt::start_mqtt_loop(mqtt_config, ingest).await {
+                error!("MQTT task ended: {}", e);
+            }
+            health.set_up(mqtt::SOURCE, false);
+        }))
+    } else {
+        info!("No MQTT topics configured, running as aggregator only");
+        if let Some(gate) = start_gate {
+            let config = config.clone();
+            tasks.spawn("wal replay", async move {
+                gate.opened().await;
+                replay_wal(&config, &ingest).await;
+            });
         }
+        None
+    };
+
+    tasks.spawn("staleness", refresh_staleness(freshness.clone(), last_seen.clone()));
+    tasks.spawn("expected", count_expected(expectation, store.clone()));
+    tasks.spawn("gauge expiry", expire_gauges(gauges.clone()));
+    tasks.spawn("rename", handlers::resume_renames(handlers::RenameContext {
+        db: db.clone(),
+        store: store.clone(),
+        mappings_file: config.mapping.file.clone(),
+        latest: latest.clone(),
+        gauges: gauges.clone(),
+        last_seen: last_seen.clone(),
+        freshness: freshness.clone(),
+    }));
+
+    let (flush_schedule_tx, flush_schedule) = watch::channel(FlushSchedule::from_config(&config.flush));
+    tasks.spawn_flush(flush_timer::run(buffer.clone(), db.clone(), flush_schedule, FlushTimerMetrics::new(&registry)?, tasks.stop_flush.clone()));
+    if let Some(days) = config.retention.days {
+        info!("Keeping {} days of data in DuckDB", days);
+    }
+    tasks.spawn("archive", archive::run_scheduled(db.clone(), config.archive.clone(), uploader.clone()));
+    let archived_only = config.archive.interval_hours.is_some();
+    tasks.spawn("retention", retention::run(db.clone(), config.retention.clone(), retention_metrics, store.clone(), archived_only));
+    tasks.spawn("rollup", rollup::run(db.clone(), config.rollup.clone(), rollup_metrics));
+    tasks.spawn("degree days", degree_days::run(degree_days, db.clone(), store.clone()));
+    let reporter = Arc::new(Reporter::new(&config.report, db.clone(), store.clone())?);
+    tasks.spawn("report", report::run(reporter.clone()));
+    let jobs = Arc::new(Jobs::new(&config.jobs));
+    tasks.spawn("jobs", jobs::run(jobs.clone()));
+    if let Some(uploader) = &uploader {
+        tasks.spawn("backup", periodic_backup(db.clone(), uploader.clone(), config.backup.clone()));
+    }
+
+    let shutdown = Arc::new(Shutdown::new(
+        config.shutdown.clone(),
+        buffer.clone(),
+        db.clone(),
+        counters,
+        warm,
+        radio,
+        live.clone(),
+        stop_mqtt,
+        mqtt_task,
+        tasks.clone(),
+    ));
+    let subscribed = topics_tx.subscribe();
+    let reloader = Reloader {
+        current: std::sync::Mutex::new(config.clone()),
+        store: store.clone(),
+        buffer: buffer.clone(),
+        topics: topics_tx,
+        flush_schedule: flush_schedule_tx,
+        tls: reload,
+        mqtt_enabled,
+    };
+
+    let version = Arc::new(Version::detect(std::path::Path::new(handlers::UI_DIR)));
+    info!("exporter {} ({}), UI build {}", version.version, version.git_sha, version.ui_hash.as_deref().unwrap_or("missing"));
+    let status = Arc::new(Status {
+        started_at,
+        version: version.clone(),
+        db: db.clone(),
+        db_path: config.duckdb.path.clone(),
+        buffer: buffer.clone(),
+        integration: probes.integration.clone(),
+        mqtt_enabled,
+        broker: format!("{}:{}", config.mqtt.host, config.mqtt.port),
+        control_topic: config.mqtt.control_topic.clone(),
+        topics: subscribed,
     });
 
     // Build the HTTP app. Layers are applied from bottom -> top: the
     // `Extension` layers provide shared state (Store and Registry) to
-    // handlers. The CORS middleware is mounted last so it can ensure
-    // headers are applied to all responses.
+    // handlers. The CORS middleware is mounted late so it can ensure
+    // headers are applied to all responses; the request id outermost, so
+    // everything else, metrics included, runs with it.
     let app = Router::new()
         .route("/mapping", put(handlers::put_mapping).get(handlers::list_mappings))
+        .route("/mapping/{sensor_id}", patch(handlers::patch_mapping).delete(handlers::delete_mapping))
         .route("/metrics", get(handlers::metrics_handler))
-        .route("/health", get(|| async { "ok" }))
+        .route("/metrics/aggregate", get(handlers::aggregate_metrics_handler))
+        .route("/api/parse-preview", post(handlers::parse_preview))
+        .route("/api/v1/read", post(remote_read::remote_read_handler))
+        .route("/grafana", get(grafana::test_handler))
+        .route("/grafana/", get(grafana::test_handler))
+        .route("/grafana/search", post(grafana::search_handler))
+        .route("/grafana/query", post(grafana::query_handler))
+        .route("/api/topics", get(handlers::topic_stats))
+        .route("/api/stale", get(handlers::stale_sensors))
+        .route("/api/sensors", get(handlers::list_sensors))
+        .route("/api/stream", get(live::stream_handler))
+        .route("/api/states", get(handlers::list_states))
+        .route("/api/states/transitions", get(handlers::state_transitions))
+        .route("/api/weather", get(handlers::weather_summary))
+        .route("/api/radio", get(radio::radio_handler))
+        .route("/api/report", get(report::report_handler))
+        .route("/api/report/send", post(report::send_handler))
+        .route("/api/sync", get(sync::sync_handler))
+        .route("/api/measurements", get(handlers::query_measurements))
+        .route("/api/series", get(handlers::query_series))
+        .route("/api/measurement-types", get(handlers::list_measurement_types))
+        .route("/api/jobs/query", post(handlers::submit_query_job))
+        .route("/api/jobs/{id}", get(handlers::get_job))
+        .route("/api/jobs/{id}/download", get(handlers::download_job))
+        .route("/api/archive", get(handlers::list_archives).post(handlers::register_archive))
+        .route("/api/export/parquet", post(handlers::export_parquet))
+        .route("/api/admin/raw", get(handlers::export_raw))
+        .route("/api/admin/explain", post(handlers::explain))
+        .route("/api/admin/flush", post(handlers::admin_flush))
+        .route("/api/admin/log-filter", get(handlers::get_log_filter).put(handlers::put_log_filter))
+        .route("/api/sensors/{model}/{id}/data", delete(handlers::purge_sensor))
+        .route("/api/sensors/{model}/{id}/battery-replaced", post(handlers::battery_replaced))
+        .route("/api/sensors/{model}/{id}/rename", post(handlers::rename_sensor))
+        .route("/health", get(handlers::health))
+        .route("/healthz/live", get(crate::health::live))
+        .route("/healthz/ready", get(crate::health::ready))
+        .route("/api/version", get(version::version_handler))
+        .route("/api/status", get(status::status_handler))
         .fallback_service(get(handlers::spa_handler))
         .layer(Extension(store))
-        .layer(Extension(registry))
-        .layer(middleware::from_fn(cors_middleware));
+        .layer(Extension(latest))
+        .layer(Extension(topic_stats))
+        .layer(Extension(last_seen))
+        .layer(Extension(freshness))
+        .layer(Extension(db.clone()))
+        .layer(Extension(uploader))
+        .layer(Extension(config.archive.clone()))
+        .layer(Extension(config.mapping.clone()))
+        .layer(Extension(gauges))
+        .layer(Extension(log_filter))
+        .layer(Extension(states))
+        .layer(Extension(weather))
+        .layer(Extension(normalizer))
+        .layer(Extension(buffer.clone()))
+        .layer(Extension(live))
+        .layer(Extension(jobs))
+        .layer(Extension(reporter))
+        .layer(Extension(handlers::AdminAccess { token: config.http.admin_token.clone(), cipher }))
+        .layer(Extension(version.clone()))
+        .layer(Extension(probes))
+        .layer(Extension(status))
+        .layer(Extension(registry.clone()));
+    let app = match Authenticator::new(&config.http.auth, config.http.admin_token.as_deref(), &registry)? {
+        Some(auth) => {
+            info!("HTTP authentication enabled");
+            app.layer(middleware::from_fn_with_state(Arc::new(auth), auth::require))
+        }
+        None => app,
+    };
+    let app = app
+        .layer(middleware::from_fn(problem::problem_json))
+        .layer(middleware::from_fn(cors_middleware))
+        .layer(middleware::from_fn_with_state(version, version::ui_version_header))
+        .layer(middleware::from_fn_with_state(http_metrics, metrics::track_http))
+        .layer(middleware::from_fn(request_id::request_id));
+
+    let bind_addr = &config.http.bind;
+    let tls = serve::tls_acceptor(&config.http)?;
+    info!("listening on {}{}", bind_addr, if tls.is_some() { " (HTTPS)" } else { "" });
 
-    let bind_addr = "0.0.0.0:3000";
-    println!("listening on {}", bind_addr);
+    let listener = tokio::net::TcpListener::bind(bind_addr.as_str()).await?;
+    let local_addr = listener.local_addr()?;
+    if let Some(redirect_bind) = &config.http.redirect_bind {
+        let redirect = tokio::net::TcpListener::bind(redirect_bind.as_str()).await?;
+        let https_port = listener.local_addr()?.port();
+        info!("redirecting HTTP on {} to HTTPS port {}", redirect_bind, https_port);
+        tasks.spawn("https redirect", async move {
+            if let Err(e) = axum::serve(redirect, serve::redirect_router(https_port)).await {
+                error!("HTTP redirect listener ended: {}", e);
+            }
+        });
+    }
+    let grace = std::time::Duration::from_secs(config.shutdown.http_timeout_secs.max(1));
+    let http = {
+        let (shutdown, http_config) = (shutdown.clone(), config.http.clone());
+        task::spawn(async move {
+            let http_ok = serve::serve(listener, app, &http_config, tls, shutdown.stop_http.clone(), grace).await?;
+            #[cfg(feature = "otel")]
+            crate::otel::shutdown(otel.as_deref()).await;
+            shutdown.finish(http_ok);
+            Ok(())
+        })
+    };
+    Ok(ServerHandle { local_addr, db, reloader, shutdown, http })
+}
+
+/// Recompute `sensor_stale` every 30 seconds.
+async fn refresh_staleness(freshness: Arc<SensorFreshness>, last_seen: LastSeen) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+        freshness.refresh(&*last_seen.read().await, std::time::SystemTime::now());
+    }
+}
+
+/// Ingest the messages the WAL kept from the last run.
+async fn replay_wal(config: &Config, ingest: &mqtt::IngestContext) {
+    match mqtt::replay_wal(config, ingest).await {
+        Ok(0) => {}
+        Ok(n) => info!("Replayed {} messages from the WAL", n),
+        Err(e) => warn!("Cannot replay the WAL, kept for the next start: {}", e),
+    }
+}
 
-    let listener = tokio::net::TcpListener::bind(bind_addr).await?;
-    axum::serve(listener, app).await?;
+/// Remove gauge series past their maximum age every 10 seconds.
+async fn expire_gauges(gauges: Arc<SensorGauges>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+    loop {
+        interval.tick().await;
+        let removed = gauges.expire(std::time::SystemTime::now());
+        if removed > 0 {
+            debug!("Removed {} gauge series past their maximum age", removed);
+        }
+    }
+}
+
+/// Advance `sensor_expected_messages_total` every 10 seconds.
+async fn count_expected(expectation: Arc<SensorExpectation>, store: Store) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+    let mut last = tokio::time::Instant::now();
+    loop {
+        let now = interval.tick().await;
+        expectation.advance(&*store.read().await, now - last);
+        last = now;
+    }
+}
 
-    Ok(())
+/// Snapshot the database to Parquet and upload it every
+/// `backup.interval_hours` while object storage is configured. Local
+/// snapshots are written to `backup.dir` and removed after a successful
+/// upload.
+async fn periodic_backup(db: DbHandle, uploader: Arc<Uploader>, backup: BackupConfig) {
+    let dir = std::path::PathBuf::from(backup.dir);
+    let period = std::time::Duration::from_secs(backup.interval_hours.max(1) * 3600);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        match upload::backup_and_upload(&db, &uploader, &dir).await {
+            Ok(key) => info!("Backup uploaded as {}", key),
+            Err(e) => error!("Backup failed: {}", e),
+        }
+    }
 }
 
 async fn cors_middleware(req: Request<axum::body::Body>, next: Next) -> Response {
     let allow_headers = HeaderValue::from_static("*");
-    let allow_methods = HeaderValue::from_static("GET,PUT,POST,OPTIONS");
+    let allow_methods = HeaderValue::from_static("GET,PUT,PATCH,POST,DELETE,OPTIONS");
     let allow_origin = HeaderValue::from_static("*");
 
-    if req.method() == &Method::OPTIONS {
+    if req.method() == Method::OPTIONS {
         let mut res = Response::new(axum::body::Body::empty());
         *res.status_mut() = StatusCode::NO_CONTENT;
         let headers = res.headers_mut();
@@ -74,5 +607,7 @@ async fn cors_middleware(req: Request<axum::body::Body>, next: Next) -> Response
     headers.insert("access-control-allow-origin", allow_origin);
     headers.insert("access-control-allow-methods", allow_methods);
     headers.insert("access-control-allow-headers", allow_headers);
+    // Let cross-origin frontends see the UI build, see `version`.
+    headers.insert("access-control-expose-headers", HeaderValue::from_static("x-ui-version, x-request-id"));
     res
 }
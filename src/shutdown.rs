@@ -0,0 +1,313 @@
+// Shutdown on SIGINT/SIGTERM, in phases that each have their own timeout
+// and log how they went:
+//
+//   1. stop ingest   the MQTT loop finishes the message at hand and
+//                    disconnects from the broker; aborted on timeout
+//      stop tasks    the periodic flush finishes the flush at hand; the
+//                    other background tasks (`Tasks`: aggregator, spool
+//                    restore, retention, remote_write, ...) are aborted
+//      state         the gauge cache is written to `state_file`, see `warm`
+//   2. flush         the ingest buffer is written to DuckDB
+//      spool         only if the flush failed: what is left of the buffer
+//                    is written to `spool_dir`, see `spool`
+//      counters      the persisted counters are saved, see `counters`
+//      radio         the radio statistics are written, see `radio`
+//   3. checkpoint    the WAL is merged into the database file
+//   4. close DB      the DB worker closes the database and stops
+//   5. stop HTTP     live streams end and open connections finish their
+//                    requests (`serve`)
+//
+//   [shutdown]
+//   ingest_timeout_secs = 5
+//   flush_timeout_secs = 30
+//   state_timeout_secs = 10
+//   spool_timeout_secs = 10
+//   counters_timeout_secs = 10
+//   radio_timeout_secs = 10
+//   checkpoint_timeout_secs = 30
+//   close_timeout_secs = 10
+//   http_timeout_secs = 10
+//
+// A phase that fails or times out is logged with the reason and the next
+// phase runs anyway, so e.g. a failed flush still gets the database
+// checkpointed and closed. The last line names the phases that failed.
+// HTTP is stopped last so probes and metrics stay available while the
+// data is written; requests needing the database fail once it is closed.
+// Once the buffer is flushed or spooled the ingest write-ahead log (`wal`,
+// not DuckDB's) is emptied; otherwise it is replayed at the next start.
+// The spool does not wait for a flush that is still running (the periodic
+// one, stuck on a locked database); its rows are not spooled, the WAL is
+// kept, and the spooled rows may then be stored twice.
+use crate::config::ShutdownConfig;
+use crate::counters::PersistedCounters;
+use crate::db::DbHandle;
+use crate::mqtt_buffer::MqttBuffer;
+use crate::live::LiveFeed;
+use crate::radio::RadioStats;
+use crate::spool;
+use crate::warm::WarmState;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// Background tasks of the exporter, stopped before the database closes.
+#[derive(Default)]
+pub struct Tasks {
+    tasks: Mutex<Vec<(&'static str, JoinHandle<()>)>>,
+    /// Stops the periodic flush between flushes, see `flush_timer::run`.
+    pub stop_flush: Arc<Notify>,
+    flush: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Tasks {
+    /// Spawn `work`, to be aborted at shutdown.
+    pub fn spawn<F>(&self, name: &'static str, work: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.lock().unwrap().push((name, tokio::spawn(work)));
+    }
+
+    /// Spawn the periodic flush, which is stopped with `stop_flush` rather
+    /// than aborted so no append is cut short.
+    pub fn spawn_flush<F>(&self, work: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        *self.flush.lock().unwrap() = Some(tokio::spawn(work));
+    }
+
+    /// Stop the periodic flush, then abort the other tasks and wait for
+    /// them to end. The flush is aborted too if it does not stop within
+    /// `timeout`.
+    async fn stop(&self, timeout: Duration) -> anyhow::Result<String> {
+        let flush = self.flush.lock().unwrap().take();
+        let mut aborted = Vec::new();
+        if let Some(mut flush) = flush {
+            self.stop_flush.notify_one();
+            if tokio::time::timeout(timeout, &mut flush).await.is_err() {
+                flush.abort();
+                aborted.push("periodic flush");
+            }
+        }
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        let n = tasks.len();
+        for (_, task) in &tasks {
+            task.abort();
+        }
+        for (name, task) in tasks {
+            if let Err(e) = task.await
+                && !e.is_cancelled()
+            {
+                warn!("Background task {} failed: {}", name, e);
+            }
+        }
+        if !aborted.is_empty() {
+            anyhow::bail!("aborted the {} after it did not stop", aborted.join(", "));
+        }
+        Ok(format!("stopped {} background tasks", n))
+    }
+}
+
+pub struct Shutdown {
+    pub config: ShutdownConfig,
+    pub buffer: Arc<MqttBuffer>,
+    pub db: DbHandle,
+    pub counters: Arc<PersistedCounters>,
+    pub warm: Arc<WarmState>,
+    pub radio: Arc<RadioStats>,
+    /// Closed before HTTP stops, see `live`.
+    pub live: Arc<LiveFeed>,
+    /// Stops the MQTT loop, see `mqtt::IngestContext::stop`.
+    pub stop_mqtt: Arc<Notify>,
+    /// The MQTT loop's task, `None` when MQTT is disabled.
+    pub mqtt: Mutex<Option<JoinHandle<()>>>,
+    pub tasks: Arc<Tasks>,
+    /// Tells `serve` to stop accepting connections.
+    pub stop_http: Arc<Notify>,
+    /// Names of the phases that failed or timed out.
+    failed: Mutex<Vec<&'static str>>,
+}
+
+fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs.max(1))
+}
+
+/// Run one phase with a timeout and log its outcome. Returns whether it
+/// succeeded.
+pub async fn phase<F>(name: &str, timeout: Duration, work: F) -> bool
+where
+    F: Future<Output = anyhow::Result<String>>,
+{
+    let started = Instant::now();
+    match tokio::time::timeout(timeout, work).await {
+        Ok(Ok(outcome)) => {
+            info!("Shutdown: {} done in {:.1?}, {}", name, started.elapsed(), outcome);
+            true
+        }
+        Ok(Err(e)) => {
+            error!("Shutdown: {} failed after {:.1?}: {}", name, started.elapsed(), e);
+            false
+        }
+        Err(_) => {
+            warn!("Shutdown: {} timed out after {}s", name, timeout.as_secs());
+            false
+        }
+    }
+}
+
+impl Shutdown {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: ShutdownConfig,
+        buffer: Arc<MqttBuffer>,
+        db: DbHandle,
+        counters: Arc<PersistedCounters>,
+        warm: Arc<WarmState>,
+        radio: Arc<RadioStats>,
+        live: Arc<LiveFeed>,
+        stop_mqtt: Arc<Notify>,
+        mqtt: Option<JoinHandle<()>>,
+        tasks: Arc<Tasks>,
+    ) -> Self {
+        Self {
+            config,
+            buffer,
+            db,
+            counters,
+            warm,
+            radio,
+            live,
+            stop_mqtt,
+            mqtt: Mutex::new(mqtt),
+            tasks,
+            stop_http: Arc::new(Notify::new()),
+            failed: Mutex::new(Vec::new()),
+        }
+    }
+
+    async fn run_phase<F>(&self, name: &'static str, timeout_secs: u64, work: F) -> bool
+    where
+        F: Future<Output = anyhow::Result<String>>,
+    {
+        let ok = phase(name, secs(timeout_secs), work).await;
+        if !ok {
+            self.failed.lock().unwrap().push(name);
+        }
+        ok
+    }
+
+    /// Phases 1 to 4, then tell `serve` to stop.
+    pub async fn run(&self) {
+        info!("Shutting down");
+        let mqtt = self.mqtt.lock().unwrap().take();
+        let mqtt_abort = mqtt.as_ref().map(|task| task.abort_handle());
+        let stopped = self
+            .run_phase("stop ingest", self.config.ingest_timeout_secs, async {
+                let Some(task) = mqtt else { return Ok("MQTT is disabled".to_string()) };
+                self.stop_mqtt.notify_one();
+                task.await?;
+                Ok("MQTT loop stopped".to_string())
+            })
+            .await;
+        if !stopped && let Some(task) = mqtt_abort {
+            warn!("Aborting the MQTT loop");
+            task.abort();
+        }
+        // The aborted tasks end at their next await; the phase allows for
+        // that beyond the wait for the periodic flush.
+        let stop_timeout = self.config.flush_timeout_secs.max(1) + self.config.ingest_timeout_secs;
+        self.run_phase("stop tasks", stop_timeout, self.tasks.stop(secs(self.config.flush_timeout_secs))).await;
+        self.run_phase("state", self.config.state_timeout_secs, self.warm.save()).await;
+        let flushed = self
+            .run_phase("flush", self.config.flush_timeout_secs, async {
+                let rows = self.buffer.flush(&self.db).await?;
+                Ok(format!("wrote {} rows", rows))
+            })
+            .await;
+        let mut in_flight = false;
+        let kept = flushed
+            || self
+                .run_phase("spool", self.config.spool_timeout_secs, async {
+                    let unwritten = self.buffer.take_unwritten();
+                    if unwritten.in_flight {
+                        // Left to the WAL, which is kept for the next start.
+                        warn!("A flush is still running; its rows are not spooled");
+                        in_flight = true;
+                    }
+                    spool::write(std::path::Path::new(&self.config.spool_dir), unwritten).await
+                })
+                .await;
+        if kept && !in_flight {
+            self.buffer.clear_wal();
+        }
+        self.run_phase("counters", self.config.counters_timeout_secs, async {
+            let saved = self.counters.save(true).await?;
+            Ok(format!("saved {} counters", saved))
+        })
+        .await;
+        self.run_phase("radio", self.config.radio_timeout_secs, async {
+            let written = self.radio.write().await?;
+            Ok(format!("wrote {} hourly aggregates", written))
+        })
+        .await;
+        self.run_phase("checkpoint", self.config.checkpoint_timeout_secs, async {
+            self.db.flush().await?;
+            Ok("database file up to date".to_string())
+        })
+        .await;
+        self.run_phase("close DB", self.config.close_timeout_secs, async {
+            self.db.close().await?;
+            Ok("database closed".to_string())
+        })
+        .await;
+        // Live streams never finish on their own.
+        self.live.close();
+        self.stop_http.notify_one();
+    }
+
+    /// Log the overall outcome once `serve` has returned with the outcome of
+    /// the HTTP phase.
+    pub fn finish(&self, http_ok: bool) {
+        let mut failed = self.failed.lock().unwrap();
+        if !http_ok {
+            failed.push("stop HTTP");
+        }
+        if failed.is_empty() {
+            info!("Shutdown complete");
+        } else {
+            warn!("Shutdown finished; failed phases: {}", failed.join(", "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stopping_tasks_waits_for_the_flush_and_aborts_the_rest() {
+        let tasks = Tasks::default();
+        let stop = tasks.stop_flush.clone();
+        let flushed = Arc::new(Mutex::new(false));
+        let done = flushed.clone();
+        tasks.spawn_flush(async move {
+            stop.notified().await;
+            *done.lock().unwrap() = true;
+        });
+        tasks.spawn("forever", std::future::pending());
+        assert_eq!(tasks.stop(Duration::from_secs(1)).await.unwrap(), "stopped 1 background tasks");
+        assert!(*flushed.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_flush_that_does_not_stop_is_aborted() {
+        let tasks = Tasks::default();
+        tasks.spawn_flush(std::future::pending());
+        assert!(tasks.stop(Duration::from_millis(10)).await.is_err());
+        assert!(tasks.flush.lock().unwrap().is_none());
+    }
+}
@@ -0,0 +1,359 @@
+// Outputs of the ingest pipeline. The MQTT loop hands the rows of each
+// message to every enabled sink, in the units of their mapping (see
+// `units`; the DuckDB buffer takes the canonical ones), after dedup,
+// composites and derived states:
+//
+//   duckdb        the ingest buffer, flushed to DuckDB (`mqtt_buffer`)
+//   gauges        the per-sensor Prometheus gauges (`metrics`)
+//   remote_write  Prometheus remote_write (`remote_write`)
+//   influx        InfluxDB line protocol (`influx`)
+//   jsonl         one JSON object per reading on stdout or appended to a
+//                 file, e.g. for piping into other tools
+//   live          the `/api/stream` subscribers (`live`), always on
+//
+//   [sinks]
+//   duckdb = true
+//   gauges = true
+//   jsonl = "-"          # stdout; or a file path; unset = disabled
+//
+// remote_write and InfluxDB are enabled by setting their `url`. With
+// `jsonl = "-"` the log goes to stderr so stdout carries only readings.
+// Sinks must not block ingest: the network outputs queue the rows in a
+// `RetryQueue` and send them from their own task, and `jsonl` hands its
+// lines to a writer thread. A new output implements `Sink` and is added to
+// the list in `server`.
+use crate::config::SinksConfig;
+use crate::handlers::MeasurementRow;
+use crate::mqtt_buffer::NormalizedRow;
+use crate::state::Mapping;
+use prometheus::{IntCounter, IntGauge};
+use reqwest::StatusCode;
+use std::collections::{HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tracing::warn;
+
+/// The rows of one message, as handed to the sinks.
+pub struct Readings<'a> {
+    /// In the units of their mapping.
+    pub rows: &'a [NormalizedRow],
+    /// The same rows in canonical units, as they are stored.
+    pub canonical: &'a [NormalizedRow],
+    /// When the message was received.
+    pub received: Instant,
+    pub mappings: &'a HashMap<String, Mapping>,
+    /// False for messages skipped by a `sampling` rule, which the DuckDB
+    /// buffer leaves out.
+    pub store: bool,
+}
+
+pub trait Sink: Send + Sync {
+    /// Short name used in logs, e.g. `influx`.
+    fn name(&self) -> &'static str;
+
+    /// Take the rows of one message.
+    fn push(&self, readings: &Readings);
+}
+
+/// Why a batch could not be sent.
+pub enum SendError {
+    /// The receiver is unavailable; the batch is retried with backoff.
+    Retry(String),
+    /// The receiver refused the batch; it is dropped.
+    Rejected(String),
+}
+
+impl SendError {
+    /// Classify an unsuccessful response the way Prometheus does: 5xx and
+    /// 429 are retried, other 4xx not.
+    pub fn from_status(status: StatusCode, detail: String) -> Self {
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Self::Retry(detail)
+        } else {
+            Self::Rejected(detail)
+        }
+    }
+}
+
+/// Bounded queue between `Sink::push` and a sender task. The sender takes
+/// a batch out of the queue while it is in flight and gives it back when
+/// sending failed, so samples queued meanwhile never displace it: when the
+/// queue overflows, the oldest queued items are dropped and counted.
+pub struct RetryQueue<T> {
+    state: Mutex<QueueState<T>>,
+    batch_size: usize,
+    max_queue: usize,
+    /// Wakes the sender early once a full batch is queued.
+    batch_ready: Notify,
+    /// Queued plus in-flight items.
+    length: IntGauge,
+    dropped: IntCounter,
+}
+
+struct QueueState<T> {
+    items: VecDeque<T>,
+    in_flight: usize,
+}
+
+impl<T> RetryQueue<T> {
+    pub fn new(batch_size: usize, max_queue: usize, length: IntGauge, dropped: IntCounter) -> anyhow::Result<Self> {
+        anyhow::ensure!(batch_size > 0, "batch_size must be at least 1");
+        Ok(Self {
+            state: Mutex::new(QueueState { items: VecDeque::new(), in_flight: 0 }),
+            batch_size,
+            max_queue,
+            batch_ready: Notify::new(),
+            length,
+            dropped,
+        })
+    }
+
+    /// Append items. Never blocks; drops the oldest queued items beyond
+    /// `max_queue`.
+    pub fn push(&self, items: impl IntoIterator<Item = T>) {
+        let mut state = self.state.lock().unwrap();
+        state.items.extend(items);
+        self.trim(&mut state);
+        if state.items.len() >= self.batch_size {
+            self.batch_ready.notify_one();
+        }
+    }
+
+    /// Wait until a full batch is queued or `interval` has passed.
+    pub async fn ready(&self, interval: Duration) {
+        let _ = tokio::time::timeout(interval, self.batch_ready.notified()).await;
+    }
+
+    /// Move the oldest `batch_size` items out of the queue. Finish the batch
+    /// with `sent` or hand it back with `retry`.
+    pub fn take(&self) -> Vec<T> {
+        let mut state = self.state.lock().unwrap();
+        debug_assert_eq!(state.in_flight, 0, "one batch in flight at a time");
+        let n = self.batch_size.min(state.items.len());
+        let batch: Vec<T> = state.items.drain(..n).collect();
+        state.in_flight = batch.len();
+        batch
+    }
+
+    /// The batch in flight was sent or dropped for good.
+    pub fn sent(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight = 0;
+        self.length.set(state.items.len() as i64);
+    }
+
+    /// Put a batch that failed back at the front, ahead of everything
+    /// queued meanwhile. If the queue overflowed in the meantime its oldest
+    /// items, the batch first, are dropped.
+    pub fn retry(&self, batch: Vec<T>) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight = 0;
+        for item in batch.into_iter().rev() {
+            state.items.push_front(item);
+        }
+        self.trim(&mut state);
+    }
+
+    fn trim(&self, state: &mut QueueState<T>) {
+        let excess = state.items.len().saturating_sub(self.max_queue);
+        if excess > 0 {
+            state.items.drain(..excess);
+            self.dropped.inc_by(excess as u64);
+        }
+        self.length.set((state.items.len() + state.in_flight) as i64);
+    }
+}
+
+/// Unix time in seconds, for the `*_last_success_timestamp_seconds` gauges.
+pub fn unix_now() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default()
+}
+
+/// Messages whose lines may wait for the JSONL writer thread; later ones
+/// are dropped, e.g. while a reader of stdout is not keeping up.
+const JSONL_QUEUE: usize = 10_000;
+
+/// Writes readings as JSON lines (`handlers::MeasurementRow`, with the
+/// unit), the format of `/api/sync`. Lines are encoded on the ingest path
+/// and written by a dedicated thread through a buffered writer, flushed
+/// once per batch of queued messages.
+pub struct JsonlSink {
+    lines: Mutex<mpsc::SyncSender<Vec<u8>>>,
+    /// Whether the queue was full at the last push, to warn once per
+    /// overflow.
+    overflowing: AtomicBool,
+}
+
+/// Writer thread: runs until the `JsonlSink` is dropped.
+fn write_lines(mut out: BufWriter<Box<dyn Write + Send>>, lines: mpsc::Receiver<Vec<u8>>) {
+    while let Ok(first) = lines.recv() {
+        // A closed stdout or full disk must not stop the thread.
+        for line in std::iter::once(first).chain(lines.try_iter()) {
+            let _ = out.write_all(&line);
+        }
+        let _ = out.flush();
+    }
+}
+
+impl JsonlSink {
+    /// Open the sink when `sinks.jsonl` is set: `-` for stdout, otherwise a
+    /// file that is appended to.
+    pub fn new(config: &SinksConfig) -> anyhow::Result<Option<Self>> {
+        let out: Box<dyn Write + Send> = match config.jsonl.as_deref() {
+            None => return Ok(None),
+            Some("-") => Box::new(std::io::stdout()),
+            Some(path) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| anyhow::anyhow!("cannot open JSONL sink {}: {}", path, e))?,
+            ),
+        };
+        Ok(Some(Self::start(out)))
+    }
+
+    fn start(out: Box<dyn Write + Send>) -> Self {
+        let (lines, receiver) = mpsc::sync_channel(JSONL_QUEUE);
+        let out = BufWriter::new(out);
+        // Spawning a thread only fails when the OS is out of resources.
+        std::thread::Builder::new()
+            .name("jsonl-sink".to_string())
+            .spawn(move || write_lines(out, receiver))
+            .expect("cannot start the JSONL writer thread");
+        Self { lines: Mutex::new(lines), overflowing: AtomicBool::new(false) }
+    }
+}
+
+impl Sink for JsonlSink {
+    fn name(&self) -> &'static str {
+        "jsonl"
+    }
+
+    fn push(&self, readings: &Readings) {
+        let mut lines = Vec::new();
+        for row in readings.rows {
+            // Serializing plain structs cannot fail.
+            let _ = serde_json::to_writer(&mut lines, &MeasurementRow::with_unit(row.clone(), readings.mappings));
+            lines.push(b'\n');
+        }
+        match self.lines.lock().unwrap().try_send(lines) {
+            Ok(()) => self.overflowing.store(false, Ordering::Relaxed),
+            Err(mpsc::TrySendError::Full(_)) => {
+                if !self.overflowing.swap(true, Ordering::Relaxed) {
+                    warn!("JSONL sink is not keeping up, dropping readings");
+                }
+            }
+            // Only when the writer thread died, which it doesn't.
+            Err(mpsc::TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(batch_size: usize, max_queue: usize) -> RetryQueue<u32> {
+        let length = IntGauge::new("queue_length", "test").unwrap();
+        let dropped = IntCounter::new("queue_dropped", "test").unwrap();
+        RetryQueue::new(batch_size, max_queue, length, dropped).unwrap()
+    }
+
+    fn queued(queue: &RetryQueue<u32>) -> Vec<u32> {
+        queue.state.lock().unwrap().items.iter().copied().collect()
+    }
+
+    #[test]
+    fn overflow_during_a_send_drops_queued_items_not_the_batch() {
+        let queue = queue(2, 3);
+        queue.push([1, 2, 3]);
+        let batch = queue.take();
+        assert_eq!(batch, vec![1, 2]);
+        queue.push([4, 5, 6]);
+        assert_eq!(queued(&queue), vec![4, 5, 6]);
+        assert_eq!(queue.dropped.get(), 1);
+        assert_eq!(queue.length.get(), 5);
+        queue.sent();
+        // The next batch starts with what was queued, nothing is skipped.
+        assert_eq!(queue.take(), vec![4, 5]);
+        assert_eq!(queue.length.get(), 3);
+    }
+
+    #[test]
+    fn a_failed_batch_goes_back_to_the_front() {
+        let queue = queue(2, 10);
+        queue.push([1, 2, 3]);
+        let batch = queue.take();
+        queue.push([4]);
+        queue.retry(batch);
+        assert_eq!(queued(&queue), vec![1, 2, 3, 4]);
+        assert_eq!(queue.length.get(), 4);
+
+        // Overflowed while in flight: the retried batch is the oldest.
+        let queue = self::queue(2, 3);
+        queue.push([1, 2]);
+        let batch = queue.take();
+        queue.push([3, 4, 5]);
+        queue.retry(batch);
+        assert_eq!(queued(&queue), vec![3, 4, 5]);
+        assert_eq!(queue.dropped.get(), 2);
+    }
+
+    #[test]
+    fn zero_batch_size_is_rejected() {
+        let length = IntGauge::new("queue_length", "test").unwrap();
+        let dropped = IntCounter::new("queue_dropped", "test").unwrap();
+        assert!(RetryQueue::<u32>::new(0, 10, length, dropped).is_err());
+    }
+
+    /// A writer the test can read back.
+    #[derive(Clone, Default)]
+    struct Shared(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn jsonl_lines_are_written_by_the_writer_thread_in_order() {
+        let out = Shared::default();
+        let sink = JsonlSink::start(Box::new(out.clone()));
+        let mappings = HashMap::new();
+        for (sensor_id, value) in [("1", 21.5), ("2", 40.0)] {
+            let rows = vec![NormalizedRow {
+                timestamp: chrono::NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_opt(8, 0, 0).unwrap(),
+                sensor_id: sensor_id.to_string(),
+                model: "Acurite-Tower".to_string(),
+                measurement_type: 1,
+                value,
+                topic: "rtl_433/events".to_string(),
+                seq: 0,
+                site: String::new(),
+                probe: String::new(),
+            }];
+            sink.push(&Readings { rows: &rows, canonical: &rows, received: Instant::now(), mappings: &mappings, store: true });
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let written = loop {
+            let written = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+            if written.lines().count() == 2 || Instant::now() > deadline {
+                break written;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        };
+        let values: Vec<f64> = written.lines().map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["value"].as_f64().unwrap()).collect();
+        assert_eq!(values, vec![21.5, 40.0]);
+    }
+}
@@ -0,0 +1,165 @@
+// Spool for the ingest buffer. When the final flush at shutdown fails or
+// times out (database locked by another process, disk full, ...), whatever
+// is still buffered is written to Arrow IPC files instead of being dropped
+// with the process:
+//
+//   [shutdown]
+//   spool_dir = "spool"
+//   spool_timeout_secs = 10
+//
+// There is one file per table (`<time>-measurements.arrow`,
+// `<time>-raw_messages.arrow`, `<time>-rejected_messages.arrow`) with the
+// table's schema. At the next start the files are appended to their tables
+// in the background and deleted; a file that cannot be read or appended is
+// logged and kept for the start after. Restored measurement rows are
+// numbered by the DB worker when they are appended, like any other rows,
+// so sync consumers that moved past older numbers still receive them. Rows whose append was still running on the DB worker when
+// the flush timed out may end up stored twice.
+use crate::db::DbHandle;
+use crate::mqtt_buffer::{raw_to_record_batch, rejected_to_record_batch, rows_to_record_batch, Unwritten};
+use arrow::ipc::reader::FileReader;
+use arrow::ipc::writer::FileWriter;
+use chrono::Utc;
+use duckdb::arrow::record_batch::RecordBatch;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use tracing::{error, info};
+
+/// Tables whose rows are spooled, in restore order.
+const TABLES: &[&str] = &["measurements", "raw_messages", "rejected_messages"];
+
+fn write_file(path: &Path, batch: &RecordBatch) -> anyhow::Result<()> {
+    // Written under a temporary name so a crash mid-write leaves no file
+    // that looks complete.
+    let tmp = path.with_extension("arrow.tmp");
+    let mut writer = FileWriter::try_new(BufWriter::new(File::create(&tmp)?), &batch.schema())?;
+    writer.write(batch)?;
+    writer.finish()?;
+    writer.into_inner()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Write `unwritten` to `dir`. Returns a summary for the shutdown log.
+pub async fn write(dir: &Path, unwritten: Unwritten) -> anyhow::Result<String> {
+    if unwritten.is_empty() {
+        return Ok("nothing left to spool".to_string());
+    }
+    let dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        std::fs::create_dir_all(&dir)?;
+        let stamp = Utc::now().format("%Y%m%dT%H%M%S%.6f");
+        let batches = [
+            (unwritten.rows.len(), rows_to_record_batch(&unwritten.rows)?),
+            (unwritten.raw.len(), raw_to_record_batch(&unwritten.raw)?),
+            (unwritten.rejected.len(), rejected_to_record_batch(&unwritten.rejected)?),
+        ];
+        for (table, (len, batch)) in TABLES.iter().zip(batches) {
+            if len > 0 {
+                write_file(&dir.join(format!("{}-{}.arrow", stamp, table)), &batch)?;
+            }
+        }
+        Ok(format!(
+            "spooled {} rows, {} raw and {} rejected messages to {}",
+            unwritten.rows.len(),
+            unwritten.raw.len(),
+            unwritten.rejected.len(),
+            dir.display()
+        ))
+    })
+    .await?
+}
+
+/// The table a spool file belongs to, `None` for other files.
+fn table_of(path: &Path) -> Option<&'static str> {
+    let name = path.file_name()?.to_str()?.strip_suffix(".arrow")?;
+    TABLES.iter().copied().find(|table| name.ends_with(&format!("-{}", table)))
+}
+
+fn read_file(path: &Path) -> anyhow::Result<Vec<RecordBatch>> {
+    let reader = FileReader::try_new(BufReader::new(File::open(path)?), None)?;
+    Ok(reader.collect::<Result<Vec<_>, _>>()?)
+}
+
+async fn restore_file(path: &Path, table: &str, db: &DbHandle) -> anyhow::Result<usize> {
+    let batches = {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || read_file(&path)).await??
+    };
+    let mut rows = 0;
+    for batch in batches {
+        rows += batch.num_rows();
+        db.append(table, batch).await?;
+    }
+    tokio::fs::remove_file(path).await?;
+    Ok(rows)
+}
+
+/// Append the files spooled at the last shutdown to their tables and
+/// delete them.
+pub async fn restore(dir: &Path, db: &DbHandle) -> anyhow::Result<()> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut files: Vec<PathBuf> = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        files.push(entry.path());
+    }
+    // Oldest first; names start with the spool time.
+    files.sort();
+    for path in files {
+        let Some(table) = table_of(&path) else { continue };
+        match restore_file(&path, table, db).await {
+            Ok(rows) => info!("Restored {} rows of {} from spool file {}", rows, table, path.display()),
+            Err(e) => error!("Cannot restore spool file {}, keeping it: {}", path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{start_db_worker, DbConfig};
+    use crate::mqtt_buffer::NormalizedRow;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Stored {
+        sensor_id: String,
+        value: f64,
+    }
+
+    #[tokio::test]
+    async fn spooled_rows_are_restored_into_their_table() {
+        let dir = std::env::temp_dir().join(format!("exporter-spool-test-{}", std::process::id()));
+        let spool_dir = dir.join("spool");
+        std::fs::create_dir_all(&dir).unwrap();
+        let row = NormalizedRow {
+            timestamp: Utc::now().naive_utc(),
+            sensor_id: "1".to_string(),
+            model: "Acurite-Tower".to_string(),
+            measurement_type: 1,
+            value: 21.5,
+            topic: "rtl_433/events".to_string(),
+            seq: 0,
+            site: String::new(),
+            probe: String::new(),
+        };
+        let unwritten = Unwritten { rows: vec![row], ..Unwritten::default() };
+        write(&spool_dir, unwritten).await.unwrap();
+        assert_eq!(std::fs::read_dir(&spool_dir).unwrap().count(), 1);
+
+        let config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let (db, _worker) = start_db_worker(&config, &prometheus::Registry::new()).unwrap();
+        restore(&spool_dir, &db).await.unwrap();
+        let stored: Vec<Stored> = db.query_as("SELECT sensor_id, value FROM measurements", Vec::new()).await.unwrap();
+        assert_eq!(stored.iter().map(|r| (r.sensor_id.as_str(), r.value)).collect::<Vec<_>>(), vec![("1", 21.5)]);
+        assert_eq!(std::fs::read_dir(&spool_dir).unwrap().count(), 0);
+        db.close().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
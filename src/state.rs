@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::{BTreeMap, HashMap}, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
 use tokio::sync::RwLock;
 
 // `Store` is the in-memory mapping store shared across handlers. It wraps
@@ -8,24 +8,114 @@ use tokio::sync::RwLock;
 pub type Store = Arc<RwLock<HashMap<String, Mapping>>>;
 
 // `Mapping` is the JSON structure accepted by the `/mapping` endpoint.
-// Keep it simple: a sensor id, manufacturer and a human-readable name.
+// Keep it simple: a sensor id, manufacturer and a human-readable name, plus
+// optional display units per measurement key (e.g. `temperature_C` -> `F`).
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Mapping {
     pub sensor_id: String,
     pub manufacturer: String,
     pub name: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub units: BTreeMap<String, String>,
+    /// Days this sensor's rows are kept, overriding `retention.days`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<u64>,
+    /// Seconds between the sensor's transmissions, for
+    /// `sensor_expected_messages_total`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_interval_secs: Option<u64>,
 }
 
-// File used as a simple placeholder persistence layer. When you migrate to
-// DuckDB/DuckLake, replace `load_mappings`/`save_mappings` implementations
-// with queries against the DB and remove this file-based path.
-pub const MAPPINGS_FILE: &str = "mappings.json";
+// `LatestReadings` keeps the most recent value seen for every
+// (model, sensor_id, measurement_type) so HTTP handlers can report current
+// values without querying DuckDB. It is updated by the MQTT loop on every
+// normalized message.
+pub type LatestReadings = Arc<RwLock<HashMap<ReadingKey, LatestReading>>>;
 
-/// Load mappings from disk. Returns an empty map if the file does not exist.
-/// This is intentionally simple and synchronous-looking (async fs) so the
-/// rest of the code can await it during startup.
-pub async fn load_mappings() -> anyhow::Result<HashMap<String, Mapping>> {
-    match tokio::fs::read_to_string(MAPPINGS_FILE).await {
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ReadingKey {
+    pub model: String,
+    pub sensor_id: String,
+    pub measurement_type: u8,
+    /// Probe of a multi-probe sensor, empty for the sensor's own readings.
+    pub probe: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct LatestReading {
+    pub value: f64,
+    /// Wall-clock time the exporter received the message.
+    pub received_at: SystemTime,
+}
+
+// `LastSeen` records when each sensor (model + id) last delivered a
+// message, independent of which measurements it carried. It backs the
+// staleness gauges and `GET /api/stale`.
+pub type LastSeen = Arc<RwLock<HashMap<SensorKey, SystemTime>>>;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SensorKey {
+    pub model: String,
+    pub sensor_id: String,
+}
+
+// `TopicStats` tracks traffic per concrete MQTT topic for `GET /api/topics`:
+// message counts and payload bytes in one-minute buckets covering the last
+// hour, plus the time of the last message. Topics stay listed after they go
+// quiet so dead ones are easy to spot.
+pub type TopicStats = Arc<RwLock<HashMap<String, TopicActivity>>>;
+
+/// Minutes covered by `TopicActivity`.
+pub const TOPIC_WINDOW_MINUTES: usize = 60;
+
+#[derive(Clone, Debug)]
+pub struct TopicActivity {
+    /// `(minute since epoch, messages, payload bytes)`, indexed by minute
+    /// modulo the window; stale buckets are skipped by their minute.
+    buckets: [(u64, u64, u64); TOPIC_WINDOW_MINUTES],
+    pub last_message: SystemTime,
+}
+
+impl TopicActivity {
+    pub fn new(now: SystemTime) -> Self {
+        Self { buckets: [(0, 0, 0); TOPIC_WINDOW_MINUTES], last_message: now }
+    }
+
+    pub fn record(&mut self, now: SystemTime, payload_bytes: usize) {
+        let minute = epoch_minute(now);
+        let bucket = &mut self.buckets[minute as usize % TOPIC_WINDOW_MINUTES];
+        if bucket.0 != minute {
+            *bucket = (minute, 0, 0);
+        }
+        bucket.1 += 1;
+        bucket.2 += payload_bytes as u64;
+        self.last_message = now;
+    }
+
+    /// Messages and payload bytes within the window ending at `now`.
+    pub fn window_totals(&self, now: SystemTime) -> (u64, u64) {
+        let current = epoch_minute(now);
+        self.buckets
+            .iter()
+            .filter(|(minute, _, _)| current.saturating_sub(*minute) < TOPIC_WINDOW_MINUTES as u64)
+            .fold((0, 0), |(messages, bytes), (_, m, b)| (messages + m, bytes + b))
+    }
+}
+
+fn epoch_minute(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs() / 60
+}
+
+// The file (`mapping.file`, `mappings.json` by default) is a simple
+// placeholder persistence layer. When you migrate to DuckDB/DuckLake,
+// replace `load_mappings`/`save_mappings` implementations with queries
+// against the DB and remove this file-based path.
+
+/// Load mappings from `path`. Returns an empty map if the file does not
+/// exist. This is intentionally simple and synchronous-looking (async fs)
+/// so the rest of the code can await it during startup.
+pub async fn load_mappings(path: &str) -> anyhow::Result<HashMap<String, Mapping>> {
+    match tokio::fs::read_to_string(path).await {
         Ok(raw) => {
             let v: Vec<Mapping> = serde_json::from_str(&raw)?;
             let map = v
@@ -38,14 +128,23 @@ pub async fn load_mappings() -> anyhow::Result<HashMap<String, Mapping>> {
     }
 }
 
+/// Replace the store's mappings with the ones in `path`, e.g. after editing
+/// the file by hand. Returns how many were loaded.
+pub async fn reload_mappings(store: &Store, path: &str) -> anyhow::Result<usize> {
+    let mappings = load_mappings(path).await?;
+    let count = mappings.len();
+    *store.write().await = mappings;
+    Ok(count)
+}
+
 /// Persist mappings to disk. This is called after updates so state survives
 /// process restarts. If you move to a DB, ensure writes are transactional
 /// and consider batching for throughput.
-pub async fn save_mappings(store: &Store) -> anyhow::Result<()> {
+pub async fn save_mappings(store: &Store, path: &str) -> anyhow::Result<()> {
     let map = store.read().await;
     let vec: Vec<Mapping> = map.values().cloned().collect();
     let raw = serde_json::to_string_pretty(&vec)?;
-    tokio::fs::write(MAPPINGS_FILE, raw).await?;
+    tokio::fs::write(path, raw).await?;
     Ok(())
 }
 
@@ -55,3 +154,37 @@ pub async fn save_mappings(store: &Store) -> anyhow::Result<()> {
 pub fn key_for(sensor_id: &str, manufacturer: &str) -> String {
     format!("{}::{}", manufacturer, sensor_id)
 }
+
+thread_local! {
+    /// Buffer `mapping_for` formats keys into.
+    static KEY: std::cell::RefCell<String> = const { std::cell::RefCell::new(String::new()) };
+}
+
+/// The mapping of a sensor, like `mappings.get(&key_for(..))` but without
+/// allocating a key: it is built in a per-thread buffer. For the ingest
+/// path, which looks up every message's sensor.
+pub fn mapping_for<'a>(mappings: &'a HashMap<String, Mapping>, sensor_id: &str, manufacturer: &str) -> Option<&'a Mapping> {
+    if mappings.is_empty() {
+        return None;
+    }
+    KEY.with_borrow_mut(|key| {
+        key.clear();
+        key.push_str(manufacturer);
+        key.push_str("::");
+        key.push_str(sensor_id);
+        mappings.get(key.as_str())
+    })
+}
+
+/// Resolve a sensor reference from the config: `model::sensor_id` as is,
+/// anything else as a mapping name. Mapping names are looked up on every
+/// call so renames take effect without a restart.
+pub fn resolve_sensor(reference: &str, mappings: &HashMap<String, Mapping>) -> Option<SensorKey> {
+    match reference.split_once("::") {
+        Some((model, sensor_id)) => Some(SensorKey { model: model.to_string(), sensor_id: sensor_id.to_string() }),
+        None => mappings
+            .values()
+            .find(|m| m.name == reference)
+            .map(|m| SensorKey { model: m.manufacturer.clone(), sensor_id: m.sensor_id.clone() }),
+    }
+}
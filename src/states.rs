@@ -0,0 +1,273 @@
+// Derived on/off states with hysteresis. A state such as "heating needed"
+// is computed per sensor from one measurement and two thresholds, so it
+// doesn't flap while the value hovers around a single limit:
+//
+//   [[states]]
+//   name = "heating_needed"
+//   measurement = "temperature_C"
+//   sensors = ["living_room", "bedroom"]   # empty: every sensor
+//   on_below = 18.0
+//   off_above = 19.0
+//
+// (`on_above` / `off_below` for states that switch on at high values.)
+// Between the thresholds a state keeps its previous value; a sensor's first
+// reading sets it on only if it is past the "on" threshold. Every change is
+// appended to `state_transitions` (including the first value seen for a
+// sensor), the current value is exported as
+// `sensor_state{state,sensor_id,model}`, and the states are restored from
+// the table at startup so a restart doesn't produce spurious transitions.
+// Only locally ingested readings (and composites) are evaluated, not rows
+// replicated by the aggregator.
+use crate::config::StateConfig;
+use crate::db::{DbHandle, StateTransition};
+use crate::mqtt_buffer::{measurement_code, NormalizedRow};
+use crate::state::{resolve_sensor, Mapping, SensorKey};
+use chrono::NaiveDateTime;
+use prometheus::{IntGaugeVec, Opts, Registry};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+pub struct States {
+    definitions: Vec<Definition>,
+    current: Mutex<HashMap<(String, SensorKey), CurrentState>>,
+    gauge: IntGaugeVec,
+    db: DbHandle,
+}
+
+struct Definition {
+    name: String,
+    measurement_type: u8,
+    sensors: Vec<String>,
+    thresholds: Thresholds,
+}
+
+#[derive(Clone, Copy)]
+enum Thresholds {
+    /// On below `on`, off again above `off` (`on <= off`).
+    Below { on: f64, off: f64 },
+    /// On above `on`, off again below `off` (`on >= off`).
+    Above { on: f64, off: f64 },
+}
+
+impl Thresholds {
+    fn next(self, active: bool, value: f64) -> bool {
+        match self {
+            Thresholds::Below { on, off } => (value < on) || (active && value <= off),
+            Thresholds::Above { on, off } => (value > on) || (active && value >= off),
+        }
+    }
+}
+
+/// The value of one state for one sensor.
+#[derive(Clone, Debug)]
+pub struct CurrentState {
+    pub active: bool,
+    /// Timestamp of the reading that set the current value.
+    pub since: NaiveDateTime,
+    pub value: f64,
+}
+
+impl States {
+    pub fn new(configs: &[StateConfig], registry: &Registry, db: DbHandle) -> anyhow::Result<Self> {
+        let mut names = HashSet::new();
+        let mut definitions = Vec::new();
+        for config in configs {
+            if config.name.is_empty() || !names.insert(config.name.as_str()) {
+                return Err(anyhow::anyhow!("state names must be unique and non-empty: {:?}", config.name));
+            }
+            let measurement_type = measurement_code(&config.measurement)
+                .ok_or_else(|| anyhow::anyhow!("state {}: unknown measurement {}", config.name, config.measurement))?;
+            let thresholds = match (config.on_below, config.off_above, config.on_above, config.off_below) {
+                (Some(on), Some(off), None, None) if on <= off => Thresholds::Below { on, off },
+                (None, None, Some(on), Some(off)) if on >= off => Thresholds::Above { on, off },
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "state {}: set on_below <= off_above, or on_above >= off_below",
+                        config.name
+                    ))
+                }
+            };
+            definitions.push(Definition {
+                name: config.name.clone(),
+                measurement_type,
+                sensors: config.sensors.clone(),
+                thresholds,
+            });
+        }
+        let gauge = IntGaugeVec::new(
+            Opts::new("sensor_state", "Derived on/off state per sensor (1 = on)"),
+            &["state", "sensor_id", "model"],
+        )?;
+        registry.register(Box::new(gauge.clone()))?;
+        Ok(Self { definitions, current: Mutex::new(HashMap::new()), gauge, db })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.definitions.is_empty()
+    }
+
+    /// Load the last recorded value of every configured state.
+    pub async fn restore(&self) -> anyhow::Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let transitions = self.db.current_states().await?;
+        let mut current = self.current.lock().unwrap();
+        for t in transitions {
+            if !self.definitions.iter().any(|d| d.name == t.state) {
+                continue;
+            }
+            self.gauge.with_label_values(&[&t.state, &t.sensor_id, &t.model]).set(t.active as i64);
+            let key = SensorKey { model: t.model, sensor_id: t.sensor_id };
+            current.insert((t.state, key), CurrentState { active: t.active, since: t.timestamp, value: t.value });
+        }
+        info!("Restored {} derived states", current.len());
+        Ok(())
+    }
+
+    /// Evaluate `rows` against every state and record the changes in the
+    /// background.
+    pub fn observe(&self, rows: &[NormalizedRow], mappings: &HashMap<String, Mapping>) {
+        let mut transitions = Vec::new();
+        {
+            let mut current = self.current.lock().unwrap();
+            for definition in &self.definitions {
+                let sensors: Option<HashSet<SensorKey>> = (!definition.sensors.is_empty())
+                    .then(|| definition.sensors.iter().filter_map(|s| resolve_sensor(s, mappings)).collect());
+                for row in rows.iter().filter(|r| r.measurement_type == definition.measurement_type && r.site.is_empty() && r.probe.is_empty()) {
+                    let key = SensorKey { model: row.model.clone(), sensor_id: row.sensor_id.clone() };
+                    if sensors.as_ref().is_some_and(|s| !s.contains(&key)) {
+                        continue;
+                    }
+                    let previous = current.get(&(definition.name.clone(), key.clone())).map(|s| s.active);
+                    let active = definition.thresholds.next(previous.unwrap_or(false), row.value);
+                    if previous == Some(active) {
+                        continue;
+                    }
+                    self.gauge.with_label_values(&[&definition.name, &row.sensor_id, &row.model]).set(active as i64);
+                    current.insert(
+                        (definition.name.clone(), key),
+                        CurrentState { active, since: row.timestamp, value: row.value },
+                    );
+                    transitions.push(StateTransition {
+                        timestamp: row.timestamp,
+                        state: definition.name.clone(),
+                        model: row.model.clone(),
+                        sensor_id: row.sensor_id.clone(),
+                        active,
+                        value: row.value,
+                    });
+                }
+            }
+        }
+        if transitions.is_empty() {
+            return;
+        }
+        for t in &transitions {
+            info!("State {} of {}::{} is now {}", t.state, t.model, t.sensor_id, if t.active { "on" } else { "off" });
+        }
+        let db = self.db.clone();
+        tokio::spawn(async move {
+            if let Err(e) = db.record_transitions(transitions).await {
+                warn!("Recording state transitions failed: {}", e);
+            }
+        });
+    }
+
+    /// Current value of every (state, sensor), sorted by state and sensor.
+    pub fn snapshot(&self) -> Vec<(String, SensorKey, CurrentState)> {
+        let current = self.current.lock().unwrap();
+        let mut states: Vec<_> = current.iter().map(|((name, key), state)| (name.clone(), key.clone(), state.clone())).collect();
+        states.sort_by(|a, b| (&a.0, &a.1.model, &a.1.sensor_id).cmp(&(&b.0, &b.1.model, &b.1.sensor_id)));
+        states
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{start_db_worker, DbConfig};
+    use std::time::Duration;
+
+    fn heating(sensors: &[&str]) -> StateConfig {
+        StateConfig {
+            name: "heating_needed".to_string(),
+            measurement: "temperature_C".to_string(),
+            sensors: sensors.iter().map(|s| s.to_string()).collect(),
+            on_below: Some(18.0),
+            off_above: Some(19.0),
+            on_above: None,
+            off_below: None,
+        }
+    }
+
+    fn row(sensor_id: &str, minute: u32, value: f64) -> NormalizedRow {
+        NormalizedRow {
+            timestamp: chrono::NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_opt(8, minute, 0).unwrap(),
+            sensor_id: sensor_id.to_string(),
+            model: "Acurite-Tower".to_string(),
+            measurement_type: measurement_code("temperature_C").unwrap(),
+            value,
+            topic: "rtl_433/events".to_string(),
+            seq: 0,
+            site: String::new(),
+            probe: String::new(),
+        }
+    }
+
+    fn active(states: &States) -> Vec<(String, bool)> {
+        states.snapshot().into_iter().map(|(_, key, state)| (key.sensor_id, state.active)).collect()
+    }
+
+    #[test]
+    fn thresholds_keep_the_state_between_the_limits() {
+        let below = Thresholds::Below { on: 18.0, off: 19.0 };
+        for (was, value, next) in [(false, 17.9, true), (false, 18.5, false), (true, 18.5, true), (true, 19.0, true), (true, 19.1, false)] {
+            assert_eq!(below.next(was, value), next, "below, {} at {}", was, value);
+        }
+        let above = Thresholds::Above { on: 60.0, off: 55.0 };
+        for (was, value, next) in [(false, 60.1, true), (false, 58.0, false), (true, 58.0, true), (true, 55.0, true), (true, 54.9, false)] {
+            assert_eq!(above.next(was, value), next, "above, {} at {}", was, value);
+        }
+    }
+
+    #[tokio::test]
+    async fn transitions_are_derived_per_sensor_and_restored() {
+        let dir = std::env::temp_dir().join(format!("exporter-states-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let (db, _worker) = start_db_worker(&config, &Registry::new()).unwrap();
+
+        let inverted = StateConfig { on_below: Some(20.0), ..heating(&[]) };
+        assert!(States::new(&[inverted], &Registry::new(), db.clone()).is_err());
+        assert!(States::new(&[heating(&[]), heating(&[])], &Registry::new(), db.clone()).is_err());
+
+        let states = States::new(&[heating(&["Acurite-Tower::1", "Acurite-Tower::2"])], &Registry::new(), db.clone()).unwrap();
+        let mappings = HashMap::new();
+        // Sensor 3 isn't listed; a first reading between the limits is off.
+        states.observe(&[row("1", 0, 17.0), row("2", 0, 18.5), row("3", 0, 10.0)], &mappings);
+        assert_eq!(active(&states), vec![("1".to_string(), true), ("2".to_string(), false)]);
+        states.observe(&[row("1", 5, 18.8), row("2", 5, 17.5)], &mappings);
+        assert_eq!(active(&states), vec![("1".to_string(), true), ("2".to_string(), true)]);
+        states.observe(&[row("1", 10, 19.5)], &mappings);
+        assert_eq!(active(&states), vec![("1".to_string(), false), ("2".to_string(), true)]);
+
+        // Transitions are recorded in the background.
+        let mut recorded = Vec::new();
+        for _ in 0..50 {
+            recorded = db.current_states().await.unwrap();
+            if recorded.iter().any(|t| t.sensor_id == "1" && !t.active) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(recorded.len(), 2);
+        let restored = States::new(&[heating(&[])], &Registry::new(), db.clone()).unwrap();
+        restored.restore().await.unwrap();
+        assert_eq!(active(&restored), vec![("1".to_string(), false), ("2".to_string(), true)]);
+        let since = restored.snapshot().into_iter().map(|(_, _, state)| state.since.format("%H:%M").to_string()).collect::<Vec<_>>();
+        assert_eq!(since, vec!["08:10", "08:05"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
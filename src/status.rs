@@ -0,0 +1,140 @@
+// Runtime overview. `GET /api/status` gathers what the UI's overview page
+// shows into one JSON document, so it does not have to scrape `/metrics`:
+//
+//   {
+//     "version": {"version": "0.1.0", "git_sha": "1a2b3c4d5e6f", "ui_hash": "9f86d081884c"},
+//     "started_at": "2024-03-01T08:00:00Z",
+//     "uptime_secs": 3600,
+//     "mqtt": {"enabled": true, "connected": true, "broker": "broker.lan:1883",
+//              "topics": ["rtl_433/+/events"], "control_topic": "exporter/control"},
+//     "buffer": {"rows": 12, "bytes": 2048, "last_flush": "2024-03-01T08:59:58Z"},
+//     "db": {"status": "ready", "path": "exporter.duckdb", "file_bytes": 10485760,
+//            "wal_bytes": 65536, "tables": {"measurements": 123456, ...}}
+//   }
+//
+// `topics` are the current subscriptions, reloads included. `last_flush` is
+// the last flush that wrote anything, `null` before the first one. The row
+// counts are taken by the DB worker behind whatever is queued; when the
+// database is unavailable or they take longer than 5 seconds `tables` is
+// `null`, `error` says why and the rest is reported anyway.
+use crate::db::{DbHandle, DbStatus};
+use crate::metrics::IntegrationHealth;
+use crate::mqtt;
+use crate::mqtt_buffer::MqttBuffer;
+use crate::version::Version;
+use axum::{extract::Extension, Json};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How long the row counts may take.
+const TABLES_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What `/api/status` reports on, shared with the handler.
+pub struct Status {
+    pub started_at: DateTime<Utc>,
+    pub version: Arc<Version>,
+    pub db: DbHandle,
+    pub db_path: String,
+    pub buffer: Arc<MqttBuffer>,
+    pub integration: IntegrationHealth,
+    pub mqtt_enabled: bool,
+    /// `host:port` of the broker.
+    pub broker: String,
+    pub control_topic: Option<String>,
+    pub topics: watch::Receiver<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct StatusReport {
+    version: Version,
+    started_at: DateTime<Utc>,
+    uptime_secs: i64,
+    mqtt: MqttStatus,
+    buffer: BufferStatus,
+    db: DbReport,
+}
+
+#[derive(Serialize)]
+struct MqttStatus {
+    enabled: bool,
+    connected: bool,
+    broker: String,
+    topics: Vec<String>,
+    control_topic: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BufferStatus {
+    rows: usize,
+    bytes: usize,
+    last_flush: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct DbReport {
+    /// `ready`, `opening` (waiting for a lock) or `failed`.
+    status: &'static str,
+    path: String,
+    file_bytes: Option<u64>,
+    wal_bytes: Option<u64>,
+    tables: Option<BTreeMap<String, u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Size of the file at `path`, `None` when there is none.
+async fn file_size(path: &str) -> Option<u64> {
+    tokio::fs::metadata(path).await.ok().map(|m| m.len())
+}
+
+/// Snapshot of the runtime state for the UI's overview page.
+pub async fn status_handler(Extension(status): Extension<Arc<Status>>) -> Json<StatusReport> {
+    let (rows, bytes) = status.buffer.backlog();
+    let (db_status, mut error) = match status.db.status() {
+        DbStatus::Ready => ("ready", None),
+        DbStatus::Opening => ("opening", Some("database is locked by another process, retrying".to_string())),
+        DbStatus::Failed(reason) => ("failed", Some(reason)),
+    };
+    let tables = match db_status {
+        "ready" => match tokio::time::timeout(TABLES_TIMEOUT, status.db.table_rows()).await {
+            Ok(Ok(tables)) => Some(tables),
+            Ok(Err(e)) => {
+                error = Some(e.to_string());
+                None
+            }
+            Err(_) => {
+                error = Some(format!("row counts took longer than {}s", TABLES_TIMEOUT.as_secs()));
+                None
+            }
+        },
+        _ => None,
+    };
+    let topics = status.topics.borrow().clone();
+    let file_bytes = file_size(&status.db_path).await;
+    let wal_bytes = file_size(&format!("{}.wal", status.db_path)).await;
+    Json(StatusReport {
+        version: status.version.as_ref().clone(),
+        started_at: status.started_at,
+        uptime_secs: (Utc::now() - status.started_at).num_seconds().max(0),
+        mqtt: MqttStatus {
+            enabled: status.mqtt_enabled,
+            connected: status.mqtt_enabled && status.integration.is_up(mqtt::SOURCE),
+            broker: status.broker.clone(),
+            topics,
+            control_topic: status.control_topic.clone(),
+        },
+        buffer: BufferStatus { rows, bytes, last_flush: status.buffer.last_flush() },
+        db: DbReport {
+            status: db_status,
+            path: status.db_path.clone(),
+            file_bytes,
+            wal_bytes,
+            tables,
+            error,
+        },
+    })
+}
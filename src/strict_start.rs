@@ -0,0 +1,186 @@
+// Strict start. A misconfigured instance (mappings volume not mounted, a
+// database file from another version) would otherwise ingest from the first
+// message and store rows that need cleaning up later. With
+//
+//   [mqtt]
+//   strict_start = true
+//
+// nothing is ingested, neither MQTT messages nor the WAL replay, the spool
+// restore or rows replicated from edge sites in aggregator mode, until
+//
+//   - the database is open and the tables the ingest appends to
+//     (`measurements`, `raw_messages`, `rejected_messages`) have the
+//     columns this version writes, in order, and
+//   - the mappings file (`mapping.file`) exists and parses, and every mapping names a sensor
+//     id, manufacturer and name, sets units only for known measurements
+//     and convertible from their unit, and no sensor is mapped twice.
+//
+// The parser settings (`mqtt.formats`, `models`, `probes`, `sentinels`,
+// `[[measurements]]`) are checked at every start anyway; errors there stop
+// the exporter. Until the checks pass they are repeated every 10 seconds,
+// failures are logged when they change, and `/healthz/ready` fails its
+// `startup` check with the reason. HTTP is served meanwhile. Once they pass,
+// the verified mappings replace whatever was loaded at startup and
+// ingestion begins.
+use crate::db::{DbHandle, DbStatus};
+use crate::mqtt_buffer::{measurement_types, raw_to_record_batch, rejected_to_record_batch, rows_to_record_batch};
+use crate::state::{key_for, Mapping, Store};
+use crate::units;
+use arrow::datatypes::Schema;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// How often failed checks are repeated.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Whether ingestion may begin, for `/healthz/ready`.
+pub struct StartGate {
+    /// Why ingestion is held back; `None` once the checks passed.
+    waiting: Mutex<Option<String>>,
+    /// Set once the checks passed, for the ingest paths waiting on it.
+    passed: watch::Sender<bool>,
+}
+
+impl Default for StartGate {
+    fn default() -> Self {
+        Self { waiting: Mutex::new(Some("not checked yet".to_string())), passed: watch::Sender::new(false) }
+    }
+}
+
+impl StartGate {
+    pub fn status(&self) -> Result<String, String> {
+        match &*self.waiting.lock().unwrap() {
+            None => Ok("verified".to_string()),
+            Some(reason) => Err(format!("ingestion held back: {}", reason)),
+        }
+    }
+
+    /// Wait until the checks passed, see `verify`.
+    pub async fn opened(&self) {
+        let _ = self.passed.subscribe().wait_for(|passed| *passed).await;
+    }
+
+    /// Repeat the checks until they pass, then load the verified mappings
+    /// from `mappings_file` into `store` and open the gate.
+    pub async fn verify(&self, db: &DbHandle, store: &Store, mappings_file: &str) {
+        let mut interval = tokio::time::interval(RETRY_INTERVAL);
+        loop {
+            interval.tick().await;
+            let reason = match check(db, mappings_file).await {
+                Ok(mappings) => {
+                    let count = mappings.len();
+                    *store.write().await = mappings;
+                    *self.waiting.lock().unwrap() = None;
+                    self.passed.send_replace(true);
+                    info!("Startup checks passed with {} mappings, starting ingestion", count);
+                    return;
+                }
+                Err(reason) => reason,
+            };
+            let mut waiting = self.waiting.lock().unwrap();
+            if waiting.as_deref() != Some(reason.as_str()) {
+                warn!("Holding back ingestion: {}", reason);
+                *waiting = Some(reason);
+            }
+        }
+    }
+}
+
+async fn check(db: &DbHandle, mappings_file: &str) -> Result<HashMap<String, Mapping>, String> {
+    match db.status() {
+        DbStatus::Ready => {}
+        DbStatus::Opening => return Err("database is locked by another process".to_string()),
+        DbStatus::Failed(reason) => return Err(reason),
+    }
+    let expected = [
+        ("measurements", rows_to_record_batch(&[]).map(|b| b.schema())),
+        ("raw_messages", raw_to_record_batch(&[]).map(|b| b.schema())),
+        ("rejected_messages", rejected_to_record_batch(&[]).map(|b| b.schema())),
+    ];
+    for (table, schema) in expected {
+        let schema = schema.map_err(|e| e.to_string())?;
+        let columns = db.table_columns(table).await.map_err(|e| format!("cannot read the columns of {}: {}", table, e))?;
+        check_columns(table, &columns, &schema)?;
+    }
+    let raw = tokio::fs::read_to_string(mappings_file).await.map_err(|e| format!("cannot read {}: {}", mappings_file, e))?;
+    let mappings: Vec<Mapping> = serde_json::from_str(&raw).map_err(|e| format!("invalid {}: {}", mappings_file, e))?;
+    check_mappings(mappings, mappings_file)
+}
+
+fn check_columns(table: &str, columns: &[String], schema: &Schema) -> Result<(), String> {
+    let expected: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+    if columns.is_empty() {
+        return Err(format!("table {} is missing", table));
+    }
+    if columns.iter().map(String::as_str).ne(expected.iter().copied()) {
+        return Err(format!("table {} has columns ({}), this version writes ({})", table, columns.join(", "), expected.join(", ")));
+    }
+    Ok(())
+}
+
+fn check_mappings(mappings: Vec<Mapping>, mappings_file: &str) -> Result<HashMap<String, Mapping>, String> {
+    let mut checked = HashMap::new();
+    for mapping in mappings {
+        let key = key_for(&mapping.sensor_id, &mapping.manufacturer);
+        let invalid = |problem: String| format!("mapping {} in {}: {}", key, mappings_file, problem);
+        if mapping.sensor_id.trim().is_empty() || mapping.manufacturer.trim().is_empty() || mapping.name.trim().is_empty() {
+            return Err(invalid("sensor_id, manufacturer and name must not be empty".to_string()));
+        }
+        for (measurement, unit) in &mapping.units {
+            match measurement_types().iter().find(|t| &t.key == measurement) {
+                None => return Err(invalid(format!("unknown measurement {}", measurement))),
+                Some(t) if !units::is_convertible(&t.unit, unit) => {
+                    return Err(invalid(format!("cannot convert {} from {} to {}", measurement, t.unit, unit)));
+                }
+                Some(_) => {}
+            }
+        }
+        if checked.insert(key.clone(), mapping).is_some() {
+            return Err(invalid("mapped more than once".to_string()));
+        }
+    }
+    Ok(checked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn mapping(sensor_id: &str, units: &[(&str, &str)]) -> Mapping {
+        Mapping {
+            sensor_id: sensor_id.to_string(),
+            manufacturer: "Acurite-Tower".to_string(),
+            name: "porch".to_string(),
+            units: units.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<BTreeMap<_, _>>(),
+            retention_days: None,
+            expected_interval_secs: None,
+        }
+    }
+
+    #[test]
+    fn mappings_are_checked_one_by_one() {
+        let checked = check_mappings(vec![mapping("1", &[("temperature_C", "F")]), mapping("2", &[])], "mappings.json").unwrap();
+        assert_eq!(checked.len(), 2);
+        let error = |mappings| check_mappings(mappings, "mappings.json").unwrap_err();
+        assert!(error(vec![mapping(" ", &[])]).contains("must not be empty"));
+        assert!(error(vec![mapping("1", &[("no_such_key", "F")])]).contains("unknown measurement no_such_key"));
+        assert!(error(vec![mapping("1", &[("temperature_C", "hPa")])]).contains("cannot convert temperature_C"));
+        assert!(error(vec![mapping("1", &[]), mapping("1", &[])]).contains("mapped more than once"));
+    }
+
+    #[test]
+    fn tables_need_the_written_columns_in_order() {
+        let schema = rows_to_record_batch(&[]).unwrap().schema();
+        let columns: Vec<String> = schema.fields().iter().map(|f| f.name().clone()).collect();
+        assert!(check_columns("measurements", &columns, &schema).is_ok());
+        assert_eq!(check_columns("measurements", &[], &schema).unwrap_err(), "table measurements is missing");
+        let mut swapped = columns.clone();
+        swapped.swap(0, 1);
+        assert!(check_columns("measurements", &swapped, &schema).unwrap_err().contains("this version writes"));
+        assert!(check_columns("measurements", &columns[1..], &schema).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+// Incremental sync for downstream replication. `GET /api/sync?since_seq=N`
+// streams every live `measurements` row with `seq > N` in `seq` order, so a
+// central aggregator can replicate many edge exporters by remembering the
+// last `seq` it received from each and asking for what came after it,
+// instead of pulling full exports. The DB worker numbers rows as it
+// commits them (see `db::Sequencer`), so once a consumer has moved past seq
+// N no row at or below N can appear later, however slow the flush that
+// wrote it was.
+//
+// Rows are read from the DB worker page by page and written to the response
+// as they arrive, either as NDJSON (one `handlers::MeasurementRow` object per
+// line, the default) or as an Arrow IPC stream with the `measurements` table
+// schema (`format=arrow`). An error after the response has started aborts
+// the stream; the consumer simply resumes from the last `seq` it stored.
+use crate::db::DbHandle;
+use crate::handlers::MeasurementRow;
+use crate::mqtt_buffer::{rows_to_record_batch, NormalizedRow};
+use crate::problem::Problem;
+use arrow::ipc::writer::StreamWriter;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Extension, Query},
+    http::{header::CONTENT_TYPE, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Rows fetched from DuckDB per round trip.
+const SYNC_PAGE_ROWS: usize = 5_000;
+
+#[derive(Deserialize)]
+pub struct SyncParams {
+    /// Last `seq` the consumer already has; `0` (default) starts from the
+    /// beginning.
+    #[serde(default)]
+    pub since_seq: u64,
+    /// Stop after this many rows; by default everything is streamed.
+    pub limit: Option<usize>,
+    /// `ndjson` (default) or `arrow`.
+    pub format: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum SyncFormat {
+    Ndjson,
+    Arrow,
+}
+
+pub async fn sync_handler(
+    Extension(db): Extension<DbHandle>,
+    Query(params): Query<SyncParams>,
+) -> Result<Response, Problem> {
+    let (format, content_type) = match params.format.as_deref().unwrap_or("ndjson") {
+        "ndjson" => (SyncFormat::Ndjson, "application/x-ndjson"),
+        "arrow" => (SyncFormat::Arrow, "application/vnd.apache.arrow.stream"),
+        other => return Err(Problem::invalid(format!("unknown format: {} (expected ndjson or arrow)", other))),
+    };
+    // A small channel bounds how far the reader runs ahead of a slow client.
+    let (tx, rx) = mpsc::channel::<anyhow::Result<Bytes>>(4);
+    tokio::spawn(async move {
+        if let Err(e) = stream_rows(&db, params.since_seq, params.limit.unwrap_or(usize::MAX), format, &tx).await {
+            warn!("Sync from seq {} aborted: {}", params.since_seq, e);
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+    let body = futures_util::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|chunk| (chunk, rx)) });
+    Ok(([(CONTENT_TYPE, HeaderValue::from_static(content_type))], Body::from_stream(body)).into_response())
+}
+
+/// Page through rows after `since_seq` and send them encoded. Returns early
+/// without error when the client went away.
+async fn stream_rows(
+    db: &DbHandle,
+    since_seq: u64,
+    limit: usize,
+    format: SyncFormat,
+    tx: &mpsc::Sender<anyhow::Result<Bytes>>,
+) -> anyhow::Result<()> {
+    let mut after = since_seq;
+    let mut remaining = limit;
+    let mut arrow: Option<StreamWriter<Vec<u8>>> = None;
+    if let SyncFormat::Arrow = format {
+        // The schema goes out first so an empty sync is still a valid stream.
+        arrow = Some(StreamWriter::try_new(Vec::new(), &rows_to_record_batch(&[])?.schema())?);
+    }
+    loop {
+        let page = remaining.min(SYNC_PAGE_ROWS);
+        let rows = if page == 0 { Vec::new() } else { db.sync_rows(after, page).await? };
+        let last_page = rows.len() < page || page == 0;
+        remaining -= rows.len();
+        if let Some(last) = rows.last() {
+            after = last.seq;
+        }
+        let chunk = match arrow.as_mut() {
+            Some(writer) => {
+                if !rows.is_empty() {
+                    writer.write(&rows_to_record_batch(&rows)?)?;
+                }
+                if last_page {
+                    writer.finish()?;
+                }
+                std::mem::take(writer.get_mut())
+            }
+            None => ndjson(rows)?,
+        };
+        if !chunk.is_empty() && tx.send(Ok(Bytes::from(chunk))).await.is_err() {
+            return Ok(());
+        }
+        if last_page {
+            return Ok(());
+        }
+    }
+}
+
+fn ndjson(rows: Vec<NormalizedRow>) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    for row in rows {
+        serde_json::to_writer(&mut out, &MeasurementRow::from(row))?;
+        out.push(b'\n');
+    }
+    Ok(out)
+}
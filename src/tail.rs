@@ -0,0 +1,143 @@
+// Live readings in a terminal. `tail` subscribes to `/api/stream` of a
+// running exporter (see `live`) and prints one line per reading as it is
+// ingested, e.g. while moving a sensor around to find a spot with
+// reception:
+//
+// Run with: `rust-to-mqtt-prometheus-exporter tail [--sensor ID] [--model MODEL] [--type KEY] [base-url]`
+//
+// `--type temperature` matches `temperature_C`, see `live`. The base URL
+// defaults to the configured `http.bind` address, and the first configured
+// token is sent, as with `selftest`, but only over https or to a loopback
+// address. Readings missed because the terminal was too slow are reported;
+// the command ends with the stream.
+use crate::config::Config;
+use serde::Deserialize;
+use std::io::Write;
+
+/// The fields of an `/api/stream` reading that are printed.
+#[derive(Deserialize)]
+struct Reading {
+    timestamp: String,
+    sensor_id: String,
+    model: String,
+    measurement: String,
+    value: f64,
+    #[serde(default)]
+    unit: String,
+    #[serde(default)]
+    site: String,
+    #[serde(default)]
+    probe: String,
+}
+
+/// Options of the `tail` command.
+#[derive(clap::Args)]
+pub struct Args {
+    /// Only readings of this sensor id.
+    #[arg(long, value_name = "ID")]
+    sensor: Option<String>,
+    /// Only readings of this model.
+    #[arg(long)]
+    model: Option<String>,
+    /// Only this measurement key, or keys starting with it and a unit
+    /// suffix.
+    #[arg(long = "type", value_name = "KEY")]
+    measurement: Option<String>,
+    /// Base URL of the exporter; defaults to the configured `http.bind`
+    /// address.
+    base_url: Option<String>,
+}
+
+/// Entry point for the `tail` command.
+pub async fn run_cli(args: Args) -> anyhow::Result<()> {
+    let config = Config::load()?;
+    let query: Vec<(&str, String)> = [("sensor_id", args.sensor), ("model", args.model), ("measurement", args.measurement)]
+        .into_iter()
+        .filter_map(|(param, value)| Some((param, value?)))
+        .collect();
+    let base_url = match args.base_url {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => config.http.local_url(),
+    };
+
+    let url = reqwest::Url::parse(&format!("{}/api/stream", base_url))?;
+    let confidential = token_stays_private(&url);
+    let mut request = reqwest::Client::new().get(url).query(&query);
+    let auth = &config.http.auth;
+    if let Some(token) = auth.read_tokens.iter().chain(&auth.write_tokens).chain(auth.keys.iter().map(|k| &k.token)).chain(&config.http.admin_token).next() {
+        if confidential {
+            request = request.bearer_auth(token);
+        } else {
+            eprintln!("Not sending the API token over plain http to {}; use https", base_url);
+        }
+    }
+    let mut response = request.send().await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        return Err(anyhow::anyhow!("{} answered {}: {}", base_url, status, response.text().await.unwrap_or_default()));
+    }
+    eprintln!("Streaming readings from {}, Ctrl-C to stop", base_url);
+
+    let mut pending = Vec::new();
+    let mut event = String::new();
+    while let Some(chunk) = response.chunk().await? {
+        pending.extend_from_slice(&chunk);
+        while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            if let Some(name) = line.strip_prefix("event:") {
+                event = name.trim().to_string();
+            } else if let Some(data) = line.strip_prefix("data:") {
+                print_event(&event, data.trim())?;
+            } else if line.is_empty() {
+                event.clear();
+            }
+        }
+    }
+    eprintln!("Stream ended");
+    Ok(())
+}
+
+/// Whether a token sent to `url` can't be read on the way: https, or
+/// plain http that doesn't leave the host.
+fn token_stays_private(url: &reqwest::Url) -> bool {
+    let Some(host) = url.host_str() else { return false };
+    url.scheme() == "https"
+        || host == "localhost"
+        || host.trim_matches(['[', ']']).parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+fn print_event(event: &str, data: &str) -> anyhow::Result<()> {
+    let mut out = std::io::stdout().lock();
+    match event {
+        "reading" => {
+            let r: Reading = serde_json::from_str(data)?;
+            let site = if r.site.is_empty() { String::new() } else { format!("{}:", r.site) };
+            let probe = if r.probe.is_empty() { String::new() } else { format!("[{}]", r.probe) };
+            // Timestamps without the fractional seconds.
+            let timestamp = r.timestamp.split('.').next().unwrap_or_default().replace('T', " ");
+            writeln!(out, "{}  {}{}/{}  {}{} = {} {}", timestamp, site, r.model, r.sensor_id, r.measurement, probe, r.value, r.unit)?;
+        }
+        "lagged" => writeln!(out, "... readings missed: {}", data)?,
+        _ => {}
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_go_over_https_or_loopback_only() {
+        let private = |url: &str| token_stays_private(&reqwest::Url::parse(url).unwrap());
+        assert!(private("https://exporter.lan:3000/api/stream"));
+        assert!(private("http://127.0.0.1:3000/api/stream"));
+        assert!(private("http://[::1]:3000/api/stream"));
+        assert!(private("http://localhost:3000/api/stream"));
+        assert!(!private("http://exporter.lan:3000/api/stream"));
+        assert!(!private("http://192.168.1.10:3000/api/stream"));
+    }
+}
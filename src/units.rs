@@ -0,0 +1,250 @@
+// Unit conversion registry. Every unit belongs to a dimension and is a
+// linear function of that dimension's base unit (`base = value * scale +
+// offset`), so any two units of the same dimension convert into each other
+// and supporting a new unit is one line in `UNITS`.
+//
+// Mappings use it to store and export a sensor's readings in another unit
+// than the canonical one of the measurement (the unit rtl_433 puts in the
+// key name, see `mqtt_buffer::MeasurementType`):
+//
+//   {"sensor_id": "42", "manufacturer": "Acurite-Tower", "name": "porch",
+//    "units": {"temperature_C": "F", "pressure_kPa": "hPa"}}
+//
+// Rows are always stored in canonical units. The outputs convert: the
+// gauges, remote_write, InfluxDB, the JSONL and live sinks get the rows in
+// the mapping's unit (metric names keep their canonical suffix), and the
+// read APIs (`/api/measurements`, `/api/series`, reports, remote read,
+// Grafana) convert stored values with the current mapping, so changing a
+// mapping's unit relabels and converts history consistently. Degree days,
+// rollups and the weather replay work on the canonical values. The CSV
+// importer uses the same registry to convert into canonical units.
+//
+// The registry also carries display hints for API clients: the symbol to
+// print (`°C` for `C`), the dimension (a stable identifier to translate an
+// axis title from) and how many decimals are meaningful. `/api/measurements`
+// reports them per unit and `/api/measurement-types` per measurement.
+use crate::mqtt_buffer::{measurement_types, NormalizedRow};
+use crate::state::{mapping_for, Mapping};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+struct Unit {
+    symbol: &'static str,
+    /// Other spellings accepted for the symbol.
+    aliases: &'static [&'static str],
+    dimension: &'static str,
+    scale: f64,
+    offset: f64,
+    /// Symbol shown to users.
+    display: &'static str,
+    decimals: u8,
+}
+
+const fn unit(symbol: &'static str, aliases: &'static [&'static str], dimension: &'static str, scale: f64, offset: f64, decimals: u8) -> Unit {
+    Unit { symbol, aliases, dimension, scale, offset, display: symbol, decimals }
+}
+
+const fn shown_as(unit: Unit, display: &'static str) -> Unit {
+    Unit { display, ..unit }
+}
+
+/// Known units, grouped by dimension; the first of each group is its base.
+const UNITS: &[Unit] = &[
+    unit("K", &[], "temperature", 1.0, 0.0, 2),
+    shown_as(unit("C", &["°C"], "temperature", 1.0, 273.15, 1), "°C"),
+    shown_as(unit("F", &["°F"], "temperature", 5.0 / 9.0, 273.15 - 32.0 * 5.0 / 9.0, 1), "°F"),
+    unit("Pa", &[], "pressure", 1.0, 0.0, 0),
+    unit("hPa", &["mbar"], "pressure", 100.0, 0.0, 1),
+    unit("kPa", &[], "pressure", 1000.0, 0.0, 2),
+    unit("inHg", &[], "pressure", 3386.389, 0.0, 2),
+    unit("mmHg", &[], "pressure", 133.322_4, 0.0, 1),
+    unit("psi", &[], "pressure", 6894.757, 0.0, 2),
+    unit("m", &[], "length", 1.0, 0.0, 2),
+    unit("mm", &[], "length", 0.001, 0.0, 1),
+    unit("cm", &[], "length", 0.01, 0.0, 1),
+    unit("in", &[], "length", 0.0254, 0.0, 2),
+    unit("m/s", &[], "speed", 1.0, 0.0, 1),
+    unit("km/h", &["kmh"], "speed", 1.0 / 3.6, 0.0, 1),
+    unit("mph", &[], "speed", 0.447_04, 0.0, 1),
+    unit("kn", &["kt"], "speed", 0.514_444, 0.0, 1),
+    unit("%", &[], "ratio", 1.0, 0.0, 0),
+    // Logarithmic, so only the symbol converts to itself.
+    unit("dB", &[], "level", 1.0, 0.0, 1),
+    unit("dBm", &[], "power_level", 1.0, 0.0, 0),
+];
+
+fn lookup(symbol: &str) -> Option<&'static Unit> {
+    UNITS.iter().find(|u| u.symbol == symbol || u.aliases.contains(&symbol))
+}
+
+/// Every symbol (not alias) in the registry.
+pub fn symbols() -> impl Iterator<Item = &'static str> {
+    UNITS.iter().map(|u| u.symbol)
+}
+
+/// How a unit should be presented.
+#[derive(Clone, Debug, Serialize)]
+pub struct UnitInfo {
+    /// Display symbol, e.g. `°C`; unknown units are shown as written.
+    pub symbol: String,
+    /// `temperature`, `pressure`, ... for known units.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimension: Option<&'static str>,
+    /// Meaningful decimal places for known units.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decimals: Option<u8>,
+}
+
+/// Display hints for `unit`, which may be a symbol, an alias or a unit the
+/// registry doesn't know.
+pub fn info(unit: &str) -> UnitInfo {
+    match lookup(unit) {
+        Some(u) => UnitInfo { symbol: u.display.to_string(), dimension: Some(u.dimension), decimals: Some(u.decimals) },
+        None => UnitInfo { symbol: unit.to_string(), dimension: None, decimals: None },
+    }
+}
+
+/// Whether values in `from` can be converted to `to`: the same symbol, or
+/// two known units of one dimension.
+pub fn is_convertible(from: &str, to: &str) -> bool {
+    from == to || matches!((lookup(from), lookup(to)), (Some(a), Some(b)) if a.dimension == b.dimension)
+}
+
+/// `value` in `from` expressed in `to`, `None` if they don't convert.
+pub fn convert(value: f64, from: &str, to: &str) -> Option<f64> {
+    if from == to {
+        return Some(value);
+    }
+    let (from, to) = (lookup(from)?, lookup(to)?);
+    if from.dimension != to.dimension {
+        return None;
+    }
+    if from.symbol == to.symbol {
+        return Some(value);
+    }
+    let converted = (value * from.scale + from.offset - to.offset) / to.scale;
+    // Drop the noise of going through the base unit (20 C -> 67.99999999999999 F).
+    Some((converted * 1e9).round() / 1e9)
+}
+
+/// Canonical unit of `measurement_type` and the unit its mapping asks for,
+/// when there is one that converts.
+fn mapping_target(measurement_type: u8, mapping: Option<&Mapping>) -> Option<(&'static str, &str)> {
+    let units = &mapping?.units;
+    if units.is_empty() {
+        return None;
+    }
+    let measurement = measurement_types().iter().find(|t| t.code == measurement_type)?;
+    let target = units.get(&measurement.key).filter(|u| is_convertible(&measurement.unit, u))?;
+    Some((measurement.unit.as_str(), target.as_str()))
+}
+
+/// Display symbol of the unit a sensor's `measurement_type` readings are
+/// presented in: its mapping's unit when that converts, else the canonical
+/// one.
+pub fn mapped_unit(measurement_type: u8, mapping: Option<&Mapping>) -> String {
+    if let Some((_, target)) = mapping_target(measurement_type, mapping) {
+        return info(target).symbol;
+    }
+    measurement_types().iter().find(|t| t.code == measurement_type).map(|t| info(&t.unit).symbol).unwrap_or_default()
+}
+
+/// A stored (canonical) value in the unit `mapped_unit` names.
+pub fn to_mapped_unit(value: f64, measurement_type: u8, mapping: Option<&Mapping>) -> f64 {
+    mapping_target(measurement_type, mapping)
+        .and_then(|(canonical, target)| convert(value, canonical, target))
+        .unwrap_or(value)
+}
+
+/// Convert rows of mapped sensors from canonical units into the units their
+/// mapping asks for. Readings without a mapping unit, or whose unit doesn't
+/// convert, are left as they are.
+pub fn apply_mapping_units(rows: &mut [NormalizedRow], mappings: &HashMap<String, Mapping>) {
+    for row in rows {
+        let mapping = mapping_for(mappings, &row.sensor_id, &row.model);
+        row.value = to_mapped_unit(row.value, row.measurement_type, mapping);
+    }
+}
+
+/// `rows` in their mappings' units, borrowed when none of them converts.
+pub fn in_mapping_units<'a>(rows: &'a [NormalizedRow], mappings: &HashMap<String, Mapping>) -> Cow<'a, [NormalizedRow]> {
+    let mapped = |row: &NormalizedRow| mapping_target(row.measurement_type, mapping_for(mappings, &row.sensor_id, &row.model)).is_some();
+    if !rows.iter().any(mapped) {
+        return Cow::Borrowed(rows);
+    }
+    let mut rows = rows.to_vec();
+    apply_mapping_units(&mut rows, mappings);
+    Cow::Owned(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt_buffer::measurement_code;
+    use crate::state::key_for;
+    use chrono::NaiveDate;
+
+    fn mapping(units: &[(&str, &str)]) -> Mapping {
+        Mapping {
+            sensor_id: "42".to_string(),
+            manufacturer: "Acurite-Tower".to_string(),
+            name: "porch".to_string(),
+            units: units.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            retention_days: None,
+            expected_interval_secs: None,
+        }
+    }
+
+    fn row(key: &str, value: f64) -> NormalizedRow {
+        NormalizedRow {
+            timestamp: NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            sensor_id: "42".to_string(),
+            model: "Acurite-Tower".to_string(),
+            measurement_type: measurement_code(key).unwrap(),
+            value,
+            topic: String::new(),
+            seq: 0,
+            site: String::new(),
+            probe: String::new(),
+        }
+    }
+
+    #[test]
+    fn converts_within_a_dimension() {
+        assert_eq!(convert(20.0, "C", "F"), Some(68.0));
+        assert_eq!(convert(68.0, "°F", "C"), Some(20.0));
+        assert_eq!(convert(101.3, "kPa", "hPa"), Some(1013.0));
+        assert_eq!(convert(1013.0, "mbar", "hPa"), Some(1013.0));
+        assert_eq!(convert(36.0, "km/h", "m/s"), Some(10.0));
+        assert_eq!(convert(20.0, "C", "hPa"), None);
+        assert_eq!(convert(1.0, "furlong", "m"), None);
+        assert_eq!(convert(1.0, "furlong", "furlong"), Some(1.0));
+    }
+
+    #[test]
+    fn mapped_units_label_and_convert_canonical_values() {
+        let porch = mapping(&[("temperature_C", "F"), ("humidity", "C")]);
+        let temperature = measurement_code("temperature_C").unwrap();
+        let humidity = measurement_code("humidity").unwrap();
+        assert_eq!(mapped_unit(temperature, Some(&porch)), "°F");
+        assert_eq!(to_mapped_unit(20.0, temperature, Some(&porch)), 68.0);
+        // A unit that doesn't convert is ignored.
+        assert_eq!(mapped_unit(humidity, Some(&porch)), "%");
+        assert_eq!(to_mapped_unit(55.0, humidity, Some(&porch)), 55.0);
+        assert_eq!(mapped_unit(temperature, None), "°C");
+        assert_eq!(to_mapped_unit(20.0, temperature, None), 20.0);
+    }
+
+    #[test]
+    fn rows_are_converted_only_for_mapped_sensors() {
+        let rows = vec![row("temperature_C", 20.0), row("humidity", 55.0)];
+        let mut mappings = HashMap::new();
+        assert!(matches!(in_mapping_units(&rows, &mappings), Cow::Borrowed(_)));
+        mappings.insert(key_for("42", "Acurite-Tower"), mapping(&[("temperature_C", "F")]));
+        let mapped = in_mapping_units(&rows, &mappings);
+        assert_eq!(mapped.iter().map(|r| r.value).collect::<Vec<_>>(), vec![68.0, 55.0]);
+        // The canonical rows are untouched.
+        assert_eq!(rows[0].value, 20.0);
+    }
+}
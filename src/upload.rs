@@ -0,0 +1,187 @@
+// Object-storage uploads. When an S3 bucket is configured, Parquet archive
+// files and periodic database backups are copied to an S3-compatible store
+// (AWS S3, MinIO, ...) so the local SD card isn't the only copy of the data.
+// Each kind of upload lives under its own key prefix and only the newest
+// `keep_copies` objects per kind (by upload time) are kept remotely.
+//
+// Credentials come from `S3_ACCESS_KEY_ID` / `S3_SECRET_ACCESS_KEY`, or from
+// files named by `S3_ACCESS_KEY_ID_FILE` / `S3_SECRET_ACCESS_KEY_FILE`
+// (e.g. Docker/Kubernetes secrets).
+use crate::config::{env_or_file, S3Config};
+use crate::db::{sql_literal, DbHandle};
+use chrono::{DateTime, Utc};
+use prometheus::{IntCounterVec, IntGaugeVec, Opts, Registry};
+use s3::{creds::Credentials, Bucket, Region};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Category of uploaded object; used as key prefix and metric label.
+#[derive(Clone, Copy, Debug)]
+pub enum UploadKind {
+    Parquet,
+    Backup,
+}
+
+impl UploadKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            UploadKind::Parquet => "parquet",
+            UploadKind::Backup => "backup",
+        }
+    }
+}
+
+pub struct Uploader {
+    bucket: Box<Bucket>,
+    prefix: String,
+    keep_copies: usize,
+    uploads_total: IntCounterVec,
+    upload_bytes_total: IntCounterVec,
+    last_success: IntGaugeVec,
+}
+
+impl Uploader {
+    /// Build an uploader from the `[s3]` config section. Returns `Ok(None)`
+    /// when no bucket is configured so uploads are simply disabled.
+    pub fn new(config: &S3Config, registry: &Registry) -> anyhow::Result<Option<Self>> {
+        let Some(bucket_name) = &config.bucket else {
+            return Ok(None);
+        };
+        let region = match &config.endpoint {
+            Some(endpoint) => Region::Custom { region: config.region.clone(), endpoint: endpoint.clone() },
+            None => config.region.parse()?,
+        };
+        let access_key = env_or_file("S3_ACCESS_KEY_ID")?;
+        let secret_key = env_or_file("S3_SECRET_ACCESS_KEY")?;
+        let credentials = Credentials::new(access_key.as_deref(), secret_key.as_deref(), None, None, None)?;
+        let mut bucket = Bucket::new(bucket_name, region, credentials)?;
+        // MinIO and most self-hosted stores expect path-style addressing.
+        if config.path_style {
+            bucket = bucket.with_path_style();
+        }
+
+        let uploads_total = IntCounterVec::new(
+            Opts::new("object_uploads_total", "Object storage uploads by kind and result"),
+            &["kind", "result"],
+        )?;
+        let upload_bytes_total = IntCounterVec::new(
+            Opts::new("object_upload_bytes_total", "Bytes successfully uploaded to object storage"),
+            &["kind"],
+        )?;
+        let last_success = IntGaugeVec::new(
+            Opts::new("object_upload_last_success_timestamp_seconds", "Unix time of the last successful upload"),
+            &["kind"],
+        )?;
+        registry.register(Box::new(uploads_total.clone()))?;
+        registry.register(Box::new(upload_bytes_total.clone()))?;
+        registry.register(Box::new(last_success.clone()))?;
+
+        info!("Uploading archives and backups to S3 bucket {}", bucket_name);
+        Ok(Some(Self {
+            bucket,
+            prefix: config.prefix.trim_matches('/').to_string(),
+            keep_copies: config.keep_copies,
+            uploads_total,
+            upload_bytes_total,
+            last_success,
+        }))
+    }
+
+    fn kind_prefix(&self, kind: UploadKind) -> String {
+        if self.prefix.is_empty() {
+            format!("{}/", kind.as_str())
+        } else {
+            format!("{}/{}/", self.prefix, kind.as_str())
+        }
+    }
+
+    /// Upload `path` under the kind's prefix, then prune old remote copies.
+    /// Returns the object key.
+    pub async fn upload_file(&self, path: &Path, kind: UploadKind) -> anyhow::Result<String> {
+        let result = self.put(path, kind).await;
+        let label = if result.is_ok() { "success" } else { "failure" };
+        self.uploads_total.with_label_values(&[kind.as_str(), label]).inc();
+        let (key, size) = result?;
+        self.upload_bytes_total.with_label_values(&[kind.as_str()]).inc_by(size);
+        self.last_success.with_label_values(&[kind.as_str()]).set(Utc::now().timestamp());
+
+        if let Err(e) = self.apply_retention(kind).await {
+            warn!("Pruning old {} objects failed: {}", kind.as_str(), e);
+        }
+        Ok(key)
+    }
+
+    async fn put(&self, path: &Path, kind: UploadKind) -> anyhow::Result<(String, u64)> {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("not a file path: {}", path.display()))?
+            .to_string_lossy();
+        let key = format!("{}{}", self.kind_prefix(kind), file_name);
+        let mut file = tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+        self.bucket.put_object_stream(&mut file, &key).await?;
+        info!("Uploaded {} to s3://{}/{}", path.display(), self.bucket.name(), key);
+        Ok((key, size))
+    }
+
+    /// Delete all but the newest `keep_copies` objects for `kind`.
+    async fn apply_retention(&self, kind: UploadKind) -> anyhow::Result<()> {
+        let objects: Vec<(String, String)> = self
+            .bucket
+            .list(self.kind_prefix(kind), None)
+            .await?
+            .into_iter()
+            .flat_map(|page| page.contents.into_iter().map(|o| (o.key, o.last_modified)))
+            .collect();
+        for key in oldest_excess(objects, self.keep_copies) {
+            self.bucket.delete_object(&key).await?;
+            info!("Deleted old remote copy s3://{}/{}", self.bucket.name(), key);
+        }
+        Ok(())
+    }
+}
+
+/// Keys of all but the `keep` most recently modified of `objects` (key,
+/// RFC 3339 `last_modified`). Archive keys are named after the data they
+/// hold, not when they were uploaded, so key order says nothing about age.
+/// Objects with an unreadable time count as the oldest; ties go by key.
+fn oldest_excess(objects: Vec<(String, String)>, keep: usize) -> Vec<String> {
+    let mut objects: Vec<(Option<DateTime<Utc>>, String)> = objects
+        .into_iter()
+        .map(|(key, last_modified)| (DateTime::parse_from_rfc3339(&last_modified).ok().map(|t| t.to_utc()), key))
+        .collect();
+    objects.sort();
+    let excess = objects.len().saturating_sub(keep);
+    objects.into_iter().take(excess).map(|(_, key)| key).collect()
+}
+
+/// Write a Parquet snapshot of the `measurements` table into `dir`, upload
+/// it and remove the local copy once the upload succeeded.
+pub async fn backup_and_upload(db: &DbHandle, uploader: &Uploader, dir: &Path) -> anyhow::Result<String> {
+    tokio::fs::create_dir_all(dir).await?;
+    let path: PathBuf = dir.join(format!("measurements-{}.parquet", Utc::now().format("%Y%m%dT%H%M%SZ")));
+    let target = sql_literal(&path.to_string_lossy());
+    db.execute(format!("COPY (SELECT * FROM measurements) TO {} (FORMAT parquet, COMPRESSION zstd)", target))
+        .await?;
+    let key = uploader.upload_file(&path, UploadKind::Backup).await?;
+    tokio::fs::remove_file(&path).await?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retention_keeps_the_most_recently_modified_objects() {
+        let objects = vec![
+            ("parquet/2024-03.parquet".to_string(), "2024-04-01T02:00:00.000Z".to_string()),
+            ("parquet/2023-12.parquet".to_string(), "2024-04-02T02:00:00.000Z".to_string()),
+            ("parquet/2024-01.parquet".to_string(), "2024-03-01T02:00:00+01:00".to_string()),
+            ("parquet/broken.parquet".to_string(), "yesterday".to_string()),
+        ];
+        assert_eq!(oldest_excess(objects.clone(), 2), vec!["parquet/broken.parquet", "parquet/2024-01.parquet"]);
+        assert_eq!(oldest_excess(objects.clone(), 4), Vec::<String>::new());
+        assert_eq!(oldest_excess(objects, 0).len(), 4);
+    }
+}
@@ -0,0 +1,64 @@
+// Build identification for clients. `GET /api/version` reports
+//
+//   {"version": "0.1.0", "git_sha": "1a2b3c4d5e6f", "ui_hash": "9f86d081884c"}
+//
+// where `git_sha` is recorded at build time (see `build.rs`) and `ui_hash`
+// identifies the UI build under `ui/dist`: a hash of its `index.html`, which
+// references every asset by its fingerprinted name, so any rebuild changes
+// it. Every response carries the hash in `x-ui-version`; the SPA remembers
+// the value it was loaded with and offers a reload when an API response
+// reports another one. Since asset names change with their content, they
+// are served as immutable while `index.html` must be revalidated.
+use axum::{
+    body::Body,
+    extract::{Extension, State},
+    http::{HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::debug;
+
+pub const UI_VERSION_HEADER: HeaderName = HeaderName::from_static("x-ui-version");
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Version {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    /// `None` when no UI build is deployed.
+    pub ui_hash: Option<String>,
+}
+
+impl Version {
+    /// The running binary and the UI build in `ui_dir`.
+    pub fn detect(ui_dir: &Path) -> Self {
+        let ui_hash = match std::fs::read(ui_dir.join("index.html")) {
+            Ok(index) => {
+                let digest = Sha256::digest(&index);
+                Some(digest.iter().take(6).map(|b| format!("{:02x}", b)).collect())
+            }
+            Err(e) => {
+                debug!("No UI build in {}: {}", ui_dir.display(), e);
+                None
+            }
+        };
+        Self { version: env!("CARGO_PKG_VERSION"), git_sha: env!("GIT_SHA"), ui_hash }
+    }
+}
+
+pub async fn version_handler(Extension(version): Extension<Arc<Version>>) -> Json<Version> {
+    Json(version.as_ref().clone())
+}
+
+/// Middleware adding `x-ui-version` to every response.
+pub async fn ui_version_header(State(version): State<Arc<Version>>, req: Request<Body>, next: Next) -> Response {
+    let mut response = next.run(req).await;
+    if let Some(value) = version.ui_hash.as_deref().and_then(|h| HeaderValue::from_str(h).ok()) {
+        response.headers_mut().insert(UI_VERSION_HEADER, value);
+    }
+    response
+}
@@ -0,0 +1,294 @@
+// Write-ahead log of the MQTT data messages. Readings only reach DuckDB
+// when the ingest buffer is flushed, so a crash (or power cut) loses
+// everything received since the last flush. With a WAL directory set, every
+// data message is appended there before it is normalized:
+//
+//   [wal]
+//   dir = "wal"
+//   sync = false    # fsync every message; survives power loss, but slow
+//
+// The log is split into segments (`<n>.jsonl`, one JSON object per message
+// with topic, payload and receive time). A new segment starts with every
+// flush; segments whose messages are all written to the database are
+// deleted, see `MqttBuffer::set_wal`. At startup the segments left by a
+// crashed process are replayed through the ingest pipeline and flushed, see
+// `mqtt::replay_wal`. A graceful shutdown empties
+// the log after the final flush (or spool). Records are written by a
+// dedicated thread fed through a channel, so neither the write nor the
+// fsync of `sync = true` holds up the MQTT loop; what is still queued for
+// that thread when the process dies is lost. Messages of a segment that were
+// already written when the process died are stored twice on replay; that
+// only happens when a flush was cut short or flush classes delay part of
+// the traffic.
+use crate::config::WalConfig;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Mutex};
+use tracing::warn;
+
+/// One logged message.
+#[derive(Deserialize, Serialize)]
+pub struct WalRecord {
+    pub topic: String,
+    /// The payload when it is UTF-8, which rtl_433 and zigbee2mqtt JSON is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    /// Any other payload, base64 encoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_base64: Option<String>,
+    /// Unix time in milliseconds.
+    pub received_ms: i64,
+}
+
+impl WalRecord {
+    pub fn payload(&self) -> anyhow::Result<Vec<u8>> {
+        match (&self.payload, &self.payload_base64) {
+            (Some(text), _) => Ok(text.clone().into_bytes()),
+            (None, Some(encoded)) => Ok(base64::engine::general_purpose::STANDARD.decode(encoded)?),
+            (None, None) => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Work for the writer thread, in the order it was asked for.
+enum WalOp {
+    Append(Vec<u8>),
+    /// Continue in this segment.
+    Rotate(u64),
+    /// Delete the segments before this one.
+    Truncate(u64),
+    /// Answer once everything before is done.
+    Barrier(mpsc::SyncSender<()>),
+}
+
+/// The log. Callers only encode records and hand them to a dedicated writer
+/// thread, so the ingest loop never waits for the disk; the thread writes
+/// whatever has queued up in one go (and syncs once per batch with `sync`).
+pub struct Wal {
+    dir: PathBuf,
+    /// Segment new records go to.
+    current: AtomicU64,
+    ops: Mutex<mpsc::Sender<WalOp>>,
+    /// Segments left by the previous process, oldest first.
+    leftover: Vec<u64>,
+}
+
+fn segment_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:020}.jsonl", number))
+}
+
+fn open_segment(dir: &Path, number: u64) -> anyhow::Result<File> {
+    let path = segment_path(dir, number);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| anyhow::anyhow!("cannot open WAL segment {}: {}", path.display(), e))
+}
+
+/// Numbers of the segments in `dir`, ascending.
+fn segments(dir: &Path) -> anyhow::Result<Vec<u64>> {
+    let mut numbers = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(number) = name.to_str().and_then(|n| n.strip_suffix(".jsonl")).and_then(|n| n.parse().ok()) {
+            numbers.push(number);
+        }
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+/// Writer thread: runs until the `Wal` is dropped.
+fn write_segments(dir: PathBuf, sync: bool, mut number: u64, mut file: File, ops: mpsc::Receiver<WalOp>) {
+    let mut barriers = Vec::new();
+    while let Ok(op) = ops.recv() {
+        let mut dirty = false;
+        for op in std::iter::once(op).chain(ops.try_iter()) {
+            match op {
+                // One write per record, so a crash leaves at most a torn
+                // last line.
+                WalOp::Append(line) => match file.write_all(&line) {
+                    Ok(()) => dirty = true,
+                    Err(e) => warn!("Cannot append to WAL segment {}: {}", number, e),
+                },
+                WalOp::Rotate(next) => match open_segment(&dir, next) {
+                    Ok(next_file) => (number, file) = (next, next_file),
+                    // Keep appending to the old segment; records of the new
+                    // one are lost if the old one is deleted first.
+                    Err(e) => warn!("{}", e),
+                },
+                WalOp::Truncate(keep_from) => {
+                    let Ok(numbers) = segments(&dir) else { continue };
+                    for old in numbers.into_iter().filter(|n| *n < keep_from.min(number)) {
+                        if let Err(e) = std::fs::remove_file(segment_path(&dir, old)) {
+                            warn!("Cannot delete WAL segment {}: {}", old, e);
+                        }
+                    }
+                }
+                WalOp::Barrier(done) => barriers.push(done),
+            }
+        }
+        if sync
+            && dirty
+            && let Err(e) = file.sync_data()
+        {
+            warn!("Cannot sync WAL segment {}: {}", number, e);
+        }
+        for done in barriers.drain(..) {
+            let _ = done.send(());
+        }
+    }
+}
+
+impl Wal {
+    /// Open the log when `wal.dir` is set, starting a new segment after the
+    /// ones left by the previous process, and start its writer thread.
+    pub fn open(config: &WalConfig) -> anyhow::Result<Option<Self>> {
+        let Some(dir) = &config.dir else { return Ok(None) };
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(&dir).map_err(|e| anyhow::anyhow!("cannot create WAL directory {}: {}", dir.display(), e))?;
+        let leftover = segments(&dir)?;
+        let number = leftover.last().map_or(0, |n| n + 1);
+        let file = open_segment(&dir, number)?;
+        let (ops, receiver) = mpsc::channel();
+        let (thread_dir, sync) = (dir.clone(), config.sync);
+        std::thread::Builder::new()
+            .name("wal-writer".to_string())
+            .spawn(move || write_segments(thread_dir, sync, number, file, receiver))?;
+        Ok(Some(Self { dir, current: AtomicU64::new(number), ops: Mutex::new(ops), leftover }))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Segments left by the previous process, oldest first.
+    pub fn leftover(&self) -> &[u64] {
+        &self.leftover
+    }
+
+    fn send(&self, op: WalOp) {
+        // Only fails when the writer thread died, which it doesn't.
+        let _ = self.ops.lock().unwrap().send(op);
+    }
+
+    /// Queue a message for the writer thread and return the segment it goes
+    /// to. Never blocks on the disk; write errors are logged and ingest
+    /// carries on without the log.
+    pub fn append(&self, topic: &str, payload: &[u8]) -> u64 {
+        let (payload, payload_base64) = match std::str::from_utf8(payload) {
+            Ok(text) => (Some(text.to_string()), None),
+            Err(_) => (None, Some(base64::engine::general_purpose::STANDARD.encode(payload))),
+        };
+        let record = WalRecord {
+            topic: topic.to_string(),
+            payload,
+            payload_base64,
+            received_ms: chrono::Utc::now().timestamp_millis(),
+        };
+        let mut line = serde_json::to_vec(&record).unwrap_or_default();
+        line.push(b'\n');
+        // Under the sender lock, so a concurrent rotate can't slip in
+        // between picking the segment and queueing the record.
+        let ops = self.ops.lock().unwrap();
+        let number = self.current.load(Ordering::Relaxed);
+        let _ = ops.send(WalOp::Append(line));
+        number
+    }
+
+    /// Start a new segment and return its number.
+    pub fn rotate(&self) -> u64 {
+        let ops = self.ops.lock().unwrap();
+        let number = self.current.fetch_add(1, Ordering::Relaxed) + 1;
+        let _ = ops.send(WalOp::Rotate(number));
+        number
+    }
+
+    /// Delete the segments before `keep_from`, never the current one.
+    pub fn truncate(&self, keep_from: u64) {
+        self.send(WalOp::Truncate(keep_from));
+    }
+
+    /// Delete every segment and start an empty one, once everything logged
+    /// is written. Returns when the segments are gone, so a shutdown right
+    /// after leaves nothing to replay.
+    pub fn clear(&self) {
+        let current = self.rotate();
+        self.truncate(current);
+        self.wait();
+    }
+
+    /// Wait until everything queued so far is on disk (synced with `sync`).
+    pub fn wait(&self) {
+        let (done, finished) = mpsc::sync_channel(1);
+        self.send(WalOp::Barrier(done));
+        let _ = finished.recv();
+    }
+
+    /// Read the messages of a segment. A torn last line from a crash is
+    /// skipped.
+    pub fn read(&self, number: u64) -> anyhow::Result<Vec<WalRecord>> {
+        let file = File::open(segment_path(&self.dir, number))?;
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                Err(e) => warn!("Skipping unreadable record in WAL segment {}: {}", number, e),
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dir: &Path) -> Wal {
+        let config = WalConfig { dir: Some(dir.to_string_lossy().into_owned()), sync: true };
+        Wal::open(&config).unwrap().unwrap()
+    }
+
+    fn topics(wal: &Wal, segment: u64) -> Vec<String> {
+        wal.read(segment).unwrap().into_iter().map(|r| r.topic).collect()
+    }
+
+    #[test]
+    fn append_rotate_truncate_and_read_back() {
+        let dir = std::env::temp_dir().join(format!("exporter-wal-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let wal = open(&dir);
+        assert_eq!(wal.append("a", b"{}"), 0);
+        assert_eq!(wal.append("b", &[0xff, 0x00]), 0);
+        assert_eq!(wal.rotate(), 1);
+        assert_eq!(wal.append("c", b"{}"), 1);
+        wal.wait();
+        assert_eq!(topics(&wal, 0), vec!["a", "b"]);
+        let records = wal.read(0).unwrap();
+        assert_eq!(records[1].payload().unwrap(), vec![0xff, 0x00]);
+        assert_eq!(topics(&wal, 1), vec!["c"]);
+
+        // Segment 0 is written; the current one is never deleted.
+        wal.truncate(5);
+        wal.wait();
+        assert_eq!(segments(&dir).unwrap(), vec![1]);
+
+        // A torn last line from a crash is skipped.
+        std::fs::OpenOptions::new().append(true).open(segment_path(&dir, 1)).unwrap().write_all(b"{\"topic\":").unwrap();
+        drop(wal);
+        let wal = open(&dir);
+        assert_eq!(wal.leftover(), &[1]);
+        assert_eq!(topics(&wal, 1), vec!["c"]);
+        assert_eq!(wal.append("d", b"{}"), 2);
+
+        wal.clear();
+        assert_eq!(segments(&dir).unwrap(), vec![3]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,281 @@
+// Warm restarts. The per-sensor gauges only exist once a sensor has sent a
+// message, so after every restart each series is missing until its sensor
+// transmits again, which shows up as gaps in dashboards and as absent
+// series in alerts. On graceful shutdown the gauge cache is written to a
+// file, together with when each sensor was last seen and the latest
+// readings composites work with, and read back at the next start before
+// ingest begins:
+//
+//   [shutdown]
+//   state_file = "warm-state.json"    # "" disables
+//
+// Restoring is stale-aware: sensors last seen longer ago than
+// `metrics.stale_after_secs` are left out, and the rest keep their original
+// last-seen time, so `sensor_last_seen_timestamp_seconds` and
+// `sensor_stale` stay truthful across the restart. The file is deleted once
+// read; after a crash there is none and the gauges start empty as before.
+use crate::metrics::{SensorFreshness, SensorGauges};
+use crate::mqtt_buffer::NormalizedRow;
+use crate::state::{LastSeen, LatestReading, LatestReadings, ReadingKey, SensorKey, Store};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Default, Deserialize, Serialize)]
+struct Snapshot {
+    gauges: Vec<NormalizedRow>,
+    last_seen: Vec<Seen>,
+    latest: Vec<Latest>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Seen {
+    model: String,
+    sensor_id: String,
+    /// Unix time in seconds.
+    at: f64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Latest {
+    model: String,
+    sensor_id: String,
+    measurement_type: u8,
+    #[serde(default)]
+    probe: String,
+    value: f64,
+    /// Unix time in seconds.
+    received_at: f64,
+}
+
+fn unix_seconds(at: SystemTime) -> f64 {
+    at.duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default()
+}
+
+/// The time `secs` after the epoch; the epoch for values no `SystemTime`
+/// holds, such as a negative or edited time in the state file.
+fn system_time(secs: f64) -> SystemTime {
+    Duration::try_from_secs_f64(secs).ok().and_then(|d| UNIX_EPOCH.checked_add(d)).unwrap_or(UNIX_EPOCH)
+}
+
+/// The in-memory state that is kept across graceful restarts.
+pub struct WarmState {
+    path: Option<PathBuf>,
+    pub gauges: Arc<SensorGauges>,
+    pub freshness: Arc<SensorFreshness>,
+    pub last_seen: LastSeen,
+    pub latest: LatestReadings,
+}
+
+impl WarmState {
+    pub fn new(
+        state_file: &str,
+        gauges: Arc<SensorGauges>,
+        freshness: Arc<SensorFreshness>,
+        last_seen: LastSeen,
+        latest: LatestReadings,
+    ) -> Self {
+        let path = (!state_file.is_empty()).then(|| PathBuf::from(state_file));
+        Self { path, gauges, freshness, last_seen, latest }
+    }
+
+    /// Write the state file. Returns what was written, for the shutdown log.
+    pub async fn save(&self) -> anyhow::Result<String> {
+        let Some(path) = &self.path else { return Ok("disabled".to_string()) };
+        let last_seen = self
+            .last_seen
+            .read()
+            .await
+            .iter()
+            .map(|(key, at)| Seen { model: key.model.clone(), sensor_id: key.sensor_id.clone(), at: unix_seconds(*at) })
+            .collect();
+        let latest = self
+            .latest
+            .read()
+            .await
+            .iter()
+            .map(|(key, reading)| Latest {
+                model: key.model.clone(),
+                sensor_id: key.sensor_id.clone(),
+                measurement_type: key.measurement_type,
+                probe: key.probe.clone(),
+                value: reading.value,
+                received_at: unix_seconds(reading.received_at),
+            })
+            .collect();
+        let snapshot = Snapshot { gauges: self.gauges.rows(), last_seen, latest };
+        let series = snapshot.gauges.len();
+        // Written under a temporary name so a crash mid-write leaves no
+        // truncated file behind.
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(&snapshot)?).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(format!("{} series written to {}", series, path.display()))
+    }
+
+    /// Read and delete the state file, restoring the sensors that are not
+    /// stale yet. Returns the number of gauge series restored.
+    pub async fn restore(&self, store: &Store) -> anyhow::Result<usize> {
+        let Some(path) = &self.path else { return Ok(0) };
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        tokio::fs::remove_file(path).await?;
+        let snapshot: Snapshot = serde_json::from_slice(&data)?;
+        let now = SystemTime::now();
+        let fresh: HashSet<(String, String)> = snapshot
+            .last_seen
+            .iter()
+            .filter(|seen| now.duration_since(system_time(seen.at)).unwrap_or_default() <= self.freshness.threshold)
+            .map(|seen| (seen.model.clone(), seen.sensor_id.clone()))
+            .collect();
+        let is_fresh = |model: &str, sensor_id: &str| fresh.contains(&(model.to_string(), sensor_id.to_string()));
+
+        {
+            let mut last_seen = self.last_seen.write().await;
+            for seen in snapshot.last_seen.into_iter().filter(|s| is_fresh(&s.model, &s.sensor_id)) {
+                let key = SensorKey { model: seen.model, sensor_id: seen.sensor_id };
+                let at = system_time(seen.at);
+                self.freshness.seen(&key, at);
+                last_seen.entry(key).or_insert(at);
+            }
+        }
+        {
+            let mut latest = self.latest.write().await;
+            for entry in snapshot.latest.into_iter().filter(|l| is_fresh(&l.model, &l.sensor_id)) {
+                let key = ReadingKey {
+                    model: entry.model,
+                    sensor_id: entry.sensor_id,
+                    measurement_type: entry.measurement_type,
+                    probe: entry.probe,
+                };
+                let reading = LatestReading { value: entry.value, received_at: system_time(entry.received_at) };
+                latest.entry(key).or_insert(reading);
+            }
+        }
+        // Replicated series have no last-seen entry; they are kept when
+        // their reading itself is recent.
+        let rows: Vec<NormalizedRow> = snapshot
+            .gauges
+            .into_iter()
+            .filter(|row| {
+                if row.site.is_empty() {
+                    return is_fresh(&row.model, &row.sensor_id);
+                }
+                let age = chrono::Utc::now().naive_utc() - row.timestamp;
+                age.to_std().unwrap_or_default() <= self.freshness.threshold
+            })
+            .collect();
+        self.gauges.restore(&rows, &*store.read().await);
+        Ok(rows.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{EnrichmentConfig, LoggingConfig, MetricsConfig};
+    use crate::db::{start_db_worker, DbConfig};
+    use crate::enrichment::Enrichment;
+    use crate::logging::LogLimiter;
+    use prometheus::Registry;
+    use std::collections::HashMap;
+
+    fn row(sensor_id: &str) -> NormalizedRow {
+        NormalizedRow {
+            timestamp: chrono::Utc::now().naive_utc(),
+            sensor_id: sensor_id.to_string(),
+            model: "Acurite-Tower".to_string(),
+            measurement_type: 1,
+            value: 21.5,
+            topic: "rtl_433/events".to_string(),
+            seq: 0,
+            site: String::new(),
+            probe: String::new(),
+        }
+    }
+
+    fn sensor(sensor_id: &str) -> SensorKey {
+        SensorKey { model: "Acurite-Tower".to_string(), sensor_id: sensor_id.to_string() }
+    }
+
+    fn reading(sensor_id: &str) -> ReadingKey {
+        ReadingKey {
+            model: "Acurite-Tower".to_string(),
+            sensor_id: sensor_id.to_string(),
+            measurement_type: 1,
+            probe: String::new(),
+        }
+    }
+
+    fn warm_state(path: &std::path::Path, db: &crate::db::DbHandle) -> WarmState {
+        let registry = Registry::new();
+        let log = Arc::new(LogLimiter::new(&LoggingConfig::default()));
+        let enrichment = Arc::new(Enrichment::new(&EnrichmentConfig::default(), db.clone(), log, &registry).unwrap());
+        let gauges = Arc::new(SensorGauges::new(&registry, &MetricsConfig::default(), enrichment).unwrap());
+        let freshness = Arc::new(SensorFreshness::new(&registry, Duration::from_secs(3600)).unwrap());
+        WarmState::new(&path.to_string_lossy(), gauges, freshness, Default::default(), Default::default())
+    }
+
+    #[test]
+    fn unrepresentable_times_read_as_the_epoch() {
+        assert_eq!(system_time(1.5), UNIX_EPOCH + Duration::from_millis(1500));
+        for secs in [-1.0, f64::NAN, f64::INFINITY, 1e300] {
+            assert_eq!(system_time(secs), UNIX_EPOCH, "{}", secs);
+        }
+    }
+
+    #[tokio::test]
+    async fn saved_state_is_restored_without_stale_sensors() {
+        let dir = std::env::temp_dir().join(format!("exporter-warm-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let (db, _worker) = start_db_worker(&config, &Registry::new()).unwrap();
+        let path = dir.join("warm-state.json");
+
+        let saved = warm_state(&path, &db);
+        saved.gauges.observe(&[row("1"), row("2")], &HashMap::new());
+        let now = SystemTime::now();
+        let stale = now - Duration::from_secs(7200);
+        saved.last_seen.write().await.extend([(sensor("1"), now), (sensor("2"), stale)]);
+        saved.latest.write().await.extend([
+            (reading("1"), LatestReading { value: 21.5, received_at: now }),
+            (reading("2"), LatestReading { value: 19.0, received_at: stale }),
+        ]);
+        assert_eq!(saved.save().await.unwrap(), format!("2 series written to {}", path.display()));
+
+        let restored = warm_state(&path, &db);
+        let store: Store = Default::default();
+        assert_eq!(restored.restore(&store).await.unwrap(), 1);
+        assert!(!path.exists());
+        let rows: Vec<String> = restored.gauges.rows().into_iter().map(|row| row.sensor_id).collect();
+        assert_eq!(rows, vec!["1"]);
+        let last_seen = restored.last_seen.read().await;
+        assert_eq!(last_seen.keys().collect::<Vec<_>>(), vec![&sensor("1")]);
+        // The original last-seen time is kept, not the time of the restart.
+        assert!((unix_seconds(last_seen[&sensor("1")]) - unix_seconds(now)).abs() < 0.001);
+        let latest = restored.latest.read().await;
+        assert_eq!(latest.keys().collect::<Vec<_>>(), vec![&reading("1")]);
+        assert_eq!(latest[&reading("1")].value, 21.5);
+
+        // Without a file, e.g. after a crash, nothing is restored.
+        assert_eq!(warm_state(&path, &db).restore(&store).await.unwrap(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_empty_state_file_disables_saving() {
+        let dir = std::env::temp_dir().join(format!("exporter-warm-disabled-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DbConfig { path: dir.join("test.duckdb").to_string_lossy().into_owned(), ..DbConfig::default() };
+        let (db, _worker) = start_db_worker(&config, &Registry::new()).unwrap();
+        let state = warm_state(std::path::Path::new(""), &db);
+        assert_eq!(state.save().await.unwrap(), "disabled");
+        assert_eq!(state.restore(&Default::default()).await.unwrap(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,344 @@
+// Weather-station series. Rain gauges report an ever-growing counter that
+// resets when the batteries are changed, and gusts are only meaningful as
+// a maximum over some time; both are awkward and fragile in PromQL, so the
+// exporter derives them natively from configured measurement keys:
+//
+//   [weather]
+//   rain = "rain_mm"              # cumulative rain counter
+//   gust = "wind_max_m_s"         # gust speed
+//   gust_window_secs = 600
+//
+// (the keys must be declared under `[[measurements]]`). Per sensor the
+// exporter tracks the rain of the current day and hour (in the host's local
+// time zone) from the counter's increments, treating a decrease as a reset
+// to zero, plus a reset-free `weather_rain_total` counter, and the highest
+// gust within the window and within the current day. Values are in the
+// unit of the configured measurement. The current day is replayed from
+// DuckDB at startup, so a restart doesn't zero the daily totals.
+use crate::config::WeatherConfig;
+use crate::db::{DbHandle, MeasurementQuery, RowQuery};
+use crate::mqtt_buffer::{measurement_code, NormalizedRow};
+use crate::state::SensorKey;
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use prometheus::{CounterVec, GaugeVec, Opts, Registry};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+/// Rows replayed at startup; a day of readings of a few stations fits easily.
+const REPLAY_LIMIT: usize = 1_000_000;
+
+pub struct Weather {
+    rain: Option<u8>,
+    gust: Option<u8>,
+    gust_window: chrono::Duration,
+    sensors: Mutex<HashMap<SensorKey, SensorWeather>>,
+    metrics: WeatherMetrics,
+}
+
+struct WeatherMetrics {
+    rain_today: GaugeVec,
+    rain_hour: GaugeVec,
+    rain_total: CounterVec,
+    gust_max: GaugeVec,
+    gust_max_today: GaugeVec,
+}
+
+#[derive(Default)]
+struct SensorWeather {
+    /// Last raw value of the rain counter.
+    rain_counter: Option<f64>,
+    /// Rain of the current local day / hour (start of the hour).
+    rain_day: (NaiveDate, f64),
+    rain_hour: (NaiveDateTime, f64),
+    /// Gust readings within the window, oldest first.
+    gusts: VecDeque<(NaiveDateTime, f64)>,
+    gust_day: (NaiveDate, f64),
+}
+
+/// Current weather values of one sensor, for `GET /api/weather`.
+#[derive(Serialize)]
+pub struct WeatherSummary {
+    pub model: String,
+    pub sensor_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rain_today: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rain_this_hour: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gust_max: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gust_max_today: Option<f64>,
+}
+
+/// Local calendar day and start of the hour of a UTC timestamp.
+fn local_periods(utc: NaiveDateTime) -> (NaiveDate, NaiveDateTime) {
+    let local = Local.from_utc_datetime(&utc).naive_local();
+    let hour = local.date().and_hms_opt(local.hour(), 0, 0).unwrap_or(local);
+    (local.date(), hour)
+}
+
+impl Weather {
+    pub fn new(config: &WeatherConfig, registry: &Registry) -> anyhow::Result<Self> {
+        let code = |key: &Option<String>| -> anyhow::Result<Option<u8>> {
+            key.as_deref()
+                .map(|k| measurement_code(k).ok_or_else(|| anyhow::anyhow!("weather: unknown measurement {} (declare it under [[measurements]])", k)))
+                .transpose()
+        };
+        let labels = &["sensor_id", "model"];
+        let metrics = WeatherMetrics {
+            rain_today: GaugeVec::new(Opts::new("weather_rain_today", "Rain since local midnight"), labels)?,
+            rain_hour: GaugeVec::new(Opts::new("weather_rain_this_hour", "Rain since the start of the local hour"), labels)?,
+            rain_total: CounterVec::new(
+                Opts::new("weather_rain_total", "Rain since the exporter started, with gauge counter resets removed"),
+                labels,
+            )?,
+            gust_max: GaugeVec::new(Opts::new("weather_wind_gust_max", "Highest gust within weather.gust_window_secs"), labels)?,
+            gust_max_today: GaugeVec::new(Opts::new("weather_wind_gust_max_today", "Highest gust since local midnight"), labels)?,
+        };
+        let weather = Self {
+            rain: code(&config.rain)?,
+            gust: code(&config.gust)?,
+            gust_window: chrono::Duration::seconds(config.gust_window_secs.max(1) as i64),
+            sensors: Mutex::new(HashMap::new()),
+            metrics,
+        };
+        if weather.rain.is_some() {
+            registry.register(Box::new(weather.metrics.rain_today.clone()))?;
+            registry.register(Box::new(weather.metrics.rain_hour.clone()))?;
+            registry.register(Box::new(weather.metrics.rain_total.clone()))?;
+        }
+        if weather.gust.is_some() {
+            registry.register(Box::new(weather.metrics.gust_max.clone()))?;
+            registry.register(Box::new(weather.metrics.gust_max_today.clone()))?;
+        }
+        Ok(weather)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.rain.is_some() || self.gust.is_some()
+    }
+
+    /// Replay today's rain and gust readings from DuckDB, starting an hour
+    /// before midnight so the first increment of the day has a baseline.
+    pub async fn restore(&self, db: &DbHandle) -> anyhow::Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let now = Utc::now().naive_utc();
+        let midnight = Local
+            .from_local_datetime(&Local::now().date_naive().and_hms_opt(0, 0, 0).unwrap_or_default())
+            .earliest()
+            .map(|t| t.naive_utc())
+            .unwrap_or(now);
+        let start = midnight.min(now - self.gust_window) - chrono::Duration::hours(1);
+        let rows = db
+            .query_page(RowQuery {
+                selection: MeasurementQuery {
+                    start_ms: start.and_utc().timestamp_millis(),
+                    end_ms: i64::MAX,
+                    measurement_types: self.rain.iter().chain(self.gust.iter()).copied().collect(),
+                    conditions: Vec::new(),
+                },
+                limit: REPLAY_LIMIT,
+                after: None,
+            })
+            .await?;
+        self.apply(&rows, false);
+        self.refresh();
+        info!("Weather: replayed {} readings since {}", rows.len(), start);
+        Ok(())
+    }
+
+    /// Update the series with newly ingested rows.
+    pub fn observe(&self, rows: &[NormalizedRow]) {
+        self.apply(rows, true);
+    }
+
+    fn apply(&self, rows: &[NormalizedRow], live: bool) {
+        let mut sensors = self.sensors.lock().unwrap();
+        for row in rows.iter().filter(|r| r.site.is_empty() && r.probe.is_empty()) {
+            let is_rain = Some(row.measurement_type) == self.rain;
+            let is_gust = Some(row.measurement_type) == self.gust;
+            if !is_rain && !is_gust {
+                continue;
+            }
+            let key = SensorKey { model: row.model.clone(), sensor_id: row.sensor_id.clone() };
+            let labels = [row.sensor_id.as_str(), row.model.as_str()];
+            let sensor = sensors.entry(key).or_default();
+            let (day, hour) = local_periods(row.timestamp);
+            if is_rain {
+                // A lower value than before means the gauge was reset.
+                let increment = match sensor.rain_counter {
+                    Some(last) if row.value >= last => row.value - last,
+                    Some(_) => row.value,
+                    None => 0.0,
+                };
+                sensor.rain_counter = Some(row.value);
+                if sensor.rain_day.0 != day {
+                    sensor.rain_day = (day, 0.0);
+                }
+                if sensor.rain_hour.0 != hour {
+                    sensor.rain_hour = (hour, 0.0);
+                }
+                sensor.rain_day.1 += increment;
+                sensor.rain_hour.1 += increment;
+                if live && increment > 0.0 {
+                    self.metrics.rain_total.with_label_values(&labels).inc_by(increment);
+                }
+                self.metrics.rain_today.with_label_values(&labels).set(sensor.rain_day.1);
+                self.metrics.rain_hour.with_label_values(&labels).set(sensor.rain_hour.1);
+            }
+            if is_gust {
+                sensor.gusts.push_back((row.timestamp, row.value));
+                if sensor.gust_day.0 != day {
+                    sensor.gust_day = (day, row.value);
+                }
+                sensor.gust_day.1 = sensor.gust_day.1.max(row.value);
+                self.metrics.gust_max_today.with_label_values(&labels).set(sensor.gust_day.1);
+                let max = sensor.gusts.iter().map(|(_, v)| *v).fold(f64::MIN, f64::max);
+                self.metrics.gust_max.with_label_values(&labels).set(max);
+            }
+        }
+    }
+
+    /// Expire gusts that left the window and start new days / hours for
+    /// sensors that have been quiet across the boundary.
+    fn refresh(&self) {
+        let now = Utc::now().naive_utc();
+        let (today, this_hour) = local_periods(now);
+        let mut sensors = self.sensors.lock().unwrap();
+        for (key, sensor) in sensors.iter_mut() {
+            let labels = [key.sensor_id.as_str(), key.model.as_str()];
+            if self.rain.is_some() {
+                if sensor.rain_day.0 != today {
+                    sensor.rain_day = (today, 0.0);
+                }
+                if sensor.rain_hour.0 != this_hour {
+                    sensor.rain_hour = (this_hour, 0.0);
+                }
+                self.metrics.rain_today.with_label_values(&labels).set(sensor.rain_day.1);
+                self.metrics.rain_hour.with_label_values(&labels).set(sensor.rain_hour.1);
+            }
+            if self.gust.is_some() {
+                while sensor.gusts.front().is_some_and(|(t, _)| *t < now - self.gust_window) {
+                    sensor.gusts.pop_front();
+                }
+                if sensor.gust_day.0 != today {
+                    sensor.gust_day = (today, 0.0);
+                }
+                let max = sensor.gusts.iter().map(|(_, v)| *v).fold(0.0, f64::max);
+                self.metrics.gust_max.with_label_values(&labels).set(max);
+                self.metrics.gust_max_today.with_label_values(&labels).set(sensor.gust_day.1);
+            }
+        }
+    }
+
+    /// Current values per sensor, sorted by model and sensor id.
+    pub fn snapshot(&self) -> Vec<WeatherSummary> {
+        self.refresh();
+        let sensors = self.sensors.lock().unwrap();
+        let mut summaries: Vec<WeatherSummary> = sensors
+            .iter()
+            .map(|(key, sensor)| WeatherSummary {
+                model: key.model.clone(),
+                sensor_id: key.sensor_id.clone(),
+                rain_today: sensor.rain_counter.map(|_| sensor.rain_day.1),
+                rain_this_hour: sensor.rain_counter.map(|_| sensor.rain_hour.1),
+                gust_max: (!sensor.gusts.is_empty()).then(|| sensor.gusts.iter().map(|(_, v)| *v).fold(0.0, f64::max)),
+                gust_max_today: (sensor.gust_day.1 > 0.0 || !sensor.gusts.is_empty()).then_some(sensor.gust_day.1),
+            })
+            .collect();
+        summaries.sort_by(|a, b| (&a.model, &a.sensor_id).cmp(&(&b.model, &b.sensor_id)));
+        summaries
+    }
+}
+
+/// Re-evaluate the windowed series every 30 seconds, so gusts expire and
+/// days roll over without new readings.
+pub async fn run(weather: Arc<Weather>) {
+    if !weather.is_enabled() {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+        weather.refresh();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Built-in keys stand in for the rain and gust measurements, which a
+    // config would declare under `[[measurements]]`.
+    const RAIN: &str = "humidity";
+    const GUST: &str = "temperature_C";
+
+    fn station() -> Weather {
+        let config = WeatherConfig { rain: Some(RAIN.to_string()), gust: Some(GUST.to_string()), gust_window_secs: 600 };
+        Weather::new(&config, &Registry::new()).unwrap()
+    }
+
+    /// Readings a minute apart from 08:00 UTC, within one local hour in
+    /// every time zone.
+    fn rows(key: &str, values: &[f64]) -> Vec<NormalizedRow> {
+        let start = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_opt(8, 0, 0).unwrap();
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| NormalizedRow {
+                timestamp: start + chrono::Duration::minutes(i as i64),
+                sensor_id: "7".to_string(),
+                model: "Fineoffset-WHx080".to_string(),
+                measurement_type: measurement_code(key).unwrap(),
+                value: *value,
+                topic: "rtl_433/events".to_string(),
+                seq: 0,
+                site: String::new(),
+                probe: String::new(),
+            })
+            .collect()
+    }
+
+    fn gauge(vec: &GaugeVec) -> f64 {
+        vec.with_label_values(&["7", "Fineoffset-WHx080"]).get()
+    }
+
+    #[test]
+    fn rain_is_summed_from_counter_increments_across_resets() {
+        let weather = station();
+        // The first reading is the baseline; 0.2 after 11.0 is a reset.
+        weather.observe(&rows(RAIN, &[10.0, 10.5, 11.0, 0.2, 0.4]));
+        assert!((gauge(&weather.metrics.rain_today) - 1.4).abs() < 1e-9);
+        assert!((gauge(&weather.metrics.rain_hour) - 1.4).abs() < 1e-9);
+        let total = weather.metrics.rain_total.with_label_values(&["7", "Fineoffset-WHx080"]);
+        assert!((total.get() - 1.4).abs() < 1e-9);
+
+        // Replayed readings restore the day but don't count again.
+        let replayed = station();
+        replayed.apply(&rows(RAIN, &[10.0, 10.5]), false);
+        assert!((gauge(&replayed.metrics.rain_today) - 0.5).abs() < 1e-9);
+        assert_eq!(replayed.metrics.rain_total.with_label_values(&["7", "Fineoffset-WHx080"]).get(), 0.0);
+    }
+
+    #[test]
+    fn gusts_keep_their_maximum() {
+        let weather = station();
+        weather.observe(&rows(GUST, &[5.0, 9.0, 7.0]));
+        assert_eq!(gauge(&weather.metrics.gust_max), 9.0);
+        assert_eq!(gauge(&weather.metrics.gust_max_today), 9.0);
+        // Rain and gusts are told apart by type code only.
+        assert_eq!(gauge(&weather.metrics.rain_today), 0.0);
+    }
+
+    #[test]
+    fn unknown_measurements_are_rejected() {
+        let config = WeatherConfig { rain: Some("rain_furlongs".to_string()), ..WeatherConfig::default() };
+        assert!(Weather::new(&config, &Registry::new()).is_err());
+        assert!(!Weather::new(&WeatherConfig::default(), &Registry::new()).unwrap().is_enabled());
+    }
+}
@@ -0,0 +1,149 @@
+// Normalizer for Zigbee2MQTT, next to the rtl_433 one in `mqtt_buffer`.
+// Topics are assigned to it by filter, everything else stays rtl_433:
+//
+//   [mqtt]
+//   topics = ["rtl_433/+/events", "zigbee2mqtt/#"]
+//
+//   [mqtt.formats]
+//   "zigbee2mqtt/#" = "zigbee2mqtt"
+//
+// Zigbee2MQTT publishes the state of each device on `<base>/<friendly
+// name>`, where the base topic is the filter up to its first wildcard
+// (friendly names may contain `/`). The friendly name becomes the sensor
+// id, the model is `device.model` when Zigbee2MQTT includes device
+// information and `zigbee2mqtt` otherwise, and `last_seen` (ISO 8601 or
+// epoch milliseconds) is the timestamp when present.
+//
+// Every member named like a measurement type becomes a row, with booleans
+// as 1/0 and `ON`/`OFF` as 1/0, so `linkquality`, `battery` (percent) and
+// `occupancy` are stored out of the box and `[[measurements]]` can add
+// more (`illuminance_lux`, `contact`, ...). `temperature` and `pressure`
+// (hPa) are stored as `temperature_C` and `pressure_kPa`, `battery_low` as
+// `battery_ok`. The rules of `sentinels` apply as for rtl_433, under the
+// model and measurement key of the row and to the value in that type's
+// unit (a Zigbee2MQTT `pressure` of 1013 hPa is checked as 101.3). An
+// `action` (a string, or an object whose string members are joined with
+// `_`) is stored as a row of the `action` type with value 1 and sensor id
+// `<friendly name>/<action>`; the legacy `<device>/action` topics with a
+// plain string payload work the same way. The bridge's own topics and the
+// `set`/`get`/`availability` topics of devices yield no rows.
+use crate::mqtt_buffer::{measurement_types, NormalizedRow};
+use crate::sentinels::Sentinels;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde_json::{Map, Value};
+
+/// Model of devices without device information in their payloads.
+const MODEL: &str = "zigbee2mqtt";
+
+/// Zigbee2MQTT members stored under another measurement type, with the
+/// divisor to that type's unit.
+const ALIASES: &[(&str, &str, f64)] = &[("temperature", "temperature_C", 1.0), ("pressure", "pressure_kPa", 10.0)];
+
+/// Device topics that carry no state.
+const IGNORED: &[&str] = &["set", "get", "availability"];
+
+/// Base topic of the devices matched by `filter`: the levels before the
+/// first wildcard, or all but the last one without wildcards.
+pub fn base_topic(filter: &str) -> String {
+    let levels: Vec<&str> = filter.split('/').collect();
+    let end = levels.iter().position(|l| *l == "+" || *l == "#").unwrap_or(levels.len().saturating_sub(1));
+    levels[..end].join("/")
+}
+
+fn timestamp(obj: &Map<String, Value>) -> NaiveDateTime {
+    match obj.get("last_seen") {
+        Some(Value::String(s)) => DateTime::parse_from_rfc3339(s).ok().map(|t| t.naive_utc()),
+        Some(Value::Number(n)) => n.as_i64().and_then(DateTime::from_timestamp_millis).map(|t| t.naive_utc()),
+        _ => None,
+    }
+    .unwrap_or_else(|| Utc::now().naive_utc())
+}
+
+/// Name of an `action`: the string itself, or an object's string members
+/// joined with `_`.
+fn action_name(value: &Value) -> Option<String> {
+    let name = match value {
+        Value::String(s) => s.clone(),
+        Value::Object(members) => members.values().filter_map(Value::as_str).collect::<Vec<_>>().join("_"),
+        _ => return None,
+    };
+    (!name.is_empty()).then_some(name)
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::Bool(b) => Some(*b as u8 as f64),
+        Value::String(s) if s.eq_ignore_ascii_case("on") => Some(1.0),
+        Value::String(s) if s.eq_ignore_ascii_case("off") => Some(0.0),
+        _ => None,
+    }
+}
+
+/// Turn a Zigbee2MQTT message received on `topic` below `base` into zero
+/// or more rows, dropping or clamping readings by `sentinels`.
+pub fn normalize(base: &str, topic: &str, payload: &[u8], sentinels: &Sentinels) -> anyhow::Result<Vec<NormalizedRow>> {
+    let device = topic
+        .strip_prefix(base)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .filter(|d| !d.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("topic is not below the Zigbee2MQTT base topic {}", base))?;
+    if device == "bridge" || device.starts_with("bridge/") {
+        return Ok(Vec::new());
+    }
+    let (device, last) = match device.rsplit_once('/') {
+        Some((device, last)) => (device, Some(last)),
+        None => (device, None),
+    };
+    let row = |sensor_id: String, model: &str, timestamp, code, value| NormalizedRow {
+        timestamp,
+        sensor_id,
+        model: model.to_string(),
+        measurement_type: code,
+        value,
+        topic: topic.to_string(),
+        seq: 0,
+        site: String::new(),
+        probe: String::new(),
+    };
+    let action_code = measurement_types().iter().find(|t| t.key == "action").map(|t| t.code);
+    match last {
+        Some(last) if IGNORED.contains(&last) => return Ok(Vec::new()),
+        Some("action") => {
+            let value = serde_json::from_slice(payload).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(payload).trim().to_string()));
+            return Ok(match (action_name(&value), action_code) {
+                (Some(action), Some(code)) => vec![row(format!("{}/{}", device, action), MODEL, Utc::now().naive_utc(), code, 1.0)],
+                _ => Vec::new(),
+            });
+        }
+        _ => {}
+    }
+    // Not a sub-topic after all: the friendly name contains `/`.
+    let device = topic[base.len() + 1..].to_string();
+
+    let v: Value = serde_json::from_slice(payload)?;
+    let obj = v.as_object().ok_or_else(|| anyhow::anyhow!("payload is not a JSON object"))?;
+    let model = obj.get("device").and_then(|d| d.get("model")).and_then(Value::as_str).unwrap_or(MODEL);
+    let timestamp = timestamp(obj);
+
+    let mut rows = Vec::new();
+    for (key, value) in obj {
+        if key == "action" {
+            if let (Some(action), Some(code)) = (action_name(value), action_code) {
+                rows.push(row(format!("{}/{}", device, action), model, timestamp, code, 1.0));
+            }
+            continue;
+        }
+        let (key, divisor, value) = match (key.as_str(), value) {
+            ("battery_low", Value::Bool(low)) => ("battery_ok", 1.0, Some(!low as u8 as f64)),
+            (key, value) => match ALIASES.iter().find(|(from, _, _)| *from == key) {
+                Some((_, to, divisor)) => (*to, *divisor, number(value)),
+                None => (key, 1.0, number(value)),
+            },
+        };
+        let (Some(value), Some(t)) = (value, measurement_types().iter().find(|t| t.key == key)) else { continue };
+        let Some(value) = sentinels.check(model, key, value / divisor) else { continue };
+        rows.push(row(device.clone(), model, timestamp, t.code, value * t.scale + t.offset));
+    }
+    Ok(rows)
+}
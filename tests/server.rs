@@ -0,0 +1,42 @@
+// Runs the whole exporter in-process and stops it again, the way an
+// embedding program does with `server::run_with`.
+use rust_to_mqtt_prometheus_exporter::{config::Config, logging, server};
+
+#[tokio::test]
+async fn the_exporter_starts_and_stops_in_process() {
+    let dir = std::env::temp_dir().join(format!("exporter-server-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+    let mut config = Config::default();
+    config.http.bind = "127.0.0.1:0".to_string();
+    // Nothing listens there; the MQTT loop keeps retrying until stopped.
+    config.mqtt.host = "127.0.0.1".to_string();
+    config.mqtt.port = 1;
+    config.duckdb.path = path("exporter.duckdb");
+    config.mapping.file = path("mappings.json");
+    config.mapping.manufacturers = vec!["Acurite-Tower".to_string()];
+    config.shutdown.spool_dir = path("spool");
+    config.shutdown.state_file = path("warm-state.json");
+    config.jobs.dir = path("exports");
+    config.archive.dir = path("archive");
+    let log_filter = logging::detached(&config.logging).unwrap();
+
+    let server = server::run_with(config, log_filter).await.unwrap();
+    let base = format!("http://{}", server.local_addr());
+    let http = reqwest::Client::new();
+    let health = http.get(format!("{}/health", base)).send().await.unwrap();
+    assert!(health.status().is_success());
+    let mapping = serde_json::json!({ "sensor_id": "1", "manufacturer": "Acurite-Tower", "name": "Garden" });
+    let put = http
+        .put(format!("{}/mapping", base))
+        .header("content-type", "application/json")
+        .body(mapping.to_string())
+        .send().await.unwrap();
+    assert_eq!(put.status().as_u16(), 201);
+    assert!(std::fs::read_to_string(path("mappings.json")).unwrap().contains("Garden"));
+
+    server.shutdown().await.unwrap();
+    assert!(http.get(format!("{}/health", base)).send().await.is_err());
+    assert!(std::path::Path::new(&path("warm-state.json")).exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
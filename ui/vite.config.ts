@@ -11,6 +11,10 @@ export default defineConfig({
       '/metrics': {
         target: 'http://localhost:3000',
         changeOrigin: true,
+      },
+      '/api': {
+        target: 'http://localhost:3000',
+        changeOrigin: true,
       }
     }
   }